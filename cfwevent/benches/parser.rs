@@ -0,0 +1,78 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Parsing a read's worth of events, and encoding them as the json of a zone's log. Run with
+//! `cargo bench`, which saves each run's results for the next one to be compared against.
+
+use cfwevent::parser::{self, CfwEvent};
+use cfwevent::wire::{self, Packet, RuleInfo, TRAFFIC_EVENT_SIZE};
+use chrono::Utc;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use std::net::Ipv4Addr;
+use uuid::Uuid;
+
+/// As many events as the device hands back from a read of a full ring
+const EVENTS: usize = 2048;
+
+/// A read's worth of traffic events spread over a few zones, mostly TCP with some blocks
+fn read_buffer() -> Vec<u8> {
+    let mut buf = vec![0; EVENTS * TRAFFIC_EVENT_SIZE];
+    for (n, event) in buf.chunks_mut(TRAFFIC_EVENT_SIZE).enumerate() {
+        let rule = RuleInfo {
+            event: if n % 10 == 9 { 1 } else { 2 },
+            zonedid: (n % 16) as u32,
+            rule_id: (n % 16) as u32,
+            rule_uuid: Uuid::nil(),
+        };
+        let packet = Packet {
+            protocol: if n % 20 == 0 { 17 } else { 6 },
+            source_ip: Ipv4Addr::new(10, 0, (n >> 8) as u8, n as u8).to_ipv6_mapped(),
+            destination_ip: Ipv4Addr::new(10, 1, 0, (n % 16) as u8).to_ipv6_mapped(),
+            source_port: 32768 + n as u16,
+            destination_port: 443,
+        };
+        wire::encode_event(&rule, &packet, 1 + (n % 2) as u8, Utc::now(), event);
+    }
+    buf
+}
+
+fn parse_all(mut bytes: &[u8]) -> Vec<CfwEvent> {
+    let mut events = Vec::with_capacity(EVENTS);
+    while !bytes.is_empty() {
+        let (rest, event) = parser::cfwevent_parse(bytes).expect("failed to parse event");
+        events.push(event);
+        bytes = rest;
+    }
+    events
+}
+
+fn parse(c: &mut Criterion) {
+    let buf = read_buffer();
+    let mut group = c.benchmark_group("parser");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+    group.bench_function("parse_read", |b| b.iter(|| parse_all(black_box(&buf))));
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let events = parse_all(&read_buffer());
+    let mut buf = Vec::with_capacity(EVENTS * 512);
+    let mut group = c.benchmark_group("record");
+    group.throughput(Throughput::Elements(EVENTS as u64));
+    group.bench_function("json", |b| {
+        b.iter(|| {
+            buf.clear();
+            for event in &events {
+                serde_json::to_writer(&mut buf, black_box(event)).unwrap();
+                buf.push(b'\n');
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, encode);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Parse arbitrary bytes as a read from the device. This has to fail cleanly rather than panic,
+//! and every event that does parse has to move the parser forward and serialize.
+
+#![no_main]
+
+use cfwevent::parser::cfwevent_parse;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut bytes = data;
+    while !bytes.is_empty() {
+        let (leftover, event) = match cfwevent_parse(bytes) {
+            Ok(parsed) => parsed,
+            Err(_) => break,
+        };
+        assert!(leftover.len() < bytes.len(), "parsing made no progress");
+        serde_json::to_string(&event).expect("failed to serialize event");
+        bytes = leftover;
+    }
+});
@@ -0,0 +1,53 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Parse a read made up of structured records, which gets past the header and length checks far
+//! more often than arbitrary bytes do. Records the device could have produced must parse back to
+//! what was encoded, and the rest must fail without a panic.
+
+#![no_main]
+
+use cfwevent::parser::{cfwevent_parse, CfwEvent};
+use cfwevent_fuzz::RawRecord;
+use libfuzzer_sys::fuzz_target;
+use std::net::Ipv6Addr;
+
+fuzz_target!(|records: Vec<RawRecord>| {
+    let mut buf = vec![];
+    for record in &records {
+        record.encode(&mut buf);
+    }
+
+    let mut bytes = &buf[..];
+    for record in &records {
+        // Malformed records are parsed too, they just mustn't panic
+        let parsed = cfwevent_parse(bytes);
+        if !record.well_formed() {
+            // There's no telling where the next record starts
+            break;
+        }
+        let (leftover, event) = parsed.expect("a well formed record failed to parse");
+        assert_eq!(bytes.len() - leftover.len(), usize::from(record.length()));
+        assert_eq!(event.zone(), record.zonedid);
+        match event {
+            CfwEvent::Traffic(e) => {
+                assert_eq!(e.rule_id, record.rule_id);
+                assert_eq!(e.source_port, record.source_port);
+                assert_eq!(e.destination_port, record.destination_port);
+                assert_eq!(e.source_ip, Ipv6Addr::from(record.source_ip));
+                assert_eq!(e.destination_ip, Ipv6Addr::from(record.destination_ip));
+                assert_eq!(e.timestamp.timestamp(), record.time_sec);
+                assert_eq!(
+                    i64::from(e.timestamp.timestamp_subsec_micros()),
+                    record.time_usec
+                );
+                assert_eq!(e.rule_uuid.as_bytes(), &record.rule_uuid);
+            }
+            CfwEvent::Unknown(e) => assert_eq!(e.raw_event, record.event),
+        }
+        bytes = leftover;
+    }
+});
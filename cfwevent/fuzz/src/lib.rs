@@ -0,0 +1,70 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Fuzzing harness for the cfw event parser in the "cfwevent" crate, see the README's "Fuzzing"
+//! section.
+
+use arbitrary::Arbitrary;
+
+/// Size of a cfwev_t as the device lays it out
+pub const RECORD_LEN: usize = 88;
+
+/// A cfwev_t with every field up to the fuzzer, including values the device never produces
+#[derive(Arbitrary, Debug)]
+pub struct RawRecord {
+    pub event: u16,
+    /// Added to the record's length, with that many extra bytes following it when positive
+    pub length_skew: i8,
+    pub zonedid: u32,
+    pub rule_id: u32,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub protocol: u8,
+    pub direction: u8,
+    pub source_ip: u128,
+    pub destination_ip: u128,
+    pub time_sec: i64,
+    pub time_usec: i64,
+    pub rule_uuid: [u8; 16],
+}
+
+impl RawRecord {
+    /// The length the record claims to be
+    pub fn length(&self) -> u16 {
+        (RECORD_LEN as i32 + i32::from(self.length_skew)) as u16
+    }
+
+    /// Whether the device could have produced this record, in which case it must parse
+    pub fn well_formed(&self) -> bool {
+        self.length_skew >= 0
+            && (!matches!(self.event, 1..=3)
+                || (matches!(self.direction, 1 | 2)
+                    && matches!(self.time_usec, 0..=999_999)
+                    // Well within the years chrono can represent
+                    && (-(1 << 40)..1 << 40).contains(&self.time_sec)))
+    }
+
+    /// Lay the record out the way the device would
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.event.to_le_bytes());
+        buf.extend_from_slice(&self.length().to_le_bytes());
+        buf.extend_from_slice(&self.zonedid.to_le_bytes());
+        buf.extend_from_slice(&self.rule_id.to_le_bytes());
+        buf.extend_from_slice(&self.source_port.to_be_bytes());
+        buf.extend_from_slice(&self.destination_port.to_be_bytes());
+        buf.push(self.protocol);
+        buf.push(self.direction);
+        buf.extend_from_slice(&[0; 6]);
+        buf.extend_from_slice(&self.source_ip.to_be_bytes());
+        buf.extend_from_slice(&self.destination_ip.to_be_bytes());
+        buf.extend_from_slice(&self.time_sec.to_le_bytes());
+        buf.extend_from_slice(&self.time_usec.to_le_bytes());
+        buf.extend_from_slice(&self.rule_uuid);
+        for _ in 0..self.length_skew.max(0) {
+            buf.push(0xff);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An indexed binary alternative to the json lines of a zone's log, so the records of a time
+//! range can be found without reading the whole file.
+//!
+//! The file is a sequence of fixed size blocks, each starting with a header, and then as many
+//! records as fit in the rest of the block. Whatever is left at the end of a block is zeroed. The
+//! header is `MAGIC`, the block size as a little endian u32, four reserved bytes, and the block's
+//! timestamp. A record is the length of its json as a little endian u32, its timestamp, and then
+//! the json itself, without a newline. Timestamps are microseconds since the epoch as little
+//! endian i64s.
+//!
+//! A block's timestamp is the latest timestamp of any record up to and including the block's first
+//! one. Since they never go down, the block a time range starts in is found with a binary search
+//! over the block headers, which makes the headers a sparse index of the file. cfwlogd writes
+//! records in the order it logs them, which is close to the order of their timestamps, but a
+//! record logged well out of order, such as an event that was held until vminfod reported its
+//! zone, can be missed by a query for a range it falls in.
+
+use chrono::{DateTime, Utc};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Every block, and so every indexed log, starts with these bytes
+pub const MAGIC: &[u8; 8] = b"cfwidx01";
+
+/// The magic, block size, reserved bytes and timestamp every block starts with
+pub const BLOCK_HEADER_LEN: usize = 24;
+
+/// The length and timestamp every record starts with
+pub const RECORD_HEADER_LEN: usize = 12;
+
+pub const DEFAULT_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// A timestamp as it's written to an indexed log
+pub fn micros(at: &DateTime<Utc>) -> i64 {
+    at.timestamp() * 1_000_000 + i64::from(at.timestamp_subsec_micros())
+}
+
+/// Whether an existing file is an indexed log, or None if the file is empty
+pub fn is_indexed<R: Read>(mut file: R) -> io::Result<Option<bool>> {
+    let mut magic = [0; 8];
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some(read == magic.len() && &magic == MAGIC))
+}
+
+/// Appends records to an indexed log
+pub struct IndexedWriter<W: Write> {
+    writer: W,
+    block_size: usize,
+    /// Where the next byte lands in the file
+    offset: u64,
+    /// The latest timestamp written so far
+    latest: Option<i64>,
+    /// Set once a write failed partway, after which the block being written may end in a partly
+    /// written record and the next record has to start a new block
+    torn: bool,
+}
+
+impl<W: Write> IndexedWriter<W> {
+    /// Write an indexed log to an empty file
+    pub fn new(writer: W, block_size: u32) -> Self {
+        IndexedWriter {
+            writer,
+            block_size: block_size as usize,
+            offset: 0,
+            latest: None,
+            torn: false,
+        }
+    }
+
+    /// Append to an indexed log of `len` bytes whose records go up to `latest`, which has to end
+    /// with a complete record, see `IndexedReader::resume_point`
+    pub fn resume(writer: W, block_size: u32, len: u64, latest: Option<i64>) -> Self {
+        IndexedWriter {
+            writer,
+            block_size: block_size as usize,
+            offset: len,
+            latest,
+            torn: false,
+        }
+    }
+
+    /// The latest timestamp written so far
+    pub fn latest(&self) -> Option<i64> {
+        self.latest
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Append a record's json, which must fit in a block along with the block's header
+    pub fn write_record(&mut self, timestamp: i64, json: &[u8]) -> io::Result<()> {
+        let len = RECORD_HEADER_LEN + json.len();
+        if json.is_empty() || BLOCK_HEADER_LEN + len > self.block_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "a record of {} bytes doesn't fit in a block of {} bytes",
+                    json.len(),
+                    self.block_size
+                ),
+            ));
+        }
+        let latest = self
+            .latest
+            .map_or(timestamp, |latest| latest.max(timestamp));
+        let used = (self.offset % self.block_size as u64) as usize;
+        if used == 0 || self.torn || used + len > self.block_size {
+            if used > 0 {
+                self.put(&vec![0; self.block_size - used])?;
+            }
+            let mut header = Vec::with_capacity(BLOCK_HEADER_LEN);
+            header.extend_from_slice(MAGIC);
+            header.extend_from_slice(&(self.block_size as u32).to_le_bytes());
+            header.extend_from_slice(&[0; 4]);
+            header.extend_from_slice(&latest.to_le_bytes());
+            self.put(&header)?;
+            self.torn = false;
+        }
+        let mut record = Vec::with_capacity(len);
+        record.extend_from_slice(&(json.len() as u32).to_le_bytes());
+        record.extend_from_slice(&timestamp.to_le_bytes());
+        record.extend_from_slice(json);
+        self.put(&record)?;
+        self.latest = Some(latest);
+        Ok(())
+    }
+
+    /// Write all of `bytes`, keeping track of how much of them made it in case of an error
+    fn put(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            match self.writer.write(bytes) {
+                Ok(0) => {
+                    self.torn = true;
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(n) => {
+                    self.offset += n as u64;
+                    bytes = &bytes[n..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.torn = true;
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the records of an indexed log, which may still be being written
+pub struct IndexedReader<R: Read + Seek> {
+    reader: R,
+    block_size: u64,
+    len: u64,
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        let mut indexed = IndexedReader {
+            reader,
+            block_size: u64::from(DEFAULT_BLOCK_SIZE),
+            len,
+        };
+        if indexed.blocks() > 0 {
+            let (block_size, _) = indexed.header(0)?;
+            if (block_size as usize) < BLOCK_HEADER_LEN + RECORD_HEADER_LEN {
+                return Err(corrupt(0));
+            }
+            indexed.block_size = u64::from(block_size);
+        }
+        Ok(indexed)
+    }
+
+    pub fn block_size(&self) -> u32 {
+        self.block_size as u32
+    }
+
+    /// The length of the file when it was opened
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The number of blocks, not counting one whose header hasn't been written in full yet
+    fn blocks(&self) -> u64 {
+        if self.len < BLOCK_HEADER_LEN as u64 {
+            return 0;
+        }
+        (self.len - BLOCK_HEADER_LEN as u64) / self.block_size + 1
+    }
+
+    /// The block size and timestamp of a block's header
+    fn header(&mut self, block: u64) -> io::Result<(u32, i64)> {
+        let offset = block * self.block_size;
+        let mut header = [0; BLOCK_HEADER_LEN];
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(corrupt(offset));
+        }
+        let mut block_size = [0; 4];
+        block_size.copy_from_slice(&header[8..12]);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&header[16..24]);
+        Ok((
+            u32::from_le_bytes(block_size),
+            i64::from_le_bytes(timestamp),
+        ))
+    }
+
+    /// Call `f` with the timestamp and json of every record in a block, returning where in the
+    /// block the last of them ends. A record at the end of the file that hasn't been written in
+    /// full is left out.
+    fn records<F>(&mut self, block: u64, f: &mut F) -> io::Result<usize>
+    where
+        F: FnMut(i64, &[u8]) -> io::Result<()>,
+    {
+        self.header(block)?;
+        let offset = block * self.block_size;
+        let size = self.block_size.min(self.len - offset) as usize;
+        let mut buf = vec![0; size - BLOCK_HEADER_LEN];
+        self.reader.read_exact(&mut buf)?;
+        let mut records = &buf[..];
+        while records.len() >= RECORD_HEADER_LEN {
+            let mut len = [0; 4];
+            len.copy_from_slice(&records[..4]);
+            let len = u32::from_le_bytes(len) as usize;
+            if len == 0 || RECORD_HEADER_LEN + len > records.len() {
+                break;
+            }
+            let mut timestamp = [0; 8];
+            timestamp.copy_from_slice(&records[4..12]);
+            f(
+                i64::from_le_bytes(timestamp),
+                &records[RECORD_HEADER_LEN..RECORD_HEADER_LEN + len],
+            )?;
+            records = &records[RECORD_HEADER_LEN + len..];
+        }
+        Ok(size - records.len())
+    }
+
+    /// Call `f` with the timestamp and json of every record from `start` up to but not including
+    /// `end`, in the order they were written. Only the blocks the range falls in are read.
+    pub fn query<F>(
+        &mut self,
+        start: Option<&DateTime<Utc>>,
+        end: Option<&DateTime<Utc>>,
+        mut f: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(i64, &[u8]) -> io::Result<()>,
+    {
+        let start = start.map(micros);
+        let end = end.map(micros);
+        let blocks = self.blocks();
+        let first = match start {
+            None => 0,
+            Some(start) => {
+                // The first block with a record at or after the start, whose records are all
+                // later than the records of every block before it. The block before it may well
+                // have some of the range in it too.
+                let (mut low, mut high) = (0, blocks);
+                while low < high {
+                    let mid = low + (high - low) / 2;
+                    if self.header(mid)?.1 < start {
+                        low = mid + 1;
+                    } else {
+                        high = mid;
+                    }
+                }
+                low.saturating_sub(1)
+            }
+        };
+        for block in first..blocks {
+            if let Some(end) = end {
+                if block > first && self.header(block)?.1 >= end {
+                    break;
+                }
+            }
+            self.records(block, &mut |timestamp, json| {
+                let after_start = start.map_or(true, |start| timestamp >= start);
+                let before_end = end.map_or(true, |end| timestamp < end);
+                if after_start && before_end {
+                    f(timestamp, json)
+                } else {
+                    Ok(())
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Where the last complete record ends, and the latest timestamp of any record, for resuming
+    /// writing the file with `IndexedWriter::resume` once anything after that point is truncated.
+    /// Whoever wrote the file last may have stopped partway through a record.
+    pub fn resume_point(&mut self) -> io::Result<(u64, Option<i64>)> {
+        let last = match self.blocks() {
+            0 => return Ok((0, None)),
+            blocks => blocks - 1,
+        };
+        let mut latest = self.header(last)?.1;
+        let end = self.records(last, &mut |timestamp, _| {
+            latest = latest.max(timestamp);
+            Ok(())
+        })?;
+        Ok((last * self.block_size + end as u64, Some(latest)))
+    }
+}
+
+fn corrupt(offset: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("no indexed log block header at offset {}", offset),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: u32 = 256;
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp(1_589_310_000 + secs, 0)
+    }
+
+    /// A log of a record a second, each of which takes up a ~third of a block
+    fn log(records: i64) -> Vec<u8> {
+        let mut writer = IndexedWriter::new(vec![], BLOCK_SIZE);
+        for secs in 0..records {
+            let json = format!("{{\"n\":{},\"pad\":\"{}\"}}", secs, "x".repeat(50));
+            writer
+                .write_record(micros(&at(secs)), json.as_bytes())
+                .unwrap();
+        }
+        writer.writer
+    }
+
+    fn query(log: &[u8], start: Option<i64>, end: Option<i64>) -> Vec<i64> {
+        let mut reader = IndexedReader::new(Cursor::new(log)).unwrap();
+        let mut found = vec![];
+        reader
+            .query(
+                start.map(at).as_ref(),
+                end.map(at).as_ref(),
+                |timestamp, json| {
+                    let json: serde_json::Value = serde_json::from_slice(json).unwrap();
+                    assert_eq!(timestamp, micros(&at(json["n"].as_i64().unwrap())));
+                    found.push(json["n"].as_i64().unwrap());
+                    Ok(())
+                },
+            )
+            .unwrap();
+        found
+    }
+
+    #[test]
+    fn time_ranges() {
+        let log = log(100);
+        assert_eq!(is_indexed(&log[..]).unwrap(), Some(true));
+        assert_eq!(is_indexed(&b"{\"event\""[..]).unwrap(), Some(false));
+        assert_eq!(is_indexed(&b""[..]).unwrap(), None);
+
+        assert_eq!(query(&log, None, None), (0..100).collect::<Vec<_>>());
+        assert_eq!(query(&log, Some(40), Some(45)), vec![40, 41, 42, 43, 44]);
+        assert_eq!(query(&log, Some(98), None), vec![98, 99]);
+        assert_eq!(query(&log, None, Some(2)), vec![0, 1]);
+        assert!(query(&log, Some(200), None).is_empty());
+    }
+
+    #[test]
+    fn partial_logs() {
+        let log = log(10);
+        // A record that's partly written at the end of the file is left out
+        assert_eq!(query(&log[..log.len() - 5], None, None).len(), 9);
+        // As is a block whose header isn't written in full
+        let mut more = log.clone();
+        more.extend_from_slice(&MAGIC[..]);
+        assert_eq!(query(&more, None, None).len(), 10);
+
+        let torn = &log[..log.len() - 5];
+        let (len, latest) = IndexedReader::new(Cursor::new(torn))
+            .unwrap()
+            .resume_point()
+            .unwrap();
+        assert_eq!(latest, Some(micros(&at(8))));
+        let mut writer =
+            IndexedWriter::resume(torn[..len as usize].to_vec(), BLOCK_SIZE, len, latest);
+        writer.write_record(micros(&at(10)), b"{\"n\":10}").unwrap();
+        assert_eq!(
+            query(&writer.writer, None, None),
+            vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 10],
+            "the torn record is cut off and the log carries on after the last complete one"
+        );
+        assert!(
+            query(&log[..10], None, None).is_empty(),
+            "the first header isn't written in full"
+        );
+
+        let mut writer = IndexedWriter::new(vec![], BLOCK_SIZE);
+        assert!(writer.write_record(0, &[b'x'; 256]).is_err(), "too big");
+        assert!(writer.write_record(0, b"").is_err());
+        let json = b"{\"schema_version\":1,\"event\":\"block\"}\n";
+        assert!(IndexedReader::new(Cursor::new(&json[..])).is_err());
+    }
+}
@@ -0,0 +1,20 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The cloud firewall event formats, shared by cfwlogd and anything else that needs to read them
+//! without linking the daemon.
+//!
+//! - `parser` parses the binary events read from the ipfev device into `CfwEvent`s.
+//! - `record` holds the schema of the records cfwlogd writes to each zone's log, and reads them
+//!   back.
+//! - `indexed` writes and queries the indexed binary format a zone's log can be written in
+//!   instead of json lines.
+//! - `wire` encodes traffic events the way the ipfev device hands them back.
+
+pub mod indexed;
+pub mod parser;
+pub mod record;
+pub mod wire;
@@ -0,0 +1,921 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2019 Joyent, Inc.
+
+use std::cell::Cell;
+use std::convert::{TryFrom, TryInto};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, TimeZone, Utc};
+use nom::bytes::complete::take;
+use nom::error::{ErrorKind, ParseError, VerboseError};
+use nom::number::complete::{le_u16, le_u32};
+use nom::IResult;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use uuid::Uuid;
+
+/// The IPv4 address an IPv4-mapped address (::ffff:a.b.c.d) maps to. Other addresses, including
+/// the deprecated IPv4-compatible ones like "::1", aren't IPv4 addresses.
+pub fn ipv4_mapped(addr: &Ipv6Addr) -> Option<Ipv4Addr> {
+    match addr.segments() {
+        [0, 0, 0, 0, 0, 0xffff, hi, lo] => Some(((u32::from(hi) << 16) | u32::from(lo)).into()),
+        _ => None,
+    }
+}
+
+/// The address as it's logged, which is the IPv4 address an IPv4-mapped address maps to when
+/// `normalize` is set. Other addresses are always logged as is.
+fn logged_ip(addr: &Ipv6Addr, normalize: bool) -> IpAddr {
+    match ipv4_mapped(addr) {
+        Some(v4) if normalize => IpAddr::V4(v4),
+        _ => IpAddr::V6(*addr),
+    }
+}
+
+/// The address as the current thread's `Serialization` logs it, see `logged_ip`
+pub fn logged_addr(addr: &Ipv6Addr) -> IpAddr {
+    logged_ip(addr, Serialization::current().normalize_ipv4_mapped)
+}
+
+/// Serialize an address as it's logged, see `logged_ip`
+pub fn serialize_ip<S: Serializer>(addr: &Ipv6Addr, serializer: S) -> Result<S::Ok, S::Error> {
+    logged_addr(addr).serialize(serializer)
+}
+
+/// How timestamps are logged, see the config's "timestamp_format"
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    /// RFC 3339 with as many fractional digits as the timestamp needs
+    Rfc3339,
+    /// RFC 3339 with all nine fractional digits
+    Rfc3339Nanos,
+    /// Milliseconds since the epoch, as a number
+    EpochMs,
+    /// Nanoseconds since the epoch, as a number
+    EpochNs,
+}
+
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::Rfc3339
+    }
+}
+
+/// The timezone RFC 3339 timestamps are logged in, see the config's "timestamp_timezone"
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Timezone {
+    Utc,
+    /// The system's timezone, with its offset from UTC
+    Local,
+}
+
+impl Default for Timezone {
+    fn default() -> Self {
+        Timezone::Utc
+    }
+}
+
+/// How addresses and timestamps are serialized, from the config's "normalize_ipv4_mapped",
+/// "timestamp_format" and "timestamp_timezone". The settings can differ between zones and sinks,
+/// so rather than being passed to every `serialize_with` function they're applied to the thread
+/// doing the serializing, for as long as it's serializing with them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Serialization {
+    pub normalize_ipv4_mapped: bool,
+    pub timestamp_format: TimestampFormat,
+    pub timestamp_timezone: Timezone,
+}
+
+thread_local! {
+    /// The settings the current thread serializes with, see `Serialization::apply`
+    static SERIALIZATION: Cell<Serialization> = Cell::new(Serialization::default());
+}
+
+impl Serialization {
+    /// Serialize with these settings on the current thread until the returned guard is dropped
+    pub fn apply(self) -> Applied {
+        Applied(SERIALIZATION.with(|current| current.replace(self)))
+    }
+
+    /// The settings the current thread serializes with, the defaults unless some were applied
+    pub fn current() -> Serialization {
+        SERIALIZATION.with(Cell::get)
+    }
+
+    fn local(self) -> bool {
+        self.timestamp_timezone == Timezone::Local
+    }
+}
+
+/// Puts back the settings a thread serialized with before `Serialization::apply`
+#[must_use]
+pub struct Applied(Serialization);
+
+impl Drop for Applied {
+    fn drop(&mut self) {
+        SERIALIZATION.with(|current| current.set(self.0));
+    }
+}
+
+fn rfc3339_in(timestamp: &DateTime<Utc>, precision: SecondsFormat, local: bool) -> String {
+    if local {
+        timestamp
+            .with_timezone(&Local)
+            .to_rfc3339_opts(precision, false)
+    } else {
+        timestamp.to_rfc3339_opts(precision, true)
+    }
+}
+
+/// A timestamp as RFC 3339 in the timezone the current thread's `Serialization` logs, for formats
+/// such as syslog's header that always want a date whatever "timestamp_format" says
+pub fn rfc3339(timestamp: &DateTime<Utc>, precision: SecondsFormat) -> String {
+    rfc3339_in(timestamp, precision, Serialization::current().local())
+}
+
+fn serialize_as<S: Serializer>(
+    timestamp: &DateTime<Utc>,
+    format: TimestampFormat,
+    local: bool,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match format {
+        TimestampFormat::Rfc3339 => {
+            serializer.collect_str(&rfc3339_in(timestamp, SecondsFormat::AutoSi, local))
+        }
+        TimestampFormat::Rfc3339Nanos => {
+            serializer.collect_str(&rfc3339_in(timestamp, SecondsFormat::Nanos, local))
+        }
+        TimestampFormat::EpochMs => serializer.serialize_i64(timestamp.timestamp_millis()),
+        TimestampFormat::EpochNs => serializer.serialize_i64(timestamp.timestamp_nanos()),
+    }
+}
+
+/// Serialize a timestamp as the current thread's `Serialization` logs it
+pub fn serialize_timestamp<S: Serializer>(
+    timestamp: &DateTime<Utc>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let serialization = Serialization::current();
+    serialize_as(
+        timestamp,
+        serialization.timestamp_format,
+        serialization.local(),
+        serializer,
+    )
+}
+
+/// Epoch timestamps at least this large are nanoseconds. In milliseconds it's the year 33658.
+const MIN_EPOCH_NS: i64 = 1_000_000_000_000_000;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyTimestamp {
+    Epoch(i64),
+    Rfc3339(DateTime<FixedOffset>),
+}
+
+/// Deserialize a timestamp logged in any `TimestampFormat` or timezone, telling epoch
+/// milliseconds and nanoseconds apart by their size
+pub fn deserialize_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<DateTime<Utc>, D::Error> {
+    let (secs, nanos) = match AnyTimestamp::deserialize(deserializer)? {
+        AnyTimestamp::Epoch(ns) if ns.abs() >= MIN_EPOCH_NS => {
+            (ns.div_euclid(NANOS_PER_SEC), ns.rem_euclid(NANOS_PER_SEC))
+        }
+        AnyTimestamp::Epoch(ms) => (ms.div_euclid(1000), ms.rem_euclid(1000) * 1_000_000),
+        AnyTimestamp::Rfc3339(timestamp) => return Ok(timestamp.with_timezone(&Utc)),
+    };
+    Utc.timestamp_opt(secs, nanos as u32)
+        .single()
+        .ok_or_else(|| serde::de::Error::custom("timestamp out of range"))
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CfwEvType {
+    Block,
+    Begin,
+    End,
+    Unknown, // Catch all (perhaps the kernel version is ahead of userland?)
+}
+
+impl From<u16> for CfwEvType {
+    fn from(val: u16) -> CfwEvType {
+        match val {
+            1 => CfwEvType::Block,
+            2 => CfwEvType::Begin,
+            3 => CfwEvType::End,
+            _ => CfwEvType::Unknown,
+        }
+    }
+}
+
+impl CfwEvType {
+    /// The name the event type is logged with
+    pub fn name(&self) -> &'static str {
+        match self {
+            CfwEvType::Block => "block",
+            CfwEvType::Begin => "begin",
+            CfwEvType::End => "end",
+            CfwEvType::Unknown => "unknown",
+        }
+    }
+}
+
+impl std::str::FromStr for CfwEvType {
+    type Err = String;
+
+    /// Parse the name an event type is logged with
+    fn from_str(s: &str) -> Result<CfwEvType, String> {
+        match s {
+            "block" => Ok(CfwEvType::Block),
+            "begin" => Ok(CfwEvType::Begin),
+            "end" => Ok(CfwEvType::End),
+            _ => Err(format!("unknown event type \"{}\"", s)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl TryFrom<u8> for Direction {
+    type Error = u8;
+
+    fn try_from(val: u8) -> Result<Direction, u8> {
+        match val {
+            1 => Ok(Direction::In),
+            2 => Ok(Direction::Out),
+            _ => Err(val),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum Protocol {
+    AH,
+    ESP,
+    ICMP,
+    ICMPV6,
+    TCP,
+    UDP,
+    UNKNOWN,
+}
+
+impl From<u8> for Protocol {
+    fn from(val: u8) -> Protocol {
+        match val {
+            1 => Protocol::ICMP,
+            6 => Protocol::TCP,
+            17 => Protocol::UDP,
+            50 => Protocol::ESP,
+            51 => Protocol::AH,
+            58 => Protocol::ICMPV6,
+            // catch all in case fwadm ever supports additional protocols
+            _ => Protocol::UNKNOWN,
+        }
+    }
+}
+
+/// The type and code of an ICMP or ICMPv6 event, which the device reports in place of the
+/// source and destination ports
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Icmp {
+    pub icmp_type: u8,
+    pub icmp_code: u8,
+}
+
+impl TrafficEvent {
+    /// Whether the event has fields past those we know, as a newer device's events may. They're
+    /// skipped when the event is parsed.
+    pub fn extended(&self) -> bool {
+        usize::from(self.length) > usize::from(HEADER_LEN) + TRAFFIC_LEN
+    }
+}
+
+impl Icmp {
+    /// The type and code of an event of `protocol` with the given ports, if it's an ICMP or ICMPv6
+    /// event. Ports that don't fit in a byte aren't a type and code.
+    pub fn from_ports(
+        protocol: &Protocol,
+        source_port: u16,
+        destination_port: u16,
+    ) -> Option<Icmp> {
+        match protocol {
+            Protocol::ICMP | Protocol::ICMPV6 => Some(Icmp {
+                icmp_type: u8::try_from(source_port).ok()?,
+                icmp_code: u8::try_from(destination_port).ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum CfwEvent {
+    Traffic(TrafficEvent),
+    Unknown(UnknownEvent),
+}
+
+impl CfwEvent {
+    pub fn zone(&self) -> u32 {
+        match &*self {
+            CfwEvent::Traffic(event) => event.zonedid,
+            CfwEvent::Unknown(event) => event.zonedid,
+        }
+    }
+
+    /// The ingest sequence number assigned by the loss audit
+    pub fn seq(&self) -> u64 {
+        match &*self {
+            CfwEvent::Traffic(event) => event.seq,
+            CfwEvent::Unknown(event) => event.seq,
+        }
+    }
+
+    pub fn set_seq(&mut self, seq: u64) {
+        match self {
+            CfwEvent::Traffic(event) => event.seq = seq,
+            CfwEvent::Unknown(event) => event.seq = seq,
+        }
+    }
+
+    /// Note when a traffic event was read, other events don't keep track
+    pub fn set_received(&mut self, received: Received) {
+        if let CfwEvent::Traffic(event) = self {
+            event.received = Some(received);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct UnknownEvent {
+    pub event: CfwEvType,
+    #[serde(skip)]
+    pub raw_event: u16,
+    #[serde(skip)]
+    pub length: u16,
+    #[serde(skip)]
+    pub zonedid: u32,
+    #[serde(skip)]
+    pub seq: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TrafficEvent {
+    pub event: CfwEvType,
+    #[serde(skip)]
+    pub length: u16,
+    #[serde(skip)]
+    pub zonedid: u32,
+    #[serde(skip)]
+    pub rule_id: u32,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub protocol: Protocol,
+    /// The type and code of ICMP and ICMPv6 events, logged as "icmp_type" and "icmp_code"
+    #[serde(flatten)]
+    pub icmp: Option<Icmp>,
+    pub direction: Direction,
+    #[serde(serialize_with = "serialize_ip")]
+    pub source_ip: Ipv6Addr,
+    #[serde(serialize_with = "serialize_ip")]
+    pub destination_ip: Ipv6Addr,
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+    #[serde(rename = "rule")]
+    pub rule_uuid: Uuid,
+    #[serde(skip)]
+    pub seq: u64,
+    /// Set by whoever read the event off the device
+    #[serde(skip)]
+    pub received: Option<Received>,
+}
+
+/// When an event was read off the device, by the wall clock and by the high resolution clock.
+/// Unlike the wall clock the high resolution clock is never stepped, so it orders events read
+/// across a clock adjustment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Received {
+    pub timestamp: DateTime<Utc>,
+    /// Nanoseconds since an arbitrary point in the past, see gethrtime(3C)
+    pub hrtime: u64,
+}
+
+type CfwEventHeader = (u16, u16, u32);
+
+/// Size of the header every event starts with
+const HEADER_LEN: u16 = 8;
+
+/// Size of the fields of a traffic event, which come after its header
+const TRAFFIC_LEN: usize = 80;
+
+/// Fail to parse the event at `bytes` because it holds a value the device never produces. Since
+/// there's no telling where the next event starts this is a `Failure` rather than an `Error`.
+fn malformed<T>(bytes: &[u8]) -> IResult<&[u8], T, VerboseError<&[u8]>> {
+    Err(nom::Err::Failure(VerboseError::from_error_kind(
+        bytes,
+        ErrorKind::Verify,
+    )))
+}
+
+/// Read the common bytes of all events, aka event type, length, and zonedid.
+fn cfwevent_parse_header<'a>(
+    bytes: &'a [u8],
+) -> IResult<&'a [u8], CfwEventHeader, VerboseError<&'a [u8]>> {
+    let (bytes, event) = le_u16(bytes)?;
+    let (bytes, length) = le_u16(bytes)?;
+    let (bytes, zonedid) = le_u32(bytes)?;
+    Ok((bytes, (event, length, zonedid)))
+}
+
+/// Parse an UnknownEvent type variant so that we can skip past the associated bytes.
+fn cfwevent_parse_unknown<'a>(
+    header: CfwEventHeader,
+    bytes: &'a [u8],
+) -> IResult<&'a [u8], CfwEvent, VerboseError<&'a [u8]>> {
+    // Take everything after the first 8 bytes (event + length + zonedid).
+    let (bytes, _skip) = take(header.1 - HEADER_LEN)(bytes)?;
+    Ok((
+        bytes,
+        CfwEvent::Unknown(UnknownEvent {
+            event: CfwEvType::Unknown,
+            raw_event: header.0,
+            length: header.1,
+            zonedid: header.2,
+            seq: 0,
+        }),
+    ))
+}
+
+/// A traffic event's field as an array of its bytes, once the event's length has been checked
+macro_rules! field {
+    ($bytes:expr, $range:expr) => {
+        $bytes[$range]
+            .try_into()
+            .expect("the event's length was checked")
+    };
+}
+
+/// Parse an TrafficEvent type variant that was generated due to a firewall rule match. The fields
+/// are parsed out of the event's length, so anything a newer device appends to them is skipped.
+/// They sit at fixed offsets, so once the length is checked they're read straight out of the read
+/// buffer rather than field by field through nom.
+fn cfwevent_parse_traffic<'a>(
+    evtype: CfwEvType,
+    header: CfwEventHeader,
+    bytes: &'a [u8],
+) -> IResult<&'a [u8], CfwEvent, VerboseError<&'a [u8]>> {
+    let event = bytes;
+    let (rest, bytes) = take(header.1 - HEADER_LEN)(bytes)?;
+    if bytes.len() < TRAFFIC_LEN {
+        return Err(nom::Err::Error(VerboseError::from_error_kind(
+            bytes,
+            ErrorKind::Eof,
+        )));
+    }
+    let rule_id = u32::from_le_bytes(field!(bytes, 0..4));
+    let source_port = u16::from_be_bytes(field!(bytes, 4..6));
+    let destination_port = u16::from_be_bytes(field!(bytes, 6..8));
+    let (protocol, direction) = (bytes[8], bytes[9]);
+    // Followed by six bytes of padding. The device doesn't report the packet's TCP flags, these are
+    // reserved and always 0, so there's nothing to decode them from.
+    let source_ip = u128::from_be_bytes(field!(bytes, 16..32));
+    let destination_ip = u128::from_be_bytes(field!(bytes, 32..48));
+    let time_sec = i64::from_le_bytes(field!(bytes, 48..56));
+    let time_usec = i64::from_le_bytes(field!(bytes, 56..64));
+    let rule_uuid = Uuid::from_bytes(field!(bytes, 64..80));
+    let direction = match Direction::try_from(direction) {
+        Ok(direction) => direction,
+        Err(_) => return malformed(event),
+    };
+    let timestamp = match time_usec {
+        0..=999_999 => Utc
+            .timestamp_opt(time_sec, time_usec as u32 * 1000)
+            .single(),
+        _ => None,
+    };
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return malformed(event),
+    };
+    let protocol = Protocol::from(protocol);
+    Ok((
+        rest,
+        CfwEvent::Traffic(TrafficEvent {
+            event: evtype,
+            length: header.1,
+            zonedid: header.2,
+            rule_id,
+            icmp: Icmp::from_ports(&protocol, source_port, destination_port),
+            protocol,
+            direction,
+            source_port,
+            destination_port,
+            source_ip: Ipv6Addr::from(source_ip),
+            destination_ip: Ipv6Addr::from(destination_ip),
+            timestamp,
+            rule_uuid,
+            seq: 0,
+            received: None,
+        }),
+    ))
+}
+
+/// Parse a single CfwEvent out of the provided bytes returning a slice that points at the next
+/// event. Malformed and truncated events are an error rather than a panic, and the returned slice
+/// is always shorter than `bytes`.
+pub fn cfwevent_parse<'a>(bytes: &'a [u8]) -> IResult<&'a [u8], CfwEvent, VerboseError<&'a [u8]>> {
+    let event = bytes;
+    let (bytes, header) = cfwevent_parse_header(bytes)?;
+    if header.1 < HEADER_LEN {
+        return malformed(event);
+    }
+    let event_type = CfwEvType::from(header.0);
+    match event_type {
+        CfwEvType::Unknown => cfwevent_parse_unknown(header, bytes),
+        _ => cfwevent_parse_traffic(event_type, header, bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A timestamp as it's logged in a given format
+    struct Logged<'a>(&'a DateTime<Utc>, TimestampFormat, bool);
+
+    impl Serialize for Logged<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize_as(self.0, self.1, self.2, serializer)
+        }
+    }
+
+    #[test]
+    fn logged_timestamps() {
+        let ts = Utc.ymd(2020, 5, 12).and_hms_micro(19, 0, 0, 123_456);
+        let logged = |format, local| serde_json::to_value(Logged(&ts, format, local)).unwrap();
+        assert_eq!(
+            logged(TimestampFormat::Rfc3339, false),
+            "2020-05-12T19:00:00.123456Z"
+        );
+        assert_eq!(
+            logged(TimestampFormat::Rfc3339Nanos, false),
+            "2020-05-12T19:00:00.123456000Z"
+        );
+        assert_eq!(
+            logged(TimestampFormat::EpochMs, false),
+            1_589_310_000_123i64
+        );
+        assert_eq!(
+            logged(TimestampFormat::EpochNs, true),
+            1_589_310_000_123_456_000i64,
+            "epoch timestamps have no timezone"
+        );
+        for format in &[
+            TimestampFormat::Rfc3339,
+            TimestampFormat::Rfc3339Nanos,
+            TimestampFormat::EpochNs,
+        ] {
+            assert_eq!(
+                deserialize_timestamp(logged(*format, true)).unwrap(),
+                ts,
+                "{:?} reads back",
+                format
+            );
+        }
+        let ms = deserialize_timestamp(logged(TimestampFormat::EpochMs, false)).unwrap();
+        assert_eq!(ms, Utc.ymd(2020, 5, 12).and_hms_milli(19, 0, 0, 123));
+    }
+
+    #[test]
+    fn applied_serialization() {
+        #[derive(Serialize)]
+        struct Logged {
+            #[serde(serialize_with = "serialize_ip")]
+            addr: Ipv6Addr,
+            #[serde(serialize_with = "serialize_timestamp")]
+            timestamp: DateTime<Utc>,
+        }
+        let logged = || {
+            serde_json::to_value(Logged {
+                addr: "::ffff:172.24.4.150".parse().unwrap(),
+                timestamp: Utc.ymd(2020, 5, 12).and_hms(19, 0, 0),
+            })
+            .unwrap()
+        };
+        let default = serde_json::json!({
+            "addr": "::ffff:172.24.4.150",
+            "timestamp": "2020-05-12T19:00:00Z",
+        });
+        let epoch = serde_json::json!({
+            "addr": "172.24.4.150",
+            "timestamp": 1_589_310_000_000i64,
+        });
+
+        assert_eq!(logged(), default);
+        let applied = Serialization {
+            normalize_ipv4_mapped: true,
+            timestamp_format: TimestampFormat::EpochMs,
+            ..Serialization::default()
+        }
+        .apply();
+        assert_eq!(logged(), epoch);
+        let nested = Serialization::default().apply();
+        assert_eq!(logged(), default);
+        drop(nested);
+        assert_eq!(logged(), epoch, "the outer settings are put back");
+        drop(applied);
+        assert_eq!(logged(), default);
+    }
+
+    #[test]
+    fn logged_addresses() {
+        let mapped: Ipv6Addr = "::ffff:172.24.4.150".parse().unwrap();
+        assert_eq!(logged_ip(&mapped, false), IpAddr::V6(mapped));
+        assert_eq!(
+            logged_ip(&mapped, true),
+            "172.24.4.150".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(ipv4_mapped(&mapped), Some(Ipv4Addr::new(172, 24, 4, 150)));
+        for addr in &[
+            "::1",
+            "::172.24.4.150",
+            "fd00::ffff:1:2",
+            "fe80::1",
+            "::ffff:0:1:2",
+        ] {
+            let addr: Ipv6Addr = addr.parse().unwrap();
+            assert_eq!(ipv4_mapped(&addr), None);
+            assert_eq!(
+                logged_ip(&addr, true),
+                IpAddr::V6(addr),
+                "{} isn't IPv4-mapped",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn parse_traffic_event() {
+        let mut event = testutils::generate_event();
+        std::dbg!(&event);
+        let now = chrono::offset::Utc::now();
+        let ts = Utc.timestamp(now.timestamp(), now.timestamp_subsec_micros() * 1000);
+        let uuid = Uuid::parse_str("a7963143-14da-48d6-bef0-422f305d1556").unwrap();
+
+        // customize some values in the generated event
+        event.time_sec = ts.timestamp();
+        event.time_usec = ts.timestamp_subsec_micros() as i64;
+        event.rule_uuid = uuid.as_bytes().clone();
+
+        let bytes = event.as_bytes();
+        let cfw_event = cfwevent_parse(bytes);
+
+        // parsed successfully
+        assert!(cfw_event.is_ok());
+        let cfw_event = cfw_event.unwrap();
+
+        assert!(cfw_event.0.is_empty(), "no bytes left over");
+        match cfw_event.1 {
+            CfwEvent::Traffic(e) => {
+                assert_eq!(e.event, CfwEvType::from(event.event));
+                assert_eq!(e.length, event.length);
+                assert_eq!(e.zonedid, event.zonedid);
+                assert_eq!(e.rule_id, event.rule_id);
+                assert_eq!(e.protocol, Protocol::from(event.protocol));
+                assert_eq!(e.direction, Direction::try_from(event.direction).unwrap());
+                assert_eq!(e.source_port, u16::from_be(event.source_port));
+                assert_eq!(e.destination_port, u16::from_be(event.destination_port));
+                assert_eq!(e.source_ip, Ipv6Addr::from(u128::from_be(event.source_ip)));
+                assert_eq!(
+                    e.destination_ip,
+                    Ipv6Addr::from(u128::from_be(event.destination_ip))
+                );
+                assert_eq!(e.timestamp, ts);
+                assert_eq!(e.rule_uuid, uuid);
+            }
+            _ => panic!("unexpected CfwEvType"),
+        }
+    }
+
+    #[test]
+    fn parse_icmp_event() {
+        let mut event = testutils::generate_event();
+        event.protocol = 58;
+        event.source_port = 128u16.to_be();
+        event.destination_port = 0;
+        match cfwevent_parse(event.as_bytes()).unwrap().1 {
+            CfwEvent::Traffic(e) => {
+                assert_eq!(
+                    e.icmp,
+                    Some(Icmp {
+                        icmp_type: 128,
+                        icmp_code: 0
+                    })
+                );
+                let json = serde_json::to_value(&e).unwrap();
+                assert_eq!(json["icmp_type"], 128);
+                assert_eq!(json["icmp_code"], 0);
+            }
+            _ => panic!("unexpected CfwEvType"),
+        }
+
+        assert_eq!(Icmp::from_ports(&Protocol::TCP, 8, 0), None);
+        assert_eq!(Icmp::from_ports(&Protocol::ICMP, 256, 0), None);
+        let json = serde_json::to_value(
+            match cfwevent_parse(testutils::generate_event().as_bytes()) {
+                Ok((_, CfwEvent::Traffic(e))) => e,
+                other => panic!("unexpected event {:?}", other),
+            },
+        )
+        .unwrap();
+        assert!(
+            json.get("icmp_type").is_none(),
+            "only ICMP events have a type"
+        );
+    }
+
+    #[test]
+    fn parse_unknown_event() {
+        let event = testutils::generate_unknown_event();
+        let bytes = event.as_bytes();
+        let cfw_event = cfwevent_parse(bytes);
+
+        // parsed successfully
+        assert!(cfw_event.is_ok());
+        let cfw_event = cfw_event.unwrap();
+
+        assert!(cfw_event.0.is_empty(), "no bytes left over");
+        match cfw_event.1 {
+            CfwEvent::Unknown(e) => {
+                assert_eq!(e.event, CfwEvType::from(event.event));
+                assert_eq!(e.length, event.length);
+                assert_eq!(e.zonedid, event.zonedid);
+            }
+            _ => panic!("unexpected CfwEvType"),
+        }
+    }
+
+    #[test]
+    fn parse_extended_event() {
+        let mut event = testutils::generate_event();
+        event.length += 16;
+        let mut bytes = event.as_bytes().to_vec();
+        bytes.extend_from_slice(&[0xab; 16]);
+        bytes.extend_from_slice(testutils::generate_event().as_bytes());
+
+        let (rest, extended) = cfwevent_parse(&bytes).expect("failed to parse extended event");
+        match extended {
+            CfwEvent::Traffic(e) => {
+                assert!(e.extended());
+                assert_eq!(e.zonedid, event.zonedid);
+            }
+            _ => panic!("unexpected CfwEvType"),
+        }
+        match cfwevent_parse(rest).expect("failed to parse the next event") {
+            (rest, CfwEvent::Traffic(e)) => {
+                assert!(!e.extended());
+                assert!(rest.is_empty(), "the unknown fields were skipped");
+            }
+            _ => panic!("unexpected CfwEvType"),
+        }
+    }
+
+    #[test]
+    fn parse_mixed_events() {
+        let event = testutils::generate_event();
+        let tbytes = event.as_bytes();
+
+        let uevent = testutils::generate_unknown_event();
+        let ubytes = uevent.as_bytes();
+
+        let mut mixed = vec![];
+        mixed.extend_from_slice(&tbytes);
+        mixed.extend_from_slice(&ubytes);
+        mixed.extend_from_slice(&ubytes);
+        mixed.extend_from_slice(&tbytes);
+        mixed.extend_from_slice(&tbytes);
+
+        let mut count = 0;
+        let mut traffic_count = 0;
+        let mut unknown_count = 0;
+        let mut bytes: &[u8] = &mixed;
+        loop {
+            let (leftover, event) = cfwevent_parse(bytes).expect("failed to parse CfwEvents");
+            match event {
+                CfwEvent::Traffic(_) => traffic_count += 1,
+                CfwEvent::Unknown(_) => unknown_count += 1,
+            };
+            bytes = leftover;
+            count += 1;
+            if bytes.is_empty() {
+                break;
+            };
+        }
+
+        assert_eq!(5, count, "saw exactly 5 events");
+        assert_eq!(3, traffic_count, "saw exactly 3 traffic events");
+        assert_eq!(2, unknown_count, "saw exactly 2 unknown events");
+    }
+
+    #[test]
+    #[should_panic]
+    fn garbage_data_fails_to_parse() {
+        // Try to pass in 100 bytes of random garbage to the parser
+        let random_bytes: Vec<u8> = std::iter::repeat_with(|| rand::random::<u8>())
+            .take(100)
+            .collect();
+        let (_leftover, _event) = cfwevent_parse(&random_bytes).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn not_enough_bytes_fails_to_parse() {
+        let mut short = testutils::generate_unknown_event();
+        // Today the "sizeof (cfwev_t)" = 88 bytes, so pick something decently bigger
+        short.length = 1000;
+        let (_leftover, _event) = cfwevent_parse(short.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn malformed_events_fail_to_parse() {
+        let mut unknown = testutils::generate_unknown_event();
+        unknown.length = 4;
+        assert!(
+            cfwevent_parse(unknown.as_bytes()).is_err(),
+            "shorter than its header"
+        );
+
+        let mut traffic = testutils::generate_event();
+        traffic.length = 40;
+        assert!(
+            cfwevent_parse(traffic.as_bytes()).is_err(),
+            "shorter than its fields"
+        );
+
+        let mut traffic = testutils::generate_event();
+        traffic.direction = 7;
+        assert!(
+            cfwevent_parse(traffic.as_bytes()).is_err(),
+            "unknown direction"
+        );
+
+        let mut traffic = testutils::generate_event();
+        traffic.time_usec = 1_000_000;
+        assert!(
+            cfwevent_parse(traffic.as_bytes()).is_err(),
+            "out of range timestamp"
+        );
+
+        let traffic = testutils::generate_event();
+        let bytes = traffic.as_bytes();
+        assert!(
+            cfwevent_parse(&bytes[..bytes.len() - 1]).is_err(),
+            "truncated"
+        );
+    }
+
+    /// Parsing has to keep up with a device delivering well over 100k events a second. This is
+    /// only meaningful in a release build, so it's run on its own with
+    /// `cargo test --release parse_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn parse_throughput() {
+        const EVENTS: usize = 1_000_000;
+        let event = testutils::generate_event();
+        let mut buf = Vec::with_capacity(EVENTS * event.as_bytes().len());
+        for _ in 0..EVENTS {
+            buf.extend_from_slice(event.as_bytes());
+        }
+        let start = std::time::Instant::now();
+        let mut bytes: &[u8] = &buf;
+        let mut parsed = 0;
+        while !bytes.is_empty() {
+            let (rest, _event) = cfwevent_parse(bytes).expect("failed to parse event");
+            bytes = rest;
+            parsed += 1;
+        }
+        let rate = parsed as f64 / start.elapsed().as_secs_f64();
+        println!("parsed {} events at {:.0} events/s", parsed, rate);
+        assert_eq!(parsed, EVENTS);
+        assert!(
+            rate >= 100_000.0,
+            "parsing fell behind at {:.0} events/s",
+            rate
+        );
+    }
+}
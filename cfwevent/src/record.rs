@@ -0,0 +1,337 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The records of a zone's log. cfwlogd writes one record per line, each a json object with an
+//! "event" field saying what kind of record it is. Traffic records, the ones written for a
+//! `CfwEvent`, are read back as a `TrafficRecord`, and everything else cfwlogd logs, such as
+//! lifecycle records and suppression summaries, is left as its fields.
+//!
+//! Addresses are read back as a `LoggedIp`, which is either family along with the zone of a
+//! scoped IPv6 address. cfwlogd logs IPv4-mapped addresses either way depending on its
+//! "normalize_ipv4_mapped" setting, so `LoggedIp::unmapped` is what to compare them by.
+
+use crate::parser::{self, CfwEvType, Direction, Protocol};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// The version of the records cfwlogd writes. Bump this whenever a field is added, renamed or
+/// changes meaning, and teach cfwlogd's "migrate" module how to upgrade the previous version.
+pub const SCHEMA_VERSION: u64 = 2;
+
+/// Serializes as the current `SCHEMA_VERSION`, so every record is stamped with the version it
+/// was written with
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SchemaVersion;
+
+impl Serialize for SchemaVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(SCHEMA_VERSION)
+    }
+}
+
+/// An address as it was logged. A scoped IPv6 address, such as the link-local "fe80::1%net0",
+/// keeps its RFC 4007 zone. cfwlogd doesn't know the zones of the addresses it logs, but tools
+/// writing records of their own might.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoggedIp {
+    pub addr: IpAddr,
+    pub zone: Option<String>,
+}
+
+impl LoggedIp {
+    /// The address with an IPv4-mapped address as the IPv4 address it maps to
+    pub fn unmapped(&self) -> IpAddr {
+        match self.addr {
+            IpAddr::V6(v6) => parser::ipv4_mapped(&v6).map_or(self.addr, IpAddr::V4),
+            IpAddr::V4(_) => self.addr,
+        }
+    }
+
+    /// The address along with a port, bracketed if it's IPv6, such as "[fe80::1%net0]:22"
+    pub fn with_port(&self, port: u16) -> String {
+        match self.addr {
+            IpAddr::V4(_) => format!("{}:{}", self, port),
+            IpAddr::V6(_) => format!("[{}]:{}", self, port),
+        }
+    }
+}
+
+impl From<IpAddr> for LoggedIp {
+    fn from(addr: IpAddr) -> Self {
+        LoggedIp { addr, zone: None }
+    }
+}
+
+impl FromStr for LoggedIp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (addr, zone) = match s.find('%') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let invalid = || format!("invalid address \"{}\"", s);
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        match zone {
+            Some("") => Err(invalid()),
+            Some(_) if addr.is_ipv4() => Err(invalid()),
+            _ => Ok(LoggedIp {
+                addr,
+                zone: zone.map(str::to_owned),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for LoggedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.zone {
+            Some(zone) => write!(f, "{}%{}", self.addr, zone),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+impl Serialize for LoggedIp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for LoggedIp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A firewall rule match as it was logged for a zone
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct TrafficRecord {
+    /// Records written before the field existed are version 0
+    #[serde(default)]
+    pub schema_version: u64,
+    pub event: CfwEvType,
+    pub source_port: u16,
+    pub destination_port: u16,
+    pub protocol: Protocol,
+    /// Only ICMP and ICMPv6 records have a type and code, which version 2 added. Their ports are
+    /// the same numbers.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icmp_type: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icmp_code: Option<u8>,
+    pub direction: Direction,
+    pub source_ip: LoggedIp,
+    pub destination_ip: LoggedIp,
+    /// In whichever format and timezone cfwlogd's "timestamp_format" and "timestamp_timezone"
+    /// logged it
+    #[serde(
+        serialize_with = "parser::serialize_timestamp",
+        deserialize_with = "parser::deserialize_timestamp"
+    )]
+    pub timestamp: DateTime<Utc>,
+    pub rule: Uuid,
+    pub vm: String,
+    pub alias: String,
+    /// Fields only some configurations log, such as "rule_owner", "epoch_ms" or "server_uuid"
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// A single line of a zone's log
+#[derive(Clone, Debug, PartialEq)]
+pub enum LogRecord {
+    Traffic(TrafficRecord),
+    /// Any other record, with its "event" field
+    Other {
+        event: String,
+        fields: Map<String, Value>,
+    },
+}
+
+impl LogRecord {
+    /// The record's "event" field
+    pub fn event(&self) -> &str {
+        match self {
+            LogRecord::Traffic(record) => record.event.name(),
+            LogRecord::Other { event, .. } => event,
+        }
+    }
+}
+
+/// Parse a line of a zone's log
+pub fn parse_record(line: &str) -> serde_json::Result<LogRecord> {
+    let fields: Map<String, Value> = serde_json::from_str(line)?;
+    let event = match fields.get("event") {
+        Some(Value::String(event)) => event.clone(),
+        _ => return Err(serde::de::Error::missing_field("event")),
+    };
+    match event.parse::<CfwEvType>() {
+        Ok(_) => Ok(LogRecord::Traffic(serde_json::from_value(Value::Object(
+            fields,
+        ))?)),
+        Err(_) => Ok(LogRecord::Other { event, fields }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn traffic_records() {
+        let line = concat!(
+            r#"{"schema_version":1,"event":"block","source_port":50000,"destination_port":22,"#,
+            r#""protocol":"TCP","direction":"in","source_ip":"172.24.4.150","#,
+            r#""destination_ip":"fd00::1","timestamp":"2020-05-12T19:00:00.123456Z","#,
+            r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+            r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":"web0","#,
+            r#""epoch_ms":1589310000123}"#
+        );
+        let record = match parse_record(line).unwrap() {
+            LogRecord::Traffic(record) => record,
+            other => panic!("not a traffic record: {:?}", other),
+        };
+        assert_eq!(record.event, CfwEvType::Block);
+        assert_eq!(record.protocol, Protocol::TCP);
+        assert_eq!(record.direction, Direction::In);
+        assert_eq!(
+            record.source_ip,
+            LoggedIp::from("172.24.4.150".parse::<IpAddr>().unwrap())
+        );
+        assert_eq!(record.destination_ip.with_port(22), "[fd00::1]:22");
+        assert_eq!(record.alias, "web0");
+        assert_eq!(record.extra["epoch_ms"], 1589310000123u64);
+        assert_eq!(
+            serde_json::from_str::<Value>(&serde_json::to_string(&record).unwrap()).unwrap(),
+            serde_json::from_str::<Value>(line).unwrap(),
+            "records are written back as they were read"
+        );
+
+        let old = concat!(
+            r#"{"event":"end","source_port":1,"destination_port":2,"protocol":"UDP","#,
+            r#""direction":"out","source_ip":"::1","destination_ip":"::2","#,
+            r#""timestamp":"2019-10-01T00:00:00Z","#,
+            r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+            r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":""}"#
+        );
+        match parse_record(old).unwrap() {
+            LogRecord::Traffic(record) => assert_eq!(record.schema_version, 0),
+            other => panic!("not a traffic record: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        let line = concat!(
+            r#"{"schema_version":1,"event":"begin","source_port":546,"destination_port":547,"#,
+            r#""protocol":"UDP","direction":"out","source_ip":"fe80::8:20ff:fe1b:4a3c%net0","#,
+            r#""destination_ip":"::ffff:172.24.4.151","timestamp":"2020-05-12T19:00:00Z","#,
+            r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+            r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":""}"#
+        );
+        let record = match parse_record(line).unwrap() {
+            LogRecord::Traffic(record) => record,
+            other => panic!("not a traffic record: {:?}", other),
+        };
+        assert_eq!(record.source_ip.zone.as_deref(), Some("net0"));
+        assert_eq!(
+            record.source_ip.with_port(546),
+            "[fe80::8:20ff:fe1b:4a3c%net0]:546"
+        );
+        assert_eq!(
+            record.destination_ip.unmapped(),
+            "172.24.4.151".parse::<IpAddr>().unwrap(),
+            "mapped addresses compare as IPv4"
+        );
+        assert_eq!(record.destination_ip.to_string(), "::ffff:172.24.4.151");
+        assert_eq!(
+            serde_json::from_str::<Value>(&serde_json::to_string(&record).unwrap()).unwrap(),
+            serde_json::from_str::<Value>(line).unwrap(),
+            "zones are written back"
+        );
+
+        for ip in &[
+            "172.24.4.150",
+            "::ffff:172.24.4.150",
+            "fd00::1",
+            "fe80::1%2",
+        ] {
+            assert_eq!(ip.parse::<LoggedIp>().unwrap().to_string(), *ip);
+        }
+        for ip in &["", "fe80::1%", "172.24.4.150%net0", "net0"] {
+            assert!(ip.parse::<LoggedIp>().is_err(), "{:?}", ip);
+        }
+    }
+
+    #[test]
+    fn parsed_addresses_are_read_back() {
+        let mut event = testutils::generate_event();
+        event.source_ip = u128::from("fd00::1:2".parse::<std::net::Ipv6Addr>().unwrap()).to_be();
+        let event = match parser::cfwevent_parse(event.as_bytes()) {
+            Ok((_, parser::CfwEvent::Traffic(event))) => event,
+            other => panic!("unexpected event {:?}", other),
+        };
+        let mut fields = match serde_json::to_value(&event).unwrap() {
+            Value::Object(fields) => fields,
+            other => panic!("not an object: {:?}", other),
+        };
+        fields.insert("vm".to_owned(), "x".into());
+        fields.insert("alias".to_owned(), "".into());
+        let record: TrafficRecord = serde_json::from_value(Value::Object(fields)).unwrap();
+        assert_eq!(record.source_ip.to_string(), "fd00::1:2");
+        assert_eq!(
+            record.destination_ip.unmapped(),
+            "172.24.4.151".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn icmp_records() {
+        let line = concat!(
+            r#"{"schema_version":2,"event":"block","source_port":8,"destination_port":0,"#,
+            r#""protocol":"ICMP","icmp_type":8,"icmp_code":0,"direction":"in","#,
+            r#""source_ip":"172.24.4.150","destination_ip":"172.24.4.151","#,
+            r#""timestamp":"2020-05-12T19:00:00Z","rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+            r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":""}"#
+        );
+        let record = match parse_record(line).unwrap() {
+            LogRecord::Traffic(record) => record,
+            other => panic!("not a traffic record: {:?}", other),
+        };
+        assert_eq!((record.icmp_type, record.icmp_code), (Some(8), Some(0)));
+        assert!(
+            record.extra.is_empty(),
+            "the type and code aren't extra fields"
+        );
+    }
+
+    #[test]
+    fn other_records() {
+        let line =
+            r#"{"schema_version":1,"event":"events_lost","vm":"x","lost":3,"timestamp":"t"}"#;
+        let record = parse_record(line).unwrap();
+        assert_eq!(record.event(), "events_lost");
+        match record {
+            LogRecord::Other { fields, .. } => assert_eq!(fields["lost"], 3),
+            other => panic!("not an other record: {:?}", other),
+        }
+
+        assert!(parse_record(r#"{"vm":"x"}"#).is_err(), "no event");
+        assert!(parse_record("garbage").is_err());
+        assert!(
+            parse_record(r#"{"event":"block","vm":"x"}"#).is_err(),
+            "a traffic record missing its fields"
+        );
+    }
+}
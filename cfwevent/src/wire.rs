@@ -0,0 +1,61 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Encoding traffic events in the ipfev wire format, the way the device hands them back from a
+//! read. cfwlogd's own event sources use this to look like the device, and tools such as
+//! cfwstress to produce events for it to read.
+
+use chrono::{DateTime, Utc};
+use std::net::Ipv6Addr;
+use uuid::Uuid;
+
+/// Size of a traffic event in the ipfev wire format, see `parser::cfwevent_parse`
+pub const TRAFFIC_EVENT_SIZE: usize = 88;
+
+/// The cloud firewall rule information that ipfev gets from the rule that matched a packet
+#[derive(Debug, PartialEq)]
+pub struct RuleInfo {
+    /// One of the `CfwEvType` values
+    pub event: u16,
+    pub zonedid: u32,
+    pub rule_id: u32,
+    pub rule_uuid: Uuid,
+}
+
+/// The parts of an IP packet that make it into a cfw event
+#[derive(Debug, PartialEq)]
+pub struct Packet {
+    pub protocol: u8,
+    pub source_ip: Ipv6Addr,
+    pub destination_ip: Ipv6Addr,
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+/// Encode an event into `buf` using the ipfev wire format
+pub fn encode_event(
+    rule: &RuleInfo,
+    packet: &Packet,
+    direction: u8,
+    timestamp: DateTime<Utc>,
+    buf: &mut [u8],
+) {
+    let buf = &mut buf[..TRAFFIC_EVENT_SIZE];
+    buf[0..2].copy_from_slice(&rule.event.to_le_bytes());
+    buf[2..4].copy_from_slice(&(TRAFFIC_EVENT_SIZE as u16).to_le_bytes());
+    buf[4..8].copy_from_slice(&rule.zonedid.to_le_bytes());
+    buf[8..12].copy_from_slice(&rule.rule_id.to_le_bytes());
+    buf[12..14].copy_from_slice(&packet.source_port.to_be_bytes());
+    buf[14..16].copy_from_slice(&packet.destination_port.to_be_bytes());
+    buf[16] = packet.protocol;
+    buf[17] = direction;
+    buf[18..24].copy_from_slice(&[0; 6]);
+    buf[24..40].copy_from_slice(&packet.source_ip.octets());
+    buf[40..56].copy_from_slice(&packet.destination_ip.octets());
+    buf[56..64].copy_from_slice(&timestamp.timestamp().to_le_bytes());
+    buf[64..72].copy_from_slice(&i64::from(timestamp.timestamp_subsec_micros()).to_le_bytes());
+    buf[72..88].copy_from_slice(rule.rule_uuid.as_bytes());
+}
@@ -0,0 +1,226 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Which records get printed, from the command line's filter flags. Every flag given has to match
+//! for a record to be printed.
+
+use cfwevent::parser::{Direction, Protocol};
+use cfwevent::record::{LogRecord, LoggedIp, TrafficRecord};
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Filter {
+    pub rule: Option<Uuid>,
+    pub protocol: Option<Protocol>,
+    /// Matches either end of the connection, see `matches_addr`
+    pub addr: Option<LoggedIp>,
+    /// Matches either end of the connection
+    pub port: Option<u16>,
+    pub direction: Option<Direction>,
+    /// Records from this time on
+    pub since: Option<DateTime<Utc>>,
+    /// Records from before this time
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Filter {
+    /// Whether no filter flags were given apart from the time range, in which case every record in
+    /// the range is printed
+    pub fn is_empty(&self) -> bool {
+        *self
+            == Filter {
+                since: self.since,
+                until: self.until,
+                ..Filter::default()
+            }
+    }
+
+    /// Whether the record is printed. Records other than traffic records, such as lifecycle
+    /// records, have none of the fields filtered on so they're only printed when nothing but the
+    /// time range is.
+    pub fn matches(&self, record: &LogRecord) -> bool {
+        match record {
+            LogRecord::Traffic(record) => {
+                self.in_range(Some(record.timestamp)) && self.matches_traffic(record)
+            }
+            LogRecord::Other { fields, .. } => {
+                let timestamp = fields
+                    .get("timestamp")
+                    .and_then(|timestamp| timestamp.as_str())
+                    .and_then(|timestamp| timestamp.parse().ok());
+                self.in_range(timestamp) && self.is_empty()
+            }
+        }
+    }
+
+    /// Whether a record logged at `timestamp` is in the time range. Records without a timestamp
+    /// are only in it when there is no range.
+    fn in_range(&self, timestamp: Option<DateTime<Utc>>) -> bool {
+        match timestamp {
+            Some(timestamp) => {
+                self.since.map_or(true, |since| timestamp >= since)
+                    && self.until.map_or(true, |until| timestamp < until)
+            }
+            None => self.since.is_none() && self.until.is_none(),
+        }
+    }
+
+    fn matches_traffic(&self, record: &TrafficRecord) -> bool {
+        fn matches<T: PartialEq>(filter: &Option<T>, value: &T) -> bool {
+            filter.as_ref().map_or(true, |filter| filter == value)
+        }
+
+        matches(&self.rule, &record.rule)
+            && matches(&self.protocol, &record.protocol)
+            && matches(&self.direction, &record.direction)
+            && self.port.map_or(true, |port| {
+                port == record.source_port || port == record.destination_port
+            })
+            && self.addr.as_ref().map_or(true, |addr| {
+                matches_addr(addr, &record.source_ip) || matches_addr(addr, &record.destination_ip)
+            })
+    }
+}
+
+/// Whether a logged address is the one filtered on. IPv4-mapped addresses match their IPv4
+/// address, and an address without a zone matches the address in any zone.
+fn matches_addr(filter: &LoggedIp, logged: &LoggedIp) -> bool {
+    filter.unmapped() == logged.unmapped()
+        && filter
+            .zone
+            .as_ref()
+            .map_or(true, |zone| logged.zone.as_ref() == Some(zone))
+}
+
+/// Parse a protocol the way it's given on the command line, such as "tcp"
+pub fn parse_protocol(s: &str) -> Result<Protocol, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "ah" => Ok(Protocol::AH),
+        "esp" => Ok(Protocol::ESP),
+        "icmp" => Ok(Protocol::ICMP),
+        "icmpv6" => Ok(Protocol::ICMPV6),
+        "tcp" => Ok(Protocol::TCP),
+        "udp" => Ok(Protocol::UDP),
+        _ => Err(format!("unknown protocol \"{}\"", s)),
+    }
+}
+
+/// Parse a time the way it's given on the command line, such as "2020-05-12T19:00:00Z"
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(s)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("invalid time \"{}\": {}", s, e))
+}
+
+/// Parse a direction the way it's given on the command line, "in" or "out"
+pub fn parse_direction(s: &str) -> Result<Direction, String> {
+    match s {
+        "in" => Ok(Direction::In),
+        "out" => Ok(Direction::Out),
+        _ => Err(format!("unknown direction \"{}\"", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::record;
+
+    const TRAFFIC: &str = concat!(
+        r#"{"schema_version":1,"event":"block","source_port":50000,"destination_port":22,"#,
+        r#""protocol":"TCP","direction":"in","source_ip":"::ffff:172.24.4.150","#,
+        r#""destination_ip":"172.24.4.151","timestamp":"2020-05-12T19:00:00.123456Z","#,
+        r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+        r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":"web0"}"#
+    );
+
+    const LIFECYCLE: &str = concat!(
+        r#"{"schema_version":1,"event":"lifecycle","vm":"x","action":"start","#,
+        r#""version":"0.1.0","timestamp":"2020-05-12T19:00:00Z"}"#
+    );
+
+    #[test]
+    fn addresses() {
+        let addr = |s: &str| s.parse::<LoggedIp>().unwrap();
+        assert!(matches_addr(
+            &addr("172.24.4.150"),
+            &addr("::ffff:172.24.4.150")
+        ));
+        assert!(matches_addr(
+            &addr("::ffff:172.24.4.150"),
+            &addr("172.24.4.150")
+        ));
+        assert!(matches_addr(&addr("fe80::1"), &addr("fe80::1%net0")));
+        assert!(matches_addr(&addr("fe80::1%net0"), &addr("fe80::1%net0")));
+        assert!(!matches_addr(&addr("fe80::1%net0"), &addr("fe80::1%net1")));
+        assert!(!matches_addr(&addr("fe80::1%net0"), &addr("fe80::1")));
+        assert!(!matches_addr(
+            &addr("::172.24.4.150"),
+            &addr("172.24.4.150")
+        ));
+    }
+
+    #[test]
+    fn filtering() {
+        let traffic = record::parse_record(TRAFFIC).unwrap();
+        let lifecycle = record::parse_record(LIFECYCLE).unwrap();
+
+        let filter = Filter::default();
+        assert!(filter.matches(&traffic));
+        assert!(filter.matches(&lifecycle), "nothing filtered on");
+
+        let filter = Filter {
+            protocol: Some(parse_protocol("tcp").unwrap()),
+            port: Some(22),
+            direction: Some(parse_direction("in").unwrap()),
+            addr: Some("172.24.4.150".parse().unwrap()),
+            rule: Some("5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f".parse().unwrap()),
+            since: Some(parse_time("2020-05-12T19:00:00Z").unwrap()),
+            until: Some(parse_time("2020-05-12T21:00:00+01:00").unwrap()),
+        };
+        assert!(filter.matches(&traffic), "mapped addresses match");
+        assert!(!filter.matches(&lifecycle));
+
+        for filter in &[
+            Filter {
+                protocol: Some(Protocol::UDP),
+                ..Filter::default()
+            },
+            Filter {
+                port: Some(80),
+                ..Filter::default()
+            },
+            Filter {
+                direction: Some(Direction::Out),
+                ..Filter::default()
+            },
+            Filter {
+                addr: Some("::ffff:172.24.4.152".parse().unwrap()),
+                ..Filter::default()
+            },
+            Filter {
+                rule: Some(Uuid::nil()),
+                ..Filter::default()
+            },
+            Filter {
+                until: Some(parse_time("2020-05-12T19:00:00Z").unwrap()),
+                ..Filter::default()
+            },
+        ] {
+            assert!(!filter.matches(&traffic), "{:?}", filter);
+        }
+
+        let range = Filter {
+            since: Some(parse_time("2020-05-12T18:00:00Z").unwrap()),
+            ..Filter::default()
+        };
+        assert!(range.matches(&lifecycle), "only a time range");
+        assert!(parse_time("yesterday").is_err());
+        assert!(parse_protocol("sctp").is_err());
+        assert!(parse_direction("both").is_err());
+    }
+}
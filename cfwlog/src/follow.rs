@@ -0,0 +1,86 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Following a zone's log as cfwlogd writes to it, like `tail -F`. Logs are rotated by renaming
+//! current.log and having cfwlogd open a new one, so once the path refers to a different file the
+//! old one is read to its end and the new one is followed from its start.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait for more to be written once the end of the file has been reached
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Call `f` with every line written to `path` from now on, until either reading or `f` fails
+pub fn follow<F: FnMut(&str) -> io::Result<()>>(path: &Path, mut f: F) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::End(0))?;
+    let mut reader = BufReader::new(file);
+    let mut partial = String::new();
+    loop {
+        read_lines(&mut reader, &mut partial, &mut f)?;
+        thread::sleep(POLL_INTERVAL);
+        let opened = reader.get_ref().metadata()?;
+        // Everything buffered has been read, so this is where the next line starts
+        let position = reader.get_mut().seek(SeekFrom::Current(0))?;
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.ino() != opened.ino() || meta.dev() != opened.dev() => {
+                read_lines(&mut reader, &mut partial, &mut f)?;
+                partial.clear();
+                reader = BufReader::new(File::open(path)?);
+            }
+            Ok(meta) if meta.len() < position => {
+                // Truncated, so start over
+                partial.clear();
+                reader.seek(SeekFrom::Start(0))?;
+            }
+            Ok(_) => (),
+            // Renamed away, and cfwlogd hasn't opened the new file yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Call `f` with every complete line up to the end of the file. A line that is only partly
+/// written is kept in `partial` until the rest of it is.
+fn read_lines<R, F>(reader: &mut R, partial: &mut String, f: &mut F) -> io::Result<()>
+where
+    R: BufRead,
+    F: FnMut(&str) -> io::Result<()>,
+{
+    while reader.read_line(partial)? > 0 {
+        if partial.ends_with('\n') {
+            f(partial.trim_end_matches('\n'))?;
+            partial.clear();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn partial_lines() {
+        let mut lines = vec![];
+        let mut partial = String::new();
+        let mut collect = |line: &str| {
+            lines.push(line.to_owned());
+            Ok(())
+        };
+        read_lines(&mut &b"one\ntw"[..], &mut partial, &mut collect).unwrap();
+        assert_eq!(partial, "tw");
+        read_lines(&mut &b"o\nthree\n"[..], &mut partial, &mut collect).unwrap();
+        assert!(partial.is_empty());
+        assert_eq!(lines, vec!["one", "two", "three"]);
+    }
+}
@@ -0,0 +1,253 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! cfwlog prints the records of cfwlogd's zone logs, or follows a zone's current.log as it's
+//! written to, filtering them by time, rule, protocol, address, port or direction and printing them
+//! in the chosen format. Indexed logs are queried for the time range rather than read whole. See
+//! the README's "Reading zone logs" section.
+
+mod filter;
+mod follow;
+mod output;
+
+use cfwevent::indexed::{self, IndexedReader};
+use cfwevent::record;
+use filter::Filter;
+use output::{Format, Printer};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const USAGE: &str = "usage: cfwlog [-f] [--format text|json|csv] [--since <time>] [--until <time>]
+              [--rule <uuid>] [--protocol <protocol>] [--addr <ip>] [--port <port>]
+              [--direction in|out] <file>...";
+
+/// What the command line asked for
+#[derive(Debug, PartialEq)]
+struct Options {
+    follow: bool,
+    format: Format,
+    filter: Filter,
+    /// "-" is stdin
+    files: Vec<String>,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        follow: false,
+        format: Format::Text,
+        filter: Filter::default(),
+        files: vec![],
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(String::as_str)
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        match arg.as_str() {
+            "-f" | "--follow" => options.follow = true,
+            "--format" => options.format = value()?.parse()?,
+            "--since" => options.filter.since = Some(filter::parse_time(value()?)?),
+            "--until" => options.filter.until = Some(filter::parse_time(value()?)?),
+            "--rule" => {
+                let rule = value()?;
+                let rule = rule
+                    .parse()
+                    .map_err(|_| format!("invalid rule uuid \"{}\"", rule))?;
+                options.filter.rule = Some(rule);
+            }
+            "--protocol" => options.filter.protocol = Some(filter::parse_protocol(value()?)?),
+            "--addr" => options.filter.addr = Some(value()?.parse()?),
+            "--port" => {
+                let port = value()?;
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port \"{}\"", port))?;
+                options.filter.port = Some(port);
+            }
+            "--direction" => options.filter.direction = Some(filter::parse_direction(value()?)?),
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown argument \"{}\"", arg))
+            }
+            _ => options.files.push(arg.clone()),
+        }
+    }
+    if options.files.is_empty() {
+        return Err("no files given".to_owned());
+    }
+    if options.follow && (options.files.len() != 1 || options.files[0] == "-") {
+        return Err("only a single file can be followed".to_owned());
+    }
+    Ok(options)
+}
+
+/// Print a line of a log if it's a record that isn't filtered out. Lines that aren't records are
+/// reported and skipped.
+fn print_line<W: Write>(
+    name: &str,
+    line: &str,
+    filter: &Filter,
+    printer: &mut Printer<W>,
+) -> io::Result<()> {
+    if line.trim().is_empty() {
+        return Ok(());
+    }
+    match record::parse_record(line) {
+        Ok(record) if filter.matches(&record) => printer.print(line, &record),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            eprintln!("{}: skipping a line that isn't a record: {}", name, e);
+            Ok(())
+        }
+    }
+}
+
+fn print_file<R: BufRead, W: Write>(
+    name: &str,
+    reader: R,
+    filter: &Filter,
+    printer: &mut Printer<W>,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        print_line(name, &line?, filter, printer)?;
+    }
+    Ok(())
+}
+
+/// Print a file, querying it for the time range if it's an indexed log
+fn print_path<W: Write>(name: &str, filter: &Filter, printer: &mut Printer<W>) -> io::Result<()> {
+    let mut file = File::open(name)?;
+    if indexed::is_indexed(&mut file)? != Some(true) {
+        file.seek(SeekFrom::Start(0))?;
+        return print_file(name, BufReader::new(file), filter, printer);
+    }
+    let mut reader = IndexedReader::new(BufReader::new(file))?;
+    reader.query(filter.since.as_ref(), filter.until.as_ref(), |_, json| {
+        print_line(name, &String::from_utf8_lossy(json), filter, printer)
+    })
+}
+
+fn run(options: &Options) -> io::Result<bool> {
+    let stdout = io::stdout();
+    let mut printer = Printer::new(options.format, stdout.lock());
+    let filter = &options.filter;
+    if options.follow {
+        let name = &options.files[0];
+        if indexed::is_indexed(File::open(name)?)? == Some(true) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: indexed logs can't be followed", name),
+            ));
+        }
+        follow::follow(Path::new(name), |line| {
+            print_line(name, line, filter, &mut printer)?;
+            printer.flush()
+        })?;
+        return Ok(true);
+    }
+
+    let mut ok = true;
+    for name in &options.files {
+        let result = if name == "-" {
+            let stdin = io::stdin();
+            let reader = stdin.lock();
+            print_file(name, reader, filter, &mut printer)
+        } else {
+            print_path(name, filter, &mut printer)
+        };
+        match result {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Err(e),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                ok = false;
+            }
+        }
+    }
+    printer.flush()?;
+    Ok(ok)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("cfwlog: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let code = match run(&options) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        // Whatever we were piped to, such as head(1), has seen enough
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => 0,
+        Err(e) => {
+            eprintln!("cfwlog: {}", e);
+            1
+        }
+    };
+    std::process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn arguments() {
+        let options = parse_args(&args(&[
+            "-f",
+            "--format",
+            "csv",
+            "--protocol",
+            "udp",
+            "--port",
+            "53",
+            "--addr",
+            "fe80::1%net0",
+            "--since",
+            "2020-05-12T19:00:00Z",
+            "current.log",
+        ]))
+        .unwrap();
+        assert!(options.follow);
+        assert_eq!(options.format, Format::Csv);
+        assert_eq!(options.filter.port, Some(53));
+        assert_eq!(options.filter.addr, Some("fe80::1%net0".parse().unwrap()));
+        assert_eq!(
+            options.filter.since,
+            Some(filter::parse_time("2020-05-12T19:00:00Z").unwrap())
+        );
+        assert!(options.filter.until.is_none());
+        assert_eq!(options.files, vec!["current.log"]);
+
+        let options = parse_args(&args(&["a.log", "-"])).unwrap();
+        assert!(options.filter.is_empty());
+        assert_eq!(options.format, Format::Text);
+
+        let bad: &[&[&str]] = &[
+            &[],
+            &["--port"],
+            &["--port", "http", "a.log"],
+            &["--rule", "nope", "a.log"],
+            &["--format", "xml", "a.log"],
+            &["--until", "2020-05-12", "a.log"],
+            &["--bogus", "a.log"],
+            &["-f", "a.log", "b.log"],
+            &["-f", "-"],
+        ];
+        for bad in bad {
+            assert!(parse_args(&args(bad)).is_err(), "{:?}", bad);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! How records are printed, see the "--format" flag.
+//!
+//! - "text", the default, is a line per record meant for reading, such as
+//!
+//!   `2020-05-12 19:00:00.123456 web0 block in TCP 172.24.4.150:50000 -> 172.24.4.151:22 rule ...`
+//!
+//!   ICMP records have their type and code instead, as in `... ICMP 172.24.4.150 -> 172.24.4.151
+//!   type 8 code 0 rule ...`.
+//!
+//! - "json" prints records as cfwlogd logged them.
+//! - "csv" prints a header followed by a row per traffic record. Other records are left out as
+//!   they don't have the same columns.
+
+use cfwevent::parser::{Direction, Protocol};
+use cfwevent::record::{LogRecord, TrafficRecord};
+use chrono::{DateTime, Utc};
+use std::io::{self, Write};
+
+/// How text lines show timestamps
+const TEXT_TIMESTAMP: &str = "%Y-%m-%d %H:%M:%S%.6f";
+
+/// Fields of other records that every text line starts with, so they aren't repeated after it
+const TEXT_SKIPPED: &[&str] = &["schema_version", "event", "vm", "timestamp"];
+
+const CSV_HEADER: &str = "timestamp,vm,alias,event,direction,protocol,source_ip,source_port,\
+                          destination_ip,destination_port,rule,icmp_type,icmp_code";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format \"{}\"", s)),
+        }
+    }
+}
+
+pub struct Printer<W: Write> {
+    format: Format,
+    out: W,
+    header_written: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(format: Format, out: W) -> Self {
+        Printer {
+            format,
+            out,
+            header_written: false,
+        }
+    }
+
+    /// Print a record along with the line of the log it was parsed from
+    pub fn print(&mut self, line: &str, record: &LogRecord) -> io::Result<()> {
+        match (self.format, record) {
+            (Format::Json, _) => writeln!(self.out, "{}", line),
+            (Format::Text, LogRecord::Traffic(record)) => self.text(record),
+            (Format::Text, LogRecord::Other { event, fields }) => {
+                let field = |name: &str| fields.get(name).and_then(|v| v.as_str());
+                let timestamp = match field("timestamp").map(str::parse::<DateTime<Utc>>) {
+                    Some(Ok(timestamp)) => timestamp.format(TEXT_TIMESTAMP).to_string(),
+                    _ => "-".to_owned(),
+                };
+                let vm = field("vm").unwrap_or("-");
+                write!(self.out, "{} {} {}", timestamp, vm, event)?;
+                for (name, value) in fields {
+                    if !TEXT_SKIPPED.contains(&name.as_str()) {
+                        write!(self.out, " {}={}", name, value)?;
+                    }
+                }
+                writeln!(self.out)
+            }
+            (Format::Csv, LogRecord::Traffic(record)) => self.csv(record),
+            (Format::Csv, LogRecord::Other { .. }) => Ok(()),
+        }
+    }
+
+    fn text(&mut self, record: &TrafficRecord) -> io::Result<()> {
+        let name = if record.alias.is_empty() {
+            &record.vm
+        } else {
+            &record.alias
+        };
+        write!(
+            self.out,
+            "{} {} {} {} {} ",
+            record.timestamp.format(TEXT_TIMESTAMP),
+            name,
+            record.event.name(),
+            direction_name(&record.direction),
+            protocol_name(&record.protocol),
+        )?;
+        match (record.icmp_type, record.icmp_code) {
+            (Some(icmp_type), Some(icmp_code)) => write!(
+                self.out,
+                "{} -> {} type {} code {}",
+                record.source_ip, record.destination_ip, icmp_type, icmp_code
+            )?,
+            _ => write!(
+                self.out,
+                "{} -> {}",
+                record.source_ip.with_port(record.source_port),
+                record.destination_ip.with_port(record.destination_port)
+            )?,
+        }
+        writeln!(self.out, " rule {}", record.rule)
+    }
+
+    fn csv(&mut self, record: &TrafficRecord) -> io::Result<()> {
+        if !self.header_written {
+            writeln!(self.out, "{}", CSV_HEADER)?;
+            self.header_written = true;
+        }
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            record.timestamp.to_rfc3339(),
+            record.vm,
+            csv_field(&record.alias),
+            record.event.name(),
+            direction_name(&record.direction),
+            protocol_name(&record.protocol),
+            record.source_ip,
+            record.source_port,
+            record.destination_ip,
+            record.destination_port,
+            record.rule,
+            optional(record.icmp_type),
+            optional(record.icmp_code)
+        )
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn direction_name(direction: &Direction) -> &'static str {
+    match direction {
+        Direction::In => "in",
+        Direction::Out => "out",
+    }
+}
+
+fn protocol_name(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::AH => "AH",
+        Protocol::ESP => "ESP",
+        Protocol::ICMP => "ICMP",
+        Protocol::ICMPV6 => "ICMPV6",
+        Protocol::TCP => "TCP",
+        Protocol::UDP => "UDP",
+        Protocol::UNKNOWN => "UNKNOWN",
+    }
+}
+
+/// A field only some records have, empty for the others
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(String::new, |value| value.to_string())
+}
+
+/// Quote a free form field, such as a vm's alias, when it would break up the row
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::record;
+
+    const TRAFFIC: &str = concat!(
+        r#"{"schema_version":1,"event":"block","source_port":50000,"destination_port":22,"#,
+        r#""protocol":"TCP","direction":"in","source_ip":"172.24.4.150","#,
+        r#""destination_ip":"fd00::1","timestamp":"2020-05-12T19:00:00.123456Z","#,
+        r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+        r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":"web,0"}"#
+    );
+
+    const LOST: &str = concat!(
+        r#"{"schema_version":1,"event":"events_lost","vm":"x","lost":3,"#,
+        r#""timestamp":"2020-05-12T19:00:00Z"}"#
+    );
+
+    fn print(format: Format) -> String {
+        let mut printer = Printer::new(format, vec![]);
+        for line in &[TRAFFIC, LOST, TRAFFIC] {
+            printer
+                .print(line, &record::parse_record(line).unwrap())
+                .unwrap();
+        }
+        String::from_utf8(printer.out).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        let text = print(Format::Text);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[0],
+            "2020-05-12 19:00:00.123456 web,0 block in TCP 172.24.4.150:50000 -> [fd00::1]:22 \
+             rule 5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f"
+        );
+        assert_eq!(lines[1], "2020-05-12 19:00:00.000000 x events_lost lost=3");
+
+        assert_eq!(
+            print(Format::Json),
+            format!("{}\n{}\n{}\n", TRAFFIC, LOST, TRAFFIC)
+        );
+
+        let csv = print(Format::Csv);
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3, "a header and only the traffic records");
+        assert_eq!(lines[0], CSV_HEADER);
+        assert_eq!(
+            lines[1],
+            "2020-05-12T19:00:00.123456+00:00,2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d,\"web,0\",\
+             block,in,TCP,172.24.4.150,50000,fd00::1,22,5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f,,"
+        );
+
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn icmp_records() {
+        let line = TRAFFIC
+            .replace(
+                r#""protocol":"TCP""#,
+                r#""protocol":"ICMP","icmp_type":8,"icmp_code":0"#,
+            )
+            .replace("50000", "8")
+            .replace(r#""destination_port":22"#, r#""destination_port":0"#);
+        let record = record::parse_record(&line).unwrap();
+        let mut printer = Printer::new(Format::Text, vec![]);
+        printer.print(&line, &record).unwrap();
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "2020-05-12 19:00:00.123456 web,0 block in ICMP 172.24.4.150 -> fd00::1 type 8 code 0 \
+             rule 5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f\n"
+        );
+
+        let mut printer = Printer::new(Format::Csv, vec![]);
+        printer.print(&line, &record).unwrap();
+        let csv = String::from_utf8(printer.out).unwrap();
+        assert!(csv.lines().nth(1).unwrap().ends_with(",8,0"), "{}", csv);
+    }
+
+    #[test]
+    fn ipv6_addresses() {
+        let line = TRAFFIC
+            .replace("172.24.4.150", "fe80::8:20ff:fe1b:4a3c%net0")
+            .replace("fd00::1", "::ffff:172.24.4.151");
+        let mut printer = Printer::new(Format::Text, vec![]);
+        printer
+            .print(&line, &record::parse_record(&line).unwrap())
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(printer.out).unwrap(),
+            "2020-05-12 19:00:00.123456 web,0 block in TCP [fe80::8:20ff:fe1b:4a3c%net0]:50000 -> \
+             [::ffff:172.24.4.151]:22 rule 5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f\n"
+        );
+    }
+}
@@ -0,0 +1,28 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+use std::process::Command;
+
+fn main() {
+    // Recorded for `cfwlogd version`, which leaves out whatever isn't known
+    if let Ok(target) = std::env::var("TARGET") {
+        println!("cargo:rustc-env=CFWLOGD_TARGET={}", target);
+    }
+    let commit = Command::new("git")
+        .args(&["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=CFWLOGD_COMMIT={}", commit.trim());
+    }
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/live.proto").expect("failed to compile proto/live.proto");
+    #[cfg(feature = "protobuf")]
+    prost_build::compile_protos(&["proto/record.proto"], &["proto"])
+        .expect("failed to compile proto/record.proto");
+}
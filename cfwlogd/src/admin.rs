@@ -0,0 +1,471 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A Unix socket for operators to look into a running cfwlogd, and the `cfwlogd ctl` subcommand
+//! that drives it. Clients send one json request per line and get one line of json back for each:
+//!
+//! ```text
+//! {"command":"queues"}
+//! {"ok":true,"queues":[{"zonedid":7,"vm":"...","queued":0,"events_written":12,...}]}
+//! ```
+//!
+//! "zones" lists the zones vminfod has reported and whether each has a `Logger`, "queues" the
+//! depth of every `Logger`'s queue along with the zone's written and dropped counts since its log
+//! was last rotated, "vminfod" whether the event stream is connected, "top" every zone's latest top
+//! talkers and rules when "top_talkers" is configured (see the "talkers" module), and "flush"
+//! flushes every zone's log the same as SIGUSR2 does, without the state dump. "selftest" sends a
+//! test event through the pipeline for the zone with the lowest zonedid, or the one its "vm"
+//! names, and reports how far it got, see the "selftest" module. "pause" and "resume" stop and
+//! restart the logging of the zone their "vm" names, see the "pause" module. A request that can't
+//! be carried out gets `{"ok":false,"error":"..."}`.
+
+use crate::config::{self, ConfigFile, SharedConfig};
+use crate::events::Loggers;
+use crate::fileutils;
+use crate::selftest::{self, Injector};
+use crate::stats::{self, Counts, Stats, ZoneCounters};
+use crate::talkers::Top;
+use crate::zones::{Vmobjs, Zonedid};
+use cfwevent::parser;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Where the admin socket is created unless "admin.socket" says otherwise
+pub const DEFAULT_SOCKET: &str = "/var/run/cfwlogd.sock";
+
+/// A client that hasn't sent a request within this long is hung up on, so it can't hold up the
+/// ones after it
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Request {
+    Zones,
+    Queues,
+    Vminfod,
+    Top,
+    Flush,
+    Selftest {
+        #[serde(default)]
+        vm: Option<String>,
+    },
+    Pause {
+        vm: String,
+    },
+    Resume {
+        vm: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct ZoneInfo {
+    zonedid: Zonedid,
+    vm: String,
+    alias: Option<String>,
+    owner_uuid: String,
+    /// Whether the zone has opted out of logging, see "Opting zones out" in the README
+    logging_disabled: bool,
+    logger: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueInfo {
+    zonedid: Zonedid,
+    vm: String,
+    queued: usize,
+    /// Seconds since the zone's `Logger` last made progress
+    heartbeat_secs: u64,
+    paused: bool,
+    #[serde(flatten)]
+    counts: Counts,
+}
+
+#[derive(Debug, Serialize)]
+struct TopInfo {
+    zonedid: Zonedid,
+    vm: String,
+    #[serde(flatten)]
+    top: Top,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// What the admin socket reports on
+pub struct Admin {
+    pub vmobjs: Vmobjs,
+    pub loggers: Loggers,
+    pub stats: Stats,
+    pub injector: Injector,
+    /// How the timestamps it reports are written, see `Config::serialization`
+    pub config: SharedConfig,
+}
+
+impl Admin {
+    fn zones(&self) -> Vec<ZoneInfo> {
+        let mut zones: Vec<ZoneInfo> = {
+            let vms = self.vmobjs.load();
+            vms.values()
+                .map(|zone| ZoneInfo {
+                    zonedid: zone.zonedid,
+                    vm: zone.uuid.clone(),
+                    alias: zone.alias.clone(),
+                    owner_uuid: zone.owner_uuid.clone(),
+                    logging_disabled: vms.logging_disabled(&zone.zonedid),
+                    logger: false,
+                })
+                .collect()
+        };
+        // The vm table isn't held while the loggers are locked, the fanout thread takes them the
+        // other way around
+        let loggers = self.loggers.lock().unwrap();
+        for zone in zones.iter_mut() {
+            zone.logger = loggers.contains_key(&zone.zonedid);
+        }
+        zones.sort_by_key(|zone| zone.zonedid);
+        zones
+    }
+
+    fn queues(&self) -> Vec<QueueInfo> {
+        let loggers = self.loggers.lock().unwrap();
+        let stats = self.stats.lock().unwrap();
+        let mut queues: Vec<QueueInfo> = loggers
+            .iter()
+            .map(|(zonedid, logger)| QueueInfo {
+                zonedid: *zonedid,
+                vm: logger.uuid.clone(),
+                queued: logger.queued(),
+                heartbeat_secs: logger.heartbeat_age().as_secs(),
+                paused: stats
+                    .get(zonedid)
+                    .map_or(false, |counters| counters.pause().is_paused()),
+                counts: stats
+                    .get(zonedid)
+                    .map(|counters| counters.peek())
+                    .unwrap_or_default(),
+            })
+            .collect();
+        queues.sort_by_key(|queue| queue.zonedid);
+        queues
+    }
+
+    fn top(&self) -> Vec<TopInfo> {
+        let loggers = self.loggers.lock().unwrap();
+        let stats = self.stats.lock().unwrap();
+        let mut tops: Vec<TopInfo> = loggers
+            .iter()
+            .filter_map(|(zonedid, logger)| {
+                let (top, timestamp) = stats.get(zonedid)?.top()?;
+                Some(TopInfo {
+                    zonedid: *zonedid,
+                    vm: logger.uuid.clone(),
+                    top,
+                    timestamp,
+                })
+            })
+            .collect();
+        tops.sort_by_key(|top| top.zonedid);
+        tops
+    }
+
+    /// Flush every zone's log, returning the vms whose `Logger` didn't take the request
+    fn flush(&self) -> (usize, Vec<String>) {
+        let loggers = self.loggers.lock().unwrap();
+        let failed: Vec<String> = loggers
+            .values()
+            .filter(|logger| logger.flush().is_err())
+            .map(|logger| logger.uuid.clone())
+            .collect();
+        (loggers.len() - failed.len(), failed)
+    }
+
+    /// Run a self-test with the zone that has the given uuid, or else the lowest zonedid
+    fn selftest(&self, vm: Option<&str>) -> Result<selftest::Report, String> {
+        let (zonedid, uuid) = {
+            let vms = self.vmobjs.load();
+            let mut zones: Vec<_> = vms
+                .values()
+                .filter(|zone| vm.map_or(true, |vm| zone.uuid == vm))
+                .filter(|zone| !vms.logging_disabled(&zone.zonedid))
+                .map(|zone| (zone.zonedid, zone.uuid.clone()))
+                .collect();
+            zones.sort();
+            match (zones.into_iter().next(), vm) {
+                (Some(zone), _) => zone,
+                (None, Some(vm)) => return Err(format!("no zone {} that is logged", vm)),
+                (None, None) => return Err("no zone that is logged".to_owned()),
+            }
+        };
+        Ok(selftest::run(
+            &self.injector,
+            zonedid,
+            &uuid,
+            selftest::TIMEOUT,
+        ))
+    }
+
+    /// The counters of the zone with the given uuid, which has to have a `Logger`
+    fn logged_zone(&self, vm: &str) -> Result<Arc<ZoneCounters>, String> {
+        let loggers = self.loggers.lock().unwrap();
+        let zonedid = loggers
+            .iter()
+            .find(|(_, logger)| logger.uuid == vm)
+            .map(|(zonedid, _)| *zonedid)
+            .ok_or_else(|| format!("no zone {} that is logged", vm))?;
+        Ok(stats::zone_counters(&self.stats, zonedid))
+    }
+
+    /// Pause or resume the zone's logging
+    fn set_paused(&self, vm: &str, pause: bool) -> Result<Value, String> {
+        let counters = self.logged_zone(vm)?;
+        let now = Utc::now();
+        if pause {
+            if !counters.pause().pause(now) {
+                return Err(format!("{} is already paused", vm));
+            }
+            warn!("admin socket: paused logging {}", vm);
+            return Ok(json!({"ok": true, "vm": vm, "paused": true}));
+        }
+        let events = counters
+            .pause()
+            .resume(now)
+            .ok_or_else(|| format!("{} isn't paused", vm))?;
+        info!(
+            "admin socket: resumed logging {}, {} events were left out",
+            vm, events
+        );
+        Ok(json!({"ok": true, "vm": vm, "paused": false, "events": events}))
+    }
+
+    fn handle(&self, line: &str) -> Value {
+        let request = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return json!({"ok": false, "error": format!("invalid request: {}", e)}),
+        };
+        match request {
+            Request::Zones => json!({"ok": true, "zones": self.zones()}),
+            Request::Queues => json!({"ok": true, "queues": self.queues()}),
+            Request::Vminfod => json!({
+                "ok": true,
+                "connected": vminfod_client::connected(),
+                "coalesced": vminfod_client::coalesced(),
+                "superseded": vminfod_client::superseded(),
+            }),
+            Request::Top => {
+                let _serialization = self.config.read().unwrap().serialization().apply();
+                json!({"ok": true, "zones": self.top()})
+            }
+            Request::Flush => {
+                let (flushed, failed) = self.flush();
+                info!("admin socket: flushed {} logs", flushed);
+                json!({"ok": failed.is_empty(), "flushed": flushed, "failed": failed})
+            }
+            Request::Selftest { vm } => match self.selftest(vm.as_deref()) {
+                Ok(report) => {
+                    if !report.ok {
+                        warn!("admin socket: self-test failed: {:?}", report.stages);
+                    }
+                    serde_json::to_value(report).unwrap_or_default()
+                }
+                Err(e) => json!({"ok": false, "error": e}),
+            },
+            Request::Pause { vm } => self
+                .set_paused(&vm, true)
+                .unwrap_or_else(|e| json!({"ok": false, "error": e})),
+            Request::Resume { vm } => self
+                .set_paused(&vm, false)
+                .unwrap_or_else(|e| json!({"ok": false, "error": e})),
+        }
+    }
+}
+
+/// Answer a client's requests until it hangs up
+fn serve_client(stream: UnixStream, admin: &Admin) -> io::Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut json = serde_json::to_vec(&admin.handle(&line))?;
+        json.push(b'\n');
+        writer.write_all(&json)?;
+    }
+    Ok(())
+}
+
+/// Bind the admin socket. Like the health socket this is done before we chroot.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let listener = fileutils::bind_private_socket(path)?;
+    info!("admin socket listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Start answering the clients of a socket from `bind`, one at a time
+pub fn start_admin(listener: UnixListener, admin: Admin) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("admin".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| serve_client(stream, &admin));
+                if let Err(e) = res {
+                    debug!("failed to answer admin client: {}", e);
+                }
+            }
+        })
+}
+
+/// The admin socket of the configured cfwlogd, or the default if the config doesn't load
+fn configured_socket() -> PathBuf {
+    ConfigFile::open(config::CONFIG_FILE)
+        .map_err(config::Error::from)
+        .and_then(|file| file.load())
+        .ok()
+        .and_then(|config| config.admin)
+        .map_or_else(|| PathBuf::from(DEFAULT_SOCKET), |admin| admin.socket)
+}
+
+/// Send a single request and return the response
+fn request(socket: &Path, request: &Value) -> io::Result<Value> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut response = String::new();
+    BufReader::new(stream).read_line(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn usage() -> i32 {
+    eprintln!(
+        "usage: cfwlogd ctl [--socket PATH] zones|queues|vminfod|top|flush|selftest [VM]|\
+         pause VM|resume VM"
+    );
+    2
+}
+
+/// The request for a command and its vm, if it takes one
+fn command_request(args: &[String]) -> Option<Value> {
+    match args {
+        [command] if !command.starts_with('-') && command != "pause" && command != "resume" => {
+            Some(json!({ "command": command }))
+        }
+        [command, vm] if matches!(command.as_str(), "selftest" | "pause" | "resume") => {
+            Some(json!({ "command": command, "vm": vm }))
+        }
+        _ => None,
+    }
+}
+
+/// Run the "ctl" subcommand, returning the process's exit code.
+pub fn run(args: &[String]) -> i32 {
+    let (socket, args) = match args {
+        [flag, socket, args @ ..] if flag == "--socket" => (Some(PathBuf::from(socket)), args),
+        args => (None, args),
+    };
+    let request_json = match command_request(args) {
+        Some(request) => request,
+        None => return usage(),
+    };
+    let socket = socket.unwrap_or_else(configured_socket);
+    match request(&socket, &request_json) {
+        Ok(response) => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&response).unwrap_or_default()
+            );
+            if response["ok"] == true {
+                0
+            } else {
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("cfwlogd ctl: {}: {}", socket.display(), e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::LossAudit;
+    use crate::inflight::InFlight;
+    use crate::memory::MemoryTracker;
+    use crate::queue::{self, Overflow};
+    use crate::zones::{SharedVmTable, VmTable};
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use vminfod_client::Zone;
+
+    #[test]
+    fn requests_are_answered() {
+        let mut vms = VmTable::default();
+        vms.insert(Zone {
+            uuid: "vm1".to_owned(),
+            alias: Some("web".to_owned()),
+            owner_uuid: "owner".to_owned(),
+            firewall_enabled: true,
+            zonedid: 7,
+            tags: HashMap::new(),
+            nics: vec![],
+        });
+        let admin = Admin {
+            vmobjs: Arc::new(SharedVmTable::new(vms)),
+            loggers: Arc::new(Mutex::new(HashMap::new())),
+            stats: Arc::new(Mutex::new(HashMap::new())),
+            injector: Injector::new(
+                queue::bounded(1, Overflow::DropNewest).0,
+                Arc::new(MemoryTracker::new(None)),
+                Arc::new(InFlight::new()),
+                Arc::new(LossAudit::new(false)),
+            ),
+            config: config::shared(config::Config::default()),
+        };
+        let zones = admin.handle(r#"{"command":"zones"}"#);
+        assert_eq!(zones["ok"], true);
+        assert_eq!(zones["zones"][0]["vm"], "vm1");
+        assert_eq!(zones["zones"][0]["logger"], false);
+        assert_eq!(admin.handle(r#"{"command":"queues"}"#)["queues"], json!([]));
+        assert_eq!(admin.handle(r#"{"command":"top"}"#)["zones"], json!([]));
+        assert_eq!(admin.handle(r#"{"command":"flush"}"#)["flushed"], 0);
+        assert_eq!(admin.handle(r#"{"command":"restart"}"#)["ok"], false);
+        let selftest = admin.handle(r#"{"command":"selftest","vm":"vm2"}"#);
+        assert_eq!(selftest["error"], "no zone vm2 that is logged");
+        let pause = admin.handle(r#"{"command":"pause","vm":"vm1"}"#);
+        assert_eq!(pause["error"], "no zone vm1 that is logged");
+        assert_eq!(
+            command_request(&["pause".to_owned(), "vm1".to_owned()]),
+            Some(json!({"command": "pause", "vm": "vm1"}))
+        );
+        assert_eq!(
+            command_request(&["pause".to_owned()]),
+            None,
+            "pause needs a vm"
+        );
+
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests/admin");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        let listener = bind(&path).expect("failed to bind admin socket");
+        let _handle = start_admin(listener, admin).expect("failed to start admin socket");
+        let response =
+            request(&path, &json!({"command": "vminfod"})).expect("failed to send request");
+        assert_eq!(response["ok"], true);
+        assert_eq!(response["connected"], false);
+
+        std::fs::remove_dir_all(&dir).expect("failed to cleanup test dir");
+    }
+}
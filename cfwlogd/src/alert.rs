@@ -0,0 +1,459 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Alerting on zones whose rules suddenly block a lot of traffic, which usually means the zone is
+//! being scanned or brute forced. Once a second the alert thread samples every zone's running
+//! block total from its `ZoneCounters`, and when a zone has blocked at least the configured rate
+//! of events every second for the whole window an alert is sent as an SNMPv2c trap and/or posted
+//! to a webhook. A zone isn't alerted on again until the cooldown has passed.
+//!
+//! When the alert config has a filter expression the events matching it are counted instead of
+//! blocked events, for example to only alert on blocked connections to ssh.
+//!
+//! Traps are sent to the configured trap OID, with the alert's details bound to
+//! "<trap oid>.1" (vm uuid), "<trap oid>.2" (blocked events in the window) and "<trap oid>.3"
+//! (window length in seconds).
+
+use crate::clock::SharedClock;
+use crate::config::AlertConfig;
+#[cfg(feature = "webhook")]
+use crate::http;
+use crate::stats::Stats;
+use crate::zones::{Vmobjs, Zonedid};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often every zone's block total is sampled
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// sysUpTime.0
+const SYS_UPTIME_OID: &[u32] = &[1, 3, 6, 1, 2, 1, 1, 3, 0];
+/// snmpTrapOID.0
+const SNMP_TRAP_OID: &[u32] = &[1, 3, 6, 1, 6, 3, 1, 1, 4, 1, 0];
+
+/// A zone whose block rate crossed the threshold
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Alert {
+    pub vm: String,
+    pub alias: String,
+    /// Blocked events during the window
+    pub blocks: u64,
+    pub window_secs: u64,
+    /// The configured threshold in blocked events per second
+    pub block_rate: u64,
+    pub timestamp: DateTime<Utc>,
+}
+
+/// Parse a dotted OID such as "1.3.6.1.4.1.8072.9999.9999"
+pub fn parse_oid(s: &str) -> Result<Vec<u32>, String> {
+    let oid = s
+        .trim_start_matches('.')
+        .split('.')
+        .map(|arc| arc.parse())
+        .collect::<Result<Vec<u32>, _>>()
+        .map_err(|_| format!("invalid OID \"{}\"", s))?;
+    match oid[..] {
+        [first, second, ..] if first <= 2 && second < 40 => Ok(oid),
+        _ => Err(format!("invalid OID \"{}\"", s)),
+    }
+}
+
+fn ber_length(len: usize, out: &mut Vec<u8>) {
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let skip = bytes.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (bytes.len() - skip) as u8);
+        out.extend_from_slice(&bytes[skip..]);
+    }
+}
+
+fn ber_tlv(tag: u8, contents: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    ber_length(contents.len(), &mut out);
+    out.extend_from_slice(contents);
+    out
+}
+
+/// Encode an INTEGER, or one of the unsigned application types that share its encoding
+fn ber_integer(tag: u8, value: i64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    // Drop leading bytes that don't change the two's complement value
+    let mut skip = 0;
+    while skip < bytes.len() - 1 {
+        let (b, next) = (bytes[skip], bytes[skip + 1]);
+        if (b == 0 && next & 0x80 == 0) || (b == 0xff && next & 0x80 != 0) {
+            skip += 1;
+        } else {
+            break;
+        }
+    }
+    ber_tlv(tag, &bytes[skip..])
+}
+
+fn ber_oid(oid: &[u32]) -> Vec<u8> {
+    let mut contents = vec![];
+    let arcs = std::iter::once(oid[0] * 40 + oid[1]).chain(oid[2..].iter().copied());
+    for arc in arcs {
+        let mut base128 = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            base128.push((rest & 0x7f) as u8 | 0x80);
+            rest >>= 7;
+        }
+        contents.extend(base128.iter().rev());
+    }
+    ber_tlv(0x06, &contents)
+}
+
+fn varbind(oid: &[u32], value: Vec<u8>) -> Vec<u8> {
+    let mut contents = ber_oid(oid);
+    contents.extend(value);
+    ber_tlv(0x30, &contents)
+}
+
+/// Encode an SNMPv2c trap message for an alert
+fn encode_trap(
+    community: &str,
+    request_id: i32,
+    uptime: Duration,
+    trap_oid: &[u32],
+    alert: &Alert,
+) -> Vec<u8> {
+    let detail = |n| {
+        let mut oid = trap_oid.to_vec();
+        oid.push(n);
+        oid
+    };
+    let centiseconds = (uptime.as_millis() / 10) as u32;
+    let varbinds: Vec<u8> = [
+        varbind(SYS_UPTIME_OID, ber_integer(0x43, i64::from(centiseconds))),
+        varbind(SNMP_TRAP_OID, ber_oid(trap_oid)),
+        varbind(&detail(1), ber_tlv(0x04, alert.vm.as_bytes())),
+        varbind(
+            &detail(2),
+            ber_integer(0x42, alert.blocks.min(u64::from(u32::max_value())) as i64),
+        ),
+        varbind(&detail(3), ber_integer(0x42, alert.window_secs as i64)),
+    ]
+    .concat();
+
+    let mut pdu = ber_integer(0x02, i64::from(request_id));
+    pdu.extend(ber_integer(0x02, 0)); // error-status
+    pdu.extend(ber_integer(0x02, 0)); // error-index
+    pdu.extend(ber_tlv(0x30, &varbinds));
+
+    let mut message = ber_integer(0x02, 1); // SNMPv2c
+    message.extend(ber_tlv(0x04, community.as_bytes()));
+    message.extend(ber_tlv(0xa7, &pdu)); // SNMPv2-Trap-PDU
+    ber_tlv(0x30, &message)
+}
+
+/// A destination for traps
+struct TrapTarget {
+    socket: UdpSocket,
+    target: SocketAddr,
+    community: String,
+    trap_oid: Vec<u32>,
+}
+
+/// Everything needed to deliver alerts. Addresses are resolved when this is created, which must
+/// happen before we chroot.
+pub struct Alerter {
+    config: AlertConfig,
+    trap: Option<TrapTarget>,
+    #[cfg(feature = "webhook")]
+    webhook: Option<ureq::Agent>,
+    started: Instant,
+    request_id: i32,
+}
+
+impl Alerter {
+    pub fn new(config: &AlertConfig) -> io::Result<Alerter> {
+        let trap = match &config.snmp {
+            Some(snmp) => {
+                let target = snmp.target.to_socket_addrs()?.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotFound, "trap target didn't resolve")
+                })?;
+                let bind = if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                Some(TrapTarget {
+                    socket: UdpSocket::bind(bind)?,
+                    target,
+                    community: snmp.community.clone(),
+                    trap_oid: parse_oid(&snmp.trap_oid)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+                })
+            }
+            None => None,
+        };
+        Ok(Alerter {
+            config: config.clone(),
+            trap,
+            #[cfg(feature = "webhook")]
+            webhook: match &config.webhook {
+                Some(url) => Some(http::pinned_agent(url)?),
+                None => None,
+            },
+            started: Instant::now(),
+            request_id: 0,
+        })
+    }
+
+    /// Deliver an alert to every configured destination. A failed destination is logged and
+    /// doesn't prevent delivery to the others.
+    fn send(&mut self, alert: &Alert) {
+        warn!(
+            "{} blocked {} events in {}s, sending alert",
+            alert.vm, alert.blocks, alert.window_secs
+        );
+        if let Some(trap) = &self.trap {
+            self.request_id = self.request_id.wrapping_add(1);
+            let message = encode_trap(
+                &trap.community,
+                self.request_id,
+                self.started.elapsed(),
+                &trap.trap_oid,
+                alert,
+            );
+            if let Err(e) = trap.socket.send_to(&message, trap.target) {
+                error!("failed to send trap to {}: {}", trap.target, e);
+            }
+        }
+        #[cfg(feature = "webhook")]
+        {
+            if let (Some(agent), Some(url)) = (&self.webhook, &self.config.webhook) {
+                let body = serde_json::to_string(alert).expect("failed to serialize alert");
+                let resp = agent
+                    .post(url)
+                    .timeout_connect(5_000)
+                    .timeout_read(5_000)
+                    .set("Content-Type", "application/json")
+                    .send_string(&body);
+                if let Err(e) = http::check_response(resp) {
+                    error!("failed to post alert to {}: {}", url, e);
+                }
+            }
+        }
+    }
+}
+
+/// A zone's recent block counts
+#[derive(Default)]
+struct ZoneRate {
+    last_total: u64,
+    /// Blocks seen in each of the most recent samples, newest last
+    window: VecDeque<u64>,
+    last_alert: Option<Instant>,
+}
+
+/// Tracks every zone's block rate and decides when a zone should be alerted on
+struct Detector {
+    /// Number of samples making up the window
+    window: usize,
+    /// Blocks per sample that every sample in the window has to reach
+    rate: u64,
+    cooldown: Duration,
+    zones: HashMap<Zonedid, ZoneRate>,
+}
+
+impl Detector {
+    fn new(config: &AlertConfig) -> Detector {
+        Detector {
+            window: config.window_secs as usize,
+            rate: config.block_rate,
+            cooldown: Duration::from_secs(config.cooldown_secs),
+            zones: HashMap::new(),
+        }
+    }
+
+    /// Record a zone's block total as of `now`, returning the number of blocks in the window if
+    /// the zone should be alerted on. A zone's first sample only sets its baseline so that blocks
+    /// from before the zone was being tracked aren't counted.
+    fn sample(&mut self, zonedid: Zonedid, total: u64, now: Instant) -> Option<u64> {
+        let (window, rate, cooldown) = (self.window, self.rate, self.cooldown);
+        let zone = match self.zones.get_mut(&zonedid) {
+            Some(zone) => zone,
+            None => {
+                self.zones.insert(
+                    zonedid,
+                    ZoneRate {
+                        last_total: total,
+                        ..ZoneRate::default()
+                    },
+                );
+                return None;
+            }
+        };
+        zone.window.push_back(total.saturating_sub(zone.last_total));
+        zone.last_total = total;
+        if zone.window.len() > window {
+            zone.window.pop_front();
+        }
+        let sustained = zone.window.len() == window && zone.window.iter().all(|&n| n >= rate);
+        let cooling_down = zone
+            .last_alert
+            .map_or(false, |last| now.duration_since(last) < cooldown);
+        if !sustained || cooling_down {
+            return None;
+        }
+        zone.last_alert = Some(now);
+        Some(zone.window.iter().sum())
+    }
+}
+
+/// Start the thread that watches every zone's block rate and alerts through `alerter`
+pub fn start_alerts(
+    mut alerter: Alerter,
+    stats: Stats,
+    vmobjs: Vmobjs,
+    clock: SharedClock,
+) -> io::Result<thread::JoinHandle<()>> {
+    let mut detector = Detector::new(&alerter.config);
+    thread::Builder::new()
+        .name("alerts".to_owned())
+        .spawn(move || loop {
+            thread::sleep(SAMPLE_INTERVAL);
+            let now = clock.now();
+            let filtered = alerter.config.filter.is_some();
+            let totals: Vec<(Zonedid, u64)> = stats
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(zonedid, counters)| {
+                    let total = if filtered {
+                        counters.alerting_total()
+                    } else {
+                        counters.totals().block
+                    };
+                    (*zonedid, total)
+                })
+                .collect();
+            for (zonedid, total) in totals {
+                let blocks = match detector.sample(zonedid, total, now) {
+                    Some(blocks) => blocks,
+                    None => continue,
+                };
+                let (vm, alias) = match vmobjs.load().get(&zonedid) {
+                    Some(vm) => (vm.uuid.clone(), vm.alias.clone().unwrap_or_default()),
+                    None => continue,
+                };
+                let alert = Alert {
+                    vm,
+                    alias,
+                    blocks,
+                    window_secs: alerter.config.window_secs,
+                    block_rate: alerter.config.block_rate,
+                    timestamp: clock.utc(),
+                };
+                alerter.send(&alert);
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AlertConfig {
+        AlertConfig {
+            block_rate: 10,
+            window_secs: 3,
+            cooldown_secs: 60,
+            webhook: None,
+            snmp: None,
+            filter: None,
+        }
+    }
+
+    #[test]
+    fn sustained_block_rates() {
+        let mut detector = Detector::new(&config());
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(
+            detector.sample(1, 1000, at(0)),
+            None,
+            "first sample is a baseline"
+        );
+        assert_eq!(
+            detector.sample(1, 1100, at(1)),
+            None,
+            "window isn't full yet"
+        );
+        assert_eq!(detector.sample(1, 1100, at(2)), None);
+        assert_eq!(
+            detector.sample(1, 1100, at(3)),
+            None,
+            "a single burst isn't sustained"
+        );
+        assert_eq!(detector.sample(1, 1110, at(4)), None);
+        assert_eq!(detector.sample(1, 1120, at(5)), None);
+        assert_eq!(detector.sample(1, 1130, at(6)), Some(30), "10/s for 3s");
+        assert_eq!(detector.sample(1, 1200, at(7)), None, "cooling down");
+        assert_eq!(
+            detector.sample(1, 2000, at(70)),
+            Some(880),
+            "alerts again after the cooldown"
+        );
+    }
+
+    #[test]
+    fn ber_encoding() {
+        assert_eq!(
+            ber_oid(SYS_UPTIME_OID),
+            [0x06, 0x08, 0x2b, 6, 1, 2, 1, 1, 3, 0]
+        );
+        assert_eq!(
+            ber_oid(&[1, 3, 6, 1, 4, 1, 8072]),
+            [0x06, 0x07, 0x2b, 6, 1, 4, 1, 0xbf, 0x08]
+        );
+        assert_eq!(ber_integer(0x02, 0), [0x02, 0x01, 0x00]);
+        assert_eq!(ber_integer(0x02, 128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(ber_integer(0x02, -1), [0x02, 0x01, 0xff]);
+        assert_eq!(ber_tlv(0x04, &[0; 200])[..3], [0x04, 0x81, 200]);
+    }
+
+    #[test]
+    fn trap_messages() {
+        let alert = Alert {
+            vm: "vm1".to_owned(),
+            alias: String::new(),
+            blocks: 30,
+            window_secs: 3,
+            block_rate: 10,
+            timestamp: Utc::now(),
+        };
+        let oid = parse_oid("1.3.6.1.4.1.8072.9999.9999").unwrap();
+        let trap = encode_trap("public", 1, Duration::from_secs(1), &oid, &alert);
+        assert_eq!(trap[0], 0x30, "message is a sequence");
+        assert_eq!(
+            usize::from(trap[1]),
+            trap.len() - 2,
+            "length covers the message"
+        );
+        assert_eq!(
+            &trap[2..13],
+            &[0x02, 0x01, 0x01, 0x04, 0x06, b'p', b'u', b'b', b'l', b'i', b'c'],
+            "v2c message for the community"
+        );
+        assert_eq!(trap[13], 0xa7, "SNMPv2-Trap-PDU");
+
+        assert!(parse_oid("1.3.6.1.x").is_err());
+        assert!(parse_oid("3.3").is_err());
+        assert_eq!(parse_oid(".1.3.6").unwrap(), vec![1, 3, 6]);
+    }
+}
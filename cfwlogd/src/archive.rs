@@ -0,0 +1,426 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Processing applied to a zone's log files after they have been rotated out from underneath
+//! cfwlogd (by logadm renaming current.log and sending us a SIGUSR1), or by cfwlogd itself. Once a
+//! `Logger` has opened its next log file every other log file in the zone's directory is
+//! complete, so it's safe to process them before they are picked up for archival. When handoff
+//! markers are enabled every finalized file also gets a "<file>.done" marker describing it, which
+//! tells log shippers the file is safe to pick up.
+
+use crate::config::Config;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+/// Suffix of the marker written next to a finalized file
+const MARKER_SUFFIX: &str = ".done";
+
+/// Contents of a handoff marker
+#[derive(Debug, Serialize)]
+struct Marker {
+    /// Name of the finalized file, relative to the zone's log directory
+    file: String,
+    size: u64,
+    /// Hex encoded sha256 of the finalized file
+    sha256: String,
+    /// Number of records in the file, counted before it was encrypted
+    records: u64,
+}
+
+/// The file in a zone's log directory that cfwlogd keeps open besides its active log
+const STATS_FILE: &str = "stats.log";
+
+/// Returns the rotated log files found in a zone's log directory, in which `active` is the log
+/// being written to. Any file ending in ".log" that cfwlogd isn't actively writing to is
+/// considered to be rotated.
+pub fn rotated_files(dir: &Path, active: &str) -> io::Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_log = path.extension().map_or(false, |ext| ext == "log");
+        let is_active = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name == active || name == STATS_FILE);
+        if is_log && !is_active && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// The rotated files in `dir` that `process_rotated` is done with: the encrypted ones with
+/// encryption, those with a handoff marker with markers, and otherwise every rotated file.
+pub fn finalized_files(dir: &Path, active: &str, config: &Config) -> io::Result<Vec<PathBuf>> {
+    let mut files = if config.encryption.is_some() {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str());
+            if name.map_or(false, |name| name.ends_with(".log.age")) && path.is_file() {
+                files.push(path);
+            }
+        }
+        files.sort();
+        files
+    } else {
+        rotated_files(dir, active)?
+    };
+    if config.handoff_markers {
+        files.retain(|file| with_suffix(file, MARKER_SUFFIX).exists());
+    }
+    Ok(files)
+}
+
+/// Remove a finalized file along with its handoff marker, once it's been shipped elsewhere
+pub fn remove_finalized(path: &Path) -> io::Result<()> {
+    std::fs::remove_file(path)?;
+    match std::fs::remove_file(with_suffix(path, MARKER_SUFFIX)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Move a finalized file along with its handoff marker into `dir`, once it won't be shipped
+pub fn move_finalized(path: &Path, dir: &Path) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    std::fs::create_dir_all(dir)?;
+    std::fs::rename(path, dir.join(name))?;
+    let marker = with_suffix(path, MARKER_SUFFIX);
+    match std::fs::rename(&marker, dir.join(marker.file_name().unwrap_or(name))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Run all of the configured post-rotation processing on the rotated files in `dir`.
+pub fn process_rotated(dir: &Path, active: &str, config: &Config) {
+    if config.encryption.is_none() && !config.handoff_markers {
+        return;
+    }
+
+    let files = match rotated_files(dir, active) {
+        Ok(files) => files,
+        Err(e) => {
+            error!("failed to find rotated logs in {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    for file in files {
+        // Without encryption rotated files stay where they are, so they are seen again after
+        // every rotation.
+        if with_suffix(&file, MARKER_SUFFIX).exists() {
+            continue;
+        }
+        // Records have to be counted before the plaintext is gone
+        let records = if config.handoff_markers {
+            match count_records(&file) {
+                Ok(records) => records,
+                Err(e) => {
+                    error!("failed to count records in {}: {}", file.display(), e);
+                    continue;
+                }
+            }
+        } else {
+            0
+        };
+        let finalized = match &config.encryption {
+            Some(encryption) => match encrypt_file(&file, &encryption.recipients) {
+                Ok(encrypted) => {
+                    info!("encrypted {} to {}", file.display(), encrypted.display());
+                    encrypted
+                }
+                Err(e) => {
+                    error!("failed to encrypt {}: {}", file.display(), e);
+                    continue;
+                }
+            },
+            None => file,
+        };
+        if config.handoff_markers {
+            if let Err(e) = write_marker(&finalized, records) {
+                error!("failed to write marker for {}: {}", finalized.display(), e);
+            }
+        }
+    }
+}
+
+/// Count the newline separated records in a log file
+fn count_records(path: &Path) -> io::Result<u64> {
+    let mut file = File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut records = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(records),
+            n => records += buf[..n].iter().filter(|&&b| b == b'\n').count() as u64,
+        }
+    }
+}
+
+/// Write the handoff marker for a finalized file. The marker is written to a temporary file and
+/// renamed into place so a shipper never sees a partial marker.
+fn write_marker(path: &Path, records: u64) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        match file.read(&mut buf)? {
+            0 => break,
+            n => {
+                hasher.update(&buf[..n]);
+                size += n as u64;
+            }
+        }
+    }
+    let marker = Marker {
+        file: path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        size,
+        sha256: hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+        records,
+    };
+    let mut contents = serde_json::to_vec(&marker)?;
+    contents.push(b'\n');
+    let marker_path = with_suffix(path, MARKER_SUFFIX);
+    let tmp_path = with_suffix(&marker_path, ".tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, &marker_path)
+}
+
+/// Returns `path` with the given suffix tacked on to the end of it
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(suffix);
+    PathBuf::from(s)
+}
+
+/// Verify that the provided recipients are usable for encrypting rotated files.
+#[cfg(feature = "encryption")]
+pub fn check_recipients(recipients: &[String]) -> Result<(), String> {
+    parse_recipients(recipients).map(|_| ())
+}
+
+/// Verify that the provided recipients are usable for encrypting rotated files.
+#[cfg(not(feature = "encryption"))]
+pub fn check_recipients(_recipients: &[String]) -> Result<(), String> {
+    Err("cfwlogd was built without the \"encryption\" feature".to_owned())
+}
+
+#[cfg(feature = "encryption")]
+fn parse_recipients(recipients: &[String]) -> Result<Vec<Box<dyn age::Recipient + Send>>, String> {
+    if recipients.is_empty() {
+        return Err("encryption requires at least one recipient".to_owned());
+    }
+    recipients
+        .iter()
+        .map(|r| {
+            r.parse::<age::x25519::Recipient>()
+                .map(|r| Box::new(r) as Box<dyn age::Recipient + Send>)
+                .map_err(|e| format!("invalid recipient \"{}\": {}", r, e))
+        })
+        .collect()
+}
+
+/// Encrypt `path` to each of the recipients, writing the result alongside the original with an
+/// ".age" extension. The plaintext file is only removed once the encrypted copy has been synced
+/// to disk and renamed into place.
+#[cfg(feature = "encryption")]
+fn encrypt_file(path: &Path, recipients: &[String]) -> io::Result<PathBuf> {
+    let recipients =
+        parse_recipients(recipients).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let encryptor = age::Encryptor::with_recipients(recipients)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no encryption recipients"))?;
+
+    let tmp_path = with_suffix(path, ".age.tmp");
+    let out_path = with_suffix(path, ".age");
+    let mut input = File::open(path)?;
+    let mut writer = encryptor
+        .wrap_output(File::create(&tmp_path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    io::copy(&mut input, &mut writer)?;
+    writer.finish()?.sync_all()?;
+    std::fs::rename(&tmp_path, &out_path)?;
+    std::fs::remove_file(path)?;
+    Ok(out_path)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn encrypt_file(_path: &Path, _recipients: &[String]) -> io::Result<PathBuf> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "cfwlogd was built without the \"encryption\" feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LOG_DIR;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir: PathBuf = [LOG_DIR, "archive-tests", name].iter().collect();
+        std::fs::create_dir_all(&dir).expect("failed to create test dir");
+        dir
+    }
+
+    #[test]
+    fn rotated_files_skips_active_files() {
+        let dir = test_dir("rotated");
+        for name in &[
+            "current.log",
+            "stats.log",
+            "2020-01-01T00:00:00.log",
+            "2020-01-01T01:00:00.log",
+            "2020-01-01T02:00:00.log.age",
+            "2020-01-01T00:00:00.log.done",
+        ] {
+            std::fs::write(dir.join(name), b"{}\n").expect("failed to write test file");
+        }
+
+        let files = rotated_files(&dir, "current.log").expect("failed to read rotated files");
+        assert_eq!(
+            files,
+            vec![
+                dir.join("2020-01-01T00:00:00.log"),
+                dir.join("2020-01-01T01:00:00.log")
+            ],
+            "only rotated plaintext logs are returned"
+        );
+        std::fs::remove_dir_all(dir).expect("failed to cleanup test dir");
+    }
+
+    #[test]
+    fn handoff_markers() {
+        let dir = test_dir("markers");
+        let path = dir.join("2020-01-01T00:00:00.log");
+        std::fs::write(&path, b"{\"event\":\"block\"}\n{\"event\":\"begin\"}\n")
+            .expect("failed to write test file");
+        let config = Config {
+            handoff_markers: true,
+            ..Config::default()
+        };
+        process_rotated(&dir, "current.log", &config);
+
+        let marker_path = dir.join("2020-01-01T00:00:00.log.done");
+        let contents = std::fs::read_to_string(&marker_path).expect("marker was written");
+        let marker: serde_json::Value = serde_json::from_str(&contents).unwrap();
+        assert_eq!(marker["file"], "2020-01-01T00:00:00.log");
+        assert_eq!(marker["size"], 36);
+        assert_eq!(marker["records"], 2);
+        assert_eq!(
+            marker["sha256"],
+            "c787252158feb4777266d541cf02f3e8f3e34db99962466c88422ef969149e62"
+        );
+
+        // A file that was already handed off is left alone
+        std::fs::write(&path, b"").unwrap();
+        process_rotated(&dir, "current.log", &config);
+        assert_eq!(std::fs::read_to_string(&marker_path).unwrap(), contents);
+
+        // Only files with a marker are finalized, and they go along with their marker
+        std::fs::write(dir.join("2020-01-01T01:00:00.log"), b"").unwrap();
+        assert_eq!(
+            finalized_files(&dir, "current.log", &config).unwrap(),
+            vec![path.clone()]
+        );
+        remove_finalized(&path).unwrap();
+        assert!(!path.exists() && !marker_path.exists());
+
+        std::fs::remove_dir_all(dir).expect("failed to cleanup test dir");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypt_file_round_trip() {
+        use std::io::Read;
+
+        let dir = test_dir("encrypt");
+        let path = dir.join("2020-01-01T00:00:00.log");
+        std::fs::write(&path, b"{\"event\":\"block\"}\n").expect("failed to write test file");
+
+        let identity = age::x25519::Identity::generate();
+        let recipients = vec![identity.to_public().to_string()];
+        let encrypted = encrypt_file(&path, &recipients).expect("failed to encrypt file");
+        assert!(!path.exists(), "plaintext file was removed");
+
+        let decryptor = match age::Decryptor::new(std::fs::File::open(&encrypted).unwrap()) {
+            Ok(age::Decryptor::Recipients(d)) => d,
+            _ => panic!("file was not encrypted to recipients"),
+        };
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .expect("failed to decrypt file");
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "{\"event\":\"block\"}\n", "contents round trip");
+
+        std::fs::remove_dir_all(dir).expect("failed to cleanup test dir");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encrypted_rotation_round_trip() {
+        use crate::config::EncryptionConfig;
+        use std::io::Read;
+
+        let dir = test_dir("encrypt-rotated");
+        let path = dir.join("2020-01-01T00:00:00.log");
+        let plaintext = "{\"event\":\"block\"}\n{\"event\":\"begin\"}\n";
+        std::fs::write(&path, plaintext).expect("failed to write test file");
+        std::fs::write(dir.join("current.log"), b"").expect("failed to write test file");
+
+        let identity = age::x25519::Identity::generate();
+        let config = Config {
+            encryption: Some(EncryptionConfig {
+                recipients: vec![identity.to_public().to_string()],
+            }),
+            handoff_markers: true,
+            ..Config::default()
+        };
+        process_rotated(&dir, "current.log", &config);
+        assert!(!path.exists(), "plaintext file was removed");
+        assert!(dir.join("current.log").exists(), "the active log is kept");
+
+        let encrypted = dir.join("2020-01-01T00:00:00.log.age");
+        assert_eq!(
+            finalized_files(&dir, "current.log", &config).unwrap(),
+            vec![encrypted.clone()]
+        );
+        let marker = std::fs::read_to_string(dir.join("2020-01-01T00:00:00.log.age.done"))
+            .expect("marker was written");
+        let marker: serde_json::Value = serde_json::from_str(&marker).unwrap();
+        assert_eq!(marker["file"], "2020-01-01T00:00:00.log.age");
+        assert_eq!(marker["records"], 2, "records are counted in the plaintext");
+
+        let decryptor = match age::Decryptor::new(std::fs::File::open(&encrypted).unwrap()) {
+            Ok(age::Decryptor::Recipients(d)) => d,
+            _ => panic!("file was not encrypted to recipients"),
+        };
+        let mut reader = decryptor
+            .decrypt(std::iter::once(&identity as &dyn age::Identity))
+            .expect("failed to decrypt file");
+        let mut contents = String::new();
+        reader.read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, plaintext, "contents round trip");
+
+        std::fs::remove_dir_all(dir).expect("failed to cleanup test dir");
+    }
+}
@@ -0,0 +1,209 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A debugging aid that verifies every event read from the device is accounted for. When the
+//! "loss_audit" option is enabled the device reader stamps each event with an ingest sequence
+//! number, and every place an event leaves the pipeline records what happened to it: either a
+//! `Logger` wrote it out, or it was dropped somewhere along the way. At shutdown the `LossAudit`
+//! produces a report of any sequence numbers that were never accounted for or were written more
+//! than once. When the audit is disabled every method is a no-op.
+
+use cfwevent::parser::CfwEvent;
+use serde::Serialize;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Limit on the number of sequence numbers listed in each section of the report
+const MAX_REPORTED_SEQUENCES: usize = 100;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Outcome {
+    written: u32,
+    dropped: u32,
+}
+
+#[derive(Debug)]
+pub struct LossAudit {
+    enabled: bool,
+    /// The next sequence number to hand out
+    next: AtomicU64,
+    /// Indexed by sequence number
+    outcomes: Mutex<Vec<Outcome>>,
+}
+
+/// What happened to every event ingested while the audit was enabled
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AuditReport {
+    pub ingested: u64,
+    pub written: u64,
+    pub dropped: u64,
+    /// Events that were never written or dropped
+    pub missing: u64,
+    /// Events that were written more than once, or both written and dropped
+    pub duplicated: u64,
+    pub missing_sequences: Vec<u64>,
+    pub duplicated_sequences: Vec<u64>,
+}
+
+impl AuditReport {
+    /// True if every ingested event was written or dropped exactly once
+    pub fn is_clean(&self) -> bool {
+        self.missing == 0 && self.duplicated == 0
+    }
+}
+
+impl LossAudit {
+    pub fn new(enabled: bool) -> Self {
+        LossAudit {
+            enabled,
+            next: AtomicU64::new(0),
+            outcomes: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Assign the next ingest sequence number to an event that was just read from the device
+    pub fn stamp(&self, event: &mut CfwEvent) {
+        if self.enabled {
+            event.set_seq(self.next.fetch_add(1, Ordering::Relaxed));
+        }
+    }
+
+    /// Returns the sequence numbers of the given events, or nothing if the audit is disabled
+    pub fn sequences<'a, I: IntoIterator<Item = &'a CfwEvent>>(&self, events: I) -> Vec<u64> {
+        if !self.enabled {
+            return vec![];
+        }
+        events.into_iter().map(CfwEvent::seq).collect()
+    }
+
+    fn record<F: Fn(&mut Outcome)>(&self, seqs: &[u64], f: F) {
+        if !self.enabled || seqs.is_empty() {
+            return;
+        }
+        let mut outcomes = self.outcomes.lock().unwrap();
+        for &seq in seqs {
+            let idx = seq as usize;
+            if idx >= outcomes.len() {
+                outcomes.resize(idx + 1, Outcome::default());
+            }
+            f(&mut outcomes[idx]);
+        }
+    }
+
+    /// Record that the events with the given sequence numbers were written out
+    pub fn written(&self, seqs: &[u64]) {
+        self.record(seqs, |outcome| outcome.written += 1);
+    }
+
+    /// Record that an event was intentionally dropped
+    pub fn dropped(&self, event: &CfwEvent) {
+        self.record(&[event.seq()], |outcome| outcome.dropped += 1);
+    }
+
+    /// Record that the events with the given sequence numbers were intentionally dropped
+    pub fn dropped_sequences(&self, seqs: &[u64]) {
+        self.record(seqs, |outcome| outcome.dropped += 1);
+    }
+
+    /// Verify that every ingested event was accounted for exactly once
+    pub fn report(&self) -> AuditReport {
+        let ingested = self.next.load(Ordering::Relaxed);
+        let outcomes = self.outcomes.lock().unwrap();
+        let mut report = AuditReport {
+            ingested,
+            ..AuditReport::default()
+        };
+        for seq in 0..ingested {
+            let outcome = outcomes.get(seq as usize).cloned().unwrap_or_default();
+            report.written += u64::from(outcome.written);
+            report.dropped += u64::from(outcome.dropped);
+            match outcome.written + outcome.dropped {
+                0 => {
+                    report.missing += 1;
+                    if report.missing_sequences.len() < MAX_REPORTED_SEQUENCES {
+                        report.missing_sequences.push(seq);
+                    }
+                }
+                1 => (),
+                _ => {
+                    report.duplicated += 1;
+                    if report.duplicated_sequences.len() < MAX_REPORTED_SEQUENCES {
+                        report.duplicated_sequences.push(seq);
+                    }
+                }
+            }
+        }
+        report
+    }
+
+    /// Log a summary of the report and write it out in full as json to `path`
+    pub fn write_report<P: AsRef<Path>>(&self, path: P) -> std::io::Result<AuditReport> {
+        let report = self.report();
+        if report.is_clean() {
+            info!(
+                "loss audit: all {} events accounted for ({} written, {} dropped)",
+                report.ingested, report.written, report.dropped
+            );
+        } else {
+            error!(
+                "loss audit: {} of {} events missing, {} duplicated",
+                report.missing, report.ingested, report.duplicated
+            );
+        }
+        let mut json = serde_json::to_vec_pretty(&report)?;
+        json.push(b'\n');
+        std::fs::write(path, json)?;
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    fn events(n: usize) -> Vec<CfwEvent> {
+        let event = testutils::generate_event();
+        (0..n)
+            .map(|_| parser::cfwevent_parse(event.as_bytes()).unwrap().1)
+            .collect()
+    }
+
+    #[test]
+    fn disabled_audit_does_nothing() {
+        let audit = LossAudit::new(false);
+        let mut events = events(2);
+        events.iter_mut().for_each(|e| audit.stamp(e));
+        assert!(audit.sequences(&events).is_empty());
+        assert_eq!(audit.report(), AuditReport::default());
+    }
+
+    #[test]
+    fn report_finds_missing_and_duplicated_events() {
+        let audit = LossAudit::new(true);
+        let mut events = events(4);
+        events.iter_mut().for_each(|e| audit.stamp(e));
+        assert_eq!(audit.sequences(&events), vec![0, 1, 2, 3]);
+
+        audit.written(&[0, 1]);
+        audit.dropped(&events[2]);
+        assert_eq!(audit.report().missing_sequences, vec![3]);
+
+        audit.written(&[1, 3]);
+        let report = audit.report();
+        assert_eq!(report.ingested, 4);
+        assert_eq!(report.written, 4);
+        assert_eq!(report.dropped, 1);
+        assert_eq!(report.missing, 0);
+        assert_eq!(report.duplicated_sequences, vec![1]);
+        assert!(!report.is_clean());
+    }
+}
@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Per-zone disk budgets, so that one noisy zone can't take up the whole log filesystem. With
+//! "disk_budget" configured each zone's `ZoneLog` adds up the files in its log directory every
+//! "check_secs", its current and rotated logs included. While that's over the zone's budget its
+//! events are no longer written to any sink: they're counted by rule in summary records like the
+//! events of "count_only" rules, or with the "drop" action dropped and summarized as suppressed
+//! with the reason `disk_budget`. cfwlogd's own records, such as those summaries, are still
+//! written, and the zone goes back to logging its events once retention, an upload or an operator
+//! brings it back under its budget. Going over and coming back under are each marked in the
+//! zone's log with a `BudgetNotice`.
+
+use crate::config::{BudgetAction, DiskBudgetConfig};
+use crate::sink::SchemaVersion;
+use crate::stats::ZoneCounters;
+use cfwevent::parser;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io;
+use std::path::Path;
+
+/// Whether a zone went over its budget or came back under it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transition {
+    Exceeded,
+    Restored,
+}
+
+/// Logged when a zone goes over its budget or comes back under it
+#[derive(Serialize)]
+pub struct BudgetNotice<'a> {
+    pub schema_version: SchemaVersion,
+    pub event: &'static str,
+    pub vm: &'a str,
+    pub state: Transition,
+    /// What happens to the zone's events while it's over its budget
+    pub action: BudgetAction,
+    pub used_bytes: u64,
+    pub budget_bytes: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// The bytes taken up by the files in `dir`, leaving out anything that isn't a file
+pub fn dir_bytes(dir: &Path) -> io::Result<u64> {
+    let mut bytes = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let metadata = match entry.and_then(|entry| entry.metadata()) {
+            Ok(metadata) => metadata,
+            // Rotated away or removed since the directory was read
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        if metadata.is_file() {
+            bytes += metadata.len();
+        }
+    }
+    Ok(bytes)
+}
+
+/// Record whether a zone using `used` bytes is over its budget, returning how that changed
+fn update(counters: &ZoneCounters, used: u64, budget: u64) -> Option<Transition> {
+    let over = used > budget;
+    match (counters.set_over_budget(over), over) {
+        (false, true) => Some(Transition::Exceeded),
+        (true, false) => Some(Transition::Restored),
+        _ => None,
+    }
+}
+
+/// Measure the zone's log directory against its budget, returning the notice to log if the zone
+/// went over or came back under
+pub fn check<'a>(
+    dir: &Path,
+    vm: &'a str,
+    config: &DiskBudgetConfig,
+    counters: &ZoneCounters,
+    at: DateTime<Utc>,
+) -> io::Result<Option<BudgetNotice<'a>>> {
+    let used = dir_bytes(dir)?;
+    let budget = config.bytes_for(vm);
+    Ok(update(counters, used, budget).map(|state| BudgetNotice {
+        schema_version: SchemaVersion,
+        event: "disk_budget",
+        vm,
+        state,
+        action: config.action,
+        used_bytes: used,
+        budget_bytes: budget,
+        timestamp: at,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LOG_DIR;
+    use std::path::PathBuf;
+
+    #[test]
+    fn zones_go_over_and_back_under() {
+        let dir: PathBuf = [LOG_DIR, "budget-tests"].iter().collect();
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        std::fs::write(dir.join("current.log"), vec![b'x'; 600]).unwrap();
+        std::fs::write(dir.join("20200102T030405Z.log"), vec![b'x'; 500]).unwrap();
+        std::fs::write(dir.join("subdir").join("ignored"), vec![b'x'; 1000]).unwrap();
+        assert_eq!(dir_bytes(&dir).unwrap(), 1100);
+
+        let config = DiskBudgetConfig {
+            bytes: 1000,
+            zones: Default::default(),
+            action: BudgetAction::CountOnly,
+            check_secs: 60,
+        };
+        let counters = ZoneCounters::default();
+        let at = Utc::now();
+        let notice = check(&dir, "vm1", &config, &counters, at).unwrap().unwrap();
+        assert_eq!(notice.state, Transition::Exceeded);
+        assert_eq!((notice.used_bytes, notice.budget_bytes), (1100, 1000));
+        assert!(counters.over_budget());
+        assert!(
+            check(&dir, "vm1", &config, &counters, at)
+                .unwrap()
+                .is_none(),
+            "staying over isn't noticed again"
+        );
+
+        std::fs::remove_file(dir.join("20200102T030405Z.log")).unwrap();
+        let notice = check(&dir, "vm1", &config, &counters, at).unwrap().unwrap();
+        assert_eq!(notice.state, Transition::Restored);
+        assert!(!counters.over_budget());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
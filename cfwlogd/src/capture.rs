@@ -0,0 +1,95 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd capture <file> [<device>]` records the raw bytes of every read from an ipfev device,
+//! /dev/ipfev unless another is given, into a capture file until it's interrupted, so that a
+//! problem seen on a CN can be reproduced elsewhere by replaying the capture, see the "replay"
+//! module.
+//!
+//! A capture file starts with `MAGIC`, followed by a frame for every read. Each frame is the time
+//! of the read in microseconds since the epoch as a little endian i64, the number of bytes read
+//! as a little endian u32, and then the bytes themselves. Frames are written out as they are
+//! read, so an interrupted capture holds every read up to its last frame.
+
+use crate::ipf::IpfevDevice;
+use crate::source::EventSource;
+use chrono::{DateTime, Utc};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Every capture file starts with these bytes
+pub const MAGIC: &[u8; 8] = b"cfwcap01";
+
+/// The timestamp and length every frame starts with
+pub const FRAME_HEADER_LEN: usize = 12;
+
+/// Path to the illumos ipfilter event device captured by default
+const IPFEV_DEVICE: &str = "/dev/ipfev";
+
+/// Writes reads of an event source to a capture file
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(CaptureWriter { writer })
+    }
+
+    /// Write the bytes of a read made at `at` as a single frame
+    pub fn write_frame(&mut self, at: DateTime<Utc>, bytes: &[u8]) -> io::Result<()> {
+        let micros = at.timestamp() * 1_000_000 + i64::from(at.timestamp_subsec_micros());
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + bytes.len());
+        frame.extend_from_slice(&micros.to_le_bytes());
+        frame.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        frame.extend_from_slice(bytes);
+        self.writer.write_all(&frame)
+    }
+}
+
+/// Capture reads of `device` into `path` until reading fails
+fn capture(path: &Path, device: &str) -> io::Result<u64> {
+    let mut device = IpfevDevice::new(device)?;
+    let (max, ringsize) = device.event_sizing()?;
+    let mut buf = vec![0; max * ringsize];
+    let mut writer = CaptureWriter::new(File::create(path)?)?;
+    let mut frames = 0;
+    loop {
+        let size = match device.read_events(&mut buf) {
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("stopped after {} reads: {}", frames, e);
+                return Ok(frames);
+            }
+        };
+        writer.write_frame(Utc::now(), &buf[..size])?;
+        frames += 1;
+    }
+}
+
+pub fn run(args: &[String]) -> i32 {
+    let (file, device) = match args {
+        [file] => (file, IPFEV_DEVICE),
+        [file, device] => (file, device.as_str()),
+        _ => {
+            eprintln!("usage: cfwlogd capture <file> [<device>]");
+            return 2;
+        }
+    };
+    match capture(Path::new(file), device) {
+        Ok(frames) => {
+            println!("{}: captured {} reads from {}", file, frames, device);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: {}", file, e);
+            1
+        }
+    }
+}
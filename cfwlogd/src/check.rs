@@ -0,0 +1,72 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd check-config [<file>]` validates a config file, the one cfwlogd loads unless another
+//! is given, the same way cfwlogd does at startup and on SIGHUP, so an SMF start method or fleet
+//! tooling can catch a bad config before restarting the service. It prints what's wrong, with the
+//! line and column of a parse error, and exits with 1 if cfwlogd would refuse the file. A missing
+//! default file is fine since cfwlogd runs with the defaults then, but a named file has to exist.
+//! What can only be found out by starting, such as whether "user" exists or a plugin loads, isn't
+//! checked.
+
+use crate::config::{self, Config};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Load and validate the config file at `path`
+fn check(path: &Path) -> Result<Config, config::Error> {
+    let s = fs::read_to_string(path)?;
+    Config::from_toml(&s)
+}
+
+/// Run the "check-config" subcommand, returning the process's exit code.
+pub fn run(args: &[String]) -> i32 {
+    let (path, named) = match args {
+        [] => (config::CONFIG_FILE, false),
+        [path] if !path.starts_with('-') => (path.as_str(), true),
+        _ => {
+            eprintln!("usage: cfwlogd check-config [<file>]");
+            return 2;
+        }
+    };
+    match check(Path::new(path)) {
+        Ok(_) => {
+            println!("{}: ok", path);
+            0
+        }
+        Err(config::Error::Io(ref e)) if e.kind() == io::ErrorKind::NotFound && !named => {
+            println!("{}: not found, cfwlogd runs with the defaults", path);
+            0
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LOG_DIR;
+
+    #[test]
+    fn configs_are_checked() {
+        fs::create_dir_all(LOG_DIR).unwrap();
+        let path = Path::new(LOG_DIR).join("check-config-test.toml");
+        fs::write(&path, "logger_workers = 4\n").unwrap();
+        assert_eq!(check(&path).unwrap().logger_workers, Some(4));
+
+        fs::write(&path, "logger_workers = 0\n").unwrap();
+        assert!(matches!(check(&path), Err(config::Error::Invalid(_))));
+        fs::write(&path, "logger_workers = \n").unwrap();
+        assert!(matches!(check(&path), Err(config::Error::Toml(_))));
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(check(&path), Err(config::Error::Io(_))));
+    }
+}
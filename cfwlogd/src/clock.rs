@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The source of time for everything that runs on an interval or stamps the records cfwlogd
+//! generates itself, such as rotation periods, periodic summaries and alert windows. Production
+//! code uses `SystemClock`, and tests use `ManualClock` to step time forward deterministically
+//! rather than sleeping.
+
+use chrono::{DateTime, Utc};
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+#[cfg(test)]
+use std::time::Duration;
+use std::time::Instant;
+
+pub trait Clock: Send + Sync {
+    /// Monotonic time, for measuring intervals
+    fn now(&self) -> Instant;
+    /// Wall clock time, for timestamps
+    fn utc(&self) -> DateTime<Utc>;
+}
+
+/// A `Clock` shared by every thread
+pub type SharedClock = Arc<dyn Clock>;
+
+/// The system's clocks
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The high resolution clock in nanoseconds, which only ever moves forward. This is gethrtime(3C)
+/// on illumos, where CLOCK_MONOTONIC is the same clock.
+pub fn hrtime() -> u64 {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Only fails for an invalid clock
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+/// A clock that only moves when it's told to
+#[cfg(test)]
+pub struct ManualClock {
+    start: Instant,
+    start_utc: DateTime<Utc>,
+    elapsed: Mutex<Duration>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new() -> Arc<ManualClock> {
+        Arc::new(ManualClock {
+            start: Instant::now(),
+            start_utc: Utc::now(),
+            elapsed: Mutex::new(Duration::from_secs(0)),
+        })
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn utc(&self) -> DateTime<Utc> {
+        let elapsed = *self.elapsed.lock().unwrap();
+        self.start_utc + chrono::Duration::from_std(elapsed).expect("elapsed time overflowed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new();
+        let (now, utc) = (clock.now(), clock.utc());
+        assert_eq!(clock.now(), now, "time stands still");
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(clock.now() - now, Duration::from_secs(90));
+        assert_eq!((clock.utc() - utc).num_seconds(), 90);
+    }
+}
@@ -0,0 +1,474 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that ships records to Amazon CloudWatch Logs with `PutLogEvents` requests. Each zone
+//! writes to a log stream of its own in "log_group", named by the "log_stream" template in which
+//! `{owner_uuid}` and `{vm}` are substituted, and the stream is created the first time CloudWatch
+//! says it doesn't exist. The group has to exist already.
+//!
+//! Each zone's sink batches its records until it has "batch_size" of them or "flush_secs" have
+//! passed. A batch is sent in as many requests as it takes to stay under the API's limits: at
+//! most `MAX_BATCH_EVENTS` events and `MAX_BATCH_BYTES` bytes per request, events in
+//! chronological order, and no more than `MAX_BATCH_SPAN` between a request's first and last
+//! event. Every stream has a sequence token that each request has to carry, which is taken from
+//! each response, and from the error CloudWatch answers with when the token we have is stale.
+//!
+//! Requests are signed with AWS Signature Version 4, see the "sigv4" module. Like the "http"
+//! module the endpoint's address is resolved once at startup. While CloudWatch is unreachable or
+//! throttling us a request is retried with exponential backoff up to "max_backoff_secs", on the
+//! zone's `Logger` thread as with the "elasticsearch" sink, and records CloudWatch refuses for any
+//! other reason are logged and dropped.
+
+use crate::config::{CloudwatchConfig, Config};
+use crate::sigv4::{self, Credentials};
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use cfwevent::parser::CfwEvent;
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait on CloudWatch before giving up on a request
+const REQUEST_TIMEOUT_MS: u64 = 30_000;
+/// The first delay before retrying a request
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// The most events `PutLogEvents` takes at a time
+const MAX_BATCH_EVENTS: usize = 10_000;
+/// The most bytes `PutLogEvents` takes at a time, counting `EVENT_OVERHEAD` for each event
+const MAX_BATCH_BYTES: usize = 1_048_576;
+/// What CloudWatch counts each event as on top of its message
+const EVENT_OVERHEAD: usize = 26;
+/// The longest message CloudWatch takes
+const MAX_MESSAGE_BYTES: usize = 256 * 1024 - EVENT_OVERHEAD;
+/// The longest time between the first and last event of a request
+const MAX_BATCH_SPAN: i64 = 24 * 60 * 60 * 1000;
+/// How many times a request is sent again with the sequence token CloudWatch says it expects
+/// before giving up, in case another writer is using the same stream
+const TOKEN_RETRIES: usize = 3;
+
+const SERVICE: &str = "logs";
+const CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+
+lazy_static! {
+    /// The client set up at startup, if there is one
+    static ref CLIENT: Mutex<Option<Arc<Client>>> = Mutex::new(None);
+}
+
+/// Why a request failed
+enum ApiError {
+    /// CloudWatch is unreachable, overloaded or throttling us
+    Unavailable(io::Error),
+    /// CloudWatch refused the request, with the exception it named and the body of its response
+    Refused(String, Value),
+}
+
+impl ApiError {
+    fn into_io(self) -> io::Error {
+        match self {
+            ApiError::Unavailable(e) => e,
+            ApiError::Refused(kind, body) => io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{}: {}",
+                    kind,
+                    body["message"].as_str().unwrap_or("no reason given")
+                ),
+            ),
+        }
+    }
+}
+
+fn unavailable(msg: String) -> ApiError {
+    ApiError::Unavailable(io::Error::new(io::ErrorKind::Other, msg))
+}
+
+struct Client {
+    agent: ureq::Agent,
+    url: String,
+    host: String,
+    region: String,
+    credentials: Credentials,
+    log_group: String,
+    log_stream: String,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_backoff: Duration,
+}
+
+impl Client {
+    /// Call a CloudWatch Logs action, returning its response
+    fn call(&self, action: &str, body: &Value) -> Result<Value, ApiError> {
+        let payload = body.to_string();
+        let now = Utc::now();
+        let mut headers = vec![
+            ("content-type", CONTENT_TYPE.to_owned()),
+            ("host", self.host.clone()),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+            ("x-amz-target", format!("Logs_20140328.{}", action)),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let payload_hash = sigv4::hex(&Sha256::digest(payload.as_bytes()));
+        let signed = sigv4::Request {
+            method: "POST",
+            path: "/",
+            query: &[],
+            headers: &headers,
+            payload_hash: &payload_hash,
+        };
+        let authorization =
+            sigv4::authorization(&self.credentials, &self.region, SERVICE, &signed, &now);
+        let mut request = self.agent.post(&self.url);
+        // ureq sets the host header itself, from the url
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request.set(name, value);
+        }
+        let resp = request
+            .set("Authorization", &authorization)
+            .timeout_connect(REQUEST_TIMEOUT_MS)
+            .timeout_read(REQUEST_TIMEOUT_MS)
+            .send_string(&payload);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(unavailable(e.to_string()));
+        }
+        let status = resp.status();
+        let status_line = resp.status_line().to_owned();
+        let body: Value = resp
+            .into_string()
+            .ok()
+            .and_then(|body| serde_json::from_str(&body).ok())
+            .unwrap_or(Value::Null);
+        if (200..300).contains(&status) {
+            return Ok(body);
+        }
+        // The exception's name may come with the namespace it's defined in
+        let kind = body["__type"]
+            .as_str()
+            .and_then(|kind| kind.rsplit('#').next())
+            .unwrap_or("")
+            .to_owned();
+        match kind.as_str() {
+            "ThrottlingException" | "ServiceUnavailableException" => Err(unavailable(kind)),
+            _ if status >= 500 => Err(unavailable(format!(
+                "CloudWatch responded with {}",
+                status_line
+            ))),
+            "" => Err(ApiError::Refused(status_line, body)),
+            _ => Err(ApiError::Refused(kind, body)),
+        }
+    }
+
+    /// Create a zone's log stream, which may have been created since we were told it was missing
+    fn create_stream(&self, stream: &str) -> Result<(), ApiError> {
+        let body = json!({"logGroupName": self.log_group, "logStreamName": stream});
+        match self.call("CreateLogStream", &body) {
+            Err(ApiError::Refused(kind, _)) if kind == "ResourceAlreadyExistsException" => Ok(()),
+            res => res.map(|_| ()),
+        }
+    }
+}
+
+/// Resolve the endpoint's address and load the credentials, which has to happen before we chroot
+pub fn init(config: &CloudwatchConfig) -> io::Result<()> {
+    let url = config
+        .endpoint
+        .clone()
+        .unwrap_or_else(|| format!("https://logs.{}.amazonaws.com", config.region));
+    let host = url
+        .trim_start_matches("https://")
+        .split('/')
+        .next()
+        .unwrap_or("")
+        .to_owned();
+    let authority = if host.contains(':') {
+        host.clone()
+    } else {
+        format!("{}:443", host)
+    };
+    let addrs: Vec<SocketAddr> = authority.to_socket_addrs()?.collect();
+    let mut agent = ureq::agent();
+    agent.set_resolver(move |_: &str| Ok(addrs.clone()));
+    let client = Client {
+        agent,
+        url: format!("https://{}/", host),
+        host,
+        region: config.region.clone(),
+        credentials: Credentials::load(
+            &config.access_key_id,
+            &config.secret_access_key,
+            &config.session_token,
+        )?,
+        log_group: config.log_group.clone(),
+        log_stream: config.log_stream.clone(),
+        batch_size: config.batch_size,
+        flush_interval: Duration::from_secs(config.flush_secs),
+        max_backoff: Duration::from_secs(config.max_backoff_secs),
+    };
+    info!(
+        "shipping records to CloudWatch Logs group {} at {}",
+        config.log_group, url
+    );
+    *CLIENT.lock().unwrap() = Some(Arc::new(client));
+    Ok(())
+}
+
+/// Open a zone's CloudWatch sink, if a client was set up at startup
+pub fn open_sink(owner_uuid: &str, vm: &str, config: &Config) -> Option<CloudwatchSink> {
+    let client = Arc::clone(CLIENT.lock().unwrap().as_ref()?);
+    let stream = client
+        .log_stream
+        .replace("{owner_uuid}", owner_uuid)
+        .replace("{vm}", vm);
+    Some(CloudwatchSink {
+        client,
+        stream,
+        token: None,
+        encoder: Encoder::for_sink("cloudwatch", config),
+        pending: vec![],
+        last_sent: Instant::now(),
+        stats: SinkStats::default(),
+    })
+}
+
+/// A record as `PutLogEvents` takes it
+#[derive(Debug, PartialEq, Serialize)]
+struct LogEvent {
+    /// Milliseconds since the epoch
+    timestamp: i64,
+    message: String,
+}
+
+/// Split events sorted by time into the ranges sent in each request, see the module's doc
+fn batches(events: &[LogEvent], max_events: usize) -> Vec<Range<usize>> {
+    let mut batches = vec![];
+    let mut start = 0;
+    let mut bytes = 0;
+    for (i, event) in events.iter().enumerate() {
+        let size = event.message.len() + EVENT_OVERHEAD;
+        if i > start
+            && (i - start == max_events
+                || bytes + size > MAX_BATCH_BYTES
+                || event.timestamp - events[start].timestamp > MAX_BATCH_SPAN)
+        {
+            batches.push(start..i);
+            start = i;
+            bytes = 0;
+        }
+        bytes += size;
+    }
+    if start < events.len() {
+        batches.push(start..events.len());
+    }
+    batches
+}
+
+pub struct CloudwatchSink {
+    client: Arc<Client>,
+    stream: String,
+    /// The sequence token the stream's next request has to carry, None for a new stream
+    token: Option<String>,
+    encoder: Encoder,
+    /// Every event not yet sent
+    pending: Vec<LogEvent>,
+    last_sent: Instant,
+    stats: SinkStats,
+}
+
+impl CloudwatchSink {
+    /// Send events in one `PutLogEvents` request, keeping the stream's sequence token up to date
+    fn put(&mut self, events: &[LogEvent]) -> Result<(), ApiError> {
+        let mut created = false;
+        let mut attempts = 0;
+        loop {
+            let mut body = json!({
+                "logGroupName": self.client.log_group,
+                "logStreamName": self.stream,
+                "logEvents": events,
+            });
+            if let Some(token) = &self.token {
+                body["sequenceToken"] = token.as_str().into();
+            }
+            let expected = |body: &Value| body["expectedSequenceToken"].as_str().map(String::from);
+            match self.client.call("PutLogEvents", &body) {
+                Ok(resp) => {
+                    self.token = resp["nextSequenceToken"].as_str().map(String::from);
+                    let rejected = &resp["rejectedLogEventsInfo"];
+                    if !rejected.is_null() {
+                        warn!(
+                            "CloudWatch rejected some of the events for {} as too old or too \
+                             new: {}",
+                            self.stream, rejected
+                        );
+                    }
+                    return Ok(());
+                }
+                Err(ApiError::Refused(kind, body)) => match kind.as_str() {
+                    "InvalidSequenceTokenException" if attempts < TOKEN_RETRIES => {
+                        attempts += 1;
+                        self.token = expected(&body);
+                    }
+                    // An earlier request that seemed to fail made it after all
+                    "DataAlreadyAcceptedException" => {
+                        self.token = expected(&body);
+                        return Ok(());
+                    }
+                    "ResourceNotFoundException" if !created => {
+                        info!("creating CloudWatch log stream {}", self.stream);
+                        self.client.create_stream(&self.stream)?;
+                        created = true;
+                        self.token = None;
+                    }
+                    _ => return Err(ApiError::Refused(kind, body)),
+                },
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Send every pending event, retrying each request until CloudWatch has taken it or refused
+    /// it for good
+    fn send(&mut self) -> io::Result<()> {
+        let mut pending = std::mem::take(&mut self.pending);
+        pending.sort_by_key(|event| event.timestamp);
+        let mut result = Ok(());
+        for range in batches(&pending, self.client.batch_size) {
+            let events = &pending[range];
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                match self.put(events) {
+                    Ok(()) => {
+                        self.stats.records += events.len() as u64;
+                        self.stats.bytes +=
+                            events.iter().map(|e| e.message.len() as u64).sum::<u64>();
+                        break;
+                    }
+                    Err(ApiError::Unavailable(e)) => {
+                        warn!(
+                            "CloudWatch is unavailable, retrying {} events in {:?}: {}",
+                            events.len(),
+                            backoff,
+                            e
+                        );
+                        thread::sleep(backoff);
+                        backoff = std::cmp::min(backoff * 2, self.client.max_backoff);
+                    }
+                    Err(e) => {
+                        result = Err(e.into_io());
+                        break;
+                    }
+                }
+            }
+        }
+        self.last_sent = Instant::now();
+        result
+    }
+}
+
+impl Sink for CloudwatchSink {
+    fn name(&self) -> &str {
+        "cloudwatch"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        let mut result = Ok(());
+        for record in records {
+            let message = self.encoder.to_line(record)?;
+            if message.len() > MAX_MESSAGE_BYTES {
+                result = Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "a {} byte record is too large for CloudWatch",
+                        message.len()
+                    ),
+                ));
+                continue;
+            }
+            let timestamp = match &record.event {
+                CfwEvent::Traffic(event) => event.timestamp,
+                CfwEvent::Unknown(_) => Utc::now(),
+            };
+            self.pending.push(LogEvent {
+                timestamp: timestamp.timestamp_millis(),
+                message,
+            });
+        }
+        if self.pending.len() >= self.client.batch_size {
+            self.send()?;
+        }
+        result
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() && self.last_sent.elapsed() >= self.client.flush_interval {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_stay_under_the_limits() {
+        let event = |timestamp: i64, len: usize| LogEvent {
+            timestamp,
+            message: "x".repeat(len),
+        };
+        let events: Vec<LogEvent> = (0..5).map(|i| event(i, 10)).collect();
+        assert_eq!(batches(&events, 2), vec![0..2, 2..4, 4..5]);
+        assert_eq!(batches(&events, 10), vec![0..5]);
+        assert!(batches(&[], 10).is_empty());
+
+        let big = MAX_BATCH_BYTES / 2;
+        let events = vec![event(0, big), event(1, big), event(2, 10)];
+        assert_eq!(
+            batches(&events, 10),
+            vec![0..1, 1..3],
+            "each event counts a little more than its message"
+        );
+
+        let events = vec![
+            event(0, 10),
+            event(MAX_BATCH_SPAN, 10),
+            event(MAX_BATCH_SPAN + 1, 10),
+        ];
+        assert_eq!(
+            batches(&events, 10),
+            vec![0..2, 2..3],
+            "a request spans a day at most"
+        );
+    }
+}
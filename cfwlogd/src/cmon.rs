@@ -0,0 +1,245 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Export of each zone's allow/block totals, and the events it lost to full queues or to the event
+//! source, for cmon, Triton's container monitor, so tenants can see their own firewall hit rates
+//! alongside the rest of their metrics. Every `Logger` keeps its zone's "cmon.txt" up to date
+//! with the running totals from the zone's `ZoneCounters`, formatted as cmon-agent plugin output
+//! (one tab separated "key, type, value, help" line per metric). cmon-agent then picks the file up
+//! through the "cfwlogd" zone plugin shipped in "cmon/".
+//!
+//! The plugin format has no histograms, so each of the zone's histograms is exported the way
+//! Prometheus exposes one: a counter for each bucket's cumulative count, keyed by the histogram's
+//! name and "_le_" with the bucket's bound or "inf", followed by the "_sum" and "_count" of its
+//! observations.
+
+use crate::clock::SharedClock;
+use crate::fileutils;
+use crate::histogram::{Histogram, Histograms};
+use crate::sink::{Record, Sink, SinkStats};
+use crate::stats::{Totals, ZoneCounters};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Name of the metrics file in each zone's log directory
+pub const CMON_FILE: &str = "cmon.txt";
+
+/// How often a zone's metrics file is rewritten
+const EXPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Format a histogram's buckets, sum and count as the lines of plugin output for `key`
+fn format_histogram(key: &str, histogram: &Histogram, help: &str) -> String {
+    let buckets = histogram.cumulative();
+    let count = buckets.last().map_or(0, |(_, count)| *count);
+    let mut lines: String = buckets
+        .iter()
+        .map(|(bound, count)| {
+            let bound = bound.map_or_else(|| "inf".to_owned(), |bound| bound.to_string());
+            format!("{}_le_{}\tcounter\t{}\t{}\n", key, bound, count, help)
+        })
+        .collect();
+    lines.push_str(&format!(
+        "{}_sum\tcounter\t{}\t{}\n",
+        key,
+        histogram.sum(),
+        help
+    ));
+    lines.push_str(&format!("{}_count\tcounter\t{}\t{}\n", key, count, help));
+    lines
+}
+
+/// Format the totals and histograms as cmon-agent plugin output
+fn format_metrics(totals: &Totals, histograms: &Histograms) -> String {
+    let metrics = [
+        (
+            "blocked_connections",
+            totals.block,
+            "Connections blocked by cloud firewall rules",
+        ),
+        (
+            "allowed_connections",
+            totals.begin,
+            "Connections allowed by cloud firewall rules",
+        ),
+        (
+            "closed_connections",
+            totals.end,
+            "Allowed connections that have since closed",
+        ),
+        (
+            "dropped_events",
+            totals.queue_full,
+            "Firewall events dropped because cfwlogd's queues were full",
+        ),
+        (
+            "source_dropped_events",
+            totals.source_lost,
+            "Firewall events the event device dropped on this host, which may include this zone's",
+        ),
+    ];
+    let histograms = [
+        (
+            "event_latency_ms",
+            &histograms.latency_ms,
+            "Milliseconds from a firewall event to cfwlogd writing it to the zone's log",
+        ),
+        (
+            "write_batch_records",
+            &histograms.batch_records,
+            "Records written to the zone's log by each write",
+        ),
+        (
+            "fsync_ms",
+            &histograms.fsync_ms,
+            "Milliseconds each sync of the zone's log took",
+        ),
+    ];
+    let mut lines: String = metrics
+        .iter()
+        .map(|(key, value, help)| format!("{}\tcounter\t{}\t{}\n", key, value, help))
+        .collect();
+    for (key, histogram, help) in &histograms {
+        lines.push_str(&format_histogram(key, histogram, help));
+    }
+    lines
+}
+
+/// Keeps a zone's metrics file up to date. No records are written to it, the metrics are read
+/// from the zone's counters every time the `Logger` checks on its sinks.
+pub struct CmonSink {
+    dir: PathBuf,
+    counters: Arc<ZoneCounters>,
+    clock: SharedClock,
+    last_export: Option<Instant>,
+    stats: SinkStats,
+}
+
+impl CmonSink {
+    /// Export metrics into the zone log directory `dir`
+    pub fn new(dir: PathBuf, counters: Arc<ZoneCounters>, clock: SharedClock) -> Self {
+        CmonSink {
+            dir,
+            counters,
+            clock,
+            last_export: None,
+            stats: SinkStats::default(),
+        }
+    }
+
+    fn export(&mut self) -> io::Result<()> {
+        self.last_export = Some(self.clock.now());
+        let metrics = format_metrics(&self.counters.totals(), self.counters.histograms());
+        let dir = fileutils::create_dir_all_nofollow(&self.dir)?;
+        fileutils::replace_file_nofollow(&dir, CMON_FILE, metrics.as_bytes())?;
+        self.stats.bytes += metrics.len() as u64;
+        Ok(())
+    }
+}
+
+impl Sink for CmonSink {
+    fn name(&self) -> &str {
+        "cmon"
+    }
+
+    fn write_batch(&mut self, _records: &[Record<'_>]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        match self.last_export {
+            Some(last) if self.clock.now() - last < EXPORT_INTERVAL => Ok(()),
+            _ => self.export(),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.export()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use cfwevent::parser::CfwEvType;
+
+    #[test]
+    fn cmon_export() {
+        let dir: PathBuf = [crate::logger::LOG_DIR, "cmon-customer", "cmon-zone"]
+            .iter()
+            .collect();
+        let counters = Arc::new(ZoneCounters::default());
+        counters.event_written(&CfwEvType::Block);
+        counters.event_written(&CfwEvType::Block);
+        counters.event_written(&CfwEvType::Begin);
+        counters.histograms().latency_ms.observe(3);
+        counters.histograms().latency_ms.observe(2000);
+
+        let manual = ManualClock::new();
+        let clock: SharedClock = manual.clone();
+        let mut sink = CmonSink::new(dir.clone(), Arc::clone(&counters), clock);
+        sink.check().expect("failed to export metrics");
+        let metrics = std::fs::read_to_string(dir.join(CMON_FILE)).unwrap();
+        let lines: Vec<Vec<&str>> = metrics.lines().map(|l| l.split('\t').collect()).collect();
+        assert_eq!(lines.len(), 5 + 3 * 12, "one line per counter and bucket");
+        assert_eq!(&lines[0][..3], &["blocked_connections", "counter", "2"]);
+        assert_eq!(&lines[1][..3], &["allowed_connections", "counter", "1"]);
+        assert_eq!(&lines[5][..3], &["event_latency_ms_le_1", "counter", "0"]);
+        assert_eq!(&lines[6][..3], &["event_latency_ms_le_4", "counter", "1"]);
+        assert_eq!(
+            &lines[14][..3],
+            &["event_latency_ms_le_inf", "counter", "2"]
+        );
+        assert_eq!(
+            &lines[15][..3],
+            &["event_latency_ms_sum", "counter", "2003"]
+        );
+        assert_eq!(&lines[16][..3], &["event_latency_ms_count", "counter", "2"]);
+
+        counters.event_written(&CfwEvType::End);
+        sink.check().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join(CMON_FILE)).unwrap(),
+            metrics,
+            "metrics are only exported once per interval"
+        );
+        manual.advance(EXPORT_INTERVAL);
+        sink.check().unwrap();
+        assert!(
+            std::fs::read_to_string(dir.join(CMON_FILE))
+                .unwrap()
+                .contains("closed_connections\tcounter\t1\t"),
+            "metrics are exported again once the interval has passed"
+        );
+        counters.event_written(&CfwEvType::End);
+        sink.close().unwrap();
+        assert!(
+            std::fs::read_to_string(dir.join(CMON_FILE))
+                .unwrap()
+                .contains("closed_connections\tcounter\t2\t"),
+            "metrics are exported when the sink is closed"
+        );
+
+        dir.parent()
+            .map(std::fs::remove_dir_all)
+            .unwrap()
+            .expect("failed to cleanup log dir");
+    }
+}
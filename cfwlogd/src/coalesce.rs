@@ -0,0 +1,180 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Collapsing runs of identical traffic events, much like syslog's "last message repeated N
+//! times", so that a flood of the same packet doesn't bury everything else in a zone's log. With
+//! the "coalesce" table set, a traffic event that repeats the one before it (the same event type,
+//! rule, protocol, direction, addresses and ports) within "coalesce.window_ms" of the first event
+//! of the run is held back rather than handed to the sinks. The first event of a run is logged
+//! as usual, and once the run ends, because a different event came along or its window is over,
+//! the last repeat is logged with a "repeats" count of the events it stands for.
+//!
+//! Unlike the flows of the "flows" module this applies to every sink, and only ever merges
+//! consecutive events so the order of a zone's records is kept. Runs are found before the drop
+//! filters and sampling, which treat a run's record the same as any other.
+
+use crate::audit::LossAudit;
+use cfwevent::parser::{CfwEvent, TrafficEvent};
+use std::time::{Duration, Instant};
+
+/// Whether `event` is a repeat of `first`, leaving aside when they happened
+fn repeats(first: &TrafficEvent, event: &TrafficEvent) -> bool {
+    first.event == event.event
+        && first.rule_uuid == event.rule_uuid
+        && first.protocol == event.protocol
+        && first.icmp == event.icmp
+        && first.direction == event.direction
+        && first.source_ip == event.source_ip
+        && first.destination_ip == event.destination_ip
+        && first.source_port == event.source_port
+        && first.destination_port == event.destination_port
+}
+
+/// The events repeating the first of a run so far
+struct Run {
+    first: TrafficEvent,
+    started: Instant,
+    /// The latest repeat, logged for the whole run once it ends
+    last: Option<TrafficEvent>,
+    repeats: u64,
+}
+
+impl Run {
+    /// The record standing for the run's repeats, if it had any
+    fn end(self) -> Option<(CfwEvent, Option<u64>)> {
+        let repeats = self.repeats;
+        self.last
+            .map(|last| (CfwEvent::Traffic(last), Some(repeats)))
+    }
+}
+
+/// A zone's current run
+pub struct Coalescer {
+    window: Duration,
+    run: Option<Run>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Self {
+        Coalescer { window, run: None }
+    }
+
+    /// Whether the current run's window is over by `now`, see `take_due`
+    pub fn due(&self, now: Instant) -> bool {
+        self.run
+            .as_ref()
+            .map_or(false, |run| now - run.started >= self.window)
+    }
+
+    /// End the current run if its window is over, returning its record
+    pub fn take_due(&mut self, now: Instant) -> Option<(CfwEvent, Option<u64>)> {
+        if !self.due(now) {
+            return None;
+        }
+        self.run.take().and_then(Run::end)
+    }
+
+    /// End the current run however long it has gone on, such as when the zone's logger stops
+    pub fn finish(&mut self) -> Option<(CfwEvent, Option<u64>)> {
+        self.run.take().and_then(Run::end)
+    }
+
+    /// Pass a batch of events through, returning those to be logged along with the number of
+    /// events each stands for when it ends a run. Repeats that another repeat took the place of
+    /// are accounted for as dropped.
+    pub fn coalesce(
+        &mut self,
+        events: Vec<CfwEvent>,
+        now: Instant,
+        audit: &LossAudit,
+    ) -> Vec<(CfwEvent, Option<u64>)> {
+        let mut logged = Vec::with_capacity(events.len());
+        logged.extend(self.take_due(now));
+        for event in events {
+            let traffic = match event {
+                CfwEvent::Traffic(traffic) => traffic,
+                unknown => {
+                    logged.extend(self.finish());
+                    logged.push((unknown, None));
+                    continue;
+                }
+            };
+            if let Some(run) = &mut self.run {
+                if repeats(&run.first, &traffic) {
+                    run.repeats += 1;
+                    if let Some(replaced) = run.last.replace(traffic) {
+                        audit.dropped(&CfwEvent::Traffic(replaced));
+                    }
+                    continue;
+                }
+            }
+            logged.extend(self.finish());
+            self.run = Some(Run {
+                first: traffic.clone(),
+                started: now,
+                last: None,
+                repeats: 0,
+            });
+            logged.push((CfwEvent::Traffic(traffic), None));
+        }
+        logged
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::CfwEvType;
+    use testutils::{traffic_event, RULE};
+
+    /// A traffic event that only differs from the others by its destination port
+    fn event(destination_port: u16) -> CfwEvent {
+        let event = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", destination_port, RULE);
+        CfwEvent::Traffic(event)
+    }
+
+    fn ports(logged: &[(CfwEvent, Option<u64>)]) -> Vec<(u16, Option<u64>)> {
+        logged
+            .iter()
+            .map(|(event, repeats)| match event {
+                CfwEvent::Traffic(event) => (event.destination_port, *repeats),
+                CfwEvent::Unknown(_) => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_are_coalesced() {
+        let audit = LossAudit::new(false);
+        let mut coalescer = Coalescer::new(Duration::from_secs(1));
+        let start = Instant::now();
+        let batch = vec![event(22), event(22), event(22), event(80), event(22)];
+        let logged = coalescer.coalesce(batch, start, &audit);
+        assert_eq!(
+            ports(&logged),
+            vec![(22, None), (22, Some(2)), (80, None), (22, None)],
+            "a different event ends the run"
+        );
+
+        let batch = vec![event(22), event(22)];
+        let logged = coalescer.coalesce(batch, start + Duration::from_millis(500), &audit);
+        assert!(logged.is_empty(), "the run carries on across batches");
+        assert!(!coalescer.due(start + Duration::from_millis(999)));
+        let logged = coalescer.take_due(start + Duration::from_secs(1));
+        assert_eq!(
+            ports(&logged.into_iter().collect::<Vec<_>>()),
+            vec![(22, Some(2))]
+        );
+
+        let logged = coalescer.coalesce(vec![event(22)], start + Duration::from_secs(2), &audit);
+        assert_eq!(
+            ports(&logged),
+            vec![(22, None)],
+            "a new run starts once the last is over"
+        );
+        assert!(coalescer.finish().is_none(), "the run had no repeats");
+    }
+}
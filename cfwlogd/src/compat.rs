@@ -0,0 +1,161 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Keeping track of the events a newer platform's device hands us that we don't fully know, so
+//! that a platform update doesn't need a cfwlogd update in lockstep. The parser skips over events
+//! of a type it doesn't know, which the fanout thread then leaves out, and over the fields a
+//! traffic event has past those it knows, logging the rest of the event as usual. Since either
+//! means cfwlogd is behind the platform, the first event of each unknown type and the first
+//! extended traffic event are warned about, followed every `REPORT_INTERVAL` by how many more
+//! there were. The totals since startup are part of the SIGUSR2 dump.
+
+use cfwevent::parser::CfwEvent;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the events seen since the last report are logged
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+lazy_static! {
+    static ref SEEN: Mutex<Seen> = Mutex::new(Seen::new(Instant::now()));
+}
+
+/// Running totals, which are never reset
+static TOTAL_UNKNOWN: AtomicU64 = AtomicU64::new(0);
+static TOTAL_EXTENDED: AtomicU64 = AtomicU64::new(0);
+
+/// The events seen since the last report, apart from those that were warned about
+struct Seen {
+    /// The unknown types that were warned about
+    warned: BTreeSet<u16>,
+    warned_extended: bool,
+    /// Events of unknown types by their raw type
+    unknown: BTreeMap<u16, u64>,
+    extended: u64,
+    last_report: Instant,
+}
+
+impl Seen {
+    fn new(now: Instant) -> Self {
+        Seen {
+            warned: BTreeSet::new(),
+            warned_extended: false,
+            unknown: BTreeMap::new(),
+            extended: 0,
+            last_report: now,
+        }
+    }
+
+    /// Count an event of unknown type `raw`, returning true if it's the first of its type
+    fn unknown(&mut self, raw: u16) -> bool {
+        if self.warned.insert(raw) {
+            return true;
+        }
+        *self.unknown.entry(raw).or_insert(0) += 1;
+        false
+    }
+
+    /// Count an extended traffic event, returning true if it's the first
+    fn extended(&mut self) -> bool {
+        if !self.warned_extended {
+            self.warned_extended = true;
+            return true;
+        }
+        self.extended += 1;
+        false
+    }
+
+    /// What was counted since the last report, once one is due
+    fn take_due(&mut self, now: Instant) -> Option<(BTreeMap<u16, u64>, u64)> {
+        if now - self.last_report < REPORT_INTERVAL {
+            return None;
+        }
+        self.last_report = now;
+        let extended = std::mem::replace(&mut self.extended, 0);
+        Some((std::mem::take(&mut self.unknown), extended))
+    }
+}
+
+/// Count an event that was just parsed if we don't fully know it
+pub fn check(event: &CfwEvent) {
+    match event {
+        CfwEvent::Unknown(unknown) => {
+            TOTAL_UNKNOWN.fetch_add(1, Ordering::Relaxed);
+            if SEEN.lock().unwrap().unknown(unknown.raw_event) {
+                warn!(
+                    "skipping events of unknown type {} ({} bytes), which a newer cfwlogd may log",
+                    unknown.raw_event, unknown.length
+                );
+            }
+        }
+        CfwEvent::Traffic(traffic) if traffic.extended() => {
+            TOTAL_EXTENDED.fetch_add(1, Ordering::Relaxed);
+            if SEEN.lock().unwrap().extended() {
+                warn!(
+                    "skipping the fields of traffic events ({} bytes) past those we know, which a \
+                     newer cfwlogd may log",
+                    traffic.length
+                );
+            }
+        }
+        CfwEvent::Traffic(_) => (),
+    }
+}
+
+/// Log what was counted since the last report, if it has been long enough
+pub fn report() {
+    let due = SEEN.lock().unwrap().take_due(Instant::now());
+    if let Some((unknown, extended)) = due {
+        for (raw, count) in unknown {
+            warn!("skipped {} more events of unknown type {}", count, raw);
+        }
+        if extended > 0 {
+            warn!(
+                "skipped the unknown fields of {} more traffic events",
+                extended
+            );
+        }
+    }
+}
+
+/// The events of unknown types and the extended traffic events seen since startup
+pub fn totals() -> (u64, u64) {
+    (
+        TOTAL_UNKNOWN.load(Ordering::Relaxed),
+        TOTAL_EXTENDED.load(Ordering::Relaxed),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_events_are_warned_about() {
+        let start = Instant::now();
+        let mut seen = Seen::new(start);
+        assert!(seen.unknown(9), "the first of a type is warned about");
+        assert!(!seen.unknown(9));
+        assert!(!seen.unknown(9));
+        assert!(seen.unknown(12));
+        assert!(seen.extended());
+        assert!(!seen.extended());
+        assert!(
+            seen.take_due(start).is_none(),
+            "reports wait out the interval"
+        );
+
+        let (unknown, extended) = seen.take_due(start + REPORT_INTERVAL).unwrap();
+        assert_eq!(unknown.into_iter().collect::<Vec<_>>(), vec![(9, 2)]);
+        assert_eq!(extended, 1);
+        assert!(!seen.unknown(12), "types are only warned about once");
+        let (unknown, extended) = seen.take_due(start + REPORT_INTERVAL * 2).unwrap();
+        assert_eq!(unknown.into_iter().collect::<Vec<_>>(), vec![(12, 1)]);
+        assert_eq!(extended, 0);
+    }
+}
@@ -0,0 +1,385 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The writer behind a zone's current.log. The zones listed in the config's "zstd" table have
+//! their current.log written through a zstd streaming encoder when cfwlogd is built with the
+//! "zstd" feature. The encoder's frame is ended every "frame_secs" and whenever the log is
+//! flushed, so the file is always a sequence of complete frames apart from the one being written,
+//! and `zstdcat current.log | tail` shows everything up to the last frame.
+//!
+//! The zones listed in the config's "indexed" table have their current.log written in the indexed
+//! binary format of `cfwevent::indexed` instead, which `cfwlog` can query by time without reading
+//! the whole file.
+//!
+//! A current.log that already has records in it keeps the format it was started with until it's
+//! rotated, so turning compression or indexing on or off never leaves a file with both in it.
+
+use cfwevent::indexed::{self, IndexedReader, IndexedWriter};
+use cfwevent::parser;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, Write};
+#[cfg(feature = "zstd")]
+use std::time::{Duration, Instant};
+
+/// The level a current.log that was started compressed keeps being written at once its zone is no
+/// longer listed
+pub const DEFAULT_LEVEL: i32 = 3;
+/// Seconds between frames when there is no "zstd" table
+pub const DEFAULT_FRAME_SECS: u64 = 5;
+
+/// Every zstd frame starts with these bytes
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Whether an existing file holds zstd frames, or None if the file is empty
+pub fn is_zstd<R: Read>(mut file: R) -> io::Result<Option<bool>> {
+    let mut magic = [0; 4];
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if read == 0 {
+        return Ok(None);
+    }
+    Ok(Some(read == magic.len() && magic == ZSTD_MAGIC))
+}
+
+/// A zone's current.log, either written as is, compressed or indexed
+pub enum LogWriter {
+    Plain(BufWriter<File>),
+    #[cfg(feature = "zstd")]
+    Zstd(ZstdWriter),
+    Indexed(IndexedLog),
+}
+
+impl LogWriter {
+    /// Write to the file at `capacity` bytes at a time, compressing at `level` if set
+    pub fn new(file: File, capacity: usize, level: Option<i32>, frame: u64) -> LogWriter {
+        let writer = BufWriter::with_capacity(capacity, file);
+        #[cfg(feature = "zstd")]
+        {
+            if let Some(level) = level {
+                return LogWriter::Zstd(ZstdWriter {
+                    frame: None,
+                    idle: Some(writer),
+                    level,
+                    frame_interval: Duration::from_secs(frame),
+                    last_frame: None,
+                });
+            }
+        }
+        #[cfg(not(feature = "zstd"))]
+        let _ = (level, frame);
+        LogWriter::Plain(writer)
+    }
+
+    /// Write an empty file as an indexed log, `capacity` bytes at a time
+    pub fn indexed(file: File, capacity: usize, block_size: u32) -> LogWriter {
+        let writer = BufWriter::with_capacity(capacity, file);
+        LogWriter::Indexed(IndexedLog {
+            writer: IndexedWriter::new(writer, block_size),
+            line: vec![],
+        })
+    }
+
+    /// Carry on writing the indexed log `existing` was opened on. Whoever wrote it last may have
+    /// stopped partway through a record, which is cut off.
+    pub fn resume_indexed<R: Read + Seek>(
+        file: File,
+        capacity: usize,
+        mut existing: IndexedReader<R>,
+    ) -> io::Result<LogWriter> {
+        let (len, latest) = existing.resume_point()?;
+        if len < existing.len() {
+            file.set_len(len)?;
+        }
+        let writer = BufWriter::with_capacity(capacity, file);
+        Ok(LogWriter::Indexed(IndexedLog {
+            writer: IndexedWriter::resume(writer, existing.block_size(), len, latest),
+            line: vec![],
+        }))
+    }
+
+    /// Whether what's written goes to the file as is
+    pub fn is_plain(&self) -> bool {
+        matches!(self, LogWriter::Plain(_))
+    }
+
+    /// The file being written to
+    pub fn get_ref(&self) -> &File {
+        match self {
+            LogWriter::Plain(writer) => writer.get_ref(),
+            #[cfg(feature = "zstd")]
+            LogWriter::Zstd(writer) => writer.get_ref(),
+            LogWriter::Indexed(log) => log.writer.get_ref().get_ref(),
+        }
+    }
+
+    /// End the frame being written if it has been open for longer than the frame interval, which
+    /// should be called periodically with the current time
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))]
+    pub fn check(&mut self, now: std::time::Instant) -> io::Result<()> {
+        match self {
+            LogWriter::Plain(_) | LogWriter::Indexed(_) => Ok(()),
+            #[cfg(feature = "zstd")]
+            LogWriter::Zstd(writer) => writer.check(now),
+        }
+    }
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            LogWriter::Plain(writer) => writer.write(buf),
+            #[cfg(feature = "zstd")]
+            LogWriter::Zstd(writer) => writer.write(buf),
+            LogWriter::Indexed(log) => log.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            LogWriter::Plain(writer) => writer.flush(),
+            #[cfg(feature = "zstd")]
+            LogWriter::Zstd(writer) => writer.flush(),
+            LogWriter::Indexed(log) => log.writer.flush(),
+        }
+    }
+}
+
+/// Writes each json line written to it as a record of an indexed log
+pub struct IndexedLog {
+    writer: IndexedWriter<BufWriter<File>>,
+    /// The start of a line the rest of which hasn't been written yet
+    line: Vec<u8>,
+}
+
+/// The only field of a record an indexed log needs
+#[derive(Deserialize)]
+struct Timestamp {
+    #[serde(default, deserialize_with = "logged_timestamp")]
+    timestamp: Option<DateTime<Utc>>,
+}
+
+/// A timestamp in whichever format the config logs them, see `parser::deserialize_timestamp`
+fn logged_timestamp<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error> {
+    parser::deserialize_timestamp(deserializer).map(Some)
+}
+
+impl IndexedLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.line.drain(..=end).collect();
+            let json = &line[..end];
+            // Records without a timestamp of their own, such as unknown events, are indexed by the
+            // latest one before them
+            let timestamp = serde_json::from_slice::<Timestamp>(json)
+                .ok()
+                .and_then(|record| record.timestamp)
+                .map(|timestamp| indexed::micros(&timestamp))
+                .or_else(|| self.writer.latest())
+                .unwrap_or(0);
+            self.writer.write_record(timestamp, json)?;
+        }
+        Ok(buf.len())
+    }
+}
+
+/// Compresses into a new frame whenever there is something to write after the previous frame was
+/// ended
+#[cfg(feature = "zstd")]
+pub struct ZstdWriter {
+    /// The encoder of the open frame, which owns the file while there is one
+    frame: Option<zstd::stream::write::Encoder<BufWriter<File>>>,
+    /// The file while no frame is open
+    idle: Option<BufWriter<File>>,
+    level: i32,
+    frame_interval: Duration,
+    /// When `check` last saw the frame ended or found no frame open
+    last_frame: Option<Instant>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdWriter {
+    fn get_ref(&self) -> &File {
+        match (&self.frame, &self.idle) {
+            (Some(encoder), _) => encoder.get_ref().get_ref(),
+            (None, Some(writer)) => writer.get_ref(),
+            (None, None) => unreachable!("the file is always owned by the frame or held idle"),
+        }
+    }
+
+    fn lost() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "the log file was lost after failing to end a zstd frame",
+        )
+    }
+
+    /// End the open frame, if any, and flush the file
+    fn end_frame(&mut self) -> io::Result<()> {
+        if let Some(encoder) = self.frame.take() {
+            self.idle = Some(encoder.finish()?);
+        }
+        self.idle.as_mut().ok_or_else(Self::lost)?.flush()
+    }
+
+    fn check(&mut self, now: Instant) -> io::Result<()> {
+        let due = self
+            .last_frame
+            .map_or(false, |last| now - last >= self.frame_interval);
+        if self.frame.is_none() || due {
+            self.last_frame = Some(now);
+        }
+        if due && self.frame.is_some() {
+            self.end_frame()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Write for ZstdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.frame.is_none() {
+            let writer = self.idle.take().ok_or_else(Self::lost)?;
+            self.frame = Some(zstd::stream::write::Encoder::new(writer, self.level)?);
+        }
+        self.frame
+            .as_mut()
+            .expect("frame was just opened")
+            .write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.end_frame()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zstd_files_are_detected() {
+        assert_eq!(is_zstd(&b""[..]).unwrap(), None, "empty file");
+        assert_eq!(is_zstd(&b"{\"event\""[..]).unwrap(), Some(false));
+        assert_eq!(is_zstd(&b"{"[..]).unwrap(), Some(false), "short file");
+        assert_eq!(
+            is_zstd(&[0x28, 0xb5, 0x2f, 0xfd, 0x00][..]).unwrap(),
+            Some(true)
+        );
+    }
+
+    #[test]
+    fn indexed_logs() {
+        let dir = std::path::PathBuf::from("/var/tmp/cfwlogd-tests/indexed");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("current.log");
+        let _ = std::fs::remove_file(&path);
+        let open = || {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap()
+        };
+        let records = |path: &std::path::Path| {
+            let mut found = vec![];
+            IndexedReader::new(File::open(path).unwrap())
+                .unwrap()
+                .query(None, None, |timestamp, json| {
+                    found.push((timestamp, String::from_utf8(json.to_vec()).unwrap()));
+                    Ok(())
+                })
+                .unwrap();
+            found
+        };
+
+        let mut writer = LogWriter::indexed(open(), 1024, 4096);
+        writer
+            .write_all(b"{\"event\":\"block\",\"timestamp\":\"2020-05-12T19:00:00.5Z\"}\n")
+            .unwrap();
+        // Records can be written in pieces, and ones without a timestamp get the latest one
+        writer.write_all(b"{\"event\":").unwrap();
+        writer.write_all(b"\"unknown\"}\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(
+            records(&path),
+            vec![
+                (
+                    1_589_310_000_500_000,
+                    "{\"event\":\"block\",\"timestamp\":\"2020-05-12T19:00:00.5Z\"}".to_owned()
+                ),
+                (1_589_310_000_500_000, "{\"event\":\"unknown\"}".to_owned()),
+            ]
+        );
+
+        // Tear the last record, as if cfwlogd had stopped partway through writing it
+        let len = std::fs::metadata(&path).unwrap().len();
+        open().set_len(len - 3).unwrap();
+        let existing = IndexedReader::new(File::open(&path).unwrap()).unwrap();
+        let mut writer = LogWriter::resume_indexed(open(), 1024, existing).unwrap();
+        writer.write_all(b"{\"event\":\"end\"}\n").unwrap();
+        writer.flush().unwrap();
+        let found = records(&path);
+        assert_eq!(found.len(), 2, "the torn record was cut off");
+        assert_eq!(
+            found[1],
+            (1_589_310_000_500_000, "{\"event\":\"end\"}".to_owned())
+        );
+        assert_eq!(
+            indexed::is_indexed(File::open(&path).unwrap()).unwrap(),
+            Some(true)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn compressed_logs_are_complete_frames() {
+        let dir = std::path::PathBuf::from("/var/tmp/cfwlogd-tests/compress");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("current.log");
+        let _ = std::fs::remove_file(&path);
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .unwrap();
+
+        let start = Instant::now();
+        let mut writer = LogWriter::new(file, 1024, Some(3), 5);
+        writer.check(start).unwrap();
+        writer.write_all(b"first\n").unwrap();
+        writer.check(start + Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            zstd::stream::decode_all(std::fs::File::open(&path).unwrap()).unwrap(),
+            b"first\n",
+            "the frame was ended once it was due"
+        );
+
+        writer.write_all(b"second\n").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(
+            zstd::stream::decode_all(std::fs::File::open(&path).unwrap()).unwrap(),
+            b"first\nsecond\n",
+            "flushing ends the frame"
+        );
+        assert_eq!(
+            is_zstd(std::fs::File::open(&path).unwrap()).unwrap(),
+            Some(true)
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
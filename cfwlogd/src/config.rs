@@ -0,0 +1,3701 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Runtime configuration for cfwlogd. The configuration is read from a TOML file once at startup
+//! before we chroot into the log directory. Every option has a default so the file itself is
+//! optional, and a missing file is treated the same as an empty one.
+
+use crate::admin;
+use crate::alert;
+use crate::archive;
+use crate::compress;
+use crate::exit::LogFormat;
+use crate::expr::Expr;
+use crate::fields::Fields;
+use crate::fileutils;
+use crate::format::Format;
+use crate::health;
+use crate::layout::{self, LogLayout};
+use crate::queue::Overflow;
+use crate::service::ServiceManager;
+use crate::sink::{Record, SCHEMA_VERSION};
+use crate::spool;
+use crate::template::Template;
+use crate::zones::{self, VmField};
+use cfwevent::indexed;
+use cfwevent::parser::{CfwEvType, CfwEvent, Direction, Serialization, TimestampFormat, Timezone};
+use chrono::format::{Item, StrftimeItems};
+use crossbeam::sync::ShardedLock;
+use log::LevelFilter;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use vminfod_client::{Settings, Transport};
+
+/// Default location of the configuration file
+pub const CONFIG_FILE: &str = "/opt/smartdc/cfwlogd/etc/config.toml";
+
+/// The smallest "write_buffer_bytes" allowed
+pub const MIN_WRITE_BUFFER_BYTES: usize = 4096;
+
+/// The sinks whose records are text, which can't be given a binary format
+const TEXT_SINKS: &[&str] = &[
+    "live",
+    "stdout",
+    "syslog",
+    "elasticsearch",
+    "cloudwatch",
+    "webhook",
+];
+
+/// The running configuration, which is replaced when the config file is reloaded
+pub type SharedConfig = Arc<ShardedLock<Arc<Config>>>;
+
+pub fn shared(config: Config) -> SharedConfig {
+    Arc::new(ShardedLock::new(Arc::new(config)))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    Invalid(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(err: toml::de::Error) -> Error {
+        Error::Toml(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "failed to read config: {}", e),
+            Error::Toml(e) => write!(f, "failed to parse config: {}", e),
+            Error::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+/// Controls how cfwlogd reacts to missing prerequisites (the event device, the log directory, or
+/// vminfod) while starting up.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StartupMode {
+    /// Exit as soon as a prerequisite is found to be unavailable
+    Strict,
+    /// Log the problem and keep retrying until the prerequisite becomes available
+    Permissive,
+}
+
+impl Default for StartupMode {
+    fn default() -> Self {
+        StartupMode::Strict
+    }
+}
+
+/// Where firewall events are read from
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SourceConfig {
+    /// The illumos ipfilter event devices, each read by its own thread
+    Ipfev {
+        #[serde(default = "default_ipfev_devices")]
+        devices: Vec<PathBuf>,
+    },
+    /// Synthetic events generated at `rate` events per second for the given zonedids
+    Simulator {
+        #[serde(default = "default_simulator_rate")]
+        rate: u64,
+        zones: Vec<u32>,
+    },
+    /// A Linux netfilter NFLOG group
+    Nflog { group: u16 },
+    /// A file of raw ipfev records, see the "replay" module
+    Replay {
+        path: PathBuf,
+        /// How many times faster than it was captured a capture is replayed, as fast as possible
+        /// when unset
+        speed: Option<f64>,
+    },
+    /// A BSD pf(4) pflog interface, see the "pflog" module for how rules are mapped
+    Pflog {
+        #[serde(default = "default_pflog_interface")]
+        interface: String,
+        /// Cloud firewall rule uuids keyed by "<zonedid>/<pf rule number>"
+        #[serde(default)]
+        rules: HashMap<String, Uuid>,
+    },
+}
+
+fn default_ipfev_devices() -> Vec<PathBuf> {
+    vec![PathBuf::from("/dev/ipfev")]
+}
+
+fn default_simulator_rate() -> u64 {
+    100
+}
+
+fn default_pflog_interface() -> String {
+    "pflog0".to_owned()
+}
+
+impl Default for SourceConfig {
+    fn default() -> Self {
+        SourceConfig::Ipfev {
+            devices: default_ipfev_devices(),
+        }
+    }
+}
+
+/// How to reach vminfod
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct VminfodConfig {
+    /// The events url, when vminfod isn't listening on its usual loopback port
+    pub url: Option<String>,
+    /// Connect over this Unix socket rather than TCP
+    pub socket: Option<PathBuf>,
+    /// Seconds to wait for vminfod to respond to a connection attempt
+    pub connect_timeout_secs: u64,
+    /// Seconds without an event after which the stream is considered lost and reconnected
+    pub idle_timeout_secs: Option<u64>,
+    /// The vmobj fields whose changes are picked up from vminfod, see `zones::VmField`
+    pub tracked_fields: Vec<VmField>,
+    /// Resume a lost event stream where it left off rather than start over with a snapshot
+    pub resume: bool,
+    /// Ask vminfod for only the vmobj fields we use, see `zones::requested_fields`
+    pub request_fields: bool,
+    /// Seconds between saves of the zones we know about, which are loaded at startup so logging
+    /// can start before vminfod is up, see `zones::start_vmobjs_cache`
+    pub cache_secs: Option<u64>,
+}
+
+impl Default for VminfodConfig {
+    fn default() -> Self {
+        VminfodConfig {
+            url: None,
+            socket: None,
+            connect_timeout_secs: 10,
+            idle_timeout_secs: None,
+            tracked_fields: VmField::ALL.to_vec(),
+            resume: false,
+            request_fields: false,
+            cache_secs: None,
+        }
+    }
+}
+
+impl VminfodConfig {
+    pub fn settings(&self) -> Settings {
+        let transport = match (&self.socket, &self.url) {
+            (Some(socket), _) => Transport::Unix(socket.clone()),
+            (None, Some(url)) => Transport::Tcp(url.clone()),
+            (None, None) => Transport::default(),
+        };
+        Settings {
+            transport,
+            connect_timeout: Duration::from_secs(self.connect_timeout_secs),
+            idle_timeout: self.idle_timeout_secs.map(Duration::from_secs),
+            resume: self.resume,
+            fields: if self.request_fields {
+                zones::requested_fields(&self.tracked_fields)
+            } else {
+                vec![]
+            },
+        }
+    }
+}
+
+/// Encryption applied to log files once they have been rotated
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct EncryptionConfig {
+    /// age X25519 public keys ("age1...") that rotated files are encrypted to
+    pub recipients: Vec<String>,
+}
+
+/// The gRPC live event service, see the "grpc" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// Unix socket the service listens on
+    pub socket: PathBuf,
+}
+
+/// The local firehose socket, see the "firehose" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FirehoseConfig {
+    /// Unix socket every record is streamed to
+    pub socket: PathBuf,
+}
+
+/// The health check socket, see the "health" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Unix socket each health check connects to
+    pub socket: PathBuf,
+    /// Seconds a thread may go without making progress before cfwlogd isn't considered live
+    #[serde(default = "default_health_stuck")]
+    pub stuck_secs: u64,
+}
+
+fn default_health_stuck() -> u64 {
+    120
+}
+
+/// The admin socket, see the "admin" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Unix socket `cfwlogd ctl` connects to
+    #[serde(default = "default_admin_socket")]
+    pub socket: PathBuf,
+}
+
+fn default_admin_socket() -> PathBuf {
+    PathBuf::from(admin::DEFAULT_SOCKET)
+}
+
+/// Handing the event devices over to a new cfwlogd, see the "handoff" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct HandoffConfig {
+    /// Unix socket a new cfwlogd asks the running one for its devices on
+    #[serde(default = "default_handoff_socket")]
+    pub socket: PathBuf,
+    /// How long a new cfwlogd waits for the running one to write out what it already read
+    #[serde(default = "default_handoff_timeout")]
+    pub timeout_secs: u64,
+}
+
+fn default_handoff_socket() -> PathBuf {
+    PathBuf::from("/var/run/cfwlogd-handoff.sock")
+}
+
+fn default_handoff_timeout() -> u64 {
+    60
+}
+
+/// Syncing rule attribution from FWAPI, see the "fwapi" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FwapiConfig {
+    /// FWAPI's "http://" url
+    pub url: String,
+    /// Seconds between syncs
+    #[serde(default = "default_fwapi_refresh")]
+    pub refresh_secs: u64,
+}
+
+fn default_fwapi_refresh() -> u64 {
+    300
+}
+
+/// Syncing rule attribution from fwadm's rule files, see the "fwadm" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FwadmConfig {
+    /// Where fwadm keeps a file per rule
+    #[serde(default = "default_fwadm_rules_dir")]
+    pub rules_dir: String,
+    /// Seconds between syncs
+    #[serde(default = "default_fwapi_refresh")]
+    pub refresh_secs: u64,
+}
+
+fn default_fwadm_rules_dir() -> String {
+    "/var/fw/rules".to_owned()
+}
+
+/// Alerting on block rate spikes, see the "alert" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Blocked events per second a zone has to sustain to be alerted on
+    pub block_rate: u64,
+    /// Seconds the rate has to be sustained for
+    #[serde(default = "default_alert_window")]
+    pub window_secs: u64,
+    /// Seconds before a zone can be alerted on again
+    #[serde(default = "default_alert_cooldown")]
+    pub cooldown_secs: u64,
+    /// "http://" url alerts are posted to as json
+    pub webhook: Option<String>,
+    pub snmp: Option<SnmpConfig>,
+    /// Count the events matching this expression towards `block_rate` instead of blocked events,
+    /// see the "expr" module
+    pub filter: Option<Expr>,
+}
+
+fn default_alert_window() -> u64 {
+    60
+}
+
+fn default_alert_cooldown() -> u64 {
+    300
+}
+
+/// Alerting on the remote addresses that set off too many of a zone's events, see the
+/// "thresholds" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ThresholdConfig {
+    /// Identifies the threshold in its alerts
+    pub name: String,
+    /// Alert once a remote address has more than this many events within the window
+    pub events: u64,
+    #[serde(default = "default_alert_window")]
+    pub window_secs: u64,
+    /// Seconds before an address can be alerted on again
+    #[serde(default = "default_alert_cooldown")]
+    pub cooldown_secs: u64,
+    /// Count the events matching this expression instead of blocked events, see the "expr"
+    /// module
+    pub filter: Option<Expr>,
+    /// A command run with the alert as json on its stdin, the program's absolute path first
+    pub exec: Option<Vec<String>>,
+    /// "http://" url alerts are posted to as json
+    pub webhook: Option<String>,
+    /// Write alerts to alerts.log in the log directory
+    #[serde(default)]
+    pub alert_log: bool,
+}
+
+/// Shipping records to Elasticsearch, see the "elasticsearch" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ElasticsearchConfig {
+    /// The cluster's "http://" or "https://" url
+    pub url: String,
+    /// Name of the index each record goes into, with `{owner_uuid}`, `{vm}` and `{date}`
+    /// substituted
+    #[serde(default = "default_elasticsearch_index")]
+    pub index: String,
+    /// Records each zone batches up before sending them
+    #[serde(default = "default_elasticsearch_batch_size")]
+    pub batch_size: usize,
+    /// Seconds before a zone sends a batch that isn't full
+    #[serde(default = "default_elasticsearch_flush")]
+    pub flush_secs: u64,
+    /// Longest delay between retries while the cluster is unavailable
+    #[serde(default = "default_elasticsearch_max_backoff")]
+    pub max_backoff_secs: u64,
+    /// With an "https://" url, see the "tls" module
+    pub tls_ca: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_pin_sha256: Vec<String>,
+    /// Spool each zone's documents to a file of at most this many bytes while the cluster is
+    /// unavailable, rather than retrying them on the zone's `Logger` thread
+    pub spool_bytes: Option<u64>,
+}
+
+fn default_elasticsearch_index() -> String {
+    "cfw-{owner_uuid}-{date}".to_owned()
+}
+
+fn default_elasticsearch_batch_size() -> usize {
+    500
+}
+
+fn default_elasticsearch_flush() -> u64 {
+    5
+}
+
+fn default_elasticsearch_max_backoff() -> u64 {
+    60
+}
+
+/// A config value that's left out of the config's debug output, such as a secret key
+#[derive(Clone, Deserialize, PartialEq)]
+#[serde(transparent)]
+pub struct Secret(pub String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Shipping records to Amazon CloudWatch Logs, see the "cloudwatch" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CloudwatchConfig {
+    /// The AWS region, such as "us-east-1"
+    pub region: String,
+    /// The log group every zone's log stream is in, which has to exist
+    pub log_group: String,
+    /// Name of each zone's log stream, with `{owner_uuid}` and `{vm}` substituted
+    #[serde(default = "default_cloudwatch_log_stream")]
+    pub log_stream: String,
+    /// An "https://" url to send requests to in place of the region's endpoint, such as a VPC
+    /// endpoint
+    pub endpoint: Option<String>,
+    /// The credentials requests are signed with. Without them the environment's are used.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<Secret>,
+    pub session_token: Option<Secret>,
+    /// Records each zone batches up before sending them
+    #[serde(default = "default_cloudwatch_batch_size")]
+    pub batch_size: usize,
+    /// Seconds before a zone sends a batch that isn't full
+    #[serde(default = "default_elasticsearch_flush")]
+    pub flush_secs: u64,
+    /// Longest delay between retries while CloudWatch is unavailable
+    #[serde(default = "default_elasticsearch_max_backoff")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_cloudwatch_log_stream() -> String {
+    "{vm}".to_owned()
+}
+
+fn default_cloudwatch_batch_size() -> usize {
+    1000
+}
+
+/// The most events CloudWatch takes in one request
+const CLOUDWATCH_MAX_BATCH: usize = 10_000;
+
+/// Uploading rotated logs to Manta, see the "manta" and "upload" modules
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct MantaConfig {
+    /// An "https://" url of Manta, such as "https://us-east.manta.joyent.com"
+    pub url: String,
+    /// The account the logs are stored under and whose key signs the requests
+    pub user: String,
+    /// The fingerprint of the account's key, as `MANTA_KEY_ID` would have it
+    pub key_id: String,
+    /// The key's private half, an RSA key in PEM, which is read at startup
+    pub key_file: PathBuf,
+    /// Where each rotated file is stored, with `{user}`, `{owner_uuid}`, `{vm}`, `{date}` and
+    /// `{file}` substituted
+    #[serde(default = "default_manta_path")]
+    pub path: String,
+    /// Longest delay between attempts at uploading a file
+    #[serde(default = "default_upload_max_backoff")]
+    pub max_backoff_secs: u64,
+}
+
+fn default_manta_path() -> String {
+    "/{user}/stor/cfwlogs/{vm}/{date}/{file}".to_owned()
+}
+
+fn default_upload_max_backoff() -> u64 {
+    300
+}
+
+/// Uploading rotated logs to an S3-compatible object store, see the "s3" and "upload" modules
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct S3Config {
+    /// An "https://" url of the store, such as "https://s3.us-east-1.amazonaws.com". Objects are
+    /// addressed by path, as "<endpoint>/<bucket>/<key>".
+    pub endpoint: String,
+    /// The region requests are signed for
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub bucket: String,
+    /// What each rotated file's key starts with, ahead of its name, with `{owner_uuid}`, `{vm}`
+    /// and `{date}` substituted
+    #[serde(default = "default_s3_prefix")]
+    pub prefix: String,
+    /// The credentials requests are signed with. Without them the environment's are used.
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<Secret>,
+    pub session_token: Option<Secret>,
+    /// Files at least this big are uploaded in parts
+    #[serde(default = "default_s3_multipart_threshold")]
+    pub multipart_threshold_bytes: u64,
+    /// The size of each part of a multipart upload
+    #[serde(default = "default_s3_part_bytes")]
+    pub part_bytes: u64,
+    /// Longest delay between attempts at uploading a file
+    #[serde(default = "default_upload_max_backoff")]
+    pub max_backoff_secs: u64,
+    /// Attempts at uploading a file before it's moved to "dead_letter_dir"
+    #[serde(default = "default_s3_max_attempts")]
+    pub max_attempts: u32,
+    /// Where files that failed to upload are kept, relative to the log directory
+    #[serde(default = "default_s3_dead_letter_dir")]
+    pub dead_letter_dir: String,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_s3_prefix() -> String {
+    "cfwlogs/{vm}/{date}/".to_owned()
+}
+
+fn default_s3_multipart_threshold() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_s3_part_bytes() -> u64 {
+    16 * 1024 * 1024
+}
+
+fn default_s3_max_attempts() -> u32 {
+    10
+}
+
+fn default_s3_dead_letter_dir() -> String {
+    "dead-letter".to_owned()
+}
+
+/// The smallest part S3 takes in a multipart upload, other than the last one
+pub const S3_MIN_PART_BYTES: u64 = 5 * 1024 * 1024;
+
+/// How the webhook sink compresses the body of its requests
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookCompression {
+    None,
+    Gzip,
+}
+
+impl Default for WebhookCompression {
+    fn default() -> Self {
+        WebhookCompression::None
+    }
+}
+
+/// Posting batches of records to an HTTP endpoint, see the "webhook" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// The "http://" or "https://" url batches are posted to
+    pub url: String,
+    /// Headers sent with every request, such as "Authorization"
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+    #[serde(default)]
+    pub compression: WebhookCompression,
+    /// Records each zone batches up before posting them
+    #[serde(default = "default_cloudwatch_batch_size")]
+    pub batch_size: usize,
+    /// Seconds before a zone posts a batch that isn't full
+    #[serde(default = "default_elasticsearch_flush")]
+    pub flush_secs: u64,
+    /// Longest delay between retries of a batch
+    #[serde(default = "default_elasticsearch_max_backoff")]
+    pub max_backoff_secs: u64,
+    /// With an "https://" url, see the "tls" module
+    pub tls_ca: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_pin_sha256: Vec<String>,
+    /// Failed requests in a row, from any zone, before requests stop for "breaker_secs"
+    #[serde(default = "default_webhook_breaker_failures")]
+    pub breaker_failures: u32,
+    /// Seconds requests stop for once the endpoint keeps failing, before trying it again
+    #[serde(default = "default_webhook_breaker_secs")]
+    pub breaker_secs: u64,
+}
+
+fn default_webhook_breaker_failures() -> u32 {
+    5
+}
+
+fn default_webhook_breaker_secs() -> u64 {
+    30
+}
+
+/// Headers the webhook sink sets itself
+const WEBHOOK_RESERVED_HEADERS: &[&str] = &[
+    "host",
+    "content-type",
+    "content-length",
+    "content-encoding",
+    "transfer-encoding",
+];
+
+/// Writing each zone's traffic records to Parquet files, see the "parquet_sink" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ParquetConfig {
+    /// Seconds covered by each file
+    #[serde(default = "default_parquet_interval")]
+    pub interval_secs: u64,
+    /// Records each zone buffers before writing them out as a row group
+    #[serde(default = "default_parquet_row_group_rows")]
+    pub row_group_rows: usize,
+}
+
+fn default_parquet_interval() -> u64 {
+    3600
+}
+
+fn default_parquet_row_group_rows() -> usize {
+    10_000
+}
+
+/// Shipping records to a syslog collector, see the "syslog" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// "host:port" of the collector
+    pub address: String,
+    /// Connect to the collector over TLS, see the "tls" module
+    #[serde(default)]
+    pub tls: bool,
+    /// PEM file with the CA certificates the collector's certificate is verified against
+    pub tls_ca: Option<PathBuf>,
+    /// PEM files with the client certificate and its key, for mutual TLS
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    /// SHA-256 fingerprints of the certificates the collector may present
+    #[serde(default)]
+    pub tls_pin_sha256: Vec<String>,
+    /// The name the collector's certificate is verified against, in place of the host of
+    /// "address", such as when that's an IP address
+    pub tls_server_name: Option<String>,
+    /// Give every zone its own connection rather than sharing one
+    #[serde(default)]
+    pub per_zone: bool,
+    #[serde(default = "default_syslog_facility")]
+    pub facility: u8,
+    /// SD-ID of the structured data element carrying each event's fields
+    #[serde(default = "default_syslog_sd_id")]
+    pub sd_id: String,
+    /// Spool each zone's messages to a file of at most this many bytes while the collector is
+    /// unavailable, rather than dropping them
+    pub spool_bytes: Option<u64>,
+}
+
+/// local0
+fn default_syslog_facility() -> u8 {
+    16
+}
+
+fn default_syslog_sd_id() -> String {
+    "cfw@32473".to_owned()
+}
+
+/// Where SNMP traps are sent
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SnmpConfig {
+    /// "host:port" of the trap receiver
+    pub target: String,
+    #[serde(default = "default_snmp_community")]
+    pub community: String,
+    #[serde(default = "default_snmp_trap_oid")]
+    pub trap_oid: String,
+}
+
+fn default_snmp_community() -> String {
+    "public".to_owned()
+}
+
+/// NET-SNMP's netSnmpPlaypen, operators are expected to pick their own OID
+fn default_snmp_trap_oid() -> String {
+    "1.3.6.1.4.1.8072.9999.9999".to_owned()
+}
+
+/// Sampling a rule's or a zone's events, see the "sampling" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SamplingConfig {
+    /// The rule sampled in every zone
+    pub rule: Option<Uuid>,
+    /// The zone sampled, for the rules without an entry of their own
+    pub vm: Option<Uuid>,
+    /// Only sample the events in this direction, taking precedence over an entry for the same
+    /// rule or zone without one
+    pub direction: Option<Direction>,
+    /// Log one event out of every this many
+    pub one_in: Option<u64>,
+    /// Log at most this many events a second
+    pub per_sec: Option<u64>,
+    /// Events that can be logged at once after a quiet period, defaults to "per_sec"
+    pub burst: Option<u64>,
+}
+
+/// Where the zones of a vm or an owner are logged, see the "layout" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogDirConfig {
+    /// The zone the entry applies to
+    pub vm: Option<Uuid>,
+    /// The owner whose zones the entry applies to
+    pub owner: Option<Uuid>,
+    /// Directory relative to the log directory, with `{owner_uuid}` and `{vm}` substituted
+    pub dir: Option<String>,
+    /// strftime template the zones' active log files are named with, rather than "log_name"
+    pub log_name: Option<String>,
+    /// Who owns the zones' logs, in place of "log_permissions"
+    #[serde(default)]
+    pub permissions: LogPermissions,
+}
+
+/// The owner and mode given to each zone's log directory and the files in it, see the "layout"
+/// module. Whatever is left unset is left the way the files are created.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogPermissions {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Mode of the log files, which are created 0644
+    pub file_mode: Option<u32>,
+    /// Mode of the zone's log directory, which is created 0755
+    pub dir_mode: Option<u32>,
+}
+
+impl LogPermissions {
+    /// These permissions, with the settings `other` has taking precedence
+    pub fn merge(self, other: LogPermissions) -> LogPermissions {
+        LogPermissions {
+            uid: other.uid.or(self.uid),
+            gid: other.gid.or(self.gid),
+            file_mode: other.file_mode.or(self.file_mode),
+            dir_mode: other.dir_mode.or(self.dir_mode),
+        }
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        for mode in self.file_mode.iter().chain(self.dir_mode.iter()) {
+            if *mode > 0o777 {
+                return Err(Error::Invalid(format!(
+                    "log modes are permission bits, {:o} isn't",
+                    mode
+                )));
+            }
+        }
+        if self.dir_mode.map_or(false, |mode| mode & 0o700 != 0o700) {
+            return Err(Error::Invalid(
+                "dir_mode has to leave the owner rwx".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Limiting each zone's events, see the "ratelimit" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Events a second each zone may log
+    pub per_sec: u64,
+    /// Events a zone can log at once after a quiet period, defaults to "per_sec"
+    pub burst: Option<u64>,
+}
+
+impl RateLimitConfig {
+    pub fn burst(&self) -> u64 {
+        self.burst.unwrap_or(self.per_sec)
+    }
+}
+
+/// Where records are exported as IPFIX, see the "ipfix" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IpfixConfig {
+    /// "host:port" of the collector
+    pub collector: String,
+    #[serde(default)]
+    pub transport: IpfixTransport,
+    /// Private enterprise number the rule, vm and action elements are defined under
+    #[serde(default = "default_ipfix_enterprise_number")]
+    pub enterprise_number: u32,
+    #[serde(default)]
+    pub observation_domain: u32,
+    /// Seconds between sending the templates again over UDP
+    #[serde(default = "default_ipfix_template_secs")]
+    pub template_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum IpfixTransport {
+    Udp,
+    Tcp,
+}
+
+impl Default for IpfixTransport {
+    fn default() -> Self {
+        IpfixTransport::Udp
+    }
+}
+
+/// The number reserved for documentation, like "syslog.sd_id"'s
+fn default_ipfix_enterprise_number() -> u32 {
+    32473
+}
+
+fn default_ipfix_template_secs() -> u64 {
+    600
+}
+
+/// Units for the numeric timestamp added to records
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EpochUnit {
+    /// Milliseconds, as an "epoch_ms" field
+    Ms,
+    /// Nanoseconds, as an "epoch_ns" field
+    Ns,
+}
+
+/// A part of the CN's identity records can be stamped with, see the "node" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeField {
+    ServerUuid,
+    ServerHostname,
+    Datacenter,
+}
+
+/// Which of a zone's traffic events are logged
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogActions {
+    /// Blocked packets and the beginnings and ends of allowed connections
+    Both,
+    /// Only blocked packets
+    Block,
+    /// Only the beginnings and ends of allowed connections
+    Allow,
+}
+
+impl Default for LogActions {
+    fn default() -> Self {
+        LogActions::Both
+    }
+}
+
+impl LogActions {
+    /// Whether events of this type are logged. Events of types we don't know about are always
+    /// logged, since we can't tell whether they are blocks or allows.
+    pub fn logs(self, event: &CfwEvType) -> bool {
+        match (self, event) {
+            (LogActions::Block, CfwEvType::Begin) | (LogActions::Block, CfwEvType::End) => false,
+            (LogActions::Allow, CfwEvType::Block) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Logging the remote addresses each zone blocks the most, see the "talkers" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TopTalkersConfig {
+    /// How many addresses are logged
+    #[serde(default = "default_top_talkers_count")]
+    pub count: usize,
+    /// Seconds between each zone's top talkers records
+    #[serde(default = "default_top_talkers_interval")]
+    pub interval_secs: u64,
+    /// Seconds of blocked events each record covers, rounded up to a whole number of intervals
+    #[serde(default = "default_top_talkers_window")]
+    pub window_secs: u64,
+}
+
+fn default_top_talkers_count() -> usize {
+    10
+}
+
+fn default_top_talkers_interval() -> u64 {
+    60
+}
+
+fn default_top_talkers_window() -> u64 {
+    300
+}
+
+/// Rules whose events are only counted, in summary records, rather than logged one by one
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CountOnlyConfig {
+    #[serde(default)]
+    pub rules: Vec<Uuid>,
+    /// Count the events matching this expression as well, see the "expr" module
+    pub filter: Option<Expr>,
+    /// Seconds between each zone's summary records
+    #[serde(default = "default_count_only_interval")]
+    pub interval_secs: u64,
+}
+
+impl CountOnlyConfig {
+    /// Whether the record's event is counted rather than logged
+    pub fn counts(&self, record: &Record<'_>) -> bool {
+        match &record.event {
+            CfwEvent::Traffic(event) => {
+                self.rules.contains(&event.rule_uuid)
+                    || self
+                        .filter
+                        .as_ref()
+                        .map_or(false, |filter| filter.matches(record))
+            }
+            CfwEvent::Unknown(_) => false,
+        }
+    }
+}
+
+fn default_count_only_interval() -> u64 {
+    60
+}
+
+/// What happens to the events of a zone that's over its disk budget
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BudgetAction {
+    /// Counted by rule, in summary records like those of "count_only" rules
+    CountOnly,
+    /// Dropped, and summarized as suppressed with the reason "disk_budget"
+    Drop,
+}
+
+impl Default for BudgetAction {
+    fn default() -> Self {
+        BudgetAction::CountOnly
+    }
+}
+
+/// How much space each zone's logs may take up, see the "budget" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DiskBudgetConfig {
+    /// Bytes of each zone's log directory, counting its rotated files
+    pub bytes: u64,
+    /// Budgets of particular zones in place of "bytes"
+    #[serde(default)]
+    pub zones: HashMap<Uuid, u64>,
+    #[serde(default)]
+    pub action: BudgetAction,
+    /// Seconds between checks of each zone's usage, and between its summary records while it's
+    /// over budget
+    #[serde(default = "default_disk_budget_check")]
+    pub check_secs: u64,
+}
+
+impl DiskBudgetConfig {
+    /// The zone's budget in bytes
+    pub fn bytes_for(&self, vm: &str) -> u64 {
+        Uuid::parse_str(vm)
+            .ok()
+            .and_then(|vm| self.zones.get(&vm))
+            .copied()
+            .unwrap_or(self.bytes)
+    }
+}
+
+fn default_disk_budget_check() -> u64 {
+    60
+}
+
+/// When a zone's buffered records are flushed to its log file and the file is synced to disk.
+/// Unset, records are flushed once the zone's write buffer fills up, and the file is only synced
+/// when it's rotated or closed.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlushConfig {
+    /// Flush once this many records have been buffered, so 1 flushes every record
+    pub every_records: Option<u64>,
+    /// Flush whatever is buffered at least this often
+    pub interval_ms: Option<u64>,
+    /// Sync the log file at least this often, once something was flushed to it
+    pub fsync_secs: Option<u64>,
+}
+
+impl FlushConfig {
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval_ms.map(Duration::from_millis)
+    }
+
+    pub fn fsync_interval(&self) -> Option<Duration> {
+        self.fsync_secs.map(Duration::from_secs)
+    }
+
+    fn validate(&self, name: &str) -> Result<(), Error> {
+        if self.every_records == Some(0)
+            || self.interval_ms == Some(0)
+            || self.fsync_secs == Some(0)
+        {
+            return Err(Error::Invalid(format!(
+                "{}.every_records, interval_ms and fsync_secs must be non-zero",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Keeping a logadm configuration file for the zones being logged, see the "logadm" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct LogadmConfig {
+    /// Name of the file in the log directory
+    #[serde(default = "default_logadm_file")]
+    pub file: String,
+    /// logadm options every zone's entry gets
+    #[serde(default = "default_logadm_options")]
+    pub options: String,
+}
+
+fn default_logadm_file() -> String {
+    "logadm.conf".to_owned()
+}
+
+fn default_logadm_options() -> String {
+    "-C 168 -z 0 -p 1h".to_owned()
+}
+
+/// Writing zones' records into their own filesystems, see the "zonefs" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneFsConfig {
+    /// The zones whose records are written into them, besides those with the tag
+    #[serde(default)]
+    pub zones: Vec<Uuid>,
+    /// Where the zones' filesystems are found
+    #[serde(default = "default_zone_fs_zones_dir")]
+    pub zones_dir: PathBuf,
+    /// The file records are appended to, relative to the zone's root
+    #[serde(default = "default_zone_fs_path")]
+    pub path: String,
+    /// The file is moved aside once it has grown to this many bytes
+    #[serde(default = "default_zone_fs_max_bytes")]
+    pub max_bytes: u64,
+}
+
+fn default_zone_fs_zones_dir() -> PathBuf {
+    PathBuf::from("/zones")
+}
+
+fn default_zone_fs_path() -> String {
+    "var/log/firewall.log".to_owned()
+}
+
+/// 64MiB
+fn default_zone_fs_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+/// Compressing zones' current.log, see the "compress" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ZstdConfig {
+    /// The zones whose logs are compressed
+    pub zones: Vec<Uuid>,
+    /// The zstd compression level
+    #[serde(default = "default_zstd_level")]
+    pub level: i32,
+    /// Seconds a frame is left open before it's ended so its records can be read back
+    #[serde(default = "default_zstd_frame")]
+    pub frame_secs: u64,
+}
+
+impl ZstdConfig {
+    /// The level the zone's log is compressed at, if it's compressed
+    pub fn level_for(&self, vm: &str) -> Option<i32> {
+        let vm = Uuid::parse_str(vm).ok()?;
+        if self.zones.contains(&vm) {
+            Some(self.level)
+        } else {
+            None
+        }
+    }
+}
+
+fn default_zstd_level() -> i32 {
+    compress::DEFAULT_LEVEL
+}
+
+fn default_zstd_frame() -> u64 {
+    compress::DEFAULT_FRAME_SECS
+}
+
+/// Writing zones' current.log in the indexed binary format, see `cfwevent::indexed`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct IndexedConfig {
+    /// The zones whose logs are indexed
+    pub zones: Vec<Uuid>,
+    /// The size of each block, whose header indexes the records in it
+    #[serde(default = "default_indexed_block_kib")]
+    pub block_kib: u32,
+}
+
+impl IndexedConfig {
+    /// The block size of the zone's log, if it's indexed
+    pub fn block_size_for(&self, vm: &str) -> Option<u32> {
+        let vm = Uuid::parse_str(vm).ok()?;
+        if self.zones.contains(&vm) {
+            Some(self.block_kib * 1024)
+        } else {
+            None
+        }
+    }
+}
+
+fn default_indexed_block_kib() -> u32 {
+    indexed::DEFAULT_BLOCK_SIZE / 1024
+}
+
+/// Coalescing zones' traffic events into flow records, see the "flows" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AggregateConfig {
+    /// The zones whose events are aggregated
+    pub zones: Vec<Uuid>,
+    /// Seconds each flow record covers
+    #[serde(default = "default_aggregate_window")]
+    pub window_secs: u64,
+    /// Most flows a zone counts per window, events of any other flow are written on their own
+    #[serde(default = "default_aggregate_max_flows")]
+    pub max_flows: usize,
+}
+
+impl AggregateConfig {
+    /// Whether the zone's events are aggregated
+    pub fn aggregates(&self, vm: &str) -> bool {
+        Uuid::parse_str(vm).map_or(false, |vm| self.zones.contains(&vm))
+    }
+}
+
+fn default_aggregate_window() -> u64 {
+    60
+}
+
+fn default_aggregate_max_flows() -> usize {
+    10_000
+}
+
+/// Collapsing runs of identical traffic events into one record, see the "coalesce" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CoalesceConfig {
+    /// Milliseconds a run of identical events may go on for before it's logged
+    #[serde(default = "default_coalesce_window")]
+    pub window_ms: u64,
+}
+
+fn default_coalesce_window() -> u64 {
+    1000
+}
+
+/// The WebSocket live tail endpoint, see the "websocket" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Address the endpoint listens on
+    pub listen: SocketAddr,
+}
+
+/// The HTTP tail endpoint, see the "tail" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TailConfig {
+    /// Loopback address the endpoint listens on
+    pub listen: SocketAddr,
+}
+
+/// Free space thresholds for the filesystem holding the logs, as a percentage of its size
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskConfig {
+    /// Warn once free space drops below this
+    pub warning_free_percent: u64,
+    /// Only log a sample of events once free space drops below this
+    pub critical_free_percent: u64,
+    /// Records each zone holds in memory while its log file can't be written because the
+    /// filesystem is full
+    pub full_buffer_records: usize,
+    /// Seconds between attempts to write to a full filesystem again
+    pub full_retry_secs: u64,
+}
+
+impl Default for DiskConfig {
+    fn default() -> Self {
+        DiskConfig {
+            warning_free_percent: 10,
+            critical_free_percent: 5,
+            full_buffer_records: 10_000,
+            full_retry_secs: 10,
+        }
+    }
+}
+
+/// Sizes of the queues events wait in on their way to the zones' loggers, see the "queue" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    /// Events waiting for the fanout thread, sized from the device's ring when unset
+    pub reader_capacity: Option<usize>,
+    /// Events waiting for each zone's logger
+    pub zone_capacity: usize,
+    /// What happens to an event that arrives at a full queue
+    pub overflow: Overflow,
+    /// Most events a zone's logger takes from its queue to write at once
+    pub batch_records: usize,
+    /// Microseconds a zone's logger waits for a batch to fill up before writing what it has
+    pub batch_delay_us: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            reader_capacity: None,
+            zone_capacity: 65_536,
+            overflow: Overflow::default(),
+            batch_records: 1024,
+            batch_delay_us: 500,
+        }
+    }
+}
+
+/// How events are read from each source
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReadConfig {
+    /// Bytes read from a source at once, enough for its whole ring when unset
+    pub buffer_bytes: Option<usize>,
+    /// Read into one buffer while the events of the previous read are parsed out of another
+    pub double_buffer: bool,
+}
+
+/// The threads and cache annotating records, see the "enrich" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnrichConfig {
+    /// Threads looking addresses up
+    pub workers: usize,
+    /// Addresses waiting to be looked up, any more are dropped
+    pub queue_len: usize,
+    /// Most annotations cached
+    pub cache_entries: usize,
+    /// Seconds an annotation is cached for
+    pub cache_secs: u64,
+}
+
+impl Default for EnrichConfig {
+    fn default() -> Self {
+        EnrichConfig {
+            workers: 2,
+            queue_len: 1024,
+            cache_entries: 65_536,
+            cache_secs: 3600,
+        }
+    }
+}
+
+/// Annotating remote addresses from MaxMind databases, see the "geoip" module
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct GeoipConfig {
+    /// A GeoIP2 or GeoLite2 Country or City database, for "remote_country"
+    pub country_db: Option<PathBuf>,
+    /// A GeoLite2 ASN database, for "remote_asn" and "remote_as_org"
+    pub asn_db: Option<PathBuf>,
+}
+
+/// Resolving remote addresses to hostnames, see the "rdns" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReverseDnsConfig {
+    /// Milliseconds before a query to one of the nameservers is given up on
+    pub timeout_ms: u64,
+}
+
+impl Default for ReverseDnsConfig {
+    fn default() -> Self {
+        ReverseDnsConfig { timeout_ms: 1000 }
+    }
+}
+
+/// Holding on to the events of zones vminfod hasn't told us about yet, see the "holding" module
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnknownZoneConfig {
+    /// Seconds a zone's events are held before they go to the unknown zone log
+    pub hold_secs: u64,
+    /// Events held for each zone, any more are dropped
+    pub max_events: usize,
+}
+
+impl Default for UnknownZoneConfig {
+    fn default() -> Self {
+        UnknownZoneConfig {
+            hold_secs: 30,
+            max_events: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub startup_mode: StartupMode,
+    pub vminfod: VminfodConfig,
+    /// Seconds a deleted zone keeps logging the events still arriving for it before its logger is
+    /// retired, on top of waiting for those already read from the device
+    pub retire_grace_secs: u64,
+    /// What runs cfwlogd, see the "service" module
+    pub service_manager: ServiceManager,
+    /// The level of cfwlogd's own log, instead of RUST_LOG's, see the "exit" module
+    pub log_level: Option<String>,
+    /// How cfwlogd's own log lines are written
+    pub log_format: LogFormat,
+    pub source: SourceConfig,
+    pub encryption: Option<EncryptionConfig>,
+    /// Approximate ceiling in megabytes for memory held in queues and buffers
+    pub memory_limit_mb: Option<usize>,
+    pub disk: DiskConfig,
+    pub queues: QueueConfig,
+    pub reads: ReadConfig,
+    /// Run the zones' loggers on this many shared threads rather than a thread each, see the
+    /// "workers" module
+    pub logger_workers: Option<usize>,
+    /// Bytes each zone's log file writer buffers, 1 MiB when unset
+    pub write_buffer_bytes: Option<usize>,
+    pub enrich: EnrichConfig,
+    pub unknown_zones: UnknownZoneConfig,
+    /// Debugging aid that verifies every event read from the device is written or accounted for
+    /// as dropped, see the "audit" module
+    pub loss_audit: bool,
+    /// Stay in the foreground rather than daemonizing, also set by "--foreground"
+    pub foreground: bool,
+    /// Also write every zone's records to stdout, see the "stdout" module. Under SMF this requires
+    /// `foreground`, and it's also set by "--stdout".
+    pub stdout: bool,
+    /// Run as this user rather than root once the event device and log directory are open
+    pub user: Option<String>,
+    /// Write records in this older schema version rather than the current one, for consumers that
+    /// haven't migrated yet, see the "migrate" module. Also set by "--schema-version=N".
+    pub schema_version: Option<u64>,
+    /// Add the CN's server uuid and hostname to every record, see the "node" module
+    pub node_identity: bool,
+    /// The parts of the CN's identity "node_identity" adds, rather than its server uuid and
+    /// hostname
+    pub node_fields: Option<Vec<NodeField>>,
+    /// The datacenter "node_fields" stamps records with, rather than the one sysinfo reports
+    pub datacenter: Option<String>,
+    /// Log IPv4-mapped addresses as plain IPv4, so IPv4 traffic isn't logged as "::ffff:a.b.c.d"
+    pub normalize_ipv4_mapped: bool,
+    /// Write a ".done" marker next to every rotated file once it's finalized, see the "archive"
+    /// module
+    pub handoff_markers: bool,
+    /// strftime template the active log file of each zone is named with, in UTC, rather than
+    /// "current.log". The file is rotated whenever the name changes.
+    pub log_name: Option<String>,
+    /// How the zones' log directories are laid out, see the "layout" module
+    pub log_layout: LogLayout,
+    /// Where the zones of particular vms or owners are logged instead, see the "layout" module
+    pub log_dirs: Vec<LogDirConfig>,
+    /// Who owns the zones' logs, unless their "log_dirs" entries say otherwise
+    pub log_permissions: LogPermissions,
+    /// Rotate a zone's current.log once it has grown to this many bytes, without waiting on
+    /// logadm
+    pub rotate_bytes: Option<u64>,
+    /// When every zone's log file is flushed and synced
+    pub flush: FlushConfig,
+    /// Each zone's flush policy in place of "flush", keyed by vm uuid
+    pub zone_flush: HashMap<Uuid, FlushConfig>,
+    /// Seconds between the per-rule statistics records written to each zone's stats.log, rather
+    /// than only including them in the rollup written at rotation
+    pub rule_stats_secs: Option<u64>,
+    /// Seconds between the heartbeat records written to each zone's log whether or not it had
+    /// any traffic, so that a quiet zone can be told apart from a broken cfwlogd
+    pub heartbeat_secs: Option<u64>,
+    pub top_talkers: Option<TopTalkersConfig>,
+    pub count_only: Option<CountOnlyConfig>,
+    pub disk_budget: Option<DiskBudgetConfig>,
+    /// Export each zone's allow/block totals for cmon, see the "cmon" module
+    pub cmon_metrics: bool,
+    /// Shared objects implementing additional sinks, see the "plugin" module
+    pub sink_plugins: Vec<PathBuf>,
+    /// Add the event's timestamp to records as a number in these units, for consumers that sort
+    /// or bucket on time and would rather not parse every record's "timestamp"
+    pub epoch_timestamp: Option<EpochUnit>,
+    /// Add when cfwlogd read each traffic event to its record, along with how long after the
+    /// event's timestamp that was, see `ReceiveTimes`
+    pub receive_timestamps: bool,
+    /// Add the interface, VLAN and nic tag of the zone's nic each traffic event was seen on to its
+    /// record, see the "nic" module
+    pub nic_context: bool,
+    /// How the timestamps of records are written, see `Config::serialization`. Syslog's
+    /// header is always RFC 3339 and CEF and LEEF always have epoch milliseconds.
+    pub timestamp_format: TimestampFormat,
+    /// The timezone RFC 3339 timestamps are written in
+    pub timestamp_timezone: Timezone,
+    /// Expression matching the events that aren't logged anywhere, see the "expr" module
+    pub drop_filter: Option<Expr>,
+    /// Expressions matching each zone's events that aren't logged anywhere, on top of
+    /// "drop_filter"
+    pub zone_drop_filters: HashMap<Uuid, Expr>,
+    /// Which traffic events are logged, checked before anything else is done with them
+    pub log_actions: LogActions,
+    /// Which of each zone's traffic events are logged, in place of "log_actions"
+    pub zone_log_actions: HashMap<Uuid, LogActions>,
+    /// Expressions limiting the records each named sink receives, see the "expr" module
+    pub sink_filters: HashMap<String, Expr>,
+    /// Expressions limiting the records of each zone that each named sink receives, in place of
+    /// the sink's entry in "sink_filters"
+    pub zone_sink_filters: HashMap<Uuid, HashMap<String, Expr>>,
+    /// The format of each named sink's records, see the "format" module
+    pub sink_formats: HashMap<String, Format>,
+    /// The fields each named sink serializes, see the "fields" module
+    pub sink_fields: HashMap<String, Fields>,
+    /// Line formats for named sinks that shouldn't get json, see the "template" module
+    pub sink_templates: HashMap<String, Template>,
+    /// Named sinks that run on a thread of their own, with a queue of up to this many records,
+    /// see the "fanout" module
+    pub sink_queues: HashMap<String, usize>,
+    pub grpc: Option<GrpcConfig>,
+    pub websocket: Option<WebSocketConfig>,
+    pub tail: Option<TailConfig>,
+    pub firehose: Option<FirehoseConfig>,
+    pub health: Option<HealthConfig>,
+    pub admin: Option<AdminConfig>,
+    pub handoff: Option<HandoffConfig>,
+    pub fwapi: Option<FwapiConfig>,
+    pub fwadm: Option<FwadmConfig>,
+    pub alerts: Option<AlertConfig>,
+    pub thresholds: Vec<ThresholdConfig>,
+    pub syslog: Option<SyslogConfig>,
+    pub ipfix: Option<IpfixConfig>,
+    pub sampling: Vec<SamplingConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub elasticsearch: Option<ElasticsearchConfig>,
+    pub cloudwatch: Option<CloudwatchConfig>,
+    pub webhook: Option<WebhookConfig>,
+    pub parquet: Option<ParquetConfig>,
+    pub manta: Option<MantaConfig>,
+    pub s3: Option<S3Config>,
+    pub zone_fs: Option<ZoneFsConfig>,
+    pub logadm: Option<LogadmConfig>,
+    pub zstd: Option<ZstdConfig>,
+    pub indexed: Option<IndexedConfig>,
+    pub aggregate: Option<AggregateConfig>,
+    pub coalesce: Option<CoalesceConfig>,
+    pub geoip: Option<GeoipConfig>,
+    pub reverse_dns: Option<ReverseDnsConfig>,
+}
+
+impl Config {
+    /// Parse a `Config` out of a TOML formatted string
+    pub fn from_toml(s: &str) -> Result<Config, Error> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Check the values that can't be validated by deserialization alone
+    fn validate(&self) -> Result<(), Error> {
+        if let Some(level) = &self.log_level {
+            level.parse::<LevelFilter>().map_err(|_| {
+                Error::Invalid(format!(
+                    "log_level must be one of off, error, warn, info, debug or trace, not {}",
+                    level
+                ))
+            })?;
+        }
+        if (self.node_fields.is_some() || self.datacenter.is_some()) && !self.node_identity {
+            return Err(Error::Invalid(
+                "node_fields and datacenter require node_identity".to_owned(),
+            ));
+        }
+        if self.node_fields.as_ref().map_or(false, Vec::is_empty) {
+            return Err(Error::Invalid(
+                "node_fields needs at least one field".to_owned(),
+            ));
+        }
+        if let Some(encryption) = &self.encryption {
+            archive::check_recipients(&encryption.recipients).map_err(Error::Invalid)?;
+        }
+        if self.disk.warning_free_percent > 100
+            || self.disk.critical_free_percent > self.disk.warning_free_percent
+        {
+            return Err(Error::Invalid(
+                "disk thresholds must satisfy critical_free_percent <= \
+                 warning_free_percent <= 100"
+                    .to_owned(),
+            ));
+        }
+        if self.disk.full_retry_secs == 0 {
+            return Err(Error::Invalid(
+                "disk.full_retry_secs must be non-zero".to_owned(),
+            ));
+        }
+        if self.enrich.workers == 0
+            || self.enrich.queue_len == 0
+            || self.enrich.cache_entries == 0
+            || self.enrich.cache_secs == 0
+        {
+            return Err(Error::Invalid(
+                "enrich.workers, queue_len, cache_entries and cache_secs must be non-zero"
+                    .to_owned(),
+            ));
+        }
+        if let Some(geoip) = &self.geoip {
+            if !cfg!(feature = "geoip") {
+                return Err(Error::Invalid(
+                    "geoip requires cfwlogd to be built with the geoip feature".to_owned(),
+                ));
+            }
+            if geoip.country_db.is_none() && geoip.asn_db.is_none() {
+                return Err(Error::Invalid(
+                    "geoip needs a country_db, an asn_db or both".to_owned(),
+                ));
+            }
+        }
+        if let Some(aggregate) = &self.aggregate {
+            if aggregate.window_secs == 0 || aggregate.max_flows == 0 {
+                return Err(Error::Invalid(
+                    "aggregate.window_secs and max_flows must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if self.coalesce.as_ref().map_or(false, |c| c.window_ms == 0) {
+            return Err(Error::Invalid(
+                "coalesce.window_ms must be non-zero".to_owned(),
+            ));
+        }
+        if let Some(reverse_dns) = &self.reverse_dns {
+            if !cfg!(feature = "reverse-dns") {
+                return Err(Error::Invalid(
+                    "reverse_dns requires cfwlogd to be built with the reverse-dns feature"
+                        .to_owned(),
+                ));
+            }
+            if reverse_dns.timeout_ms == 0 {
+                return Err(Error::Invalid(
+                    "reverse_dns.timeout_ms must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if self.unknown_zones.max_events == 0 {
+            return Err(Error::Invalid(
+                "unknown_zones.max_events must be non-zero".to_owned(),
+            ));
+        }
+        if self.queues.reader_capacity == Some(0) || self.queues.zone_capacity == 0 {
+            return Err(Error::Invalid(
+                "queues.reader_capacity and queues.zone_capacity must be non-zero".to_owned(),
+            ));
+        }
+        if self.logger_workers == Some(0) {
+            return Err(Error::Invalid("logger_workers must be non-zero".to_owned()));
+        }
+        if self
+            .write_buffer_bytes
+            .map_or(false, |bytes| bytes < MIN_WRITE_BUFFER_BYTES)
+        {
+            return Err(Error::Invalid(format!(
+                "write_buffer_bytes must be at least {}",
+                MIN_WRITE_BUFFER_BYTES
+            )));
+        }
+        if self.reads.buffer_bytes == Some(0) {
+            return Err(Error::Invalid(
+                "reads.buffer_bytes must be non-zero".to_owned(),
+            ));
+        }
+        if self.queues.batch_records == 0 {
+            return Err(Error::Invalid(
+                "queues.batch_records must be non-zero".to_owned(),
+            ));
+        }
+        if !self.sink_plugins.is_empty() && !cfg!(feature = "dynamic-sinks") {
+            return Err(Error::Invalid(
+                "sink_plugins requires cfwlogd to be built with the dynamic-sinks feature"
+                    .to_owned(),
+            ));
+        }
+        let file_format = self.sink_formats.get("file").copied();
+        if self.sink_filters.contains_key("file")
+            || self
+                .zone_sink_filters
+                .values()
+                .any(|filters| filters.contains_key("file"))
+            || file_format.map_or(false, |format| !format.is_binary())
+            || self.sink_fields.contains_key("file")
+            || self.sink_templates.contains_key("file")
+        {
+            return Err(Error::Invalid(
+                "sink_filters, sink_fields and sink_templates don't apply to the zone's log file, \
+                 which always receives every record in full, and its only sink_formats are the \
+                 binary ones"
+                    .to_owned(),
+            ));
+        }
+        if self.sink_queues.contains_key("file") {
+            return Err(Error::Invalid(
+                "the zone's log file is always written on the zone's own thread, so sink_queues \
+                 can't have an entry for file"
+                    .to_owned(),
+            ));
+        }
+        if let Some((sink, _)) = self.sink_queues.iter().find(|(_, records)| **records == 0) {
+            return Err(Error::Invalid(format!(
+                "sink_queues.{} must hold at least one record",
+                sink
+            )));
+        }
+        if file_format.is_some() && self.indexed.is_some() {
+            return Err(Error::Invalid(
+                "indexed logs are written as json, so sink_formats can't have an entry for file"
+                    .to_owned(),
+            ));
+        }
+        for (sink, format) in &self.sink_formats {
+            if let Some(feature) = format.missing_feature() {
+                return Err(Error::Invalid(format!(
+                    "{} is formatted as {:?}, which requires cfwlogd to be built with the {} \
+                     feature",
+                    sink, format, feature
+                )));
+            }
+            if format.is_binary() && TEXT_SINKS.contains(&sink.as_str()) {
+                return Err(Error::Invalid(format!(
+                    "{} only takes text, so it can't be formatted as {:?}",
+                    sink, format
+                )));
+            }
+            if *format != Format::Json
+                && (self.sink_fields.contains_key(sink) || self.sink_templates.contains_key(sink))
+            {
+                return Err(Error::Invalid(format!(
+                    "sink_fields and sink_templates only apply to json, but {} is formatted as \
+                     {:?}",
+                    sink, format
+                )));
+            }
+        }
+        if self.vminfod.url.is_some() && self.vminfod.socket.is_some() {
+            return Err(Error::Invalid(
+                "vminfod.url and vminfod.socket are mutually exclusive".to_owned(),
+            ));
+        }
+        if let Some(url) = &self.vminfod.url {
+            if !url.starts_with("http://") {
+                return Err(Error::Invalid(
+                    "vminfod.url must be an http:// url".to_owned(),
+                ));
+            }
+        }
+        if self.vminfod.connect_timeout_secs == 0 || self.vminfod.idle_timeout_secs == Some(0) {
+            return Err(Error::Invalid(
+                "vminfod timeouts must be non-zero".to_owned(),
+            ));
+        }
+        if self
+            .handoff
+            .as_ref()
+            .map_or(false, |handoff| handoff.timeout_secs == 0)
+        {
+            return Err(Error::Invalid(
+                "handoff.timeout_secs must be non-zero".to_owned(),
+            ));
+        }
+        if self.vminfod.cache_secs == Some(0) {
+            return Err(Error::Invalid(
+                "vminfod.cache_secs must be non-zero".to_owned(),
+            ));
+        }
+        if let Some(name) = &self.log_name {
+            check_log_name(name)?;
+        }
+        self.log_permissions.validate()?;
+        for entry in &self.log_dirs {
+            if entry.vm.is_some() == entry.owner.is_some() {
+                return Err(Error::Invalid(
+                    "each log_dirs entry needs either a vm or an owner".to_owned(),
+                ));
+            }
+            if entry.dir.is_none()
+                && entry.log_name.is_none()
+                && entry.permissions == LogPermissions::default()
+            {
+                return Err(Error::Invalid(
+                    "each log_dirs entry needs a dir, a log_name or permissions".to_owned(),
+                ));
+            }
+            entry.permissions.validate()?;
+            if let Some(dir) = &entry.dir {
+                layout::check_dir(dir).map_err(Error::Invalid)?;
+                if entry.owner.is_some() && !dir.contains("{vm}") {
+                    return Err(Error::Invalid(format!(
+                        "the dir of an owner's log_dirs entry must substitute {{vm}}: {}",
+                        dir
+                    )));
+                }
+            }
+            if let Some(name) = &entry.log_name {
+                check_log_name(name)?;
+            }
+        }
+        if self.rotate_bytes == Some(0) {
+            return Err(Error::Invalid("rotate_bytes must be non-zero".to_owned()));
+        }
+        self.flush.validate("flush")?;
+        for (vm, flush) in &self.zone_flush {
+            flush.validate(&format!("zone_flush.{}", vm))?;
+        }
+        if self.rule_stats_secs == Some(0) {
+            return Err(Error::Invalid(
+                "rule_stats_secs must be non-zero".to_owned(),
+            ));
+        }
+        if self.heartbeat_secs == Some(0) {
+            return Err(Error::Invalid("heartbeat_secs must be non-zero".to_owned()));
+        }
+        if let Some(count_only) = &self.count_only {
+            if (count_only.rules.is_empty() && count_only.filter.is_none())
+                || count_only.interval_secs == 0
+            {
+                return Err(Error::Invalid(
+                    "count_only requires rules or a filter, and a non-zero interval_secs"
+                        .to_owned(),
+                ));
+            }
+        }
+        if let Some(budget) = &self.disk_budget {
+            if budget.bytes == 0 || budget.zones.values().any(|&bytes| bytes == 0) {
+                return Err(Error::Invalid(
+                    "disk_budget budgets must be non-zero".to_owned(),
+                ));
+            }
+            if budget.check_secs == 0 {
+                return Err(Error::Invalid(
+                    "disk_budget.check_secs must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if let Some(talkers) = &self.top_talkers {
+            if talkers.count == 0
+                || talkers.interval_secs == 0
+                || talkers.window_secs < talkers.interval_secs
+            {
+                return Err(Error::Invalid(
+                    "top_talkers requires a non-zero count and interval_secs, and a window_secs \
+                     of at least interval_secs"
+                        .to_owned(),
+                ));
+            }
+        }
+        if self.grpc.is_some() && !cfg!(feature = "grpc") {
+            return Err(Error::Invalid(
+                "grpc requires cfwlogd to be built with the grpc feature".to_owned(),
+            ));
+        }
+        if let Some(health) = &self.health {
+            if health.stuck_secs <= health::HEARTBEAT_INTERVAL.as_secs() {
+                return Err(Error::Invalid(format!(
+                    "health.stuck_secs must be more than {}, how often idle threads check in",
+                    health::HEARTBEAT_INTERVAL.as_secs()
+                )));
+            }
+        }
+        if self.websocket.is_some() && !cfg!(feature = "websocket") {
+            return Err(Error::Invalid(
+                "websocket requires cfwlogd to be built with the websocket feature".to_owned(),
+            ));
+        }
+        if let Some(tail) = &self.tail {
+            // The endpoint has no authentication of its own
+            if !tail.listen.ip().is_loopback() {
+                return Err(Error::Invalid(
+                    "tail.listen must be a loopback address".to_owned(),
+                ));
+            }
+        }
+        if let Some(fwapi) = &self.fwapi {
+            if !cfg!(feature = "fwapi") {
+                return Err(Error::Invalid(
+                    "fwapi requires cfwlogd to be built with the fwapi feature".to_owned(),
+                ));
+            }
+            if !fwapi.url.starts_with("http://") || fwapi.refresh_secs == 0 {
+                return Err(Error::Invalid(
+                    "fwapi requires an http:// url and a non-zero refresh_secs".to_owned(),
+                ));
+            }
+        }
+        if let Some(fwadm) = &self.fwadm {
+            if self.fwapi.is_some() {
+                return Err(Error::Invalid(
+                    "fwapi and fwadm are mutually exclusive".to_owned(),
+                ));
+            }
+            if fwadm.refresh_secs == 0 {
+                return Err(Error::Invalid(
+                    "fwadm.refresh_secs must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if let Some(alerts) = &self.alerts {
+            alert_config(alerts)?;
+        }
+        for (i, threshold) in self.thresholds.iter().enumerate() {
+            if self.thresholds[..i]
+                .iter()
+                .any(|t| t.name == threshold.name)
+            {
+                return Err(Error::Invalid(format!(
+                    "thresholds: {} is configured more than once",
+                    threshold.name
+                )));
+            }
+            threshold_config(threshold)?;
+        }
+        if let Some(syslog) = &self.syslog {
+            syslog_config(syslog)?;
+        }
+        for sampling in &self.sampling {
+            if sampling.rule.is_some() == sampling.vm.is_some() {
+                return Err(Error::Invalid(
+                    "each sampling entry needs either a rule or a vm".to_owned(),
+                ));
+            }
+            match (sampling.one_in, sampling.per_sec, sampling.burst) {
+                (Some(one_in), None, None) if one_in > 0 => (),
+                (None, Some(per_sec), burst) if per_sec > 0 && burst != Some(0) => (),
+                _ => {
+                    return Err(Error::Invalid(
+                        "each sampling entry needs a non-zero one_in, or per_sec and an optional \
+                         burst"
+                            .to_owned(),
+                    ))
+                }
+            }
+        }
+        if let Some(version) = self.schema_version {
+            if version > SCHEMA_VERSION {
+                return Err(Error::Invalid(format!(
+                    "schema_version {} is newer than the current version {}",
+                    version, SCHEMA_VERSION
+                )));
+            }
+        }
+        if let Some(rate_limit) = &self.rate_limit {
+            if rate_limit.per_sec == 0 || rate_limit.burst == Some(0) {
+                return Err(Error::Invalid(
+                    "rate_limit.per_sec and burst must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if let Some(ipfix) = &self.ipfix {
+            if ipfix.template_secs == 0 {
+                return Err(Error::Invalid(
+                    "ipfix.template_secs must be non-zero".to_owned(),
+                ));
+            }
+        }
+        if let Some(elasticsearch) = &self.elasticsearch {
+            self.elasticsearch_config(elasticsearch)?;
+        }
+        if let Some(cloudwatch) = &self.cloudwatch {
+            cloudwatch_config(cloudwatch)?;
+        }
+        if let Some(webhook) = &self.webhook {
+            webhook_config(webhook)?;
+        }
+        if let Some(manta) = &self.manta {
+            manta_config(manta)?;
+        }
+        if let Some(s3) = &self.s3 {
+            if self.manta.is_some() {
+                return Err(Error::Invalid(
+                    "manta and s3 can't both be set, rotated logs are uploaded to one store"
+                        .to_owned(),
+                ));
+            }
+            s3_config(s3)?;
+        }
+        if let Some(parquet) = &self.parquet {
+            if !cfg!(feature = "parquet") {
+                return Err(Error::Invalid(
+                    "parquet requires cfwlogd to be built with the parquet feature".to_owned(),
+                ));
+            }
+            if parquet.interval_secs == 0 || parquet.row_group_rows == 0 {
+                return Err(Error::Invalid(
+                    "parquet.interval_secs and row_group_rows must be non-zero".to_owned(),
+                ));
+            }
+            if self.sink_formats.contains_key("parquet")
+                || self.sink_fields.contains_key("parquet")
+                || self.sink_templates.contains_key("parquet")
+            {
+                return Err(Error::Invalid(
+                    "parquet files have fixed columns, so sink_formats, sink_fields and \
+                     sink_templates don't apply to them"
+                        .to_owned(),
+                ));
+            }
+        }
+        if let Some(logadm) = &self.logadm {
+            if logadm.file.is_empty() || logadm.file.contains('/') || logadm.file.starts_with('.') {
+                return Err(Error::Invalid(format!(
+                    "logadm.file must name a file in the log directory: {}",
+                    logadm.file
+                )));
+            }
+            if logadm.options.contains('\n') {
+                return Err(Error::Invalid(
+                    "logadm.options must be a single line".to_owned(),
+                ));
+            }
+        }
+        if let Some(zone_fs) = &self.zone_fs {
+            layout::check_dir(&zone_fs.path)
+                .map_err(|e| Error::Invalid(format!("zone_fs.path {}", e)))?;
+            if !zone_fs.zones_dir.is_absolute() || zone_fs.max_bytes == 0 {
+                return Err(Error::Invalid(
+                    "zone_fs.zones_dir must be absolute and max_bytes non-zero".to_owned(),
+                ));
+            }
+        }
+        if let Some(zstd) = &self.zstd {
+            if !cfg!(feature = "zstd") {
+                return Err(Error::Invalid(
+                    "zstd requires cfwlogd to be built with the zstd feature".to_owned(),
+                ));
+            }
+            if !(1..=19).contains(&zstd.level) || zstd.frame_secs == 0 {
+                return Err(Error::Invalid(
+                    "zstd requires a level from 1 to 19 and a non-zero frame_secs".to_owned(),
+                ));
+            }
+        }
+        if let Some(indexed) = &self.indexed {
+            if !(4..=1024).contains(&indexed.block_kib) {
+                return Err(Error::Invalid(
+                    "indexed.block_kib must be from 4 to 1024".to_owned(),
+                ));
+            }
+            let compressed = self.zstd.as_ref().map_or(&[][..], |zstd| &zstd.zones[..]);
+            if let Some(vm) = indexed.zones.iter().find(|vm| compressed.contains(vm)) {
+                return Err(Error::Invalid(format!(
+                    "zone {} can't be both compressed and indexed",
+                    vm
+                )));
+            }
+        }
+        match &self.source {
+            SourceConfig::Ipfev { devices } if devices.is_empty() => {
+                return Err(Error::Invalid(
+                    "the ipfev source requires at least one device".to_owned(),
+                ));
+            }
+            SourceConfig::Replay {
+                speed: Some(speed), ..
+            } if !speed.is_finite() || *speed <= 0.0 => {
+                return Err(Error::Invalid(
+                    "the replay source requires a positive speed".to_owned(),
+                ));
+            }
+            SourceConfig::Simulator { rate, zones } if *rate == 0 || zones.is_empty() => {
+                return Err(Error::Invalid(
+                    "the simulator source requires a non-zero rate and at least one zone"
+                        .to_owned(),
+                ));
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Check an `ElasticsearchConfig` for values deserialization can't catch
+    fn elasticsearch_config(&self, elasticsearch: &ElasticsearchConfig) -> Result<(), Error> {
+        let invalid = |msg: &str| Err(Error::Invalid(format!("elasticsearch: {}", msg)));
+        if !cfg!(feature = "elasticsearch") {
+            return invalid("requires cfwlogd to be built with the elasticsearch feature");
+        }
+        if !elasticsearch.url.starts_with("http://") && !elasticsearch.url.starts_with("https://") {
+            return invalid("url must be an http:// or https:// url");
+        }
+        tls_files(
+            elasticsearch.url.starts_with("https://"),
+            &elasticsearch.tls_ca,
+            &elasticsearch.tls_cert,
+            &elasticsearch.tls_key,
+            &elasticsearch.tls_pin_sha256,
+        )
+        .or_else(|msg| invalid(&msg))?;
+        if elasticsearch.batch_size == 0
+            || elasticsearch.flush_secs == 0
+            || elasticsearch.max_backoff_secs == 0
+        {
+            return invalid("batch_size, flush_secs and max_backoff_secs must be non-zero");
+        }
+        if elasticsearch
+            .spool_bytes
+            .map_or(false, |bytes| bytes < spool::MIN_BYTES)
+        {
+            return invalid(&format!(
+                "spool_bytes must be at least {}",
+                spool::MIN_BYTES
+            ));
+        }
+        let literal = elasticsearch
+            .index
+            .replace("{owner_uuid}", "")
+            .replace("{vm}", "")
+            .replace("{date}", "");
+        if elasticsearch.index.is_empty()
+            || literal.contains(|c: char| c == '{' || c == '}' || c.is_uppercase())
+        {
+            return invalid(
+                "index must be lowercase, with only {owner_uuid}, {vm} and {date} substituted",
+            );
+        }
+        let format = self.sink_formats.get("elasticsearch").copied();
+        if format.unwrap_or_default() != Format::Json
+            || self.sink_templates.contains_key("elasticsearch")
+        {
+            return invalid("records have to be sent as json");
+        }
+        Ok(())
+    }
+
+    /// The schema version records are written in
+    pub fn schema_version(&self) -> u64 {
+        self.schema_version.unwrap_or(SCHEMA_VERSION)
+    }
+
+    /// The "log_permissions" and those of every "log_dirs" entry
+    pub fn all_log_permissions(&self) -> impl Iterator<Item = &LogPermissions> {
+        std::iter::once(&self.log_permissions).chain(self.log_dirs.iter().map(|e| &e.permissions))
+    }
+
+    /// The configured level of cfwlogd's own log, if any
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log_level.as_ref().and_then(|level| level.parse().ok())
+    }
+
+    /// How records' addresses and timestamps are written, see `parser::Serialization`
+    pub fn serialization(&self) -> Serialization {
+        Serialization {
+            normalize_ipv4_mapped: self.normalize_ipv4_mapped,
+            timestamp_format: self.timestamp_format,
+            timestamp_timezone: self.timestamp_timezone,
+        }
+    }
+
+    /// The flush policy of a zone's log file, from "zone_flush" when the zone has an entry
+    pub fn flush_for(&self, vm: &str) -> &FlushConfig {
+        Uuid::parse_str(vm)
+            .ok()
+            .and_then(|vm| self.zone_flush.get(&vm))
+            .unwrap_or(&self.flush)
+    }
+
+    /// Which of a zone's traffic events are logged, from "zone_log_actions" when the zone has an
+    /// entry
+    pub fn log_actions_for(&self, vm: &str) -> LogActions {
+        Uuid::parse_str(vm)
+            .ok()
+            .and_then(|vm| self.zone_log_actions.get(&vm))
+            .copied()
+            .unwrap_or(self.log_actions)
+    }
+
+    /// The expression limiting the zone's records the sink receives, from "zone_sink_filters" when
+    /// the zone has an entry for the sink
+    pub fn sink_filter(&self, vm: &str, sink: &str) -> Option<&Expr> {
+        Uuid::parse_str(vm)
+            .ok()
+            .and_then(|vm| self.zone_sink_filters.get(&vm))
+            .and_then(|filters| filters.get(sink))
+            .or_else(|| self.sink_filters.get(sink))
+    }
+
+    /// Merge a reloaded config into this, the running one. The settings that are only read at
+    /// startup keep their running values, and the names of the ones that were changed are
+    /// returned since those changes only take effect once cfwlogd restarts.
+    pub fn reload(&self, mut new: Config) -> (Config, Vec<&'static str>) {
+        let mut ignored = vec![];
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if new.$field != self.$field {
+                        ignored.push(stringify!($field));
+                        new.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            startup_mode,
+            vminfod,
+            retire_grace_secs,
+            service_manager,
+            source,
+            memory_limit_mb,
+            disk,
+            queues,
+            reads,
+            logger_workers,
+            write_buffer_bytes,
+            enrich,
+            unknown_zones,
+            loss_audit,
+            foreground,
+            stdout,
+            user,
+            node_identity,
+            node_fields,
+            datacenter,
+            top_talkers,
+            cmon_metrics,
+            sink_plugins,
+            grpc,
+            websocket,
+            tail,
+            firehose,
+            health,
+            admin,
+            handoff,
+            fwapi,
+            fwadm,
+            alerts,
+            thresholds,
+            syslog,
+            ipfix,
+            log_layout,
+            log_dirs,
+            log_permissions,
+            elasticsearch,
+            cloudwatch,
+            webhook,
+            parquet,
+            manta,
+            s3,
+            zone_fs,
+            logadm,
+            sink_queues,
+            aggregate,
+            coalesce,
+            geoip,
+            reverse_dns
+        );
+        (new, ignored)
+    }
+}
+
+/// The config file along with the directory it lives in, which is opened before we chroot so the
+/// file can still be reloaded afterwards.
+pub struct ConfigFile {
+    /// None when the directory doesn't exist, in which case the defaults are used
+    dir: Option<File>,
+    name: String,
+}
+
+impl ConfigFile {
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<ConfigFile> {
+        let path = path.into();
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid config file path");
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(invalid)?
+            .to_owned();
+        let dir = match File::open(path.parent().ok_or_else(invalid)?) {
+            Ok(dir) => Some(dir),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        Ok(ConfigFile { dir, name })
+    }
+
+    /// Load the `Config`, falling back to the defaults if the file does not exist.
+    pub fn load(&self) -> Result<Config, Error> {
+        let dir = match &self.dir {
+            Some(dir) => dir,
+            None => return Ok(Config::default()),
+        };
+        match fileutils::open_read_nofollow(dir, &self.name) {
+            Ok(mut file) => {
+                let mut s = String::new();
+                file.read_to_string(&mut s)?;
+                Config::from_toml(&s)
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Check a "log_name" template
+fn check_log_name(name: &str) -> Result<(), Error> {
+    let invalid = StrftimeItems::new(name).any(|item| item == Item::Error);
+    if invalid || name.contains('/') || !name.ends_with(".log") || name == "stats.log" {
+        return Err(Error::Invalid(
+            "log_name must be a valid strftime template for a file name ending in .log".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check an `AlertConfig` for values deserialization can't catch
+fn alert_config(alerts: &AlertConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("alerts: {}", msg)));
+    if alerts.block_rate == 0 || alerts.window_secs == 0 {
+        return invalid("block_rate and window_secs must be non-zero");
+    }
+    if alerts.webhook.is_none() && alerts.snmp.is_none() {
+        return invalid("at least one of webhook or snmp is required");
+    }
+    if let Some(url) = &alerts.webhook {
+        if !cfg!(feature = "webhook") {
+            return invalid("webhook requires cfwlogd to be built with the webhook feature");
+        }
+        if !url.starts_with("http://") {
+            return invalid("webhook must be an http:// url");
+        }
+    }
+    if let Some(snmp) = &alerts.snmp {
+        alert::parse_oid(&snmp.trap_oid).map_err(Error::Invalid)?;
+    }
+    Ok(())
+}
+
+/// Check a `ThresholdConfig` for values deserialization can't catch
+fn threshold_config(threshold: &ThresholdConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| {
+        Err(Error::Invalid(format!(
+            "thresholds.{}: {}",
+            threshold.name, msg
+        )))
+    };
+    if threshold.name.is_empty() {
+        return Err(Error::Invalid("thresholds: name must be set".to_owned()));
+    }
+    if threshold.window_secs == 0 {
+        return invalid("window_secs must be non-zero");
+    }
+    if threshold.exec.is_none() && threshold.webhook.is_none() && !threshold.alert_log {
+        return invalid("at least one of exec, webhook or alert_log is required");
+    }
+    if let Some(command) = &threshold.exec {
+        if !command
+            .first()
+            .map_or(false, |program| program.starts_with('/'))
+        {
+            return invalid("exec must start with the program's absolute path");
+        }
+    }
+    if let Some(url) = &threshold.webhook {
+        if !cfg!(feature = "webhook") {
+            return invalid("webhook requires cfwlogd to be built with the webhook feature");
+        }
+        if !url.starts_with("http://") {
+            return invalid("webhook must be an http:// url");
+        }
+    }
+    Ok(())
+}
+
+/// Check the "tls_*" settings a sink shares with the others using the "tls" module, which only
+/// apply when the sink uses TLS
+fn tls_files(
+    uses_tls: bool,
+    ca: &Option<PathBuf>,
+    cert: &Option<PathBuf>,
+    key: &Option<PathBuf>,
+    pins: &[String],
+) -> Result<(), String> {
+    if !uses_tls && (ca.is_some() || cert.is_some() || key.is_some() || !pins.is_empty()) {
+        return Err("tls_ca, tls_cert, tls_key and tls_pin_sha256 only apply to TLS".to_owned());
+    }
+    if cert.is_some() != key.is_some() {
+        return Err("tls_cert and tls_key go together".to_owned());
+    }
+    for pin in pins {
+        let digits: Vec<char> = pin.chars().filter(|c| *c != ':').collect();
+        if digits.len() != 64 || !digits.iter().all(char::is_ascii_hexdigit) {
+            return Err(format!(
+                "tls_pin_sha256 entry {} isn't a SHA-256 fingerprint",
+                pin
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check a `SyslogConfig` for values deserialization can't catch
+fn syslog_config(syslog: &SyslogConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("syslog: {}", msg)));
+    if syslog.facility > 23 {
+        return invalid("facility must be between 0 and 23");
+    }
+    let sd_name = |name: &str| {
+        !name.is_empty()
+            && name.len() <= 32
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && !b"=]\"".contains(&b))
+    };
+    let mut parts = syslog.sd_id.splitn(2, '@');
+    let valid_sd_id = match (parts.next(), parts.next()) {
+        (Some(name), Some(number)) => {
+            sd_name(&syslog.sd_id)
+                && sd_name(name)
+                && !number.is_empty()
+                && number.bytes().all(|b| b.is_ascii_digit())
+        }
+        _ => false,
+    };
+    if !valid_sd_id {
+        return invalid("sd_id must look like name@<private enterprise number>");
+    }
+    if syslog.tls && !cfg!(feature = "syslog-tls") {
+        return invalid("tls requires cfwlogd to be built with the syslog-tls feature");
+    }
+    tls_files(
+        syslog.tls,
+        &syslog.tls_ca,
+        &syslog.tls_cert,
+        &syslog.tls_key,
+        &syslog.tls_pin_sha256,
+    )
+    .or_else(|msg| invalid(&msg))?;
+    if syslog.tls_server_name.is_some() && !syslog.tls {
+        return invalid("tls_server_name requires tls");
+    }
+    if syslog
+        .spool_bytes
+        .map_or(false, |bytes| bytes < spool::MIN_BYTES)
+    {
+        return invalid(&format!(
+            "spool_bytes must be at least {}",
+            spool::MIN_BYTES
+        ));
+    }
+    Ok(())
+}
+
+/// Check a `MantaConfig` for values deserialization can't catch
+fn manta_config(manta: &MantaConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("manta: {}", msg)));
+    if !cfg!(feature = "manta") {
+        return invalid("requires cfwlogd to be built with the manta feature");
+    }
+    if !manta.url.starts_with("https://") {
+        return invalid("url must be an https:// url");
+    }
+    if manta.user.is_empty() || manta.user.contains('/') || manta.key_id.is_empty() {
+        return invalid("user must be an account name, and key_id must be set");
+    }
+    let literal = ["{user}", "{owner_uuid}", "{vm}", "{date}", "{file}"]
+        .iter()
+        .fold(manta.path.clone(), |path, name| path.replace(name, ""));
+    if !manta.path.starts_with('/')
+        || !manta.path.contains("{file}")
+        || literal.contains(|c: char| c == '{' || c == '}')
+    {
+        return invalid(
+            "path must be absolute, include {file}, and only has {user}, {owner_uuid}, {vm}, \
+             {date} and {file} substituted",
+        );
+    }
+    if manta.max_backoff_secs == 0 {
+        return invalid("max_backoff_secs must be non-zero");
+    }
+    Ok(())
+}
+
+/// Check an `S3Config` for values deserialization can't catch
+fn s3_config(s3: &S3Config) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("s3: {}", msg)));
+    if !cfg!(feature = "s3") {
+        return invalid("requires cfwlogd to be built with the s3 feature");
+    }
+    if !s3.endpoint.starts_with("https://") {
+        return invalid("endpoint must be an https:// url");
+    }
+    if s3.bucket.is_empty() || s3.bucket.contains('/') || s3.region.is_empty() {
+        return invalid("bucket must be a bucket's name, and region must be set");
+    }
+    let literal = ["{owner_uuid}", "{vm}", "{date}"]
+        .iter()
+        .fold(s3.prefix.clone(), |prefix, name| prefix.replace(name, ""));
+    if s3.prefix.starts_with('/') || literal.contains(|c: char| c == '{' || c == '}') {
+        return invalid(
+            "prefix must be relative to the bucket, and only has {owner_uuid}, {vm} and {date} \
+             substituted",
+        );
+    }
+    if s3.part_bytes < S3_MIN_PART_BYTES || s3.multipart_threshold_bytes < s3.part_bytes {
+        return invalid(&format!(
+            "part_bytes must be at least {}, and multipart_threshold_bytes at least part_bytes",
+            S3_MIN_PART_BYTES
+        ));
+    }
+    if s3.max_backoff_secs == 0 || s3.max_attempts == 0 {
+        return invalid("max_backoff_secs and max_attempts must be non-zero");
+    }
+    let dir = Path::new(&s3.dead_letter_dir);
+    if s3.dead_letter_dir.is_empty() || !dir.components().all(|c| matches!(c, Component::Normal(_)))
+    {
+        return invalid("dead_letter_dir must be a path within the log directory");
+    }
+    Ok(())
+}
+
+/// Check a `CloudwatchConfig` for values deserialization can't catch
+fn cloudwatch_config(cloudwatch: &CloudwatchConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("cloudwatch: {}", msg)));
+    if !cfg!(feature = "cloudwatch") {
+        return invalid("requires cfwlogd to be built with the cloudwatch feature");
+    }
+    if cloudwatch.region.is_empty()
+        || !cloudwatch
+            .region
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        return invalid("region must be an AWS region such as us-east-1");
+    }
+    if cloudwatch.log_group.is_empty() {
+        return invalid("log_group must be set");
+    }
+    let literal = cloudwatch
+        .log_stream
+        .replace("{owner_uuid}", "")
+        .replace("{vm}", "");
+    if cloudwatch.log_stream.is_empty()
+        || literal.contains(|c: char| c == '{' || c == '}' || c == ':' || c == '*')
+    {
+        return invalid(
+            "log_stream must not contain : or *, and only has {owner_uuid} and {vm} substituted",
+        );
+    }
+    if cloudwatch
+        .endpoint
+        .as_ref()
+        .map_or(false, |endpoint| !endpoint.starts_with("https://"))
+    {
+        return invalid("endpoint must be an https:// url");
+    }
+    if cloudwatch.access_key_id.is_some() != cloudwatch.secret_access_key.is_some()
+        || (cloudwatch.session_token.is_some() && cloudwatch.access_key_id.is_none())
+    {
+        return invalid(
+            "access_key_id and secret_access_key go together, along with any session_token",
+        );
+    }
+    if cloudwatch.batch_size == 0
+        || cloudwatch.batch_size > CLOUDWATCH_MAX_BATCH
+        || cloudwatch.flush_secs == 0
+        || cloudwatch.max_backoff_secs == 0
+    {
+        return invalid(&format!(
+            "batch_size must be from 1 to {}, and flush_secs and max_backoff_secs non-zero",
+            CLOUDWATCH_MAX_BATCH
+        ));
+    }
+    Ok(())
+}
+
+/// Check a `WebhookConfig` for values deserialization can't catch
+fn webhook_config(webhook: &WebhookConfig) -> Result<(), Error> {
+    let invalid = |msg: &str| Err(Error::Invalid(format!("webhook: {}", msg)));
+    if !cfg!(feature = "webhook") {
+        return invalid("requires cfwlogd to be built with the webhook feature");
+    }
+    if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+        return invalid("url must be an http:// or https:// url");
+    }
+    tls_files(
+        webhook.url.starts_with("https://"),
+        &webhook.tls_ca,
+        &webhook.tls_cert,
+        &webhook.tls_key,
+        &webhook.tls_pin_sha256,
+    )
+    .or_else(|msg| invalid(&msg))?;
+    for name in webhook.headers.keys() {
+        let token = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        if name.is_empty() || !name.chars().all(token) {
+            return invalid(&format!("{:?} isn't a valid header name", name));
+        }
+        if WEBHOOK_RESERVED_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            return invalid(&format!("the {} header is set by cfwlogd", name));
+        }
+    }
+    if webhook
+        .headers
+        .values()
+        .any(|value| value.0.contains(|c: char| c == '\r' || c == '\n'))
+    {
+        return invalid("header values must be a single line");
+    }
+    if webhook.batch_size == 0
+        || webhook.flush_secs == 0
+        || webhook.max_backoff_secs == 0
+        || webhook.breaker_failures == 0
+        || webhook.breaker_secs == 0
+    {
+        return invalid(
+            "batch_size, flush_secs, max_backoff_secs, breaker_failures and breaker_secs must be \
+             non-zero",
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config_uses_defaults() {
+        let config = Config::from_toml("").expect("failed to parse empty config");
+        assert_eq!(
+            config,
+            Config::default(),
+            "empty config matches the defaults"
+        );
+        assert_eq!(config.startup_mode, StartupMode::Strict);
+    }
+
+    #[test]
+    fn missing_config_uses_defaults() {
+        for path in &[
+            "/var/tmp/does-not-exist.toml",
+            "/var/tmp/cfwlogd-tests/does-not-exist/config.toml",
+        ] {
+            let config = ConfigFile::open(*path)
+                .expect("missing config directory is not an error")
+                .load()
+                .expect("missing config file is not an error");
+            assert_eq!(
+                config,
+                Config::default(),
+                "missing config matches the defaults"
+            );
+        }
+    }
+
+    #[test]
+    fn config_file_is_reloaded() {
+        let dir: PathBuf = ["/var/tmp/cfwlogd-tests", &Uuid::new_v4().to_string()]
+            .iter()
+            .collect();
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let file = ConfigFile::open(&path).unwrap();
+        assert_eq!(file.load().unwrap(), Config::default());
+
+        std::fs::write(&path, "epoch_timestamp = \"ms\"\ncmon_metrics = true\n").unwrap();
+        let reloaded = file.load().expect("the new file is read");
+        let (config, ignored) = Config::default().reload(reloaded);
+        assert_eq!(
+            config.epoch_timestamp,
+            Some(EpochUnit::Ms),
+            "applied at runtime"
+        );
+        assert!(!config.cmon_metrics, "only read at startup");
+        assert_eq!(ignored, vec!["cmon_metrics"]);
+
+        std::fs::write(&path, "not_an_option = true\n").unwrap();
+        assert!(file.load().is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn parse_startup_mode() {
+        let config = Config::from_toml(r#"startup_mode = "permissive""#)
+            .expect("failed to parse startup_mode");
+        assert_eq!(config.startup_mode, StartupMode::Permissive);
+
+        assert!(
+            Config::from_toml(r#"startup_mode = "sometimes""#).is_err(),
+            "unknown startup modes are rejected"
+        );
+    }
+
+    #[test]
+    fn parse_source() {
+        assert_eq!(
+            Config::default().source,
+            SourceConfig::Ipfev {
+                devices: vec![PathBuf::from("/dev/ipfev")],
+            },
+            "ipfev is the default source"
+        );
+        let config = Config::from_toml(
+            "[source]\ntype = \"ipfev\"\ndevices = [\"/dev/ipfev\", \"/dev/ipfev1\"]",
+        )
+        .expect("failed to parse ipfev devices");
+        assert_eq!(
+            config.source,
+            SourceConfig::Ipfev {
+                devices: vec![PathBuf::from("/dev/ipfev"), PathBuf::from("/dev/ipfev1")],
+            }
+        );
+        assert!(
+            Config::from_toml("[source]\ntype = \"ipfev\"\ndevices = []").is_err(),
+            "ipfev requires a device"
+        );
+        let config = Config::from_toml("[source]\ntype = \"replay\"\npath = \"/var/tmp/events\"")
+            .expect("failed to parse replay source");
+        assert_eq!(
+            config.source,
+            SourceConfig::Replay {
+                path: PathBuf::from("/var/tmp/events"),
+                speed: None,
+            }
+        );
+        assert!(
+            Config::from_toml(
+                "[source]\ntype = \"replay\"\npath = \"/var/tmp/events\"\nspeed = 0.0"
+            )
+            .is_err(),
+            "replays need a positive speed"
+        );
+        let config = Config::from_toml("[source]\ntype = \"nflog\"\ngroup = 5")
+            .expect("failed to parse source");
+        assert_eq!(config.source, SourceConfig::Nflog { group: 5 });
+        assert!(
+            Config::from_toml("[source]\ntype = \"nflog\"").is_err(),
+            "nflog requires a group"
+        );
+
+        let config = Config::from_toml("[source]\ntype = \"simulator\"\nzones = [1, 2]")
+            .expect("failed to parse simulator source");
+        assert_eq!(
+            config.source,
+            SourceConfig::Simulator {
+                rate: 100,
+                zones: vec![1, 2]
+            }
+        );
+        assert!(
+            Config::from_toml("[source]\ntype = \"simulator\"\nzones = []").is_err(),
+            "the simulator requires zones"
+        );
+
+        let uuid = Uuid::new_v4();
+        let config = Config::from_toml(&format!(
+            "[source]\ntype = \"pflog\"\n[source.rules]\n\"12/3\" = \"{}\"",
+            uuid
+        ))
+        .expect("failed to parse pflog source");
+        let mut rules = HashMap::new();
+        rules.insert("12/3".to_owned(), uuid);
+        assert_eq!(
+            config.source,
+            SourceConfig::Pflog {
+                interface: "pflog0".to_owned(),
+                rules
+            }
+        );
+    }
+
+    #[test]
+    fn encryption_requires_recipients() {
+        assert!(
+            Config::from_toml("[encryption]\nrecipients = []").is_err(),
+            "encryption without any recipients is rejected"
+        );
+    }
+
+    #[test]
+    fn parse_disk_thresholds() {
+        let config = Config::from_toml("[disk]\ncritical_free_percent = 2")
+            .expect("failed to parse disk thresholds");
+        assert_eq!(
+            config.disk.warning_free_percent, 10,
+            "unset threshold uses the default"
+        );
+        assert_eq!(config.disk.critical_free_percent, 2);
+        assert_eq!(config.disk.full_buffer_records, 10_000);
+
+        assert!(
+            Config::from_toml("[disk]\nwarning_free_percent = 1").is_err(),
+            "a warning threshold below the critical threshold is rejected"
+        );
+        assert!(Config::from_toml("[disk]\nfull_retry_secs = 0").is_err());
+    }
+
+    #[test]
+    fn parse_queues() {
+        let config = Config::from_toml("[queues]\nzone_capacity = 100\noverflow = \"drop-oldest\"")
+            .expect("failed to parse queues");
+        assert_eq!(
+            config.queues,
+            QueueConfig {
+                reader_capacity: None,
+                zone_capacity: 100,
+                overflow: Overflow::DropOldest,
+                batch_records: 1024,
+                batch_delay_us: 500,
+            }
+        );
+        assert!(Config::from_toml("[queues]\nreader_capacity = 0").is_err());
+        assert!(Config::from_toml("[queues]\nbatch_records = 0").is_err());
+        assert!(
+            Config::from_toml("[queues]\noverflow = \"drop-everything\"").is_err(),
+            "unknown overflow policies are rejected"
+        );
+    }
+
+    #[test]
+    fn parse_reads() {
+        let config = Config::from_toml("[reads]\nbuffer_bytes = 1048576\ndouble_buffer = true")
+            .expect("failed to parse reads");
+        assert_eq!(
+            config.reads,
+            ReadConfig {
+                buffer_bytes: Some(1_048_576),
+                double_buffer: true,
+            }
+        );
+        assert_eq!(Config::default().reads, ReadConfig::default());
+        assert!(Config::from_toml("[reads]\nbuffer_bytes = 0").is_err());
+    }
+
+    #[test]
+    fn parse_logger_workers() {
+        let config = Config::from_toml("logger_workers = 8").expect("valid logger_workers");
+        assert_eq!(config.logger_workers, Some(8));
+        assert_eq!(Config::default().logger_workers, None, "a thread per zone");
+        assert!(Config::from_toml("logger_workers = 0").is_err());
+    }
+
+    #[test]
+    fn parse_write_buffer_bytes() {
+        let config = Config::from_toml("write_buffer_bytes = 65536").expect("valid buffer size");
+        assert_eq!(config.write_buffer_bytes, Some(65536));
+        assert!(Config::from_toml("write_buffer_bytes = 1024").is_err());
+    }
+
+    #[test]
+    fn parse_unknown_zones() {
+        let config =
+            Config::from_toml("[unknown_zones]\nhold_secs = 0").expect("valid unknown_zones");
+        assert_eq!(
+            config.unknown_zones.hold_secs, 0,
+            "events can go straight to the log"
+        );
+        assert_eq!(config.unknown_zones.max_events, 1000);
+        assert!(Config::from_toml("[unknown_zones]\nmax_events = 0").is_err());
+    }
+
+    #[test]
+    fn parse_alerts() {
+        let config = Config::from_toml(
+            r#"
+            [alerts]
+            block_rate = 50
+            snmp = { target = "10.99.99.5:162" }
+            "#,
+        )
+        .expect("valid alerts config");
+        let alerts = config.alerts.expect("alerts are configured");
+        assert_eq!(alerts.window_secs, 60, "window defaults to a minute");
+        assert_eq!(alerts.snmp.unwrap().community, "public");
+
+        assert!(
+            Config::from_toml("[alerts]\nblock_rate = 50\n").is_err(),
+            "a destination is required"
+        );
+        assert!(Config::from_toml(
+            "[alerts]\nblock_rate = 50\nsnmp = { target = \"a:162\", trap_oid = \"x\" }\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn parse_thresholds() {
+        let config = Config::from_toml(
+            r#"
+            [[thresholds]]
+            name = "ssh"
+            events = 100
+            filter = "action == block && dport == 22"
+            exec = ["/opt/custom/bin/ban", "--zone"]
+            alert_log = true
+            "#,
+        )
+        .expect("valid thresholds");
+        assert_eq!(
+            config.thresholds[0].window_secs, 60,
+            "window defaults to a minute"
+        );
+        assert_eq!(config.thresholds[0].cooldown_secs, 300);
+        assert!(config.thresholds[0].filter.is_some());
+
+        assert!(
+            Config::from_toml("[[thresholds]]\nname = \"ssh\"\nevents = 100\n").is_err(),
+            "an action is required"
+        );
+        assert!(
+            Config::from_toml("[[thresholds]]\nname = \"ssh\"\nevents = 1\nexec = [\"ban\"]\n")
+                .is_err(),
+            "commands are run by absolute path"
+        );
+        let twice = "[[thresholds]]\nname = \"ssh\"\nevents = 1\nalert_log = true\n";
+        assert!(
+            Config::from_toml(&twice.repeat(2)).is_err(),
+            "names are unique"
+        );
+    }
+
+    #[test]
+    fn parse_sink_filters() {
+        let config = Config::from_toml(
+            r#"
+            [sink_filters]
+            live = "action == block && dport in (22, 3389)"
+            "#,
+        )
+        .expect("valid sink filters");
+        assert!(config.sink_filters.contains_key("live"));
+
+        assert!(
+            Config::from_toml("[sink_filters]\nlive = \"dport in 22\"\n").is_err(),
+            "expressions are compiled when the config is loaded"
+        );
+        assert!(
+            Config::from_toml("[sink_filters]\nfile = \"dport == 22\"\n").is_err(),
+            "the zone's log file can't be filtered"
+        );
+
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let config = Config::from_toml(&format!(
+            "[sink_filters]\nlive = \"dir == in\"\n\
+             [zone_sink_filters.\"{}\"]\nlive = \"dir == out\"\n",
+            vm
+        ))
+        .expect("valid zone sink filters");
+        assert_ne!(
+            config.sink_filter(vm, "live"),
+            config.sink_filter("other", "live"),
+            "the zone's entry replaces the sink's"
+        );
+        assert!(config.sink_filter(vm, "cmon").is_none());
+        assert!(Config::from_toml(&format!(
+            "[zone_sink_filters.\"{}\"]\nfile = \"dport == 22\"\n",
+            vm
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn parse_drop_filters() {
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let config = Config::from_toml(&format!(
+            "drop_filter = \"proto == udp && dport == 53\"\n[zone_drop_filters]\n\"{}\" = \
+             \"direction == in && dport in (22, 2222)\"\n",
+            vm
+        ))
+        .expect("valid drop filters");
+        assert!(config.drop_filter.is_some());
+        assert!(config.zone_drop_filters.contains_key(&vm.parse().unwrap()));
+        assert!(Config::from_toml("drop_filter = \"dport in\"\n").is_err());
+        assert!(
+            Config::from_toml("[zone_drop_filters]\nzone = \"dport == 22\"\n").is_err(),
+            "zones are named by uuid"
+        );
+    }
+
+    #[test]
+    fn parse_log_actions() {
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let config = Config::from_toml(&format!(
+            "log_actions = \"block\"\n[zone_log_actions]\n\"{}\" = \"both\"\n",
+            vm
+        ))
+        .expect("valid log actions");
+        assert_eq!(config.log_actions_for("other"), LogActions::Block);
+        assert_eq!(config.log_actions_for(vm), LogActions::Both);
+        assert!(!LogActions::Block.logs(&CfwEvType::Begin));
+        assert!(LogActions::Allow.logs(&CfwEvType::End));
+        assert!(Config::from_toml("log_actions = \"deny\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_disk_budget() {
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let config = Config::from_toml(&format!(
+            "[disk_budget]\nbytes = 1000\naction = \"drop\"\n[disk_budget.zones]\n\"{}\" = 5000\n",
+            vm
+        ))
+        .expect("valid disk_budget");
+        let budget = config.disk_budget.unwrap();
+        assert_eq!(budget.action, BudgetAction::Drop);
+        assert_eq!(budget.check_secs, 60);
+        assert_eq!(budget.bytes_for(vm), 5000);
+        assert_eq!(budget.bytes_for("other"), 1000);
+        let config = Config::from_toml("[disk_budget]\nbytes = 1000\n").unwrap();
+        assert_eq!(config.disk_budget.unwrap().action, BudgetAction::CountOnly);
+        assert!(Config::from_toml("[disk_budget]\nbytes = 0\n").is_err());
+        assert!(Config::from_toml("[disk_budget]\nbytes = 1\ncheck_secs = 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_count_only() {
+        let config = Config::from_toml(
+            "[count_only]\nrules = [\"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d\"]\nfilter = \"dport == \
+             53\"\n",
+        )
+        .expect("valid count_only");
+        assert_eq!(config.count_only.unwrap().interval_secs, 60);
+        assert!(
+            Config::from_toml("[count_only]\ninterval_secs = 60\n").is_err(),
+            "something has to be counted"
+        );
+    }
+
+    #[test]
+    fn parse_tail() {
+        let config = Config::from_toml("[tail]\nlisten = \"127.0.0.1:9202\"\n").unwrap();
+        assert_eq!(config.tail.unwrap().listen.port(), 9202);
+        assert!(
+            Config::from_toml("[tail]\nlisten = \"0.0.0.0:9202\"\n").is_err(),
+            "only loopback"
+        );
+    }
+
+    #[test]
+    fn parse_sink_fields() {
+        let config = Config::from_toml("[sink_fields.live]\ninclude = [\".vm\"]\n")
+            .expect("valid sink fields");
+        assert_eq!(config.sink_fields["live"].include.len(), 1);
+        assert!(
+            Config::from_toml("[sink_fields.live]\ninclude = [\"vm\"]\n").is_err(),
+            "paths start with a '.'"
+        );
+    }
+
+    #[test]
+    fn parse_log_level() {
+        let config = Config::from_toml("log_level = \"debug\"\nlog_format = \"json\"\n")
+            .expect("valid log settings");
+        assert_eq!(config.log_level(), Some(LevelFilter::Debug));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(
+            Config::default().log_level(),
+            None,
+            "RUST_LOG decides by default"
+        );
+        assert!(
+            Config::from_toml("log_level = \"loud\"\n").is_err(),
+            "unknown levels are rejected"
+        );
+    }
+
+    #[test]
+    fn parse_user() {
+        let config = Config::from_toml("user = \"cfwlog\"\n").expect("valid user");
+        assert_eq!(config.user.as_deref(), Some("cfwlog"));
+        assert_eq!(Config::default().user, None, "root by default");
+    }
+
+    #[test]
+    fn parse_health() {
+        let config = Config::from_toml("[health]\nsocket = \"/var/run/cfwlogd-health.sock\"\n")
+            .expect("valid health config");
+        let health = config.health.unwrap();
+        assert_eq!(health.socket, PathBuf::from("/var/run/cfwlogd-health.sock"));
+        assert_eq!(health.stuck_secs, 120);
+        assert!(
+            Config::from_toml("[health]\nsocket = \"/s\"\nstuck_secs = 5\n").is_err(),
+            "idle threads would look stuck"
+        );
+    }
+
+    #[test]
+    fn parse_admin() {
+        let config = Config::from_toml("[admin]\n").expect("valid admin config");
+        assert_eq!(
+            config.admin.unwrap().socket,
+            PathBuf::from("/var/run/cfwlogd.sock")
+        );
+        assert_eq!(Config::default().admin, None, "no admin socket by default");
+    }
+
+    #[test]
+    fn parse_sink_queues() {
+        let config = Config::from_toml("[sink_queues]\nsyslog = 1000\n").expect("valid queues");
+        assert_eq!(config.sink_queues["syslog"], 1000);
+        assert!(
+            Config::from_toml("[sink_queues]\nsyslog = 0\n").is_err(),
+            "queues can't be empty"
+        );
+        assert!(
+            Config::from_toml("[sink_queues]\nfile = 1000\n").is_err(),
+            "the log file isn't queued"
+        );
+    }
+
+    #[test]
+    fn parse_sink_formats() {
+        let config =
+            Config::from_toml("[sink_formats]\nsyslog = \"cef\"\n").expect("valid sink formats");
+        assert_eq!(config.sink_formats["syslog"], Format::Cef);
+        assert!(
+            Config::from_toml("[sink_formats]\nsyslog = \"xml\"\n").is_err(),
+            "unknown format"
+        );
+        assert!(
+            Config::from_toml(
+                "[sink_formats]\nsyslog = \"cef\"\n[sink_templates]\nsyslog = \"{{ vm }}\"\n"
+            )
+            .is_err(),
+            "templates are json only"
+        );
+
+        let binary =
+            Config::from_toml("[sink_formats]\nfile = \"cbor\"\nmy_plugin = \"msgpack\"\n");
+        assert_eq!(binary.is_ok(), cfg!(feature = "binary-formats"));
+        let protobuf = Config::from_toml("[sink_formats]\nfile = \"protobuf\"\n");
+        assert_eq!(protobuf.is_ok(), cfg!(feature = "protobuf"));
+        assert!(
+            Config::from_toml("[sink_formats]\nlive = \"cbor\"\n").is_err(),
+            "live subscribers get text"
+        );
+        assert!(
+            Config::from_toml("[sink_formats]\nfile = \"cef\"\n").is_err(),
+            "the zone's log only gets every field"
+        );
+    }
+
+    #[test]
+    fn parse_sink_templates() {
+        let config = Config::from_toml("[sink_templates]\nlive = \"{{ vm }} {{ event }}\"\n")
+            .expect("valid sink templates");
+        assert!(config.sink_templates.contains_key("live"));
+        assert!(
+            Config::from_toml("[sink_templates]\nlive = \"{{ vm\"\n").is_err(),
+            "templates are compiled when the config is loaded"
+        );
+    }
+
+    #[test]
+    fn parse_vminfod() {
+        assert_eq!(Config::default().vminfod.settings(), Settings::default());
+        let config = Config::from_toml("[vminfod]\nsocket = \"/var/run/vminfod.sock\"\n")
+            .expect("valid vminfod config");
+        assert_eq!(
+            config.vminfod.settings().transport,
+            Transport::Unix(PathBuf::from("/var/run/vminfod.sock"))
+        );
+
+        let config = Config::from_toml(
+            "[vminfod]\nurl = \"http://127.0.0.1:9191/events\"\nconnect_timeout_secs = 3\n\
+             idle_timeout_secs = 600\n",
+        )
+        .expect("valid vminfod config");
+        let settings = config.vminfod.settings();
+        assert_eq!(
+            settings.transport,
+            Transport::Tcp("http://127.0.0.1:9191/events".to_owned())
+        );
+        assert_eq!(settings.connect_timeout, Duration::from_secs(3));
+        assert_eq!(settings.idle_timeout, Some(Duration::from_secs(600)));
+
+        assert!(
+            Config::from_toml("[vminfod]\nurl = \"http://h/events\"\nsocket = \"/s\"\n").is_err(),
+            "url and socket are mutually exclusive"
+        );
+        assert!(Config::from_toml("[vminfod]\nurl = \"https://h/events\"\n").is_err());
+        assert!(Config::from_toml("[vminfod]\nconnect_timeout_secs = 0\n").is_err());
+        assert!(Config::from_toml("[vminfod]\ncache_secs = 0\n").is_err());
+
+        assert_eq!(Config::default().vminfod.tracked_fields, VmField::ALL);
+        let config = Config::from_toml("[vminfod]\ntracked_fields = [\"alias\", \"owner_uuid\"]\n")
+            .expect("valid tracked fields");
+        assert_eq!(
+            config.vminfod.tracked_fields,
+            vec![VmField::Alias, VmField::OwnerUuid]
+        );
+        assert!(Config::from_toml("[vminfod]\ntracked_fields = [\"state\"]\n").is_err());
+    }
+
+    #[test]
+    fn parse_syslog() {
+        let config = Config::from_toml("[syslog]\naddress = \"collector:6514\"\n")
+            .expect("valid syslog config");
+        let syslog = config.syslog.unwrap();
+        assert_eq!(syslog.facility, 16);
+        assert_eq!(syslog.sd_id, "cfw@32473");
+        assert!(!syslog.tls && !syslog.per_zone);
+
+        for bad in &[
+            "facility = 24",
+            "sd_id = \"cfw\"",
+            "sd_id = \"cfw@\"",
+            "sd_id = \"c fw@32473\"",
+            "spool_bytes = 1024",
+            "tls_ca = \"/etc/ssl/ca.pem\"",
+            "tls = true\ntls_cert = \"/etc/ssl/cfwlogd.pem\"",
+            "tls = true\ntls_pin_sha256 = [\"5d5b09f6\"]",
+        ] {
+            assert!(
+                Config::from_toml(&format!("[syslog]\naddress = \"c:6514\"\n{}\n", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_parquet() {
+        let config = Config::from_toml("[parquet]\n");
+        if !cfg!(feature = "parquet") {
+            assert!(config.is_err(), "requires the parquet feature");
+            return;
+        }
+        let parquet = config.expect("valid parquet config").parquet.unwrap();
+        assert_eq!(parquet.interval_secs, 3600);
+        assert_eq!(parquet.row_group_rows, 10_000);
+        assert!(Config::from_toml("[parquet]\ninterval_secs = 0\n").is_err());
+        assert!(Config::from_toml("[parquet]\n[sink_formats]\nparquet = \"cbor\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_elasticsearch() {
+        let config = Config::from_toml("[elasticsearch]\nurl = \"http://es:9200\"\n");
+        if !cfg!(feature = "elasticsearch") {
+            assert!(config.is_err(), "requires the elasticsearch feature");
+            return;
+        }
+        let elasticsearch = config
+            .expect("valid elasticsearch config")
+            .elasticsearch
+            .unwrap();
+        assert_eq!(elasticsearch.index, "cfw-{owner_uuid}-{date}");
+        assert_eq!(elasticsearch.batch_size, 500);
+        assert!(
+            Config::from_toml("[elasticsearch]\nurl = \"https://es:9200\"\n").is_ok(),
+            "https urls are sent over the tls module"
+        );
+
+        for bad in &[
+            "url = \"es:9200\"",
+            "url = \"http://es:9200\"\nbatch_size = 0",
+            "url = \"http://es:9200\"\nindex = \"cfw-{owner}\"",
+            "url = \"http://es:9200\"\nindex = \"CFW\"",
+            "url = \"http://es:9200\"\nspool_bytes = 1024",
+        ] {
+            assert!(
+                Config::from_toml(&format!("[elasticsearch]\n{}\n", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_webhook() {
+        let config = Config::from_toml(
+            r#"
+            [webhook]
+            url = "https://hooks.example.com/cfw"
+            compression = "gzip"
+            headers = { Authorization = "Bearer hunter2" }
+            "#,
+        );
+        if !cfg!(feature = "webhook") {
+            assert!(config.is_err(), "requires the webhook feature");
+            return;
+        }
+        let webhook = config.expect("valid webhook config").webhook.unwrap();
+        assert_eq!(webhook.compression, WebhookCompression::Gzip);
+        assert_eq!((webhook.batch_size, webhook.breaker_failures), (1000, 5));
+        assert!(
+            !format!("{:?}", webhook).contains("hunter2"),
+            "header values are left out of the debug output"
+        );
+
+        let header = |name: &str| {
+            Config::from_toml(&format!(
+                "[webhook]\nurl = \"http://a\"\nheaders = {{ \"{}\" = \"x\" }}\n",
+                name
+            ))
+        };
+        assert!(header("X-Source").is_ok());
+        assert!(header("Content-Type").is_err(), "set by cfwlogd");
+        assert!(header("Bad Header").is_err());
+        assert!(Config::from_toml("[webhook]\nurl = \"ftp://a\"\n").is_err());
+    }
+
+    #[test]
+    fn parse_s3() {
+        let minimal = "[s3]\nendpoint = \"https://s3.us-west-2.amazonaws.com\"\n\
+                       bucket = \"logs\"\n";
+        let config = Config::from_toml(minimal);
+        if !cfg!(feature = "s3") {
+            assert!(config.is_err(), "requires the s3 feature");
+            return;
+        }
+        let s3 = config.expect("valid s3 config").s3.unwrap();
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.prefix, "cfwlogs/{vm}/{date}/");
+        assert_eq!(s3.part_bytes, 16 * 1024 * 1024);
+        assert_eq!(s3.max_attempts, 10);
+        assert_eq!(s3.dead_letter_dir, "dead-letter");
+        for bad in &[
+            "prefix = \"/cfwlogs/\"",
+            "prefix = \"{zone}/\"",
+            "part_bytes = 1024",
+            "multipart_threshold_bytes = 6291456",
+            "max_attempts = 0",
+            "dead_letter_dir = \"../elsewhere\"",
+            "dead_letter_dir = \"\"",
+        ] {
+            assert!(
+                Config::from_toml(&format!("{}{}\n", minimal, bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(Config::from_toml(&minimal.replace("https", "http")).is_err());
+    }
+
+    #[test]
+    fn parse_manta() {
+        let minimal = "[manta]\nurl = \"https://us-east.manta.joyent.com\"\nuser = \"ops\"\n\
+                       key_id = \"a1:b2\"\nkey_file = \"/root/.ssh/id_rsa\"\n";
+        let config = Config::from_toml(minimal);
+        if !cfg!(feature = "manta") {
+            assert!(config.is_err(), "requires the manta feature");
+            return;
+        }
+        let manta = config.expect("valid manta config").manta.unwrap();
+        assert_eq!(manta.path, "/{user}/stor/cfwlogs/{vm}/{date}/{file}");
+        assert_eq!(manta.max_backoff_secs, 300);
+        for bad in &[
+            "path = \"/{user}/stor/{vm}\"",
+            "path = \"{user}/stor/{file}\"",
+            "path = \"/{user}/stor/{zone}/{file}\"",
+            "max_backoff_secs = 0",
+        ] {
+            assert!(
+                Config::from_toml(&format!("{}{}\n", minimal, bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(Config::from_toml(&minimal.replace("https", "http")).is_err());
+    }
+
+    #[test]
+    fn parse_cloudwatch() {
+        let minimal = "[cloudwatch]\nregion = \"us-east-1\"\nlog_group = \"cfw\"\n";
+        let config = Config::from_toml(minimal);
+        if !cfg!(feature = "cloudwatch") {
+            assert!(config.is_err(), "requires the cloudwatch feature");
+            return;
+        }
+        let cloudwatch = config.expect("valid cloudwatch config").cloudwatch.unwrap();
+        assert_eq!(cloudwatch.log_stream, "{vm}");
+        assert_eq!(cloudwatch.batch_size, 1000);
+        assert_eq!(cloudwatch.secret_access_key, None);
+
+        let config = Config::from_toml(&format!(
+            "{}access_key_id = \"AKID\"\nsecret_access_key = \"hunter2\"\n",
+            minimal
+        ))
+        .expect("valid credentials");
+        assert!(
+            !format!("{:?}", config).contains("hunter2"),
+            "secrets aren't logged"
+        );
+
+        for bad in &[
+            "region = \"US East\"\nlog_group = \"cfw\"",
+            "region = \"us-east-1\"\nlog_group = \"\"",
+            "region = \"us-east-1\"\nlog_group = \"cfw\"\nlog_stream = \"{alias}\"",
+            "region = \"us-east-1\"\nlog_group = \"cfw\"\nlog_stream = \"a:{vm}\"",
+            "region = \"us-east-1\"\nlog_group = \"cfw\"\nendpoint = \"http://logs\"",
+            "region = \"us-east-1\"\nlog_group = \"cfw\"\naccess_key_id = \"AKID\"",
+            "region = \"us-east-1\"\nlog_group = \"cfw\"\nbatch_size = 20000",
+        ] {
+            assert!(
+                Config::from_toml(&format!("[cloudwatch]\n{}\n", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_log_name() {
+        let config = Config::from_toml("log_name = \"%Y%m%dT%H.log\"").expect("valid log_name");
+        assert_eq!(config.log_name.as_deref(), Some("%Y%m%dT%H.log"));
+        for bad in &["%Y%m%d", "%Y/%m.log", "%Q.log", "stats.log"] {
+            assert!(
+                Config::from_toml(&format!("log_name = \"{}\"", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_flush() {
+        let vm = "2e4a24af-97a2-4cb1-a2a4-1edb209fb311";
+        let config = Config::from_toml(&format!(
+            "[flush]\ninterval_ms = 500\nfsync_secs = 10\n[zone_flush.\"{}\"]\nevery_records = 1\n",
+            vm
+        ))
+        .expect("valid flush config");
+        assert_eq!(config.flush.interval(), Some(Duration::from_millis(500)));
+        assert_eq!(config.flush_for(vm).every_records, Some(1));
+        assert_eq!(
+            config.flush_for(vm).interval(),
+            None,
+            "a zone's policy replaces the default"
+        );
+        assert_eq!(
+            config.flush_for("other").fsync_interval(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(Config::default().flush, FlushConfig::default());
+        assert!(Config::from_toml("[flush]\nevery_records = 0\n").is_err());
+        assert!(Config::from_toml(&format!("[zone_flush.\"{}\"]\nfsync_secs = 0\n", vm)).is_err());
+    }
+
+    #[test]
+    fn parse_rotate_bytes() {
+        let config = Config::from_toml("rotate_bytes = 104857600").expect("valid rotate_bytes");
+        assert_eq!(config.rotate_bytes, Some(100 << 20));
+        assert_eq!(Config::default().rotate_bytes, None, "off by default");
+        assert!(Config::from_toml("rotate_bytes = 0").is_err());
+    }
+
+    #[test]
+    fn parse_zstd() {
+        let vm = "0f2d6d34-9f35-4a92-8bd2-e3a8a1e2bf5c";
+        let config = Config::from_toml(&format!("[zstd]\nzones = [\"{}\"]\n", vm));
+        if !cfg!(feature = "zstd") {
+            assert!(config.is_err(), "requires the zstd feature");
+            return;
+        }
+        let zstd = config.expect("valid zstd config").zstd.unwrap();
+        assert_eq!(zstd.level_for(vm), Some(3));
+        assert_eq!(zstd.frame_secs, 5);
+        assert_eq!(
+            zstd.level_for("b3a8e5e4-38f0-44e8-a1d2-a3ef6e4a1c4b"),
+            None,
+            "only the listed zones are compressed"
+        );
+
+        for bad in &["level = 0", "level = 22", "frame_secs = 0"] {
+            assert!(
+                Config::from_toml(&format!("[zstd]\nzones = []\n{}\n", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_indexed() {
+        let vm = "0f2d6d34-9f35-4a92-8bd2-e3a8a1e2bf5c";
+        let config = Config::from_toml(&format!("[indexed]\nzones = [\"{}\"]\n", vm)).unwrap();
+        let indexed = config.indexed.unwrap();
+        assert_eq!(indexed.block_size_for(vm), Some(64 * 1024));
+        assert_eq!(
+            indexed.block_size_for("b3a8e5e4-38f0-44e8-a1d2-a3ef6e4a1c4b"),
+            None,
+            "only the listed zones are indexed"
+        );
+
+        for bad in &["block_kib = 0", "block_kib = 2048"] {
+            assert!(
+                Config::from_toml(&format!("[indexed]\nzones = []\n{}\n", bad)).is_err(),
+                "{} is rejected",
+                bad
+            );
+        }
+        if cfg!(feature = "zstd") {
+            let both = format!(
+                "[indexed]\nzones = [\"{0}\"]\n[zstd]\nzones = [\"{0}\"]\n",
+                vm
+            );
+            assert!(Config::from_toml(&both).is_err(), "compressed and indexed");
+        }
+    }
+
+    #[test]
+    fn parse_fwadm() {
+        let config = Config::from_toml("[fwadm]\n").unwrap();
+        assert_eq!(
+            config.fwadm,
+            Some(FwadmConfig {
+                rules_dir: "/var/fw/rules".to_owned(),
+                refresh_secs: 300,
+            })
+        );
+        assert!(Config::from_toml("[fwadm]\nrefresh_secs = 0\n").is_err());
+        let both = "[fwadm]\n[fwapi]\nurl = \"http://fwapi\"\n";
+        assert!(Config::from_toml(both).is_err(), "fwapi and fwadm");
+    }
+
+    #[test]
+    fn parse_aggregate() {
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let config = Config::from_toml(&format!("[aggregate]\nzones = [\"{}\"]\n", vm)).unwrap();
+        let aggregate = config.aggregate.expect("aggregate table");
+        assert_eq!(aggregate.window_secs, 60);
+        assert_eq!(aggregate.max_flows, 10_000);
+        assert!(aggregate.aggregates(vm));
+        assert!(!aggregate.aggregates("not-a-uuid"));
+        assert!(Config::from_toml("[aggregate]\nzones = []\nwindow_secs = 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_coalesce() {
+        let config = Config::from_toml("[coalesce]\n").unwrap();
+        assert_eq!(config.coalesce.expect("coalesce table").window_ms, 1000);
+        assert!(Config::from_toml("[coalesce]\nwindow_ms = 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_sampling() {
+        let rule = "5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f";
+        let config = format!("[[sampling]]\nrule = \"{}\"\nper_sec = 100\n", rule);
+        let config = Config::from_toml(&config).unwrap();
+        assert_eq!(config.sampling[0].rule, Some(rule.parse().unwrap()));
+        assert_eq!(config.sampling[0].burst, None);
+        assert_eq!(config.sampling[0].direction, None);
+        assert!(Config::default().sampling.is_empty());
+
+        for bad in &[
+            format!("rule = \"{}\"", rule),
+            format!("rule = \"{}\"\none_in = 0", rule),
+            format!("rule = \"{}\"\none_in = 2\nper_sec = 2", rule),
+            format!("rule = \"{}\"\nper_sec = 2\nburst = 0", rule),
+            "one_in = 2".to_owned(),
+        ] {
+            assert!(
+                Config::from_toml(&format!("[[sampling]]\n{}\n", bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_log_dirs() {
+        let owner = "930896af-bf8c-48d4-885c-6573a94b1853";
+        let config = Config::from_toml(&format!(
+            "[[log_dirs]]\nowner = \"{}\"\ndir = \"pool2/{{vm}}\"\n",
+            owner
+        ))
+        .expect("valid log_dirs");
+        assert_eq!(config.log_dirs[0].owner, Some(owner.parse().unwrap()));
+        assert!(Config::default().log_dirs.is_empty());
+
+        for bad in &[
+            format!("owner = \"{}\"", owner),
+            format!("owner = \"{}\"\ndir = \"pool2\"", owner),
+            format!("owner = \"{}\"\ndir = \"../{{vm}}\"", owner),
+            format!("vm = \"{}\"\nlog_name = \"fw\"", owner),
+            "dir = \"pool2/{vm}\"".to_owned(),
+        ] {
+            assert!(
+                Config::from_toml(&format!("[[log_dirs]]\n{}\n", bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_zone_fs() {
+        let config = Config::from_toml("[zone_fs]\npath = \"zones/{vm}/data/firewall.log\"\n")
+            .expect("valid zone_fs");
+        let zone_fs = config.zone_fs.expect("zone_fs table");
+        assert_eq!(zone_fs.zones_dir, PathBuf::from("/zones"));
+        assert!(zone_fs.zones.is_empty());
+        for bad in &[
+            "path = \"/var/log/firewall.log\"",
+            "path = \"../../etc\"",
+            "max_bytes = 0",
+        ] {
+            assert!(
+                Config::from_toml(&format!("[zone_fs]\n{}\n", bad)).is_err(),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn parse_log_permissions() {
+        let owner = "930896af-bf8c-48d4-885c-6573a94b1853";
+        let config = Config::from_toml(&format!(
+            "[log_permissions]\ngid = 5\nfile_mode = 0o640\n\n\
+             [[log_dirs]]\nowner = \"{}\"\npermissions = {{ uid = 1001 }}\n",
+            owner
+        ))
+        .expect("valid log_permissions");
+        assert_eq!(config.log_permissions.file_mode, Some(0o640));
+        assert_eq!(
+            config.log_permissions.merge(config.log_dirs[0].permissions),
+            LogPermissions {
+                uid: Some(1001),
+                gid: Some(5),
+                file_mode: Some(0o640),
+                dir_mode: None,
+            }
+        );
+
+        for bad in &[
+            "[log_permissions]\nfile_mode = 0o4755\n",
+            "[log_permissions]\ndir_mode = 0o555\n",
+            "[log_permissions]\nowner = 1\n",
+        ] {
+            assert!(Config::from_toml(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn parse_schema_version() {
+        assert_eq!(Config::default().schema_version(), SCHEMA_VERSION);
+        let config = Config::from_toml("schema_version = 1\n").unwrap();
+        assert_eq!(config.schema_version(), 1);
+        assert!(Config::from_toml(&format!("schema_version = {}\n", SCHEMA_VERSION + 1)).is_err());
+    }
+
+    #[test]
+    fn parse_rate_limit() {
+        let config = Config::from_toml("[rate_limit]\nper_sec = 500\n").unwrap();
+        let rate_limit = config.rate_limit.expect("rate_limit table");
+        assert_eq!(rate_limit.burst(), 500);
+        assert!(Config::default().rate_limit.is_none());
+        assert!(Config::from_toml("[rate_limit]\nper_sec = 0\n").is_err());
+        assert!(Config::from_toml("[rate_limit]\nper_sec = 5\nburst = 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_ipfix() {
+        let config = Config::from_toml("[ipfix]\ncollector = \"127.0.0.1:4739\"\n").unwrap();
+        let ipfix = config.ipfix.expect("ipfix table");
+        assert_eq!(ipfix.transport, IpfixTransport::Udp);
+        assert_eq!(ipfix.enterprise_number, 32473);
+        assert_eq!(ipfix.template_secs, 600);
+        let tcp = "[ipfix]\ncollector = \"collector:4739\"\ntransport = \"tcp\"\n";
+        assert_eq!(
+            Config::from_toml(tcp).unwrap().ipfix.unwrap().transport,
+            IpfixTransport::Tcp
+        );
+        assert!(
+            Config::from_toml("[ipfix]\ncollector = \"c:4739\"\ntransport = \"sctp\"\n").is_err()
+        );
+        assert!(Config::from_toml("[ipfix]\ncollector = \"c:4739\"\ntemplate_secs = 0\n").is_err());
+    }
+
+    #[test]
+    fn parse_enrich() {
+        let config = Config::from_toml("[enrich]\nworkers = 4\n").unwrap();
+        assert_eq!(config.enrich.workers, 4);
+        assert_eq!(config.enrich.cache_secs, 3600);
+        assert!(Config::from_toml("[enrich]\ncache_entries = 0\n").is_err());
+        assert!(Config::from_toml("[geoip]\n").is_err(), "no databases");
+        let geoip = "[geoip]\nasn_db = \"/opt/geoip/GeoLite2-ASN.mmdb\"\n";
+        assert_eq!(Config::from_toml(geoip).is_ok(), cfg!(feature = "geoip"));
+        let reverse_dns = "[reverse_dns]\ntimeout_ms = 500\n";
+        assert_eq!(
+            Config::from_toml(reverse_dns).is_ok(),
+            cfg!(feature = "reverse-dns")
+        );
+        if cfg!(feature = "reverse-dns") {
+            assert!(Config::from_toml("[reverse_dns]\ntimeout_ms = 0\n").is_err());
+        }
+    }
+
+    #[test]
+    fn parse_receive_timestamps() {
+        let config = Config::from_toml("receive_timestamps = true\n").unwrap();
+        assert!(config.receive_timestamps);
+        assert!(!Config::default().receive_timestamps, "off by default");
+    }
+
+    #[test]
+    fn parse_nic_context() {
+        let config = Config::from_toml("nic_context = true\n").unwrap();
+        assert!(config.nic_context);
+        assert!(!Config::default().nic_context, "off by default");
+    }
+
+    #[test]
+    fn parse_timestamp_format() {
+        let config =
+            Config::from_toml("timestamp_format = \"epoch_ms\"\ntimestamp_timezone = \"local\"\n")
+                .expect("valid timestamp format");
+        assert_eq!(config.timestamp_format, TimestampFormat::EpochMs);
+        assert_eq!(config.timestamp_timezone, Timezone::Local);
+        let config = Config::default();
+        assert_eq!(config.timestamp_format, TimestampFormat::Rfc3339);
+        assert_eq!(config.timestamp_timezone, Timezone::Utc);
+        assert!(
+            Config::from_toml("timestamp_format = \"epoch_us\"\n").is_err(),
+            "unknown formats are rejected"
+        );
+    }
+
+    #[test]
+    fn parse_epoch_timestamp() {
+        let config = Config::from_toml("epoch_timestamp = \"ns\"\n").expect("valid epoch unit");
+        assert_eq!(config.epoch_timestamp, Some(EpochUnit::Ns));
+        assert_eq!(Config::default().epoch_timestamp, None, "off by default");
+        assert!(
+            Config::from_toml("epoch_timestamp = \"us\"\n").is_err(),
+            "only ms and ns are supported"
+        );
+    }
+
+    #[test]
+    fn parse_node_fields() {
+        let config = Config::from_toml(
+            "node_identity = true\nnode_fields = [\"server_hostname\", \"datacenter\"]\n\
+             datacenter = \"lab\"\n",
+        )
+        .expect("valid node fields");
+        assert_eq!(
+            config.node_fields,
+            Some(vec![NodeField::ServerHostname, NodeField::Datacenter])
+        );
+        assert_eq!(config.datacenter.as_deref(), Some("lab"));
+        assert!(
+            Config::from_toml("node_fields = [\"datacenter\"]\n").is_err(),
+            "node_identity has to be set"
+        );
+        assert!(Config::from_toml("node_identity = true\nnode_fields = []\n").is_err());
+        assert!(Config::from_toml("node_identity = true\nnode_fields = [\"rack\"]\n").is_err());
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(
+            Config::from_toml("not_an_option = true").is_err(),
+            "unknown config options are rejected"
+        );
+    }
+}
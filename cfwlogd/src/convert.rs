@@ -0,0 +1,477 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd convert --format <format> [--output <dir>] <file>...` rewrites logs written as json
+//! lines into one of the formats cfwlogd has since learned to write, so historical data can join
+//! pipelines that only take the new ones. The formats are "cef" and "leef" lines, "parquet" files
+//! with the columns of the Parquet sink, and "indexed" logs, see `cfwevent::indexed`.
+//!
+//! Inputs can be current or rotated logs as they are, compressed with zstd or gzip, or already
+//! indexed. Every record keeps the timestamp it was logged with, whichever "timestamp_format" that
+//! was in. Only traffic records have a CEF, LEEF or Parquet form, so cfwlogd's own records, such as
+//! summaries and notices, are only carried over to indexed logs, and lines that aren't records at
+//! all are skipped. Both are counted.
+//!
+//! Each file is converted to a file of the same name, without a ".gz" or ".zst" suffix and with
+//! one for the format, next to it or in the output directory. It's written next to where it goes
+//! and renamed into place once the whole input has been read, so a file that can't be converted
+//! leaves nothing behind.
+
+use crate::compress::{self, LogWriter};
+use crate::format;
+#[cfg(feature = "parquet")]
+use crate::parquet_sink::ParquetFile;
+use crate::sink::Record;
+use cfwevent::indexed::{self, IndexedReader};
+use cfwevent::parser::{CfwEvent, Icmp, TrafficEvent};
+use cfwevent::record::{self, LogRecord, TrafficRecord};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const USAGE: &str = "usage: cfwlogd convert --format <cef|leef|parquet|indexed> [--output <dir>] \
+                     <file>...";
+
+/// Rows per row group of the Parquet files, the same as the sink's default
+#[cfg(feature = "parquet")]
+const ROW_GROUP_ROWS: usize = 10_000;
+
+/// Every gzip member starts with these bytes
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// A format logs can be converted to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Target {
+    Cef,
+    Leef,
+    Parquet,
+    Indexed,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cef" => Ok(Target::Cef),
+            "leef" => Ok(Target::Leef),
+            "parquet" => Ok(Target::Parquet),
+            "indexed" => Ok(Target::Indexed),
+            _ => Err(format!("unknown format \"{}\"", s)),
+        }
+    }
+}
+
+impl Target {
+    /// The suffix of converted files
+    fn extension(self) -> &'static str {
+        match self {
+            Target::Cef => "cef",
+            Target::Leef => "leef",
+            Target::Parquet => "parquet",
+            Target::Indexed => "idx",
+        }
+    }
+}
+
+/// How a file was converted
+#[derive(Debug, Default, PartialEq)]
+pub struct Converted {
+    pub records: u64,
+    /// Records the format has no form for
+    pub skipped: u64,
+    /// Lines that aren't records
+    pub invalid: u64,
+}
+
+/// Call `f` with every line of the log at `path`, whatever it was written as
+fn read_lines<F>(path: &Path, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&str) -> io::Result<()>,
+{
+    let mut file = File::open(path)?;
+    if indexed::is_indexed(&mut file)? == Some(true) {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = IndexedReader::new(BufReader::new(file))?;
+        return reader.query(None, None, |_, json| f(&String::from_utf8_lossy(json)));
+    }
+    file.seek(SeekFrom::Start(0))?;
+    if compress::is_zstd(&mut file)? == Some(true) {
+        file.seek(SeekFrom::Start(0))?;
+        return for_each_line(zstd_reader(file)?, f);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let mut magic = [0; 2];
+    let gzipped = file.read_exact(&mut magic).is_ok() && magic == GZIP_MAGIC;
+    file.seek(SeekFrom::Start(0))?;
+    if gzipped {
+        return for_each_line(gzip_reader(file)?, f);
+    }
+    for_each_line(BufReader::new(file), f)
+}
+
+fn for_each_line<R: BufRead, F: FnMut(&str) -> io::Result<()>>(
+    reader: R,
+    mut f: F,
+) -> io::Result<()> {
+    for line in reader.lines() {
+        f(&line?)?;
+    }
+    Ok(())
+}
+
+/// An error for what cfwlogd can only do when it's built with `feature`
+#[allow(dead_code)]
+fn missing_feature(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("cfwlogd was built without the \"{}\" feature", feature),
+    )
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_reader(file: File) -> io::Result<impl BufRead> {
+    Ok(BufReader::new(zstd::stream::read::Decoder::new(file)?))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_reader(_file: File) -> io::Result<BufReader<File>> {
+    Err(missing_feature("zstd"))
+}
+
+/// Rotated logs are gzipped by logadm's -z, which writes a member per file, but a concatenation of
+/// them is read too
+#[cfg(feature = "flate2")]
+fn gzip_reader(file: File) -> io::Result<impl BufRead> {
+    Ok(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+}
+
+#[cfg(not(feature = "flate2"))]
+fn gzip_reader(_file: File) -> io::Result<BufReader<File>> {
+    Err(missing_feature("flate2"))
+}
+
+/// Logged addresses as the device hands them to us, with IPv4 addresses mapped
+fn device_addr(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// The event a traffic record was logged for
+fn traffic_event(record: &TrafficRecord) -> CfwEvent {
+    let icmp = match (record.icmp_type, record.icmp_code) {
+        (Some(icmp_type), Some(icmp_code)) => Some(Icmp {
+            icmp_type,
+            icmp_code,
+        }),
+        // Records from before version 2 only have them as the ports
+        _ => Icmp::from_ports(
+            &record.protocol,
+            record.source_port,
+            record.destination_port,
+        ),
+    };
+    CfwEvent::Traffic(TrafficEvent {
+        event: record.event.clone(),
+        length: 0,
+        zonedid: 0,
+        rule_id: 0,
+        source_port: record.source_port,
+        destination_port: record.destination_port,
+        protocol: record.protocol.clone(),
+        icmp,
+        direction: record.direction.clone(),
+        source_ip: device_addr(record.source_ip.addr),
+        destination_ip: device_addr(record.destination_ip.addr),
+        timestamp: record.timestamp,
+        rule_uuid: record.rule,
+        seq: 0,
+        received: None,
+    })
+}
+
+/// A traffic record as a `Record` for the encoders, with the counts it stands for
+fn to_record(record: &TrafficRecord) -> Record<'_> {
+    let count = |name: &str| record.extra.get(name).and_then(|value| value.as_u64());
+    Record {
+        sampled: count("sampled"),
+        repeats: count("repeats"),
+        ..Record::new(traffic_event(record), &record.vm, &record.alias)
+    }
+}
+
+/// A converted file, while it's being written
+enum Output {
+    Lines(Target, BufWriter<File>),
+    Indexed(LogWriter),
+    #[cfg(feature = "parquet")]
+    Parquet(ParquetFile, File),
+}
+
+impl Output {
+    fn new(target: Target, file: File) -> io::Result<Output> {
+        match target {
+            Target::Cef | Target::Leef => Ok(Output::Lines(target, BufWriter::new(file))),
+            Target::Indexed => Ok(Output::Indexed(LogWriter::indexed(
+                file,
+                64 * 1024,
+                indexed::DEFAULT_BLOCK_SIZE,
+            ))),
+            #[cfg(feature = "parquet")]
+            Target::Parquet => Ok(Output::Parquet(
+                ParquetFile::new(file.try_clone()?, ROW_GROUP_ROWS)?,
+                file,
+            )),
+            #[cfg(not(feature = "parquet"))]
+            Target::Parquet => Err(missing_feature("parquet")),
+        }
+    }
+
+    /// Write a line of the input, returning false if the format has no form for its record
+    fn write(&mut self, line: &str, record: &LogRecord) -> io::Result<bool> {
+        let traffic = match record {
+            LogRecord::Traffic(traffic) => Some(to_record(traffic)),
+            LogRecord::Other { .. } => None,
+        };
+        match (self, traffic) {
+            (Output::Indexed(writer), _) => {
+                writer.write_all(line.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+            (Output::Lines(target, writer), Some(record)) => {
+                let mut buf = vec![];
+                match target {
+                    Target::Leef => format::leef(&record, &mut buf),
+                    _ => format::cef(&record, &mut buf),
+                }
+                buf.push(b'\n');
+                writer.write_all(&buf)?;
+            }
+            #[cfg(feature = "parquet")]
+            (Output::Parquet(writer, _), Some(record)) => return writer.write(&record),
+            (_, None) => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    /// Write out whatever is buffered and make sure it's on disk
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Output::Lines(_, mut writer) => {
+                writer.flush()?;
+                writer.get_ref().sync_all()
+            }
+            Output::Indexed(mut writer) => {
+                writer.flush()?;
+                writer.get_ref().sync_all()
+            }
+            #[cfg(feature = "parquet")]
+            Output::Parquet(writer, file) => {
+                writer.close()?;
+                file.sync_all()
+            }
+        }
+    }
+}
+
+/// Convert every line read from `path` into `output`
+fn convert_lines(path: &Path, output: &mut Output) -> io::Result<Converted> {
+    let mut converted = Converted::default();
+    read_lines(path, |line| {
+        match record::parse_record(line) {
+            Ok(record) => {
+                if output.write(line, &record)? {
+                    converted.records += 1;
+                } else {
+                    converted.skipped += 1;
+                }
+            }
+            Err(_) => converted.invalid += 1,
+        }
+        Ok(())
+    })?;
+    Ok(converted)
+}
+
+/// Where the file at `path` is converted to
+pub fn output_path(path: &Path, dir: Option<&Path>, target: Target) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let stem = name
+        .strip_suffix(".gz")
+        .or_else(|| name.strip_suffix(".zst"))
+        .unwrap_or(&name);
+    let name = format!("{}.{}", stem, target.extension());
+    match dir {
+        Some(dir) => dir.join(name),
+        None => path.with_file_name(name),
+    }
+}
+
+/// Convert the log at `path` to `output` in the `target` format
+pub fn convert_file(path: &Path, output: &Path, target: Target) -> io::Result<Converted> {
+    let mut tmp_path = output.as_os_str().to_owned();
+    tmp_path.push(".convert.tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let result = File::create(&tmp_path)
+        .and_then(|file| Output::new(target, file))
+        .and_then(|mut writer| {
+            let converted = convert_lines(path, &mut writer)?;
+            writer.finish()?;
+            std::fs::rename(&tmp_path, output)?;
+            Ok(converted)
+        });
+    // Nothing is left behind if the file couldn't be converted
+    let _ = std::fs::remove_file(&tmp_path);
+    result
+}
+
+/// The format, output directory and files the subcommand was given
+fn parse_args(args: &[String]) -> Result<(Target, Option<PathBuf>, &[String]), String> {
+    let mut target = None;
+    let mut dir = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--format" | "--output" if i + 1 == args.len() => {
+                return Err(format!("{} needs a value", args[i]))
+            }
+            "--format" => target = Some(args[i + 1].parse()?),
+            "--output" => dir = Some(PathBuf::from(&args[i + 1])),
+            _ => break,
+        }
+        i += 2;
+    }
+    let target = target.ok_or_else(|| "a --format is needed".to_owned())?;
+    if i == args.len() {
+        return Err("no files to convert".to_owned());
+    }
+    Ok((target, dir, &args[i..]))
+}
+
+/// Run the "convert" subcommand, returning the process's exit code
+pub fn run(args: &[String]) -> i32 {
+    let (target, dir, files) = match parse_args(args) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("cfwlogd convert: {}\n{}", e, USAGE);
+            return 2;
+        }
+    };
+    let mut failed = false;
+    for file in files {
+        let path = Path::new(file);
+        let output = output_path(path, dir.as_deref(), target);
+        match convert_file(path, &output, target) {
+            Ok(converted) => {
+                println!(
+                    "{}: wrote {} records to {}",
+                    file,
+                    converted.records,
+                    output.display()
+                );
+                if converted.skipped > 0 {
+                    println!(
+                        "{}: skipped {} records that aren't traffic records",
+                        file, converted.skipped
+                    );
+                }
+                if converted.invalid > 0 {
+                    println!(
+                        "{}: skipped {} lines that aren't records",
+                        file, converted.invalid
+                    );
+                }
+            }
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LINES: &str = concat!(
+        r#"{"schema_version":1,"event":"block","source_port":8,"destination_port":0,"#,
+        r#""protocol":"ICMP","direction":"in","source_ip":"172.24.4.150","#,
+        r#""destination_ip":"10.0.0.1","timestamp":"2020-05-12T19:00:00.123456Z","#,
+        r#""rule":"5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f","#,
+        r#""vm":"2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d","alias":"web0"}"#,
+        "\n",
+        r#"{"schema_version":2,"event":"summary","vm":"vm1","timestamp":1589310060000}"#,
+        "\n",
+        "not a record\n"
+    );
+
+    #[test]
+    fn logs_are_converted() {
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests").join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("2020-05-12T19:00:00.log");
+        std::fs::write(&input, LINES).unwrap();
+
+        let output = output_path(&input.with_extension("log.gz"), None, Target::Cef);
+        assert_eq!(output, dir.join("2020-05-12T19:00:00.log.cef"));
+        let converted = convert_file(&input, &output, Target::Cef).unwrap();
+        assert_eq!(
+            converted,
+            Converted {
+                records: 1,
+                skipped: 1,
+                invalid: 1
+            }
+        );
+        let cef = std::fs::read_to_string(&output).unwrap();
+        assert!(cef.starts_with("CEF:0|Joyent|cfwlogd|"));
+        assert!(cef.contains("rt=1589310000123 "), "the timestamp is kept");
+        assert!(
+            cef.contains("cn1=8"),
+            "the ICMP type is taken from the port"
+        );
+
+        let output = output_path(&input, Some(&dir), Target::Indexed);
+        let converted = convert_file(&input, &output, Target::Indexed).unwrap();
+        assert_eq!((converted.records, converted.invalid), (2, 1));
+        let mut timestamps = vec![];
+        let mut reader = IndexedReader::new(File::open(&output).unwrap()).unwrap();
+        reader
+            .query(None, None, |timestamp, _| {
+                timestamps.push(timestamp);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            timestamps,
+            vec![1_589_310_000_123_456, 1_589_310_060_000_000]
+        );
+
+        let reconverted = dir.join("reconverted.cef");
+        let converted = convert_file(&output, &reconverted, Target::Cef).unwrap();
+        assert_eq!(converted.records, 1, "indexed logs are read too");
+        assert_eq!(std::fs::read_to_string(&reconverted).unwrap(), cef);
+
+        assert!(convert_file(&dir.join("missing.log"), &reconverted, Target::Cef).is_err());
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(entries, 4, "no temporary files are left behind");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Monitoring of the free space left on the filesystem holding the logs. A thread periodically
+//! statvfs(2)'s the log directory and warns once free space falls below the configured warning
+//! threshold. Below the critical threshold the device reader only keeps a sample of events, which
+//! slows down how quickly we consume what is left rather than finding out when writes start
+//! failing with ENOSPC.
+
+use crate::config::DiskConfig;
+use crate::exit::{self, Subsystem};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the log filesystem is checked
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// While space is critically low we only keep one out of every `CRITICAL_SAMPLE_RATE` events
+const CRITICAL_SAMPLE_RATE: usize = 10;
+
+/// How much space is left relative to the configured thresholds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DiskState {
+    Ok,
+    Low,
+    Critical,
+}
+
+#[derive(Debug)]
+pub struct DiskMonitor {
+    config: DiskConfig,
+    critical: AtomicBool,
+    /// Used to pick which events are kept while space is critically low
+    sample: AtomicUsize,
+}
+
+impl DiskMonitor {
+    pub fn new(config: DiskConfig) -> Self {
+        DiskMonitor {
+            config,
+            critical: AtomicBool::new(false),
+            sample: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record the latest free space measurement, returning the resulting state
+    fn update(&self, free_percent: u64) -> DiskState {
+        let state = if free_percent < self.config.critical_free_percent {
+            DiskState::Critical
+        } else if free_percent < self.config.warning_free_percent {
+            DiskState::Low
+        } else {
+            DiskState::Ok
+        };
+        self.critical
+            .store(state == DiskState::Critical, Ordering::Relaxed);
+        state
+    }
+
+    /// Decide if the next event should be queued given how much space is left
+    pub fn admit(&self) -> bool {
+        if !self.critical.load(Ordering::Relaxed) {
+            return true;
+        }
+        self.sample.fetch_add(1, Ordering::Relaxed) % CRITICAL_SAMPLE_RATE == 0
+    }
+}
+
+/// Percentage of the filesystem containing `path` that is available to us
+fn free_percent(path: &Path) -> io::Result<u64> {
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "path contained nuls"))?;
+    let mut vfs: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut vfs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let blocks = vfs.f_blocks as u64;
+    if blocks == 0 {
+        return Ok(100);
+    }
+    Ok(vfs.f_bavail as u64 * 100 / blocks)
+}
+
+/// Start a thread that keeps the `DiskMonitor` up to date with the free space on the filesystem
+/// containing `path`.
+pub fn start_disk_monitor<P: Into<PathBuf>>(
+    monitor: Arc<DiskMonitor>,
+    path: P,
+) -> thread::JoinHandle<()> {
+    let path = path.into();
+    thread::Builder::new()
+        .name("disk_monitor".to_owned())
+        .spawn(move || {
+            let mut last = DiskState::Ok;
+            loop {
+                match free_percent(&path) {
+                    Ok(free) => {
+                        let state = monitor.update(free);
+                        if state != last {
+                            match state {
+                                DiskState::Ok => {
+                                    info!("log filesystem has {}% free space again", free)
+                                }
+                                DiskState::Low => {
+                                    warn!("log filesystem is running low on space ({}% free)", free)
+                                }
+                                DiskState::Critical => error!(
+                                    "log filesystem is critically low on space ({}% free), \
+                                     only a sample of events will be logged",
+                                    free
+                                ),
+                            }
+                            last = state;
+                        }
+                    }
+                    Err(e) => warn!("failed to statvfs {}: {}", path.display(), e),
+                }
+                thread::sleep(DISK_CHECK_INTERVAL);
+            }
+        })
+        .unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to spawn disk monitor thread: {}", e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let monitor = DiskMonitor::new(DiskConfig {
+            warning_free_percent: 10,
+            critical_free_percent: 5,
+            ..DiskConfig::default()
+        });
+        assert_eq!(monitor.update(50), DiskState::Ok);
+        assert!(monitor.admit(), "events are admitted with plenty of space");
+
+        assert_eq!(monitor.update(9), DiskState::Low);
+        assert!(monitor.admit(), "events are admitted when space is low");
+
+        assert_eq!(monitor.update(4), DiskState::Critical);
+        let admitted = (0..100).filter(|_| monitor.admit()).count();
+        assert_eq!(
+            admitted,
+            100 / CRITICAL_SAMPLE_RATE,
+            "only a sample is admitted"
+        );
+
+        assert_eq!(monitor.update(10), DiskState::Ok);
+        assert!(monitor.admit(), "events are admitted once space recovers");
+    }
+
+    #[test]
+    fn free_percent_of_tmp() {
+        let free = free_percent(Path::new("/var/tmp")).expect("failed to statvfs /var/tmp");
+        assert!(free <= 100);
+    }
+}
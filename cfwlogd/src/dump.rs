@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A snapshot of cfwlogd's internal state, written to the daemon log on SIGUSR2 so that a running
+//! cfwlogd can be looked into on a CN without restarting it. Every zone we have seen events for
+//! gets a line with the depth of its `Logger`'s queue and its counters from the "stats" module.
+//! The written and dropped counts cover the period since the zone's log was last rotated, while
+//! the per type totals cover everything since cfwlogd started.
+
+use crate::compat;
+use crate::events::Loggers;
+use crate::fileutils;
+use crate::stats::{Stats, ZoneCounters};
+use crate::zones::Zonedid;
+use std::fs::File;
+
+/// Write the snapshot to the daemon log. Open file descriptors are only counted with our
+/// /proc/self/fd, see `fileutils::open_fds`.
+pub fn log_snapshot(loggers: &Loggers, stats: &Stats, fd_dir: Option<&File>) {
+    if vminfod_client::connected() {
+        info!("SIGUSR2: vminfod is connected");
+    } else {
+        info!("SIGUSR2: vminfod is disconnected");
+    }
+    if let Some(fd_dir) = fd_dir {
+        match fileutils::open_fds(fd_dir) {
+            Ok(fds) => info!("SIGUSR2: {} open file descriptors", fds),
+            Err(e) => warn!("SIGUSR2: failed to count open file descriptors: {}", e),
+        }
+    }
+    let (unknown, extended) = compat::totals();
+    info!(
+        "SIGUSR2: {} events of unknown types and {} traffic events with unknown fields skipped",
+        unknown, extended
+    );
+
+    let loggers = loggers.lock().unwrap();
+    let stats = stats.lock().unwrap();
+    let mut zonedids: Vec<Zonedid> = stats.keys().copied().collect();
+    zonedids.sort();
+    for zonedid in zonedids {
+        let logger = loggers
+            .get(&zonedid)
+            .map(|logger| (logger.uuid.as_str(), logger.queued()));
+        info!(
+            "SIGUSR2: {}",
+            describe_zone(zonedid, logger, &stats[&zonedid])
+        );
+    }
+}
+
+/// Describe a zone's counters along with the vm uuid and queue depth of its `Logger`, if it has
+/// one.
+fn describe_zone(
+    zonedid: Zonedid,
+    logger: Option<(&str, usize)>,
+    counters: &ZoneCounters,
+) -> String {
+    let logger = match logger {
+        Some((uuid, queued)) => format!("{}, {} queued", uuid, queued),
+        None => "no logger".to_owned(),
+    };
+    let counts = counters.peek();
+    let dropped = &counts.dropped;
+    let totals = counters.totals();
+    let last_event = counters
+        .last_event()
+        .map_or_else(|| "never".to_owned(), |at| at.to_rfc3339());
+    format!(
+        "zonedid {} ({}): {} written and {} dropped since rotation (queue full {}, logger \
+         disconnected {}, memory pressure {}, disk space {}, disk full {}, rate limited {}, disk \
+         budget {}), {} block {} begin {} end since startup, last event {}",
+        zonedid,
+        logger,
+        counts.events_written,
+        dropped.queue_full
+            + dropped.logger_disconnected
+            + dropped.memory_pressure
+            + dropped.disk_space
+            + dropped.disk_full
+            + dropped.rate_limited
+            + dropped.disk_budget,
+        dropped.queue_full,
+        dropped.logger_disconnected,
+        dropped.memory_pressure,
+        dropped.disk_space,
+        dropped.disk_full,
+        dropped.rate_limited,
+        dropped.disk_budget,
+        totals.block,
+        totals.begin,
+        totals.end,
+        last_event
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::DropReason;
+    use cfwevent::parser::CfwEvType;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn zones_are_described() {
+        let counters = ZoneCounters::default();
+        assert_eq!(
+            describe_zone(7, None, &counters),
+            "zonedid 7 (no logger): 0 written and 0 dropped since rotation (queue full 0, logger \
+             disconnected 0, memory pressure 0, disk space 0, disk full 0, rate limited 0, disk \
+             budget 0), 0 block 0 begin 0 end since startup, last event never"
+        );
+
+        counters.written(2);
+        counters.event_written(&CfwEvType::Block);
+        counters.event_written(&CfwEvType::Begin);
+        counters.event_at(&Utc.ymd(2020, 1, 2).and_hms(3, 4, 5));
+        counters.dropped(DropReason::DiskSpace);
+        assert_eq!(
+            describe_zone(7, Some(("vm1", 3)), &counters),
+            "zonedid 7 (vm1, 3 queued): 2 written and 1 dropped since rotation (queue full 0, \
+             logger disconnected 0, memory pressure 0, disk space 1, disk full 0, rate limited \
+             0, disk budget 0), 1 block 1 begin 0 end since startup, last event \
+             2020-01-02T03:04:05+00:00"
+        );
+        assert_eq!(
+            counters.peek().events_written,
+            2,
+            "describing doesn't reset"
+        );
+    }
+}
@@ -0,0 +1,481 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that ships records to Elasticsearch with `_bulk` requests. Each zone's sink batches its
+//! records until it has "batch_size" of them or "flush_secs" have passed, and every record is
+//! indexed into the index named by the "index" template, in which `{owner_uuid}`, `{vm}` and
+//! `{date}` (the event's day as "YYYY.MM.DD") are substituted. The cluster's address is resolved
+//! once at startup, see the "http" module, and an "https://" cluster is connected to with the
+//! "tls_*" settings, see the "tls" module.
+//!
+//! While the cluster is unavailable, or is rejecting documents because it's overloaded, the batch
+//! is retried with exponential backoff up to "max_backoff_secs" for as long as it takes. Retrying
+//! happens on the zone's `Logger` thread, so the zone's records queue up in front of it, where
+//! they are accounted for by the `MemoryTracker` and eventually pause reading from the device,
+//! instead of being dropped. With "spool_bytes" set the batch is spilled to the zone's spool
+//! instead, and the `Logger` carries on while the spool is replayed with the same backoff, see the
+//! "spool" module. Records the cluster refuses for any other reason are logged and dropped.
+
+use crate::config::{Config, ElasticsearchConfig};
+use crate::http;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use crate::spool::{self, Spool};
+use crate::tls::Tls;
+use cfwevent::parser::CfwEvent;
+use chrono::Utc;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait on the cluster before giving up on a request
+const REQUEST_TIMEOUT_MS: u64 = 30_000;
+/// The first delay before retrying a batch
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// The cluster set up at startup, if there is one
+    static ref CLUSTER: Mutex<Option<Arc<Cluster>>> = Mutex::new(None);
+}
+
+struct Cluster {
+    agent: ureq::Agent,
+    /// Set for an "https://" cluster
+    tls: Option<Arc<Tls>>,
+    bulk_url: String,
+    index: String,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_backoff: Duration,
+    /// Size of each zone's spool, if documents are spooled while the cluster is unavailable
+    spool_bytes: Option<u64>,
+}
+
+/// Why a bulk request has to be retried or given up on
+enum BulkError {
+    /// The cluster is unreachable or overloaded
+    Unavailable(io::Error),
+    /// The cluster refused the request itself
+    Refused(io::Error),
+}
+
+/// Elasticsearch's response to a `_bulk` request
+#[derive(Debug, Deserialize)]
+struct BulkResponse {
+    errors: bool,
+    /// One entry per document, keyed by the action
+    items: Vec<HashMap<String, BulkItem>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BulkItem {
+    status: u16,
+    error: Option<serde_json::Value>,
+}
+
+/// What became of the documents of a bulk request
+#[derive(Debug, Default, PartialEq)]
+struct BulkOutcome {
+    /// The documents the cluster was too busy for, which should be sent again
+    retry: Vec<usize>,
+    /// The number of documents refused for good, along with the first reason given
+    refused: usize,
+    reason: Option<String>,
+}
+
+/// Sort a bulk response's items into the documents that made it, should be retried, or were
+/// refused
+fn parse_bulk_response<R: io::Read>(body: R) -> io::Result<BulkOutcome> {
+    let resp: BulkResponse = serde_json::from_reader(body)?;
+    let mut outcome = BulkOutcome::default();
+    if !resp.errors {
+        return Ok(outcome);
+    }
+    for (i, item) in resp.items.iter().enumerate() {
+        let item = match item.values().next() {
+            Some(item) => item,
+            None => continue,
+        };
+        match item.status {
+            200..=299 => (),
+            429 => outcome.retry.push(i),
+            _ => {
+                outcome.refused += 1;
+                if outcome.reason.is_none() {
+                    outcome.reason = item.error.as_ref().map(|e| e.to_string());
+                }
+            }
+        }
+    }
+    Ok(outcome)
+}
+
+impl Cluster {
+    /// Send the documents in one `_bulk` request
+    fn bulk(&self, docs: &[String]) -> Result<BulkOutcome, BulkError> {
+        let mut request = self.agent.post(&self.bulk_url);
+        request
+            .timeout_connect(REQUEST_TIMEOUT_MS)
+            .timeout_read(REQUEST_TIMEOUT_MS)
+            .set("Content-Type", "application/x-ndjson");
+        if let Some(tls) = &self.tls {
+            request.set_tls_config(tls.client_config());
+        }
+        let resp = request.send_string(&docs.concat());
+        if let Some(e) = resp.synthetic_error() {
+            return Err(BulkError::Unavailable(io::Error::new(
+                io::ErrorKind::Other,
+                e.to_string(),
+            )));
+        }
+        let status = resp.status();
+        if status == 429 || status >= 500 {
+            return Err(BulkError::Unavailable(io::Error::new(
+                io::ErrorKind::Other,
+                format!("cluster responded with {}", resp.status_line()),
+            )));
+        }
+        http::check_response(resp)
+            .and_then(|resp| parse_bulk_response(resp.into_reader()))
+            .map_err(BulkError::Refused)
+    }
+}
+
+/// Resolve the cluster's address, which has to happen before we chroot
+pub fn init(config: &ElasticsearchConfig) -> io::Result<()> {
+    let tls = if config.url.starts_with("https://") {
+        Some(Tls::open(
+            "elasticsearch",
+            config.tls_ca.as_deref(),
+            config.tls_cert.as_deref(),
+            config.tls_key.as_deref(),
+            &config.tls_pin_sha256,
+        )?)
+    } else {
+        None
+    };
+    let cluster = Cluster {
+        agent: http::pinned_agent(&config.url)?,
+        tls,
+        bulk_url: format!("{}/_bulk", config.url.trim_end_matches('/')),
+        index: config.index.clone(),
+        batch_size: config.batch_size,
+        flush_interval: Duration::from_secs(config.flush_secs),
+        max_backoff: Duration::from_secs(config.max_backoff_secs),
+        spool_bytes: config.spool_bytes,
+    };
+    info!("shipping records to Elasticsearch at {}", config.url);
+    *CLUSTER.lock().unwrap() = Some(Arc::new(cluster));
+    Ok(())
+}
+
+/// Open a zone's Elasticsearch sink, if a cluster was set up at startup. The zone's spool, if any,
+/// is kept in `dir`.
+pub fn open_sink(owner_uuid: &str, dir: &Path, config: &Config) -> Option<ElasticsearchSink> {
+    let cluster = Arc::clone(CLUSTER.lock().unwrap().as_ref()?);
+    let spool = cluster.spool_bytes.and_then(|bytes| {
+        let path = dir.join(spool::file_name("elasticsearch"));
+        Spool::open(path, bytes)
+            .map_err(|e| {
+                error!(
+                    "failed to open the elasticsearch spool in {}: {}",
+                    dir.display(),
+                    e
+                )
+            })
+            .ok()
+    });
+    Some(ElasticsearchSink {
+        cluster,
+        owner_uuid: owner_uuid.to_owned(),
+        encoder: Encoder::for_sink("elasticsearch", config),
+        pending: vec![],
+        last_sent: Instant::now(),
+        spool,
+        backoff: INITIAL_BACKOFF,
+        retry_at: None,
+        stats: SinkStats::default(),
+    })
+}
+
+/// The index a record goes into
+fn index_name(template: &str, owner_uuid: &str, record: &Record<'_>) -> String {
+    let timestamp = match &record.event {
+        CfwEvent::Traffic(event) => event.timestamp,
+        CfwEvent::Unknown(_) => Utc::now(),
+    };
+    template
+        .replace("{owner_uuid}", owner_uuid)
+        .replace("{vm}", record.vm)
+        .replace("{date}", &timestamp.format("%Y.%m.%d").to_string())
+}
+
+pub struct ElasticsearchSink {
+    cluster: Arc<Cluster>,
+    owner_uuid: String,
+    encoder: Encoder,
+    /// The action and source lines of every document not yet sent
+    pending: Vec<String>,
+    last_sent: Instant,
+    spool: Option<Spool>,
+    /// The delay before replaying the spool again once the cluster was unavailable
+    backoff: Duration,
+    retry_at: Option<Instant>,
+    stats: SinkStats,
+}
+
+impl ElasticsearchSink {
+    /// Send the documents in one bulk request, returning the documents the cluster was too busy
+    /// for along with an error for any it refused
+    fn bulk(&mut self, docs: &[String]) -> Result<(Vec<String>, io::Result<()>), BulkError> {
+        let bytes: usize = docs.iter().map(String::len).sum();
+        let outcome = self.cluster.bulk(docs)?;
+        let sent = docs.len() - outcome.retry.len() - outcome.refused;
+        self.stats.records += sent as u64;
+        self.stats.bytes += bytes as u64;
+        let result = if outcome.refused > 0 {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} documents were refused: {}",
+                    outcome.refused,
+                    outcome.reason.as_deref().unwrap_or("no reason given")
+                ),
+            ))
+        } else {
+            Ok(())
+        };
+        let retry = outcome
+            .retry
+            .into_iter()
+            .filter_map(|i| docs.get(i).cloned())
+            .collect();
+        Ok((retry, result))
+    }
+
+    /// Send every pending document, retrying until the cluster has taken all of them it's going to
+    fn send(&mut self) -> io::Result<()> {
+        if self.spool.is_some() {
+            return self.send_spooled();
+        }
+        let mut backoff = INITIAL_BACKOFF;
+        let mut result = Ok(());
+        while !self.pending.is_empty() {
+            let pending = std::mem::take(&mut self.pending);
+            match self.bulk(&pending) {
+                Ok((retry, sent)) => {
+                    if sent.is_err() {
+                        result = sent;
+                    }
+                    self.pending = retry;
+                    if self.pending.is_empty() {
+                        break;
+                    }
+                }
+                Err(BulkError::Unavailable(e)) => {
+                    warn!(
+                        "Elasticsearch is unavailable, retrying {} documents in {:?}: {}",
+                        pending.len(),
+                        backoff,
+                        e
+                    );
+                    self.pending = pending;
+                }
+                Err(BulkError::Refused(e)) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+            thread::sleep(backoff);
+            backoff = std::cmp::min(backoff * 2, self.cluster.max_backoff);
+        }
+        self.last_sent = Instant::now();
+        result
+    }
+
+    /// Send every pending document once, spilling them to the spool if the cluster is
+    /// unavailable, or if older documents are still waiting in the spool
+    fn send_spooled(&mut self) -> io::Result<()> {
+        self.replay()?;
+        let pending = std::mem::take(&mut self.pending);
+        self.last_sent = Instant::now();
+        let mut result = Ok(());
+        let unsent = if self.spool.as_ref().map_or(false, Spool::is_empty) {
+            match self.bulk(&pending) {
+                Ok((retry, sent)) => {
+                    result = sent;
+                    retry
+                }
+                Err(BulkError::Unavailable(e)) => {
+                    warn!(
+                        "Elasticsearch is unavailable, spooling {} documents: {}",
+                        pending.len(),
+                        e
+                    );
+                    self.back_off();
+                    pending
+                }
+                Err(BulkError::Refused(e)) => return Err(e),
+            }
+        } else {
+            pending
+        };
+        let spool = self.spool.as_mut().expect("only spooling with a spool");
+        for doc in &unsent {
+            spool.push(doc.as_bytes())?;
+        }
+        result
+    }
+
+    /// Send the spooled documents, oldest first, until the spool is empty or the cluster is
+    /// unavailable again
+    fn replay(&mut self) -> io::Result<()> {
+        if self.retry_at.map_or(false, |at| Instant::now() < at) {
+            return Ok(());
+        }
+        loop {
+            let docs: Vec<String> = match &self.spool {
+                Some(spool) if !spool.is_empty() => spool
+                    .peek(self.cluster.batch_size)?
+                    .into_iter()
+                    .map(|doc| String::from_utf8_lossy(&doc).into_owned())
+                    .collect(),
+                _ => return Ok(()),
+            };
+            let (retry, result) = match self.bulk(&docs) {
+                Ok(sent) => sent,
+                Err(BulkError::Unavailable(e)) => {
+                    debug!("Elasticsearch is still unavailable: {}", e);
+                    self.back_off();
+                    return Ok(());
+                }
+                Err(BulkError::Refused(e)) => (vec![], Err(e)),
+            };
+            let spool = self.spool.as_mut().expect("only replaying a spool");
+            spool.pop(docs.len())?;
+            // The documents the cluster was too busy for wait their turn again
+            for doc in &retry {
+                spool.push(doc.as_bytes())?;
+            }
+            if !retry.is_empty() {
+                self.back_off();
+            } else {
+                self.backoff = INITIAL_BACKOFF;
+                self.retry_at = None;
+            }
+            result?;
+            if self.retry_at.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait a while longer before replaying the spool again
+    fn back_off(&mut self) {
+        self.retry_at = Some(Instant::now() + self.backoff);
+        self.backoff = std::cmp::min(self.backoff * 2, self.cluster.max_backoff);
+    }
+}
+
+impl Sink for ElasticsearchSink {
+    fn name(&self) -> &str {
+        "elasticsearch"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        for record in records {
+            let index = index_name(&self.cluster.index, &self.owner_uuid, record);
+            let mut doc = format!(
+                "{{\"index\":{{\"_index\":{}}}}}\n",
+                serde_json::to_string(&index)?
+            );
+            doc.push_str(&self.encoder.to_line(record)?);
+            doc.push('\n');
+            self.pending.push(doc);
+        }
+        if self.pending.len() >= self.cluster.batch_size {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() && self.last_sent.elapsed() >= self.cluster.flush_interval {
+            self.send()?;
+        }
+        self.replay()
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    #[test]
+    fn indexes_are_named() {
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "",
+        );
+        let date = match &record.event {
+            CfwEvent::Traffic(event) => event.timestamp.format("%Y.%m.%d").to_string(),
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        assert_eq!(
+            index_name("cfw-{owner_uuid}-{vm}-{date}", "owner1", &record),
+            format!("cfw-owner1-vm1-{}", date)
+        );
+    }
+
+    #[test]
+    fn bulk_responses() {
+        let ok = r#"{"took": 3, "errors": false, "items": [{"index": {"status": 201}}]}"#;
+        assert_eq!(
+            parse_bulk_response(ok.as_bytes()).unwrap(),
+            BulkOutcome::default()
+        );
+
+        let partial = r#"{"took": 3, "errors": true, "items": [
+            {"index": {"status": 201}},
+            {"index": {"status": 429, "error": {"type": "es_rejected_execution_exception"}}},
+            {"index": {"status": 400, "error": {"type": "mapper_parsing_exception"}}},
+            {"index": {"status": 400, "error": {"type": "illegal_argument_exception"}}}
+        ]}"#;
+        let outcome = parse_bulk_response(partial.as_bytes()).unwrap();
+        assert_eq!(outcome.retry, vec![1], "rejected documents are retried");
+        assert_eq!(outcome.refused, 2);
+        assert!(
+            outcome.reason.unwrap().contains("mapper_parsing_exception"),
+            "the first reason is kept"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Annotating traffic records with what's known about the remote address of their event, the
+//! source of inbound traffic and the destination of outbound traffic, such as where it is (see
+//! the "geoip" module) and its hostname (see the "rdns" module).
+//!
+//! Looking an address up can be slow, so it never happens on a logger's thread. Loggers only read
+//! the annotation cache, and hand the addresses it doesn't have to a small pool of enricher threads
+//! that look them up and fill the cache in. The records of an address's first events are written
+//! without its annotation and the ones after the lookup finishes have it. When the pool falls
+//! behind, addresses are dropped rather than queued and are asked for again by a later event.
+//! Cached annotations expire after "enrich.cache_secs" so they follow changes to what they were
+//! looked up in, and the cache is emptied whenever the lookups are reloaded on SIGHUP.
+
+use crate::config::EnrichConfig;
+use cfwevent::parser::{self, Direction, TrafficEvent};
+use crossbeam::channel::{self, Receiver, Sender};
+use crossbeam::sync::ShardedLock;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+lazy_static! {
+    /// The enricher started at startup, if any lookups are configured
+    static ref ENRICHER: Mutex<Option<Arc<Enricher>>> = Mutex::new(None);
+}
+
+/// What's known about a remote address, serialized alongside its records
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Annotation {
+    /// ISO 3166-1 code of the country the address is in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_country: Option<String>,
+    /// The autonomous system the address is announced by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_asn: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_as_org: Option<String>,
+    /// The name the address's PTR record points to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_hostname: Option<String>,
+}
+
+/// A source of annotations, run on the enricher threads
+pub trait Lookup: Send + Sync {
+    /// Fill in what this lookup knows about `addr`, which is IPv4 for IPv4-mapped addresses
+    fn annotate(&self, addr: IpAddr, annotation: &mut Annotation);
+
+    /// Reread whatever the lookup is based on, keeping what it had if that fails
+    fn reload(&self);
+}
+
+struct Cached {
+    annotation: Arc<Annotation>,
+    expires: Instant,
+}
+
+pub struct Enricher {
+    lookups: Vec<Box<dyn Lookup>>,
+    cache: ShardedLock<HashMap<Ipv6Addr, Cached>>,
+    /// Addresses handed to the pool that it hasn't looked up yet, so they're only asked for once
+    pending: Mutex<HashSet<Ipv6Addr>>,
+    requests: Sender<Ipv6Addr>,
+    cache_entries: usize,
+    ttl: Duration,
+}
+
+/// The remote address of an event
+pub fn remote_addr(event: &TrafficEvent) -> Ipv6Addr {
+    match event.direction {
+        Direction::In => event.source_ip,
+        Direction::Out => event.destination_ip,
+    }
+}
+
+impl Enricher {
+    fn new(config: &EnrichConfig, lookups: Vec<Box<dyn Lookup>>) -> (Self, Receiver<Ipv6Addr>) {
+        let (requests, pool) = channel::bounded(config.queue_len);
+        let enricher = Enricher {
+            lookups,
+            cache: ShardedLock::new(HashMap::new()),
+            pending: Mutex::new(HashSet::new()),
+            requests,
+            cache_entries: config.cache_entries,
+            ttl: Duration::from_secs(config.cache_secs),
+        };
+        (enricher, pool)
+    }
+
+    /// The cached annotation of `addr`, asking the pool to look it up if there isn't one
+    pub fn annotation(&self, addr: &Ipv6Addr) -> Option<Arc<Annotation>> {
+        if let Some(cached) = self.cache.read().unwrap().get(addr) {
+            if cached.expires > Instant::now() {
+                return Some(Arc::clone(&cached.annotation));
+            }
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if pending.insert(*addr) && self.requests.try_send(*addr).is_err() {
+            pending.remove(addr);
+        }
+        None
+    }
+
+    /// Look an address up and cache what was found
+    fn look_up(&self, addr: Ipv6Addr) {
+        let ip = parser::ipv4_mapped(&addr).map_or(IpAddr::V6(addr), IpAddr::V4);
+        let mut annotation = Annotation::default();
+        for lookup in &self.lookups {
+            lookup.annotate(ip, &mut annotation);
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.write().unwrap();
+        if cache.len() >= self.cache_entries {
+            cache.retain(|_, cached| cached.expires > now);
+        }
+        // Still full of live entries, so this one is looked up again next time
+        if cache.len() < self.cache_entries {
+            let cached = Cached {
+                annotation: Arc::new(annotation),
+                expires: now + self.ttl,
+            };
+            cache.insert(addr, cached);
+        }
+        drop(cache);
+        self.pending.lock().unwrap().remove(&addr);
+    }
+
+    fn reload(&self) {
+        for lookup in &self.lookups {
+            lookup.reload();
+        }
+        self.cache.write().unwrap().clear();
+    }
+}
+
+/// Start the enricher threads for the given lookups, unless there are none
+pub fn init(config: &EnrichConfig, lookups: Vec<Box<dyn Lookup>>) -> io::Result<()> {
+    if lookups.is_empty() {
+        return Ok(());
+    }
+    let (enricher, pool) = Enricher::new(config, lookups);
+    let enricher = Arc::new(enricher);
+    for i in 0..config.workers {
+        let enricher = Arc::clone(&enricher);
+        let pool = pool.clone();
+        thread::Builder::new()
+            .name(format!("enricher{}", i))
+            .spawn(move || {
+                for addr in pool.iter() {
+                    enricher.look_up(addr);
+                }
+            })?;
+    }
+    *ENRICHER.lock().unwrap() = Some(enricher);
+    Ok(())
+}
+
+/// The running enricher, if there is one
+pub fn enricher() -> Option<Arc<Enricher>> {
+    ENRICHER.lock().unwrap().as_ref().cloned()
+}
+
+/// Reload every lookup, see `Lookup::reload`
+pub fn reload() {
+    if let Some(enricher) = enricher() {
+        enricher.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Countries;
+
+    impl Lookup for Countries {
+        fn annotate(&self, addr: IpAddr, annotation: &mut Annotation) {
+            if let IpAddr::V4(v4) = addr {
+                annotation.remote_country = Some(format!("{}", v4.octets()[3]));
+            }
+        }
+
+        fn reload(&self) {}
+    }
+
+    #[test]
+    fn annotations_are_cached() {
+        let config = EnrichConfig {
+            cache_entries: 1,
+            ..EnrichConfig::default()
+        };
+        let (enricher, pool) = Enricher::new(&config, vec![Box::new(Countries)]);
+        let first: Ipv6Addr = "::ffff:192.0.2.1".parse().unwrap();
+        let second: Ipv6Addr = "::ffff:192.0.2.2".parse().unwrap();
+
+        assert_eq!(enricher.annotation(&first), None, "not looked up yet");
+        assert_eq!(enricher.annotation(&first), None);
+        assert_eq!(
+            pool.try_iter().collect::<Vec<_>>(),
+            vec![first],
+            "asked for once"
+        );
+        enricher.look_up(first);
+        let annotation = enricher.annotation(&first).expect("cached");
+        assert_eq!(annotation.remote_country.as_deref(), Some("1"));
+
+        enricher.annotation(&second);
+        enricher.look_up(second);
+        assert_eq!(enricher.annotation(&second), None, "the cache is full");
+        assert!(enricher.annotation(&first).is_some());
+
+        enricher.reload();
+        assert_eq!(
+            enricher.annotation(&first),
+            None,
+            "reloading empties the cache"
+        );
+    }
+
+    /// Cached annotations are looked up for every event. Run on its own in a release build with
+    /// `cargo test --release annotation_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn annotation_throughput() {
+        const LOOKUPS: u32 = 1_000_000;
+        let (enricher, _pool) = Enricher::new(&EnrichConfig::default(), vec![Box::new(Countries)]);
+        let addrs: Vec<Ipv6Addr> = (0..1024u32)
+            .map(|i| std::net::Ipv4Addr::from(0xc000_0200 + i).to_ipv6_mapped())
+            .collect();
+        for addr in &addrs {
+            enricher.look_up(*addr);
+        }
+        let start = Instant::now();
+        let found = (0..LOOKUPS)
+            .filter(|i| {
+                enricher
+                    .annotation(&addrs[*i as usize % addrs.len()])
+                    .is_some()
+            })
+            .count();
+        let rate = f64::from(LOOKUPS) / start.elapsed().as_secs_f64();
+        println!("looked up {} annotations at {:.0} events/s", found, rate);
+        assert_eq!(found, LOOKUPS as usize);
+        assert!(
+            rate >= 100_000.0,
+            "enrichment fell behind at {:.0} events/s",
+            rate
+        );
+    }
+}
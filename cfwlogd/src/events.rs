@@ -23,35 +23,60 @@
 //! small amount of time in hopes of coalescing multiple events coming down the channel. After
 //! waiting the fanout thread will loop over each of the received events and attempt to send off
 //! the event to the appropriate `Logger` if one exists.  If the `Logger` does not exist it will
-//! spawn a new `Logger` thread for the zone and proceed to send it the `Logger` via an unbounded
-//! channel. These channels are unbounded because writing to disk is almost always slower than the
-//! `EventSource` producing events. The `Logger` then takes care of serializing the event into json
-//! and writing it out to the appropriate log file.
+//! spawn a new `Logger` thread for the zone and proceed to send it the `Logger` via another bounded
+//! channel, sized by the config's "queues.zone_capacity". What happens to events arriving at a full
+//! channel is up to the "queues.overflow" policy, see the "queue" module. The `Logger` then takes
+//! care of serializing the event into json and writing it out to the appropriate log file.
 //!
+//! With "reads.double_buffer" set the parsing moves off the device reading thread onto one of its
+//! own, so the next read is issued while the last one is being parsed, see `ReadBuffers`.
 
+use crate::audit::LossAudit;
+use crate::clock::{self, SharedClock};
+use crate::compat;
+use crate::config::{QueueConfig, ReadConfig, SharedConfig};
+use crate::disk::DiskMonitor;
+use crate::exit::{self, ExitReason, Subsystem};
+use crate::health::{self, Heartbeat};
+use crate::holding::{self, Holding};
+use crate::inflight::InFlight;
+use crate::live::LiveHub;
 use crate::logger::{self, Logger};
-use crate::parser::{self, CfwEvent};
-use crate::zones::{Vmobjs, Zonedid};
-use crossbeam::channel::{self, Receiver, Select, Sender, TrySendError};
+use crate::memory::{MemoryTracker, Pressure};
+use crate::node::NodeIdentity;
+use crate::queue::{self, DropReport};
+use crate::rules::Rules;
+use crate::selftest::{self, Injector};
+use crate::source::EventSource;
+use crate::stats::{self, DropReason, Stats};
+use crate::zones::{Vmobjs, ZoneChange, Zonedid};
+use cfwevent::parser::{self, CfwEvent, Received};
+use chrono::Utc;
+use crossbeam::channel::{self, Receiver, Select, SendError, Sender};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const RING_CAPACITY_MULTIPLIER: usize = 512;
 
+/// How long the device reader waits before checking if memory pressure has subsided
+const MEMORY_PAUSE_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How often the device reader asks the source whether it has dropped any events
+const SOURCE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long the fanout thread waits before checking again whether a deleted zone whose events
+/// were still in flight can be retired
+const RETIRE_RECHECK: Duration = Duration::from_millis(100);
+
 /// Holds a Mutex protected mapping of zonedid to Logging thread
 pub type Loggers = Arc<Mutex<HashMap<Zonedid, Logger>>>;
 
-/// Trait that represents a Firewall Event Source
-pub trait EventSource: Send {
-    /// Reads n events into the given buffer, returning how many bytes were read.
-    fn read_events(&mut self, buf: &mut [u8]) -> std::io::Result<usize>;
-    /// Returns a tuple that tells you the largest event size, and the max number of events that
-    /// may be returned in a single read.
-    fn event_sizing(&mut self) -> std::io::Result<(usize, usize)>;
-}
-
 /// Clamp the ringsize so that it falls somewhere between the min and max values.
 fn clamp_ring_size(min: usize, max: usize, value: usize) -> usize {
     if value > max {
@@ -63,95 +88,486 @@ fn clamp_ring_size(min: usize, max: usize, value: usize) -> usize {
     }
 }
 
-/// Start a thread that consumes events from an `EventSource`.
-/// The consumed events will be sent to the returned `Receiver`.
-pub fn start_event_reader<T: EventSource + 'static>(
-    mut device: T,
-) -> (Receiver<CfwEvent>, thread::JoinHandle<()>) {
-    let (max, ringsize) = device.event_sizing().unwrap_or_else(|e| {
-        error!("failed to get ring size from device: {}", e);
-        std::process::exit(e.raw_os_error().unwrap_or(-1));
-    });
-    debug!(
-        "device responded with max event size: {}, ring size: {}",
-        &max, &ringsize
-    );
+/// Notices a source's drop counter going up, such as when the ipfev device's ring overflowed
+/// because we didn't read it fast enough, and tells every zone about the gap.
+struct SourceDrops {
+    /// The source's name in the daemon log
+    name: String,
+    /// The source's drop counter as of the last check
+    dropped: u64,
+    last_check: Instant,
+}
+
+impl SourceDrops {
+    fn new<T: EventSource>(name: String, device: &mut T) -> Self {
+        let dropped = match device.stats() {
+            Ok(s) => {
+                if s.dropped > 0 {
+                    info!("{} dropped {} events before we started", name, s.dropped);
+                }
+                s.dropped
+            }
+            Err(e) => {
+                warn!("failed to get {} stats: {}", name, e);
+                0
+            }
+        };
+        SourceDrops {
+            name,
+            dropped,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Check the source's drop counter, if it has been long enough since the last check
+    fn check<T: EventSource>(&mut self, device: &mut T, stats: &Stats) {
+        if self.last_check.elapsed() < SOURCE_STATS_INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+        match device.stats() {
+            Ok(s) if s.dropped > self.dropped => {
+                let lost = s.dropped - self.dropped;
+                self.dropped = s.dropped;
+                // CMON TRITON-1755
+                warn!("{} dropped {} events", self.name, lost);
+                stats::record_source_lost(stats, lost);
+            }
+            Ok(_) => (),
+            Err(e) => debug!("failed to get {} stats: {}", self.name, e),
+        }
+    }
+}
+
+/// Shared with the device reader threads, to tell them to stop and to see how many are still
+/// reading
+#[derive(Debug)]
+pub struct ReaderState {
+    stop: AtomicBool,
+    sources: usize,
+    running: AtomicUsize,
+}
+
+impl ReaderState {
+    pub fn new(sources: usize) -> Self {
+        ReaderState {
+            stop: AtomicBool::new(false),
+            sources,
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Have the readers stop once they're done with the events they already read, so that a
+    /// shutdown can drain everything that was read without more arriving behind it
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+
+    fn stopping(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub fn started(&self) {
+        self.running.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn stopped(&self) {
+        self.running.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// The number of sources being read from
+    pub fn sources(&self) -> usize {
+        self.sources
+    }
+
+    /// The number of sources still being read from
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::SeqCst)
+    }
+}
+
+/// Start a thread for each of the named `EventSource`s that consumes its events. The events of
+/// every source are merged into the returned `Receiver`, counted in `inflight` until the fanout
+/// thread takes them, and the threads keep `readers` up to date. The `Injector` queues events
+/// alongside theirs, see the "selftest" module.
+#[allow(clippy::too_many_arguments)]
+pub fn start_event_readers<T: EventSource + 'static>(
+    devices: Vec<(String, T)>,
+    queues: QueueConfig,
+    reads: ReadConfig,
+    stats: Stats,
+    memory: Arc<MemoryTracker>,
+    disk: Arc<DiskMonitor>,
+    audit: Arc<LossAudit>,
+    inflight: Arc<InFlight>,
+    readers: Arc<ReaderState>,
+) -> (
+    queue::Receiver<CfwEvent>,
+    Injector,
+    Vec<thread::JoinHandle<()>>,
+) {
+    let devices: Vec<_> = devices
+        .into_iter()
+        .map(|(name, mut device)| {
+            let (max, ringsize) = device.event_sizing().unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Device,
+                    &format!("failed to get ring size from {}: {}", name, e),
+                )
+            });
+            debug!(
+                "{} responded with max event size: {}, ring size: {}",
+                name, max, ringsize
+            );
+            (name, device, max, ringsize)
+        })
+        .collect();
     // This value is kind of picked out of thin air based on initial testing during development.
     // It's quite possible this will need to be tuned at some point in the future. We could make
     // the channel unbounded at the cost of some performance, but it's probably a good idea to have
     // some sort of backpressure control here so we don't endlessly grow in memory. Testing also
     // showed that selecting a small ringsize provides the potential for cfwlogd to drop events
     // so we set the ringsize lower bound to 1024. We also set an upper bound to 2048 so that we
-    // don't needlessly allocate a large chunk of memory at startup.
-    let rs = clamp_ring_size(1024, 2048, ringsize);
-    debug!(
-        "sizing the channel capacity to {} * {}",
-        rs, RING_CAPACITY_MULTIPLIER
+    // don't needlessly allocate a large chunk of memory at startup. Every source gets that much
+    // room in the channel they share.
+    let capacity = queues.reader_capacity.unwrap_or_else(|| {
+        devices
+            .iter()
+            .map(|(name, _, _, ringsize)| {
+                let rs = clamp_ring_size(1024, 2048, *ringsize);
+                debug!(
+                    "sizing the channel capacity for {} to {} * {}",
+                    name, rs, RING_CAPACITY_MULTIPLIER
+                );
+                RING_CAPACITY_MULTIPLIER * rs
+            })
+            .sum()
+    });
+    let (tx, rx) = queue::bounded(capacity, queues.overflow);
+    let injector = Injector::new(
+        tx.clone(),
+        Arc::clone(&memory),
+        Arc::clone(&inflight),
+        Arc::clone(&audit),
     );
-    let capacity = RING_CAPACITY_MULTIPLIER * rs;
-    let (tx, rx) = channel::bounded(capacity);
-    (
-        rx,
-        thread::Builder::new()
-            .name("EventReader".to_owned())
-            .spawn(move || {
-                // a buffer that can hold a full read of the ringbuffer
-                let mut buf = vec![0; max * ringsize];
-
-                loop {
-                    let size = match device.read_events(&mut buf) {
-                        Ok(size) => size,
-                        Err(e) => {
-                            match e.kind() {
-                                std::io::ErrorKind::Interrupted => continue,
-                                _ => {
-                                    // We failed to read from the EventSource so let's log the
-                                    // error and drop our `Sender` so that we can shutdown
-                                    // gracefully"
-                                    error!("failed to read from the EventSource: {:?}", e);
-                                    break;
-                                }
-                            }
-                        }
+    let handles = devices
+        .into_iter()
+        .map(|(name, device, max, ringsize)| {
+            let tx = tx.clone();
+            let stats = Arc::clone(&stats);
+            let memory = Arc::clone(&memory);
+            let disk = Arc::clone(&disk);
+            let audit = Arc::clone(&audit);
+            let inflight = Arc::clone(&inflight);
+            let readers = Arc::clone(&readers);
+            readers.started();
+            thread::Builder::new()
+                .name("EventReader".to_owned())
+                .spawn(move || {
+                    // Room for at least one event, and by default the whole ring
+                    let buffer_size = reads.buffer_bytes.map_or(max * ringsize, |b| b.max(max));
+                    let parser = EventParser {
+                        tx,
+                        stats: Arc::clone(&stats),
+                        memory: Arc::clone(&memory),
+                        disk,
+                        audit,
+                        inflight,
+                        report: DropReport::new("fanout"),
                     };
+                    match ReadBuffers::new(buffer_size, reads.double_buffer, parser) {
+                        Ok(buffers) => read_source(name, device, buffers, stats, memory, &readers),
+                        Err(e) => error!("failed to start parsing {}'s events: {}", name, e),
+                    }
+                    readers.stopped();
+                })
+                .unwrap_or_else(|e| {
+                    exit::fault(
+                        Subsystem::Device,
+                        &format!("failed to start event reader thread: {}", e),
+                    )
+                })
+        })
+        .collect();
+    (rx, injector, handles)
+}
+
+/// What the events of a source's reads are parsed and queued with
+struct EventParser {
+    tx: queue::Sender<CfwEvent>,
+    stats: Stats,
+    memory: Arc<MemoryTracker>,
+    disk: Arc<DiskMonitor>,
+    audit: Arc<LossAudit>,
+    inflight: Arc<InFlight>,
+    report: DropReport,
+}
+
+impl EventParser {
+    /// Queue the events of a read, returning true once the receiving side of the channel is gone
+    fn parse(&mut self, bytes: &[u8]) -> bool {
+        let done = parse_events(
+            bytes,
+            &self.tx,
+            &self.stats,
+            &self.memory,
+            &self.disk,
+            &self.audit,
+            &self.inflight,
+            &mut self.report,
+        );
+        self.report.check();
+        compat::report();
+        done
+    }
+}
 
-                    if parse_events(&buf[..size], &tx) {
-                        // The recv channel is closed so we can stop reading events
+/// The buffers a source is read into. With a single buffer each read is parsed before the next
+/// one is issued. Double buffered, a parser thread works through one buffer while the source is
+/// read into the other, so a burst of events is taken off the device's ring sooner. The reader
+/// only waits on the parser once both buffers are full.
+enum ReadBuffers {
+    Single {
+        buf: Vec<u8>,
+        parser: EventParser,
+    },
+    Double {
+        /// Filled buffers on their way to the parser, along with how much of each was read
+        filled: Sender<(Vec<u8>, usize)>,
+        /// Buffers the parser is done with
+        free: Receiver<Vec<u8>>,
+        /// A buffer left over from a failed read
+        spare: Option<Vec<u8>>,
+        parser: thread::JoinHandle<()>,
+    },
+}
+
+impl ReadBuffers {
+    fn new(size: usize, double: bool, mut parser: EventParser) -> io::Result<ReadBuffers> {
+        if !double {
+            return Ok(ReadBuffers::Single {
+                buf: vec![0; size],
+                parser,
+            });
+        }
+        let (filled, to_parse) = channel::bounded::<(Vec<u8>, usize)>(1);
+        let (parsed, free) = channel::bounded(2);
+        parsed
+            .send(vec![0; size])
+            .expect("there's room for both buffers");
+        let parser = thread::Builder::new()
+            .name("EventParser".to_owned())
+            .spawn(move || {
+                for (buf, size) in to_parse.iter() {
+                    if parser.parse(&buf[..size]) || parsed.send(buf).is_err() {
                         break;
                     }
                 }
-            })
-            .expect("failed to start event reader thread"),
-    )
+            })?;
+        Ok(ReadBuffers::Double {
+            filled,
+            free,
+            spare: Some(vec![0; size]),
+            parser,
+        })
+    }
+
+    /// Read from `device` and hand what was read to the parser, returning Ok(true) once the
+    /// receiving side of the channel is gone
+    fn read<T: EventSource>(&mut self, device: &mut T) -> io::Result<bool> {
+        match self {
+            ReadBuffers::Single { buf, parser } => {
+                let size = device.read_events(buf)?;
+                probe!(read(size as u64));
+                Ok(parser.parse(&buf[..size]))
+            }
+            ReadBuffers::Double {
+                filled,
+                free,
+                spare,
+                ..
+            } => {
+                // Both buffers are out until the parser is done with one, or gives up on them
+                let mut buf = match spare.take().map_or_else(|| free.recv(), Ok) {
+                    Ok(buf) => buf,
+                    Err(_) => return Ok(true),
+                };
+                match device.read_events(&mut buf) {
+                    Ok(size) => {
+                        probe!(read(size as u64));
+                        Ok(filled.send((buf, size)).is_err())
+                    }
+                    Err(e) => {
+                        *spare = Some(buf);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Wait for the parser to get through the reads it was handed
+    fn finish(self) {
+        if let ReadBuffers::Double { filled, parser, .. } = self {
+            drop(filled);
+            if parser.join().is_err() {
+                error!("the event parser thread panicked");
+            }
+        }
+    }
+}
+
+/// Read events from `device` into `buffers` until it fails or the receiving side of the channel
+/// goes away
+fn read_source<T: EventSource>(
+    name: String,
+    mut device: T,
+    mut buffers: ReadBuffers,
+    stats: Stats,
+    memory: Arc<MemoryTracker>,
+    readers: &ReaderState,
+) {
+    let mut paused = false;
+    let mut drops = SourceDrops::new(name.clone(), &mut device);
+
+    loop {
+        if readers.stopping() {
+            info!("stopped reading from {} for shutdown", name);
+            break;
+        }
+        drops.check(&mut device, &stats);
+        // Stop reading from the device while we are close to our memory ceiling so the
+        // loggers have a chance to catch up.
+        if memory.pressure() == Pressure::Paused {
+            if !paused {
+                warn!(
+                    "approaching memory ceiling ({} bytes used), pausing reads from {}",
+                    memory.used(),
+                    name
+                );
+                paused = true;
+            }
+            thread::sleep(MEMORY_PAUSE_INTERVAL);
+            continue;
+        } else if paused {
+            info!("memory pressure has subsided, resuming reads from {}", name);
+            paused = false;
+        }
+
+        let done = match buffers.read(&mut device) {
+            Ok(done) => done,
+            Err(e) => {
+                match e.kind() {
+                    std::io::ErrorKind::Interrupted => continue,
+                    // The source has nothing left to read, such as a replayed file
+                    std::io::ErrorKind::UnexpectedEof => {
+                        info!("{} has no more events: {}", name, e);
+                        break;
+                    }
+                    _ => {
+                        // We failed to read from the EventSource so let's log the error and
+                        // drop our `Sender` so that we can shutdown gracefully"
+                        error!("failed to read from {}: {:?}", name, e);
+                        break;
+                    }
+                }
+            }
+        };
+        if done {
+            // The recv channel is closed so we can stop reading events
+            break;
+        }
+    }
+    buffers.finish();
+
+    match device.stats() {
+        Ok(s) => info!(
+            "{} received {} events, dropped {}",
+            name, s.received, s.dropped
+        ),
+        Err(e) => warn!("failed to get {} stats: {}", name, e),
+    }
+    if let Err(e) = device.close() {
+        warn!("failed to close {}: {}", name, e);
+    }
 }
 
 /// Takes a buffer of bytes and slices them up into `CfwEvent`s that are then sent to the provided
-/// `Sender`.
-fn parse_events(bytes: &[u8], sender: &Sender<CfwEvent>) -> bool {
+/// `Sender`. Under memory pressure, or when the log filesystem is critically low on space, only a
+/// sample of the events are sent. Events dropped because the channel is full are counted in
+/// `report`.
+#[allow(clippy::too_many_arguments)]
+fn parse_events(
+    bytes: &[u8],
+    sender: &queue::Sender<CfwEvent>,
+    stats: &Stats,
+    memory: &MemoryTracker,
+    disk: &DiskMonitor,
+    audit: &LossAudit,
+    inflight: &InFlight,
+    report: &mut DropReport,
+) -> bool {
     let mut bytes = bytes;
+    // Every event of a read was read at the same time
+    let received = Received {
+        timestamp: Utc::now(),
+        hrtime: clock::hrtime(),
+    };
     loop {
-        // Leaving this as an expect call because if we ever get out of sync or the device returns
-        // us not enough data we will be in a very bad place in terms of figuring out how to
-        // continue so it's best we just crash.
-        let (leftover, event) = parser::cfwevent_parse(&bytes).expect("event parsing failed");
-        if let Err(e) = sender.try_send(event) {
-            match e {
-                // Unfortunately we have to drop an event
+        // If we ever get out of sync or the source returns us a truncated event there's no telling
+        // where the next event starts, so the rest of the read is discarded and we carry on with
+        // the next one.
+        let (leftover, mut event) = match parser::cfwevent_parse(&bytes) {
+            Ok(parsed) => parsed,
+            Err(_) => {
                 // CMON TRITON-1755
-                TrySendError::Full(dropped_event) => warn!(
-                    "processing channel is full ({} queued) so we are dropping an event for \\
-                     zonedid: {}",
-                    sender.len(),
-                    dropped_event.zone()
-                ),
-                // We are in the process of shutting down
-                TrySendError::Disconnected(_) => {
-                    info!("the event processing channel has disconnected");
-                    return true;
-                }
+                error!(
+                    "discarding the last {} bytes of a read, they don't parse as an event",
+                    bytes.len()
+                );
+                break;
             }
-        }
+        };
         bytes = leftover;
+        probe!(parse(event.zone()));
+        compat::check(&event);
+        audit.stamp(&mut event);
+        event.set_received(received);
+        let dropped = if !memory.admit() {
+            Some(DropReason::MemoryPressure)
+        } else if !disk.admit() {
+            Some(DropReason::DiskSpace)
+        } else {
+            None
+        };
+        if let Some(reason) = dropped {
+            stats::record_suppressed(stats, &event, reason);
+            audit.dropped(&event);
+            if bytes.is_empty() {
+                break;
+            };
+            continue;
+        }
+        // Account for the event before it's sent so the receiving side can never see it before
+        // we do.
+        memory.event_queued();
+        inflight.queued(event.zone());
+        // Unfortunately we may have to drop an event when the channel is full
+        let sent = sender.send(event, |dropped| {
+            memory.events_done(1);
+            inflight.dropped(dropped.zone());
+            stats::record_drop(stats, dropped.zone(), DropReason::QueueFull);
+            audit.dropped(&dropped);
+            report.dropped(dropped.zone());
+        });
+        // We are in the process of shutting down
+        if let Err(SendError(event)) = sent {
+            memory.events_done(1);
+            inflight.dropped(event.zone());
+            audit.dropped(&event);
+            info!("the event processing channel has disconnected");
+            return true;
+        }
         if bytes.is_empty() {
             break;
         };
@@ -160,10 +576,25 @@ fn parse_events(bytes: &[u8], sender: &Sender<CfwEvent>) -> bool {
 }
 
 /// Starts a thread that will receive `CfwEvent`s and fan them out to per zone logging threads.
+/// Zones being created and deleted are received on `changes`, see `zones::start_vminfod`, and the
+/// events taken off `events` are released from `inflight`. The thread beats `heartbeat` for the
+/// "health" module.
+#[allow(clippy::too_many_arguments)]
 pub fn start_event_fanout(
-    events: Receiver<CfwEvent>,
+    events: queue::Receiver<CfwEvent>,
     shutdown: Receiver<()>,
+    changes: Receiver<ZoneChange>,
     vmobjs: Vmobjs,
+    rules: Rules,
+    stats: Stats,
+    config: SharedConfig,
+    memory: Arc<MemoryTracker>,
+    audit: Arc<LossAudit>,
+    inflight: Arc<InFlight>,
+    live: Arc<LiveHub>,
+    clock: SharedClock,
+    node: Option<Arc<NodeIdentity>>,
+    heartbeat: Arc<Heartbeat>,
 ) -> (Loggers, thread::JoinHandle<()>) {
     let loggers: Loggers = Arc::new(Mutex::new(HashMap::new()));
     let loggers2 = Arc::clone(&loggers);
@@ -171,45 +602,174 @@ pub fn start_event_fanout(
         loggers,
         thread::Builder::new()
             .name("EventFanout".to_owned())
-            .spawn(move || fanout_events(events, shutdown, vmobjs, loggers2))
-            .expect("failed to start event fanout thread"),
+            .spawn(move || {
+                fanout_events(
+                    events, shutdown, changes, vmobjs, rules, stats, config, memory, audit,
+                    &inflight, live, clock, node, &heartbeat, loggers2,
+                )
+            })
+            .unwrap_or_else(|e| {
+                exit::fault(
+                    Subsystem::Events,
+                    &format!("failed to start event fanout thread: {}", e),
+                )
+            }),
     )
 }
 
 /// Fanout events coming from the Receiver into the appropriate Logger, creating a new Logger if
-/// one does not yet exist.
+/// one does not yet exist. Events of zones that aren't in `Vmobjs` yet are held until the zone is
+/// created, see the "holding" module. A deleted zone is tombstoned for the configured grace
+/// period, during which events still arriving for it are logged as usual, and is then retired
+/// once none of its events are left on `events`, see the "inflight" module.
+#[allow(clippy::too_many_arguments)]
 fn fanout_events(
-    events: Receiver<CfwEvent>,
+    events: queue::Receiver<CfwEvent>,
     shutdown: Receiver<()>,
+    changes: Receiver<ZoneChange>,
     vmobjs: Vmobjs,
+    rules: Rules,
+    stats: Stats,
+    config: SharedConfig,
+    memory: Arc<MemoryTracker>,
+    audit: Arc<LossAudit>,
+    inflight: &InFlight,
+    live: Arc<LiveHub>,
+    clock: SharedClock,
+    node: Option<Arc<NodeIdentity>>,
+    heartbeat: &Heartbeat,
     mut loggers: Loggers,
 ) {
+    // Deleted zones along with when they are retired, unless their events are still in flight
+    let mut tombstones: Vec<(Zonedid, String, Instant)> = vec![];
+    let mut report = DropReport::new("logger");
+    let mut holding = Holding::new(config.read().unwrap().unknown_zones);
+    // Until vminfod is ready every zone we don't know about may just not have been reported yet,
+    // so nothing held is expired
+    let mut ready = false;
+
     let mut sel = Select::new();
     let events_ready = sel.recv(&events);
     let shutdown_ready = sel.recv(&shutdown);
+    let changes_ready = sel.recv(&changes);
 
     loop {
-        match sel.ready() {
+        heartbeat.beat();
+        let wait = tombstones
+            .iter()
+            .map(|(_, _, retire_at)| *retire_at)
+            .chain(holding.next_expiry().filter(|_| ready))
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+            .fold(health::HEARTBEAT_INTERVAL, Duration::min);
+        let selected = sel.ready_timeout(wait).ok();
+        match selected {
             // This should never be a Disconnected message because the thread holding the tx end of
             // the channel will never close it. There's also no way to currently check if the
             // channel is disconnected given the current API.
-            i if i == events_ready => {
+            Some(i) if i == events_ready => {
                 thread::sleep(std::time::Duration::from_nanos(500_000));
+                let batch: Vec<_> = events.try_iter().take(1024).collect();
+                inflight.taken(&batch);
                 queue_zone_events(
-                    events.try_iter().take(1024).collect(),
+                    batch,
                     &vmobjs,
+                    &rules,
+                    &stats,
+                    &config,
+                    &memory,
+                    &audit,
+                    &live,
+                    &clock,
+                    &node,
                     &mut loggers,
+                    &mut holding,
+                    &mut report,
                 )
             }
-            i if i == shutdown_ready => {
+            Some(i) if i == shutdown_ready => {
                 shutdown
                     .recv()
                     .expect("the signal rx should never outlive the tx");
                 debug!("event fanout thread received shutdown signal");
                 break;
             }
-            _ => unreachable!(),
+            Some(i) if i == changes_ready => match changes.recv() {
+                Ok(ZoneChange::Created(zonedid)) => {
+                    let held = holding.release(zonedid);
+                    if !held.is_empty() {
+                        queue_zone_events(
+                            held,
+                            &vmobjs,
+                            &rules,
+                            &stats,
+                            &config,
+                            &memory,
+                            &audit,
+                            &live,
+                            &clock,
+                            &node,
+                            &mut loggers,
+                            &mut holding,
+                            &mut report,
+                        );
+                    }
+                }
+                Ok(ZoneChange::Deleted(zonedid, uuid)) => {
+                    let grace = Duration::from_secs(config.read().unwrap().retire_grace_secs);
+                    tombstones.push((zonedid, uuid, Instant::now() + grace));
+                }
+                Ok(ZoneChange::Ready) => {
+                    if !ready {
+                        // The zones still held get the full hold to show up from here on
+                        holding.restart(Instant::now());
+                        ready = true;
+                    }
+                }
+                // Deleted zones are simply never retired without the vminfod watcher, and the
+                // events of new zones are held until they expire
+                Err(_) => {
+                    sel.remove(changes_ready);
+                    ready = true;
+                }
+            },
+            Some(_) => unreachable!(),
+            // The oldest tombstone or held zone is due, or it's time for a heartbeat
+            None => (),
+        }
+
+        let now = Instant::now();
+        for (zonedid, uuid, retire_at) in tombstones.iter_mut() {
+            if *retire_at > now {
+                continue;
+            }
+            if inflight.referenced(*zonedid) {
+                // Check again once the events in flight have had a chance to be taken
+                *retire_at = now + RETIRE_RECHECK;
+                continue;
+            }
+            retire_zone(*zonedid, uuid, &vmobjs, &loggers);
         }
+        tombstones.retain(|(_, _, retire_at)| *retire_at > now);
+        let expired = if ready { holding.expired(now) } else { vec![] };
+        let known = expire_held(expired, &vmobjs, &config, &memory, &audit);
+        if !known.is_empty() {
+            queue_zone_events(
+                known,
+                &vmobjs,
+                &rules,
+                &stats,
+                &config,
+                &memory,
+                &audit,
+                &live,
+                &clock,
+                &node,
+                &mut loggers,
+                &mut holding,
+                &mut report,
+            );
+        }
+        report.check();
     }
 
     // Attempt to grab all of the events that are currently in the queue so we can
@@ -218,50 +778,273 @@ fn fanout_events(
     // incoming events and we can't disconnect in a timely manner.
     drop(sel);
     let drain: Vec<_> = events.try_iter().take(events.len()).collect();
+    inflight.taken(&drain);
     drop(events);
     debug!(
         "event processing thread drained {} remaining events before shutdown",
         drain.len()
     );
-    queue_zone_events(drain, &vmobjs, &mut loggers);
+    queue_zone_events(
+        drain,
+        &vmobjs,
+        &rules,
+        &stats,
+        &config,
+        &memory,
+        &audit,
+        &live,
+        &clock,
+        &node,
+        &mut loggers,
+        &mut holding,
+        &mut report,
+    );
+    // Nothing else is going to show up for the zones still being held
+    let known = expire_held(holding.drain(), &vmobjs, &config, &memory, &audit);
+    queue_zone_events(
+        known,
+        &vmobjs,
+        &rules,
+        &stats,
+        &config,
+        &memory,
+        &audit,
+        &live,
+        &clock,
+        &node,
+        &mut loggers,
+        &mut holding,
+        &mut report,
+    );
+    for (zonedid, events) in holding.drain() {
+        write_held(zonedid, events, &config, &memory, &audit);
+    }
 
     info!("event processing thread exiting");
 }
 
+/// Retire a deleted zone's `Logger`, which drains its queue and closes the log file before
+/// removing the zone from `Vmobjs`. A zone without a `Logger` is removed right away. Whatever
+/// another zone that has since been given the zonedid has is left alone.
+fn retire_zone(zonedid: Zonedid, uuid: &str, vmobjs: &Vmobjs, loggers: &Loggers) {
+    let logger = {
+        let mut loggers = loggers.lock().unwrap();
+        match loggers.get(&zonedid) {
+            Some(logger) if logger.uuid == uuid => loggers.remove(&zonedid),
+            _ => None,
+        }
+    };
+    match logger {
+        Some(logger) => retire_logger(logger),
+        None => {
+            vmobjs.update(|vms| vms.remove_zone(&zonedid, uuid));
+        }
+    }
+}
+
+/// Tell a `Logger` to finish up, see `Logger::retire`
+fn retire_logger(logger: Logger) {
+    let uuid = logger.uuid.clone();
+    if let Err(e) = logger.retire() {
+        // The zone stays in `Vmobjs` since the Logger may still be writing its events
+        warn!("failed to retire the logger for {}: {}", uuid, e);
+    }
+}
+
+/// Handle the events of zones that were held for too long. Zones vminfod has reported in the
+/// meantime get their events back to be queued, the rest are written to the unknown zone log.
+fn expire_held(
+    held: Vec<(Zonedid, Vec<CfwEvent>)>,
+    vmobjs: &Vmobjs,
+    config: &SharedConfig,
+    memory: &MemoryTracker,
+    audit: &LossAudit,
+) -> Vec<CfwEvent> {
+    let mut known = vec![];
+    for (zonedid, events) in held {
+        if vmobjs.load().get(&zonedid).is_some() {
+            known.extend(events);
+        } else {
+            write_held(zonedid, events, config, memory, audit);
+        }
+    }
+    known
+}
+
+/// Write the events held for a zone that never showed up to the unknown zone log
+fn write_held(
+    zonedid: Zonedid,
+    events: Vec<CfwEvent>,
+    config: &SharedConfig,
+    memory: &MemoryTracker,
+    audit: &LossAudit,
+) {
+    let _serialization = config.read().unwrap().serialization().apply();
+    match holding::write_unknown(Path::new(logger::LOG_DIR), zonedid, &events) {
+        Ok(()) => {
+            warn!(
+                "zonedid {} never showed up, wrote its {} held events to {}",
+                zonedid,
+                events.len(),
+                holding::UNKNOWN_ZONE_LOG
+            );
+            audit.written(&audit.sequences(&events));
+        }
+        Err(e) => {
+            // CMON TRITON-1755
+            error!(
+                "failed to write the held events of zonedid {} to {}: {}",
+                zonedid,
+                holding::UNKNOWN_ZONE_LOG,
+                e
+            );
+            events.iter().for_each(|event| audit.dropped(event));
+        }
+    }
+    memory.events_done(events.len());
+}
+
+/// Queue an event for a zone's `Logger`, returning false if the `Logger` is gone
+#[allow(clippy::too_many_arguments)]
+fn send_event(
+    logger: &Logger,
+    event: CfwEvent,
+    zonedid: Zonedid,
+    stats: &Stats,
+    memory: &MemoryTracker,
+    audit: &LossAudit,
+    report: &mut DropReport,
+) -> bool {
+    let test = selftest::test_id(&event);
+    let sent = logger.send(event, |dropped| {
+        stats::record_drop(stats, zonedid, DropReason::QueueFull);
+        audit.dropped(&dropped);
+        memory.events_done(1);
+        report.dropped(zonedid);
+    });
+    match sent {
+        Ok(()) => {
+            probe!(enqueue(
+                zonedid,
+                logger.uuid.as_str(),
+                logger.queued() as u64
+            ));
+            if let Some(id) = test {
+                selftest::reached(id, "fanout".to_owned(), Ok(()));
+            }
+            true
+        }
+        Err(SendError(event)) => {
+            // Receive side of the log was disconnected somehow, so we drop the entry allowing it
+            // to be recreated on the next event.
+            // CMON TRITON-1755
+            error!(
+                "failed to log event for zone {} (logger channel disconnected)",
+                zonedid
+            );
+            stats::record_drop(stats, zonedid, DropReason::LoggerDisconnected);
+            audit.dropped(&event);
+            memory.events_done(1);
+            false
+        }
+    }
+}
+
 /// For a given cfw event, find or create a `Logger` thats responsible for serializing the event to
-/// disk.
-fn queue_zone_events(events: Vec<CfwEvent>, vmobjs: &Vmobjs, loggers: &mut Loggers) {
+/// disk. Events of zones that aren't in `Vmobjs` yet go to `holding`, and once a zone's `Logger` is
+/// created the events held for it are queued first. Events of zones that opted out of being logged
+/// are discarded, events over the zone's "rate_limit" are suppressed unless they're a self-test's,
+/// and events dropped because the `Logger`'s queue is full are counted in `report`.
+#[allow(clippy::too_many_arguments)]
+fn queue_zone_events(
+    events: Vec<CfwEvent>,
+    vmobjs: &Vmobjs,
+    rules: &Rules,
+    stats: &Stats,
+    config: &SharedConfig,
+    memory: &Arc<MemoryTracker>,
+    audit: &Arc<LossAudit>,
+    live: &Arc<LiveHub>,
+    clock: &SharedClock,
+    node: &Option<Arc<NodeIdentity>>,
+    loggers: &mut Loggers,
+    holding: &mut Holding,
+    report: &mut DropReport,
+) {
+    let rate_limit = config.read().unwrap().rate_limit;
+    let now = Instant::now();
     let mut loggers = loggers.lock().unwrap();
     for event in events {
         if let CfwEvent::Unknown(_) = event {
+            audit.dropped(&event);
+            memory.events_done(1);
             continue;
         };
         let zonedid = event.zone();
+        let vms = vmobjs.load();
+        // The zone opted out of being logged, see `zones::LOGGING_DISABLED_TAG`
+        if vms.logging_disabled(&zonedid) {
+            audit.dropped(&event);
+            memory.events_done(1);
+            continue;
+        }
+        // The zonedid was given to another zone and the `Logger` is the old zone's, which is done
+        // with once it has written the events already queued, see `VmTable::displaced_by`
+        let reused = match (loggers.get(&zonedid), vms.get(&zonedid)) {
+            (Some(logger), Some(zone)) => logger.uuid != zone.uuid,
+            _ => false,
+        };
+        if reused {
+            if let Some(logger) = loggers.remove(&zonedid) {
+                info!(
+                    "retiring the logger for {} since zonedid {} was reused",
+                    logger.uuid, zonedid
+                );
+                retire_logger(logger);
+            }
+        }
         let logger = match loggers.entry(zonedid) {
             Entry::Occupied(entry) => entry.into_mut(),
-            Entry::Vacant(entry) => match logger::start_logger(zonedid, Arc::clone(&vmobjs)) {
-                Some(logger) => {
-                    info!("new logging thread started for zonedid {}", zonedid);
-                    entry.insert(logger)
-                }
-                None => {
-                    // CMON TRITON-1755
-                    error!(
-                        "unable to match zonedid {} to vm object; dropping event",
-                        zonedid
-                    );
-                    continue;
+            Entry::Vacant(entry) => {
+                let logger = logger::start_logger(
+                    zonedid,
+                    Arc::clone(&vmobjs),
+                    Arc::clone(rules),
+                    stats,
+                    Arc::clone(&config.read().unwrap()),
+                    Arc::clone(memory),
+                    Arc::clone(audit),
+                    Arc::clone(live),
+                    Arc::clone(clock),
+                    node.clone(),
+                );
+                match logger {
+                    Some(logger) => {
+                        info!("new logging thread started for zonedid {}", zonedid);
+                        let logger = entry.insert(logger);
+                        for held in holding.release(zonedid) {
+                            send_event(logger, held, zonedid, stats, memory, audit, report);
+                        }
+                        logger
+                    }
+                    None => {
+                        // vminfod hasn't told us about the zone yet
+                        if let Some(event) = holding.hold(event) {
+                            audit.dropped(&event);
+                            memory.events_done(1);
+                        }
+                        continue;
+                    }
                 }
-            },
+            }
         };
-        if logger.send(event).is_err() {
-            // Receive side of the log was disconnected somehow, so we drop the entry allowing it
-            // to be recreated on the next event.
-            // CMON TRITON-1755
-            error!(
-                "failed to log event for zone {} (logger channel disconnected)",
-                zonedid
-            );
+        if selftest::test_id(&event).is_none() && !logger.admit(rate_limit.as_ref(), now) {
+            stats::record_suppressed(stats, &event, DropReason::RateLimited);
+            audit.dropped(&event);
+            memory.events_done(1);
+            continue;
+        }
+        if !send_event(logger, event, zonedid, stats, memory, audit, report) {
             loggers.remove(&zonedid);
         }
     }
@@ -270,6 +1053,10 @@ fn queue_zone_events(events: Vec<CfwEvent>, vmobjs: &Vmobjs, loggers: &mut Logge
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::SystemClock;
+    use crate::config::{self, Config, DiskConfig, UnknownZoneConfig};
+    use crate::queue::Overflow;
+    use crate::source::SourceStats;
     use crossbeam::sync::ShardedLock;
     use std::time::Duration;
 
@@ -294,7 +1081,7 @@ mod tests {
     #[test]
     fn parse_events_test() {
         let num_events = 10;
-        let (tx, rx) = crossbeam::channel::unbounded();
+        let (tx, rx) = queue::bounded(num_events, Overflow::DropNewest);
         let event = testutils::generate_event();
 
         let mut bytes = vec![];
@@ -302,7 +1089,22 @@ mod tests {
             .take(num_events)
             .for_each(|b| bytes.extend_from_slice(b));
 
-        let done = parse_events(&bytes, &tx);
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let memory = MemoryTracker::new(None);
+        let disk = DiskMonitor::new(DiskConfig::default());
+        let audit = LossAudit::new(false);
+        let mut report = DropReport::new("fanout");
+        let inflight = InFlight::new();
+        let done = parse_events(
+            &bytes,
+            &tx,
+            &stats,
+            &memory,
+            &disk,
+            &audit,
+            &inflight,
+            &mut report,
+        );
 
         // Parse_events returns false because the channel is still open
         assert!(!done);
@@ -316,31 +1118,205 @@ mod tests {
         // drop the sender so we can easily iterate over all the events in the channel
         drop(tx);
         let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
-        for e in rx.iter() {
+        for mut e in rx.iter() {
+            if let CfwEvent::Traffic(event) = &mut e {
+                let received = event.received.take().expect("the event was stamped");
+                assert!(received.hrtime > 0);
+            }
             assert_eq!(cfwevent, e, "all events match the passed in events");
         }
     }
 
     #[test]
-    fn start_event_reader_test() {
-        let device = MockEventSource {};
-        let (events, _handle) = start_event_reader(device);
+    fn parse_events_into_full_queue_test() {
+        let num_events = 10;
+        let (tx, rx) = queue::bounded(4, Overflow::DropOldest);
+        let event = testutils::generate_event();
+
+        let mut bytes = vec![];
+        std::iter::repeat(event.as_bytes())
+            .take(num_events)
+            .for_each(|b| bytes.extend_from_slice(b));
+
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let memory = MemoryTracker::new(None);
+        let disk = DiskMonitor::new(DiskConfig::default());
+        let audit = LossAudit::new(false);
+        let mut report = DropReport::new("fanout");
+        assert!(!parse_events(
+            &bytes,
+            &tx,
+            &stats,
+            &memory,
+            &disk,
+            &audit,
+            &InFlight::new(),
+            &mut report
+        ));
+
+        assert_eq!(rx.len(), 4, "the queue was filled");
+        let counters = stats::zone_counters(&stats, event.zonedid);
+        assert_eq!(counters.totals().queue_full, 6, "the rest were dropped");
+        assert_eq!(counters.take().dropped.queue_full, 6);
+    }
+
+    #[test]
+    fn parse_events_under_memory_pressure_test() {
+        let num_events = 10;
+        let (tx, rx) = queue::bounded(num_events, Overflow::DropNewest);
+        let event = testutils::generate_event();
+
+        let mut bytes = vec![];
+        std::iter::repeat(event.as_bytes())
+            .take(num_events)
+            .for_each(|b| bytes.extend_from_slice(b));
+
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let memory = MemoryTracker::new(Some(1));
+        memory.buffer_allocated(1);
+        let disk = DiskMonitor::new(DiskConfig::default());
+        let audit = LossAudit::new(false);
+        let mut report = DropReport::new("fanout");
+        let inflight = InFlight::new();
+        let done = parse_events(
+            &bytes,
+            &tx,
+            &stats,
+            &memory,
+            &disk,
+            &audit,
+            &inflight,
+            &mut report,
+        );
+
+        assert!(!done);
+        assert!(rx.is_empty(), "no events were queued while paused");
+        assert_eq!(
+            stats::zone_counters(&stats, event.zonedid)
+                .take()
+                .dropped
+                .memory_pressure,
+            num_events as u64,
+            "every event was counted as dropped"
+        );
+    }
+
+    #[test]
+    fn start_event_readers_test() {
+        start_event_readers_with(ReadConfig::default());
+        start_event_readers_with(ReadConfig {
+            buffer_bytes: Some(1),
+            double_buffer: true,
+        });
+    }
+
+    fn start_event_readers_with(reads: ReadConfig) {
+        let devices = vec![
+            ("mock1".to_owned(), MockEventSource {}),
+            ("mock2".to_owned(), MockEventSource {}),
+        ];
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let memory = Arc::new(MemoryTracker::new(None));
+        let disk = Arc::new(DiskMonitor::new(DiskConfig::default()));
+        let audit = Arc::new(LossAudit::new(false));
+        let queues = QueueConfig::default();
+        let readers = Arc::new(ReaderState::new(devices.len()));
+        let (events, _injector, handles) = start_event_readers(
+            devices,
+            queues,
+            reads,
+            stats,
+            memory,
+            disk,
+            audit,
+            Arc::new(InFlight::new()),
+            Arc::clone(&readers),
+        );
+        assert_eq!(handles.len(), 2, "every source gets a reader");
         let event = events.recv_timeout(Duration::from_secs(1));
         assert!(
             event.is_ok(),
             "at least one event has made it through the returned rx channel"
         );
+        assert_eq!(readers.running(), 2);
+        readers.stop();
+        for handle in handles {
+            handle.join().expect("reader stopped");
+        }
+        assert_eq!(readers.running(), 0);
+    }
+
+    #[test]
+    fn source_drops_test() {
+        struct DroppingSource {
+            dropped: u64,
+        }
+
+        impl EventSource for DroppingSource {
+            fn read_events(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+                unreachable!()
+            }
+            fn event_sizing(&mut self) -> std::io::Result<(usize, usize)> {
+                unreachable!()
+            }
+            fn stats(&mut self) -> std::io::Result<SourceStats> {
+                Ok(SourceStats {
+                    received: 0,
+                    dropped: self.dropped,
+                })
+            }
+        }
+
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let counters = stats::zone_counters(&stats, 1);
+        let mut source = DroppingSource { dropped: 3 };
+        let mut drops = SourceDrops::new("dropping".to_owned(), &mut source);
+        source.dropped = 10;
+        drops.check(&mut source, &stats);
+        assert_eq!(counters.take_source_lost(), 0, "checked too soon");
+
+        drops.last_check -= SOURCE_STATS_INTERVAL;
+        drops.check(&mut source, &stats);
+        assert_eq!(
+            counters.take_source_lost(),
+            7,
+            "events dropped before we started aren't counted"
+        );
+        drops.last_check -= SOURCE_STATS_INTERVAL;
+        drops.check(&mut source, &stats);
+        assert_eq!(counters.take_source_lost(), 0);
     }
 
     #[test]
     fn queue_zone_events_test() {
         let mut loggers: Loggers = Arc::new(Mutex::new(HashMap::new()));
-        let vmobjs: Vmobjs = Arc::new(ShardedLock::new(HashMap::new()));
+        let vmobjs: Vmobjs = Vmobjs::default();
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let config = config::shared(Config::default());
+        let memory = Arc::new(MemoryTracker::new(None));
+        let audit = Arc::new(LossAudit::new(false));
+        let live = Arc::new(LiveHub::new());
+        let clock = SystemClock::shared();
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
 
         // Test that we don't create a logger for a zone we don't know about
         let event = testutils::generate_event();
         let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
-        queue_zone_events(vec![cfwevent], &vmobjs, &mut loggers);
+        queue_zone_events(
+            vec![cfwevent],
+            &vmobjs,
+            &rules,
+            &stats,
+            &config,
+            &memory,
+            &audit,
+            &live,
+            &clock,
+            &None,
+            &mut loggers,
+            &mut Holding::new(UnknownZoneConfig::default()),
+            &mut DropReport::new("logger"),
+        );
 
         let logs = loggers.lock().unwrap();
         assert!(logs.is_empty(), "there were no loggers created");
@@ -351,13 +1327,25 @@ mod tests {
         let event = testutils::generate_event_for_zone(&zone1);
         let customer_uuid = zone1.owner_uuid.clone();
 
-        let mut vms = vmobjs.write().unwrap();
-        vms.insert(zone1.zonedid, zone1);
-        drop(vms);
+        vmobjs.update(|vms| vms.insert(zone1));
 
         // Test that we create a logger for a zone found in vmobjs
         let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
-        queue_zone_events(vec![cfwevent], &vmobjs, &mut loggers);
+        queue_zone_events(
+            vec![cfwevent],
+            &vmobjs,
+            &rules,
+            &stats,
+            &config,
+            &memory,
+            &audit,
+            &live,
+            &clock,
+            &None,
+            &mut loggers,
+            &mut Holding::new(UnknownZoneConfig::default()),
+            &mut DropReport::new("logger"),
+        );
         let mut logs = loggers.lock().unwrap();
         assert_eq!(logs.len(), 1, "there is exactly one logger created");
         for (zonedid, logger) in logs.drain() {
@@ -376,30 +1364,102 @@ mod tests {
         );
     }
 
+    #[test]
+    fn rate_limited_events_are_suppressed_test() {
+        let mut loggers: Loggers = Arc::new(Mutex::new(HashMap::new()));
+        let vmobjs: Vmobjs = Vmobjs::default();
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let config = config::shared(Config::from_toml("[rate_limit]\nper_sec = 2\n").unwrap());
+        let memory = Arc::new(MemoryTracker::new(None));
+        let audit = Arc::new(LossAudit::new(false));
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+
+        let zone1 = testutils::create_zone();
+        let event = testutils::generate_event_for_zone(&zone1);
+        let customer_uuid = zone1.owner_uuid.clone();
+        vmobjs.update(|vms| vms.insert(zone1));
+
+        let events = (0..5)
+            .map(|_| parser::cfwevent_parse(event.as_bytes()).unwrap().1)
+            .collect();
+        queue_zone_events(
+            events,
+            &vmobjs,
+            &rules,
+            &stats,
+            &config,
+            &memory,
+            &audit,
+            &Arc::new(LiveHub::new()),
+            &SystemClock::shared(),
+            &None,
+            &mut loggers,
+            &mut Holding::new(UnknownZoneConfig::default()),
+            &mut DropReport::new("logger"),
+        );
+        let counters = stats::zone_counters(&stats, event.zonedid);
+        assert_eq!(
+            counters.peek().dropped.rate_limited,
+            3,
+            "only the burst was queued"
+        );
+        let suppressed = counters.take_suppressed();
+        assert_eq!(suppressed.len(), 1);
+        assert_eq!(suppressed[0].reason, DropReason::RateLimited);
+        assert_eq!(suppressed[0].events, 3);
+
+        for (_, logger) in loggers.lock().unwrap().drain() {
+            logger.shutdown().expect("failed to shutdown logger");
+        }
+        std::fs::remove_dir_all(format!("{}/{}", crate::logger::LOG_DIR, customer_uuid))
+            .expect("successfully cleaned up test files");
+    }
+
     #[test]
     fn start_event_fanout_test() {
-        let vmobjs: Vmobjs = Arc::new(ShardedLock::new(HashMap::new()));
+        let vmobjs: Vmobjs = Vmobjs::default();
 
         let zone1 = testutils::create_zone();
         let event = testutils::generate_event_for_zone(&zone1);
         let customer_uuid = zone1.owner_uuid.clone();
 
-        let mut vms = vmobjs.write().unwrap();
-        vms.insert(zone1.zonedid, zone1);
-        drop(vms);
+        vmobjs.update(|vms| vms.insert(zone1));
 
         let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
 
-        let (tx, rx) = crossbeam::channel::unbounded();
+        let (tx, rx) = queue::bounded(16, Overflow::Block);
         let (stx, srx) = crossbeam::channel::unbounded();
-        let (loggers, handle) = start_event_fanout(rx, srx, Arc::clone(&vmobjs));
+        let (_dtx, drx) = crossbeam::channel::unbounded();
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let config = config::shared(Config::default());
+        let memory = Arc::new(MemoryTracker::new(None));
+        let audit = Arc::new(LossAudit::new(false));
+        let live = Arc::new(LiveHub::new());
+        let clock = SystemClock::shared();
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        let (loggers, handle) = start_event_fanout(
+            rx,
+            srx,
+            drx,
+            Arc::clone(&vmobjs),
+            rules,
+            stats,
+            config,
+            memory,
+            audit,
+            Arc::new(InFlight::new()),
+            live,
+            clock,
+            None,
+            Arc::new(Heartbeat::new()),
+        );
 
         let logs = loggers.lock().unwrap();
         assert!(logs.is_empty(), "no loggers exist yet");
         drop(logs);
 
         assert!(
-            tx.send(cfwevent).is_ok(),
+            tx.send(cfwevent, |_| ()).is_ok(),
             "successfully sent CfwEvent to fanout thread"
         );
 
@@ -408,7 +1468,7 @@ mod tests {
         thread::sleep(Duration::from_millis(500));
         let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
         assert!(
-            tx.send(cfwevent).is_err(),
+            tx.send(cfwevent, |_| ()).is_err(),
             "thread should no longer be accepting CfwEvents"
         );
         // XXX not sure how to deal with this potentially hanging forever other than setting a
@@ -424,4 +1484,65 @@ mod tests {
             "successfully cleaned up test files"
         );
     }
+
+    #[test]
+    fn deleted_zone_is_retired_test() {
+        let vmobjs: Vmobjs = Vmobjs::default();
+        let zone1 = testutils::create_zone();
+        let event = testutils::generate_event_for_zone(&zone1);
+        let customer_uuid = zone1.owner_uuid.clone();
+        let zonedid = zone1.zonedid;
+        let vm_uuid = zone1.uuid.clone();
+        vmobjs.update(|vms| vms.insert(zone1));
+
+        let (tx, rx) = queue::bounded(16, Overflow::Block);
+        let (stx, srx) = crossbeam::channel::unbounded();
+        let (dtx, drx) = crossbeam::channel::unbounded();
+        let (loggers, handle) = start_event_fanout(
+            rx,
+            srx,
+            drx,
+            Arc::clone(&vmobjs),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(Mutex::new(HashMap::new())),
+            config::shared(Config::default()),
+            Arc::new(MemoryTracker::new(None)),
+            Arc::new(LossAudit::new(false)),
+            Arc::new(InFlight::new()),
+            Arc::new(LiveHub::new()),
+            SystemClock::shared(),
+            None,
+            Arc::new(Heartbeat::new()),
+        );
+
+        let cfwevent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        tx.send(cfwevent, |_| ()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(loggers.lock().unwrap().len(), 1, "a logger was started");
+
+        dtx.send(ZoneChange::Deleted(zonedid, vm_uuid.clone()))
+            .unwrap();
+        thread::sleep(Duration::from_millis(500));
+        assert!(loggers.lock().unwrap().is_empty(), "the logger was retired");
+        assert!(
+            vmobjs.load().get(&zonedid).is_none(),
+            "the zone was forgotten once its log was closed"
+        );
+
+        let log: std::path::PathBuf = [
+            crate::logger::LOG_DIR,
+            &customer_uuid,
+            &vm_uuid,
+            "current.log",
+        ]
+        .iter()
+        .collect();
+        let log = std::fs::read_to_string(log).unwrap();
+        assert_eq!(log.lines().count(), 3, "start, the event, and stop");
+        assert!(log.lines().last().unwrap().contains("\"action\":\"stop\""));
+
+        stx.send(()).unwrap();
+        handle.join().unwrap();
+        std::fs::remove_dir_all(format!("{}/{}", crate::logger::LOG_DIR, customer_uuid)).unwrap();
+    }
 }
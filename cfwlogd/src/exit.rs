@@ -0,0 +1,488 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Every way cfwlogd can intentionally exit goes through `exit`, which writes a machine readable
+//! summary of why we stopped to `cfwlogd-exit.json` in the log directory and exits with a code
+//! that's meaningful to SMF. The summary includes the most recent error messages that were logged
+//! so operators can see what led up to the exit without digging through the SMF log. Along with
+//! the reason, which decides the exit code, it names the `Subsystem` that failed so that fleet
+//! tooling can tell a bad config from a failing device or vminfod without parsing messages. The
+//! failures that were once panics, such as a zone's log file failing to write, exit through
+//! `fault`, and so does any panic once `set_panic_hook` was called.
+//!
+//! The daemon's own log is set up here as well. Its level comes from "RUST_LOG" unless the config
+//! sets "log_level", which is applied again on every reload so verbosity can be raised on a CN
+//! without restarting. With "log_format" set to "json" every line is a json object that also
+//! names the thread it came from, which for a zone's `Logger` is the zone's vm uuid.
+
+use chrono::{DateTime, Utc};
+use env_logger::filter::{self, Filter};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError};
+
+/// As defined in smf_method(5): method completed successfully.
+pub const SMF_EXIT_OK: i32 = 0;
+
+/// As defined in smf_method(5): method successfully but purposefully leaves no processes remaining
+/// in the contract; it should be treated as if it had a transient service model.
+pub const SMF_EXIT_NODAEMON: i32 = 94;
+
+/// As defined in smf_method(5): an error that may go away if the method is retried.
+pub const SMF_EXIT_ERR_OTHER: i32 = 1;
+
+/// As defined in smf_method(5): an unrecoverable error.
+pub const SMF_EXIT_ERR_FATAL: i32 = 95;
+
+/// As defined in smf_method(5): an unrecoverable configuration error.
+pub const SMF_EXIT_ERR_CONFIG: i32 = 96;
+
+/// Location of the exit summary before and after we have chrooted into the log directory
+const SUMMARY_FILE: &str = "/var/log/firewall/cfwlogd-exit.json";
+const CHROOTED_SUMMARY_FILE: &str = "/cfwlogd-exit.json";
+
+/// Number of error messages kept around for the exit summary
+const MAX_RECENT_ERRORS: usize = 10;
+
+static CHROOTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether we exit with the smf_method(5) codes, see `set_smf_exit_codes`
+static SMF_EXIT_CODES: AtomicBool = AtomicBool::new(true);
+
+/// The configured "log_level" as a `LevelFilter`, or `NO_LEVEL` to follow "RUST_LOG"
+static LOG_LEVEL: AtomicUsize = AtomicUsize::new(NO_LEVEL);
+const NO_LEVEL: usize = usize::MAX;
+
+/// Whether the daemon's log lines are written as json, see `LogFormat`
+static LOG_JSON: AtomicBool = AtomicBool::new(false);
+
+/// How the daemon's own log lines are written
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+lazy_static! {
+    static ref RECENT_ERRORS: Mutex<VecDeque<String>> =
+        Mutex::new(VecDeque::with_capacity(MAX_RECENT_ERRORS));
+}
+
+/// Why cfwlogd exited
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExitReason {
+    /// We were told to shutdown via a signal
+    Shutdown,
+    /// We were told to shutdown but it didn't finish in time, or we were told again
+    ShutdownIncomplete,
+    /// The event device doesn't exist on this platform
+    DeviceUnsupported,
+    /// The configuration file is invalid
+    Config,
+    /// The event device couldn't be opened or queried, which may work on a restart
+    Device,
+    /// vminfod was unavailable, which may work on a restart
+    Vminfod,
+    /// Setting up the process (privileges, daemonizing, chroot) failed
+    Setup,
+    /// Something failed while we were running that we can't carry on without, which may work on
+    /// a restart
+    Fault,
+}
+
+/// The part of cfwlogd an exit came from
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    Shutdown,
+    Config,
+    /// The event devices and the threads reading them
+    Device,
+    /// The vminfod client and the zones it keeps up to date
+    Vminfod,
+    /// The fanout of events to zones
+    Events,
+    /// A zone's logger, its log file and its sinks
+    Logger,
+    /// The process itself and its helper threads
+    Setup,
+    /// A panic that none of the above is known for
+    Internal,
+}
+
+impl ExitReason {
+    /// The exit code that corresponds to this reason
+    pub fn code(self) -> i32 {
+        self.code_for(SMF_EXIT_CODES.load(Ordering::SeqCst))
+    }
+
+    /// The SMF exit code that corresponds to this reason, or when not running under SMF 0 for
+    /// success and 1 for everything else
+    fn code_for(self, smf: bool) -> i32 {
+        let code = self.smf_code();
+        match code {
+            _ if smf => code,
+            SMF_EXIT_OK | SMF_EXIT_NODAEMON => 0,
+            _ => 1,
+        }
+    }
+
+    fn smf_code(self) -> i32 {
+        match self {
+            ExitReason::Shutdown => SMF_EXIT_OK,
+            ExitReason::DeviceUnsupported => SMF_EXIT_NODAEMON,
+            ExitReason::Config => SMF_EXIT_ERR_CONFIG,
+            ExitReason::Device | ExitReason::Vminfod | ExitReason::Fault => SMF_EXIT_ERR_OTHER,
+            ExitReason::ShutdownIncomplete | ExitReason::Setup => SMF_EXIT_ERR_FATAL,
+        }
+    }
+
+    /// The subsystem an exit for this reason comes from, unless it's a fault which names its own
+    fn subsystem(self) -> Subsystem {
+        match self {
+            ExitReason::Shutdown | ExitReason::ShutdownIncomplete => Subsystem::Shutdown,
+            ExitReason::DeviceUnsupported | ExitReason::Device => Subsystem::Device,
+            ExitReason::Config => Subsystem::Config,
+            ExitReason::Vminfod => Subsystem::Vminfod,
+            ExitReason::Setup => Subsystem::Setup,
+            ExitReason::Fault => Subsystem::Internal,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ExitSummary<'a> {
+    reason: ExitReason,
+    subsystem: Subsystem,
+    code: i32,
+    message: &'a str,
+    pid: u32,
+    timestamp: DateTime<Utc>,
+    /// Events that were still queued and never made it to disk
+    unflushed_events: usize,
+    recent_errors: Vec<String>,
+}
+
+/// The configured "log_level", if any
+fn log_level() -> Option<LevelFilter> {
+    match LOG_LEVEL.load(Ordering::SeqCst) {
+        NO_LEVEL => None,
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    }
+}
+
+/// A daemon log line when "log_format" is "json"
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: DateTime<Utc>,
+    level: String,
+    target: &'a str,
+    thread: Option<&'a str>,
+    message: String,
+}
+
+/// A `Log` implementation that remembers the most recent error messages before passing every
+/// record on to the wrapped logger. Records are filtered here rather than by the wrapped logger,
+/// which lets everything through, so that the level can change at runtime.
+struct RecordingLogger<L> {
+    inner: L,
+    /// The filter from "RUST_LOG"
+    filter: Filter,
+}
+
+impl<L: Log> RecordingLogger<L> {
+    fn matches(&self, record: &Record) -> bool {
+        match log_level() {
+            Some(level) => record.level() <= level,
+            None => self.filter.matches(record),
+        }
+    }
+}
+
+impl<L: Log> Log for RecordingLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match log_level() {
+            Some(level) => metadata.level() <= level,
+            None => self.filter.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.matches(record) {
+            return;
+        }
+        if record.level() == Level::Error {
+            let mut errors = RECENT_ERRORS.lock().unwrap();
+            if errors.len() == MAX_RECENT_ERRORS {
+                errors.pop_front();
+            }
+            errors.push_back(record.args().to_string());
+        }
+        if !LOG_JSON.load(Ordering::SeqCst) {
+            return self.inner.log(record);
+        }
+        let thread = std::thread::current();
+        let line = JsonLine {
+            timestamp: Utc::now(),
+            level: record.level().to_string(),
+            target: record.target(),
+            thread: thread.name(),
+            message: record.args().to_string(),
+        };
+        if let Ok(mut json) = serde_json::to_vec(&line) {
+            json.push(b'\n');
+            let _ = std::io::stderr().write_all(&json);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// The filter "RUST_LOG" describes, in the same way `pretty_env_logger::init` reads it
+fn env_filter() -> Filter {
+    let mut builder = filter::Builder::new();
+    if let Ok(filters) = std::env::var("RUST_LOG") {
+        builder.parse(&filters);
+    }
+    builder.build()
+}
+
+/// Setup the daemon's logger, honoring "RUST_LOG" in the same way `pretty_env_logger::init` does.
+pub fn init_logging() {
+    let logger = pretty_env_logger::formatted_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let filter = env_filter();
+    let max_level = filter.filter();
+    log::set_boxed_logger(Box::new(RecordingLogger {
+        inner: logger,
+        filter,
+    }))
+    .expect("logger was already initialized");
+    log::set_max_level(max_level);
+}
+
+/// Apply the config's "log_level" and "log_format", which take over from "RUST_LOG" until the
+/// level is unset again
+pub fn set_logging(level: Option<LevelFilter>, format: LogFormat) {
+    LOG_JSON.store(format == LogFormat::Json, Ordering::SeqCst);
+    let previous = log_level();
+    if level == previous {
+        return;
+    }
+    let max_level = match level {
+        Some(level) => {
+            LOG_LEVEL.store(level as usize, Ordering::SeqCst);
+            level
+        }
+        None => {
+            LOG_LEVEL.store(NO_LEVEL, Ordering::SeqCst);
+            env_filter().filter()
+        }
+    };
+    log::set_max_level(max_level);
+    info!(
+        "logging at {}",
+        level.map_or_else(|| "RUST_LOG's level".to_owned(), |l| l.to_string())
+    );
+}
+
+/// Use plain 0 and 1 exit codes when a service manager other than SMF is running us, since the
+/// smf_method(5) codes would look like arbitrary failures to them
+pub fn set_smf_exit_codes(smf: bool) {
+    SMF_EXIT_CODES.store(smf, Ordering::SeqCst);
+}
+
+/// Let the exit summary know that the log directory is now "/"
+pub fn set_chrooted() {
+    CHROOTED.store(true, Ordering::SeqCst);
+}
+
+fn write_summary(summary: &ExitSummary) -> std::io::Result<()> {
+    let path = if CHROOTED.load(Ordering::SeqCst) {
+        CHROOTED_SUMMARY_FILE
+    } else {
+        SUMMARY_FILE
+    };
+    let mut json = serde_json::to_vec_pretty(summary)?;
+    json.push(b'\n');
+    std::fs::write(path, json)
+}
+
+/// Write out the exit summary and exit the process with the appropriate SMF exit code.
+pub fn exit(reason: ExitReason, message: &str, unflushed_events: usize) -> ! {
+    exit_from(reason, reason.subsystem(), message, unflushed_events)
+}
+
+fn exit_from(
+    reason: ExitReason,
+    subsystem: Subsystem,
+    message: &str,
+    unflushed_events: usize,
+) -> ! {
+    let code = reason.code();
+    // A thread that panicked while logging an error poisoned the lock, but what it holds is fine
+    let recent_errors = RECENT_ERRORS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .cloned()
+        .collect();
+    if code == SMF_EXIT_OK || code == SMF_EXIT_NODAEMON {
+        info!("exiting ({:?}): {}", reason, message);
+    } else {
+        error!("exiting ({:?} in {:?}): {}", reason, subsystem, message);
+    }
+
+    let summary = ExitSummary {
+        reason,
+        subsystem,
+        code,
+        message,
+        pid: std::process::id(),
+        timestamp: Utc::now(),
+        unflushed_events,
+        recent_errors,
+    };
+    if let Err(e) = write_summary(&summary) {
+        warn!("failed to write exit summary: {}", e);
+    }
+    std::process::exit(code);
+}
+
+/// Exit due to an error that happened before any events could have been queued
+pub fn fatal(reason: ExitReason, message: &str) -> ! {
+    exit(reason, message, 0)
+}
+
+/// Exit due to a failure in `subsystem` that we can't carry on without. The events still queued
+/// elsewhere aren't known from here, so the summary counts none.
+pub fn fault(subsystem: Subsystem, message: &str) -> ! {
+    exit_from(ExitReason::Fault, subsystem, message, 0)
+}
+
+/// The subsystem a thread belongs to, going by its name. A zone's logger thread is named after
+/// the zone's uuid.
+fn thread_subsystem(name: Option<&str>) -> Subsystem {
+    let name = match name {
+        Some(name) => name,
+        None => return Subsystem::Internal,
+    };
+    match name {
+        "EventReader" | "EventParser" => Subsystem::Device,
+        "EventFanout" => Subsystem::Events,
+        "vminfod_client" | "vminfod_event_processor" | "vmobjs_cache" => Subsystem::Vminfod,
+        _ if name.starts_with("enricher") => Subsystem::Events,
+        _ if name.starts_with("logger_worker_") || name.ends_with("_sink") => Subsystem::Logger,
+        _ if name.parse::<uuid::Uuid>().is_ok() => Subsystem::Logger,
+        _ => Subsystem::Internal,
+    }
+}
+
+/// Turn any later panic into a fault of the panicking thread's subsystem, after the usual
+/// message has been printed. Until now a panic only ended its own thread, which could leave us
+/// running without, say, the fanout thread and nothing but the SMF log telling why.
+pub fn set_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        let thread = std::thread::current();
+        let message = match info.payload().downcast_ref::<&str>() {
+            Some(message) => (*message).to_owned(),
+            None => match info.payload().downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "panic".to_owned(),
+            },
+        };
+        let message = match info.location() {
+            Some(location) => format!("{} at {}", message, location),
+            None => message,
+        };
+        fault(
+            thread_subsystem(thread.name()),
+            &format!(
+                "thread {} panicked: {}",
+                thread.name().unwrap_or("unnamed"),
+                message
+            ),
+        );
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(ExitReason::Shutdown.code(), SMF_EXIT_OK);
+        assert_eq!(ExitReason::DeviceUnsupported.code(), SMF_EXIT_NODAEMON);
+        assert_eq!(ExitReason::Config.code(), SMF_EXIT_ERR_CONFIG);
+        assert_eq!(ExitReason::ShutdownIncomplete.code(), SMF_EXIT_ERR_FATAL);
+        assert_eq!(ExitReason::Device.code(), SMF_EXIT_ERR_OTHER);
+        assert_eq!(ExitReason::Vminfod.code(), SMF_EXIT_ERR_OTHER);
+        assert_eq!(ExitReason::Setup.code(), SMF_EXIT_ERR_FATAL);
+        assert_eq!(ExitReason::Fault.code(), SMF_EXIT_ERR_OTHER);
+
+        assert_eq!(ExitReason::Shutdown.code_for(false), 0);
+        assert_eq!(ExitReason::DeviceUnsupported.code_for(false), 0);
+        assert_eq!(ExitReason::Config.code_for(false), 1);
+        assert_eq!(ExitReason::Setup.code_for(false), 1);
+    }
+
+    #[test]
+    fn log_level_follows_config() {
+        set_logging(Some(LevelFilter::Debug), LogFormat::Text);
+        assert_eq!(log_level(), Some(LevelFilter::Debug));
+        set_logging(None, LogFormat::Text);
+        assert_eq!(log_level(), None, "back to RUST_LOG");
+    }
+
+    #[test]
+    fn summary_serialization() {
+        let summary = ExitSummary {
+            reason: ExitReason::DeviceUnsupported,
+            subsystem: ExitReason::DeviceUnsupported.subsystem(),
+            code: ExitReason::DeviceUnsupported.code(),
+            message: "no device",
+            pid: 1,
+            timestamp: Utc::now(),
+            unflushed_events: 0,
+            recent_errors: vec!["oops".to_owned()],
+        };
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["reason"], "device_unsupported");
+        assert_eq!(json["subsystem"], "device");
+        assert_eq!(json["code"], SMF_EXIT_NODAEMON);
+        assert_eq!(json["recent_errors"][0], "oops");
+
+        assert_eq!(thread_subsystem(Some("logger_worker_3")), Subsystem::Logger);
+        assert_eq!(
+            thread_subsystem(Some("2d1ae2b3-a1d0-4a16-9e8e-c5fe4a4f0e2b")),
+            Subsystem::Logger
+        );
+        assert_eq!(thread_subsystem(Some("EventFanout")), Subsystem::Events);
+        assert_eq!(thread_subsystem(None), Subsystem::Internal);
+    }
+}
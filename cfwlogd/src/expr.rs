@@ -0,0 +1,611 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A small expression language for matching records, used wherever the configuration needs to
+//! pick out some events, such as which records are routed to a sink or which events count towards
+//! an alert. Expressions are compiled when the configuration is loaded, so a bad expression is a
+//! configuration error rather than something discovered per event. For example:
+//!
+//! ```text
+//! action == block && dport in (22, 3389) && src != 10.0.0.0/8
+//! ```
+//!
+//! The config's "drop_filter" and "zone_drop_filters" keep the events they match from being logged
+//! at all, and its "sink_filters" route records to the sinks whose expression they match.
+//!
+//! Comparisons are joined with `&&`, `||` and `!`, and grouped with parentheses. `&&` binds
+//! tighter than `||`. The fields are:
+//!
+//! | field              | values                            | operators                 |
+//! |--------------------|-----------------------------------|---------------------------|
+//! | `action`           | `block`, `begin`, `end`           | `==` `!=` `in` `not in`   |
+//! | `proto`            | `tcp`, `udp`, `icmp`, `icmp6`, `ah`, `esp` | `==` `!=` `in` `not in` |
+//! | `dir`, `direction` | `in`, `out`                       | `==` `!=` `in` `not in`   |
+//! | `src`, `dst`       | an address or a CIDR network      | `==` `!=` `in` `not in`   |
+//! | `sport`, `dport`   | a port                            | all of the above and `<` `<=` `>` `>=` |
+//! | `port`             | matches either port               | same as `sport`           |
+//! | `vm`, `alias`      | a word or a "quoted string"       | `==` `!=` `in` `not in`   |
+//! | `rule`             | a rule uuid                       | `==` `!=` `in` `not in`   |
+//!
+//! `!=` and `not in` are the negation of `==` and `in`, so `port != 22` matches records where
+//! neither port is 22. IPv4 networks also match the IPv4-mapped addresses events are logged with.
+//! Records for unknown events never match a comparison.
+
+use crate::sink::Record;
+use cfwevent::parser::{CfwEvType, CfwEvent, Direction, Protocol, TrafficEvent};
+use serde::de::{self, Deserialize, Deserializer};
+use std::fmt;
+use std::iter::Peekable;
+use std::net::{IpAddr, Ipv6Addr};
+use std::str::Chars;
+use uuid::Uuid;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Field {
+    Action,
+    Proto,
+    Dir,
+    Src,
+    Dst,
+    Sport,
+    Dport,
+    Port,
+    Vm,
+    Alias,
+    Rule,
+}
+
+impl Field {
+    fn parse(s: &str) -> Result<Field, String> {
+        match s {
+            "action" => Ok(Field::Action),
+            "proto" => Ok(Field::Proto),
+            "dir" | "direction" => Ok(Field::Dir),
+            "src" => Ok(Field::Src),
+            "dst" => Ok(Field::Dst),
+            "sport" => Ok(Field::Sport),
+            "dport" => Ok(Field::Dport),
+            "port" => Ok(Field::Port),
+            "vm" => Ok(Field::Vm),
+            "alias" => Ok(Field::Alias),
+            "rule" => Ok(Field::Rule),
+            _ => Err(format!("unknown field \"{}\"", s)),
+        }
+    }
+
+    fn is_port(self) -> bool {
+        self == Field::Sport || self == Field::Dport || self == Field::Port
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+    NotIn,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Action(CfwEvType),
+    Proto(Protocol),
+    Dir(Direction),
+    /// An IPv6 network, IPv4 networks are stored as their IPv4-mapped equivalent
+    Net(Ipv6Addr, u8),
+    Port(u16),
+    Str(String),
+    Rule(Uuid),
+}
+
+impl Value {
+    /// Parse a value for the given field out of its token
+    fn parse(field: Field, s: &str) -> Result<Value, String> {
+        let invalid = |what: &str| format!("invalid {} \"{}\"", what, s);
+        match field {
+            Field::Action => s.parse().map(Value::Action),
+            Field::Proto => match s.to_ascii_lowercase().as_str() {
+                "tcp" => Ok(Value::Proto(Protocol::TCP)),
+                "udp" => Ok(Value::Proto(Protocol::UDP)),
+                "icmp" => Ok(Value::Proto(Protocol::ICMP)),
+                "icmp6" | "icmpv6" => Ok(Value::Proto(Protocol::ICMPV6)),
+                "ah" => Ok(Value::Proto(Protocol::AH)),
+                "esp" => Ok(Value::Proto(Protocol::ESP)),
+                _ => Err(invalid("protocol")),
+            },
+            Field::Dir => match s {
+                "in" => Ok(Value::Dir(Direction::In)),
+                "out" => Ok(Value::Dir(Direction::Out)),
+                _ => Err(invalid("direction")),
+            },
+            Field::Src | Field::Dst => {
+                let mut parts = s.splitn(2, '/');
+                let addr: IpAddr = parts
+                    .next()
+                    .unwrap_or("")
+                    .parse()
+                    .map_err(|_| invalid("address"))?;
+                let (addr, offset, max) = match addr {
+                    IpAddr::V4(v4) => (v4.to_ipv6_mapped(), 96, 32),
+                    IpAddr::V6(v6) => (v6, 0, 128),
+                };
+                let prefix = match parts.next() {
+                    Some(prefix) => match prefix.parse::<u8>() {
+                        Ok(prefix) if prefix <= max => prefix,
+                        _ => return Err(invalid("network")),
+                    },
+                    None => max,
+                };
+                Ok(Value::Net(addr, offset + prefix))
+            }
+            Field::Sport | Field::Dport | Field::Port => {
+                s.parse().map(Value::Port).map_err(|_| invalid("port"))
+            }
+            Field::Vm | Field::Alias => Ok(Value::Str(s.to_owned())),
+            Field::Rule => s.parse().map(Value::Rule).map_err(|_| invalid("rule uuid")),
+        }
+    }
+}
+
+/// Returns true if `addr` falls within the network
+fn in_net(addr: &Ipv6Addr, net: &Ipv6Addr, prefix: u8) -> bool {
+    let mask = match prefix {
+        0 => 0,
+        prefix => u128::max_value() << (128 - u32::from(prefix)),
+    };
+    u128::from(*addr) & mask == u128::from(*net) & mask
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Not(Box<Node>),
+    Compare {
+        field: Field,
+        op: Op,
+        /// A single value, or the list for `in` and `not in`
+        values: Vec<Value>,
+    },
+}
+
+/// Compare a port against a value with an ordering operator
+fn compare_port(port: u16, op: Op, value: &Value) -> bool {
+    let value = match value {
+        Value::Port(value) => *value,
+        _ => return false,
+    };
+    match op {
+        Op::Lt => port < value,
+        Op::Le => port <= value,
+        Op::Gt => port > value,
+        Op::Ge => port >= value,
+        _ => port == value,
+    }
+}
+
+/// Returns true if the record's field equals (or for addresses falls within) the value
+fn field_is(field: Field, event: &TrafficEvent, record: &Record<'_>, value: &Value) -> bool {
+    match (field, value) {
+        (Field::Action, Value::Action(v)) => event.event == *v,
+        (Field::Proto, Value::Proto(v)) => event.protocol == *v,
+        (Field::Dir, Value::Dir(v)) => event.direction == *v,
+        (Field::Src, Value::Net(net, prefix)) => in_net(&event.source_ip, net, *prefix),
+        (Field::Dst, Value::Net(net, prefix)) => in_net(&event.destination_ip, net, *prefix),
+        (Field::Sport, Value::Port(v)) => event.source_port == *v,
+        (Field::Dport, Value::Port(v)) => event.destination_port == *v,
+        (Field::Port, Value::Port(v)) => event.source_port == *v || event.destination_port == *v,
+        (Field::Vm, Value::Str(v)) => record.vm == v,
+        (Field::Alias, Value::Str(v)) => record.alias == v,
+        (Field::Rule, Value::Rule(v)) => event.rule_uuid == *v,
+        _ => false,
+    }
+}
+
+impl Node {
+    fn matches(&self, record: &Record<'_>) -> bool {
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => return false,
+        };
+        self.matches_event(event, record)
+    }
+
+    fn matches_event(&self, event: &TrafficEvent, record: &Record<'_>) -> bool {
+        match self {
+            Node::And(a, b) => a.matches_event(event, record) && b.matches_event(event, record),
+            Node::Or(a, b) => a.matches_event(event, record) || b.matches_event(event, record),
+            Node::Not(a) => !a.matches_event(event, record),
+            Node::Compare { field, op, values } => {
+                let any = || values.iter().any(|v| field_is(*field, event, record, v));
+                match op {
+                    Op::Eq | Op::In => any(),
+                    Op::Ne | Op::NotIn => !any(),
+                    Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                        let ports: &[u16] = match field {
+                            Field::Sport => &[event.source_port],
+                            Field::Dport => &[event.destination_port],
+                            _ => &[event.source_port, event.destination_port],
+                        };
+                        ports
+                            .iter()
+                            .any(|port| compare_port(*port, *op, &values[0]))
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    /// A double quoted string, which is never treated as a keyword
+    Quoted(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+    Comma,
+}
+
+/// Consume the current character, and the one after it if it's `next`, returning whether the
+/// two character token was found
+fn pair(chars: &mut Peekable<Chars<'_>>, next: char) -> bool {
+    chars.next();
+    if chars.peek() == Some(&next) {
+        chars.next();
+        true
+    } else {
+        false
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' | ')' | ',' => {
+                chars.next();
+                match c {
+                    '(' => Token::Open,
+                    ')' => Token::Close,
+                    _ => Token::Comma,
+                }
+            }
+            '&' if pair(&mut chars, '&') => Token::And,
+            '|' if pair(&mut chars, '|') => Token::Or,
+            '=' if pair(&mut chars, '=') => Token::Op(Op::Eq),
+            '!' if pair(&mut chars, '=') => Token::Op(Op::Ne),
+            '!' => Token::Not,
+            '<' if pair(&mut chars, '=') => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if pair(&mut chars, '=') => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' => {
+                chars.next();
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => quoted.push(c),
+                        None => return Err("unterminated string".to_owned()),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            c if c.is_alphanumeric() || ".:/-_".contains(c) => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_alphanumeric() || ".:/-_".contains(c)) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+            c => return Err(format!("unexpected character '{}'", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser over the tokens of an expression
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token, what: &str) -> Result<(), String> {
+        match self.next() {
+            Some(ref token) if *token == expected => Ok(()),
+            _ => Err(format!("expected {}", what)),
+        }
+    }
+
+    fn or(&mut self) -> Result<Node, String> {
+        let mut node = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            node = Node::Or(Box::new(node), Box::new(self.and()?));
+        }
+        Ok(node)
+    }
+
+    fn and(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            node = Node::And(Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Node::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::Open) => {
+                self.next();
+                let node = self.or()?;
+                self.expect(Token::Close, "')'")?;
+                Ok(node)
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn value(&mut self, field: Field) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Word(s)) | Some(Token::Quoted(s)) => Value::parse(field, &s),
+            _ => Err("expected a value".to_owned()),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Node, String> {
+        let field = match self.next() {
+            Some(Token::Word(s)) => Field::parse(&s)?,
+            _ => return Err("expected a field".to_owned()),
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(Token::Word(ref w)) if w == "in" => Op::In,
+            Some(Token::Word(ref w)) if w == "not" => match self.next() {
+                Some(Token::Word(ref w)) if w == "in" => Op::NotIn,
+                _ => return Err("expected \"in\" after \"not\"".to_owned()),
+            },
+            _ => return Err("expected an operator".to_owned()),
+        };
+        let ordering = [Op::Lt, Op::Le, Op::Gt, Op::Ge].contains(&op);
+        if ordering && !field.is_port() {
+            return Err("only ports can be compared with < <= > >=".to_owned());
+        }
+        let values = if op == Op::In || op == Op::NotIn {
+            self.expect(Token::Open, "'(' after \"in\"")?;
+            let mut values = vec![self.value(field)?];
+            while self.peek() == Some(&Token::Comma) {
+                self.next();
+                values.push(self.value(field)?);
+            }
+            self.expect(Token::Close, "')'")?;
+            values
+        } else {
+            vec![self.value(field)?]
+        };
+        Ok(Node::Compare { field, op, values })
+    }
+}
+
+/// A compiled filter expression
+#[derive(Clone)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+impl Expr {
+    pub fn parse(s: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+        };
+        let root = parser.or()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!("unexpected {:?}", parser.tokens[parser.pos]));
+        }
+        Ok(Expr {
+            source: s.to_owned(),
+            root,
+        })
+    }
+
+    /// The expression matching records where every field has one of its values, such as a query
+    /// string's `field=value` pairs. A field given more than once may have any of its values.
+    pub fn fields(pairs: &[(&str, &str)]) -> Result<Expr, String> {
+        let mut compares: Vec<(Field, Vec<Value>)> = vec![];
+        for (name, value) in pairs {
+            let field = Field::parse(name)?;
+            let value = Value::parse(field, value)?;
+            match compares.iter_mut().find(|(f, _)| *f == field) {
+                Some((_, values)) => values.push(value),
+                None => compares.push((field, vec![value])),
+            }
+        }
+        // Only used to tell expressions apart, see `PartialEq`
+        let source = pairs
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("&");
+        let root = compares
+            .into_iter()
+            .map(|(field, values)| Node::Compare {
+                field,
+                op: Op::In,
+                values,
+            })
+            .fold(None, |root, node| match root {
+                Some(root) => Some(Node::And(Box::new(root), Box::new(node))),
+                None => Some(node),
+            })
+            .ok_or_else(|| "no fields given".to_owned())?;
+        Ok(Expr { source, root })
+    }
+
+    /// Returns true if the record matches the expression
+    pub fn matches(&self, record: &Record<'_>) -> bool {
+        self.root.matches(record)
+    }
+}
+
+impl fmt::Debug for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Expr({:?})", self.source)
+    }
+}
+
+/// Expressions are equal when they were compiled from the same text
+impl PartialEq for Expr {
+    fn eq(&self, other: &Expr) -> bool {
+        self.source == other.source
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Expr, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Expr::parse(&s)
+            .map_err(|e| de::Error::custom(format!("invalid expression \"{}\": {}", s, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    fn record<'a>(vm: &'a str, source_ip: &str, destination_port: u16) -> Record<'a> {
+        let event = testutils::generate_event();
+        let mut event = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        if let CfwEvent::Traffic(ref mut event) = event {
+            event.event = CfwEvType::Block;
+            event.protocol = Protocol::TCP;
+            event.direction = Direction::In;
+            event.source_ip = match source_ip.parse().unwrap() {
+                IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+                IpAddr::V6(v6) => v6,
+            };
+            event.source_port = 40000;
+            event.destination_port = destination_port;
+        }
+        Record::new(event, vm, "web")
+    }
+
+    fn matches(expr: &str, record: &Record) -> bool {
+        Expr::parse(expr)
+            .unwrap_or_else(|e| panic!("{}: {}", expr, e))
+            .matches(record)
+    }
+
+    #[test]
+    fn expressions_match_records() {
+        let ssh = record("vm1", "192.0.2.10", 22);
+        let example = "action == block && dport in (22, 3389) && src != 10.0.0.0/8";
+        assert!(matches(example, &ssh));
+        assert!(
+            !matches(example, &record("vm1", "10.1.2.3", 22)),
+            "internal source"
+        );
+        assert!(
+            !matches(example, &record("vm1", "192.0.2.10", 80)),
+            "other port"
+        );
+
+        assert!(matches("proto == TCP && dir == in", &ssh));
+        assert!(
+            matches("port == 40000 && port == 22", &ssh),
+            "port matches either"
+        );
+        assert!(matches("port != 80", &ssh));
+        assert!(matches("dport < 1024 && sport >= 1024", &ssh));
+        assert!(matches("vm == vm1 && alias == \"web\"", &ssh));
+        assert!(matches("vm not in (vm2, vm3)", &ssh));
+        assert!(matches("!(action == begin || action == end)", &ssh));
+        assert!(matches(
+            "action == begin || action == block && dport == 22",
+            &ssh
+        ));
+        assert!(!matches(
+            "(action == begin || action == block) && dport == 23",
+            &ssh
+        ));
+        assert!(
+            matches("src == ::ffff:192.0.2.0/120", &ssh),
+            "mapped v6 network"
+        );
+        assert!(matches("src == 192.0.2.10", &ssh));
+        assert!(matches("src == 0.0.0.0/0", &ssh));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expr in &[
+            "",
+            "action",
+            "action == allow",
+            "alias > web",
+            "dport == http",
+            "src == 10.0.0.0/33",
+            "dport in 22",
+            "dport in (22,",
+            "(action == block",
+            "action == block &&",
+            "action == block dport == 22",
+            "vm == \"vm1",
+            "bogus == 1",
+            "action = block",
+        ] {
+            assert!(Expr::parse(expr).is_err(), "{} is invalid", expr);
+        }
+    }
+
+    #[test]
+    fn expressions_deserialize() {
+        #[derive(serde::Deserialize)]
+        struct Wrapper {
+            filter: Expr,
+        }
+        let w: Wrapper = toml::from_str("filter = \"dport == 22\"").unwrap();
+        assert_eq!(w.filter, Expr::parse("dport == 22").unwrap());
+        assert!(toml::from_str::<Wrapper>("filter = \"dport ==\"").is_err());
+    }
+}
@@ -0,0 +1,290 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Every zone's records fan out to all of its sinks: its log file, and then whichever of live,
+//! cmon, syslog, Elasticsearch, CloudWatch, webhook, Parquet and plugins are configured, each
+//! with its own filter and encoder. By default the sinks are written one after the other on the
+//! zone's `Logger` thread, so a sink that's slow to write to holds up the rest of them, the zone's
+//! log file included.
+//!
+//! A sink with an entry in "sink_queues" instead runs on a thread of its own, behind a queue of at
+//! most that many records. While the queue is full the zone's records are left out of that sink
+//! rather than holding up the others, and counted in its `SinkStats`. The sink's own errors are
+//! logged on its thread when it starts failing and once it recovers, rather than for every batch.
+//! The zone's log file is always written on the `Logger` thread.
+
+use crate::config::Config;
+use crate::sink::{OwnedRecord, Record, Sink, SinkStats};
+use cfwevent::parser::Serialization;
+use crossbeam::channel::{self, Receiver, Sender};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// What the `Logger` asks of a queued sink
+enum Op {
+    Write(Vec<OwnedRecord>),
+    Flush,
+    Rotate,
+    Check,
+    Reload(Arc<Config>),
+}
+
+/// What's shared between a queued sink and its thread
+struct Shared {
+    /// Records that haven't been written yet
+    queued: AtomicUsize,
+    stats: Mutex<SinkStats>,
+}
+
+/// A sink running on its own thread, see the "fanout" module
+pub struct QueuedSink {
+    name: String,
+    vm: String,
+    capacity: usize,
+    /// Each request goes along with how the `Logger` serializes, for the sink's thread to do the
+    /// same
+    tx: Option<Sender<(Op, Serialization)>>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+    shared: Arc<Shared>,
+    dropped: u64,
+    /// Set while records are being dropped, so only the start of a stretch of drops is logged
+    dropping: bool,
+}
+
+impl QueuedSink {
+    /// Move `sink` onto a thread of its own, with a queue of up to `capacity` records
+    pub fn spawn(vm: &str, sink: Box<dyn Sink>, capacity: usize) -> io::Result<QueuedSink> {
+        let name = sink.name().to_owned();
+        let shared = Arc::new(Shared {
+            queued: AtomicUsize::new(0),
+            stats: Mutex::new(SinkStats::default()),
+        });
+        let (tx, rx) = channel::unbounded();
+        let thread = {
+            let vm = vm.to_owned();
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name(format!("{}_sink", name))
+                .spawn(move || run(&vm, sink, &rx, &shared))?
+        };
+        Ok(QueuedSink {
+            name,
+            vm: vm.to_owned(),
+            capacity,
+            tx: Some(tx),
+            thread: Some(thread),
+            shared,
+            dropped: 0,
+            dropping: false,
+        })
+    }
+
+    fn send(&self, op: Op) -> io::Result<()> {
+        let tx = self.tx.as_ref().expect("the sink isn't closed");
+        tx.send((op, Serialization::current())).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                format!("the {} sink's thread has exited", self.name),
+            )
+        })
+    }
+}
+
+/// Carry out the `Logger`'s requests until it closes the sink
+fn run(
+    vm: &str,
+    mut sink: Box<dyn Sink>,
+    rx: &Receiver<(Op, Serialization)>,
+    shared: &Shared,
+) -> io::Result<()> {
+    let mut failures = 0u64;
+    let mut serialization = Serialization::default();
+    for (op, sent) in rx.iter() {
+        serialization = sent;
+        let _serialization = serialization.apply();
+        let (what, result) = match op {
+            Op::Write(records) => {
+                let batch: Vec<Record<'_>> = records.iter().map(OwnedRecord::record).collect();
+                let result = sink.write_batch(&batch);
+                shared.queued.fetch_sub(records.len(), Ordering::Relaxed);
+                ("write to", result)
+            }
+            Op::Flush => ("flush", sink.flush()),
+            Op::Rotate => ("rotate", sink.rotate()),
+            Op::Check => ("check", sink.check()),
+            Op::Reload(config) => {
+                sink.reload(&config);
+                ("reload", Ok(()))
+            }
+        };
+        match result {
+            Err(e) if failures == 0 => {
+                error!("failed to {} {}'s {} sink: {}", what, vm, sink.name(), e);
+                failures += 1;
+            }
+            Err(_) => failures += 1,
+            Ok(()) if failures > 0 => {
+                info!(
+                    "{}'s {} sink recovered after {} failures",
+                    vm,
+                    sink.name(),
+                    failures
+                );
+                failures = 0;
+            }
+            Ok(()) => (),
+        }
+        let stats = sink.stats();
+        let mut shared_stats = shared.stats.lock().unwrap();
+        shared_stats.records = stats.records;
+        shared_stats.bytes = stats.bytes;
+    }
+    // Whatever the sink still has buffered is written the same way as the rest
+    let _serialization = serialization.apply();
+    sink.close()
+}
+
+impl Sink for QueuedSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        let queued = self.shared.queued.load(Ordering::Relaxed);
+        if queued + records.len() > self.capacity {
+            if !self.dropping {
+                warn!(
+                    "{}'s {} sink isn't keeping up, dropping records until its queue drains",
+                    &self.vm, &self.name
+                );
+                self.dropping = true;
+            }
+            self.dropped += records.len() as u64;
+            return Ok(());
+        }
+        self.dropping = false;
+        self.shared
+            .queued
+            .fetch_add(records.len(), Ordering::Relaxed);
+        self.send(Op::Write(records.iter().map(OwnedRecord::new).collect()))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send(Op::Flush)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.send(Op::Rotate)
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        self.send(Op::Check)
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        let _ = self.send(Op::Reload(Arc::clone(config)));
+    }
+
+    /// Wait for the queue to drain and the sink to be closed
+    fn close(&mut self) -> io::Result<()> {
+        // Hanging up ends the thread once it has gone through the queue
+        self.tx = None;
+        match self.thread.take().map(thread::JoinHandle::join) {
+            Some(Ok(result)) => result,
+            Some(Err(_)) => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("the {} sink's thread panicked", self.name),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn stats(&self) -> SinkStats {
+        SinkStats {
+            dropped: self.dropped,
+            ..*self.shared.stats.lock().unwrap()
+        }
+    }
+}
+
+/// Move every sink with an entry in "sink_queues" onto a thread of its own, apart from the zone's
+/// log file which comes first
+pub fn queue_sinks(vm: &str, sinks: Vec<Box<dyn Sink>>, config: &Config) -> Vec<Box<dyn Sink>> {
+    let mut queued: Vec<Box<dyn Sink>> = Vec::with_capacity(sinks.len());
+    for (i, sink) in sinks.into_iter().enumerate() {
+        let capacity = match config.sink_queues.get(sink.name()) {
+            Some(capacity) if i > 0 => *capacity,
+            _ => {
+                queued.push(sink);
+                continue;
+            }
+        };
+        let name = sink.name().to_owned();
+        match QueuedSink::spawn(vm, sink, capacity) {
+            Ok(sink) => queued.push(Box::new(sink)),
+            Err(e) => error!("failed to start {}'s {} sink: {}", vm, name, e),
+        }
+    }
+    queued
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+    use std::time::Duration;
+
+    /// A sink that takes a while to write each batch
+    struct SlowSink {
+        stats: SinkStats,
+    }
+
+    impl Sink for SlowSink {
+        fn name(&self) -> &str {
+            "slow"
+        }
+        fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+            thread::sleep(Duration::from_millis(50));
+            self.stats.records += records.len() as u64;
+            Ok(())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        fn rotate(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        fn close(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+        fn stats(&self) -> SinkStats {
+            self.stats
+        }
+    }
+
+    #[test]
+    fn slow_sinks_drop_records_instead_of_blocking() {
+        let sink = Box::new(SlowSink {
+            stats: SinkStats::default(),
+        });
+        let mut queued = QueuedSink::spawn("vm1", sink, 4).unwrap();
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web",
+        );
+        let batch = vec![record.clone(), record.clone(), record];
+        queued.write_batch(&batch).unwrap();
+        queued.write_batch(&batch).unwrap();
+        queued.close().unwrap();
+        let stats = queued.stats();
+        assert_eq!(stats.records, 3, "the first batch was written");
+        assert_eq!(stats.dropped, 3, "the second didn't fit in the queue");
+    }
+}
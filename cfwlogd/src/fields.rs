@@ -0,0 +1,276 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Trimming the fields a sink serializes for each record, so a sink shipping records over a slow
+//! link can send a slim record while the zone's log file keeps every field. Fields are named with
+//! jq style paths such as ".source_ip", and a selection lists the only fields to `include`, the
+//! fields to `exclude`, or both:
+//!
+//! ```toml
+//! [sink_fields.live]
+//! include = [".vm", ".event", ".source_ip", ".source_port", ".destination_ip",
+//!            ".destination_port", ".protocol"]
+//! ```
+//!
+//! The selected fields can then be moved to other paths with `rename`, such as the ECS names a
+//! pipeline expects, and fields with fixed values added with `add`:
+//!
+//! ```toml
+//! [sink_fields.syslog.rename]
+//! ".source_ip" = ".source.ip"
+//! ".destination_port" = ".destination.port"
+//! [sink_fields.syslog.add]
+//! ".observer.hostname" = "cn1"
+//! ```
+//!
+//! Paths that don't exist in a record are ignored. An empty selection serializes the whole record
+//! without any extra work.
+
+use crate::migrate;
+use crate::sink::Record;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// A jq style path to a field, such as ".source_ip" or ".a.b"
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct FieldPath(Vec<String>);
+
+impl FieldPath {
+    fn parse(s: &str) -> Result<FieldPath, String> {
+        if !s.starts_with('.') || s.len() == 1 {
+            return Err(format!("invalid field path \"{}\", expected \".field\"", s));
+        }
+        let keys: Vec<String> = s[1..].split('.').map(str::to_owned).collect();
+        if keys.iter().any(String::is_empty) {
+            return Err(format!("invalid field path \"{}\"", s));
+        }
+        Ok(FieldPath(keys))
+    }
+}
+
+impl<'de> Deserialize<'de> for FieldPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<FieldPath, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        FieldPath::parse(&s).map_err(de::Error::custom)
+    }
+}
+
+/// Which fields of a record a sink serializes
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fields {
+    /// Only these fields, or every field if empty
+    pub include: Vec<FieldPath>,
+    /// Every field but these
+    pub exclude: Vec<FieldPath>,
+    /// Fields moved to another path, in the order of their current paths
+    pub rename: BTreeMap<FieldPath, FieldPath>,
+    /// Fields added with a fixed value, replacing any field already at the path
+    pub add: BTreeMap<FieldPath, Value>,
+}
+
+/// Copy the value at `path` from `from` into `to`, creating objects along the way
+fn copy_path(from: &Map<String, Value>, to: &mut Map<String, Value>, path: &[String]) {
+    let value = match from.get(&path[0]) {
+        Some(value) => value,
+        None => return,
+    };
+    if path.len() == 1 {
+        to.insert(path[0].clone(), value.clone());
+        return;
+    }
+    if let Value::Object(from) = value {
+        let to = to
+            .entry(path[0].clone())
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(to) = to {
+            copy_path(from, to, &path[1..]);
+        }
+    }
+}
+
+/// Remove the value at `path` from `map`, returning it
+fn remove_path(map: &mut Map<String, Value>, path: &[String]) -> Option<Value> {
+    if path.len() == 1 {
+        map.remove(&path[0])
+    } else if let Some(Value::Object(map)) = map.get_mut(&path[0]) {
+        remove_path(map, &path[1..])
+    } else {
+        None
+    }
+}
+
+/// Insert `value` at `path` in `map`, creating objects along the way and replacing anything in the
+/// way that isn't one
+fn insert_path(map: &mut Map<String, Value>, path: &[String], value: Value) {
+    if path.len() == 1 {
+        map.insert(path[0].clone(), value);
+        return;
+    }
+    let next = map
+        .entry(path[0].clone())
+        .or_insert_with(|| Value::Object(Map::new()));
+    if !next.is_object() {
+        *next = Value::Object(Map::new());
+    }
+    if let Value::Object(next) = next {
+        insert_path(next, &path[1..], value);
+    }
+}
+
+impl Fields {
+    /// Returns true if the selection would include every field
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.rename.is_empty()
+            && self.add.is_empty()
+    }
+
+    /// Apply the selection to a serialized record
+    fn select(&self, record: Map<String, Value>) -> Map<String, Value> {
+        let mut selected = if self.include.is_empty() {
+            record
+        } else {
+            let mut selected = Map::new();
+            for path in &self.include {
+                copy_path(&record, &mut selected, &path.0);
+            }
+            selected
+        };
+        for path in &self.exclude {
+            remove_path(&mut selected, &path.0);
+        }
+        // Taken out first so that renames can swap fields or move them into each other's place
+        let renamed: Vec<(&FieldPath, Value)> = self
+            .rename
+            .iter()
+            .filter_map(|(from, to)| remove_path(&mut selected, &from.0).map(|value| (to, value)))
+            .collect();
+        for (to, value) in renamed {
+            insert_path(&mut selected, &to.0, value);
+        }
+        for (path, value) in &self.add {
+            insert_path(&mut selected, &path.0, value.clone());
+        }
+        selected
+    }
+
+    /// Serialize the selected fields of a record as json in the given schema version. Fields are
+    /// selected after the record is downgraded, so paths refer to that version's fields.
+    pub fn to_writer<W: Write>(
+        &self,
+        writer: W,
+        record: &Record<'_>,
+        version: u64,
+    ) -> serde_json::Result<()> {
+        if self.is_empty() {
+            return migrate::to_writer(writer, record, version);
+        }
+        match serde_json::to_value(record)? {
+            Value::Object(mut map) => {
+                migrate::downgrade_record(&mut map, version);
+                serde_json::to_writer(writer, &self.select(map))
+            }
+            value => serde_json::to_writer(writer, &value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::SCHEMA_VERSION;
+    use cfwevent::parser;
+
+    fn record() -> Record<'static> {
+        let event = testutils::generate_event();
+        Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web",
+        )
+    }
+
+    fn selected(fields: &str) -> Value {
+        let fields: Fields = toml::from_str(fields).unwrap();
+        let mut buf = vec![];
+        fields
+            .to_writer(&mut buf, &record(), SCHEMA_VERSION)
+            .unwrap();
+        serde_json::from_slice(&buf).unwrap()
+    }
+
+    #[test]
+    fn field_selection() {
+        let full = selected("");
+        assert!(full.get("source_ip").is_some() && full.get("alias").is_some());
+
+        let slim = selected("include = [\".vm\", \".source_port\", \".missing\"]");
+        let keys: Vec<&String> = slim.as_object().unwrap().keys().collect();
+        assert_eq!(
+            keys,
+            vec!["source_port", "vm"],
+            "only included fields are kept"
+        );
+
+        let trimmed = selected("exclude = [\".alias\", \".timestamp\"]");
+        assert!(trimmed.get("alias").is_none() && trimmed.get("timestamp").is_none());
+        assert!(trimmed.get("vm").is_some(), "other fields are kept");
+    }
+
+    #[test]
+    fn nested_paths() {
+        let mut record = Map::new();
+        record.insert("a".to_owned(), serde_json::json!({"b": 1, "c": 2}));
+        record.insert("d".to_owned(), Value::from(3));
+        let fields = Fields {
+            include: vec![FieldPath::parse(".a").unwrap()],
+            exclude: vec![FieldPath::parse(".a.c").unwrap()],
+            ..Fields::default()
+        };
+        assert_eq!(
+            Value::Object(fields.select(record)),
+            serde_json::json!({"a": {"b": 1}})
+        );
+    }
+
+    #[test]
+    fn renamed_and_added_fields() {
+        let ecs = selected(
+            "include = [\".source_ip\", \".source_port\", \".vm\"]\n\
+             [rename]\n\".source_ip\" = \".source.ip\"\n\".source_port\" = \".source.port\"\n\
+             \".missing\" = \".gone\"\n\
+             [add]\n\".observer.hostname\" = \"cn1\"\n\".vm\" = \"replaced\"\n",
+        );
+        assert_eq!(ecs["source"]["ip"], selected("")["source_ip"]);
+        assert!(ecs["source"]["port"].is_number());
+        assert!(ecs.get("source_ip").is_none(), "renamed fields are moved");
+        assert!(ecs.get("gone").is_none(), "missing fields aren't renamed");
+        assert_eq!(ecs["observer"]["hostname"], "cn1");
+        assert_eq!(ecs["vm"], "replaced", "added fields replace existing ones");
+
+        let mut swapped = Map::new();
+        swapped.insert("a".to_owned(), Value::from(1));
+        swapped.insert("b".to_owned(), Value::from(2));
+        let fields: Fields =
+            toml::from_str("[rename]\n\".a\" = \".b\"\n\".b\" = \".a\"\n").unwrap();
+        assert_eq!(
+            Value::Object(fields.select(swapped)),
+            serde_json::json!({"a": 2, "b": 1})
+        );
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for path in &["", ".", "source_ip", ".a..b", ".a."] {
+            assert!(FieldPath::parse(path).is_err(), "{:?} is invalid", path);
+        }
+    }
+}
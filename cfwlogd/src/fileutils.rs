@@ -4,9 +4,23 @@
 
 // Copyright 2019 Joyent, Inc.
 
+use libc::c_int;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::{Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Component, Path};
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Owners other than root and us whose files are written to, see `trust_owners`
+    static ref TRUSTED_UIDS: Mutex<Vec<libc::uid_t>> = Mutex::new(vec![]);
+}
 
 /// Represents the total length of the Reader and position of the character of interest.
 pub struct ReaderSeekInfo {
@@ -26,7 +40,8 @@ fn rev_locate_char(bytes: &[u8], c: u8) -> Option<usize> {
 /// not found. This function panics if the chunk size is 0.
 pub fn rseek_and_scan<R: Read + Seek>(r: &mut R, chunk: u64, c: u8) -> io::Result<ReaderSeekInfo> {
     assert!(chunk > 0, "chunk size must be greater than 0");
-    let mut buf = String::with_capacity(chunk as usize);
+    // A torn record can end partway through a utf-8 sequence, so the chunks are read as bytes
+    let mut buf = Vec::with_capacity(chunk as usize);
     // Track the overall seek offset
     let mut ptr = r.seek(SeekFrom::End(0))?;
     let file_len = ptr;
@@ -42,10 +57,10 @@ pub fn rseek_and_scan<R: Read + Seek>(r: &mut R, chunk: u64, c: u8) -> io::Resul
         // Avoid overlapping chunks when there is less than a chunk's worth of data left
         let max = if ptr < chunk { ptr } else { chunk };
         // Read the max number of bytes into the buffer.
-        r.take(max).read_to_string(&mut buf)?;
+        r.take(max).read_to_end(&mut buf)?;
 
         // Attempt to locate the char.
-        if let Some(found) = rev_locate_char(buf.as_bytes(), c) {
+        if let Some(found) = rev_locate_char(&buf, c) {
             return Ok(ReaderSeekInfo {
                 length: file_len,
                 index: Some(found as u64 + pos),
@@ -67,10 +82,410 @@ pub fn rseek_and_scan<R: Read + Seek>(r: &mut R, chunk: u64, c: u8) -> io::Resul
     })
 }
 
+/// Cut a json lines file back to the end of its last complete record, returning whatever was
+/// removed. A file that doesn't end in a newline was left partway through a record, by a crash or
+/// a write that failed, and anything after the last newline is that torn record.
+pub fn truncate_torn_record(file: &mut File) -> io::Result<Vec<u8>> {
+    let info = rseek_and_scan(file, 512, b'\n')?;
+    // Keep the newline itself
+    let keep = info.index.map_or(0, |i| i + 1);
+    if keep == info.length {
+        return Ok(vec![]);
+    }
+    let mut torn = vec![];
+    file.seek(SeekFrom::Start(keep))?;
+    file.take(info.length - keep).read_to_end(&mut torn)?;
+    file.set_len(keep)?;
+    Ok(torn)
+}
+
+fn to_cstring(s: &OsStr) -> io::Result<CString> {
+    CString::new(s.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contained nuls"))
+}
+
+/// openat(2) the given name relative to `dir` without ever following a symlink.
+fn openat_nofollow(
+    dir: &File,
+    name: &CString,
+    flags: c_int,
+    mode: libc::mode_t,
+) -> io::Result<File> {
+    let flags = flags | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+    match unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode as libc::c_uint) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Also accept files and directories owned by `uids`, which the config has us give zones' logs to
+pub fn trust_owners(uids: Vec<libc::uid_t>) {
+    *TRUSTED_UIDS.lock().unwrap() = uids;
+}
+
+/// Verify that the open file is owned by either root, our effective user or one of the owners we
+/// gave files to, so that a file or directory planted by someone else isn't written to.
+fn check_owner(file: &File) -> io::Result<()> {
+    let uid = file.metadata()?.uid();
+    let euid = unsafe { libc::geteuid() };
+    if uid != 0 && uid != euid && !TRUSTED_UIDS.lock().unwrap().contains(&uid) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("owned by unexpected uid {}", uid),
+        ));
+    }
+    Ok(())
+}
+
+/// Create every directory in `path` that doesn't exist yet and return the final directory opened.
+/// Unlike `std::fs::create_dir_all` the path is walked one component at a time with
+/// mkdirat(2)/openat(2) relative to the previous component, no symlinks are followed, and every
+/// directory along the way must be owned by root or us. This prevents a compromised path component
+/// from redirecting our writes elsewhere.
+pub fn create_dir_all_nofollow(path: &Path) -> io::Result<File> {
+    let mut dir = File::open(if path.is_absolute() { "/" } else { "." })?;
+    for component in path.components() {
+        let name = match component {
+            Component::RootDir | Component::CurDir => continue,
+            Component::Normal(name) => to_cstring(name)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "path must not contain \"..\" or prefix components",
+                ))
+            }
+        };
+        if unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o755) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::AlreadyExists {
+                return Err(e);
+            }
+        }
+        dir = openat_nofollow(&dir, &name, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+        check_owner(&dir)?;
+    }
+    Ok(dir)
+}
+
+/// Open the existing directory `path` relative to `base` one component at a time, never following
+/// a symlink and never creating anything. Unlike `create_dir_all_nofollow` the owners aren't
+/// checked, for directories that belong to someone else, such as everything under a zone's root.
+pub fn open_foreign_dir(base: &File, path: &Path) -> io::Result<File> {
+    let mut dir = base.try_clone()?;
+    for component in path.components() {
+        let name = match component {
+            Component::CurDir => continue,
+            Component::Normal(name) => to_cstring(name)?,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "path must be relative and not contain \"..\"",
+                ))
+            }
+        };
+        dir = openat_nofollow(&dir, &name, libc::O_RDONLY | libc::O_DIRECTORY, 0)?;
+    }
+    Ok(dir)
+}
+
+/// Open (creating if needed) the file `name` found in `dir` for appending without checking who
+/// owns it, for files that belong to someone else. The file is never opened through a symlink or
+/// blocked on as a FIFO, and it must be a regular file without any other links to it.
+pub fn open_foreign_append(dir: &File, name: &str) -> io::Result<File> {
+    let name = to_cstring(OsStr::new(name))?;
+    let file = openat_nofollow(
+        dir,
+        &name,
+        libc::O_WRONLY | libc::O_APPEND | libc::O_CREAT | libc::O_NONBLOCK,
+        0o644,
+    )?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.nlink() != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file with a single link",
+        ));
+    }
+    Ok(file)
+}
+
+/// Open (creating if needed) the file `name` found in `dir` for appending, and for reading back
+/// how it ends. The file must be a regular file owned by root or us, and is never opened through a
+/// symlink.
+pub fn open_append_nofollow(dir: &File, name: &str) -> io::Result<File> {
+    let name = to_cstring(OsStr::new(name))?;
+    let file = openat_nofollow(
+        dir,
+        &name,
+        libc::O_RDWR | libc::O_APPEND | libc::O_CREAT,
+        0o644,
+    )?;
+    check_owner(&file)?;
+    if !file.metadata()?.is_file() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a regular file",
+        ));
+    }
+    Ok(file)
+}
+
+/// Change the owner and mode of an open file or directory, leaving whatever isn't given as is
+pub fn set_permissions(
+    file: &File,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    mode: Option<libc::mode_t>,
+) -> io::Result<()> {
+    let fd = file.as_raw_fd();
+    if uid.is_some() || gid.is_some() {
+        // -1 leaves the id unchanged
+        let uid = uid.unwrap_or(libc::uid_t::MAX);
+        let gid = gid.unwrap_or(libc::gid_t::MAX);
+        if unsafe { libc::fchown(fd, uid, gid) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(mode) = mode {
+        if unsafe { libc::fchmod(fd, mode) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Open the file `name` found in `dir` for reading, never through a symlink.
+pub fn open_read_nofollow(dir: &File, name: &str) -> io::Result<File> {
+    let name = to_cstring(OsStr::new(name))?;
+    openat_nofollow(dir, &name, libc::O_RDONLY, 0)
+}
+
+/// Atomically replace the contents of the file `name` found in `dir` by writing them to a
+/// temporary file and renaming it over `name`, so readers never see a partially written file.
+/// Neither file is ever opened through a symlink.
+pub fn replace_file_nofollow(dir: &File, name: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp = to_cstring(OsStr::new(&format!(".{}.tmp", name)))?;
+    let name = to_cstring(OsStr::new(name))?;
+    let mut file = openat_nofollow(
+        dir,
+        &tmp,
+        libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC,
+        0o644,
+    )?;
+    check_owner(&file)?;
+    file.write_all(contents)?;
+    let fd = dir.as_raw_fd();
+    if unsafe { libc::renameat(fd, tmp.as_ptr(), fd, name.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Rename the file `from` found in `dir` to `to`, replacing anything already at `to`. Neither
+/// name is resolved through a symlink.
+pub fn rename_at(dir: &File, from: &str, to: &str) -> io::Result<()> {
+    let from = to_cstring(OsStr::new(from))?;
+    let to = to_cstring(OsStr::new(to))?;
+    let fd = dir.as_raw_fd();
+    if unsafe { libc::renameat(fd, from.as_ptr(), fd, to.as_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Remove the file `name` found in `dir`, if there is one
+pub fn remove_at(dir: &File, name: &str) -> io::Result<()> {
+    let name = to_cstring(OsStr::new(name))?;
+    if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) } == -1 {
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Rotate the file `name` found in `dir` out to `rotated`, and move the file `next` into its place.
+/// The caller has to have opened `next` and flushed everything it wrote to `name` beforehand, so
+/// nothing is written to `name` once it's renamed and there's never a moment where neither file is
+/// open for writing. If `next` can't be moved into place `name` is renamed back. The directory is
+/// synced before returning, so that a crash can't undo the renames.
+pub fn rotate_at(dir: &File, name: &str, rotated: &str, next: &str) -> io::Result<()> {
+    rename_at(dir, name, rotated)?;
+    if let Err(e) = rename_at(dir, next, name) {
+        let _ = rename_at(dir, rotated, name);
+        return Err(e);
+    }
+    dir.sync_all()
+}
+
+/// The names of the entries of `dir`, other than "." and "..". Only the directory's descriptor is
+/// used, so this works for directories opened before we chrooted.
+pub fn list_dir(dir: &File) -> io::Result<Vec<String>> {
+    // closedir(3C) closes the descriptor it was given, so it gets a duplicate of ours
+    let fd = unsafe { libc::fcntl(dir.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let dirp = unsafe { libc::fdopendir(fd) };
+    if dirp.is_null() {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    // The duplicate shares our offset, which the last listing left at the end
+    unsafe { libc::rewinddir(dirp) };
+    let mut names = vec![];
+    loop {
+        let entry = unsafe { libc::readdir(dirp) };
+        if entry.is_null() {
+            break;
+        }
+        let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+        match name.to_bytes() {
+            b"." | b".." => (),
+            name => names.push(String::from_utf8_lossy(name).into_owned()),
+        }
+    }
+    unsafe { libc::closedir(dirp) };
+    Ok(names)
+}
+
+/// Returns true if the open `file` is no longer the file found at `path`. This happens when
+/// something outside of cfwlogd has renamed or unlinked the file out from underneath us, in which
+/// case anything we continue to write to `file` will never show up at `path`.
+pub fn file_replaced(file: &File, path: &Path) -> io::Result<bool> {
+    let open = file.metadata()?;
+    if open.nlink() == 0 {
+        return Ok(true);
+    }
+    match std::fs::metadata(path) {
+        Ok(current) => Ok(current.dev() != open.dev() || current.ino() != open.ino()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// The number of file descriptors we have open, listed from `fd_dir`, our /proc/self/fd opened
+/// before we chrooted. Neither `fd_dir` itself nor the duplicate `list_dir` reads it through are
+/// counted.
+pub fn open_fds(fd_dir: &File) -> io::Result<usize> {
+    Ok(list_dir(fd_dir)?.len().saturating_sub(2))
+}
+
+/// Bind a Unix socket at `path` that only root can connect to, replacing a stale socket left
+/// behind by a previous run.
+pub fn bind_private_socket(path: &Path) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::fs::OpenOptions;
     use std::io::Cursor;
+    use std::path::PathBuf;
+
+    fn test_file(name: &str) -> (PathBuf, File) {
+        let dir: PathBuf = ["/var/tmp/cfwlogd-tests", "fileutils"].iter().collect();
+        std::fs::create_dir_all(&dir).expect("failed to create test dir");
+        let path = dir.join(name);
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .expect("failed to create test file");
+        (path, file)
+    }
+
+    #[test]
+    fn test_create_dir_all_nofollow() {
+        let base: PathBuf = ["/var/tmp/cfwlogd-tests", "nofollow", "dirs"]
+            .iter()
+            .collect();
+        let path = base.join("customer").join("vm");
+        let dir = create_dir_all_nofollow(&path).expect("failed to create directories");
+        assert!(path.is_dir(), "all directories were created");
+
+        let file = open_append_nofollow(&dir, "current.log").expect("failed to open file");
+        assert!(file.metadata().unwrap().is_file(), "file was created");
+        assert!(
+            path.join("current.log").is_file(),
+            "file is in the right dir"
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_symlinks_are_not_followed() {
+        let base: PathBuf = ["/var/tmp/cfwlogd-tests", "nofollow", "links"]
+            .iter()
+            .collect();
+        let target = base.join("target");
+        std::fs::create_dir_all(&target).unwrap();
+        std::os::unix::fs::symlink(&target, base.join("customer")).unwrap();
+        assert!(
+            create_dir_all_nofollow(&base.join("customer").join("vm")).is_err(),
+            "symlinked directory is rejected"
+        );
+        assert!(
+            !target.join("vm").exists(),
+            "nothing was created via the symlink"
+        );
+
+        let dir = create_dir_all_nofollow(&base).unwrap();
+        std::os::unix::fs::symlink(target.join("file"), base.join("current.log")).unwrap();
+        assert!(
+            open_append_nofollow(&dir, "current.log").is_err(),
+            "symlinked file is rejected"
+        );
+        assert!(
+            !target.join("file").exists(),
+            "nothing was created via the symlink"
+        );
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn test_file_not_replaced() {
+        let (path, file) = test_file("not-replaced.log");
+        assert!(!file_replaced(&file, &path).unwrap(), "file is unchanged");
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_file_renamed() {
+        let (path, file) = test_file("renamed.log");
+        let rotated = path.with_extension("rotated");
+        std::fs::rename(&path, &rotated).unwrap();
+        assert!(file_replaced(&file, &path).unwrap(), "file was renamed");
+
+        // A new file showing up in its place is still a different file
+        let (_, _new) = test_file("renamed.log");
+        assert!(file_replaced(&file, &path).unwrap(), "file was replaced");
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(rotated).unwrap();
+    }
+
+    #[test]
+    fn test_file_unlinked() {
+        let (path, file) = test_file("unlinked.log");
+        std::fs::remove_file(&path).unwrap();
+        assert!(file_replaced(&file, &path).unwrap(), "file was unlinked");
+    }
 
     #[test]
     fn test_beginning() {
@@ -152,4 +567,159 @@ mod test {
             r#"byte should be \n"#
         );
     }
+
+    #[test]
+    fn test_torn_utf8() {
+        // The record was cut off partway through "é"
+        let mut data = Cursor::new(b"{\"a\":1}\n{\"alias\":\"caf\xc3".to_vec());
+        let pos = rseek_and_scan(&mut data, 4, b'\n').unwrap().index;
+        assert_eq!(
+            Some(7),
+            pos,
+            "a chunk that isn't valid utf-8 is still scanned"
+        );
+    }
+
+    #[test]
+    fn test_truncate_torn_record() {
+        let (path, _) = test_file("torn.log");
+        std::fs::write(&path, "{\"a\":1}\n{\"a\":2}\n{\"a\":").unwrap();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let torn = truncate_torn_record(&mut file).expect("failed to truncate");
+        assert_eq!(torn, b"{\"a\":");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"a\":1}\n{\"a\":2}\n"
+        );
+        let torn = truncate_torn_record(&mut file).expect("failed to truncate");
+        assert!(torn.is_empty(), "a complete file is left alone");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_bind_private_socket() {
+        let (path, _file) = test_file("socket");
+        assert!(
+            bind_private_socket(&path).is_err(),
+            "regular files are not replaced"
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let first = bind_private_socket(&path).expect("failed to bind socket");
+        let mode = std::fs::metadata(&path).unwrap().mode();
+        assert_eq!(
+            mode & 0o777,
+            0o600,
+            "socket is only accessible by its owner"
+        );
+        drop(first);
+        bind_private_socket(&path).expect("stale socket is replaced");
+        // Left behind, the socket would fail the next run's `test_file` opening it
+        std::fs::remove_file(&path).expect("failed to cleanup socket");
+    }
+
+    #[test]
+    fn test_replace_file_nofollow() {
+        let path: PathBuf = ["/var/tmp/cfwlogd-tests", "replace"].iter().collect();
+        let dir = create_dir_all_nofollow(&path).expect("failed to create directories");
+        replace_file_nofollow(&dir, "file", b"first").expect("failed to write file");
+        replace_file_nofollow(&dir, "file", b"second").expect("failed to replace file");
+        assert_eq!(std::fs::read(path.join("file")).unwrap(), b"second");
+        assert!(
+            !path.join(".file.tmp").exists(),
+            "temporary file was renamed"
+        );
+        std::fs::remove_dir_all(path).expect("failed to cleanup test dir");
+    }
+
+    #[test]
+    fn test_rotate_at() {
+        use std::sync::{Arc, Mutex};
+        let path: PathBuf = ["/var/tmp/cfwlogd-tests", "rotate"].iter().collect();
+        let _ = std::fs::remove_dir_all(&path);
+        let dir = create_dir_all_nofollow(&path).expect("failed to create directories");
+        let current = Arc::new(Mutex::new(
+            open_append_nofollow(&dir, "current.log").unwrap(),
+        ));
+        // Lines keep being written while the file is rotated out from under the writer
+        let writer = {
+            let current = Arc::clone(&current);
+            std::thread::spawn(move || {
+                for i in 0..10_000 {
+                    writeln!(current.lock().unwrap(), "{}", i).unwrap();
+                }
+            })
+        };
+        for i in 0..50 {
+            let mut current = current.lock().unwrap();
+            let next = open_append_nofollow(&dir, ".current.log.next").unwrap();
+            rotate_at(
+                &dir,
+                "current.log",
+                &format!("{:02}.log", i),
+                ".current.log.next",
+            )
+            .expect("failed to rotate");
+            *current = next;
+        }
+        writer.join().unwrap();
+        let mut names = list_dir(&dir).unwrap();
+        names.sort();
+        assert_eq!(names.len(), 51, "{:?}", names);
+        assert_eq!(
+            names.last().unwrap(),
+            "current.log",
+            "the next file took its place"
+        );
+        let lines: Vec<u64> = names
+            .iter()
+            .flat_map(|name| {
+                let contents = std::fs::read_to_string(path.join(name)).unwrap();
+                assert!(contents.is_empty() || contents.ends_with('\n'));
+                contents
+                    .lines()
+                    .map(|line| line.parse().unwrap())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            (0..10_000).collect::<Vec<_>>(),
+            "no line was lost or repeated"
+        );
+
+        // A failed rotation leaves the file where it was
+        assert!(rotate_at(&dir, "current.log", "failed.log", "missing").is_err());
+        assert!(path.join("current.log").exists() && !path.join("failed.log").exists());
+        remove_at(&dir, "current.log").unwrap();
+        remove_at(&dir, "current.log").expect("a missing file is already removed");
+        std::fs::remove_dir_all(path).expect("failed to cleanup test dir");
+    }
+
+    #[test]
+    fn test_list_dir() {
+        let path: PathBuf = ["/var/tmp/cfwlogd-tests", "list"].iter().collect();
+        let dir = create_dir_all_nofollow(&path).expect("failed to create directories");
+        replace_file_nofollow(&dir, "b", b"").expect("failed to write file");
+        replace_file_nofollow(&dir, "a", b"").expect("failed to write file");
+        for _ in 0..2 {
+            let mut names = list_dir(&dir).expect("failed to list dir");
+            names.sort();
+            assert_eq!(names, vec!["a", "b"], "listing again starts over");
+        }
+        std::fs::remove_dir_all(path).expect("failed to cleanup test dir");
+    }
+
+    #[test]
+    fn test_open_fds() {
+        // Other tests open and close files concurrently, so all we can check is that the fd we're
+        // holding is counted.
+        let fd_dir = File::open("/proc/self/fd").unwrap();
+        let _file = File::open("/dev/null").unwrap();
+        assert!(open_fds(&fd_dir).expect("failed to count fds") >= 1);
+    }
 }
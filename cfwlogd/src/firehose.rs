@@ -0,0 +1,203 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A Unix socket that streams every record cfwlogd logs, for other agents on the CN (an IDS, an
+//! anomaly detector) that want the enriched stream without reading /dev/ipfev or the log files
+//! themselves. Each client simply connects and reads newline separated json, formatted the same
+//! way as the zone log files. Like every other `LiveHub` subscriber, a client that isn't keeping up
+//! has records dropped instead of slowing down logging.
+//!
+//! A client only interested in some of the records can send a `Subscription` as its first line
+//! right after connecting, such as `{"vms":["<vm uuid>"],"action":"block"}`. Clients that send
+//! nothing within `SUBSCRIBE_WAIT`, or shut down their end of the socket, are sent every record. An
+//! invalid subscription is answered with `{"ok":false,"error":"..."}` and the connection closed.
+
+use crate::fileutils;
+use crate::live::{Filter, LiveHub};
+use cfwevent::parser::CfwEvType;
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Records queued in the `LiveHub` for each client before they start getting dropped
+const SUBSCRIBER_CAPACITY: usize = 8192;
+
+/// How long a client has to send its subscription before it's sent every record
+const SUBSCRIBE_WAIT: Duration = Duration::from_millis(250);
+
+/// The records a client wants, see `live::Filter`
+#[derive(Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+struct Subscription {
+    vms: Vec<String>,
+    action: Option<CfwEvType>,
+    port: Option<u16>,
+}
+
+/// Wait for the client's subscription, if it sends one
+fn read_subscription(stream: &UnixStream) -> io::Result<Filter> {
+    stream.set_read_timeout(Some(SUBSCRIBE_WAIT))?;
+    let mut line = String::new();
+    match BufReader::new(stream).read_line(&mut line) {
+        Ok(_) => (),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) =>
+        {
+            return Ok(Filter::default())
+        }
+        Err(e) => return Err(e),
+    }
+    if line.trim().is_empty() {
+        return Ok(Filter::default());
+    }
+    let subscription: Subscription =
+        serde_json::from_str(&line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(Filter {
+        vms: subscription.vms,
+        action: subscription.action,
+        port: subscription.port,
+        expr: None,
+    })
+}
+
+/// Write records to a client until it goes away
+fn serve_client(mut stream: UnixStream, hub: &LiveHub) -> io::Result<()> {
+    let filter = match read_subscription(&stream) {
+        Ok(filter) => filter,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            let error = json!({"ok": false, "error": format!("invalid subscription: {}", e)});
+            writeln!(stream, "{}", error)?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+    let records = hub.subscribe(filter, SUBSCRIBER_CAPACITY);
+    let mut writer = BufWriter::new(stream);
+    for record in records.iter() {
+        writer.write_all(record.json.as_bytes())?;
+        writer.write_all(b"\n")?;
+        // Batch up writes while records are arriving faster than we can send them
+        if records.is_empty() {
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Bind the firehose socket and start streaming records published on `hub` to every client
+pub fn start_firehose(path: &Path, hub: Arc<LiveHub>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = fileutils::bind_private_socket(path)?;
+    info!("firehose listening on {}", path.display());
+    thread::Builder::new()
+        .name("firehose".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept firehose connection: {}", e);
+                        continue;
+                    }
+                };
+                let hub = Arc::clone(&hub);
+                let res = thread::Builder::new()
+                    .name("firehose_client".to_owned())
+                    .spawn(move || {
+                        if let Err(e) = serve_client(stream, &hub) {
+                            debug!("firehose client went away: {}", e);
+                        }
+                    });
+                if let Err(e) = res {
+                    error!("failed to spawn firehose client thread: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::{Encoder, Record};
+    use cfwevent::parser::{self, CfwEvent};
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn firehose_streams_records() {
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests/firehose");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("firehose.sock");
+        let hub = Arc::new(LiveHub::new());
+        let _handle = start_firehose(&path, Arc::clone(&hub)).expect("failed to start firehose");
+
+        let client = UnixStream::connect(&path).expect("failed to connect");
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let event = testutils::generate_event();
+        let event: CfwEvent = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        let record = Record::new(event, "vm1", "web");
+
+        // The client is subscribed by its own thread, so wait for it to show up before publishing
+        let start = Instant::now();
+        while hub.subscribers() == 0 {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "client never subscribed"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        hub.publish(&[record.clone()], &Encoder::default());
+
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["vm"], "vm1");
+        assert_eq!(json["alias"], "web");
+        assert!(line.ends_with('\n'), "records are newline separated");
+
+        // A client subscribed to one vm is only sent its records
+        let mut client = UnixStream::connect(&path).expect("failed to connect");
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client.write_all(b"{\"vms\":[\"vm2\"]}\n").unwrap();
+        while hub.subscribers() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "client never subscribed"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        let other = Record {
+            vm: "vm2",
+            ..record.clone()
+        };
+        hub.publish(&[record, other], &Encoder::default());
+        let mut line = String::new();
+        BufReader::new(&client).read_line(&mut line).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["vm"], "vm2");
+
+        let mut client = UnixStream::connect(&path).expect("failed to connect");
+        client.write_all(b"{\"zones\":[]}\n").unwrap();
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["ok"], false, "invalid subscriptions are refused");
+
+        std::fs::remove_dir_all(&dir).expect("failed to cleanup test dir");
+    }
+}
@@ -0,0 +1,140 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Coalescing a zone's traffic events into flows, so that a scanner hitting the same port over
+//! and over is logged as a handful of records rather than millions of nearly identical ones. For
+//! the zones in "aggregate.zones" the `ZoneLog` counts every traffic event towards the flow it
+//! belongs to, identified by its event type, rule, protocol, direction, addresses and destination
+//! port, instead of writing it out. At the end of every "aggregate.window_secs" each flow is
+//! logged as one record with the number of events it had and the timestamps of the first and
+//! last. Other sinks still receive every event.
+
+use cfwevent::parser::{self, CfwEvType, Direction, Protocol, TrafficEvent};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use uuid::Uuid;
+
+/// What the events of a flow have in common
+#[derive(Clone, Debug, Eq, Hash, PartialEq, Serialize)]
+pub struct FlowKey {
+    /// The type of the events, the record's "event" is "flow"
+    pub flow_event: CfwEvType,
+    pub rule: Uuid,
+    pub protocol: Protocol,
+    pub direction: Direction,
+    #[serde(serialize_with = "parser::serialize_ip")]
+    pub source_ip: Ipv6Addr,
+    #[serde(serialize_with = "parser::serialize_ip")]
+    pub destination_ip: Ipv6Addr,
+    pub destination_port: u16,
+}
+
+impl FlowKey {
+    fn new(event: &TrafficEvent) -> Self {
+        FlowKey {
+            flow_event: event.event.clone(),
+            rule: event.rule_uuid,
+            protocol: event.protocol.clone(),
+            direction: event.direction.clone(),
+            source_ip: event.source_ip,
+            destination_ip: event.destination_ip,
+            destination_port: event.destination_port,
+        }
+    }
+}
+
+/// A flow's events over a window
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Flow {
+    #[serde(flatten)]
+    pub key: FlowKey,
+    pub events: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub first_timestamp: DateTime<Utc>,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub last_timestamp: DateTime<Utc>,
+}
+
+/// A zone's flows over the current window
+pub struct Flows {
+    max_flows: usize,
+    flows: HashMap<FlowKey, Flow>,
+}
+
+impl Flows {
+    pub fn new(max_flows: usize) -> Self {
+        Flows {
+            max_flows,
+            flows: HashMap::new(),
+        }
+    }
+
+    /// Count the event towards its flow. Returns false when the window already has
+    /// "aggregate.max_flows" other flows, in which case the event should be written on its own.
+    pub fn absorb(&mut self, event: &TrafficEvent) -> bool {
+        let key = FlowKey::new(event);
+        if let Some(flow) = self.flows.get_mut(&key) {
+            flow.events += 1;
+            flow.first_timestamp = flow.first_timestamp.min(event.timestamp);
+            flow.last_timestamp = flow.last_timestamp.max(event.timestamp);
+            return true;
+        }
+        if self.flows.len() >= self.max_flows {
+            return false;
+        }
+        let flow = Flow {
+            key: key.clone(),
+            events: 1,
+            first_timestamp: event.timestamp,
+            last_timestamp: event.timestamp,
+        };
+        self.flows.insert(key, flow);
+        true
+    }
+
+    /// End the window, returning its flows in the order they started
+    pub fn take(&mut self) -> Vec<Flow> {
+        let mut flows: Vec<Flow> = self.flows.drain().map(|(_, flow)| flow).collect();
+        flows.sort_by_key(|flow| flow.first_timestamp);
+        flows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use testutils::{traffic_event, RULE};
+
+    #[test]
+    fn events_are_coalesced_into_flows() {
+        let mut flows = Flows::new(2);
+        let mut scan = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        let start = scan.timestamp;
+        for i in 0..3 {
+            scan.timestamp = start + Duration::seconds(i);
+            scan.source_port = 40000 + i as u16;
+            assert!(flows.absorb(&scan));
+        }
+        let mut telnet = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 23, RULE);
+        telnet.timestamp = start + Duration::seconds(1);
+        assert!(flows.absorb(&telnet));
+        let other = traffic_event(CfwEvType::Block, "::ffff:192.0.2.2", 22, RULE);
+        assert!(!flows.absorb(&other), "there's no room for a third flow");
+
+        let taken = flows.take();
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].events, 3, "source ports don't split flows");
+        assert_eq!(taken[0].key.destination_port, 22);
+        assert_eq!(taken[0].first_timestamp, start);
+        assert_eq!(taken[0].last_timestamp, start + Duration::seconds(2));
+        assert_eq!(taken[1].events, 1);
+        assert!(flows.take().is_empty(), "the next window starts empty");
+        assert!(flows.absorb(&other));
+    }
+}
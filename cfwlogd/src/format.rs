@@ -0,0 +1,540 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The record formats a sink can be given in the config's "sink_formats", for sinks feeding a
+//! SIEM that ingests its own format rather than json:
+//!
+//! ```toml
+//! [sink_formats]
+//! syslog = "cef"
+//! ```
+//!
+//! A sink without an entry gets json lines, shaped by its "sink_fields" or "sink_templates" entry.
+//! The other formats always carry the same fields, so they can't be combined with either.
+//!
+//! - "cef" is ArcSight's Common Event Format. The event type is the signature id, blocks have
+//!   severity 5 and the other events 3, and the extension has the addresses, ports, protocol and
+//!   direction under their standard keys along with the rule, vm and alias as custom strings.
+//!   CEF's src and dst keys only hold IPv4 addresses, so IPv4-mapped addresses are given as the
+//!   IPv4 address they map to whatever "normalize_ipv4_mapped" says, and other IPv6 addresses
+//!   are given under c6a2 and c6a3, the source and destination IPv6 address keys. ICMP events
+//!   have their type and code under cn1 and cn2 rather than ports. Its rt is epoch milliseconds
+//!   whatever "timestamp_format" says, since CEF has no RFC 3339 dates.
+//! - "leef" is QRadar's Log Event Extended Format 1.0, with tab separated attributes. Along with
+//!   the standard src, dst, srcPort, dstPort, proto, action, cat, sev and devTime attributes it
+//!   has the rule, vm, alias and direction, with icmpType and icmpCode in place of the ports of
+//!   ICMP events. LEEF has no escaping, so tabs and line breaks in values are replaced with spaces.
+//! - "cbor" and "msgpack" are the same fields as json, encoded as a CBOR or MessagePack map per
+//!   record rather than a line of text, for very busy zones whose json encoding is a measurable
+//!   part of cfwlogd's CPU. Each value delimits itself, so records are simply written one after
+//!   another without a separator. They need cfwlogd to be built with the binary-formats feature.
+//! - "protobuf" is the `Record` message of "proto/record.proto", each preceded by its length, see
+//!   the "protobuf" module. It needs cfwlogd to be built with the protobuf feature.
+//!
+//! Since the last three are binary they only apply to the zone's log file and to plugins, the
+//! sinks that take bytes rather than text.
+
+use crate::migrate;
+#[cfg(feature = "protobuf")]
+use crate::protobuf;
+use crate::sink::{Record, SCHEMA_VERSION};
+use cfwevent::parser::{self, logged_addr, CfwEvType, CfwEvent, Direction, Protocol, TrafficEvent};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io;
+use std::net::Ipv6Addr;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Json,
+    Cef,
+    Leef,
+    Cbor,
+    Msgpack,
+    Protobuf,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Json
+    }
+}
+
+impl Format {
+    /// Whether records are encoded as bytes rather than lines of text
+    pub fn is_binary(self) -> bool {
+        match self {
+            Format::Cbor | Format::Msgpack | Format::Protobuf => true,
+            Format::Json | Format::Cef | Format::Leef => false,
+        }
+    }
+
+    /// The feature cfwlogd has to be built with to write the format, if this build doesn't have it
+    pub fn missing_feature(self) -> Option<&'static str> {
+        match self {
+            Format::Cbor | Format::Msgpack if !cfg!(feature = "binary-formats") => {
+                Some("binary-formats")
+            }
+            Format::Protobuf if !cfg!(feature = "protobuf") => Some("protobuf"),
+            _ => None,
+        }
+    }
+}
+
+/// Append a record, or a record cfwlogd generated itself, in one of the binary formats and the
+/// given schema version
+pub fn binary<T: Serialize>(
+    format: Format,
+    record: &T,
+    version: u64,
+    buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    if version >= SCHEMA_VERSION {
+        return encode_binary(format, record, buf);
+    }
+    let mut value = serde_json::to_value(record)?;
+    if let Value::Object(map) = &mut value {
+        migrate::downgrade_record(map, version);
+    }
+    encode_binary(format, &value, buf)
+}
+
+fn encode_binary<T: Serialize>(format: Format, record: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+    match format {
+        #[cfg(feature = "binary-formats")]
+        Format::Cbor => serde_cbor::to_writer(buf, record)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+        #[cfg(feature = "binary-formats")]
+        Format::Msgpack => {
+            let encoded = rmp_serde::to_vec_named(record)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            buf.extend_from_slice(&encoded);
+            Ok(())
+        }
+        #[cfg(feature = "protobuf")]
+        Format::Protobuf => protobuf::encode(record, buf),
+        _ => {
+            let _ = (record, buf);
+            unreachable!(
+                "{:?} isn't binary, or the config wasn't validated against its feature",
+                format
+            )
+        }
+    }
+}
+
+fn protocol_name(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::AH => "ah",
+        Protocol::ESP => "esp",
+        Protocol::ICMP => "icmp",
+        Protocol::ICMPV6 => "ipv6-icmp",
+        Protocol::TCP => "tcp",
+        Protocol::UDP => "udp",
+        Protocol::UNKNOWN => "unknown",
+    }
+}
+
+fn event_name(event: &CfwEvType) -> (&'static str, &'static str) {
+    match event {
+        CfwEvType::Block => ("block", "Connection blocked"),
+        CfwEvType::Begin => ("begin", "Connection allowed"),
+        CfwEvType::End => ("end", "Connection ended"),
+        CfwEvType::Unknown => ("unknown", "Unknown event"),
+    }
+}
+
+/// Append a CEF header field, in which pipes and backslashes have to be escaped
+fn push_cef_header(value: &str, out: &mut Vec<u8>) {
+    for b in value.bytes() {
+        if b == b'|' || b == b'\\' {
+            out.push(b'\\');
+        }
+        out.push(b);
+    }
+    out.push(b'|');
+}
+
+/// Append a CEF extension, in which equal signs, backslashes and line breaks have to be escaped
+fn push_cef_extension(key: &str, value: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(key.as_bytes());
+    out.push(b'=');
+    for b in value.bytes() {
+        match b {
+            b'=' | b'\\' => out.extend_from_slice(&[b'\\', b]),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            _ => out.push(b),
+        }
+    }
+}
+
+/// Append the record as a CEF line, without a newline
+pub fn cef(record: &Record<'_>, out: &mut Vec<u8>) {
+    let event = match &record.event {
+        CfwEvent::Traffic(event) => event,
+        // These never make it to a sink, but there is nothing to say about them anyway
+        CfwEvent::Unknown(_) => return,
+    };
+    let (id, name) = event_name(&event.event);
+    out.extend_from_slice(b"CEF:0|");
+    push_cef_header("Joyent", out);
+    push_cef_header("cfwlogd", out);
+    push_cef_header(env!("CARGO_PKG_VERSION"), out);
+    push_cef_header(id, out);
+    push_cef_header(name, out);
+    push_cef_header(
+        match event.event {
+            CfwEvType::Block => "5",
+            _ => "3",
+        },
+        out,
+    );
+    for (i, (key, value)) in cef_extensions(record, event).iter().enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        push_cef_extension(key, value, out);
+    }
+}
+
+/// Append the record as a LEEF line, without a newline
+pub fn leef(record: &Record<'_>, out: &mut Vec<u8>) {
+    let event = match &record.event {
+        CfwEvent::Traffic(event) => event,
+        // These never make it to a sink, but there is nothing to say about them anyway
+        CfwEvent::Unknown(_) => return,
+    };
+    let (id, _) = event_name(&event.event);
+    out.extend_from_slice(
+        format!(
+            "LEEF:1.0|Joyent|cfwlogd|{}|{}|",
+            env!("CARGO_PKG_VERSION"),
+            id
+        )
+        .as_bytes(),
+    );
+    // The ports of ICMP events are the type and code, which LEEF has attributes of its own for
+    let (source_port, destination_port) = match event.icmp {
+        Some(icmp) => (
+            ("icmpType", icmp.icmp_type.to_string()),
+            ("icmpCode", icmp.icmp_code.to_string()),
+        ),
+        None => (
+            ("srcPort", event.source_port.to_string()),
+            ("dstPort", event.destination_port.to_string()),
+        ),
+    };
+    let attributes = [
+        ("devTime", event.timestamp.timestamp_millis().to_string()),
+        ("cat", id.to_owned()),
+        (
+            "sev",
+            match event.event {
+                CfwEvType::Block => "5",
+                _ => "3",
+            }
+            .to_owned(),
+        ),
+        (
+            "action",
+            match event.event {
+                CfwEvType::Block => "block",
+                _ => "allow",
+            }
+            .to_owned(),
+        ),
+        ("proto", protocol_name(&event.protocol).to_owned()),
+        ("src", logged_addr(&event.source_ip).to_string()),
+        source_port,
+        ("dst", logged_addr(&event.destination_ip).to_string()),
+        destination_port,
+        (
+            "direction",
+            match event.direction {
+                Direction::In => "in",
+                Direction::Out => "out",
+            }
+            .to_owned(),
+        ),
+        ("rule", event.rule_uuid.to_string()),
+        ("vm", record.vm.to_owned()),
+        ("alias", record.alias.to_owned()),
+    ];
+    for (i, (key, value)) in attributes.iter().enumerate() {
+        if i > 0 {
+            out.push(b'\t');
+        }
+        out.extend_from_slice(key.as_bytes());
+        out.push(b'=');
+        out.extend(value.bytes().map(|b| match b {
+            b'\t' | b'\n' | b'\r' => b' ',
+            _ => b,
+        }));
+    }
+}
+
+/// The CEF extension for an address, under the `v4` key if it's IPv4-mapped and the `v6` one if
+/// it isn't
+fn cef_address(addr: &Ipv6Addr, v4: &'static str, v6: &'static str) -> (&'static str, String) {
+    match parser::ipv4_mapped(addr) {
+        Some(mapped) => (v4, mapped.to_string()),
+        None => (v6, addr.to_string()),
+    }
+}
+
+fn cef_extensions(record: &Record<'_>, event: &TrafficEvent) -> Vec<(&'static str, String)> {
+    let mut extensions = vec![
+        ("rt", event.timestamp.timestamp_millis().to_string()),
+        (
+            "act",
+            match event.event {
+                CfwEvType::Block => "block",
+                _ => "allow",
+            }
+            .to_owned(),
+        ),
+        (
+            "deviceDirection",
+            match event.direction {
+                Direction::In => "0",
+                Direction::Out => "1",
+            }
+            .to_owned(),
+        ),
+        ("proto", protocol_name(&event.protocol).to_owned()),
+    ];
+    match event.icmp {
+        // The ports are the type and code, which CEF has no keys of its own for
+        Some(icmp) => extensions.extend(vec![
+            cef_address(&event.source_ip, "src", "c6a2"),
+            cef_address(&event.destination_ip, "dst", "c6a3"),
+            ("cn1Label", "icmp_type".to_owned()),
+            ("cn1", icmp.icmp_type.to_string()),
+            ("cn2Label", "icmp_code".to_owned()),
+            ("cn2", icmp.icmp_code.to_string()),
+        ]),
+        None => extensions.extend(vec![
+            cef_address(&event.source_ip, "src", "c6a2"),
+            ("spt", event.source_port.to_string()),
+            cef_address(&event.destination_ip, "dst", "c6a3"),
+            ("dpt", event.destination_port.to_string()),
+        ]),
+    }
+    extensions.extend(vec![
+        ("cs1Label", "rule".to_owned()),
+        ("cs1", event.rule_uuid.to_string()),
+        ("cs2Label", "vm".to_owned()),
+        ("cs2", record.vm.to_owned()),
+    ]);
+    if !record.alias.is_empty() {
+        extensions.push(("cs3Label", "alias".to_owned()));
+        extensions.push(("cs3", record.alias.to_owned()));
+    }
+    if let Some(hostname) = record.node.and_then(|node| node.hostname.as_ref()) {
+        extensions.push(("dvchost", hostname.clone()));
+    }
+    extensions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    #[test]
+    fn records_are_formatted_as_cef() {
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "a=b|c",
+        );
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        let mut buf = vec![];
+        cef(&record, &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+
+        let (id, name) = event_name(&event.event);
+        assert!(
+            line.starts_with(&format!(
+                "CEF:0|Joyent|cfwlogd|{}|{}|{}|",
+                env!("CARGO_PKG_VERSION"),
+                id,
+                name
+            )),
+            "header: {}",
+            line
+        );
+        assert!(
+            line.contains(&format!(
+                " src=172.24.4.150 spt={} dst=172.24.4.151 dpt={} ",
+                event.source_port, event.destination_port
+            )),
+            "IPv4-mapped addresses are given as IPv4: {}",
+            line
+        );
+        assert!(
+            line.ends_with(" cs2Label=vm cs2=vm1 cs3Label=alias cs3=a\\=b|c"),
+            "extension values are escaped: {}",
+            line
+        );
+    }
+
+    #[test]
+    fn ipv6_addresses_in_cef() {
+        let mut event = match parser::cfwevent_parse(testutils::generate_event().as_bytes()) {
+            Ok((_, CfwEvent::Traffic(event))) => event,
+            other => panic!("unexpected event {:?}", other),
+        };
+        event.source_ip = "fe80::8:20ff:fe1b:4a3c".parse().unwrap();
+        let record = Record::new(CfwEvent::Traffic(event), "vm1", "");
+        let mut buf = vec![];
+        cef(&record, &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+        assert!(
+            line.contains(" c6a2=fe80::8:20ff:fe1b:4a3c spt=") && !line.contains(" src="),
+            "IPv6 sources are given under c6a2: {}",
+            line
+        );
+        assert!(line.contains(" dst=172.24.4.151 "), "{}", line);
+    }
+
+    #[test]
+    fn icmp_types_and_codes() {
+        let mut event = match parser::cfwevent_parse(testutils::generate_event().as_bytes()) {
+            Ok((_, CfwEvent::Traffic(event))) => event,
+            other => panic!("unexpected event {:?}", other),
+        };
+        event.protocol = Protocol::ICMP;
+        event.icmp = Some(parser::Icmp {
+            icmp_type: 3,
+            icmp_code: 13,
+        });
+        let record = Record::new(CfwEvent::Traffic(event), "vm1", "");
+        let mut buf = vec![];
+        cef(&record, &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+        assert!(
+            line.contains(" cn1Label=icmp_type cn1=3 cn2Label=icmp_code cn2=13 ")
+                && !line.contains(" spt="),
+            "cef: {}",
+            line
+        );
+
+        let mut buf = vec![];
+        leef(&record, &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+        let attributes: Vec<&str> = line.split('\t').collect();
+        assert!(
+            attributes.contains(&"icmpType=3") && attributes.contains(&"icmpCode=13"),
+            "leef: {}",
+            line
+        );
+        assert!(!line.contains("srcPort="), "leef: {}", line);
+    }
+
+    #[test]
+    fn records_are_formatted_as_leef() {
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web\t1",
+        );
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        let mut buf = vec![];
+        leef(&record, &mut buf);
+        let line = String::from_utf8(buf).unwrap();
+
+        let (id, _) = event_name(&event.event);
+        let prefix = format!(
+            "LEEF:1.0|Joyent|cfwlogd|{}|{}|",
+            env!("CARGO_PKG_VERSION"),
+            id
+        );
+        assert!(line.starts_with(&prefix), "header: {}", line);
+        let attributes: Vec<&str> = line[prefix.len()..].split('\t').collect();
+        assert!(attributes.contains(&format!("srcPort={}", event.source_port).as_str()));
+        assert!(attributes.contains(&format!("dstPort={}", event.destination_port).as_str()));
+        assert_eq!(
+            attributes.last(),
+            Some(&"alias=web 1"),
+            "tabs in values are replaced"
+        );
+    }
+
+    #[cfg(feature = "binary-formats")]
+    #[test]
+    fn records_are_encoded_as_binary() {
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web",
+        );
+        let json = serde_json::to_value(&record).unwrap();
+
+        let mut buf = vec![];
+        binary(Format::Cbor, &record, SCHEMA_VERSION, &mut buf).unwrap();
+        binary(Format::Cbor, &record, 1, &mut buf).unwrap();
+        let mut records = serde_cbor::Deserializer::from_slice(&buf).into_iter::<Value>();
+        assert_eq!(
+            records.next().unwrap().unwrap(),
+            json,
+            "the same fields as json"
+        );
+        let older = records.next().unwrap().unwrap();
+        assert_eq!(older["schema_version"], 1, "records follow one another");
+        assert!(records.next().is_none());
+
+        let mut buf = vec![];
+        binary(Format::Msgpack, &record, SCHEMA_VERSION, &mut buf).unwrap();
+        let decoded: Value = rmp_serde::from_slice(&buf).unwrap();
+        assert_eq!(decoded, json);
+    }
+
+    /// Every record is encoded at least once, for its zone's log. Run on its own in a release
+    /// build with `cargo test --release encoding_throughput -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn encoding_throughput() {
+        const RECORDS: u32 = 200_000;
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web",
+        );
+        for format in &["json", "cef", "leef"] {
+            let config = crate::config::Config::from_toml(&format!(
+                "[sink_formats]\nsyslog = \"{}\"",
+                format
+            ))
+            .unwrap();
+            let encoder = crate::sink::Encoder::for_sink("syslog", &config);
+            let mut buf = Vec::with_capacity(1024);
+            let start = std::time::Instant::now();
+            for _ in 0..RECORDS {
+                buf.clear();
+                encoder.encode(&record, &mut buf).unwrap();
+            }
+            let rate = f64::from(RECORDS) / start.elapsed().as_secs_f64();
+            println!(
+                "encoded {} records as {} at {:.0} records/s",
+                RECORDS, format, rate
+            );
+            assert!(rate >= 100_000.0, "{} encoding fell behind", format);
+        }
+    }
+}
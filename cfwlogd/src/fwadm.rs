@@ -0,0 +1,101 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Periodic sync of every rule's owner and description from the rule files fwadm keeps on the CN,
+//! one "<uuid>.json" per rule, into the shared `Rules` mapping. This is an alternative to syncing
+//! from FWAPI for CNs that can't reach it, and needs no extra feature. The directory is opened
+//! before we chroot and read through that descriptor afterwards. A rule file that can't be read
+//! is logged and skipped, and a failed sync keeps the previous mapping until the next attempt.
+
+use crate::config::FwadmConfig;
+use crate::fileutils;
+use crate::rules::{RuleObject, RuleOwner, Rules};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Read every rule file in `dir`
+fn read_rules(dir: &File) -> io::Result<HashMap<Uuid, RuleOwner>> {
+    let mut rules = HashMap::new();
+    for name in fileutils::list_dir(dir)? {
+        if !name.ends_with(".json") {
+            continue;
+        }
+        let rule = fileutils::open_read_nofollow(dir, &name).and_then(|file| {
+            serde_json::from_reader::<_, RuleObject>(file).map_err(io::Error::from)
+        });
+        match rule {
+            Ok(rule) => {
+                let (uuid, owner) = rule.into_entry();
+                rules.insert(uuid, owner);
+            }
+            // fwadm may be replacing it as we read it, in which case the next sync picks it up
+            Err(e) => warn!("skipping fwadm rule file {}: {}", name, e),
+        }
+    }
+    Ok(rules)
+}
+
+/// Open fwadm's rule directory and start a thread that keeps `rules` in sync with it. This must
+/// happen before we chroot.
+pub fn start_fwadm_sync(config: &FwadmConfig, rules: Rules) -> io::Result<thread::JoinHandle<()>> {
+    let dir = File::open(&config.rules_dir)?;
+    let interval = Duration::from_secs(config.refresh_secs);
+    thread::Builder::new()
+        .name("fwadm_sync".to_owned())
+        .spawn(move || loop {
+            match read_rules(&dir) {
+                Ok(synced) => {
+                    debug!("synced {} rules from fwadm", synced.len());
+                    *rules.write().unwrap() = synced;
+                }
+                // CMON TRITON-1755
+                Err(e) => warn!("failed to sync rules from fwadm: {}", e),
+            }
+            thread::sleep(interval);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn fwadm_rules() {
+        let path: PathBuf = ["/var/tmp/cfwlogd-tests", "fwadm", "rules"]
+            .iter()
+            .collect();
+        let dir = fileutils::create_dir_all_nofollow(&path).expect("failed to create test dir");
+        let ssh = r#"{
+            "uuid": "0f8c2972-0a38-4e39-9f76-46b5738ed404",
+            "owner_uuid": "930896af-bf8c-48d4-885c-6573a94b1853",
+            "description": "allow ssh",
+            "enabled": true,
+            "version": "1589310000000.000001",
+            "rule": "FROM any TO vm 9b2d6f8a-1f24-4ec3-8d4d-7b0c5f8b5c1f ALLOW tcp PORT 22"
+        }"#;
+        let files: &[(&str, &str)] = &[
+            ("0f8c2972-0a38-4e39-9f76-46b5738ed404.json", ssh),
+            ("5d24b6a9-2a8c-4be1-ae70-7ab3d3a1df62.json", "{\"uuid\":"),
+            ("README", "not a rule"),
+        ];
+        for (name, contents) in files {
+            fileutils::replace_file_nofollow(&dir, name, contents.as_bytes()).unwrap();
+        }
+
+        let rules = read_rules(&dir).expect("failed to read rules");
+        assert_eq!(rules.len(), 1, "unreadable and other files are skipped");
+        let ssh = &rules[&"0f8c2972-0a38-4e39-9f76-46b5738ed404".parse().unwrap()];
+        assert_eq!(ssh.description.as_deref(), Some("allow ssh"));
+        assert!(ssh.owner_uuid.is_some());
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,88 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Periodic sync of every rule's owner and description from FWAPI into the shared `Rules` mapping.
+//! FWAPI's address is resolved once at startup (see the "http" module). A failed sync is logged
+//! and the previous mapping is kept until the next attempt.
+
+use crate::config::FwapiConfig;
+use crate::http;
+use crate::rules::{RuleObject, RuleOwner, Rules};
+use std::collections::HashMap;
+use std::io;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How long to wait on FWAPI before giving up on a sync
+const REQUEST_TIMEOUT_MS: u64 = 30_000;
+
+/// Build the rule mapping out of FWAPI's "ListRules" response
+fn parse_rules<R: io::Read>(body: R) -> io::Result<HashMap<Uuid, RuleOwner>> {
+    let rules: Vec<RuleObject> = serde_json::from_reader(body)?;
+    Ok(rules.into_iter().map(RuleObject::into_entry).collect())
+}
+
+fn fetch_rules(agent: &ureq::Agent, url: &str) -> io::Result<HashMap<Uuid, RuleOwner>> {
+    let resp = agent
+        .get(&format!("{}/rules", url.trim_end_matches('/')))
+        .timeout_connect(REQUEST_TIMEOUT_MS)
+        .timeout_read(REQUEST_TIMEOUT_MS)
+        .call();
+    parse_rules(http::check_response(resp)?.into_reader())
+}
+
+/// Resolve FWAPI's address and start a thread that keeps `rules` in sync with it. This must
+/// happen before we chroot.
+pub fn start_fwapi_sync(config: &FwapiConfig, rules: Rules) -> io::Result<thread::JoinHandle<()>> {
+    let url = config.url.clone();
+    let agent = http::pinned_agent(&url)?;
+    let interval = Duration::from_secs(config.refresh_secs);
+    thread::Builder::new()
+        .name("fwapi_sync".to_owned())
+        .spawn(move || loop {
+            match fetch_rules(&agent, &url) {
+                Ok(synced) => {
+                    debug!("synced {} rules from FWAPI", synced.len());
+                    *rules.write().unwrap() = synced;
+                }
+                // CMON TRITON-1755
+                Err(e) => warn!("failed to sync rules from FWAPI: {}", e),
+            }
+            thread::sleep(interval);
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fwapi_rules() {
+        let body = r#"[
+            {
+                "uuid": "0f8c2972-0a38-4e39-9f76-46b5738ed404",
+                "owner_uuid": "930896af-bf8c-48d4-885c-6573a94b1853",
+                "description": "allow ssh",
+                "enabled": true,
+                "rule": "FROM any TO vm 9b2d6f8a-1f24-4ec3-8d4d-7b0c5f8b5c1f ALLOW tcp PORT 22"
+            },
+            {
+                "uuid": "5d24b6a9-2a8c-4be1-ae70-7ab3d3a1df62",
+                "global": true,
+                "enabled": true,
+                "rule": "FROM any TO all vms ALLOW icmp TYPE 8 CODE 0"
+            }
+        ]"#;
+        let rules = parse_rules(body.as_bytes()).expect("failed to parse rules");
+        assert_eq!(rules.len(), 2);
+        let ssh = &rules[&"0f8c2972-0a38-4e39-9f76-46b5738ed404".parse().unwrap()];
+        assert_eq!(ssh.description.as_deref(), Some("allow ssh"));
+        assert!(ssh.owner_uuid.is_some());
+        let global = &rules[&"5d24b6a9-2a8c-4be1-ae70-7ab3d3a1df62".parse().unwrap()];
+        assert_eq!(global, &RuleOwner::default(), "global rules have no owner");
+    }
+}
@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Annotating remote addresses with their country and autonomous system from MaxMind format
+//! databases, as an "enrich" module lookup. The directories the databases are in are opened before
+//! we chroot, and the databases are read into memory from them at startup and again on every
+//! SIGHUP, so a tool like geoipupdate can replace them in place. A database that fails to load on
+//! SIGHUP is logged and the one already loaded is kept.
+
+use crate::config::GeoipConfig;
+use crate::enrich::{Annotation, Lookup};
+use crate::fileutils;
+use crossbeam::sync::ShardedLock;
+use maxminddb::{geoip2, Reader};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+/// A database along with where to reload it from
+struct Database {
+    dir: File,
+    name: String,
+    reader: ShardedLock<Reader<Vec<u8>>>,
+}
+
+impl Database {
+    fn open(path: &Path) -> io::Result<Database> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid database path");
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(invalid)?
+            .to_owned();
+        let dir = File::open(path.parent().ok_or_else(invalid)?)?;
+        let reader = ShardedLock::new(load(&dir, &name)?);
+        Ok(Database { dir, name, reader })
+    }
+
+    fn reload(&self) {
+        match load(&self.dir, &self.name) {
+            Ok(reader) => *self.reader.write().unwrap() = reader,
+            // CMON TRITON-1755
+            Err(e) => error!("failed to reload GeoIP database {}: {}", self.name, e),
+        }
+    }
+}
+
+fn load(dir: &File, name: &str) -> io::Result<Reader<Vec<u8>>> {
+    let mut buf = vec![];
+    fileutils::open_read_nofollow(dir, name)?.read_to_end(&mut buf)?;
+    Reader::from_source(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", e)))
+}
+
+pub struct Geoip {
+    country: Option<Database>,
+    asn: Option<Database>,
+}
+
+impl Geoip {
+    /// Load the configured databases. This must happen before we chroot.
+    pub fn open(config: &GeoipConfig) -> io::Result<Geoip> {
+        let open = |path: &Option<PathBuf>| match path {
+            Some(path) => Database::open(path).map(Some),
+            None => Ok(None),
+        };
+        Ok(Geoip {
+            country: open(&config.country_db)?,
+            asn: open(&config.asn_db)?,
+        })
+    }
+}
+
+impl Lookup for Geoip {
+    fn annotate(&self, addr: IpAddr, annotation: &mut Annotation) {
+        // Addresses the databases don't have, such as private ones, are left alone
+        if let Some(db) = &self.country {
+            if let Ok(country) = db.reader.read().unwrap().lookup::<geoip2::Country>(addr) {
+                annotation.remote_country = country.country.and_then(|country| country.iso_code);
+            }
+        }
+        if let Some(db) = &self.asn {
+            if let Ok(asn) = db.reader.read().unwrap().lookup::<geoip2::Asn>(addr) {
+                annotation.remote_asn = asn.autonomous_system_number;
+                annotation.remote_as_org = asn.autonomous_system_organization;
+            }
+        }
+    }
+
+    fn reload(&self) {
+        self.country
+            .iter()
+            .chain(self.asn.iter())
+            .for_each(Database::reload);
+    }
+}
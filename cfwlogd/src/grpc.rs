@@ -0,0 +1,197 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An optional gRPC service (see "proto/live.proto") that lets local clients subscribe to a
+//! filtered stream of the records being logged, so response tooling can react to firewall activity
+//! as it happens. The service only listens on a Unix socket that is created mode 0600, so access is
+//! limited to root on the CN. The socket is bound before we chroot into the log directory and the
+//! service runs on its own thread with a small tokio runtime, everything else in cfwlogd stays
+//! synchronous. It's the same tokio 1 the vminfod client runs on, so a build with the service
+//! links a single tokio.
+
+use crate::fileutils;
+use crate::live::{Filter, LiveHub};
+use futures::TryStreamExt;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::{ReceiverStream, UnixListenerStream};
+use tonic::transport::server::Connected;
+use tonic::{Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("cfwlogd.live");
+}
+
+use proto::live_server::{Live, LiveServer};
+use proto::{Record, SubscribeRequest};
+
+/// Records queued in the `LiveHub` for each subscriber before they start getting dropped
+const SUBSCRIBER_CAPACITY: usize = 4096;
+/// Records buffered between a subscriber's forwarding thread and its gRPC stream
+const STREAM_BUFFER: usize = 64;
+
+/// Build the `Filter` described by a subscription request
+fn request_filter(req: SubscribeRequest) -> Result<Filter, Status> {
+    let action = match req.action.as_str() {
+        "" => None,
+        action => Some(action.parse().map_err(Status::invalid_argument)?),
+    };
+    let port = match req.port {
+        0 => None,
+        port if port <= u32::from(u16::max_value()) => Some(port as u16),
+        port => return Err(Status::invalid_argument(format!("invalid port {}", port))),
+    };
+    Ok(Filter {
+        vms: req.vms,
+        action,
+        port,
+        expr: None,
+    })
+}
+
+struct LiveService {
+    hub: Arc<LiveHub>,
+}
+
+#[tonic::async_trait]
+impl Live for LiveService {
+    type SubscribeStream = ReceiverStream<Result<Record, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let filter = request_filter(request.into_inner())?;
+        debug!("new gRPC subscriber: {:?}", filter);
+        let records = self.hub.subscribe(filter, SUBSCRIBER_CAPACITY);
+        let (tx, rx) = mpsc::channel(STREAM_BUFFER);
+        // The hub hands out crossbeam channels, so each subscriber gets a thread that forwards
+        // records onto its stream until the client goes away.
+        thread::Builder::new()
+            .name("grpc_subscriber".to_owned())
+            .spawn(move || {
+                for record in records.iter() {
+                    let record = Record {
+                        vm: record.vm.clone(),
+                        json: record.json.clone(),
+                    };
+                    if futures::executor::block_on(tx.send(Ok(record))).is_err() {
+                        break;
+                    }
+                }
+                debug!("gRPC subscriber went away");
+            })
+            .map_err(|e| Status::internal(format!("failed to start subscriber: {}", e)))?;
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+/// A Unix socket connection that tonic can serve
+struct Connection(tokio::net::UnixStream);
+
+impl Connected for Connection {}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// Bind the service's socket and start serving subscriptions to records published on `hub`
+pub fn start_grpc_server(path: &Path, hub: Arc<LiveHub>) -> io::Result<thread::JoinHandle<()>> {
+    let listener = fileutils::bind_private_socket(path)?;
+    listener.set_nonblocking(true)?;
+    info!("gRPC live event service listening on {}", path.display());
+    thread::Builder::new()
+        .name("grpc_server".to_owned())
+        .spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed to build the gRPC runtime");
+            let res = rt.block_on(async move {
+                let listener = tokio::net::UnixListener::from_std(listener)?;
+                let incoming = UnixListenerStream::new(listener).map_ok(Connection);
+                tonic::transport::Server::builder()
+                    .add_service(LiveServer::new(LiveService { hub }))
+                    .serve_with_incoming(incoming)
+                    .await
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            });
+            if let Err(e) = res {
+                // CMON TRITON-1755
+                error!("gRPC live event service stopped: {}", e);
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::CfwEvType;
+
+    #[test]
+    fn subscribe_request_filters() {
+        let filter = request_filter(SubscribeRequest {
+            vms: vec!["vm1".to_owned()],
+            action: "block".to_owned(),
+            port: 22,
+        })
+        .expect("valid request");
+        assert_eq!(
+            filter,
+            Filter {
+                vms: vec!["vm1".to_owned()],
+                action: Some(CfwEvType::Block),
+                port: Some(22),
+                expr: None,
+            }
+        );
+        assert_eq!(
+            request_filter(SubscribeRequest::default()).unwrap(),
+            Filter::default(),
+            "unset fields match everything"
+        );
+        assert!(request_filter(SubscribeRequest {
+            port: 70000,
+            ..SubscribeRequest::default()
+        })
+        .is_err());
+        assert!(request_filter(SubscribeRequest {
+            action: "allow".to_owned(),
+            ..SubscribeRequest::default()
+        })
+        .is_err());
+    }
+}
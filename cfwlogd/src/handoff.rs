@@ -0,0 +1,233 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Handing the event devices over to a new cfwlogd, so that upgrading cfwlogd doesn't leave a
+//! window where nothing has the devices open and the kernel's events are lost. With "handoff"
+//! configured cfwlogd listens on "handoff.socket", and a new cfwlogd started while it's running
+//! connects to it before opening any devices itself. The running cfwlogd then:
+//!
+//! 1. stops reading the devices, but keeps them open
+//! 2. drains everything it already read into the zones' logs and closes them, as it does on SIGTERM
+//! 3. sends the devices' descriptors over the socket with SCM_RIGHTS, and exits
+//!
+//! The events reported in the meantime wait in the devices' ring buffers for the new cfwlogd. The
+//! zones' log files are closed rather than handed over: the new cfwlogd appends to them where the
+//! old one left off, and since the old one has written everything it read by then, no file is ever
+//! written by both and the records stay in order. A new cfwlogd that finds nobody listening, or
+//! whose request fails, opens the devices itself. Only "ipfev" devices are handed over.
+
+use crate::fileutils;
+use crossbeam::channel::Sender;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// What a new cfwlogd asks for
+const REQUEST: &str = "handoff\n";
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// The most devices handed over at once
+const MAX_DEVICES: usize = 16;
+/// The longest reply a new cfwlogd accepts
+const MAX_REPLY: usize = 64 * 1024;
+
+/// Sent along with the descriptors, naming the device each one is for
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+struct Reply {
+    devices: Vec<String>,
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+/// Send `payload` along with `fds` in a single message
+fn send_fds(stream: &UnixStream, payload: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    let fds_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = control.len() as _;
+        // This is unsafe because we are filling in the control message through raw pointers
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+            std::ptr::copy_nonoverlapping(
+                fds.as_ptr() as *const u8,
+                libc::CMSG_DATA(cmsg),
+                fds_len as usize,
+            );
+        }
+    }
+    match unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } {
+        -1 => Err(io::Error::last_os_error()),
+        n if n as usize != payload.len() => Err(invalid("short write")),
+        _ => Ok(()),
+    }
+}
+
+/// Receive a message along with the descriptors sent with it
+fn recv_fds(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<File>)> {
+    let mut buf = vec![0u8; MAX_REPLY];
+    let fds_len = (MAX_DEVICES * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let n = match unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) } {
+        -1 => return Err(io::Error::last_os_error()),
+        n => n as usize,
+    };
+    let mut files = vec![];
+    // This is unsafe because we are walking the control messages through raw pointers, and
+    // taking ownership of the descriptors in them
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                for i in 0..len / mem::size_of::<RawFd>() {
+                    let fd = std::ptr::read_unaligned(data.add(i));
+                    libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                    files.push(File::from_raw_fd(fd));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & (libc::MSG_CTRUNC | libc::MSG_TRUNC) != 0 {
+        return Err(invalid("the reply was truncated"));
+    }
+    buf.truncate(n);
+    Ok((buf, files))
+}
+
+/// Bind the socket a new cfwlogd asks for the devices on, which has to happen before we chroot
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    fileutils::bind_private_socket(path)
+}
+
+fn read_request(stream: &UnixStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream).read_line(&mut line)?;
+    if line != REQUEST {
+        return Err(invalid("unknown request"));
+    }
+    Ok(())
+}
+
+/// Wait for a new cfwlogd to ask for the devices, and pass its connection on to `successor` so
+/// the main thread can shut down and then hand the devices over
+pub fn start_handoff(
+    listener: UnixListener,
+    successor: Sender<UnixStream>,
+) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("handoff".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept handoff connection: {}", e);
+                        continue;
+                    }
+                };
+                match read_request(&stream) {
+                    Ok(()) => {
+                        info!("a new cfwlogd asked to take over");
+                        let _ = successor.send(stream);
+                        return;
+                    }
+                    Err(e) => warn!("ignoring handoff request: {}", e),
+                }
+            }
+        })
+}
+
+/// Send the devices to the new cfwlogd, once everything read from them has been written out
+pub fn hand_over(stream: UnixStream, devices: &[(String, File)]) -> io::Result<()> {
+    let reply = Reply {
+        devices: devices.iter().map(|(name, _)| name.clone()).collect(),
+    };
+    let mut payload = serde_json::to_vec(&reply)?;
+    payload.push(b'\n');
+    let fds: Vec<RawFd> = devices.iter().map(|(_, file)| file.as_raw_fd()).collect();
+    send_fds(&stream, &payload, &fds)
+}
+
+/// Ask the cfwlogd listening on `path` for its devices, giving it up to `timeout` to write out
+/// what it already read
+pub fn take_over(path: &Path, timeout: Duration) -> io::Result<Vec<(String, File)>> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.write_all(REQUEST.as_bytes())?;
+    let (payload, files) = recv_fds(&stream)?;
+    let reply: Reply = serde_json::from_slice(&payload)?;
+    if reply.devices.len() != files.len() {
+        return Err(invalid("the reply doesn't name every descriptor"));
+    }
+    Ok(reply.devices.into_iter().zip(files).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom};
+
+    #[test]
+    fn descriptors_are_handed_over() {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut device = tempfile().unwrap();
+        device.write_all(b"events").unwrap();
+        hand_over(server, &[("/dev/ipfev".to_owned(), device)]).unwrap();
+
+        let (payload, mut files) = recv_fds(&client).unwrap();
+        let reply: Reply = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(reply.devices, vec!["/dev/ipfev"]);
+        assert_eq!(files.len(), 1);
+        let mut contents = String::new();
+        files[0].seek(SeekFrom::Start(0)).unwrap();
+        files[0].read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "events", "the same file came across");
+    }
+
+    fn tempfile() -> io::Result<File> {
+        let path = Path::new(crate::logger::LOG_DIR).join("handoff-test");
+        std::fs::create_dir_all(crate::logger::LOG_DIR)?;
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        std::fs::remove_file(&path)?;
+        Ok(file)
+    }
+}
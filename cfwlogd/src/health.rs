@@ -0,0 +1,175 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A Unix socket reporting whether cfwlogd is healthy, for SMF probes and monitoring agents on the
+//! CN. Each client that connects is sent a single line of json and the connection is closed:
+//!
+//! ```text
+//! {"ready":true,"live":true,"vminfod_connected":true,"sources":1,"sources_open":1,
+//!  "event_loop_secs":2,"stuck_zones":[]}
+//! ```
+//!
+//! Ready means vminfod is connected and every event source is still being read. Live means the
+//! event fanout thread and every zone's `Logger` have made progress within "health.stuck_secs",
+//! so a logger wedged on a write (a hung filesystem, say) shows up in "stuck_zones" rather than
+//! only as a growing queue. Each of those threads keeps a `Heartbeat` that it beats at least
+//! every `HEARTBEAT_INTERVAL` while it's idle.
+
+use crate::events::{Loggers, ReaderState};
+use crate::fileutils;
+use serde::Serialize;
+use std::io::{self, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The longest a thread with a `Heartbeat` goes without beating it while nothing is happening
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The last time a thread showed it was making progress
+#[derive(Debug)]
+pub struct Heartbeat(Mutex<Instant>);
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat::new()
+    }
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat(Mutex::new(Instant::now()))
+    }
+
+    pub fn beat(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    /// How long it has been since the last beat
+    pub fn age(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+/// What the health socket reports on
+pub struct Health {
+    pub readers: Arc<ReaderState>,
+    pub fanout: Arc<Heartbeat>,
+    pub loggers: Loggers,
+    /// How long a thread may go without a heartbeat before cfwlogd isn't considered live
+    pub stuck_after: Duration,
+}
+
+#[derive(Debug, Serialize)]
+struct Status {
+    ready: bool,
+    live: bool,
+    vminfod_connected: bool,
+    sources: usize,
+    sources_open: usize,
+    /// Seconds since the event fanout thread's last heartbeat
+    event_loop_secs: u64,
+    /// The zones whose `Logger` hasn't had a heartbeat within "health.stuck_secs"
+    stuck_zones: Vec<String>,
+}
+
+impl Health {
+    fn status(&self, vminfod_connected: bool) -> Status {
+        let event_loop = self.fanout.age();
+        let mut stuck_zones: Vec<String> = self
+            .loggers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|logger| logger.heartbeat_age() > self.stuck_after)
+            .map(|logger| logger.uuid.clone())
+            .collect();
+        stuck_zones.sort();
+        let (sources, sources_open) = (self.readers.sources(), self.readers.running());
+        Status {
+            ready: vminfod_connected && sources_open == sources,
+            live: event_loop <= self.stuck_after && stuck_zones.is_empty(),
+            vminfod_connected,
+            sources,
+            sources_open,
+            event_loop_secs: event_loop.as_secs(),
+            stuck_zones,
+        }
+    }
+}
+
+/// Send a client the current status
+fn serve_client(mut stream: UnixStream, health: &Health) -> io::Result<()> {
+    let status = health.status(vminfod_client::connected());
+    let mut json = serde_json::to_vec(&status)?;
+    json.push(b'\n');
+    stream.write_all(&json)
+}
+
+/// Bind the health socket. This is done before we chroot, since the socket lives outside of the
+/// log directory, while answering clients waits for the pipeline to be running.
+pub fn bind(path: &Path) -> io::Result<UnixListener> {
+    let listener = fileutils::bind_private_socket(path)?;
+    info!("health checks listening on {}", path.display());
+    Ok(listener)
+}
+
+/// Start answering the clients of a socket from `bind`
+pub fn start_health(listener: UnixListener, health: Health) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("health".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let res = stream.and_then(|stream| serve_client(stream, &health));
+                if let Err(e) = res {
+                    debug!("failed to answer health check: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+
+    #[test]
+    fn health_is_reported() {
+        let readers = Arc::new(ReaderState::new(2));
+        readers.started();
+        let health = Health {
+            readers: Arc::clone(&readers),
+            fanout: Arc::new(Heartbeat::new()),
+            loggers: Arc::new(Mutex::new(HashMap::new())),
+            stuck_after: Duration::from_secs(60),
+        };
+        let status = health.status(true);
+        assert!(!status.ready, "a source isn't being read");
+        assert!(status.live);
+        readers.started();
+        assert!(health.status(true).ready);
+        assert!(!health.status(false).ready, "vminfod isn't connected");
+
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests/health");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("health.sock");
+        let listener = bind(&path).expect("failed to bind health socket");
+        let _handle = start_health(listener, health).expect("failed to start health checks");
+        let client = UnixStream::connect(&path).expect("failed to connect");
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["sources_open"], 2);
+        assert_eq!(json["live"], true);
+        assert_eq!(json["stuck_zones"], serde_json::json!([]));
+
+        std::fs::remove_dir_all(&dir).expect("failed to cleanup test dir");
+    }
+}
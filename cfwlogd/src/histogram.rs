@@ -0,0 +1,83 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Histograms of how long a zone's events take to be written and how they're written, kept in its
+//! `ZoneCounters` and exported by the "cmon" module. Every histogram has the same buckets, powers
+//! of 4 from 1 to 65536, which cover milliseconds up to about a minute and batches of up to the
+//! most events a `Logger` takes at once. Like Prometheus' histograms the buckets are cumulative
+//! when they're read, each counting the observations at or under its bound.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The upper bound of each bucket, apart from the last which has none
+pub const BOUNDS: [u64; 9] = [1, 4, 16, 64, 256, 1024, 4096, 16384, 65536];
+
+/// Counts of observations by bucket, along with their sum, neither of which are ever reset
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BOUNDS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, value: u64) {
+        let bucket = BOUNDS
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(BOUNDS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Each bucket's bound, `None` for the last, along with the observations at or under it
+    pub fn cumulative(&self) -> Vec<(Option<u64>, u64)> {
+        let mut count = 0;
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, bucket)| {
+                count += bucket.load(Ordering::Relaxed);
+                (BOUNDS.get(i).copied(), count)
+            })
+            .collect()
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+/// Distributions of how a zone's events were written, see `ZoneCounters::histograms`
+#[derive(Debug, Default)]
+pub struct Histograms {
+    /// Milliseconds from an event's timestamp to the write of it to the zone's log completing
+    pub latency_ms: Histogram,
+    /// The records each write to the zone's log took
+    pub batch_records: Histogram,
+    /// Milliseconds each sync of the zone's log took
+    pub fsync_ms: Histogram,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn observations_are_bucketed() {
+        let histogram = Histogram::default();
+        for value in &[0, 1, 2, 4, 5, 70_000] {
+            histogram.observe(*value);
+        }
+        let buckets = histogram.cumulative();
+        assert_eq!(buckets.len(), 10);
+        assert_eq!(buckets[0], (Some(1), 2));
+        assert_eq!(buckets[1], (Some(4), 4));
+        assert_eq!(buckets[2], (Some(16), 5));
+        assert_eq!(buckets[8], (Some(65536), 5));
+        assert_eq!(buckets[9], (None, 6), "the last bucket counts everything");
+        assert_eq!(histogram.sum(), 70_012);
+    }
+}
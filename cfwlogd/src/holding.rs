@@ -0,0 +1,213 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Events for zonedids that aren't in `Vmobjs` yet. At startup, and while a zone is being
+//! provisioned, the device can hand us a zone's events before vminfod has told us about the zone,
+//! so rather than dropping them the event fanout thread holds up to "unknown_zones.max_events" of
+//! them for each such zone. Once vminfod reports the zone the held events are queued for its
+//! `Logger` ahead of anything newer, and if the zone hasn't shown up within
+//! "unknown_zones.hold_secs" they are written to the unknown zone log instead, along with their
+//! zonedid since there's no vm to attribute them to. Since the device is read while we wait for
+//! vminfod at startup, nothing is expired until vminfod's first `Ready` event has been processed
+//! and every zone's hold starts over then.
+
+use crate::config::UnknownZoneConfig;
+use crate::sink::SchemaVersion;
+use crate::zones::Zonedid;
+use cfwevent::parser::CfwEvent;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Name of the log, in the log directory, that events of zones that never showed up go to
+pub const UNKNOWN_ZONE_LOG: &str = "unknown-zone.log";
+
+/// A record of the unknown zone log
+#[derive(Serialize)]
+struct UnknownZoneRecord<'a> {
+    schema_version: SchemaVersion,
+    #[serde(flatten)]
+    event: &'a CfwEvent,
+    zonedid: Zonedid,
+}
+
+/// The events held for a single zone
+struct Held {
+    events: Vec<CfwEvent>,
+    expires: Instant,
+    /// Events that didn't fit
+    dropped: u64,
+}
+
+pub struct Holding {
+    zones: HashMap<Zonedid, Held>,
+    hold: Duration,
+    max_events: usize,
+}
+
+impl Holding {
+    pub fn new(config: UnknownZoneConfig) -> Self {
+        Holding {
+            zones: HashMap::new(),
+            hold: Duration::from_secs(config.hold_secs),
+            max_events: config.max_events,
+        }
+    }
+
+    /// Hold an event for a zone we don't know about, returning it if the zone's buffer is full
+    pub fn hold(&mut self, event: CfwEvent) -> Option<CfwEvent> {
+        let zonedid = event.zone();
+        let max_events = self.max_events;
+        let expires = Instant::now() + self.hold;
+        let held = self.zones.entry(zonedid).or_insert_with(|| {
+            info!(
+                "holding events for unknown zonedid {} until vminfod reports it",
+                zonedid
+            );
+            Held {
+                events: vec![],
+                expires,
+                dropped: 0,
+            }
+        });
+        if held.events.len() >= max_events {
+            if held.dropped == 0 {
+                // CMON TRITON-1755
+                error!(
+                    "too many events are held for unknown zonedid {}, dropping the rest",
+                    zonedid
+                );
+            }
+            held.dropped += 1;
+            return Some(event);
+        }
+        held.events.push(event);
+        None
+    }
+
+    /// Take the events held for a zone, oldest first
+    pub fn release(&mut self, zonedid: Zonedid) -> Vec<CfwEvent> {
+        match self.zones.remove(&zonedid) {
+            Some(held) => {
+                info!(
+                    "vminfod reported zonedid {}, releasing {} held events ({} dropped)",
+                    zonedid,
+                    held.events.len(),
+                    held.dropped
+                );
+                held.events
+            }
+            None => vec![],
+        }
+    }
+
+    /// When the zone that has been held the longest expires
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.zones.values().map(|held| held.expires).min()
+    }
+
+    /// Start every zone's hold over from `now`, once vminfod has told us which zones exist
+    pub fn restart(&mut self, now: Instant) {
+        let expires = now + self.hold;
+        for held in self.zones.values_mut() {
+            held.expires = expires;
+        }
+    }
+
+    /// Take the events of every zone that has been held for too long
+    pub fn expired(&mut self, now: Instant) -> Vec<(Zonedid, Vec<CfwEvent>)> {
+        let expired: Vec<Zonedid> = self
+            .zones
+            .iter()
+            .filter(|(_, held)| held.expires <= now)
+            .map(|(zonedid, _)| *zonedid)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|zonedid| Some((zonedid, self.zones.remove(&zonedid)?.events)))
+            .collect()
+    }
+
+    /// Take the events of every zone, as nothing more is going to show up
+    pub fn drain(&mut self) -> Vec<(Zonedid, Vec<CfwEvent>)> {
+        self.zones
+            .drain()
+            .map(|(zonedid, held)| (zonedid, held.events))
+            .collect()
+    }
+}
+
+/// Append a zone's events to the unknown zone log in `dir`
+pub fn write_unknown(dir: &Path, zonedid: Zonedid, events: &[CfwEvent]) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(UNKNOWN_ZONE_LOG))?;
+    let mut writer = BufWriter::new(file);
+    for event in events {
+        let record = UnknownZoneRecord {
+            schema_version: SchemaVersion,
+            event,
+            zonedid,
+        };
+        serde_json::to_writer(&mut writer, &record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    fn event() -> CfwEvent {
+        let event = testutils::generate_event();
+        parser::cfwevent_parse(event.as_bytes()).unwrap().1
+    }
+
+    #[test]
+    fn events_are_held_until_released_or_expired() {
+        let mut holding = Holding::new(UnknownZoneConfig {
+            hold_secs: 30,
+            max_events: 2,
+        });
+        let zonedid = event().zone();
+        assert!(holding.hold(event()).is_none());
+        assert!(holding.hold(event()).is_none());
+        assert!(holding.hold(event()).is_some(), "the zone's buffer is full");
+        assert_eq!(holding.release(zonedid).len(), 2);
+        assert!(holding.release(zonedid).is_empty(), "released only once");
+
+        holding.hold(event());
+        let later = holding.next_expiry().unwrap() + Duration::from_secs(10);
+        holding.restart(later - Duration::from_secs(30));
+        let expires = holding.next_expiry().unwrap();
+        assert_eq!(expires, later, "the hold starts over");
+        assert!(holding.expired(expires - Duration::from_secs(1)).is_empty());
+        let expired = holding.expired(expires);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, zonedid);
+        assert_eq!(holding.next_expiry(), None);
+    }
+
+    #[test]
+    fn unknown_zone_log() {
+        let dir = Path::new(crate::logger::LOG_DIR).join("holding-tests");
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(dir.join(UNKNOWN_ZONE_LOG));
+        write_unknown(&dir, 42, &[event(), event()]).unwrap();
+        let log = std::fs::read_to_string(dir.join(UNKNOWN_ZONE_LOG)).unwrap();
+        assert_eq!(log.lines().count(), 2);
+        let record: serde_json::Value = serde_json::from_str(log.lines().next().unwrap()).unwrap();
+        assert_eq!(record["zonedid"], 42, "records carry their zonedid");
+        assert!(record["event"].is_string());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,85 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Helpers for the optional features that make HTTP requests to other services. Once we chroot
+//! into the log directory there is no resolver configuration left to consult, so every service's
+//! address is resolved once at startup and all of its requests are sent to that address. Most of
+//! these services live on the admin network and are only reached over plain "http://",
+//! Elasticsearch and the webhook sink also take "https://" urls.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+
+/// Split the "host:port" out of an "http://" or "https://" url, defaulting the port to 80 or 443
+fn url_authority(url: &str) -> io::Result<(&str, u16)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid url {}", url));
+    let (rest, default_port) = if url.starts_with("http://") {
+        (&url["http://".len()..], 80)
+    } else if url.starts_with("https://") {
+        (&url["https://".len()..], 443)
+    } else {
+        return Err(invalid());
+    };
+    let authority = rest.split('/').next().unwrap_or("");
+    let mut parts = authority.rsplitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(port), Some(host)) if !host.is_empty() => {
+            Ok((host, port.parse().map_err(|_| invalid())?))
+        }
+        (Some(host), None) if !host.is_empty() => Ok((host, default_port)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Resolve the host in `url` and return an agent that sends every request to it. This must
+/// happen before we chroot.
+pub fn pinned_agent(url: &str) -> io::Result<ureq::Agent> {
+    let addrs: Vec<SocketAddr> = url_authority(url)?.to_socket_addrs()?.collect();
+    let mut agent = ureq::agent();
+    agent.set_resolver(move |_: &str| Ok(addrs.clone()));
+    Ok(agent)
+}
+
+/// Turn a failed request or an error status into an `io::Error`
+pub fn check_response(resp: ureq::Response) -> io::Result<ureq::Response> {
+    if let Some(e) = resp.synthetic_error() {
+        return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+    }
+    if !resp.ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("server responded with {}", resp.status_line()),
+        ));
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn http_urls() {
+        assert_eq!(
+            url_authority("http://fwapi.coal.joyent.us").unwrap(),
+            ("fwapi.coal.joyent.us", 80)
+        );
+        assert_eq!(
+            url_authority("http://10.99.99.22:8080/").unwrap(),
+            ("10.99.99.22", 8080)
+        );
+        assert_eq!(
+            url_authority("https://hooks.example.com/cfw").unwrap(),
+            ("hooks.example.com", 443)
+        );
+        assert!(
+            url_authority("ftp://fwapi.coal.joyent.us").is_err(),
+            "only http and https are supported"
+        );
+        assert!(url_authority("http://fwapi:http").is_err());
+        assert!(url_authority("http://").is_err());
+    }
+}
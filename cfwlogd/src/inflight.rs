@@ -0,0 +1,89 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Per-zone counts of the events that were read from the device but haven't been taken off the
+//! queue by the fanout thread yet. A deleted zone is only retired once none of its events are left
+//! on that queue, and its `Logger` drains the events queued for it before it closes the log and
+//! removes the zone from `Vmobjs`, so a zone is held onto exactly as long as any of its events is
+//! on its way to disk. Zones without events in flight have no entry, so the counts don't grow
+//! with every zone ever seen on a CN with churny zones.
+
+use crate::zones::Zonedid;
+use cfwevent::parser::CfwEvent;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+fn release(zones: &mut HashMap<Zonedid, usize>, zonedid: Zonedid) {
+    if let Some(count) = zones.get_mut(&zonedid) {
+        *count -= 1;
+        if *count == 0 {
+            zones.remove(&zonedid);
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct InFlight {
+    zones: Mutex<HashMap<Zonedid, usize>>,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        InFlight::default()
+    }
+
+    /// An event for `zonedid` is about to be queued for the fanout thread
+    pub fn queued(&self, zonedid: Zonedid) {
+        *self.zones.lock().unwrap().entry(zonedid).or_insert(0) += 1;
+    }
+
+    /// An event for `zonedid` didn't make it onto the queue after all
+    pub fn dropped(&self, zonedid: Zonedid) {
+        release(&mut self.zones.lock().unwrap(), zonedid);
+    }
+
+    /// The fanout thread took the events off the queue
+    pub fn taken(&self, events: &[CfwEvent]) {
+        let mut zones = self.zones.lock().unwrap();
+        for event in events {
+            release(&mut zones, event.zone());
+        }
+    }
+
+    /// Whether any of the zone's events are still on their way to the fanout thread
+    pub fn referenced(&self, zonedid: Zonedid) -> bool {
+        self.zones.lock().unwrap().contains_key(&zonedid)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    #[test]
+    fn zones_are_referenced_while_events_are_queued() {
+        let zone = testutils::create_zone();
+        let event = testutils::generate_event_for_zone(&zone);
+        let event = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        let inflight = InFlight::new();
+        assert!(!inflight.referenced(zone.zonedid));
+
+        inflight.queued(zone.zonedid);
+        inflight.queued(zone.zonedid);
+        inflight.taken(&[event.clone()]);
+        assert!(inflight.referenced(zone.zonedid), "one event is left");
+        inflight.dropped(zone.zonedid);
+        assert!(!inflight.referenced(zone.zonedid));
+        assert!(inflight.zones.lock().unwrap().is_empty(), "nothing is kept");
+
+        inflight.taken(&[event]);
+        assert!(
+            !inflight.referenced(zone.zonedid),
+            "unaccounted events are ignored"
+        );
+    }
+}
@@ -6,7 +6,7 @@
 
 //! This is the implementation of an EventSource for ipfilter.
 
-use crate::events::EventSource;
+use crate::source::{EventSource, SourceStats};
 use libc::c_int;
 use std::fs::File;
 use std::io::Read;
@@ -41,6 +41,26 @@ impl IpfevDevice {
         info!("connected to {}", device.display());
         Ok(IpfevDevice { file })
     }
+
+    /// Wrap the device's descriptor the previous cfwlogd handed over, see the "handoff" module
+    pub fn inherit(file: File, device: &Path) -> Self {
+        info!("took over {} from the previous cfwlogd", device.display());
+        IpfevDevice { file }
+    }
+
+    /// Read the device's current configuration and counters
+    fn config(&self) -> std::io::Result<Ipfcfwcfg> {
+        let mut cfg = Ipfcfwcfg::default();
+        // This is unsafe because we are calling out to the device via an ioctl, and because we are
+        // dealing with a raw pointer being passed into the C interface.
+        unsafe {
+            let cfg_ptr = &mut cfg as *mut Ipfcfwcfg;
+            match libc::ioctl(self.file.as_raw_fd(), SIOCIPFCFWCFG, cfg_ptr) {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(cfg),
+            }
+        }
+    }
 }
 
 impl EventSource for IpfevDevice {
@@ -54,15 +74,23 @@ impl EventSource for IpfevDevice {
 
     /// Dynamically read the largest known event size and the current sizing of the ring buffer
     fn event_sizing(&mut self) -> std::io::Result<(usize, usize)> {
-        let mut cfg = Ipfcfwcfg::default();
-        // This is unsafe because we are calling out to the device via an ioctl, and because we are
-        // dealing with a raw pointer being passed into the C interface.
-        unsafe {
-            let cfg_ptr = &mut cfg as *mut Ipfcfwcfg;
-            match libc::ioctl(self.file.as_raw_fd(), SIOCIPFCFWCFG, cfg_ptr) {
-                -1 => Err(std::io::Error::last_os_error()),
-                _ => Ok((cfg.max_event_size as usize, cfg.ring_size as usize)),
-            }
-        }
+        let cfg = self.config()?;
+        Ok((cfg.max_event_size as usize, cfg.ring_size as usize))
+    }
+
+    /// The device keeps count of the events it has reported and the events it had to drop because
+    /// the ring buffer was full
+    fn stats(&mut self) -> std::io::Result<SourceStats> {
+        let cfg = self.config()?;
+        Ok(SourceStats {
+            received: cfg.num_reports,
+            dropped: cfg.num_drops,
+        })
+    }
+
+    /// The device's ring buffer keeps filling up for as long as the device is open, so a
+    /// duplicate of its descriptor can be handed over without losing events
+    fn descriptor(&self) -> Option<File> {
+        self.file.try_clone().ok()
     }
 }
@@ -0,0 +1,577 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that exports traffic records to an IPFIX (RFC 7011) collector over UDP or TCP, so that
+//! existing NetFlow tooling can consume them. Every event is exported as one data record, using
+//! the template for IPv4 records when both of its addresses are IPv4-mapped and the one for IPv6
+//! records otherwise. Besides the standard information elements for the addresses, ports,
+//! protocol, direction and firewall event, each record carries the rule uuid, vm uuid and event
+//! type as enterprise-specific elements under "ipfix.enterprise_number". Records of unknown events
+//! aren't exported.
+//!
+//! Like the syslog collector, the collector's address is resolved when cfwlogd starts, and every
+//! zone's sink shares a single exporting process with one sequence number. Over UDP the templates
+//! are sent ahead of the first message and again every "ipfix.template_secs", and over TCP once
+//! per connection. Records written while the collector is unreachable are dropped, and a lost TCP
+//! connection is retried at most every `RETRY_INTERVAL`.
+
+use crate::config::{Config, IpfixConfig, IpfixTransport};
+use crate::sink::{Record, Sink, SinkStats};
+use cfwevent::parser::{self, CfwEvType, CfwEvent, Direction, Protocol, TrafficEvent};
+use std::io::{self, Write};
+use std::net::{Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait after a failed connection before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET: u16 = 2;
+const IPV4_TEMPLATE: u16 = 256;
+const IPV6_TEMPLATE: u16 = 257;
+/// Largest message sent over UDP, which keeps them from being fragmented on a 1500 byte MTU
+const MAX_UDP_MESSAGE: usize = 1400;
+/// Largest message sent over TCP, the most a message's 16 bit length can describe
+const MAX_TCP_MESSAGE: usize = 65_535;
+
+/// The enterprise-specific information elements
+const RULE_UUID: u16 = 1;
+const VM_UUID: u16 = 2;
+const ACTION: u16 = 3;
+
+/// The values of the "action" element
+const ACTION_BLOCK: u8 = 1;
+const ACTION_BEGIN: u8 = 2;
+const ACTION_END: u8 = 3;
+
+/// The values of firewallEvent, see RFC 7011's companion IANA registry
+const FIREWALL_CREATED: u8 = 1;
+const FIREWALL_DELETED: u8 = 2;
+const FIREWALL_DENIED: u8 = 3;
+
+/// An information element of a template and its length, enterprise-specific ones are marked by
+/// having the enterprise bit set
+type Field = (u16, u16);
+
+const ENTERPRISE_BIT: u16 = 0x8000;
+
+/// flowStartMilliseconds, the addresses, sourceTransportPort, destinationTransportPort,
+/// protocolIdentifier, flowDirection, firewallEvent, the ICMP type and code, and the cfw elements
+fn template_fields(ipv6: bool) -> [Field; 12] {
+    let (addr_len, source, destination, icmp) = match ipv6 {
+        false => (4, 8, 12, 32),
+        true => (16, 27, 28, 139),
+    };
+    [
+        (152, 8),
+        (source, addr_len),
+        (destination, addr_len),
+        (7, 2),
+        (11, 2),
+        (4, 1),
+        (61, 1),
+        (233, 1),
+        (icmp, 2),
+        (ENTERPRISE_BIT | RULE_UUID, 16),
+        (ENTERPRISE_BIT | VM_UUID, 16),
+        (ENTERPRISE_BIT | ACTION, 1),
+    ]
+}
+
+lazy_static! {
+    /// The exporter set up at startup, if there is one
+    static ref EXPORTER: Mutex<Option<Arc<Exporter>>> = Mutex::new(None);
+}
+
+enum Transport {
+    Udp(UdpSocket),
+    Tcp {
+        stream: Option<TcpStream>,
+        /// When the connection may next be attempted after a failure
+        retry_at: Option<Instant>,
+    },
+}
+
+struct Exporter {
+    addrs: Vec<SocketAddr>,
+    enterprise_number: u32,
+    observation_domain: u32,
+    template_interval: Duration,
+    state: Mutex<State>,
+}
+
+struct State {
+    transport: Transport,
+    /// Data records exported so far, modulo 2^32
+    sequence: u32,
+    /// When the templates were last sent, None until they are sent on the current connection
+    templates_sent: Option<Instant>,
+    /// Records dropped since the collector was last reachable
+    dropped: u64,
+}
+
+/// A message being put together, see RFC 7011 section 3
+struct Message {
+    buf: Vec<u8>,
+    /// The template and start of the set being appended to
+    set: Option<(u16, usize)>,
+    records: u32,
+}
+
+impl Message {
+    fn new(export_time: u32, sequence: u32, observation_domain: u32) -> Self {
+        let mut buf = Vec::with_capacity(MAX_UDP_MESSAGE);
+        buf.extend_from_slice(&VERSION.to_be_bytes());
+        // The length is filled in once the message is finished
+        buf.extend_from_slice(&[0, 0]);
+        buf.extend_from_slice(&export_time.to_be_bytes());
+        buf.extend_from_slice(&sequence.to_be_bytes());
+        buf.extend_from_slice(&observation_domain.to_be_bytes());
+        Message {
+            buf,
+            set: None,
+            records: 0,
+        }
+    }
+
+    fn start_set(&mut self, id: u16) {
+        self.end_set();
+        self.set = Some((id, self.buf.len()));
+        self.buf.extend_from_slice(&id.to_be_bytes());
+        self.buf.extend_from_slice(&[0, 0]);
+    }
+
+    fn end_set(&mut self) {
+        if let Some((_, start)) = self.set.take() {
+            let len = (self.buf.len() - start) as u16;
+            self.buf[start + 2..start + 4].copy_from_slice(&len.to_be_bytes());
+        }
+    }
+
+    /// Append a template set with both templates
+    fn push_templates(&mut self, enterprise_number: u32) {
+        self.start_set(TEMPLATE_SET);
+        for &(id, ipv6) in &[(IPV4_TEMPLATE, false), (IPV6_TEMPLATE, true)] {
+            let fields = template_fields(ipv6);
+            self.buf.extend_from_slice(&id.to_be_bytes());
+            self.buf
+                .extend_from_slice(&(fields.len() as u16).to_be_bytes());
+            for (element, len) in fields.iter() {
+                self.buf.extend_from_slice(&element.to_be_bytes());
+                self.buf.extend_from_slice(&len.to_be_bytes());
+                if element & ENTERPRISE_BIT != 0 {
+                    self.buf.extend_from_slice(&enterprise_number.to_be_bytes());
+                }
+            }
+        }
+        self.end_set();
+    }
+
+    /// Append a data record, in the set for its template
+    fn push_record(&mut self, template: u16, data: &[u8]) {
+        if self.set.map(|(id, _)| id) != Some(template) {
+            self.start_set(template);
+        }
+        self.buf.extend_from_slice(data);
+        self.records += 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        self.end_set();
+        let len = self.buf.len() as u16;
+        self.buf[2..4].copy_from_slice(&len.to_be_bytes());
+        self.buf
+    }
+}
+
+/// The protocol number of a protocol, unknown ones are exported as the reserved 255
+fn protocol_number(protocol: &Protocol) -> u8 {
+    match protocol {
+        Protocol::ICMP => 1,
+        Protocol::TCP => 6,
+        Protocol::UDP => 17,
+        Protocol::ESP => 50,
+        Protocol::AH => 51,
+        Protocol::ICMPV6 => 58,
+        Protocol::UNKNOWN => 255,
+    }
+}
+
+/// Encode an event as a data record, returning the template it's for
+fn encode_record(event: &TrafficEvent, vm: &str, out: &mut Vec<u8>) -> u16 {
+    out.clear();
+    out.extend_from_slice(&(event.timestamp.timestamp_millis() as u64).to_be_bytes());
+    let addrs = (
+        parser::ipv4_mapped(&event.source_ip),
+        parser::ipv4_mapped(&event.destination_ip),
+    );
+    let template = match addrs {
+        (Some(source), Some(destination)) => {
+            out.extend_from_slice(&source.octets());
+            out.extend_from_slice(&destination.octets());
+            IPV4_TEMPLATE
+        }
+        _ => {
+            out.extend_from_slice(&event.source_ip.octets());
+            out.extend_from_slice(&event.destination_ip.octets());
+            IPV6_TEMPLATE
+        }
+    };
+    // ICMP events report their type and code in place of the ports, IPFIX has an element for that
+    let (ports, icmp) = match event.icmp {
+        Some(icmp) => ((0, 0), u16::from_be_bytes([icmp.icmp_type, icmp.icmp_code])),
+        None => ((event.source_port, event.destination_port), 0),
+    };
+    out.extend_from_slice(&ports.0.to_be_bytes());
+    out.extend_from_slice(&ports.1.to_be_bytes());
+    out.push(protocol_number(&event.protocol));
+    out.push(match event.direction {
+        Direction::In => 0,
+        Direction::Out => 1,
+    });
+    let (firewall_event, action) = match event.event {
+        CfwEvType::Block => (FIREWALL_DENIED, ACTION_BLOCK),
+        CfwEvType::Begin => (FIREWALL_CREATED, ACTION_BEGIN),
+        CfwEvType::End => (FIREWALL_DELETED, ACTION_END),
+        CfwEvType::Unknown => (0, 0),
+    };
+    out.push(firewall_event);
+    out.extend_from_slice(&icmp.to_be_bytes());
+    out.extend_from_slice(event.rule_uuid.as_bytes());
+    out.extend_from_slice(
+        Uuid::parse_str(vm)
+            .unwrap_or_else(|_| Uuid::nil())
+            .as_bytes(),
+    );
+    out.push(action);
+    template
+}
+
+impl Exporter {
+    fn max_message(&self, state: &State) -> usize {
+        match state.transport {
+            Transport::Udp(_) => MAX_UDP_MESSAGE,
+            Transport::Tcp { .. } => MAX_TCP_MESSAGE,
+        }
+    }
+
+    /// Whether the templates have to go ahead of the next message
+    fn templates_due(&self, state: &State) -> bool {
+        match (&state.transport, state.templates_sent) {
+            (_, None) => true,
+            (Transport::Udp(_), Some(sent)) => sent.elapsed() >= self.template_interval,
+            (Transport::Tcp { .. }, Some(_)) => false,
+        }
+    }
+
+    /// Encode the events into as many messages as they need, advancing the sequence number
+    fn encode(&self, state: &mut State, events: &[(&TrafficEvent, &str)]) -> Vec<Vec<u8>> {
+        let export_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs() as u32);
+        let max_message = self.max_message(state);
+        let mut messages = vec![];
+        let mut message = Message::new(export_time, state.sequence, self.observation_domain);
+        if self.templates_due(state) {
+            message.push_templates(self.enterprise_number);
+            state.templates_sent = Some(Instant::now());
+        }
+        let mut data = vec![];
+        for (event, vm) in events {
+            let template = encode_record(event, vm, &mut data);
+            // Leaving room for the header of a new set
+            if message.buf.len() + data.len() + 4 > max_message {
+                state.sequence = state.sequence.wrapping_add(message.records);
+                messages.push(message.finish());
+                message = Message::new(export_time, state.sequence, self.observation_domain);
+            }
+            message.push_record(template, &data);
+        }
+        state.sequence = state.sequence.wrapping_add(message.records);
+        messages.push(message.finish());
+        messages
+    }
+
+    /// Export the traffic records, returning the number of records and bytes that were sent
+    fn export(&self, records: &[Record<'_>]) -> io::Result<(u64, u64)> {
+        let events: Vec<(&TrafficEvent, &str)> = records
+            .iter()
+            .filter_map(|record| match &record.event {
+                CfwEvent::Traffic(event) => Some((event, record.vm)),
+                CfwEvent::Unknown(_) => None,
+            })
+            .collect();
+        if events.is_empty() {
+            return Ok((0, 0));
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Err(e) = self.connect(&mut state) {
+            state.dropped += events.len() as u64;
+            return match e.kind() {
+                io::ErrorKind::NotConnected => Ok((0, 0)),
+                _ => Err(e),
+            };
+        }
+        let mut bytes = 0;
+        for message in self.encode(&mut state, &events) {
+            if let Err(e) = self.send(&mut state, &message) {
+                state.dropped += events.len() as u64;
+                return Err(e);
+            }
+            bytes += message.len() as u64;
+        }
+        Ok((events.len() as u64, bytes))
+    }
+
+    /// Connect to the collector over TCP if we aren't already, failing with `NotConnected` until
+    /// a lost connection may be retried
+    fn connect(&self, state: &mut State) -> io::Result<()> {
+        let (stream, retry_at) = match &mut state.transport {
+            Transport::Udp(_) => return Ok(()),
+            Transport::Tcp { stream, retry_at } => (stream, retry_at),
+        };
+        if stream.is_some() {
+            return Ok(());
+        }
+        if retry_at.map_or(false, |at| Instant::now() < at) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "waiting to reconnect to the IPFIX collector",
+            ));
+        }
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+        for addr in &self.addrs {
+            match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+                Ok(connected) => {
+                    connected.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    *stream = Some(connected);
+                    *retry_at = None;
+                    // A new connection is a new transport session, which needs the templates
+                    state.templates_sent = None;
+                    if state.dropped > 0 {
+                        warn!(
+                            "reconnected to the IPFIX collector, {} records were dropped while \
+                             disconnected",
+                            state.dropped
+                        );
+                        state.dropped = 0;
+                    }
+                    return Ok(());
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        *retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        Err(last_err)
+    }
+
+    fn send(&self, state: &mut State, message: &[u8]) -> io::Result<()> {
+        match &mut state.transport {
+            Transport::Udp(socket) => socket.send(message).map(|_| ()),
+            Transport::Tcp { stream, retry_at } => {
+                let result = match stream {
+                    Some(connected) => connected.write_all(message),
+                    None => Err(io::Error::from(io::ErrorKind::NotConnected)),
+                };
+                if result.is_err() {
+                    *stream = None;
+                    *retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                }
+                result
+            }
+        }
+    }
+}
+
+/// Resolve the collector, and bind the socket records are sent from over UDP, which has to happen
+/// before we chroot
+pub fn init(config: &IpfixConfig) -> io::Result<()> {
+    let addrs: Vec<SocketAddr> = config.collector.to_socket_addrs()?.collect();
+    let transport = match config.transport {
+        IpfixTransport::Udp => {
+            let addr = addrs.first().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "no addresses to send to")
+            })?;
+            let local: SocketAddr = match addr {
+                SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+                SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+            };
+            let socket = UdpSocket::bind(local)?;
+            socket.connect(addr)?;
+            Transport::Udp(socket)
+        }
+        IpfixTransport::Tcp => Transport::Tcp {
+            stream: None,
+            retry_at: None,
+        },
+    };
+    let exporter = Exporter {
+        addrs,
+        enterprise_number: config.enterprise_number,
+        observation_domain: config.observation_domain,
+        template_interval: Duration::from_secs(config.template_secs),
+        state: Mutex::new(State {
+            transport,
+            sequence: 0,
+            templates_sent: None,
+            dropped: 0,
+        }),
+    };
+    info!(
+        "exporting records to the IPFIX collector at {}",
+        config.collector
+    );
+    *EXPORTER.lock().unwrap() = Some(Arc::new(exporter));
+    Ok(())
+}
+
+/// Open a zone's IPFIX sink, if an exporter was set up at startup
+pub fn open_sink() -> Option<IpfixSink> {
+    let exporter = Arc::clone(EXPORTER.lock().unwrap().as_ref()?);
+    Some(IpfixSink {
+        exporter,
+        stats: SinkStats::default(),
+    })
+}
+
+pub struct IpfixSink {
+    exporter: Arc<Exporter>,
+    stats: SinkStats,
+}
+
+impl Sink for IpfixSink {
+    fn name(&self) -> &str {
+        "ipfix"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        let (records, bytes) = self.exporter.export(records)?;
+        self.stats.records += records;
+        self.stats.bytes += bytes;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every batch is sent as soon as it's written
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reload(&mut self, _config: &Arc<Config>) {}
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(vm: &str) -> Record<'_> {
+        let event = testutils::generate_event();
+        Record::new(parser::cfwevent_parse(event.as_bytes()).unwrap().1, vm, "")
+    }
+
+    fn exporter(addr: SocketAddr) -> Exporter {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(addr).unwrap();
+        Exporter {
+            addrs: vec![addr],
+            enterprise_number: 32473,
+            observation_domain: 7,
+            template_interval: Duration::from_secs(600),
+            state: Mutex::new(State {
+                transport: Transport::Udp(socket),
+                sequence: 0,
+                templates_sent: None,
+                dropped: 0,
+            }),
+        }
+    }
+
+    fn u16_at(buf: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes([buf[at], buf[at + 1]])
+    }
+
+    fn u32_at(buf: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+    }
+
+    #[test]
+    fn records_are_exported_over_udp() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let exporter = exporter(collector.local_addr().unwrap());
+        let vm = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+        let mut buf = [0; MAX_UDP_MESSAGE];
+
+        let records = vec![record(vm), record(vm)];
+        assert_eq!(exporter.export(&records).unwrap().0, 2);
+        let len = collector.recv(&mut buf).unwrap();
+        let message = &buf[..len];
+        assert_eq!(u16_at(message, 0), VERSION);
+        assert_eq!(u16_at(message, 2) as usize, len);
+        assert_eq!(u32_at(message, 8), 0, "no records were exported before");
+        assert_eq!(u32_at(message, 12), 7);
+        assert_eq!(u16_at(message, 16), TEMPLATE_SET, "the templates go first");
+        let templates = u16_at(message, 18) as usize;
+        let data = &message[16 + templates..];
+        assert_eq!(u16_at(data, 0), IPV4_TEMPLATE, "both addresses are mapped");
+        assert_eq!(u16_at(data, 2) as usize, data.len(), "the last set");
+        let record = &data[4..];
+        assert_eq!(record.len(), 2 * 58);
+        assert_eq!(&record[8..12], &[172, 24, 4, 150]);
+        assert_eq!(record[20], 6, "TCP");
+        assert_eq!(record[22], FIREWALL_DENIED);
+        assert_eq!(&record[41..57], Uuid::parse_str(vm).unwrap().as_bytes());
+        assert_eq!(record[57], ACTION_BLOCK);
+
+        exporter.export(&records[..1]).unwrap();
+        let len = collector.recv(&mut buf).unwrap();
+        assert_eq!(u32_at(&buf, 8), 2, "the sequence counts data records");
+        assert_eq!(u16_at(&buf, 16), IPV4_TEMPLATE, "the templates aren't due");
+        assert_eq!(len, 16 + 4 + 58);
+    }
+
+    #[test]
+    fn large_batches_are_split() {
+        let exporter = exporter("127.0.0.1:9".parse().unwrap());
+        let record = record("vm1");
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        let events = vec![(event, "vm1"); 100];
+        let mut state = exporter.state.lock().unwrap();
+        let messages = exporter.encode(&mut state, &events);
+        assert!(messages.len() > 1);
+        for message in &messages {
+            assert!(message.len() <= MAX_UDP_MESSAGE);
+            assert_eq!(u16_at(message, 2) as usize, message.len());
+        }
+        let first = (messages[0].len() - 16 - u16_at(&messages[0], 18) as usize - 4) / 58;
+        assert_eq!(
+            u32_at(&messages[1], 8) as usize,
+            first,
+            "the records before it"
+        );
+        assert_eq!(state.sequence, 100);
+    }
+}
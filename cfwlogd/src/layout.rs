@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Where each zone's logs are written. By default that's "<owner_uuid>/<vm>" in the log
+//! directory, grouping each owner's zones together, or just "<vm>" with the config's "log_layout"
+//! set to "vm". The config's "log_dirs" entries can move the zones of a vm or an owner elsewhere
+//! and name their active log file differently, and a zone's `LOG_DIR_TAG` tag moves just that
+//! zone. An entry for the vm takes precedence over one for its owner, and the tag over both.
+//!
+//! The entries' "permissions" likewise override the config's "log_permissions", so an owner's
+//! entry can hand the logs of all of the owner's zones to the uid their in-zone tooling or log
+//! shipper runs as. The zone's directory and files are chowned and chmoded as they are opened.
+//!
+//! Since we chroot into the log directory the alternate directories are relative to it, so a
+//! delegated dataset or another pool has to be mounted somewhere underneath it. A zone's layout
+//! is decided when its `Logger` starts, so changes to the entries or the tag take effect once
+//! cfwlogd restarts.
+
+use crate::config::{Config, LogPermissions};
+use crate::logger::LOG_DIR;
+use serde::Deserialize;
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+use vminfod_client::Zone;
+
+/// The tag naming a directory, relative to the log directory, that the zone is logged to
+pub const LOG_DIR_TAG: &str = "triton.cfwlog_dir";
+
+/// How the zones' directories are laid out in the log directory, unless "log_dirs" or the zone's
+/// tag say otherwise
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLayout {
+    /// "<owner_uuid>/<vm>"
+    Owner,
+    /// "<vm>"
+    Vm,
+}
+
+impl Default for LogLayout {
+    fn default() -> Self {
+        LogLayout::Owner
+    }
+}
+
+impl LogLayout {
+    /// The zones' directory, as a "log_dirs" entry's dir would be written
+    fn dir(self) -> &'static str {
+        match self {
+            LogLayout::Owner => "{owner_uuid}/{vm}",
+            LogLayout::Vm => "{vm}",
+        }
+    }
+}
+
+/// Where a zone's logs are written
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layout {
+    /// The zone's log directory
+    pub dir: PathBuf,
+    /// The zone's owner, which rotated files are uploaded under, see the "upload" module
+    pub owner_uuid: String,
+    /// strftime template the active log file is named with, rather than the config's "log_name"
+    pub log_name: Option<String>,
+    /// Who owns the zone's directory and files
+    pub permissions: LogPermissions,
+}
+
+impl Layout {
+    /// The layout of the given zone
+    pub fn for_zone(zone: &Zone, config: &Config) -> Layout {
+        let mut layout = Layout {
+            dir: expand(config.log_layout.dir(), zone),
+            owner_uuid: zone.owner_uuid.clone(),
+            log_name: None,
+            permissions: config.log_permissions,
+        };
+        let vm: Option<Uuid> = zone.uuid.parse().ok();
+        let owner: Option<Uuid> = zone.owner_uuid.parse().ok();
+        let entries = &config.log_dirs;
+        let by_vm = entries.iter().find(|e| e.vm.is_some() && e.vm == vm);
+        let by_owner = entries
+            .iter()
+            .find(|e| e.owner.is_some() && e.owner == owner);
+        for entry in by_owner.into_iter().chain(by_vm) {
+            if let Some(dir) = &entry.dir {
+                layout.dir = expand(dir, zone);
+            }
+            if let Some(log_name) = &entry.log_name {
+                layout.log_name = Some(log_name.clone());
+            }
+            layout.permissions = layout.permissions.merge(entry.permissions);
+        }
+        match zone.tags.get(LOG_DIR_TAG) {
+            Some(serde_json::Value::String(dir)) => match check_dir(dir) {
+                Ok(()) => layout.dir = expand(dir, zone),
+                Err(e) => warn!("ignoring {}'s {} tag: {}", &zone.uuid, LOG_DIR_TAG, e),
+            },
+            Some(other) => warn!(
+                "ignoring {}'s {} tag, {} isn't a string",
+                &zone.uuid, LOG_DIR_TAG, other
+            ),
+            None => (),
+        }
+        layout
+    }
+
+    /// Path to the named file in the zone's log directory
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+}
+
+/// The directory relative to the log directory, with the zone's `{owner_uuid}` and `{vm}`
+/// substituted
+fn expand(dir: &str, zone: &Zone) -> PathBuf {
+    let dir = dir
+        .replace("{owner_uuid}", &zone.owner_uuid)
+        .replace("{vm}", &zone.uuid);
+    Path::new(LOG_DIR).join(dir)
+}
+
+/// Check that an alternate directory stays within the log directory, and only substitutes
+/// `{owner_uuid}` and `{vm}`
+pub fn check_dir(dir: &str) -> Result<(), String> {
+    let path = Path::new(dir);
+    if dir.is_empty() || !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(format!(
+            "\"{}\" must be a relative directory without any \"..\"",
+            dir
+        ));
+    }
+    if dir
+        .replace("{owner_uuid}", "")
+        .replace("{vm}", "")
+        .contains(|c| c == '{' || c == '}')
+    {
+        return Err(format!(
+            "\"{}\" may only substitute {{owner_uuid}} and {{vm}}",
+            dir
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const VM: &str = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+    const OWNER: &str = "930896af-bf8c-48d4-885c-6573a94b1853";
+
+    fn zone(tags: &[(&str, &str)]) -> Zone {
+        Zone {
+            uuid: VM.to_owned(),
+            alias: None,
+            owner_uuid: OWNER.to_owned(),
+            firewall_enabled: true,
+            zonedid: 1,
+            tags: tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), serde_json::Value::String(v.to_string())))
+                .collect::<HashMap<_, _>>(),
+            nics: vec![],
+        }
+    }
+
+    #[test]
+    fn zones_are_laid_out() {
+        let config = Config::default();
+        let layout = Layout::for_zone(&zone(&[]), &config);
+        assert_eq!(layout.dir, Path::new(LOG_DIR).join(OWNER).join(VM));
+        assert_eq!(layout.log_name, None);
+        assert_eq!(layout.path("current.log"), layout.dir.join("current.log"));
+
+        let config = Config::from_toml(&format!(
+            "[[log_dirs]]\nowner = \"{}\"\ndir = \"pool2/{{owner_uuid}}/{{vm}}\"\n\
+             log_name = \"%Y%m%d.log\"\npermissions = {{ uid = 1001, file_mode = 0o640 }}\n\
+             [[log_dirs]]\nvm = \"{}\"\ndir = \"delegated\"\npermissions = {{ uid = 1002 }}\n",
+            OWNER, VM
+        ))
+        .unwrap();
+        let layout = Layout::for_zone(&zone(&[]), &config);
+        assert_eq!(
+            layout.dir,
+            Path::new(LOG_DIR).join("delegated"),
+            "the vm's entry wins"
+        );
+        assert_eq!(
+            layout.log_name.as_deref(),
+            Some("%Y%m%d.log"),
+            "entries are merged"
+        );
+        assert_eq!(
+            (layout.permissions.uid, layout.permissions.file_mode),
+            (Some(1002), Some(0o640))
+        );
+
+        let layout = Layout::for_zone(&zone(&[(LOG_DIR_TAG, "tagged/{vm}")]), &config);
+        assert_eq!(layout.dir, Path::new(LOG_DIR).join("tagged").join(VM));
+        let layout = Layout::for_zone(&zone(&[(LOG_DIR_TAG, "../etc")]), &config);
+        assert_eq!(
+            layout.dir,
+            Path::new(LOG_DIR).join("delegated"),
+            "bad tags are ignored"
+        );
+
+        let config = Config::from_toml("log_layout = \"vm\"\n").unwrap();
+        let layout = Layout::for_zone(&zone(&[]), &config);
+        assert_eq!(layout.dir, Path::new(LOG_DIR).join(VM));
+        assert!(Config::from_toml("log_layout = \"alias\"\n").is_err());
+    }
+
+    #[test]
+    fn dirs_stay_within_the_log_directory() {
+        assert!(check_dir("pool2/{owner_uuid}/{vm}").is_ok());
+        for bad in &["", "/var/tmp", "a/../../b", "./a", "{alias}"] {
+            assert!(check_dir(bad).is_err(), "{}", bad);
+        }
+    }
+}
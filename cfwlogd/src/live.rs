@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Live streams of records for local subscribers that want to see events as they happen rather
+//! than tailing each zone's log file. Every `Logger` publishes the records it writes to the shared
+//! `LiveHub` through a `LiveSink`, and the hub hands a copy of each record to every subscriber
+//! whose `Filter` matches it. Subscribers get a bounded channel, and records are dropped for a
+//! subscriber that isn't keeping up rather than slowing down the `Logger`s. When nobody is
+//! subscribed publishing is a single atomic load.
+
+use crate::config::Config;
+use crate::expr::Expr;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use cfwevent::parser::{CfwEvType, CfwEvent};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Which records a subscriber wants to see. Every condition that is set must match.
+#[derive(Debug, Default, PartialEq)]
+pub struct Filter {
+    /// Only records for these vm uuids, or every vm if empty
+    pub vms: Vec<String>,
+    /// Only records for this event type
+    pub action: Option<CfwEvType>,
+    /// Only records where either the source or destination port is this port
+    pub port: Option<u16>,
+    /// Only records matching this expression, see the "expr" module
+    pub expr: Option<Expr>,
+}
+
+impl Filter {
+    pub fn matches(&self, record: &Record<'_>) -> bool {
+        if !self.vms.is_empty() && !self.vms.iter().any(|vm| vm == record.vm) {
+            return false;
+        }
+        if !self.expr.as_ref().map_or(true, |expr| expr.matches(record)) {
+            return false;
+        }
+        if self.action.is_none() && self.port.is_none() {
+            return true;
+        }
+        match &record.event {
+            CfwEvent::Traffic(event) => {
+                self.action.as_ref().map_or(true, |a| *a == event.event)
+                    && self.port.map_or(true, |p| {
+                        event.source_port == p || event.destination_port == p
+                    })
+            }
+            CfwEvent::Unknown(_) => false,
+        }
+    }
+}
+
+/// A record as it's handed to subscribers
+#[derive(Debug, PartialEq)]
+pub struct LiveRecord {
+    pub vm: String,
+    /// The record serialized the same way it is in the zone's log file, or as the live sink's
+    /// "sink_fields" or "sink_templates" entry says, without a newline
+    pub json: String,
+}
+
+struct Subscriber {
+    filter: Filter,
+    sender: Sender<Arc<LiveRecord>>,
+    /// Records dropped because the subscriber wasn't keeping up
+    dropped: u64,
+    connected: bool,
+}
+
+#[derive(Default)]
+pub struct LiveHub {
+    subscribers: Mutex<Vec<Subscriber>>,
+    /// Mirrors `subscribers.len()` so publishing can skip taking the lock
+    count: AtomicUsize,
+}
+
+impl LiveHub {
+    pub fn new() -> Self {
+        LiveHub::default()
+    }
+
+    /// Subscribe to the records matching `filter`. Up to `capacity` records are queued for the
+    /// subscriber before records start getting dropped. Dropping the returned `Receiver`
+    /// unsubscribes.
+    pub fn subscribe(&self, filter: Filter, capacity: usize) -> Receiver<Arc<LiveRecord>> {
+        let (sender, receiver) = channel::bounded(capacity);
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(Subscriber {
+            filter,
+            sender,
+            dropped: 0,
+            connected: true,
+        });
+        self.count.store(subscribers.len(), Ordering::Relaxed);
+        receiver
+    }
+
+    /// The number of currently connected subscribers
+    #[cfg(test)]
+    pub fn subscribers(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Hand each record to every subscriber that wants it, encoded by `encoder`
+    pub fn publish(&self, records: &[Record<'_>], encoder: &Encoder) {
+        if self.count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        for record in records {
+            // Records are only serialized once no matter how many subscribers want them
+            let mut live = None;
+            for sub in subscribers
+                .iter_mut()
+                .filter(|sub| sub.connected && sub.filter.matches(record))
+            {
+                let live = live.get_or_insert_with(|| {
+                    Arc::new(LiveRecord {
+                        vm: record.vm.to_owned(),
+                        json: encoder.to_line(record).expect("failed to serialize record"),
+                    })
+                });
+                match sub.sender.try_send(Arc::clone(live)) {
+                    Ok(()) => (),
+                    Err(TrySendError::Full(_)) => {
+                        if sub.dropped == 0 {
+                            warn!("live subscriber isn't keeping up, dropping records");
+                        }
+                        sub.dropped += 1;
+                    }
+                    Err(TrySendError::Disconnected(_)) => sub.connected = false,
+                }
+            }
+        }
+        subscribers.retain(|sub| {
+            if !sub.connected {
+                info!(
+                    "live subscriber disconnected, {} records were dropped",
+                    sub.dropped
+                );
+            }
+            sub.connected
+        });
+        self.count.store(subscribers.len(), Ordering::Relaxed);
+    }
+}
+
+/// Publishes a zone's records to the `LiveHub`
+pub struct LiveSink {
+    hub: Arc<LiveHub>,
+    encoder: Encoder,
+    stats: SinkStats,
+}
+
+impl LiveSink {
+    pub fn new(hub: Arc<LiveHub>, encoder: Encoder) -> Self {
+        LiveSink {
+            hub,
+            encoder,
+            stats: SinkStats::default(),
+        }
+    }
+}
+
+impl Sink for LiveSink {
+    fn name(&self) -> &str {
+        "live"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        self.hub.publish(records, &self.encoder);
+        self.stats.records += records.len() as u64;
+        Ok(())
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    fn record(vm: &str, destination_port: u16) -> Record<'_> {
+        let event = testutils::generate_event();
+        let mut event = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        if let CfwEvent::Traffic(ref mut event) = event {
+            event.event = CfwEvType::Block;
+            event.source_port = 40000;
+            event.destination_port = destination_port;
+        }
+        Record::new(event, vm, "")
+    }
+
+    #[test]
+    fn filter_matching() {
+        let rec = record("vm1", 22);
+        assert!(Filter::default().matches(&rec), "empty filter matches");
+
+        let by_vm = Filter {
+            vms: vec!["vm2".to_owned()],
+            ..Filter::default()
+        };
+        assert!(!by_vm.matches(&rec));
+
+        let by_port = Filter {
+            port: Some(22),
+            ..Filter::default()
+        };
+        assert!(by_port.matches(&rec));
+        assert!(by_port.matches(&record("vm1", 22)));
+        assert!(!by_port.matches(&record("vm1", 23)));
+
+        let by_action = Filter {
+            action: Some(CfwEvType::Begin),
+            ..Filter::default()
+        };
+        assert!(!by_action.matches(&rec));
+    }
+
+    #[test]
+    fn slow_and_disconnected_subscribers() {
+        let hub = LiveHub::new();
+        let all = hub.subscribe(Filter::default(), 1);
+        let gone = hub.subscribe(Filter::default(), 1);
+        drop(gone);
+
+        hub.publish(&[record("vm1", 22), record("vm1", 23)], &Encoder::default());
+        assert_eq!(hub.subscribers(), 1, "disconnected is removed");
+
+        let received: Vec<_> = all.try_iter().collect();
+        assert_eq!(received.len(), 1, "records beyond the capacity are dropped");
+        let json: serde_json::Value = serde_json::from_str(&received[0].json).unwrap();
+        assert_eq!(json["destination_port"], 22);
+        assert_eq!(received[0].vm, "vm1");
+    }
+}
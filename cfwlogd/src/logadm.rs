@@ -0,0 +1,138 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An optional logadm configuration file, "logadm.file" in the log directory, that is kept in step
+//! with the zones being logged. The entry `firewall-logger-agent-setup` installs only covers the
+//! default layout, so zones logged elsewhere by "log_dirs", their tag or the "vm" layout would
+//! otherwise never be rotated. Every `Logger` adds its zone's entry once the zone's log is open,
+//! a retired zone's entry is removed once its log is closed, and the file is atomically rewritten
+//! every time either happens. Zones whose active log is named by "log_name" or rotated by
+//! "rotate_bytes" rotate themselves, so they get no entry.
+//!
+//! The entries are keyed by zonedid, as a rebooted zone's new `Logger` can start before the old
+//! one is retired. The file is started afresh whenever cfwlogd starts, and zones are added back as
+//! their loggers start.
+
+use crate::config::{Config, LogadmConfig};
+use crate::fileutils;
+use crate::layout::Layout;
+use crate::logger::LOG_DIR;
+use crate::zones::Zonedid;
+use std::collections::{BTreeSet, HashMap};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Where the log directory is outside of our chroot, which is what logadm sees
+const FIREWALL_DIR: &str = "/var/log/firewall";
+
+/// What logadm runs once it has rotated a zone's log, which is what the installed entry runs
+const POST_COMMAND: &str = "pkill -USR1 -z global cfwlogd; true";
+
+lazy_static! {
+    /// The entries of the zones being logged
+    static ref ENTRIES: Mutex<HashMap<Zonedid, String>> = Mutex::new(HashMap::new());
+}
+
+/// The zone's logadm entry, if logadm rotates its log
+fn entry(vm: &str, layout: &Layout, config: &Config, logadm: &LogadmConfig) -> Option<String> {
+    if config.rotate_bytes.is_some() || layout.log_name.is_some() || config.log_name.is_some() {
+        return None;
+    }
+    let dir = layout.dir.strip_prefix(LOG_DIR).ok()?;
+    let path = Path::new(FIREWALL_DIR).join(dir).join("current.log");
+    Some(format!(
+        "cfwlogd_{} {} -a '{}' -t '$dirname/%FT%T.log' {}\n",
+        vm,
+        logadm.options,
+        POST_COMMAND,
+        path.display()
+    ))
+}
+
+/// Replace the file with the given entries
+fn write(entries: &HashMap<Zonedid, String>, logadm: &LogadmConfig) -> io::Result<()> {
+    let lines: BTreeSet<&String> = entries.values().collect();
+    let mut contents = "# Generated by cfwlogd, changes are overwritten\n".to_owned();
+    contents.extend(lines.into_iter().map(String::as_str));
+    let dir = fileutils::create_dir_all_nofollow(Path::new(LOG_DIR))?;
+    fileutils::replace_file_nofollow(&dir, &logadm.file, contents.as_bytes())
+}
+
+/// Update the file with `update`, if the config has us keep one
+fn update<F>(config: &Config, update: F)
+where
+    F: FnOnce(&mut HashMap<Zonedid, String>, &LogadmConfig) -> bool,
+{
+    let logadm = match &config.logadm {
+        Some(logadm) => logadm,
+        None => return,
+    };
+    let mut entries = ENTRIES.lock().unwrap();
+    if update(&mut entries, logadm) {
+        if let Err(e) = write(&entries, logadm) {
+            error!("failed to write {}: {}", &logadm.file, e);
+        }
+    }
+}
+
+/// The zone's log was opened, so make sure logadm rotates it
+pub fn zone_started(zonedid: Zonedid, vm: &str, layout: &Layout, config: &Config) {
+    update(config, |entries, logadm| {
+        match entry(vm, layout, config, logadm) {
+            Some(line) => entries.insert(zonedid, line.clone()) != Some(line),
+            None => entries.remove(&zonedid).is_some(),
+        }
+    });
+}
+
+/// The zone was deleted and its log closed, so logadm can forget about it
+pub fn zone_retired(zonedid: Zonedid, config: &Config) {
+    update(config, |entries, _| entries.remove(&zonedid).is_some());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zones_get_entries() {
+        let config = Config::from_toml(
+            "log_layout = \"vm\"\n[logadm]\nfile = \"logadm-test.conf\"\noptions = \"-C 2\"\n",
+        )
+        .unwrap();
+        let logadm = config.logadm.as_ref().unwrap();
+        let layout = Layout::for_zone(&testutils::create_zone(), &config);
+        let vm = layout.dir.file_name().unwrap().to_str().unwrap().to_owned();
+        let line = entry(&vm, &layout, &config, logadm).expect("an entry");
+        assert_eq!(
+            line,
+            format!(
+                "cfwlogd_{} -C 2 -a '{}' -t '$dirname/%FT%T.log' {}/{}/current.log\n",
+                vm, POST_COMMAND, FIREWALL_DIR, vm
+            )
+        );
+        let rotating = Config::from_toml("rotate_bytes = 10\n[logadm]\n").unwrap();
+        assert_eq!(
+            entry(&vm, &layout, &rotating, rotating.logadm.as_ref().unwrap()),
+            None,
+            "zones rotating themselves are left out"
+        );
+
+        let mut entries = HashMap::new();
+        entries.insert(1, line.clone());
+        entries.insert(2, line.clone());
+        write(&entries, logadm).unwrap();
+        let path = Path::new(LOG_DIR).join(&logadm.file);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents.lines().count(),
+            2,
+            "a rebooted zone's entry is written once"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}
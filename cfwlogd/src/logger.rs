@@ -4,22 +4,81 @@
 
 // Copyright 2019 Joyent, Inc.
 
-//! A Logger is a thread that is responsible for receiving CfwEvents and logging them out to the
-//! appropriate directory in the current.log file. A Logger can also be told to perform a variety
-//! of tasks such as flushing its internal buffer to disk, flushing its buffer to disk and
-//! reopening current.log, or to simply flush its buffer to disk and shutdown.
+//! A Logger is a thread, or a share of one of the "logger_workers", that is responsible for
+//! receiving CfwEvents and logging them out to the appropriate directory in the current.log file.
+//! A Logger can also be told to perform a variety of tasks such as flushing its internal buffer to
+//! disk, flushing its buffer to disk and reopening current.log, or to simply flush its buffer to
+//! disk and shutdown. Every time current.log is reopened the Logger appends a `Rollup` of the
+//! closed period to the zone's stats.log sidecar file, and then runs any configured post-rotation
+//! processing on the files that were rotated out. The thread is the sole owner of the zone's open
+//! files, so rotations and flushes requested from other threads are always serialized with the
+//! writes.
 //!
 
-use crate::parser::CfwEvent;
+use crate::archive;
+use crate::audit::LossAudit;
+use crate::budget::{self, Transition};
+use crate::clock::SharedClock;
+#[cfg(feature = "cloudwatch")]
+use crate::cloudwatch;
+use crate::cmon::CmonSink;
+use crate::coalesce::Coalescer;
+use crate::compress::{self, LogWriter};
+use crate::config::{BudgetAction, Config, LogActions, RateLimitConfig};
+#[cfg(feature = "elasticsearch")]
+use crate::elasticsearch;
+use crate::enrich::{self, Annotation};
+use crate::exit::{self, Subsystem};
+use crate::expr::Expr;
+use crate::fanout;
+use crate::fileutils;
+use crate::flows::{Flow, Flows};
+use crate::format::{self, Format};
+use crate::health::{self, Heartbeat};
+use crate::ipfix;
+use crate::layout::Layout;
+use crate::live::{LiveHub, LiveSink};
+use crate::logadm;
+use crate::memory::MemoryTracker;
+use crate::migrate;
+use crate::nic;
+use crate::node::NodeIdentity;
+#[cfg(feature = "parquet")]
+use crate::parquet_sink;
+#[cfg(feature = "dynamic-sinks")]
+use crate::plugin;
+use crate::queue;
+use crate::ratelimit::RateLimiter;
+use crate::rules::Rules;
+use crate::sampling::{Decision, Sampler};
+use crate::selftest;
+use crate::sink::{Encoder, Epoch, ReceiveTimes, Record, SchemaVersion, Sink, SinkStats};
+use crate::stats::{self, DropReason, Rollup, RuleReport, RuleStats, Stats, ZoneCounters};
+use crate::stdout::StdoutSink;
+use crate::syslog;
+use crate::talkers::{Top, TopTalkers};
+use crate::thresholds::{self, ZoneThresholds};
+use crate::upload;
+#[cfg(feature = "webhook")]
+use crate::webhook;
+use crate::workers;
+use crate::zonefs;
 use crate::zones::{Vmobjs, Zonedid};
-use crossbeam::channel::{self, Select, SendError};
+use cfwevent::indexed::{self, IndexedReader};
+use cfwevent::parser::{self, CfwEvent};
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{self, Select, SendError, SendTimeoutError, TryRecvError};
 use serde::Serialize;
-use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use vminfod_client::Zone;
 
 /// Configure where log files will be created
 /// We are now chrooting into "/var/log/firewall" so the base dir should just be "/"
@@ -28,15 +87,51 @@ pub const LOG_DIR: &str = "/";
 #[cfg(test)]
 pub const LOG_DIR: &str = "/var/tmp/cfwlogd-tests";
 
-/// Capacity used for Logger's BufWriter.  This may need to be tuned later.
+/// Capacity used for Logger's BufWriter, unless the config's "write_buffer_bytes" is set
 const BUF_SIZE: usize = 1024 * 1024;
 
-#[derive(Debug, Serialize)]
-struct LogEvent<'a> {
-    #[serde(flatten)]
-    event: CfwEvent,
-    vm: &'a str,
-    alias: &'a str,
+/// How often a Logger checks that its open file is still the zone's current.log
+const LOG_FILE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often a zone's log gets a summary of the events suppressed since the last one
+const SUPPRESSION_SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for a Logger to accept a signal. A Logger that's wedged (e.g. blocked writing
+/// to disk) must not be able to hang whoever is trying to signal it.
+const SIGNAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How much of a torn record cut off a log file is logged
+const TORN_PREVIEW: usize = 120;
+
+lazy_static! {
+    /// The log files `Logger`s have open, and how many of them have each, see `open_file`
+    static ref OPENED: Mutex<HashMap<PathBuf, usize>> = Mutex::new(HashMap::new());
+}
+
+/// A log file counted in `OPENED` for as long as this is held
+struct Opened(PathBuf);
+
+impl Opened {
+    /// Count `path` as open, along with whether nothing else had it open
+    fn new(path: PathBuf) -> (Opened, bool) {
+        let mut opened = OPENED.lock().unwrap();
+        let count = opened.entry(path.clone()).or_insert(0);
+        *count += 1;
+        let first = *count == 1;
+        (Opened(path), first)
+    }
+}
+
+impl Drop for Opened {
+    fn drop(&mut self) {
+        let mut opened = OPENED.lock().unwrap();
+        if let Some(count) = opened.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                opened.remove(&self.0);
+            }
+        }
+    }
 }
 
 /// A signal that can be sent to the logger
@@ -48,6 +143,11 @@ pub enum LoggerSignal {
     Shutdown,
     /// Tell the thread to flush and rotate the log file
     Rotate,
+    /// Tell the thread its zone was deleted, so once its queue is drained and the log file closed
+    /// the zone can be forgotten
+    Retire,
+    /// Tell the thread to use the reloaded config from now on
+    Reload(Arc<Config>),
 }
 
 /// A Logger represents a thread tied to a specific zone that is responsible persisting CfwEvents
@@ -55,208 +155,2189 @@ pub enum LoggerSignal {
 pub struct Logger {
     /// Zone UUID
     pub uuid: String,
-    /// Threads handle
-    handle: thread::JoinHandle<()>,
-    /// Send half of a channel that's used to get CfwEvents into the Logger
-    sender: channel::Sender<CfwEvent>,
+    /// What to wait on for the logger to finish
+    running: Running,
+    /// Send half of a queue that's used to get CfwEvents into the Logger
+    sender: queue::Sender<CfwEvent>,
     /// Send half of a channel that's used to signal the Logger to perform specific actions
     signal: channel::Sender<LoggerSignal>,
+    /// The zone's "rate_limit", applied before events are queued
+    limiter: RateLimiter,
+    /// Beaten by the thread between batches, see the "health" module
+    heartbeat: Arc<Heartbeat>,
 }
 
 impl Logger {
-    /// Send an event to the logger to be logged out to disk
-    pub fn send(&self, e: CfwEvent) -> Result<(), SendError<CfwEvent>> {
-        self.sender.send(e)
+    /// Send an event to the logger to be logged out to disk, handing any event dropped because
+    /// the logger's queue is full to `dropped`
+    pub fn send<F: FnMut(CfwEvent)>(
+        &self,
+        e: CfwEvent,
+        dropped: F,
+    ) -> Result<(), SendError<CfwEvent>> {
+        self.sender.send(e, dropped)
+    }
+
+    /// Whether an event arriving `now` is within the zone's rate limit, see the "ratelimit" module
+    pub fn admit(&mut self, limit: Option<&RateLimitConfig>, now: Instant) -> bool {
+        self.limiter.admit(limit, now)
+    }
+
+    /// Number of events waiting to be written by the logger
+    pub fn queued(&self) -> usize {
+        self.sender.queued()
+    }
+
+    /// How long it has been since the thread was last between batches
+    pub fn heartbeat_age(&self) -> Duration {
+        self.heartbeat.age()
     }
 
     /// Flushes the logger's internal `BufWriter` to disk
-    pub fn flush(&self) -> Result<(), SendError<LoggerSignal>> {
-        self.signal.send(LoggerSignal::Flush)
+    pub fn flush(&self) -> Result<(), SendTimeoutError<LoggerSignal>> {
+        self.signal
+            .send_timeout(LoggerSignal::Flush, SIGNAL_TIMEOUT)
     }
 
     /// Flushes the logger's internal `BufWriter` to disk, and reopens "current.log"
-    pub fn rotate(&self) -> Result<(), SendError<LoggerSignal>> {
-        self.signal.send(LoggerSignal::Rotate)
+    pub fn rotate(&self) -> Result<(), SendTimeoutError<LoggerSignal>> {
+        self.signal
+            .send_timeout(LoggerSignal::Rotate, SIGNAL_TIMEOUT)
     }
 
     /// Flushes the logger's internal `BufWriter` to disk, and shutdowns the `Logger`, therefore
     /// requiring ownership of self to be consumed.
-    pub fn shutdown(self) -> Result<(), SendError<LoggerSignal>> {
-        self.signal.send(LoggerSignal::Shutdown).and_then(|_| {
-            self.handle.join().unwrap();
-            Ok(())
-        })
+    pub fn shutdown(self) -> Result<(), SendTimeoutError<LoggerSignal>> {
+        self.signal
+            .send_timeout(LoggerSignal::Shutdown, SIGNAL_TIMEOUT)
+            .and_then(|_| {
+                match self.running {
+                    Running::Thread(handle) => {
+                        if handle.join().is_err() {
+                            error!("logging thread for {} panicked", self.uuid);
+                        }
+                    }
+                    // The worker hangs up once the zone's log is closed
+                    Running::Worker(done) => while done.recv().is_ok() {},
+                }
+                Ok(())
+            })
+    }
+
+    /// Hand the `Logger` a reloaded config
+    pub fn reload(&self, config: Arc<Config>) -> Result<(), SendTimeoutError<LoggerSignal>> {
+        self.signal
+            .send_timeout(LoggerSignal::Reload(config), SIGNAL_TIMEOUT)
+    }
+
+    /// Tell the `Logger` its zone was deleted. Unlike `shutdown` this doesn't wait for the thread,
+    /// which drains its queue, closes the log file and removes the zone from `Vmobjs` on its own.
+    pub fn retire(self) -> Result<(), SendTimeoutError<LoggerSignal>> {
+        self.signal
+            .send_timeout(LoggerSignal::Retire, SIGNAL_TIMEOUT)
     }
 }
 
-/// Open "current.log" in "RW" for the given customer and zone.
-fn open_file(vm: &str, customer: &str) -> std::io::Result<File> {
-    let path: PathBuf = [LOG_DIR, customer, vm, "current.log"].iter().collect();
-    // we know the unwrap is safe because we just created the path above
-    std::fs::create_dir_all(path.parent().unwrap())?;
-    Ok(OpenOptions::new().append(true).create(true).open(path)?)
-}
-
-/// Given a collection of `CfwEvent`, serialize them out to disk as JSON formatted logs.
-fn log_events<W: Write>(events: Vec<CfwEvent>, mut writer: W, vmobjs: &Vmobjs) {
-    // force the event type for now
-    let vmobjs = vmobjs.read().unwrap();
-    for event in events {
-        let vmobj = vmobjs
-            .get(&event.zone())
-            .expect("we should have the zonedid:uuid mapping already");
-        // Check if the zone has an alias set, if not we provide a default one
-        // Note instead of String::as_ref we could also use "|s| &**s"
-        let alias = vmobj.alias.as_ref().map_or("", String::as_ref);
-        let event = LogEvent {
-            event,
-            vm: &vmobj.uuid,
-            alias: &alias,
-        };
-        // We decided that the only reason we would fail to write here would be due to something
-        // like ENOSPC/EDQUOT in which case none of the loggers are likely to make any forward
-        // progress so we will force unwrap the results and abort if we hit this scenario.
-        serde_json::to_writer(&mut writer, &event).expect("failed to CfwEvent to the BufWriter");
-        writer
-            .write_all(b"\n")
-            .expect("failed to write newline to the BufWriter");
+/// Create the zone's log directory, giving it the layout's owner and "dir_mode"
+fn open_zone_dir(layout: &Layout) -> std::io::Result<File> {
+    let dir = fileutils::create_dir_all_nofollow(&layout.dir)?;
+    let permissions = &layout.permissions;
+    fileutils::set_permissions(&dir, permissions.uid, permissions.gid, permissions.dir_mode)?;
+    Ok(dir)
+}
+
+/// Open the named file in append mode in the zone's log directory `dir`, giving it the layout's
+/// owner and "file_mode". Neither the directories leading up to the file nor the file itself may
+/// be symlinks.
+fn open_zone_file(dir: &File, layout: &Layout, name: &str) -> std::io::Result<File> {
+    let file = fileutils::open_append_nofollow(dir, name)?;
+    let permissions = &layout.permissions;
+    fileutils::set_permissions(
+        &file,
+        permissions.uid,
+        permissions.gid,
+        permissions.file_mode,
+    )?;
+    Ok(file)
+}
+
+/// The name of the active log file at `now`, which is "current.log" unless the zone's layout or
+/// the config has a "log_name" template
+pub fn log_name(config: &Config, layout: &Layout, now: DateTime<Utc>) -> String {
+    match layout
+        .log_name
+        .as_ref()
+        .or_else(|| config.log_name.as_ref())
+    {
+        Some(template) => now.format(template).to_string(),
+        None => "current.log".to_owned(),
+    }
+}
+
+/// Bytes a zone's log file writer buffers
+fn buf_size(config: &Config) -> usize {
+    config.write_buffer_bytes.unwrap_or(BUF_SIZE)
+}
+
+/// Look for a log file that ends partway through a json record, since cfwlogd or the CN went down
+/// in the middle of writing it, and cut it back to its last complete record so that consumers of
+/// the log don't trip over it.
+fn truncate_torn_record(path: &Path) {
+    let mut file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            error!(
+                "failed to open file ({}) for cleanup: {}",
+                path.display(),
+                e
+            );
+            return;
+        }
+    };
+    match fileutils::truncate_torn_record(&mut file) {
+        Ok(torn) if torn.is_empty() => (),
+        Ok(torn) => {
+            let preview = String::from_utf8_lossy(&torn[..torn.len().min(TORN_PREVIEW)]);
+            warn!(
+                "truncated a torn record of {} bytes from the end of {}: {:?}",
+                torn.len(),
+                path.display(),
+                preview
+            );
+        }
+        Err(e) => error!(
+            "failed to truncate torn records from {}: {}",
+            path.display(),
+            e
+        ),
     }
 }
 
-// Process a signal sent to the Logger, and return true if the Logger was told to shutdown
-fn logger_handle_signal(
+/// Open the active log file `name` in "RW" in the zone's log directory, compressing or indexing
+/// it if the zone is listed in the config's "zstd" or "indexed" table. A file that already has
+/// records in it keeps being written the way it was started. A plain json file has a torn record
+/// cut off the first time we open it, compressed and indexed files have their own framing, and a
+/// torn indexed file is cut off when it's resumed. Files another `Logger` has open are left alone,
+/// since a rebooted zone's old `Logger` may still be writing to the file.
+fn open_file(
+    layout: &Layout,
     vm: &str,
-    customer: &str,
-    signal: LoggerSignal,
-    writer: &mut BufWriter<File>,
-) -> bool {
-    match signal {
-        LoggerSignal::Rotate => {
-            let _ = writer.flush();
-            let file = match open_file(vm, customer) {
-                Ok(file) => file,
-                Err(e) => {
+    name: &str,
+    config: &Config,
+) -> std::io::Result<(LogWriter, Opened)> {
+    let (opened, first_open) = Opened::new(layout.path(name));
+    let dir = open_zone_dir(layout)?;
+    let file = open_zone_file(&dir, layout, name)?;
+    let block_size = config
+        .indexed
+        .as_ref()
+        .and_then(|indexed| indexed.block_size_for(vm));
+    match indexed::is_indexed(fileutils::open_read_nofollow(&dir, name)?)? {
+        Some(true) => {
+            let existing = IndexedReader::new(fileutils::open_read_nofollow(&dir, name)?)?;
+            let writer = LogWriter::resume_indexed(file, buf_size(config), existing)?;
+            return Ok((writer, opened));
+        }
+        None => {
+            if let Some(block_size) = block_size {
+                let writer = LogWriter::indexed(file, buf_size(config), block_size);
+                return Ok((writer, opened));
+            }
+        }
+        Some(false) => (),
+    }
+    let configured = config.zstd.as_ref().and_then(|zstd| zstd.level_for(vm));
+    let level = match compress::is_zstd(fileutils::open_read_nofollow(&dir, name)?)? {
+        None => configured,
+        Some(false) => {
+            if first_open && !config.sink_formats.contains_key("file") {
+                truncate_torn_record(&layout.path(name));
+            }
+            None
+        }
+        Some(true) => Some(configured.unwrap_or(compress::DEFAULT_LEVEL)),
+    };
+    let frame_secs = config
+        .zstd
+        .as_ref()
+        .map_or(compress::DEFAULT_FRAME_SECS, |zstd| zstd.frame_secs);
+    let writer = LogWriter::new(file, buf_size(config), level, frame_secs);
+    Ok((writer, opened))
+}
+
+/// Append a `Rollup` covering everything from `period_start` to the zone's "stats.log", and
+/// reset the zone's counters for the next period.
+fn write_rollup(
+    vm: &str,
+    layout: &Layout,
+    counters: &ZoneCounters,
+    rules: &Rules,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> std::io::Result<()> {
+    let rollup = Rollup {
+        vm,
+        period_start,
+        period_end,
+        counts: counters.take(),
+        rules: counters.take_rules(rules),
+    };
+    append_stats(layout, &rollup)
+}
+
+/// Append a `RuleStats` record with the per-rule counts accumulated from `period_start` to the
+/// zone's "stats.log", unless no rules were hit.
+fn write_rule_stats(
+    vm: &str,
+    layout: &Layout,
+    counters: &ZoneCounters,
+    rules: &Rules,
+    period_start: DateTime<Utc>,
+    period_end: DateTime<Utc>,
+) -> std::io::Result<()> {
+    let rules = counters.take_rules(rules);
+    if rules.is_empty() {
+        return Ok(());
+    }
+    let record = RuleStats {
+        vm,
+        period_start,
+        period_end,
+        rules,
+    };
+    append_stats(layout, &record)
+}
+
+/// Append a line of json to the zone's "stats.log"
+fn append_stats<T: Serialize>(layout: &Layout, record: &T) -> std::io::Result<()> {
+    let mut file = open_zone_file(&open_zone_dir(layout)?, layout, "stats.log")?;
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)
+}
+
+/// Append a record as it's written to the zone's log: a line of json in the configured schema
+/// version, or a value in the file's binary format
+fn encode_record<T: Serialize>(
+    record: &T,
+    format: Option<Format>,
+    config: &Config,
+    buf: &mut Vec<u8>,
+) -> std::io::Result<()> {
+    match format {
+        Some(format) => format::binary(format, record, config.schema_version(), buf),
+        None => {
+            migrate::to_writer(&mut *buf, record, config.schema_version())?;
+            buf.push(b'\n');
+            Ok(())
+        }
+    }
+}
+
+/// Write a record cfwlogd generated itself to the zone's log, returning the number of bytes
+/// written.
+fn write_line<T: Serialize, W: Write>(
+    mut writer: W,
+    record: &T,
+    format: Option<Format>,
+    config: &Config,
+) -> std::io::Result<u64> {
+    let mut line = vec![];
+    encode_record(record, format, config, &mut line)?;
+    writer.write_all(&line)?;
+    Ok(line.len() as u64)
+}
+
+/// Whether a write failed because the filesystem is out of space or the zone's owner is out of
+/// quota, which cfwlogd waits out rather than giving up on the zone's log
+fn is_disk_full(e: &std::io::Error) -> bool {
+    match e.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => true,
+        _ => false,
+    }
+}
+
+/// Write as much of `buf` as `writer` takes, returning how many bytes it had taken along with the
+/// error if it failed partway through
+fn write_counted<W: Write>(writer: &mut W, buf: &[u8]) -> Result<(), (usize, std::io::Error)> {
+    let mut taken = 0;
+    while taken < buf.len() {
+        match writer.write(&buf[taken..]) {
+            Ok(0) => return Err((taken, std::io::ErrorKind::WriteZero.into())),
+            Ok(n) => taken += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err((taken, e)),
+        }
+    }
+    Ok(())
+}
+
+/// A zone's log file that can't be written to because the filesystem is full. Records are held
+/// in memory, up to the config's "disk.full_buffer_records", and writing them out is retried
+/// every "disk.full_retry_secs". Records arriving once the backlog is full are dropped and
+/// counted.
+struct Stall {
+    since: Instant,
+    retry_at: Instant,
+    /// The sequence number and line of every record waiting to be written, oldest first
+    backlog: VecDeque<(u64, Vec<u8>)>,
+    /// Records dropped because the backlog was full
+    dropped: u64,
+}
+
+/// Write out as much of the backlog as the file takes, moving the sequence numbers of the records
+/// written to `written`. This only succeeds once everything has been flushed to the file. A record
+/// the file only took part of keeps the rest of its line, which picks up where it left off.
+fn drain_backlog(
+    writer: &mut LogWriter,
+    backlog: &mut VecDeque<(u64, Vec<u8>)>,
+    written: &mut Vec<u64>,
+    bytes: &mut u64,
+) -> std::io::Result<()> {
+    writer.flush()?;
+    while let Some((seq, line)) = backlog.front_mut() {
+        if let Err((taken, e)) = write_counted(writer, line) {
+            line.drain(..taken);
+            *bytes += taken as u64;
+            return Err(e);
+        }
+        written.push(*seq);
+        *bytes += line.len() as u64;
+        backlog.pop_front();
+    }
+    writer.flush()
+}
+
+/// A zone's open log file along with everything needed to rotate it. A `ZoneLog` is moved into its
+/// `Logger`'s thread and never shared, so every write, flush, reopen and rotation of the underlying
+/// file descriptor happens on that one thread. Everybody else has to ask for those operations by
+/// sending the `Logger` a `LoggerSignal`, which means a rotation can never race with a write.
+struct ZoneLog {
+    vm: String,
+    layout: Layout,
+    /// The name of the open log file, see `log_name`
+    file_name: String,
+    writer: LogWriter,
+    /// Counts the open file in `OPENED`
+    opened: Opened,
+    /// The binary format the open file is written in, from the config's "sink_formats", rather
+    /// than json lines
+    format: Option<Format>,
+    counters: Arc<ZoneCounters>,
+    rules: Rules,
+    config: Arc<Config>,
+    clock: SharedClock,
+    /// When the period covered by the open current.log started
+    period_start: DateTime<Utc>,
+    /// When the per-rule counts were last taken
+    rules_start: DateTime<Utc>,
+    /// The last time we checked that `writer` still refers to the zone's current.log
+    last_check: Instant,
+    /// The last time suppressed events were summarized
+    last_summary: Instant,
+    /// The last time a heartbeat was logged, and what had been written and dropped by then
+    last_heartbeat: (Instant, SinkStats),
+    /// Blocked events by remote address and events by rule, when "top_talkers" is configured
+    talkers: Option<TopTalkers>,
+    /// The last time the top talkers were logged
+    last_talkers: Instant,
+    /// The last time the events of "count_only" rules were summarized
+    last_counted: Instant,
+    /// The last time the zone's logs were measured against its "disk_budget", if they have been
+    last_budget: Option<Instant>,
+    /// Events by remote address for each of "thresholds", when any are configured
+    thresholds: Option<ZoneThresholds>,
+    /// The window's flows, when the zone is in "aggregate.zones"
+    flows: Option<Flows>,
+    /// When the window being aggregated started
+    last_flows: Instant,
+    /// Set while the filesystem is full
+    stall: Option<Stall>,
+    /// The rest of a record a write stopped partway through, which is written before anything
+    /// else so that the record still ends up whole
+    torn: Vec<u8>,
+    /// Records written to `writer` since it was last flushed, for "flush.every_records"
+    unflushed: u64,
+    last_flush: Instant,
+    /// Set once something was written to the file since it was last synced
+    unsynced: bool,
+    last_fsync: Instant,
+    audit: Arc<LossAudit>,
+    stats: SinkStats,
+}
+
+impl ZoneLog {
+    /// Open the zone's current.log
+    #[allow(clippy::too_many_arguments)]
+    fn open(
+        vm: String,
+        layout: Layout,
+        counters: Arc<ZoneCounters>,
+        rules: Rules,
+        config: Arc<Config>,
+        clock: SharedClock,
+        audit: Arc<LossAudit>,
+    ) -> std::io::Result<ZoneLog> {
+        let (now, utc) = (clock.now(), clock.utc());
+        let file_name = log_name(&config, &layout, utc);
+        let (writer, opened) = open_file(&layout, &vm, &file_name, &config)?;
+        let flows = config
+            .aggregate
+            .as_ref()
+            .filter(|aggregate| aggregate.aggregates(&vm))
+            .map(|aggregate| Flows::new(aggregate.max_flows));
+        let dropped = counters.totals().dropped;
+        let mut log = ZoneLog {
+            vm,
+            layout,
+            file_name,
+            writer,
+            opened,
+            format: config.sink_formats.get("file").copied(),
+            counters,
+            rules,
+            period_start: utc,
+            rules_start: utc,
+            last_check: now,
+            last_summary: now,
+            last_heartbeat: (
+                now,
+                SinkStats {
+                    dropped,
+                    ..SinkStats::default()
+                },
+            ),
+            talkers: config.top_talkers.as_ref().map(TopTalkers::new),
+            last_talkers: now,
+            last_counted: now,
+            last_budget: None,
+            thresholds: Some(&config.thresholds)
+                .filter(|configs| !configs.is_empty())
+                .map(|configs| ZoneThresholds::new(configs, now)),
+            flows,
+            last_flows: now,
+            config,
+            clock,
+            stall: None,
+            torn: vec![],
+            unflushed: 0,
+            last_flush: now,
+            unsynced: false,
+            last_fsync: now,
+            audit,
+            stats: SinkStats::default(),
+        };
+        log.write_lifecycle(Lifecycle::Start)?;
+        Ok(log)
+    }
+
+    /// Mark something cfwlogd did in the zone's log
+    fn write_lifecycle(&mut self, action: Lifecycle) -> std::io::Result<()> {
+        self.finish_torn()?;
+        let record = LifecycleRecord {
+            schema_version: SchemaVersion,
+            event: "lifecycle",
+            vm: &self.vm,
+            action,
+            records: Some(self.stats.records).filter(|_| action == Lifecycle::Stop),
+            version: env!("CARGO_PKG_VERSION"),
+            timestamp: self.clock.utc(),
+        };
+        self.stats.bytes += write_line(&mut self.writer, &record, self.format, &self.config)?;
+        Ok(())
+    }
+
+    /// Log what was written to and dropped from the zone's log since the last heartbeat, which
+    /// happens even when there was nothing, so consumers can tell a quiet zone from a stuck
+    /// cfwlogd
+    fn write_heartbeat(&mut self) -> std::io::Result<()> {
+        let now = self.clock.now();
+        let (since, last) = self.last_heartbeat;
+        self.finish_torn()?;
+        let stats = SinkStats {
+            dropped: self.counters.totals().dropped,
+            ..self.stats
+        };
+        self.last_heartbeat = (now, stats);
+        let record = HeartbeatRecord {
+            schema_version: SchemaVersion,
+            event: "heartbeat",
+            vm: &self.vm,
+            events: stats.records - last.records,
+            bytes: stats.bytes - last.bytes,
+            dropped: stats.dropped - last.dropped,
+            period_secs: (now - since).as_secs(),
+            timestamp: self.clock.utc(),
+        };
+        self.stats.bytes += write_line(&mut self.writer, &record, self.format, &self.config)?;
+        Ok(())
+    }
+
+    /// Log a summary record of the events counted for each "count_only" rule since the last
+    /// summary, in place of the events themselves
+    fn write_counted(&mut self) -> std::io::Result<()> {
+        let now = self.clock.now();
+        let period_secs = (now - self.last_counted).as_secs();
+        self.last_counted = now;
+        for report in self.counters.take_counted(&self.rules) {
+            self.finish_torn()?;
+            let summary = CountSummary {
+                schema_version: SchemaVersion,
+                event: "summary",
+                vm: &self.vm,
+                report,
+                period_secs,
+                timestamp: self.clock.utc(),
+            };
+            self.stats.bytes += write_line(&mut self.writer, &summary, self.format, &self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Measure the zone's logs against its "disk_budget", logging it going over or back under
+    fn check_budget(&mut self) -> std::io::Result<()> {
+        self.last_budget = Some(self.clock.now());
+        let config = Arc::clone(&self.config);
+        let budget = match &config.disk_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+        self.finish_torn()?;
+        let utc = self.clock.utc();
+        let notice = match budget::check(&self.layout.dir, &self.vm, budget, &self.counters, utc)? {
+            Some(notice) => notice,
+            None => return Ok(()),
+        };
+        match notice.state {
+            Transition::Exceeded => warn!(
+                "{}'s logs take up {} bytes, over its budget of {}, {} its events",
+                &self.vm,
+                notice.used_bytes,
+                notice.budget_bytes,
+                match budget.action {
+                    BudgetAction::CountOnly => "only counting",
+                    BudgetAction::Drop => "dropping",
+                }
+            ),
+            Transition::Restored => info!(
+                "{}'s logs are back under its budget of {} bytes, logging its events again",
+                &self.vm, notice.budget_bytes
+            ),
+        }
+        self.stats.bytes += write_line(&mut self.writer, &notice, self.format, &config)?;
+        Ok(())
+    }
+
+    /// Log that the zone's logging was resumed, if it was since this was last called
+    fn write_resumed(&mut self) -> std::io::Result<()> {
+        self.finish_torn()?;
+        let notice = match self.counters.pause().take_resumed(&self.vm, &self.rules) {
+            Some(notice) => notice,
+            None => return Ok(()),
+        };
+        self.stats.bytes += write_line(&mut self.writer, &notice, self.format, &self.config)?;
+        Ok(())
+    }
+
+    /// Log the top talkers and rules over the window
+    fn write_talkers(&mut self) -> std::io::Result<()> {
+        self.last_talkers = self.clock.now();
+        let top = match &mut self.talkers {
+            Some(talkers) => talkers.take(),
+            None => return Ok(()),
+        };
+        let timestamp = self.clock.utc();
+        self.counters.set_top(top.clone(), timestamp);
+        if top.talkers.is_empty() && top.rules.is_empty() {
+            return Ok(());
+        }
+        self.finish_torn()?;
+        let summary = TopTalkersSummary {
+            schema_version: SchemaVersion,
+            event: "top_talkers",
+            vm: &self.vm,
+            top,
+            timestamp,
+        };
+        self.stats.bytes += write_line(&mut self.writer, &summary, self.format, &self.config)?;
+        Ok(())
+    }
+
+    /// Log every flow of the window that just ended
+    fn write_flows(&mut self) -> std::io::Result<()> {
+        self.last_flows = self.clock.now();
+        let (flows, window_secs) = match (&mut self.flows, &self.config.aggregate) {
+            (Some(flows), Some(config)) => (flows.take(), config.window_secs),
+            _ => return Ok(()),
+        };
+        for flow in flows {
+            self.finish_torn()?;
+            let summary = FlowSummary {
+                schema_version: SchemaVersion,
+                event: "flow",
+                vm: &self.vm,
+                window_secs,
+                flow,
+                timestamp: self.clock.utc(),
+            };
+            self.stats.bytes += write_line(&mut self.writer, &summary, self.format, &self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Write out every periodic record that is due: events lost by the source as soon as they are
+    /// noticed, suppression summaries every `SUPPRESSION_SUMMARY_INTERVAL`, and per-rule
+    /// statistics, top talkers and flows on their configured intervals
+    fn write_periodic(&mut self) {
+        let lost = self.counters.take_source_lost();
+        if lost > 0 {
+            if let Err(e) = self.write_lost(lost) {
+                warn!("failed to mark {}'s lost events: {}", &self.vm, e);
+            }
+        }
+        let now = self.clock.now();
+        if now - self.last_summary >= SUPPRESSION_SUMMARY_INTERVAL {
+            if let Err(e) = self.write_summaries() {
+                warn!(
+                    "failed to summarize {}'s suppressed events: {}",
+                    &self.vm, e
+                );
+            }
+        }
+        if let Some(secs) = self.config.rule_stats_secs {
+            let utc = self.clock.utc();
+            if (utc - self.rules_start).num_seconds() >= secs as i64 {
+                if let Err(e) = write_rule_stats(
+                    &self.vm,
+                    &self.layout,
+                    &self.counters,
+                    &self.rules,
+                    self.rules_start,
+                    utc,
+                ) {
+                    error!("failed to write {}'s rule stats: {}", &self.vm, e);
+                }
+                self.rules_start = utc;
+            }
+        }
+        if let Some(secs) = self.config.heartbeat_secs {
+            if now - self.last_heartbeat.0 >= Duration::from_secs(secs) {
+                if let Err(e) = self.write_heartbeat() {
+                    warn!("failed to write {}'s heartbeat: {}", &self.vm, e);
+                }
+            }
+        }
+        if let Err(e) = self.write_resumed() {
+            warn!("failed to mark {}'s resume: {}", &self.vm, e);
+        }
+        if let Some(config) = &self.config.disk_budget {
+            let interval = Duration::from_secs(config.check_secs);
+            if self.last_budget.map_or(true, |last| now - last >= interval) {
+                if let Err(e) = self.check_budget() {
+                    warn!("failed to check {}'s disk budget: {}", &self.vm, e);
+                }
+            }
+        }
+        // A zone over its disk budget may have its events counted like those of count only rules
+        let counted_secs = match (&self.config.count_only, &self.config.disk_budget) {
+            (Some(config), _) => Some(config.interval_secs),
+            (None, Some(config)) if config.action == BudgetAction::CountOnly => {
+                Some(config.check_secs)
+            }
+            _ => None,
+        };
+        if let Some(secs) = counted_secs {
+            if now - self.last_counted >= Duration::from_secs(secs) {
+                if let Err(e) = self.write_counted() {
+                    warn!("failed to summarize {}'s count only rules: {}", &self.vm, e);
+                }
+            }
+        }
+        if let Some(config) = &self.config.top_talkers {
+            if now - self.last_talkers >= Duration::from_secs(config.interval_secs) {
+                if let Err(e) = self.write_talkers() {
+                    warn!("failed to write {}'s top talkers: {}", &self.vm, e);
+                }
+            }
+        }
+        if let Some(config) = &self.config.aggregate {
+            if now - self.last_flows >= Duration::from_secs(config.window_secs) {
+                if let Err(e) = self.write_flows() {
+                    warn!("failed to write {}'s flows: {}", &self.vm, e);
+                }
+            }
+        }
+    }
+
+    /// Log that the source dropped `lost` events, which may have included some of the zone's, so
+    /// consumers of the log know it has a gap
+    fn write_lost(&mut self, lost: u64) -> std::io::Result<()> {
+        self.finish_torn()?;
+        let record = EventsLost {
+            schema_version: SchemaVersion,
+            event: "events_lost",
+            vm: &self.vm,
+            lost,
+            timestamp: self.clock.utc(),
+        };
+        self.stats.bytes += write_line(&mut self.writer, &record, self.format, &self.config)?;
+        Ok(())
+    }
+
+    /// Log a summary record of the events suppressed since the last summary for each reason and
+    /// rule, so that sampling under pressure doesn't silently hide what a zone's rules matched.
+    fn write_summaries(&mut self) -> std::io::Result<()> {
+        let now = self.clock.now();
+        let period_secs = (now - self.last_summary).as_secs();
+        self.last_summary = now;
+        for suppressed in self.counters.take_suppressed() {
+            self.finish_torn()?;
+            let summary = SuppressionSummary {
+                schema_version: SchemaVersion,
+                event: "suppressed",
+                vm: &self.vm,
+                reason: suppressed.reason,
+                rule: suppressed.rule,
+                suppressed: suppressed.events,
+                period_secs,
+                timestamp: self.clock.utc(),
+            };
+            self.stats.bytes += write_line(&mut self.writer, &summary, self.format, &self.config)?;
+        }
+        Ok(())
+    }
+
+    /// Stop writing to the log file after a write failed with ENOSPC or EDQUOT, see `Stall`
+    fn stall_writes(&mut self, e: &std::io::Error) {
+        error!(
+            "{}'s log file can't be written, holding up to {} records until it can: {}",
+            &self.vm, self.config.disk.full_buffer_records, e
+        );
+        let now = self.clock.now();
+        self.stall = Some(Stall {
+            since: now,
+            retry_at: now + Duration::from_secs(self.config.disk.full_retry_secs),
+            backlog: VecDeque::new(),
+            dropped: 0,
+        });
+    }
+
+    /// Try writing the backlog out again, which ends the stall once all of it has made it to the
+    /// file. Only errors other than the filesystem still being full are returned.
+    fn retry_stalled(&mut self) -> std::io::Result<()> {
+        if self.stall.is_none() {
+            return Ok(());
+        }
+        let finished = self.finish_torn();
+        let stall = self.stall.as_mut().expect("writes are stalled");
+        let mut written = vec![];
+        let result = match finished {
+            Ok(()) => drain_backlog(
+                &mut self.writer,
+                &mut stall.backlog,
+                &mut written,
+                &mut self.stats.bytes,
+            ),
+            Err(e) => Err(e),
+        };
+        self.audit.written(&written);
+        match result {
+            Ok(()) => {
+                info!(
+                    "{}'s log file can be written again after {:?}, {} records were dropped",
+                    &self.vm,
+                    self.clock.now() - stall.since,
+                    stall.dropped
+                );
+                self.stall = None;
+                Ok(())
+            }
+            Err(e) if is_disk_full(&e) => {
+                stall.retry_at =
+                    self.clock.now() + Duration::from_secs(self.config.disk.full_retry_secs);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write out the rest of the record the last write stopped partway through, if there is one
+    fn finish_torn(&mut self) -> std::io::Result<()> {
+        let result = write_counted(&mut self.writer, &self.torn);
+        let taken = match &result {
+            Ok(()) => self.torn.len(),
+            Err((taken, _)) => *taken,
+        };
+        self.torn.drain(..taken);
+        self.stats.bytes += taken as u64;
+        result.map_err(|(_, e)| e)
+    }
+
+    /// Let go of a log file whose last record couldn't be written out in full, cutting the file
+    /// back to the end of the record before it. Only json lines logs can be cut back this way, the
+    /// framing of the others is left to be repaired when they're next opened.
+    fn cut_torn_record(&mut self, old: LogWriter, format: Option<Format>) {
+        self.torn.clear();
+        let file = old.get_ref().try_clone();
+        let plain = old.is_plain() && format.is_none();
+        // Dropping the writer makes one last attempt at flushing it
+        drop(old);
+        if !plain {
+            return;
+        }
+        match file.and_then(|mut file| fileutils::truncate_torn_record(&mut file)) {
+            Ok(torn) if torn.is_empty() => (),
+            Ok(torn) => warn!(
+                "cut a torn record of {} bytes from the end of {}'s previous log",
+                torn.len(),
+                &self.vm
+            ),
+            Err(e) => error!("failed to cut {}'s previous log back: {}", &self.vm, e),
+        }
+    }
+
+    /// Sync the log file, timing how long that took
+    fn sync(&mut self) -> std::io::Result<()> {
+        let started = Instant::now();
+        self.writer.get_ref().sync_all()?;
+        let elapsed = started.elapsed().as_millis() as u64;
+        self.counters.histograms().fsync_ms.observe(elapsed);
+        Ok(())
+    }
+
+    /// Flush and sync the log file when its "flush" policy says they're due. Failing to is logged
+    /// rather than returned, since the file is still open and later writes may well succeed.
+    fn flush_if_due(&mut self, now: Instant) {
+        let flush = self.config.flush_for(&self.vm);
+        let (interval, fsync) = (flush.interval(), flush.fsync_interval());
+        if self.unflushed > 0 && interval.map_or(false, |i| now - self.last_flush >= i) {
+            if let Err(e) = self.flush() {
+                warn!("failed to flush {}'s log file: {}", &self.vm, e);
+            }
+        }
+        if !self.unsynced || !fsync.map_or(false, |i| now - self.last_fsync >= i) {
+            return;
+        }
+        if let Err(e) = self.flush() {
+            warn!("failed to flush {}'s log file: {}", &self.vm, e);
+            return;
+        }
+        // Nothing more makes it to the file until the filesystem has room again
+        if self.stall.is_some() {
+            return;
+        }
+        match self.sync() {
+            Ok(()) => {
+                self.unsynced = false;
+                self.last_fsync = now;
+            }
+            Err(e) => warn!("failed to sync {}'s log file: {}", &self.vm, e),
+        }
+    }
+
+    /// Once the log file has grown to "rotate_bytes" on disk, rename it and start a new one, the
+    /// same as logadm would. current.log is renamed after the current time, and a file named by
+    /// "log_name" gets a sequence number added to its name. Anything still buffered ends up in the
+    /// renamed file, so the threshold can be overshot by up to the buffer's size. The file is only
+    /// renamed once everything has been written to it and its successor is open, see
+    /// `start_next_file`.
+    fn rotate_if_full(&mut self) -> std::io::Result<()> {
+        let limit = match self.config.rotate_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.writer.get_ref().metadata()?.len() < limit {
+            return Ok(());
+        }
+        let template = self.layout.log_name.as_ref();
+        let (stamp, first) = match template.or_else(|| self.config.log_name.as_ref()) {
+            Some(_) => (self.file_name.trim_end_matches(".log").to_owned(), 1),
+            None => (self.clock.utc().format("%Y%m%dT%H%M%SZ").to_string(), 0),
+        };
+        let name = (first..)
+            .map(|seq| match seq {
+                0 => format!("{}.log", stamp),
+                _ => format!("{}.{}.log", stamp, seq),
+            })
+            .find(|name| !self.layout.path(name).exists())
+            .expect("some sequence number is free");
+        let file_name = self.file_name.clone();
+        self.start_next_file(Some(&name))?;
+        info!(
+            "{}'s {} reached {} bytes, rotated it to {}",
+            &self.vm, file_name, limit, name
+        );
+        Ok(())
+    }
+
+    /// Open the file that takes over from the full log file, and rotate the full one out to
+    /// `rotated`. When the successor has the same name it's opened under a temporary name first and
+    /// then moved into place, so whatever is written from then on goes to the new file. A file
+    /// left under the temporary name by a crash partway through is started over.
+    fn open_successor(
+        &self,
+        file_name: &str,
+        rotated: &str,
+    ) -> std::io::Result<(LogWriter, Opened)> {
+        let dir = open_zone_dir(&self.layout)?;
+        if file_name != self.file_name {
+            let opened = open_file(&self.layout, &self.vm, file_name, &self.config)?;
+            fileutils::rename_at(&dir, &self.file_name, rotated)?;
+            dir.sync_all()?;
+            return Ok(opened);
+        }
+        let next = format!(".{}.next", file_name);
+        fileutils::remove_at(&dir, &next)?;
+        let (writer, _) = open_file(&self.layout, &self.vm, &next, &self.config)?;
+        fileutils::rotate_at(&dir, file_name, rotated, &next)?;
+        // It's counted under the name it's written under from now on
+        let (opened, _) = Opened::new(self.layout.path(file_name));
+        Ok((writer, opened))
+    }
+
+    /// Flush the log file, append the closed period's `Rollup` to stats.log, and then start the
+    /// file "log_name" currently names, see `Sink::rotate`. With `rotated` the log file is also
+    /// renamed to it, once it's been flushed and its successor is open, see `open_successor`. If
+    /// the new file can't be opened we keep the previous file open, under its old name, and
+    /// return the error.
+    fn start_next_file(&mut self, rotated: Option<&str>) -> std::io::Result<()> {
+        // The backlog belongs in the open file. Once it has been written out `check` notices the
+        // file was rotated and reopens it.
+        if self.stall.is_some() {
+            return Ok(());
+        }
+        // The flows so far belong with the events of the period that's ending
+        if let Err(e) = self.write_flows() {
+            warn!("failed to write {}'s flows: {}", &self.vm, e);
+        }
+        // The old file may be picked up as soon as we let go of it, such as for logadm to compress,
+        // so every record has to be in it whole by then
+        let finished = self.finish_torn().and_then(|()| self.writer.flush());
+        if finished.is_ok() {
+            let _ = self.sync();
+        }
+        let now = self.clock.utc();
+        // The stats are only informational so failing to write them shouldn't prevent us
+        // from continuing to log events.
+        if let Err(e) = write_rollup(
+            &self.vm,
+            &self.layout,
+            &self.counters,
+            &self.rules,
+            self.period_start,
+            now,
+        ) {
+            error!("failed to write {}'s rollup stats: {}", &self.vm, e);
+        }
+        self.period_start = now;
+        self.rules_start = now;
+        let file_name = log_name(&self.config, &self.layout, now);
+        let (writer, opened) = match rotated {
+            Some(rotated) => self.open_successor(&file_name, rotated)?,
+            None => open_file(&self.layout, &self.vm, &file_name, &self.config)?,
+        };
+        // Drop the old writer and create a new one. The old file stays counted until we're done
+        // with it.
+        let old = std::mem::replace(&mut self.writer, writer);
+        let _old_opened = std::mem::replace(&mut self.opened, opened);
+        if let Err(e) = finished {
+            warn!(
+                "failed to finish writing {}'s previous log: {}",
+                &self.vm, e
+            );
+            self.cut_torn_record(old, self.format);
+        }
+        let now = self.clock.now();
+        self.unflushed = 0;
+        self.last_flush = now;
+        self.unsynced = false;
+        self.last_fsync = now;
+        self.format = self.config.sink_formats.get("file").copied();
+        self.file_name = file_name;
+        if let Err(e) = self.write_lifecycle(Lifecycle::Rotate) {
+            warn!("failed to mark {}'s rotation: {}", &self.vm, e);
+        }
+        archive::process_rotated(&self.layout.dir, &self.file_name, &self.config);
+        upload::queue_rotated(&self.layout, &self.vm, &self.file_name, &self.config);
+        Ok(())
+    }
+}
+
+impl Sink for ZoneLog {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    /// Serialize the records out to current.log, or hold on to them while the filesystem is full.
+    /// The whole batch is encoded first and written with a single write, so a batch larger than
+    /// the write buffer goes straight to the file rather than a buffer's worth at a time. If the
+    /// file only takes part of the batch, the rest of the record it stopped in is kept in `torn`
+    /// and finished before anything else is written, and the records after it are held or lost
+    /// whole, so that no record is ever written twice or split up.
+    fn write_batch(&mut self, records: &[Record<'_>]) -> std::io::Result<()> {
+        // Every encoded record, along with where its line starts in `lines`
+        let mut lines = vec![];
+        let mut encoded = Vec::with_capacity(records.len());
+        let mut bytes = 0;
+        let mut written = vec![];
+        // The records that were either written or held
+        let mut kept = Vec::with_capacity(records.len());
+        for record in records {
+            // Aggregated events are written as part of their flow's record
+            if let (Some(flows), CfwEvent::Traffic(event)) = (&mut self.flows, &record.event) {
+                if flows.absorb(event) {
+                    written.push(record);
+                    kept.push(record);
+                    continue;
+                }
+            }
+            encoded.push((record, lines.len()));
+            encode_record(record, self.format, &self.config, &mut lines)?;
+        }
+        if !encoded.is_empty() && self.stall.is_none() {
+            let result = match self.finish_torn() {
+                Ok(()) => write_counted(&mut self.writer, &lines),
+                Err(e) => Err((0, e)),
+            };
+            let taken = match &result {
+                Ok(()) => lines.len(),
+                Err((taken, _)) => *taken,
+            };
+            // Every record the file took any of counts as written, the one it stopped in is
+            // finished later
+            let split = encoded
+                .iter()
+                .take_while(|(_, start)| *start < taken)
+                .count();
+            let end = encoded.get(split).map_or(lines.len(), |(_, start)| *start);
+            self.torn.extend_from_slice(&lines[taken..end]);
+            bytes += taken as u64;
+            let (done, histograms) = (self.clock.utc(), self.counters.histograms());
+            for (record, _) in encoded.drain(..split) {
+                if let CfwEvent::Traffic(event) = &record.event {
+                    let latency = done.signed_duration_since(event.timestamp);
+                    histograms
+                        .latency_ms
+                        .observe(latency.num_milliseconds().max(0) as u64);
+                }
+                written.push(record);
+                kept.push(record);
+            }
+            if split > 0 {
+                histograms.batch_records.observe(split as u64);
+                self.unflushed += split as u64;
+                self.unsynced = true;
+            }
+            match result {
+                Ok(()) => {
+                    let every = self.config.flush_for(&self.vm).every_records;
+                    if every.map_or(false, |every| self.unflushed >= every) {
+                        self.flush()?;
+                    }
+                }
+                Err((_, e)) if is_disk_full(&e) => self.stall_writes(&e),
+                Err((_, e)) => return Err(e),
+            }
+        }
+        // Whatever is left wasn't written because the filesystem is full
+        for (i, (record, start)) in encoded.iter().enumerate() {
+            let end = encoded.get(i + 1).map_or(lines.len(), |(_, next)| *next);
+            let stall = self.stall.as_mut().expect("writes are stalled");
+            if stall.backlog.len() < self.config.disk.full_buffer_records {
+                stall
+                    .backlog
+                    .push_back((record.event.seq(), lines[*start..end].to_vec()));
+                kept.push(*record);
+            } else {
+                stall.dropped += 1;
+                self.counters.dropped(DropReason::DiskFull);
+                self.audit.dropped(&record.event);
+            }
+        }
+        self.audit.written(
+            &self
+                .audit
+                .sequences(written.iter().map(|record| &record.event)),
+        );
+
+        let count = kept.len() as u64;
+        self.counters.written(count);
+        for record in &kept {
+            if let CfwEvent::Traffic(event) = &record.event {
+                self.counters.event_written(&event.event);
+                self.counters.event_at(&event.timestamp);
+                if let Some(talkers) = &mut self.talkers {
+                    talkers.event_written(event);
+                }
+            }
+        }
+        if let Some(filter) = self.config.alerts.as_ref().and_then(|a| a.filter.as_ref()) {
+            let matched = kept.iter().filter(|record| filter.matches(record)).count();
+            self.counters.alerting_written(matched as u64);
+        }
+        if let Some(zone_thresholds) = &mut self.thresholds {
+            let (now, utc) = (self.clock.now(), self.clock.utc());
+            for record in &kept {
+                for alert in zone_thresholds.written(record, now, utc) {
+                    thresholds::raise(alert);
+                }
+            }
+        }
+        self.counters
+            .rules_written(kept.iter().filter_map(|record| match &record.event {
+                CfwEvent::Traffic(event) => Some(event.rule_uuid),
+                CfwEvent::Unknown(_) => None,
+            }));
+        self.stats.records += count;
+        self.stats.bytes += bytes;
+        if self.stall.is_some() {
+            return Ok(());
+        }
+        self.rotate_if_full()
+    }
+
+    /// Flush the log file, which stalls writes if the filesystem is full
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.stall.is_some() {
+            return Ok(());
+        }
+        match self.writer.flush() {
+            Err(e) if is_disk_full(&e) => {
+                self.stall_writes(&e);
+                Ok(())
+            }
+            Err(e) => Err(e),
+            Ok(()) => {
+                self.unflushed = 0;
+                self.last_flush = self.clock.now();
+                Ok(())
+            }
+        }
+    }
+
+    /// Start a new log file, once logadm or something else renamed the old one or "log_name"
+    /// names a different file, see `start_next_file`.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.start_next_file(None)
+    }
+
+    /// If current.log was renamed or removed by something other than logadm (which would have
+    /// sent us a SIGUSR1) treat it the same as a rotation so that we don't keep writing to a file
+    /// nobody can see. This is only checked once every `LOG_FILE_CHECK_INTERVAL`, and an error is
+    /// only returned if current.log needed to be reopened but couldn't be. Any periodic records
+    /// that are due are written first, and the log is rotated as soon as "log_name" names a
+    /// different file. While the filesystem is full, retrying the writes is all that happens.
+    fn check(&mut self) -> std::io::Result<()> {
+        let now = self.clock.now();
+        if self
+            .stall
+            .as_ref()
+            .map_or(false, |stall| now >= stall.retry_at)
+        {
+            self.retry_stalled()?;
+        }
+        if self.stall.is_some() {
+            return Ok(());
+        }
+        self.write_periodic();
+        self.flush_if_due(now);
+        if log_name(&self.config, &self.layout, self.clock.utc()) != self.file_name {
+            return self.rotate();
+        }
+        self.writer.check(now)?;
+        if now - self.last_check < LOG_FILE_CHECK_INTERVAL {
+            return Ok(());
+        }
+        self.last_check = now;
+        let current_log = self.layout.path(&self.file_name);
+        match fileutils::file_replaced(self.writer.get_ref(), &current_log) {
+            Ok(false) => Ok(()),
+            Ok(true) => {
+                warn!(
+                    "{}'s {} was replaced or removed, reopening it",
+                    &self.vm, self.file_name
+                );
+                self.rotate()
+            }
+            Err(e) => {
+                warn!("failed to check {}'s {}: {}", &self.vm, self.file_name, e);
+                Ok(())
+            }
+        }
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.config = Arc::clone(config);
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        self.retry_stalled()?;
+        if let Some(stall) = self.stall.take() {
+            warn!(
+                "{}'s log file still can't be written, dropping the {} records held for it",
+                &self.vm,
+                stall.backlog.len()
+            );
+            for _ in 0..stall.backlog.len() {
+                self.counters.dropped(DropReason::DiskFull);
+            }
+            let seqs: Vec<u64> = stall.backlog.iter().map(|(seq, _)| *seq).collect();
+            self.audit.dropped_sequences(&seqs);
+            // A record left torn is cut off the next time cfwlogd starts
+            return Ok(());
+        }
+        if let Err(e) = self.write_summaries() {
+            warn!(
+                "failed to summarize {}'s suppressed events: {}",
+                &self.vm, e
+            );
+        }
+        if let Err(e) = self.write_counted() {
+            warn!("failed to summarize {}'s count only rules: {}", &self.vm, e);
+        }
+        if let Err(e) = self.write_flows() {
+            warn!("failed to write {}'s flows: {}", &self.vm, e);
+        }
+        if let Err(e) = self.write_lifecycle(Lifecycle::Stop) {
+            warn!("failed to mark {}'s shutdown: {}", &self.vm, e);
+        }
+        self.writer.flush()?;
+        self.sync()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+/// What cfwlogd did when it wrote a `LifecycleRecord`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Lifecycle {
+    /// Logging started, because either cfwlogd started or this is the zone's first event since
+    Start,
+    /// current.log was reopened after being rotated or replaced
+    Rotate,
+    /// cfwlogd is shutting down gracefully, so no events are being logged until the next start
+    Stop,
+}
+
+/// Logged when cfwlogd starts, stops, or rotates the zone's log, so that gaps in the zone's
+/// records can be explained from the zone's log alone
+#[derive(Serialize)]
+struct LifecycleRecord<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    action: Lifecycle,
+    /// When stopping, how many of the zone's records were logged since logging started, so a
+    /// consumer can tell whether it has seen all of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    records: Option<u64>,
+    /// The version of cfwlogd that did it
+    version: &'static str,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// The remote addresses a zone blocked the most over the window, and the rules that logged the
+/// most of its events, see the "talkers" module
+#[derive(Serialize)]
+struct TopTalkersSummary<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    #[serde(flatten)]
+    top: Top,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// A flow of the zone's events over the window, see the "flows" module
+#[derive(Serialize)]
+struct FlowSummary<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    window_secs: u64,
+    #[serde(flatten)]
+    flow: Flow,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Logged once the event source reports having dropped events, see `stats::record_source_lost`
+#[derive(Serialize)]
+struct EventsLost<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    /// Events the source dropped on this host since the last record, not only the zone's
+    lost: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Logged in place of the events of a "count_only" rule
+#[derive(Serialize)]
+struct CountSummary<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    #[serde(flatten)]
+    report: RuleReport,
+    period_secs: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Logged every "heartbeat_secs"
+#[derive(Serialize)]
+struct HeartbeatRecord<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    /// Events written to the zone's log over the period
+    events: u64,
+    /// Bytes written to the zone's log over the period, including cfwlogd's own records
+    bytes: u64,
+    /// Events dropped rather than written over the period, for any reason
+    dropped: u64,
+    period_secs: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Logged in place of the events sampling suppressed
+#[derive(Serialize)]
+struct SuppressionSummary<'a> {
+    schema_version: SchemaVersion,
+    event: &'static str,
+    vm: &'a str,
+    reason: DropReason,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rule: Option<Uuid>,
+    suppressed: u64,
+    period_secs: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    timestamp: DateTime<Utc>,
+}
+
+/// Every `Sink` a Logger writes its zone's records to. The zone's `ZoneLog` is always the first
+/// sink, and failures writing to it are treated as fatal to the Logger just as they were before
+/// there were other sinks, apart from the filesystem filling up which it waits out itself. Failures
+/// in any other sink are logged and otherwise ignored so that a broken destination can't stop
+/// events from reaching the zone's log file.
+///
+/// Sinks other than the zone's log file only receive the records matching their entry in the
+/// config's `sink_filters`, if they have one.
+struct ZoneSinks {
+    vm: String,
+    /// The zone as its logger found it, for the events still queued once it's gone from `Vmobjs`
+    /// or its zonedid was given to another zone, see `VmTable::get_zone`
+    zone: Arc<Zone>,
+    sinks: Vec<Box<dyn Sink>>,
+    rules: Rules,
+    config: Arc<Config>,
+    node: Option<Arc<NodeIdentity>>,
+    /// Set when the zone or any rule is sampled, see the "sampling" module
+    sampler: Option<Sampler>,
+    /// Set when "coalesce" is configured
+    coalescer: Option<Coalescer>,
+    /// For counting the events of "count_only" rules, which the zone's `ZoneLog` summarizes
+    counters: Arc<ZoneCounters>,
+    audit: Arc<LossAudit>,
+}
+
+/// Write a batch of the vm's records to one of its sinks
+fn write_records(sink: &mut dyn Sink, vm: &str, records: &[Record<'_>]) -> std::io::Result<()> {
+    probe!(write(vm, sink.name(), records.len() as u64));
+    sink.write_batch(records)
+}
+
+impl ZoneSinks {
+    /// Pair each `CfwEvent` with its zone's information and write them out to every sink,
+    /// returning the number of events that were handled, either written or left out by the
+    /// config's "log_actions" or drop filters, by sampling or by coalescing repeats
+    fn write(&mut self, mut events: Vec<CfwEvent>, vmobjs: &Vmobjs) -> u64 {
+        let handled = events.len() as u64;
+        // Left out before any other work is done on them, since for some zones these are the
+        // vast majority of events
+        let actions = self.config.log_actions_for(&self.vm);
+        if actions != LogActions::Both {
+            let audit = &self.audit;
+            events.retain(|event| match event {
+                CfwEvent::Traffic(traffic)
+                    if !actions.logs(&traffic.event) && selftest::test_id(event).is_none() =>
+                {
+                    audit.dropped(event);
+                    false
+                }
+                _ => true,
+            });
+        }
+        let events = match &mut self.coalescer {
+            Some(coalescer) => coalescer.coalesce(events, Instant::now(), &self.audit),
+            None => events.into_iter().map(|event| (event, None)).collect(),
+        };
+        self.write_coalesced(events, vmobjs);
+        handled
+    }
+
+    /// Log the zone's current run of repeated events once its window is over, or whatever the
+    /// run is up to when `finish` is set
+    fn write_repeats(&mut self, vmobjs: &Vmobjs, finish: bool) {
+        let ended = match &mut self.coalescer {
+            Some(coalescer) if finish => coalescer.finish(),
+            Some(coalescer) => coalescer.take_due(Instant::now()),
+            None => None,
+        };
+        if let Some(ended) = ended {
+            self.write_coalesced(vec![ended], vmobjs);
+        }
+    }
+
+    /// Write out events along with the number of repeats each stands for, see `write`
+    fn write_coalesced(&mut self, events: Vec<(CfwEvent, Option<u64>)>, vmobjs: &Vmobjs) {
+        let vmobjs = vmobjs.load();
+        let opened = Arc::clone(&self.zone);
+        let rules = self.rules.read().unwrap();
+        let epoch_unit = self.config.epoch_timestamp;
+        let receive_timestamps = self.config.receive_timestamps;
+        // The nics of a zone that's no longer in vmobjs, only when they're needed
+        let nic_context = self.config.nic_context;
+        let opened_nics = if nic_context {
+            nic::zone_nics(&opened)
+        } else {
+            vec![]
+        };
+        // Looked up ahead of pairing since that moves the events into their records
+        let enricher = enrich::enricher();
+        let annotations: Vec<Option<Arc<Annotation>>> = events
+            .iter()
+            .map(|(event, _)| match (&enricher, event) {
+                (Some(enricher), CfwEvent::Traffic(event)) => {
+                    enricher.annotation(&enrich::remote_addr(event))
+                }
+                _ => None,
+            })
+            .collect();
+        // The records only borrow the fields they need, which leaves the sinks free to write them
+        let (vm, node) = (self.vm.as_str(), self.node.as_deref());
+        let records: Vec<Record> = events
+            .into_iter()
+            .zip(annotations.iter())
+            .map(|((event, repeats), annotation)| {
+                let vmobj = vmobjs.get_zone(&event.zone(), vm).unwrap_or(&opened);
+                // Check if the zone has an alias set, if not we provide a default one
+                // Note instead of String::as_ref we could also use "|s| &**s"
+                let alias = vmobj.alias.as_ref().map_or("", String::as_ref);
+                let (rule_owner, epoch, received, nic) = match &event {
+                    CfwEvent::Traffic(event) => (
+                        rules.get(&event.rule_uuid),
+                        epoch_unit.map(|unit| Epoch::new(unit, &event.timestamp)),
+                        Some(event)
+                            .filter(|_| receive_timestamps)
+                            .and_then(ReceiveTimes::new),
+                        Some(event).filter(|_| nic_context).and_then(|event| {
+                            let nics = vmobjs.nics(&event.zonedid, vm);
+                            nic::attribute(nics.unwrap_or(&opened_nics), event)
+                        }),
+                    ),
+                    CfwEvent::Unknown(_) => (None, None, None, None),
+                };
+                Record {
+                    rule_owner,
+                    annotation: annotation.as_deref(),
+                    repeats,
+                    epoch,
+                    received,
+                    node,
+                    nic,
+                    ..Record::new(event, &vmobj.uuid, alias)
+                }
+            })
+            .collect();
+        // Owned, so that the filters borrowed from it leave the sampler free to be borrowed mutably
+        let config = Arc::clone(&self.config);
+        let zone_filter = Uuid::parse_str(&self.vm)
+            .ok()
+            .and_then(|vm| config.zone_drop_filters.get(&vm));
+        let drop_filters: Vec<&Expr> = config.drop_filter.iter().chain(zone_filter).collect();
+        let (sampler, audit, counters) = (&mut self.sampler, &self.audit, &self.counters);
+        let count_only = config.count_only.as_ref();
+        // Once the zone is over its disk budget its events are only accounted for
+        let over_budget = config
+            .disk_budget
+            .as_ref()
+            .filter(|_| counters.over_budget())
+            .map(|budget| budget.action);
+        // While an operator has the zone paused its events are only counted
+        let paused = counters.pause().is_paused();
+        let now = Instant::now();
+        let records: Vec<Record> = records
+            .into_iter()
+            .filter_map(|mut record| {
+                // Nothing about the zone's config should fail a self-test
+                if selftest::test_id(&record.event).is_some() {
+                    return Some(record);
+                }
+                if drop_filters.iter().any(|filter| filter.matches(&record)) {
+                    audit.dropped(&record.event);
+                    return None;
+                }
+                if paused {
+                    counters.pause().counted(match &record.event {
+                        CfwEvent::Traffic(traffic) => Some(traffic.rule_uuid),
+                        CfwEvent::Unknown(_) => None,
+                    });
+                    audit.dropped(&record.event);
+                    return None;
+                }
+                if let Some(action) = over_budget {
+                    let rule = match &record.event {
+                        CfwEvent::Traffic(traffic) => Some(traffic.rule_uuid),
+                        CfwEvent::Unknown(_) => None,
+                    };
+                    match (action, rule) {
+                        (BudgetAction::CountOnly, Some(rule)) => {
+                            counters.counted(rule, record.repeats.unwrap_or(1))
+                        }
+                        _ => {
+                            counters.dropped(DropReason::DiskBudget);
+                            counters.suppressed(DropReason::DiskBudget, rule);
+                        }
+                    }
+                    audit.dropped(&record.event);
+                    return None;
+                }
+                if count_only.map_or(false, |count_only| count_only.counts(&record)) {
+                    if let CfwEvent::Traffic(traffic) = &record.event {
+                        counters.counted(traffic.rule_uuid, record.repeats.unwrap_or(1));
+                    }
+                    audit.dropped(&record.event);
+                    return None;
+                }
+                let decision = match (sampler.as_mut(), &record.event) {
+                    (Some(sampler), CfwEvent::Traffic(traffic)) => sampler.sample(traffic, now),
+                    _ => Decision::Unsampled,
+                };
+                match decision {
+                    Decision::Unsampled => (),
+                    Decision::Keep(events) => record.sampled = Some(events),
+                    Decision::Skip => {
+                        audit.dropped(&record.event);
+                        return None;
+                    }
+                }
+                Some(record)
+            })
+            .collect();
+        let tests: Vec<u16> = records
+            .iter()
+            .filter_map(|record| selftest::test_id(&record.event))
+            .collect();
+        for id in &tests {
+            selftest::routed(*id, self.sinks.iter().map(|sink| sink.name()));
+        }
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            let filter = match i {
+                0 => None,
+                _ => config.sink_filter(&self.vm, sink.name()),
+            };
+            let result = match filter {
+                Some(filter) => {
+                    let routed: Vec<Record> = records
+                        .iter()
+                        .filter(|record| {
+                            filter.matches(record) || selftest::test_id(&record.event).is_some()
+                        })
+                        .cloned()
+                        .collect();
+                    if routed.is_empty() {
+                        Ok(())
+                    } else {
+                        write_records(sink.as_mut(), &self.vm, &routed)
+                    }
+                }
+                None => write_records(sink.as_mut(), &self.vm, &records),
+            };
+            for id in &tests {
+                let result = result.as_ref().map(|_| ()).map_err(|e| e.to_string());
+                selftest::written(*id, sink.name(), result);
+            }
+            if let Err(e) = result {
+                // The zone's log file rides out ENOSPC/EDQUOT on its own, so anything else it
+                // fails with means we are in a bad place and should exit to let the operator
+                // know.
+                if i == 0 {
+                    exit::fault(
+                        Subsystem::Logger,
+                        &format!("failed to write to {}'s log file: {}", &self.vm, e),
+                    );
+                }
+                error!(
+                    "failed to write to {}'s {} sink: {}",
+                    &self.vm,
+                    sink.name(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Run `op` on every sink, returning the zone log's result
+    fn each<F>(&mut self, what: &str, mut op: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut dyn Sink) -> std::io::Result<()>,
+    {
+        let mut result = Ok(());
+        for (i, sink) in self.sinks.iter_mut().enumerate() {
+            match op(sink.as_mut()) {
+                Err(e) if i == 0 => result = Err(e),
+                Err(e) => error!(
+                    "failed to {} {}'s {} sink: {}",
+                    what,
+                    &self.vm,
+                    sink.name(),
+                    e
+                ),
+                Ok(()) => (),
+            }
+        }
+        result
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.each("flush", |sink| sink.flush())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.each("rotate", |sink| sink.rotate())
+    }
+
+    fn check(&mut self) -> std::io::Result<()> {
+        self.each("check", |sink| sink.check())
+    }
+
+    fn reload(&mut self, config: Arc<Config>) {
+        for sink in &mut self.sinks {
+            sink.reload(&config);
+        }
+        if config.sampling != self.config.sampling {
+            self.sampler = Sampler::new(&config.sampling, &self.vm, Instant::now());
+        }
+        self.config = config;
+    }
+
+    /// How long the `Logger` may wait on its channels before its sinks need checking again, which
+    /// is sooner than `HEARTBEAT_INTERVAL` when the zone's log is flushed on a shorter timer
+    fn check_interval(&self) -> Duration {
+        let interval = self.config.flush_for(&self.vm).interval();
+        let window = self
+            .config
+            .coalesce
+            .as_ref()
+            .map(|coalesce| Duration::from_millis(coalesce.window_ms));
+        interval
+            .into_iter()
+            .chain(window)
+            .fold(health::HEARTBEAT_INTERVAL, Duration::min)
+    }
+
+    fn close(&mut self) -> std::io::Result<()> {
+        let result = self.each("close", |sink| sink.close());
+        for sink in &self.sinks {
+            let stats = sink.stats();
+            debug!(
+                "{}'s {} sink wrote {} records ({} bytes), dropped {}",
+                &self.vm,
+                sink.name(),
+                stats.records,
+                stats.bytes,
+                stats.dropped
+            );
+        }
+        result
+    }
+
+    /// Process a signal sent to the Logger, and return true if the Logger was told to shutdown
+    fn handle_signal(&mut self, signal: LoggerSignal) -> bool {
+        match signal {
+            LoggerSignal::Rotate => {
+                if let Err(e) = self.rotate() {
                     // CMON TRITON-1755
-                    error!("failed to open {}'s log file after rotation: {}", &vm, e);
+                    error!(
+                        "failed to open {}'s log file after rotation: {}",
+                        &self.vm, e
+                    );
                     return true;
                 }
-            };
-            // Drop the old writer and create a new one
-            *writer = BufWriter::with_capacity(1024 * 1024, file);
+            }
+            LoggerSignal::Shutdown | LoggerSignal::Retire => return true,
+            LoggerSignal::Reload(config) => self.reload(config),
+            LoggerSignal::Flush => {
+                info!("flushing log for {}", self.vm);
+                // A full filesystem doesn't fail the flush, so if flushing fails anyway we should
+                // just exit to let the operator know we are in a bad place.
+                if let Err(e) = self.flush() {
+                    exit::fault(
+                        Subsystem::Logger,
+                        &format!("failed to flush log for {}: {}", self.vm, e),
+                    );
+                }
+            }
+        }
+        false
+    }
+}
+
+/// Take the next batch of events from a zone's queue, once at least one is ready. Rather than
+/// writing events as they trickle in, we wait up to `delay` for the batch to fill up to `max`
+/// events, so a busy zone's records go out in a few large writes, while a quiet zone's records are
+/// held up by no more than that.
+fn next_batch(events: &queue::Receiver<CfwEvent>, max: usize, delay: Duration) -> Vec<CfwEvent> {
+    let deadline = Instant::now() + delay;
+    let mut batch = Vec::with_capacity(max.min(1024));
+    while batch.len() < max {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match events.recv_timeout(remaining) {
+            Ok(event) => batch.push(event),
+            Err(_) => break,
+        }
+    }
+    batch
+}
+
+/// Everything a zone's logger is started with
+pub struct ZoneSetup {
+    zonedid: Zonedid,
+    vm: String,
+    zone: Arc<Zone>,
+    customer: String,
+    layout: Layout,
+    vmobjs: Vmobjs,
+    rules: Rules,
+    counters: Arc<ZoneCounters>,
+    config: Arc<Config>,
+    memory: Arc<MemoryTracker>,
+    audit: Arc<LossAudit>,
+    live: Arc<LiveHub>,
+    clock: SharedClock,
+    node: Option<Arc<NodeIdentity>>,
+    events: queue::Receiver<CfwEvent>,
+    signal: channel::Receiver<LoggerSignal>,
+    heartbeat: Arc<Heartbeat>,
+}
+
+/// A zone's logger once its sinks are open. It runs on a thread of its own, or alongside other
+/// zones' on one of the "logger_workers", see the "workers" module.
+pub struct ZoneTask {
+    zonedid: Zonedid,
+    log: ZoneSinks,
+    vmobjs: Vmobjs,
+    memory: Arc<MemoryTracker>,
+    pub events: queue::Receiver<CfwEvent>,
+    pub signal: channel::Receiver<LoggerSignal>,
+    heartbeat: Arc<Heartbeat>,
+    /// Set when the zone was deleted rather than cfwlogd shutting down
+    retired: bool,
+}
+
+impl ZoneTask {
+    /// Open the zone's log file and the rest of its sinks. If the log file can't be opened the
+    /// events already queued for the zone are dropped, and there's nothing to run.
+    pub fn open(setup: ZoneSetup) -> Option<ZoneTask> {
+        let ZoneSetup {
+            zonedid,
+            vm,
+            zone,
+            customer,
+            layout,
+            vmobjs,
+            rules,
+            counters,
+            config,
+            memory,
+            audit,
+            live,
+            clock,
+            node,
+            events,
+            signal,
+            heartbeat,
+        } = setup;
+        // Everything a zone logs is serialized the way its config says, see `Config::serialization`
+        let _serialization = config.serialization().apply();
+        #[cfg(not(any(
+            feature = "elasticsearch",
+            feature = "cloudwatch",
+            feature = "dynamic-sinks"
+        )))]
+        let _ = &customer;
+        let cmon = if config.cmon_metrics {
+            Some(CmonSink::new(
+                layout.dir.clone(),
+                Arc::clone(&counters),
+                Arc::clone(&clock),
+            ))
+        } else {
+            None
+        };
+        let log = match ZoneLog::open(
+            vm.clone(),
+            layout.clone(),
+            Arc::clone(&counters),
+            Arc::clone(&rules),
+            Arc::clone(&config),
+            clock,
+            Arc::clone(&audit),
+        ) {
+            Ok(log) => log,
+            Err(e) => {
+                // CMON TRITON-1755
+                error!("failed to open log file: {}", e);
+                for event in events.try_iter() {
+                    audit.dropped(&event);
+                    memory.events_done(1);
+                }
+                return None;
+            }
+        };
+        memory.buffer_allocated(buf_size(&config));
+        logadm::zone_started(zonedid, &vm, &layout, &config);
+        let mut sinks: Vec<Box<dyn Sink>> = vec![
+            Box::new(log),
+            Box::new(LiveSink::new(live, Encoder::for_sink("live", &config))),
+        ];
+        if let Some(cmon) = cmon {
+            sinks.push(Box::new(cmon));
+        }
+        if config.stdout {
+            sinks.push(Box::new(StdoutSink::new(Encoder::for_sink(
+                "stdout", &config,
+            ))));
+        }
+        if let Some(syslog) = syslog::open_sink(&layout.dir, &config) {
+            sinks.push(Box::new(syslog));
+        }
+        if let Some(ipfix) = ipfix::open_sink() {
+            sinks.push(Box::new(ipfix));
+        }
+        if let Some(zone_fs) = zonefs::open_sink(&zone, &config) {
+            sinks.push(Box::new(zone_fs));
+        }
+        #[cfg(feature = "elasticsearch")]
+        sinks.extend(
+            elasticsearch::open_sink(&customer, &layout.dir, &config)
+                .map(|sink| Box::new(sink) as Box<dyn Sink>),
+        );
+        #[cfg(feature = "cloudwatch")]
+        sinks.extend(
+            cloudwatch::open_sink(&customer, &vm, &config)
+                .map(|sink| Box::new(sink) as Box<dyn Sink>),
+        );
+        #[cfg(feature = "webhook")]
+        sinks.extend(webhook::open_sink(&config).map(|sink| Box::new(sink) as Box<dyn Sink>));
+        #[cfg(feature = "parquet")]
+        sinks.extend(
+            parquet_sink::open_sink(&layout.dir, &config)
+                .map(|sink| Box::new(sink) as Box<dyn Sink>),
+        );
+        #[cfg(feature = "dynamic-sinks")]
+        sinks.extend(plugin::open_sinks(&vm, &customer, &config));
+        let sinks = fanout::queue_sinks(&vm, sinks, &config);
+        let log = ZoneSinks {
+            sampler: Sampler::new(&config.sampling, &vm, Instant::now()),
+            coalescer: config
+                .coalesce
+                .as_ref()
+                .map(|coalesce| Coalescer::new(Duration::from_millis(coalesce.window_ms))),
+            vm,
+            zone,
+            sinks,
+            rules,
+            config: Arc::clone(&config),
+            node,
+            counters,
+            audit: Arc::clone(&audit),
+        };
+        Some(ZoneTask {
+            zonedid,
+            log,
+            vmobjs,
+            memory,
+            events,
+            signal,
+            heartbeat,
+            retired: false,
+        })
+    }
+
+    /// How long the logger may wait for events or a signal
+    pub fn check_interval(&self) -> Duration {
+        self.log.check_interval()
+    }
+
+    /// Write out the next batch of the zone's events, waiting up to `delay` for it to fill up
+    pub fn write_events(&mut self, delay: Duration) {
+        let _serialization = self.log.config.serialization().apply();
+        let batch = next_batch(&self.events, self.log.config.queues.batch_records, delay);
+        probe!(dequeue(
+            self.zonedid,
+            self.log.vm.as_str(),
+            batch.len() as u64
+        ));
+        let written = self.log.write(batch, &self.vmobjs);
+        self.memory.events_done(written as usize);
+    }
+
+    /// Handle the signal waiting for the logger, returning true once it was told to stop
+    pub fn handle_signal(&mut self) -> bool {
+        let _serialization = self.log.config.serialization().apply();
+        match self.signal.try_recv() {
+            Ok(signal) => {
+                let retire = signal == LoggerSignal::Retire;
+                let stop = self.log.handle_signal(signal);
+                self.retired = stop && retire;
+                stop
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => {
+                warn!(
+                    "{}'s signal channel was disconnected which means the Logger itself was \
+                     dropped so we can safely shutdown as well",
+                    &self.log.vm
+                );
+                true
+            }
+        }
+    }
+
+    /// Show the logger is still making progress, and write out whatever periodic records are due.
+    /// Returns false once the zone's log file can't be written anymore.
+    pub fn check(&mut self) -> bool {
+        let _serialization = self.log.config.serialization().apply();
+        self.heartbeat.beat();
+        self.log.write_repeats(&self.vmobjs, false);
+        match self.log.check() {
+            Ok(()) => true,
+            Err(e) => {
+                // CMON TRITON-1755
+                error!("failed to reopen {}'s log file: {}", &self.log.vm, e);
+                false
+            }
         }
-        LoggerSignal::Shutdown => return true,
-        LoggerSignal::Flush => {
-            info!("flushing log for {}", vm);
-            // If flushing fails, we are once again most likely hitting something like ENOSPC,
-            // which means we should just abort to let the operator know we are in a bad place.
-            writer
-                .flush()
-                .unwrap_or_else(|_| panic!("failed to flush log for {}", vm));
+    }
+
+    /// Drain the events still queued for the zone and close its sinks. A retired zone is removed
+    /// from `Vmobjs` once that's done.
+    pub fn finish(self) {
+        let ZoneTask {
+            zonedid,
+            mut log,
+            vmobjs,
+            memory,
+            events,
+            retired,
+            ..
+        } = self;
+        let _serialization = log.config.serialization().apply();
+        let batch: Vec<CfwEvent> = events.try_iter().collect();
+        probe!(dequeue(zonedid, log.vm.as_str(), batch.len() as u64));
+        let written = log.write(batch, &vmobjs);
+        memory.events_done(written as usize);
+        log.write_repeats(&vmobjs, true);
+        // Anything sent from now on fails rather than waiting in a queue nobody reads
+        drop(events);
+        let _res = log.close();
+        memory.buffer_freed(buf_size(&log.config));
+        if retired {
+            logadm::zone_retired(zonedid, &log.config);
+            vmobjs.update(|vms| vms.remove_zone(&zonedid, &log.vm));
+            info!("retired the logger for deleted zone {}", &log.vm);
         }
     }
-    false
-}
 
-/// Start the actual logging thread that receives events or signals on channels and loops forever
-/// until it is told to no longer do so.
-fn _start_logger(
-    vm: String,
-    customer: String,
-    vmobjs: Vmobjs,
-    events: channel::Receiver<CfwEvent>,
-    signal: channel::Receiver<LoggerSignal>,
-) -> thread::JoinHandle<()> {
-    thread::Builder::new()
-        .name(vm.clone())
-        .spawn(move || {
-            let file = match open_file(&vm, &customer) {
-                Ok(file) => file,
-                Err(e) => {
-                    // CMON TRITON-1755
-                    error!("failed to open log file: {}", e);
-                    return;
-                }
+    /// Run the logger on the current thread until it's told to stop or its log can't be written
+    fn run(mut self) {
+        loop {
+            self.heartbeat.beat();
+            let ready = {
+                let mut sel = Select::new();
+                let events_ready = sel.recv(&self.events);
+                sel.recv(&self.signal);
+                sel.ready_timeout(self.check_interval())
+                    .map(|i| i == events_ready)
             };
-
-            let mut writer = BufWriter::with_capacity(BUF_SIZE, file);
-            let mut sel = Select::new();
-            let events_ready = sel.recv(&events);
-            let signal_ready = sel.recv(&signal);
-            loop {
-                match sel.ready() {
-                    i if i == events_ready => {
-                        // Wait a small amount of time in hopes of coalescing events coming down
-                        // the channel, which helps reduce the number of calls to yield(2) and
-                        // reduces lock contention on the vmobjs rw lock.
-                        thread::sleep(std::time::Duration::from_nanos(500_000));
-                        log_events(events.try_iter().take(1024).collect(), &mut writer, &vmobjs)
+            match ready {
+                Ok(true) => {
+                    let delay = Duration::from_micros(self.log.config.queues.batch_delay_us);
+                    self.write_events(delay);
+                }
+                Ok(false) => {
+                    if self.handle_signal() {
+                        break;
                     }
-                    i if i == signal_ready => match signal.recv() {
-                        Ok(signal) => {
-                            if logger_handle_signal(&vm, &customer, signal, &mut writer) {
-                                break;
-                            }
-                        }
-                        Err(_) => {
-                            warn!(
-                                "{}'s signal channel was disconnected which means the \\
-                                 Logger itself was dropped so we can safely shutdown as well",
-                                &vm
-                            );
-                        }
-                    },
-                    _ => unreachable!(),
                 }
+                // Nothing happened within the interval, so fall through to checking the file
+                Err(_) => (),
             }
+            if !self.check() {
+                break;
+            }
+        }
+        self.finish();
+    }
+}
 
-            // We are shutting down now so we drain the channel and then drop it
-            log_events(events.try_iter().collect(), &mut writer, &vmobjs);
-            drop(sel);
-            drop(events);
-            let _res = writer.flush();
-        })
-        .expect("failed to spawn Logger thread")
+/// How a `Logger` is waited on at shutdown
+enum Running {
+    /// The zone's own thread
+    Thread(thread::JoinHandle<()>),
+    /// Disconnected once the worker running the zone is done with it
+    Worker(channel::Receiver<()>),
 }
 
-/// Return a Logger if we have information for the zone already otherwise return None
-pub fn start_logger(zonedid: Zonedid, vmobjs: Vmobjs) -> Option<Logger> {
-    // TODO TRITON-1787
-    let (event_tx, event_rx) = channel::unbounded();
+/// Return a Logger if we have information for the zone already otherwise return None. The zone is
+/// handed to a worker when "logger_workers" is set, and otherwise gets a thread of its own.
+#[allow(clippy::too_many_arguments)]
+pub fn start_logger(
+    zonedid: Zonedid,
+    vmobjs: Vmobjs,
+    rules: Rules,
+    stats: &Stats,
+    config: Arc<Config>,
+    memory: Arc<MemoryTracker>,
+    audit: Arc<LossAudit>,
+    live: Arc<LiveHub>,
+    clock: SharedClock,
+    node: Option<Arc<NodeIdentity>>,
+) -> Option<Logger> {
+    let (event_tx, event_rx) = queue::bounded(config.queues.zone_capacity, config.queues.overflow);
     let (signal_tx, signal_rx) = channel::bounded(1);
-    let vms = vmobjs.read().unwrap();
-    if let Some(vm) = vms.get(&zonedid) {
-        let handle = _start_logger(
-            vm.uuid.clone(),
-            vm.owner_uuid.clone(),
-            Arc::clone(&vmobjs),
-            event_rx,
-            signal_rx,
-        );
-        return Some(Logger {
-            uuid: vm.uuid.clone(),
-            handle,
-            sender: event_tx,
-            signal: signal_tx,
-        });
-    }
-    None
+    let vms = vmobjs.load();
+    let vm = vms.get(&zonedid)?;
+    let heartbeat = Arc::new(Heartbeat::new());
+    let setup = ZoneSetup {
+        zonedid,
+        vm: vm.uuid.clone(),
+        zone: Arc::new(vm.clone()),
+        customer: vm.owner_uuid.clone(),
+        layout: Layout::for_zone(vm, &config),
+        vmobjs: Arc::clone(&vmobjs),
+        rules,
+        counters: stats::zone_counters(stats, zonedid),
+        config,
+        memory,
+        audit,
+        live,
+        clock,
+        node,
+        events: event_rx,
+        signal: signal_rx,
+        heartbeat: Arc::clone(&heartbeat),
+    };
+    let running = match workers::assign(setup) {
+        Ok(done) => Running::Worker(done),
+        Err(setup) => Running::Thread(
+            thread::Builder::new()
+                .name(setup.vm.clone())
+                .spawn(move || {
+                    if let Some(task) = ZoneTask::open(setup) {
+                        task.run();
+                    }
+                })
+                .unwrap_or_else(|e| {
+                    exit::fault(
+                        Subsystem::Logger,
+                        &format!("failed to spawn Logger thread: {}", e),
+                    )
+                }),
+        ),
+    };
+    Some(Logger {
+        uuid: vm.uuid.clone(),
+        running,
+        sender: event_tx,
+        signal: signal_tx,
+        limiter: RateLimiter::default(),
+        heartbeat,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::rules::RuleOwner;
     use crate::zones::Vmobjs;
+    use cfwevent::parser;
     use crossbeam::sync::ShardedLock;
     use std::collections::HashMap;
     use std::io::Read;
-    use std::path::PathBuf;
+
+    /// The layout of the zone `vm` of `owner_uuid` under the default config
+    fn layout(vm: &str, owner_uuid: &str) -> Layout {
+        let zone = Zone {
+            uuid: vm.to_owned(),
+            owner_uuid: owner_uuid.to_owned(),
+            ..testutils::create_zone()
+        };
+        Layout::for_zone(&zone, &Config::default())
+    }
+
+    /// A writer that takes a few bytes at a time until it runs out of room
+    struct FillingWriter {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for FillingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.room == 0 {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            let n = buf.len().min(self.room).min(3);
+            self.written.extend_from_slice(&buf[..n]);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn torn_writes_pick_up_where_they_stopped() {
+        let lines = b"{\"a\":1}\n{\"a\":2}\n";
+        let mut writer = FillingWriter {
+            written: vec![],
+            room: 10,
+        };
+        let (taken, e) = write_counted(&mut writer, lines).expect_err("the writer ran out of room");
+        assert_eq!(taken, 10, "the writer took part of the second record");
+        assert!(is_disk_full(&e));
+        writer.room = 100;
+        write_counted(&mut writer, &lines[taken..]).expect("failed to finish the record");
+        assert_eq!(
+            writer.written, lines,
+            "no record was written twice or split up"
+        );
+    }
 
     #[test]
     fn open_file_test() {
         let vm = "zone1";
         let customer = "customer1";
-        let _f = open_file(vm, customer).expect("failed to open file");
+        let _f = open_file(&layout(vm, customer), vm, "current.log", &Config::default())
+            .expect("failed to open file");
         let mut path: PathBuf = [LOG_DIR, customer, vm, "current.log"].iter().collect();
         assert!(path.as_path().is_file(), "current.log file path is correct");
         path.pop(); // current.log
@@ -265,28 +2346,743 @@ mod tests {
     }
 
     #[test]
-    fn log_events_test() {
+    fn opened_files_are_forgotten_once_closed() {
+        let path: PathBuf = [LOG_DIR, "opened-test", "current.log"].iter().collect();
+        let (first, only) = Opened::new(path.clone());
+        assert!(only, "nothing else has the file open");
+        let (second, only) = Opened::new(path.clone());
+        assert!(
+            !only,
+            "a rebooted zone's old logger still has the file open"
+        );
+        drop(first);
+        assert!(OPENED.lock().unwrap().contains_key(&path));
+        drop(second);
+        assert!(!OPENED.lock().unwrap().contains_key(&path));
+    }
+
+    #[test]
+    fn write_rollup_test() {
+        let vm = "zone2";
+        let customer = "customer2";
+        let counters = ZoneCounters::default();
+        counters.written(5);
+        let rule = uuid::Uuid::new_v4();
+        counters.rules_written(vec![rule]);
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        write_rollup(
+            vm,
+            &layout(vm, customer),
+            &counters,
+            &rules,
+            Utc::now(),
+            Utc::now(),
+        )
+        .expect("failed to write rollup");
+
+        let mut path: PathBuf = [LOG_DIR, customer, vm, "stats.log"].iter().collect();
+        let contents = std::fs::read_to_string(&path).expect("failed to read stats.log");
+        let rollup: serde_json::Value =
+            serde_json::from_str(contents.trim_end()).expect("rollup is valid json");
+        assert_eq!(rollup["vm"], vm, "rollup is for the correct vm");
+        assert_eq!(rollup["events_written"], 5, "rollup contains written count");
+        assert_eq!(
+            rollup["rules"][0]["rule"],
+            rule.to_string(),
+            "rollup contains per-rule counts"
+        );
+        assert_eq!(
+            counters.take().events_written,
+            0,
+            "counters were reset after the rollup"
+        );
+
+        path.pop(); // stats.log
+        path.pop(); // zone name
+        std::fs::remove_dir_all(path).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn write_rule_stats_test() {
+        let vm = "zone4";
+        let customer = "customer4";
+        let counters = ZoneCounters::default();
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        let path: PathBuf = [LOG_DIR, customer, vm, "stats.log"].iter().collect();
+        write_rule_stats(
+            vm,
+            &layout(vm, customer),
+            &counters,
+            &rules,
+            Utc::now(),
+            Utc::now(),
+        )
+        .unwrap();
+        assert!(!path.exists(), "nothing is written without any rule hits");
+
+        let rule = uuid::Uuid::new_v4();
+        counters.rules_written(vec![rule, rule]);
+        write_rule_stats(
+            vm,
+            &layout(vm, customer),
+            &counters,
+            &rules,
+            Utc::now(),
+            Utc::now(),
+        )
+        .unwrap();
+        let contents = std::fs::read_to_string(&path).expect("failed to read stats.log");
+        let stats: serde_json::Value = serde_json::from_str(contents.trim_end()).unwrap();
+        assert_eq!(stats["rules"][0]["events"], 2);
+        assert!(
+            stats.get("events_written").is_none(),
+            "only rules are included"
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap().parent().unwrap())
+            .expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_rotate_test() {
+        let vm = "zone3";
+        let customer = "customer3";
+        let counters = Arc::new(ZoneCounters::default());
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::clone(&counters),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(Config::default()),
+            SystemClock::shared(),
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let opened = log.stats();
+        log.write_batch(&[]).expect("failed to write empty batch");
+        assert_eq!(log.stats(), opened, "nothing was written");
+
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        counters.suppressed(DropReason::DiskSpace, None);
+        log.write_summaries().expect("failed to summarize");
+        counters.source_lost(7);
+        log.write_periodic();
+        log.flush().expect("failed to flush zone log");
+        let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        let records: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["event"], "lifecycle");
+        assert_eq!(records[0]["action"], "start");
+        let summary = &records[1];
+        assert_eq!(summary["event"], "suppressed");
+        assert_eq!(summary["reason"], "disk_space");
+        assert_eq!(summary["suppressed"], 1);
+        assert_eq!(records[2]["event"], "events_lost");
+        assert_eq!(records[2]["lost"], 7);
+
+        std::fs::rename(dir.join("current.log"), dir.join("rotated.log"))
+            .expect("failed to rename current.log");
+        log.rotate().expect("failed to rotate zone log");
+        assert!(
+            dir.join("current.log").is_file(),
+            "current.log was reopened"
+        );
+        assert!(dir.join("stats.log").is_file(), "rollup was written");
+        log.close().expect("failed to close zone log");
+        let actions: Vec<serde_json::Value> = std::fs::read_to_string(dir.join("current.log"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["action"].clone())
+            .collect();
+        assert_eq!(
+            actions,
+            vec!["rotate", "stop"],
+            "the reopened log is marked"
+        );
+
+        dir.parent()
+            .map(std::fs::remove_dir_all)
+            .unwrap()
+            .expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_rotates_by_size_test() {
+        let vm = "zone6";
+        let customer = "customer6";
+        let clock: SharedClock = ManualClock::new();
+        let config = Config::from_toml("rotate_bytes = 10").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::new(ZoneCounters::default()),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            Arc::clone(&clock),
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+
+        log.write_batch(&[]).unwrap();
+        assert!(
+            archive::rotated_files(&dir, "current.log")
+                .unwrap()
+                .is_empty(),
+            "buffered records don't count"
+        );
+        for _ in 0..2 {
+            log.flush().unwrap();
+            log.write_batch(&[]).unwrap();
+        }
+        let stamp = clock.utc().format("%Y%m%dT%H%M%SZ");
+        assert_eq!(
+            archive::rotated_files(&dir, "current.log").unwrap(),
+            vec![
+                dir.join(format!("{}.1.log", stamp)),
+                dir.join(format!("{}.log", stamp)),
+            ],
+            "rotations within a second get a sequence number"
+        );
+        assert!(
+            !dir.join(".current.log.next").exists(),
+            "the new current.log was moved into place"
+        );
+        log.close().unwrap();
+        let current = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        assert!(
+            current.contains("\"rotate\""),
+            "a new current.log was started"
+        );
+
+        dir.parent()
+            .map(std::fs::remove_dir_all)
+            .unwrap()
+            .expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_rotates_by_time_test() {
+        let vm = "zone7";
+        let customer = "customer7";
+        let manual = ManualClock::new();
+        let clock: SharedClock = manual.clone();
+        let config = Arc::new(Config::from_toml("log_name = \"%Y%m%dT%H.log\"").unwrap());
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::new(ZoneCounters::default()),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::clone(&config),
+            Arc::clone(&clock),
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let first = log_name(&config, &log.layout, clock.utc());
+        assert!(
+            dir.join(&first).is_file(),
+            "the log is named after the hour"
+        );
+
+        log.check().unwrap();
+        assert_eq!(log.file_name, first, "the hour isn't over yet");
+        manual.advance(Duration::from_secs(3600));
+        log.check().unwrap();
+        let second = log_name(&config, &log.layout, clock.utc());
+        assert_ne!(first, second);
+        assert_eq!(log.file_name, second, "rotated on the hour");
+        assert_eq!(
+            archive::rotated_files(&dir, &second).unwrap(),
+            vec![dir.join(&first)]
+        );
+        log.close().unwrap();
+
+        dir.parent()
+            .map(std::fs::remove_dir_all)
+            .unwrap()
+            .expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_periodic_records_test() {
+        let vm = "zone5";
+        let customer = "customer5";
+        let counters = Arc::new(ZoneCounters::default());
+        let manual = ManualClock::new();
+        let clock: SharedClock = manual.clone();
+        let config = Config::from_toml("rule_stats_secs = 120").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::clone(&counters),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            Arc::clone(&clock),
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let logged = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap_or_default();
+
+        counters.suppressed(DropReason::MemoryPressure, None);
+        counters.rules_written(vec![uuid::Uuid::new_v4()]);
+        log.check().unwrap();
+        log.flush().unwrap();
+        assert_eq!(
+            logged("current.log").lines().count(),
+            1,
+            "nothing is due yet besides the start marker"
+        );
+
+        manual.advance(SUPPRESSION_SUMMARY_INTERVAL);
+        log.check().unwrap();
+        log.flush().unwrap();
+        assert_eq!(
+            logged("current.log").lines().count(),
+            2,
+            "suppression summary"
+        );
+        assert!(logged("stats.log").is_empty(), "rule stats aren't due yet");
+
+        manual.advance(Duration::from_secs(60));
+        log.check().unwrap();
+        let stats: serde_json::Value =
+            serde_json::from_str(logged("stats.log").trim_end()).unwrap();
+        assert_eq!(stats["rules"][0]["events"], 1, "rule stats are written");
+        assert_eq!(
+            (clock.utc()
+                - DateTime::parse_from_rfc3339(stats["period_start"].as_str().unwrap())
+                    .unwrap()
+                    .with_timezone(&Utc))
+            .num_seconds(),
+            120,
+            "the period is measured with the zone log's clock"
+        );
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_heartbeat_test() {
+        let vm = "zone14";
+        let customer = "customer14";
+        let counters = Arc::new(ZoneCounters::default());
+        counters.dropped(DropReason::QueueFull);
+        let clock = ManualClock::new();
+        let config = Config::from_toml("heartbeat_secs = 60").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::clone(&counters),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            Arc::clone(&clock) as SharedClock,
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let heartbeats = || -> Vec<serde_json::Value> {
+            std::fs::read_to_string(dir.join("current.log"))
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .filter(|record: &serde_json::Value| record["event"] == "heartbeat")
+                .collect()
+        };
+
+        counters.dropped(DropReason::RateLimited);
+        clock.advance(Duration::from_secs(59));
+        log.check().unwrap();
+        log.flush().unwrap();
+        assert!(heartbeats().is_empty(), "the heartbeat isn't due yet");
+
+        clock.advance(Duration::from_secs(1));
+        log.check().unwrap();
+        log.flush().unwrap();
+        let heartbeat = &heartbeats()[0];
+        assert_eq!(
+            heartbeat["events"], 0,
+            "a heartbeat is logged without traffic"
+        );
+        assert_eq!(
+            heartbeat["dropped"], 1,
+            "drops before the log opened aren't included"
+        );
+        assert_eq!(heartbeat["period_secs"], 60);
+
+        clock.advance(Duration::from_secs(60));
+        log.check().unwrap();
+        log.flush().unwrap();
+        let heartbeats = heartbeats();
+        assert_eq!(heartbeats.len(), 2);
+        assert_eq!(
+            heartbeats[1]["dropped"], 0,
+            "counts are since the last heartbeat"
+        );
+        assert!(
+            heartbeats[1]["bytes"].as_u64().unwrap() > 0,
+            "the first heartbeat record is counted"
+        );
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_writes_older_schema_test() {
+        let vm = "zone11";
+        let customer = "customer11";
+        let config = Config::from_toml("schema_version = 1").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::new(ZoneCounters::default()),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            ManualClock::new() as SharedClock,
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        log.flush().unwrap();
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        let marker: serde_json::Value = serde_json::from_str(logged.trim_end()).unwrap();
+        assert_eq!(marker["event"], "lifecycle");
+        assert_eq!(marker["schema_version"], 1);
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_marks_shutdown_test() {
+        let vm = "zone12";
+        let customer = "customer12";
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::new(ZoneCounters::default()),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(Config::default()),
+            ManualClock::new() as SharedClock,
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        log.close().expect("failed to close zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        let markers: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(markers.len(), 2);
+        assert_eq!(markers[0]["action"], "start");
+        assert!(markers[0].get("records").is_none());
+        assert_eq!(markers[1]["action"], "stop");
+        assert_eq!(markers[1]["records"], 0);
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_flush_policy_test() {
+        let vm = "zone13";
+        let customer = "customer13";
+        let clock = ManualClock::new();
+        let config = Config::from_toml("[flush]\nevery_records = 2\ninterval_ms = 1000\n").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::new(ZoneCounters::default()),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            Arc::clone(&clock) as SharedClock,
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let logged = || {
+            std::fs::read_to_string(dir.join("current.log"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        let record = Record::new(
+            parser::cfwevent_parse(testutils::generate_event().as_bytes())
+                .unwrap()
+                .1,
+            vm,
+            "",
+        );
+
+        log.write_batch(&[record.clone()]).unwrap();
+        assert_eq!(logged(), 0, "one record is buffered");
+        log.write_batch(&[record.clone()]).unwrap();
+        assert_eq!(logged(), 3, "flushed at the second record");
+        log.write_batch(&[record.clone()]).unwrap();
+        assert_eq!(logged(), 3);
+        log.check().unwrap();
+        assert_eq!(logged(), 3, "the timer isn't due yet");
+        clock.advance(Duration::from_secs(1));
+        log.check().unwrap();
+        assert_eq!(logged(), 4, "flushed on the timer");
+        log.close().unwrap();
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn next_batch_test() {
+        let (tx, rx) = queue::bounded(10, Default::default());
+        let event = || {
+            parser::cfwevent_parse(testutils::generate_event().as_bytes())
+                .unwrap()
+                .1
+        };
+        for _ in 0..3 {
+            assert!(tx.send(event(), |_| ()).is_ok());
+        }
+        let delay = Duration::from_micros(500);
+        assert_eq!(next_batch(&rx, 2, delay).len(), 2, "the batch filled up");
+        assert_eq!(next_batch(&rx, 2, delay).len(), 1, "the delay ran out");
+        assert!(next_batch(&rx, 2, delay).is_empty());
+    }
+
+    #[test]
+    fn zone_log_aggregates_flows_test() {
+        let vm = "7a3b9c1e-5d2f-4e8a-9b6c-0d1e2f3a4b5c";
+        let customer = "customer10";
+        let counters = Arc::new(ZoneCounters::default());
+        let clock = ManualClock::new();
+        let config = format!("[aggregate]\nzones = [\"{}\"]\nwindow_secs = 10\n", vm);
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::clone(&counters),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(Config::from_toml(&config).unwrap()),
+            Arc::clone(&clock) as SharedClock,
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        let logged = || std::fs::read_to_string(dir.join("current.log")).unwrap();
+
+        let event = parser::cfwevent_parse(testutils::generate_event().as_bytes())
+            .unwrap()
+            .1;
+        let records: Vec<Record> = (0..3).map(|_| Record::new(event.clone(), vm, "")).collect();
+        log.write_batch(&records).unwrap();
+        log.flush().unwrap();
+        assert_eq!(logged().lines().count(), 1, "only the start marker");
+        assert_eq!(
+            counters.peek().events_written,
+            3,
+            "aggregated events count as written"
+        );
+
+        clock.advance(Duration::from_secs(10));
+        log.check().unwrap();
+        log.flush().unwrap();
+        let flow: serde_json::Value =
+            serde_json::from_str(logged().lines().nth(1).expect("a flow record")).unwrap();
+        assert_eq!(flow["event"], "flow");
+        assert_eq!(flow["events"], 3);
+        assert_eq!(flow["window_secs"], 10);
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zone_log_waits_out_full_disk_test() {
+        // Writing to /dev/full always fails with ENOSPC
+        if !std::path::Path::new("/dev/full").exists() {
+            return;
+        }
+        let vm = "zone8";
+        let customer = "customer8";
+        let counters = Arc::new(ZoneCounters::default());
+        let manual = ManualClock::new();
+        let clock: SharedClock = manual.clone();
+        let config = Config::from_toml("[disk]\nfull_buffer_records = 1").unwrap();
+        let mut log = ZoneLog::open(
+            vm.to_owned(),
+            layout(vm, customer),
+            Arc::clone(&counters),
+            Arc::new(ShardedLock::new(HashMap::new())),
+            Arc::new(config),
+            Arc::clone(&clock),
+            Arc::new(LossAudit::new(false)),
+        )
+        .expect("failed to open zone log");
+        let dir: PathBuf = [LOG_DIR, customer, vm].iter().collect();
+        log.flush().unwrap();
+
+        let full = std::fs::OpenOptions::new()
+            .write(true)
+            .open("/dev/full")
+            .unwrap();
+        let file = std::mem::replace(&mut log.writer, LogWriter::new(full, BUF_SIZE, None, 0));
+        let records: Vec<Record> = (0..3)
+            .map(|_| {
+                Record::new(
+                    parser::cfwevent_parse(testutils::generate_event().as_bytes())
+                        .unwrap()
+                        .1,
+                    vm,
+                    "",
+                )
+            })
+            .collect();
+        log.write_batch(&records[..1]).unwrap();
+        log.flush().expect("a full disk doesn't fail the flush");
+        assert!(log.stall.is_some(), "writes are stalled");
+        log.write_batch(&records[1..]).unwrap();
+        assert_eq!(
+            counters.peek().dropped.disk_full,
+            1,
+            "the backlog overflowed"
+        );
+
+        // The record that was buffered when the flush failed is lost with the file
+        log.writer = file;
+        manual.advance(Duration::from_secs(10));
+        log.check().unwrap();
+        assert!(log.stall.is_none(), "writes resumed");
+        let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        assert_eq!(
+            logged.lines().count(),
+            2,
+            "the start marker and the backlog"
+        );
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    /// A sink that writes into a shared buffer
+    struct VecSink(Arc<Mutex<Vec<u8>>>);
+
+    impl Sink for VecSink {
+        fn name(&self) -> &str {
+            "vec"
+        }
+        fn write_batch(&mut self, records: &[Record<'_>]) -> std::io::Result<()> {
+            let mut buf = self.0.lock().unwrap();
+            for record in records {
+                serde_json::to_writer(&mut *buf, record)?;
+                buf.push(b'\n');
+            }
+            Ok(())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn rotate(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn close(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn stats(&self) -> SinkStats {
+            SinkStats::default()
+        }
+    }
+
+    /// A sink whose destination is always unavailable
+    struct BrokenSink;
+
+    impl Sink for BrokenSink {
+        fn name(&self) -> &str {
+            "broken"
+        }
+        fn write_batch(&mut self, _records: &[Record<'_>]) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn rotate(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn close(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn stats(&self) -> SinkStats {
+            SinkStats::default()
+        }
+    }
+
+    #[test]
+    fn zone_sinks_test() {
         let num_events = 4;
-        let vmobjs: Vmobjs = Arc::new(ShardedLock::new(HashMap::new()));
+        let vmobjs: Vmobjs = Vmobjs::default();
 
         let zone1 = testutils::create_zone();
 
-        let events = std::iter::repeat_with(|| {
+        let events: Vec<CfwEvent> = std::iter::repeat_with(|| {
             let event = testutils::generate_event_for_zone(&zone1);
             parser::cfwevent_parse(event.as_bytes()).unwrap().1
         })
         .take(num_events)
         .collect();
 
-        let mut vms = vmobjs.write().unwrap();
-        vms.insert(zone1.zonedid, zone1);
-        drop(vms);
+        // Only the first event's rule is known to FWAPI
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        if let CfwEvent::Traffic(event) = &events[0] {
+            let owner = RuleOwner {
+                owner_uuid: None,
+                description: Some("allow ssh".to_owned()),
+            };
+            rules.write().unwrap().insert(event.rule_uuid, owner);
+        }
+
+        let opened = Arc::new(zone1.clone());
+        vmobjs.update(|vms| vms.insert(zone1));
+
+        // The second "vec" sink is only routed the first event, the zone log is never filtered
+        let first_rule = match &events[0] {
+            CfwEvent::Traffic(event) => event.rule_uuid,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        let config = Config::from_toml(&format!(
+            "[sink_filters]\nvec = \"rule == {}\"\n",
+            first_rule
+        ))
+        .unwrap();
 
-        let mut writer = vec![];
-        log_events(events, &mut writer, &vmobjs);
+        let writer = Arc::new(Mutex::new(vec![]));
+        let routed = Arc::new(Mutex::new(vec![]));
+        let mut sinks = ZoneSinks {
+            vm: opened.uuid.clone(),
+            zone: opened,
+            sinks: vec![
+                Box::new(VecSink(Arc::clone(&writer))),
+                Box::new(BrokenSink),
+                Box::new(VecSink(Arc::clone(&routed))),
+            ],
+            rules,
+            config: Arc::new(config),
+            node: None,
+            sampler: None,
+            coalescer: None,
+            counters: Arc::new(ZoneCounters::default()),
+            audit: Arc::new(LossAudit::new(false)),
+        };
+        let first_event = events[0].clone();
+        let second_event = events[1].clone();
+        let written = sinks.write(events, &vmobjs);
+        assert_eq!(written, num_events as u64, "all events were counted");
+        assert!(
+            sinks.flush().is_ok(),
+            "only the first sink's failures are returned"
+        );
 
         let mut buf = String::new();
         writer
+            .lock()
+            .unwrap()
             .as_slice()
             .read_to_string(&mut buf)
             .expect("failed to read all of the bytes from the writer");
@@ -298,12 +3094,281 @@ mod tests {
             lines.len(),
             "all events were written to the writer"
         );
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(
+            first["rule_description"], "allow ssh",
+            "records are attributed to their rule"
+        );
+        assert!(
+            first.get("rule_owner").is_none(),
+            "unknown attribution is left out"
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&routed.lock().unwrap())
+                .lines()
+                .count(),
+            1,
+            "filtered sinks only receive matching records"
+        );
+
+        // Sampling the first event's rule leaves every other one of its events out
+        let sampling = format!("[[sampling]]\nrule = \"{}\"\none_in = 2\n", first_rule);
+        sinks.reload(Arc::new(Config::from_toml(&sampling).unwrap()));
+        writer.lock().unwrap().clear();
+        let sampled = vec![first_event.clone(), first_event.clone()];
+        assert_eq!(
+            sinks.write(sampled, &vmobjs),
+            2,
+            "events left out are handled too"
+        );
+        let buf = String::from_utf8(writer.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = buf.lines().collect();
+        assert_eq!(lines.len(), 1);
+        let kept: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(kept["sampled"], 2, "the record stands for both events");
+
+        // Events matching the drop filter aren't written to any sink, even one routed them
+        let drop_filter = format!(
+            "drop_filter = \"rule == {0}\"\n[sink_filters]\nvec = \"rule == {0}\"\n",
+            first_rule
+        );
+        sinks.reload(Arc::new(Config::from_toml(&drop_filter).unwrap()));
+        writer.lock().unwrap().clear();
+        routed.lock().unwrap().clear();
+        assert_eq!(
+            sinks.write(vec![first_event.clone(), second_event], &vmobjs),
+            2,
+            "dropped events are handled too"
+        );
+        let buf = String::from_utf8(writer.lock().unwrap().clone()).unwrap();
+        assert_eq!(
+            buf.lines().count(),
+            1,
+            "only the other rule's event is logged"
+        );
+        assert!(routed.lock().unwrap().is_empty());
+
+        // Only logging allowed connections leaves the blocked events out
+        let actions = Config::from_toml("log_actions = \"allow\"\n").unwrap();
+        sinks.reload(Arc::new(actions));
+        writer.lock().unwrap().clear();
+        assert_eq!(sinks.write(vec![first_event.clone()], &vmobjs), 1);
+        assert!(writer.lock().unwrap().is_empty(), "blocks aren't logged");
+
+        // The events of count only rules are counted for their summary instead
+        let count_only = format!("[count_only]\nrules = [\"{}\"]\n", first_rule);
+        sinks.reload(Arc::new(Config::from_toml(&count_only).unwrap()));
+        assert_eq!(sinks.write(vec![first_event.clone()], &vmobjs), 1);
+        assert!(
+            writer.lock().unwrap().is_empty(),
+            "counted events aren't logged"
+        );
+        let counted = sinks.counters.take_counted(&sinks.rules);
+        assert_eq!((counted[0].rule, counted[0].events), (first_rule, 1));
+
+        // A zone over its disk budget has its events counted, or dropped with "drop"
+        let budget = "[disk_budget]\nbytes = 1000\n";
+        sinks.reload(Arc::new(Config::from_toml(budget).unwrap()));
+        sinks.counters.set_over_budget(true);
+        assert_eq!(sinks.write(vec![first_event.clone()], &vmobjs), 1);
+        let counted = sinks.counters.take_counted(&sinks.rules);
+        assert_eq!((counted[0].rule, counted[0].events), (first_rule, 1));
+        let budget = format!("{}action = \"drop\"\n", budget);
+        sinks.reload(Arc::new(Config::from_toml(&budget).unwrap()));
+        assert_eq!(sinks.write(vec![first_event.clone()], &vmobjs), 1);
+        assert!(
+            writer.lock().unwrap().is_empty(),
+            "events over budget aren't logged"
+        );
+        let suppressed = sinks.counters.take_suppressed();
+        assert_eq!(suppressed[0].reason, DropReason::DiskBudget);
+        assert_eq!(sinks.counters.peek().dropped.disk_budget, 1);
+        sinks.counters.set_over_budget(false);
+
+        // A paused zone has its events counted until it's resumed
+        sinks.reload(Arc::new(Config::default()));
+        assert!(sinks.counters.pause().pause(Utc::now()));
+        assert_eq!(sinks.write(vec![first_event.clone()], &vmobjs), 1);
+        assert!(
+            writer.lock().unwrap().is_empty(),
+            "a paused zone's events aren't logged"
+        );
+        assert_eq!(sinks.counters.pause().resume(Utc::now()), Some(1));
+
+        // A run of repeats is logged as its first event and then one record for the rest
+        sinks.reload(Arc::new(Config::default()));
+        sinks.coalescer = Some(Coalescer::new(Duration::from_secs(60)));
+        writer.lock().unwrap().clear();
+        let repeated = vec![first_event.clone(), first_event.clone(), first_event];
+        assert_eq!(sinks.write(repeated, &vmobjs), 3, "repeats are handled too");
+        sinks.write_repeats(&vmobjs, false);
+        assert_eq!(
+            String::from_utf8_lossy(&writer.lock().unwrap())
+                .lines()
+                .count(),
+            1,
+            "the run's window isn't over yet"
+        );
+        sinks.write_repeats(&vmobjs, true);
+        let buf = String::from_utf8(writer.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = buf.lines().collect();
+        assert_eq!(lines.len(), 2, "the run ended once the logger stopped");
+        let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        assert!(first.get("repeats").is_none());
+        let run: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(run["repeats"], 2, "the record stands for both repeats");
+    }
+
+    #[test]
+    fn worker_pool_test() {
+        let pool = workers::Pool::start(1).expect("failed to start workers");
+        let mut zone = testutils::create_zone();
+        // The zonedid of the generated events
+        zone.zonedid = 16;
+        let dir: PathBuf = [LOG_DIR, &zone.owner_uuid, &zone.uuid].iter().collect();
+        let config = Arc::new(Config::default());
+        let (event_tx, events) = queue::bounded(10, Default::default());
+        let (signal_tx, signal) = channel::bounded(1);
+        let setup = ZoneSetup {
+            zonedid: zone.zonedid,
+            vm: zone.uuid.clone(),
+            zone: Arc::new(zone.clone()),
+            customer: zone.owner_uuid.clone(),
+            layout: Layout::for_zone(&zone, &config),
+            vmobjs: Vmobjs::default(),
+            rules: Arc::new(ShardedLock::new(HashMap::new())),
+            counters: Arc::new(ZoneCounters::default()),
+            config,
+            memory: Arc::new(MemoryTracker::new(None)),
+            audit: Arc::new(LossAudit::new(false)),
+            live: Arc::new(LiveHub::new()),
+            clock: SystemClock::shared(),
+            node: None,
+            events,
+            signal,
+            heartbeat: Arc::new(Heartbeat::new()),
+        };
+        setup.vmobjs.update(|vms| vms.insert(zone));
+        let done = match pool.assign(setup) {
+            Ok(done) => done,
+            Err(_) => panic!("the worker didn't take the zone"),
+        };
+        for _ in 0..2 {
+            let event = parser::cfwevent_parse(testutils::generate_event().as_bytes())
+                .unwrap()
+                .1;
+            assert!(event_tx.send(event, |_| ()).is_ok());
+        }
+        assert!(signal_tx.send(LoggerSignal::Shutdown).is_ok());
+        while done.recv().is_ok() {}
+
+        let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+        let records: Vec<serde_json::Value> = logged
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records.len(),
+            4,
+            "both events between the start and stop markers"
+        );
+        assert_eq!(records[3]["records"], 2);
+
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+    }
+
+    #[test]
+    fn zones_serialize_the_way_their_config_says_test() {
+        let pool = workers::Pool::start(1).expect("failed to start workers");
+        let configs = [
+            Config::default(),
+            Config::from_toml("timestamp_format = \"epoch_ms\"").unwrap(),
+        ];
+        let mut zones = vec![];
+        for config in configs.iter() {
+            let mut zone = testutils::create_zone();
+            // The zonedid of the generated events
+            zone.zonedid = 16;
+            let config = Arc::new(config.clone());
+            let (event_tx, events) = queue::bounded(10, Default::default());
+            let (signal_tx, signal) = channel::bounded(1);
+            let setup = ZoneSetup {
+                zonedid: zone.zonedid,
+                vm: zone.uuid.clone(),
+                zone: Arc::new(zone.clone()),
+                customer: zone.owner_uuid.clone(),
+                layout: Layout::for_zone(&zone, &config),
+                vmobjs: Vmobjs::default(),
+                rules: Arc::new(ShardedLock::new(HashMap::new())),
+                counters: Arc::new(ZoneCounters::default()),
+                config,
+                memory: Arc::new(MemoryTracker::new(None)),
+                audit: Arc::new(LossAudit::new(false)),
+                live: Arc::new(LiveHub::new()),
+                clock: SystemClock::shared(),
+                node: None,
+                events,
+                signal,
+                heartbeat: Arc::new(Heartbeat::new()),
+            };
+            let dir: PathBuf = [LOG_DIR, &zone.owner_uuid, &zone.uuid].iter().collect();
+            setup.vmobjs.update(|vms| vms.insert(zone));
+            let done = match pool.assign(setup) {
+                Ok(done) => done,
+                Err(_) => panic!("the worker didn't take the zone"),
+            };
+            zones.push((event_tx, signal_tx, done, dir));
+        }
+        // Both zones are logged by the same worker, one event at a time each
+        for _ in 0..2 {
+            for (event_tx, _, _, _) in &zones {
+                let event = parser::cfwevent_parse(testutils::generate_event().as_bytes())
+                    .unwrap()
+                    .1;
+                assert!(event_tx.send(event, |_| ()).is_ok());
+            }
+        }
+        for (_, signal_tx, done, _) in &zones {
+            assert!(signal_tx.send(LoggerSignal::Shutdown).is_ok());
+            while done.recv().is_ok() {}
+        }
+
+        for (i, (_, _, _, dir)) in zones.iter().enumerate() {
+            let logged = std::fs::read_to_string(dir.join("current.log")).unwrap();
+            let records: Vec<serde_json::Value> = logged
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(records.len(), 4);
+            for record in &records {
+                assert_eq!(record["timestamp"].is_string(), i == 0, "{}", record);
+                assert_eq!(record["timestamp"].is_u64(), i == 1, "{}", record);
+            }
+            std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
+        }
     }
 
     #[test]
     fn start_logger_test() {
-        let vmobjs: Vmobjs = Arc::new(ShardedLock::new(HashMap::new()));
-        let logger = start_logger(10, Arc::clone(&vmobjs));
+        let vmobjs: Vmobjs = Vmobjs::default();
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let config = Arc::new(Config::default());
+        let memory = Arc::new(MemoryTracker::new(None));
+        let audit = Arc::new(LossAudit::new(false));
+        let live = Arc::new(LiveHub::new());
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        let logger = start_logger(
+            10,
+            Arc::clone(&vmobjs),
+            Arc::clone(&rules),
+            &stats,
+            Arc::clone(&config),
+            Arc::clone(&memory),
+            Arc::clone(&audit),
+            Arc::clone(&live),
+            SystemClock::shared(),
+            None,
+        );
         assert!(
             logger.is_none(),
             "no logger is created for an unknown zonedid",
@@ -311,14 +3376,30 @@ mod tests {
 
         let zone1 = testutils::create_zone();
         let zonedid = zone1.zonedid;
-        let mut vms = vmobjs.write().unwrap();
-        vms.insert(zone1.zonedid, zone1);
-        drop(vms);
+        let dir: PathBuf = [LOG_DIR, &zone1.owner_uuid, &zone1.uuid].iter().collect();
+        vmobjs.update(|vms| vms.insert(zone1));
 
-        let logger = start_logger(zonedid, Arc::clone(&vmobjs));
+        let logger = start_logger(
+            zonedid,
+            Arc::clone(&vmobjs),
+            rules,
+            &stats,
+            config,
+            memory,
+            audit,
+            live,
+            SystemClock::shared(),
+            None,
+        );
         assert!(
             logger.is_some(),
             "logger is created when we have the correct zone info",
         );
+
+        logger
+            .unwrap()
+            .shutdown()
+            .expect("failed to shutdown logger");
+        std::fs::remove_dir_all(dir.parent().unwrap()).expect("failed to cleanup log dir");
     }
 }
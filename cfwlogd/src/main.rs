@@ -59,41 +59,177 @@
 
 use crossbeam::channel;
 use crossbeam::sync::ShardedLock;
-use daemonize::Daemonize;
 use illumos_priv::{PrivOp, PrivPtype, PrivSet, Privilege};
 use libc::c_int;
 
 use std::collections::HashMap;
 use std::ffi::CString;
-use std::fs::OpenOptions;
+use std::fs::File;
 use std::io;
 use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[macro_use]
+extern crate lazy_static;
 #[macro_use]
 extern crate log;
 
+// Declared ahead of the other modules so they can use its `probe!` macro
+#[macro_use]
+mod probes;
+
+mod admin;
+mod alert;
+mod archive;
+mod audit;
+mod budget;
+mod capture;
+mod check;
+mod clock;
+#[cfg(feature = "cloudwatch")]
+mod cloudwatch;
+mod cmon;
+mod coalesce;
+mod compat;
+mod compress;
+mod config;
+mod convert;
+mod disk;
+mod dump;
+#[cfg(feature = "elasticsearch")]
+mod elasticsearch;
+mod enrich;
 mod events;
+mod exit;
+mod expr;
+mod fanout;
+mod fields;
 mod fileutils;
+mod firehose;
+mod flows;
+mod format;
+mod fwadm;
+#[cfg(feature = "fwapi")]
+mod fwapi;
+#[cfg(feature = "geoip")]
+mod geoip;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handoff;
+mod health;
+mod histogram;
+mod holding;
+#[cfg(any(
+    feature = "elasticsearch",
+    feature = "fwapi",
+    feature = "manta",
+    feature = "s3",
+    feature = "webhook"
+))]
+mod http;
+mod inflight;
 mod ipf;
+mod ipfix;
+mod layout;
+mod live;
+mod logadm;
 mod logger;
-mod parser;
+#[cfg(feature = "manta")]
+mod manta;
+mod memory;
+mod migrate;
+#[cfg(target_os = "linux")]
+mod nflog;
+mod nic;
+mod node;
+#[cfg(feature = "parquet")]
+mod parquet_sink;
+mod pause;
+#[cfg(feature = "pflog")]
+mod pflog;
+#[cfg(feature = "dynamic-sinks")]
+mod plugin;
+#[cfg(feature = "protobuf")]
+mod protobuf;
+mod queue;
+mod ratelimit;
+#[cfg(feature = "reverse-dns")]
+mod rdns;
+mod replay;
+mod rules;
+#[cfg(feature = "s3")]
+mod s3;
+mod sampling;
+mod schema;
+mod selftest;
+mod service;
 mod signal;
+#[cfg(any(feature = "cloudwatch", feature = "s3"))]
+mod sigv4;
+mod simulator;
+mod sink;
+mod source;
+mod spool;
+mod stats;
+mod stdout;
+mod syslog;
+mod tail;
+mod talkers;
+mod template;
+mod thresholds;
+#[cfg(feature = "tls")]
+#[cfg_attr(
+    not(any(feature = "webhook", feature = "syslog-tls", feature = "elasticsearch")),
+    allow(dead_code)
+)]
+mod tls;
+mod upload;
+mod version;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "websocket")]
+mod websocket;
+mod wire;
+mod workers;
+mod zonefs;
 mod zones;
-use events::Loggers;
-use ipf::IpfevDevice;
+use admin::Admin;
+use audit::LossAudit;
+use clock::SystemClock;
+use config::{Config, ConfigFile, SharedConfig, SourceConfig, StartupMode};
+use disk::DiskMonitor;
+use events::{Loggers, ReaderState};
+use exit::{ExitReason, Subsystem};
+use health::{Health, Heartbeat};
+use inflight::InFlight;
+use live::LiveHub;
+use memory::MemoryTracker;
+use node::NodeIdentity;
+use rules::Rules;
+use service::{Service, ServiceManager};
+use stats::Stats;
 use zones::Vmobjs;
 
 const LOG_DIR: &str = "/var/log/firewall";
 
-/// As defined in smf_method(5): method successfully but purposefully leaves no processes remaining
-/// in the contract; it should be treated as if it had a transient service model.
-const SMF_EXIT_NODAEMON: i32 = 94;
+/// How long to wait between attempts at satisfying a startup prerequisite in permissive mode.
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a graceful shutdown may take before we give up on it. This needs to stay under the
+/// stop method's "timeout_seconds" in the SMF manifest so that we always get a chance to write
+/// out the exit summary before SMF resorts to killing us.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How long the readers are given to stop before the devices are handed over to a new cfwlogd
+const READER_STOP_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// Set's the daemon's privileges to the basic set plus a few extras that allow us to open the
-/// /dev/ipfev device and chroot ourselves into LOG_DIR
-fn cfwlogd_set_privs() -> io::Result<()> {
+/// /dev/ipfev device and chroot ourselves into LOG_DIR. When we are going to run as a user other
+/// than root we also keep the privileges to read and write the root owned files in LOG_DIR.
+fn cfwlogd_set_privs(user: bool, chown: bool) -> io::Result<()> {
     let set = PrivSet::new_basic()?;
     // Remove
     set.delset(Privilege::ProcInfo)?;
@@ -104,11 +240,48 @@ fn cfwlogd_set_privs() -> io::Result<()> {
     set.addset(Privilege::ProcChroot)?;
     set.addset(Privilege::ProcSetid)?;
     set.addset(Privilege::SysNetConfig)?;
+    if user || chown {
+        set.addset(Privilege::FileDacRead)?;
+        set.addset(Privilege::FileDacSearch)?;
+        set.addset(Privilege::FileDacWrite)?;
+    }
+    // Giving zones' logs to other owners, and keeping their modes once they're given away
+    if chown {
+        set.addset(Privilege::FileChown)?;
+        set.addset(Privilege::FileOwner)?;
+    }
 
     illumos_priv::setppriv(PrivOp::Set, PrivPtype::Permitted, &set)?;
     Ok(())
 }
 
+/// Look up the uid and gid of the user we run as, which has to happen before we chroot away from
+/// /etc/passwd
+fn cfwlogd_lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let cname = CString::new(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "user name contained nuls"))?;
+    let pw = unsafe { libc::getpwnam(cname.as_ptr()) };
+    if pw.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no such user {}", name),
+        ));
+    }
+    let pw = unsafe { &*pw };
+    Ok((pw.pw_uid, pw.pw_gid))
+}
+
+/// Switch to the user we run as. Being privilege aware, we keep our privileges across the switch,
+/// and this has to happen before proc_setid is dropped.
+fn cfwlogd_set_user(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    unsafe {
+        if libc::setgroups(1, &gid) == -1 || libc::setgid(gid) == -1 || libc::setuid(uid) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
 /// Drop all the privileges that we no longer need once we are running as a child aka daemon.
 fn cfwlogd_drop_privs() -> io::Result<()> {
     let set = illumos_priv::getppriv(PrivPtype::Permitted)?;
@@ -121,75 +294,47 @@ fn cfwlogd_drop_privs() -> io::Result<()> {
     Ok(())
 }
 
-/// Fork cfwlogd as a daemon.
-fn cfwlogd_daemonize() {
-    // The "daemonize" crate unfortunately sets stdout/stderr to devnull if you don't specify a
-    // path, so for now we reopen them manually via their respective devices.
-    let stdout = OpenOptions::new()
-        .write(true)
-        .open("/dev/stdout")
-        .expect("failed to open stdout");
-    let stderr = OpenOptions::new()
-        .write(true)
-        .open("/dev/stderr")
-        .expect("failed to open stderr");
-
-    // Drop all groups
-    if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
-        let e = io::Error::last_os_error();
-        error!("failed to drop all groups: {}", e);
-        std::process::exit(e.raw_os_error().unwrap_or(1));
-    }
-
-    if let Err(e) = Daemonize::new()
-        .stdout(stdout)
-        .stderr(stderr)
-        .group("daemon")
-        .umask(0o022)
-        .start()
-    {
-        error!("failed to daemonize: {}", e);
-        std::process::exit(1);
-    };
-}
-
 /// Process incoming unix signals. Returns `true` if the signal indicates that
 /// we should shutdown the process.
-fn cfwlogd_handle_signals(s: c_int, loggers: &Loggers) -> bool {
+fn cfwlogd_handle_signals(
+    s: c_int,
+    loggers: &Loggers,
+    config_file: &ConfigFile,
+    args: &[String],
+    config: &SharedConfig,
+    stats: &Stats,
+    fd_dir: Option<&File>,
+) -> bool {
     let mut shutdown = false;
     match s {
-        // CMON TRITON-1755 -- dump some cmon counters somewhere?
-        libc::SIGUSR1 => info!("SIGUSR1: caught sigusr1"),
-        // Tell the logger threads to flush to disk
+        // Tell the logger threads to flush and reopen current.log, for rotation tools that
+        // shouldn't also reload the config
+        libc::SIGUSR1 => {
+            info!("SIGUSR1: reopening log files");
+            rotate_logs(loggers);
+        }
+        // Tell the logger threads to flush to disk, and dump our internal state to the log
         libc::SIGUSR2 => {
             info!("SIGUSR2: flushing logs");
-            let loggers = loggers.lock().unwrap();
-            loggers.values().for_each(|logger| {
+            loggers.lock().unwrap().values().for_each(|logger| {
                 if logger.flush().is_err() {
                     error!(
                         "Failed to flush logs for {} because the logger is no \
-                         longer listening on its signal handler",
+                         longer responding to its signal handler",
                         &logger.uuid
                     );
                 }
             });
+            dump::log_snapshot(loggers, stats, fd_dir);
         }
-        // Tell logger threads to flush and reopen current.log
+        // Reload the config, and tell logger threads to flush and reopen current.log
         libc::SIGHUP => {
+            reload_config(config_file, args, config, loggers);
             info!(
                 "SIGHUP: log rotation -- flushing currently opened files and \
                  reopening current.log"
             );
-            let loggers = loggers.lock().unwrap();
-            loggers.values().for_each(|logger| {
-                if logger.rotate().is_err() {
-                    error!(
-                        "Failed to rotate logs for {} because the logger is no \
-                         longer listening on its signal handler",
-                        &logger.uuid
-                    );
-                }
-            });
+            rotate_logs(loggers);
         }
         // Shutdown
         libc::SIGINT => {
@@ -207,6 +352,125 @@ fn cfwlogd_handle_signals(s: c_int, loggers: &Loggers) -> bool {
     shutdown
 }
 
+/// Tell every logger to flush, sync and close its log file and then reopen current.log. Loggers
+/// handle signals between batches of records, so no record is split across the two files.
+fn rotate_logs(loggers: &Loggers) {
+    let loggers = loggers.lock().unwrap();
+    loggers.values().for_each(|logger| {
+        if logger.rotate().is_err() {
+            error!(
+                "Failed to rotate logs for {} because the logger is no \
+                 longer responding to its signal handler",
+                &logger.uuid
+            );
+        }
+    });
+}
+
+/// Re-read the config file and hand it to the event fanout thread, for the loggers it starts from
+/// now on, and to every running logger. Settings that are only read at startup keep their running
+/// values, see `Config::reload`. A config file that fails to load is ignored.
+fn reload_config(file: &ConfigFile, args: &[String], config: &SharedConfig, loggers: &Loggers) {
+    // Whatever annotations are looked up in is updated in place, such as GeoIP databases by
+    // geoipupdate, and picked up on SIGHUP even if the config didn't change
+    enrich::reload();
+    // Likewise for certificates, which are renewed in place
+    #[cfg(feature = "tls")]
+    tls::reload();
+    let mut reloaded = match file.load() {
+        Ok(reloaded) => reloaded,
+        Err(e) => {
+            error!("SIGHUP: keeping the running config: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = apply_flags(args, &mut reloaded) {
+        error!("SIGHUP: keeping the running config: {}", e);
+        return;
+    }
+    let running = Arc::clone(&config.read().unwrap());
+    let (reloaded, ignored) = running.reload(reloaded);
+    if !ignored.is_empty() {
+        warn!(
+            "SIGHUP: changes to {} take effect once cfwlogd restarts",
+            ignored.join(", ")
+        );
+    }
+    if reloaded == *running {
+        debug!("SIGHUP: config unchanged");
+        return;
+    }
+    exit::set_logging(reloaded.log_level(), reloaded.log_format);
+    let reloaded = Arc::new(reloaded);
+    *config.write().unwrap() = Arc::clone(&reloaded);
+    let loggers = loggers.lock().unwrap();
+    loggers.values().for_each(|logger| {
+        if logger.reload(Arc::clone(&reloaded)).is_err() {
+            error!(
+                "Failed to reload the config for {} because the logger is no \
+                 longer responding to its signal handler",
+                &logger.uuid
+            );
+        }
+    });
+    info!("SIGHUP: reloaded {}", config::CONFIG_FILE);
+}
+
+/// Give the readers a moment to stop, so that nothing they read is left behind when the devices
+/// are handed over. A reader waiting on an idle device hasn't read anything, so it isn't waited
+/// for any longer than that.
+fn wait_for_readers(readers: &ReaderState) {
+    let start = Instant::now();
+    while readers.running() > 0 && start.elapsed() < READER_STOP_TIMEOUT {
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Start a thread that guarantees the process exits within `SHUTDOWN_TIMEOUT`, even if some part
+/// of the pipeline is wedged and never finishes draining. Receiving another SIGINT or SIGTERM
+/// while the graceful shutdown is in progress exits immediately.
+fn start_shutdown_watchdog(
+    signals: channel::Receiver<c_int>,
+    memory: Arc<MemoryTracker>,
+) -> thread::JoinHandle<()> {
+    thread::Builder::new()
+        .name("shutdown_watchdog".to_owned())
+        .spawn(move || {
+            let deadline = Instant::now() + SHUTDOWN_TIMEOUT;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match signals.recv_timeout(remaining) {
+                    Ok(libc::SIGINT) | Ok(libc::SIGTERM) => exit::exit(
+                        ExitReason::ShutdownIncomplete,
+                        "shutdown signal received again, exiting immediately",
+                        memory.queued_events(),
+                    ),
+                    Ok(sig) => debug!("ignoring signal {} while shutting down", sig),
+                    Err(channel::RecvTimeoutError::Disconnected) => {
+                        thread::sleep(deadline.saturating_duration_since(Instant::now()))
+                    }
+                    Err(channel::RecvTimeoutError::Timeout) => (),
+                }
+                if Instant::now() >= deadline {
+                    exit::exit(
+                        ExitReason::ShutdownIncomplete,
+                        &format!(
+                            "shutdown did not complete within {} seconds",
+                            SHUTDOWN_TIMEOUT.as_secs()
+                        ),
+                        memory.queued_events(),
+                    );
+                }
+            }
+        })
+        .unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Shutdown,
+                &format!("failed to spawn shutdown watchdog thread: {}", e),
+            )
+        })
+}
+
 /// Chroot into the provided path.
 fn cfwlogd_chroot<P: Into<PathBuf>>(p: P) -> io::Result<()> {
     let path = p.into();
@@ -222,138 +486,853 @@ fn cfwlogd_chroot<P: Into<PathBuf>>(p: P) -> io::Result<()> {
     }
 }
 
-/// Given a list of zones, iterate through them looking for log files that have incomplete newline
-/// separated json logs. Truncate logs to the first "\n" found from the end of the file seeking
-/// backwards.
-fn validate_log_files(vmobjs: &Vmobjs) {
-    let zones = vmobjs.read().unwrap();
-    for zone in zones.values() {
-        let path: PathBuf = ["/", &zone.owner_uuid, &zone.uuid, "current.log"]
-            .iter()
-            .collect();
+/// Attempt to satisfy a startup prerequisite. In strict mode the first failure is returned to the
+/// caller, while in permissive mode the failure is logged and `f` is retried until it succeeds.
+fn startup_retry<T, F>(mode: StartupMode, what: &str, mut f: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    loop {
+        match f() {
+            Ok(val) => return Ok(val),
+            Err(e) => {
+                if mode == StartupMode::Strict {
+                    return Err(e);
+                }
+                warn!(
+                    "{} failed, retrying in {} seconds: {}",
+                    what,
+                    STARTUP_RETRY_INTERVAL.as_secs(),
+                    e
+                );
+                thread::sleep(STARTUP_RETRY_INTERVAL);
+            }
+        }
+    }
+}
 
-        // If the file doesn't yet exist on disk we can skip over it
-        if !path.is_file() {
-            continue;
-        };
+/// Parse the value of a tuning flag, which has to be a positive number
+fn tuning_value(flag: &str, value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!(
+            "{} must be a positive number, not \"{}\"",
+            flag, value
+        )),
+    }
+}
 
-        match OpenOptions::new().read(true).write(true).open(&path) {
-            Ok(mut file) => match fileutils::rseek_and_scan(&mut file, 512, b'\n') {
-                Ok(info) => {
-                    // If we find the "\n" we add 1 to the index so that when we truncate the file
-                    // we keep the newline.
-                    let idx = info.index.map(|i| i + 1).unwrap_or(0);
-                    // If idx matches the length of the file we can skip the
-                    // truncation.
-                    if idx == info.length {
-                        continue;
-                    }
-                    if let Err(e) = file.set_len(idx) {
-                        error!("failed to truncate file ({}): {}", &path.display(), e);
-                    } else {
-                        info!(
-                            "truncated {} by {} bytes",
-                            &path.display(),
-                            info.length - idx
-                        );
+/// Apply one of the flags overriding the config's tunables, so a CN that needs bigger queues or
+/// more threads can be given them from the SMF manifest without a rebuild
+fn apply_tuning_flag(arg: &str, config: &mut Config) -> Result<(), String> {
+    let mut parts = arg.splitn(2, '=');
+    let flag = parts.next().unwrap_or_default();
+    let value = parts.next().unwrap_or_default();
+    match flag {
+        "--reader-capacity" => config.queues.reader_capacity = Some(tuning_value(flag, value)?),
+        "--zone-capacity" => config.queues.zone_capacity = tuning_value(flag, value)?,
+        "--batch-records" => config.queues.batch_records = tuning_value(flag, value)?,
+        "--batch-delay-us" => {
+            config.queues.batch_delay_us = value
+                .parse()
+                .map_err(|_| format!("{} must be a number, not \"{}\"", flag, value))?
+        }
+        "--read-buffer-bytes" => config.reads.buffer_bytes = Some(tuning_value(flag, value)?),
+        "--logger-workers" => config.logger_workers = Some(tuning_value(flag, value)?),
+        "--write-buffer-bytes" => {
+            let bytes = tuning_value(flag, value)?;
+            if bytes < config::MIN_WRITE_BUFFER_BYTES {
+                return Err(format!(
+                    "{} must be at least {}",
+                    flag,
+                    config::MIN_WRITE_BUFFER_BYTES
+                ));
+            }
+            config.write_buffer_bytes = Some(bytes)
+        }
+        "--flush-interval-ms" => config.flush.interval_ms = Some(tuning_value(flag, value)? as u64),
+        _ => return Err(format!("unknown argument \"{}\"", arg)),
+    }
+    Ok(())
+}
+
+/// Apply the command line flags, which take precedence over the config file
+fn apply_flags(args: &[String], config: &mut Config) -> Result<(), String> {
+    for arg in args {
+        match arg.as_str() {
+            "--foreground" => config.foreground = true,
+            "--stdout" => config.stdout = true,
+            arg if arg.starts_with("--schema-version=") => {
+                let version = &arg["--schema-version=".len()..];
+                match version.parse() {
+                    Ok(version) if version <= sink::SCHEMA_VERSION => {
+                        config.schema_version = Some(version)
                     }
+                    _ => return Err(format!("invalid schema version \"{}\"", version)),
                 }
-                Err(e) => {
-                    error!(
-                        "failed to scan file ({}) for a newline: {}",
-                        &path.display(),
-                        e
-                    );
-                }
-            },
-            Err(e) => error!(
-                "failed to open file ({}) for cleanup: {}",
-                path.display(),
-                e
-            ),
+            }
+            arg if arg.starts_with("--") && arg.contains('=') => apply_tuning_flag(arg, config)?,
+            _ => return Err(format!("unknown argument \"{}\"", arg)),
         }
     }
+    let daemonizes = config.service_manager == ServiceManager::Smf && !config.foreground;
+    if config.stdout && daemonizes {
+        return Err("writing records to stdout requires staying in the foreground".to_owned());
+    }
+    Ok(())
 }
 
 fn main() {
-    pretty_env_logger::init();
+    exit::init_logging();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("migrate") => std::process::exit(migrate::run(&args[1..])),
+        Some("convert") => std::process::exit(convert::run(&args[1..])),
+        Some("capture") => std::process::exit(capture::run(&args[1..])),
+        Some("check-config") => std::process::exit(check::run(&args[1..])),
+        Some("schema") => std::process::exit(schema::run(&args[1..])),
+        Some("ctl") => std::process::exit(admin::run(&args[1..])),
+        Some("--selftest") => {
+            // The same as "ctl selftest", for monitoring that runs cfwlogd with flags
+            let mut ctl = args[1..].to_vec();
+            ctl.push("selftest".to_owned());
+            std::process::exit(admin::run(&ctl))
+        }
+        Some("alert-exec") => std::process::exit(thresholds::run_exec(&args[1..])),
+        Some("version") => std::process::exit(version::run(&args[1..])),
+        _ => (),
+    }
+    exit::set_panic_hook();
+
+    // The file is reloaded on SIGHUP, after we have chrooted
+    let (config_file, mut config) = ConfigFile::open(config::CONFIG_FILE)
+        .map_err(config::Error::from)
+        .and_then(|file| file.load().map(|config| (file, config)))
+        .unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Config,
+                &format!("{}: {}", config::CONFIG_FILE, e),
+            )
+        });
+    if let Err(e) = apply_flags(&args, &mut config) {
+        exit::fatal(ExitReason::Config, &e);
+    }
+    exit::set_logging(config.log_level(), config.log_format);
+    debug!("loaded config: {:?}", config);
+    // The system's timezone can't be read once we have chrooted, so it's loaded now in case a
+    // reload switches to local timestamps
+    let _ = chrono::Local::now();
+
+    // Plugins live outside of the log directory so they have to be loaded before we chroot.
+    #[cfg(feature = "dynamic-sinks")]
+    plugin::load(&config.sink_plugins).unwrap_or_else(|e| {
+        exit::fatal(
+            ExitReason::Config,
+            &format!("failed to load sink plugin {}", e),
+        )
+    });
+
+    // sysinfo has to be run before we give up the privilege to exec it
+    let node = if config.node_identity {
+        let fields = config
+            .node_fields
+            .as_deref()
+            .unwrap_or(node::DEFAULT_FIELDS);
+        let node = startup_retry(config.startup_mode, "reading sysinfo", || {
+            NodeIdentity::from_sysinfo(fields, config.datacenter.as_deref())
+        })
+        .unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to read the CN's identity from sysinfo: {}", e),
+            )
+        });
+        info!("stamping records with {}", node.describe());
+        Some(Arc::new(node))
+    } else {
+        None
+    };
+
+    let user = config.user.as_ref().map(|name| {
+        let (uid, gid) = cfwlogd_lookup_user(name).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to look up user {}: {}", name, e),
+            )
+        });
+        (name, uid, gid)
+    });
+
+    // Threshold alert commands are run by a process of their own, as we won't be allowed to start
+    // any once our privileges are limited
+    let alert_exec =
+        thresholds::spawn_exec(&config.thresholds, user.map(|(_, uid, gid)| (uid, gid)))
+            .unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!("failed to start the alert-exec process: {}", e),
+                )
+            });
+
+    // Files we gave to the configured owners are ours to keep writing to
+    let log_owners: Vec<libc::uid_t> = config
+        .all_log_permissions()
+        .filter_map(|permissions| permissions.uid)
+        .collect();
+    let chown = !log_owners.is_empty()
+        || config
+            .all_log_permissions()
+            .any(|permissions| permissions.gid.is_some());
+    fileutils::trust_owners(log_owners);
 
     // Since we are running as root limit our privileges as early as possible.
-    if let Err(e) = cfwlogd_set_privs() {
-        error!("failed to add extra privileges: {}", e);
-        std::process::exit(e.raw_os_error().unwrap_or(1));
+    if let Err(e) = cfwlogd_set_privs(user.is_some(), chown) {
+        exit::fatal(
+            ExitReason::Setup,
+            &format!("failed to add extra privileges: {}", e),
+        );
     }
     debug!("successfully set new privileges");
 
-    let device = IpfevDevice::new("/dev/ipfev").unwrap_or_else(|e| match e.kind() {
+    // A cfwlogd that's still running hands its devices over rather than us opening them again,
+    // see the "handoff" module
+    let mut inherited = config.handoff.as_ref().map_or_else(Vec::new, |handoff| {
+        let timeout = Duration::from_secs(handoff.timeout_secs);
+        match handoff::take_over(&handoff.socket, timeout) {
+            Ok(devices) => devices,
+            Err(e) => {
+                match e.kind() {
+                    io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+                        debug!("no running cfwlogd to take over from: {}", e)
+                    }
+                    _ => warn!("failed to take over from the running cfwlogd: {}", e),
+                }
+                vec![]
+            }
+        }
+    });
+    let devices = startup_retry(config.startup_mode, "opening the event source", || {
+        source::open(&config.source, &mut inherited)
+    })
+    .unwrap_or_else(|e| match (&config.source, e.kind()) {
         // The device was not found but ipfilter is online because the smf dependency
         // requires it to be up before starting, therefore we are on a platform that
         // doesn't support ipfev. So we exit with SMF_EXIT_NODAEMON to indicate success
-        // leaving no process running.
-        io::ErrorKind::NotFound => {
-            info!(
-                "/dev/ipfev not present on this system -- \
-                 treating the daemon as a transient service"
-            );
-            std::process::exit(SMF_EXIT_NODAEMON);
-        }
+        // leaving no process running. In permissive mode we never make it here because we
+        // keep waiting for the device to show up instead.
+        (SourceConfig::Ipfev { .. }, io::ErrorKind::NotFound) => exit::fatal(
+            ExitReason::DeviceUnsupported,
+            &format!(
+                "ipfev not present on this system ({}) -- treating the daemon as a transient \
+                 service",
+                e
+            ),
+        ),
         // Anything other than NotFound should be treated as a hard error.
-        _ => {
-            error!("failed to open /dev/ipfev: {}", e);
-            std::process::exit(e.raw_os_error().unwrap_or(1));
-        }
+        _ => exit::fatal(
+            ExitReason::Device,
+            &format!("failed to open the event source: {}", e),
+        ),
+    });
+
+    let mut service = Service::start(&config).unwrap_or_else(|e| {
+        exit::fatal(
+            ExitReason::Setup,
+            &format!("failed to start under {:?}: {}", config.service_manager, e),
+        )
     });
 
-    cfwlogd_daemonize();
+    probes::register();
 
     // The vminfod client and signal handler need access to /dev/{u}random so we handle these
     // things before we chroot into "/var/log/firewall"
     // TODO make sure the vminfod client is able to be restarted later once we drop privs
-    let vmobjs = Arc::new(ShardedLock::new(HashMap::new()));
-    let _vminfod_handle = zones::start_vminfod(Arc::clone(&vmobjs));
+    let vmobjs = Vmobjs::default();
+    // The cache is read and written through a descriptor opened now so it keeps working after we
+    // chroot, see `zones::start_vmobjs_cache`
+    let mut cached = false;
+    let _vmobjs_cache_handle = config.vminfod.cache_secs.map(|secs| {
+        let dir = fileutils::create_dir_all_nofollow(Path::new(LOG_DIR)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to open {} for the vmobjs cache: {}", LOG_DIR, e),
+            )
+        });
+        match zones::load_vmobjs_cache(&dir, &vmobjs) {
+            Ok(0) => (),
+            Ok(count) => {
+                info!("loaded {} zones from the vmobjs cache", count);
+                cached = true;
+            }
+            Err(e) => warn!("ignoring the vmobjs cache: {}", e),
+        }
+        zones::start_vmobjs_cache(Arc::clone(&vmobjs), dir, Duration::from_secs(secs))
+            .unwrap_or_else(|e| {
+                exit::fault(
+                    Subsystem::Vminfod,
+                    &format!("failed to start vmobjs cache thread: {}", e),
+                )
+            })
+    });
+    // This doesn't wait for vminfod, the devices are read while its first snapshot is on the way
+    // and the fanout thread holds the events of the zones it hasn't reported yet
+    let (zone_changes_tx, zone_changes_rx) = channel::unbounded();
+    let _vminfod_handle = zones::start_vminfod(
+        Arc::clone(&vmobjs),
+        config.startup_mode,
+        config.vminfod.settings(),
+        config.vminfod.tracked_fields.clone(),
+        zone_changes_tx,
+        cached,
+    );
 
     // This is unbounded so that we don't block in the signal handler
     let (sig_tx, sig_rx) = channel::unbounded();
     let _signal_handle = signal::start_signalhandler(sig_tx);
 
+    // FWAPI's address has to be resolved and fwadm's rule directory opened before we chroot, see
+    // the "fwapi" and "fwadm" modules
+    let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+    let _fwadm_handle = config.fwadm.as_ref().map(|fwadm| {
+        fwadm::start_fwadm_sync(fwadm, Arc::clone(&rules)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to start syncing rules from {}: {}",
+                    fwadm.rules_dir, e
+                ),
+            )
+        })
+    });
+    #[cfg(feature = "fwapi")]
+    let _fwapi_handle = config.fwapi.as_ref().map(|fwapi| {
+        fwapi::start_fwapi_sync(fwapi, Arc::clone(&rules)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to start syncing rules from {}: {}", fwapi.url, e),
+            )
+        })
+    });
+
+    #[cfg(feature = "elasticsearch")]
+    {
+        if let Some(elasticsearch) = &config.elasticsearch {
+            elasticsearch::init(elasticsearch).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!(
+                        "failed to set up Elasticsearch at {}: {}",
+                        elasticsearch.url, e
+                    ),
+                )
+            });
+        }
+    }
+
+    #[cfg(feature = "cloudwatch")]
+    {
+        if let Some(cloudwatch) = &config.cloudwatch {
+            cloudwatch::init(cloudwatch).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!(
+                        "failed to set up CloudWatch Logs in {}: {}",
+                        cloudwatch.region, e
+                    ),
+                )
+            });
+        }
+    }
+
+    #[cfg(feature = "webhook")]
+    {
+        if let Some(webhook) = &config.webhook {
+            webhook::init(webhook).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!("failed to set up the webhook sink {}: {}", webhook.url, e),
+                )
+            });
+        }
+    }
+
+    // The object store's address has to be resolved and its credentials read before we chroot,
+    // while the uploads only start once we have, see the "upload" module
+    #[allow(unused_mut)]
+    let mut uploads: Option<(Box<dyn upload::Store>, upload::Settings)> = None;
+    #[cfg(feature = "manta")]
+    {
+        if let Some(manta) = &config.manta {
+            let store = manta::Manta::new(manta).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!("failed to set up uploads to Manta at {}: {}", manta.url, e),
+                )
+            });
+            let settings = upload::Settings {
+                path: manta::Manta::path(manta),
+                max_backoff: Duration::from_secs(manta.max_backoff_secs),
+                dead_letter: None,
+            };
+            uploads = Some((Box::new(store), settings));
+        }
+    }
+    #[cfg(feature = "s3")]
+    {
+        if let Some(s3) = &config.s3 {
+            let store = s3::S3::new(s3).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!("failed to set up uploads to {}: {}", s3.endpoint, e),
+                )
+            });
+            let settings = upload::Settings {
+                path: s3::S3::path(s3),
+                max_backoff: Duration::from_secs(s3.max_backoff_secs),
+                dead_letter: Some(upload::DeadLetter {
+                    max_failures: s3.max_attempts,
+                    dir: Path::new(logger::LOG_DIR).join(&s3.dead_letter_dir),
+                }),
+            };
+            uploads = Some((Box::new(store), settings));
+        }
+    }
+
+    // GeoIP databases are read through directories opened, and the nameservers reverse DNS queries
+    // go to are read, before we chroot, see the "geoip" and "rdns" modules
+    #[allow(unused_mut)]
+    let mut lookups: Vec<Box<dyn enrich::Lookup>> = vec![];
+    #[cfg(feature = "geoip")]
+    {
+        if let Some(geoip) = &config.geoip {
+            let geoip = geoip::Geoip::open(geoip).unwrap_or_else(|e| {
+                exit::fatal(
+                    ExitReason::Setup,
+                    &format!("failed to load the GeoIP databases: {}", e),
+                )
+            });
+            lookups.push(Box::new(geoip));
+        }
+    }
+    #[cfg(feature = "reverse-dns")]
+    {
+        if let Some(reverse_dns) = &config.reverse_dns {
+            let rdns =
+                rdns::ReverseDns::new(reverse_dns, config.enrich.workers).unwrap_or_else(|e| {
+                    exit::fatal(
+                        ExitReason::Setup,
+                        &format!("failed to set up reverse DNS: {}", e),
+                    )
+                });
+            lookups.push(Box::new(rdns));
+        }
+    }
+    enrich::init(&config.enrich, lookups).unwrap_or_else(|e| {
+        exit::fatal(
+            ExitReason::Setup,
+            &format!("failed to start the enricher: {}", e),
+        )
+    });
+
+    // The syslog collector's address and CA certificate, see the "syslog" module
+    if let Some(syslog) = &config.syslog {
+        syslog::init(syslog).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to set up the syslog collector {}: {}",
+                    syslog.address, e
+                ),
+            )
+        });
+    }
+
+    // Likewise for the IPFIX collector and the socket records are sent to it from
+    if let Some(ipfix) = &config.ipfix {
+        ipfix::init(ipfix).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to set up the IPFIX collector {}: {}",
+                    ipfix.collector, e
+                ),
+            )
+        });
+    }
+
+    // Zones' filesystems are only reachable through a descriptor opened before we chroot
+    if let Some(zone_fs) = &config.zone_fs {
+        zonefs::init(zone_fs).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to open {}: {}", zone_fs.zones_dir.display(), e),
+            )
+        });
+    }
+
+    // Zones' loggers share these threads rather than getting one each, see the "workers" module
+    if let Some(workers) = config.logger_workers {
+        workers::init(workers).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to start logger workers: {}", e),
+            )
+        });
+    }
+
+    // Likewise for the alert destinations, the alert thread itself starts once the pipeline does
+    let alerter = config.alerts.as_ref().map(|alerts| {
+        alert::Alerter::new(alerts).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to set up alert destinations: {}", e),
+            )
+        })
+    });
+    let _thresholds_handle = if config.thresholds.is_empty() {
+        None
+    } else {
+        Some(
+            thresholds::init(&config.thresholds, alert_exec, Path::new(LOG_DIR)).unwrap_or_else(
+                |e| {
+                    exit::fatal(
+                        ExitReason::Setup,
+                        &format!("failed to set up threshold alerts: {}", e),
+                    )
+                },
+            ),
+        )
+    };
+
+    // Local subscribers connect over sockets that live outside of the log directory, so they are
+    // bound before we chroot and drop privileges.
+    let live = Arc::new(LiveHub::new());
+    let _firehose_handle = config.firehose.as_ref().map(|firehose| {
+        firehose::start_firehose(&firehose.socket, Arc::clone(&live)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to start the firehose on {}: {}",
+                    firehose.socket.display(),
+                    e
+                ),
+            )
+        })
+    });
+    let health_listener = config.health.as_ref().map(|health| {
+        let listener = health::bind(&health.socket).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to bind the health socket {}: {}",
+                    health.socket.display(),
+                    e
+                ),
+            )
+        });
+        (listener, Duration::from_secs(health.stuck_secs))
+    });
+    let handoff_listener = config.handoff.as_ref().map(|handoff| {
+        handoff::bind(&handoff.socket).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to bind the handoff socket {}: {}",
+                    handoff.socket.display(),
+                    e
+                ),
+            )
+        })
+    });
+    let admin_listener = config.admin.as_ref().map(|admin| {
+        admin::bind(&admin.socket).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to bind the admin socket {}: {}",
+                    admin.socket.display(),
+                    e
+                ),
+            )
+        })
+    });
+    #[cfg(feature = "grpc")]
+    let _grpc_handle = config.grpc.as_ref().map(|grpc| {
+        grpc::start_grpc_server(&grpc.socket, Arc::clone(&live)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to start the gRPC service on {}: {}",
+                    grpc.socket.display(),
+                    e
+                ),
+            )
+        })
+    });
+    #[cfg(feature = "websocket")]
+    let _websocket_handle = config.websocket.as_ref().map(|websocket| {
+        websocket::start_websocket_server(websocket.listen, Arc::clone(&live)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!(
+                    "failed to start the WebSocket endpoint on {}: {}",
+                    websocket.listen, e
+                ),
+            )
+        })
+    });
+
+    let _tail_handle = config.tail.as_ref().map(|tail| {
+        tail::start_tail_server(tail.listen, Arc::clone(&live)).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to start the HTTP tail on {}: {}", tail.listen, e),
+            )
+        })
+    });
+
+    // SIGUSR2 counts our open file descriptors in /proc, which is out of reach once we chroot
+    let fd_dir = match File::open("/proc/self/fd") {
+        Ok(dir) => Some(dir),
+        Err(e) => {
+            warn!("SIGUSR2 won't count open file descriptors: {}", e);
+            None
+        }
+    };
+
     // Since we are running as root lock ourselves into the LOG_DIR, and then further limit our
     // privileges.
-    if let Err(e) = cfwlogd_chroot(LOG_DIR) {
-        error!("failed to chroot into {}: {}", LOG_DIR, e);
-        std::process::exit(e.raw_os_error().unwrap_or(1));
+    if let Err(e) = startup_retry(config.startup_mode, "setting up the log directory", || {
+        cfwlogd_chroot(LOG_DIR)
+    }) {
+        exit::fatal(
+            ExitReason::Setup,
+            &format!("failed to chroot into {}: {}", LOG_DIR, e),
+        );
+    }
+    exit::set_chrooted();
+    if let Some((name, uid, gid)) = user {
+        if let Err(e) = cfwlogd_set_user(uid, gid) {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to switch to user {}: {}", name, e),
+            );
+        }
+        info!("running as {} ({}:{})", name, uid, gid);
     }
     if let Err(e) = cfwlogd_drop_privs() {
-        error!("failed to drop privileges: {}", e);
-        std::process::exit(e.raw_os_error().unwrap_or(1));
+        exit::fatal(
+            ExitReason::Setup,
+            &format!("failed to drop privileges: {}", e),
+        );
     }
     debug!("successfully dropped privileges");
 
-    validate_log_files(&vmobjs);
-
     // Setup our processing pipeline
+    let stats = Arc::new(Mutex::new(HashMap::new()));
+    let memory = Arc::new(MemoryTracker::new(
+        config.memory_limit_mb.map(|mb| mb * 1024 * 1024),
+    ));
+    let disk = Arc::new(DiskMonitor::new(config.disk));
+    let _disk_handle = disk::start_disk_monitor(Arc::clone(&disk), logger::LOG_DIR);
+    let _upload_handle = uploads.map(|(store, settings)| {
+        upload::start(store, settings).unwrap_or_else(|e| {
+            exit::fatal(
+                ExitReason::Setup,
+                &format!("failed to start uploading rotated logs: {}", e),
+            )
+        })
+    });
+    let clock = SystemClock::shared();
+    let _alert_handle = alerter.map(|alerter| {
+        alert::start_alerts(
+            alerter,
+            Arc::clone(&stats),
+            Arc::clone(&vmobjs),
+            Arc::clone(&clock),
+        )
+        .unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to start alert thread: {}", e),
+            )
+        })
+    });
+    let audit = Arc::new(LossAudit::new(config.loss_audit));
+    if audit.enabled() {
+        warn!("loss audit enabled, every event will be tracked until shutdown");
+    }
+    let inflight = Arc::new(InFlight::new());
     let (shutdown_tx, shutdown_rx) = channel::bounded(1);
-    let (ipf_events, _ipf_handle) = events::start_event_reader(device);
-    let (loggers, fanout_handle) =
-        events::start_event_fanout(ipf_events, shutdown_rx, Arc::clone(&vmobjs));
-
-    // Handle signals until we are told to exit
-    for sig in sig_rx.iter() {
-        if cfwlogd_handle_signals(sig, &loggers) {
-            break;
+    let readers = Arc::new(ReaderState::new(devices.len()));
+    // Our own duplicates of the devices' descriptors keep them open for a new cfwlogd once the
+    // readers have closed theirs
+    let handoff_devices: Vec<(String, File)> = if handoff_listener.is_some() {
+        devices
+            .iter()
+            .filter_map(|(name, device)| Some((name.clone(), device.descriptor()?)))
+            .collect()
+    } else {
+        vec![]
+    };
+    let (ipf_events, injector, _ipf_handles) = events::start_event_readers(
+        devices,
+        config.queues,
+        config.reads,
+        Arc::clone(&stats),
+        Arc::clone(&memory),
+        disk,
+        Arc::clone(&audit),
+        Arc::clone(&inflight),
+        Arc::clone(&readers),
+    );
+    let config = config::shared(config);
+    let fanout_heartbeat = Arc::new(Heartbeat::new());
+    let (loggers, fanout_handle) = events::start_event_fanout(
+        ipf_events,
+        shutdown_rx,
+        zone_changes_rx,
+        Arc::clone(&vmobjs),
+        rules,
+        Arc::clone(&stats),
+        Arc::clone(&config),
+        Arc::clone(&memory),
+        Arc::clone(&audit),
+        inflight,
+        live,
+        clock,
+        node,
+        Arc::clone(&fanout_heartbeat),
+    );
+    let _health_handle = health_listener.map(|(listener, stuck_after)| {
+        let health = Health {
+            readers: Arc::clone(&readers),
+            fanout: fanout_heartbeat,
+            loggers: Arc::clone(&loggers),
+            stuck_after,
         };
+        health::start_health(listener, health).unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to start health check thread: {}", e),
+            )
+        })
+    });
+    let _admin_handle = admin_listener.map(|listener| {
+        let admin = Admin {
+            vmobjs: Arc::clone(&vmobjs),
+            loggers: Arc::clone(&loggers),
+            stats: Arc::clone(&stats),
+            injector,
+            config: Arc::clone(&config),
+        };
+        admin::start_admin(listener, admin).unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to start admin socket thread: {}", e),
+            )
+        })
+    });
+    let (successor_tx, mut successor_rx) = channel::bounded(1);
+    let _handoff_handle = handoff_listener.map(|listener| {
+        handoff::start_handoff(listener, successor_tx).unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to start handoff thread: {}", e),
+            )
+        })
+    });
+    service.ready();
+
+    // Handle signals until we are told to exit, or a new cfwlogd asks to take over
+    let mut shutdown_signal = None;
+    let mut successor = None;
+    loop {
+        let mut sel = channel::Select::new();
+        let signal = sel.recv(&sig_rx);
+        sel.recv(&successor_rx);
+        let op = sel.select();
+        if op.index() == signal {
+            match op.recv(&sig_rx) {
+                Ok(sig) => {
+                    if cfwlogd_handle_signals(
+                        sig,
+                        &loggers,
+                        &config_file,
+                        &args,
+                        &config,
+                        &stats,
+                        fd_dir.as_ref(),
+                    ) {
+                        shutdown_signal = Some(sig);
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        } else {
+            match op.recv(&successor_rx) {
+                Ok(stream) => {
+                    successor = Some(stream);
+                    break;
+                }
+                Err(_) => successor_rx = channel::never(),
+            }
+        }
     }
 
-    // Wait for the event processor to drain all queued events into its loggers
-    shutdown_tx.send(()).unwrap();
-    fanout_handle.join().unwrap();
+    service.stopping();
+    let _watchdog_handle = start_shutdown_watchdog(sig_rx, Arc::clone(&memory));
+
+    // Stop taking in new events, then wait for the event processor to drain the queued ones into
+    // its loggers
+    readers.stop();
+    if successor.is_some() {
+        wait_for_readers(&readers);
+    }
+    if shutdown_tx.send(()).is_err() || fanout_handle.join().is_err() {
+        error!("event fanout thread exited before shutdown");
+    }
 
     // Wait for loggers to finish flushing to disk
-    let mut loggers = loggers.lock().unwrap();
+    let mut loggers = loggers.lock().unwrap_or_else(|e| e.into_inner());
     for (zonedid, logger) in loggers.drain() {
         info!(
             "shutting down logging thread for {} ({})",
             logger.uuid, zonedid
         );
-        logger.shutdown().unwrap();
+        let uuid = logger.uuid.clone();
+        if logger.shutdown().is_err() {
+            error!("logging thread for {} did not accept shutdown", uuid);
+        }
+    }
+
+    if audit.enabled() {
+        let path = PathBuf::from(logger::LOG_DIR).join("loss-audit.json");
+        if let Err(e) = audit.write_report(&path) {
+            error!("failed to write {}: {}", path.display(), e);
+        }
     }
+
+    let handed_over = successor.map_or(false, |stream| {
+        match handoff::hand_over(stream, &handoff_devices) {
+            Ok(()) => {
+                info!("handed {} devices over", handoff_devices.len());
+                true
+            }
+            Err(e) => {
+                error!("failed to hand the devices over: {}", e);
+                false
+            }
+        }
+    });
+
+    let reason = match shutdown_signal {
+        Some(libc::SIGINT) => "shutdown requested by SIGINT",
+        Some(libc::SIGTERM) => "shutdown requested by SIGTERM",
+        _ if handed_over => "handed over to a new cfwlogd",
+        _ => "signal handler stopped",
+    };
+    exit::exit(ExitReason::Shutdown, reason, memory.queued_events());
 }
@@ -0,0 +1,157 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Approximate accounting of the memory held by cfwlogd's queues and buffers. The device reader
+//! consults the `MemoryTracker` before it reads and before it queues each event. As usage
+//! approaches the configured ceiling the reader starts sampling events, and once usage gets too
+//! close to the ceiling it stops reading from the device altogether until the loggers have caught
+//! up. This trades dropped events for a guarantee that cfwlogd can't OOM the global zone.
+
+use cfwevent::parser::CfwEvent;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Percentage of the ceiling at which we start sampling events
+const SAMPLING_THRESHOLD: usize = 75;
+/// Percentage of the ceiling at which we stop reading from the device
+const PAUSE_THRESHOLD: usize = 90;
+/// While sampling we only keep one out of every `SAMPLE_RATE` events
+const SAMPLE_RATE: usize = 10;
+
+/// Approximate memory used by an event sitting in one of the pipeline's queues
+const EVENT_SIZE: usize = std::mem::size_of::<CfwEvent>();
+
+/// How much pressure the pipeline is under relative to the memory ceiling
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pressure {
+    /// Every event is queued
+    Normal,
+    /// Only a sample of events are queued
+    Sampling,
+    /// No events should be read from the device
+    Paused,
+}
+
+#[derive(Debug)]
+pub struct MemoryTracker {
+    /// Upper bound in bytes, `None` if there is no ceiling
+    ceiling: Option<usize>,
+    /// Number of events currently sitting in a queue somewhere in the pipeline
+    queued_events: AtomicUsize,
+    /// Bytes allocated for write buffers
+    buffers: AtomicUsize,
+    /// Used to pick which events are kept while sampling
+    sample: AtomicUsize,
+}
+
+impl MemoryTracker {
+    /// Create a new `MemoryTracker` with an optional ceiling in bytes
+    pub fn new(ceiling: Option<usize>) -> Self {
+        MemoryTracker {
+            ceiling,
+            queued_events: AtomicUsize::new(0),
+            buffers: AtomicUsize::new(0),
+            sample: AtomicUsize::new(0),
+        }
+    }
+
+    /// Approximate number of bytes in use by queues and buffers
+    pub fn used(&self) -> usize {
+        self.queued_events.load(Ordering::Relaxed) * EVENT_SIZE
+            + self.buffers.load(Ordering::Relaxed)
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        let ceiling = match self.ceiling {
+            Some(ceiling) if ceiling > 0 => ceiling,
+            _ => return Pressure::Normal,
+        };
+        let percent = self.used().saturating_mul(100) / ceiling;
+        if percent >= PAUSE_THRESHOLD {
+            Pressure::Paused
+        } else if percent >= SAMPLING_THRESHOLD {
+            Pressure::Sampling
+        } else {
+            Pressure::Normal
+        }
+    }
+
+    /// Decide if the next event should be queued given the current memory pressure.
+    pub fn admit(&self) -> bool {
+        match self.pressure() {
+            Pressure::Normal => true,
+            Pressure::Sampling => self.sample.fetch_add(1, Ordering::Relaxed) % SAMPLE_RATE == 0,
+            Pressure::Paused => false,
+        }
+    }
+
+    /// Number of events currently sitting in a queue somewhere in the pipeline
+    pub fn queued_events(&self) -> usize {
+        self.queued_events.load(Ordering::Relaxed)
+    }
+
+    /// An event has been placed on a queue
+    pub fn event_queued(&self) {
+        self.queued_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// `n` events have left the pipeline, either by being written or dropped
+    pub fn events_done(&self, n: usize) {
+        self.queued_events.fetch_sub(n, Ordering::Relaxed);
+    }
+
+    /// A write buffer of the given size has been allocated
+    pub fn buffer_allocated(&self, size: usize) {
+        self.buffers.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// A write buffer of the given size has been freed
+    pub fn buffer_freed(&self, size: usize) {
+        self.buffers.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_ceiling_is_always_normal() {
+        let memory = MemoryTracker::new(None);
+        memory.buffer_allocated(usize::max_value() / 2);
+        assert_eq!(memory.pressure(), Pressure::Normal);
+        assert!(memory.admit(), "events are always admitted");
+    }
+
+    #[test]
+    fn pressure_follows_usage() {
+        let memory = MemoryTracker::new(Some(1000));
+        assert_eq!(memory.pressure(), Pressure::Normal);
+
+        memory.buffer_allocated(800);
+        assert_eq!(memory.pressure(), Pressure::Sampling);
+        let admitted = (0..100).filter(|_| memory.admit()).count();
+        assert_eq!(admitted, 100 / SAMPLE_RATE, "only a sample is admitted");
+
+        memory.buffer_allocated(100);
+        assert_eq!(memory.pressure(), Pressure::Paused);
+        assert!(!memory.admit(), "nothing is admitted while paused");
+
+        memory.buffer_freed(900);
+        assert_eq!(memory.pressure(), Pressure::Normal);
+    }
+
+    #[test]
+    fn queued_events_are_counted() {
+        let memory = MemoryTracker::new(Some(EVENT_SIZE * 10));
+        for _ in 0..9 {
+            memory.event_queued();
+        }
+        assert_eq!(memory.used(), EVENT_SIZE * 9);
+        assert_eq!(memory.pressure(), Pressure::Paused);
+        memory.events_done(9);
+        assert_eq!(memory.used(), 0);
+    }
+}
@@ -0,0 +1,347 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd migrate <file>...` upgrades log files written by older versions of cfwlogd to the
+//! current record schema, so consumers reading a mix of old and new archives only have to
+//! understand one format. Every record carries the `schema_version` it was written with, and
+//! records from before versions existed are treated as version 0.
+//!
+//! The same steps are taken in reverse when "schema_version" asks for records to be written in an
+//! older schema, for consumers that haven't caught up with the current one yet.
+//!
+//! Each file is rewritten next to itself and renamed into place only once every line has been
+//! upgraded, so a file with a line that isn't a record is left untouched. Files that are already
+//! current aren't rewritten at all.
+
+use crate::sink::SCHEMA_VERSION;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Upgrades a record from the version it's indexed by to the next version
+type Upgrade = fn(&mut Map<String, Value>);
+
+/// The upgrade for every schema version before `SCHEMA_VERSION`
+const UPGRADES: &[Upgrade] = &[upgrade_v0, upgrade_v1];
+
+/// Version 0 records only lack the "schema_version" field itself
+fn upgrade_v0(_record: &mut Map<String, Value>) {}
+
+/// Version 1 ICMP and ICMPv6 records only have their type and code as ports
+fn upgrade_v1(record: &mut Map<String, Value>) {
+    match record.get("protocol").and_then(Value::as_str) {
+        Some("ICMP") | Some("ICMPV6") => (),
+        _ => return,
+    }
+    let port = |name: &str| {
+        record
+            .get(name)
+            .and_then(Value::as_u64)
+            .filter(|&n| n <= 255)
+    };
+    if let (Some(icmp_type), Some(icmp_code)) = (port("source_port"), port("destination_port")) {
+        record.insert("icmp_type".to_owned(), Value::from(icmp_type));
+        record.insert("icmp_code".to_owned(), Value::from(icmp_code));
+    }
+}
+
+/// The downgrade to the previous version for every schema version after 0, indexed by the version
+/// before it, so `DOWNGRADES[v]` undoes `UPGRADES[v]`
+const DOWNGRADES: &[Upgrade] = &[downgrade_v1, downgrade_v2];
+
+/// Version 0 records don't have a "schema_version"
+fn downgrade_v1(record: &mut Map<String, Value>) {
+    record.remove("schema_version");
+}
+
+/// Version 1 records leave the ICMP type and code in the ports, where version 2 still has them
+fn downgrade_v2(record: &mut Map<String, Value>) {
+    record.remove("icmp_type");
+    record.remove("icmp_code");
+}
+
+/// Downgrade a current record to the given older version
+pub fn downgrade_record(record: &mut Map<String, Value>, version: u64) {
+    if version >= SCHEMA_VERSION {
+        return;
+    }
+    record.insert("schema_version".to_owned(), Value::from(version));
+    for downgrade in DOWNGRADES[version as usize..].iter().rev() {
+        downgrade(record);
+    }
+}
+
+/// Serialize a record as json in the given schema version, which is only any slower than
+/// `serde_json::to_writer` for older versions
+pub fn to_writer<W: Write, T: Serialize>(
+    writer: W,
+    record: &T,
+    version: u64,
+) -> serde_json::Result<()> {
+    if version >= SCHEMA_VERSION {
+        return serde_json::to_writer(writer, record);
+    }
+    match serde_json::to_value(record)? {
+        Value::Object(mut map) => {
+            downgrade_record(&mut map, version);
+            serde_json::to_writer(writer, &map)
+        }
+        value => serde_json::to_writer(writer, &value),
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Io(io::Error),
+    /// A line that isn't a json object
+    InvalidRecord {
+        line: u64,
+    },
+    /// A record written by a newer cfwlogd than this one
+    UnsupportedVersion {
+        line: u64,
+        version: u64,
+    },
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MigrateError::Io(e) => write!(f, "{}", e),
+            MigrateError::InvalidRecord { line } => write!(f, "line {} is not a record", line),
+            MigrateError::UnsupportedVersion { line, version } => write!(
+                f,
+                "line {} has schema version {}, this cfwlogd only understands up to {}",
+                line, version, SCHEMA_VERSION
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for MigrateError {
+    fn from(e: io::Error) -> Self {
+        MigrateError::Io(e)
+    }
+}
+
+/// Upgrade a single record to `SCHEMA_VERSION`, returning true if it had to be changed
+fn upgrade_record(record: &mut Map<String, Value>) -> Result<bool, u64> {
+    let version = record
+        .get("schema_version")
+        .and_then(Value::as_u64)
+        .unwrap_or(0);
+    if version > SCHEMA_VERSION {
+        return Err(version);
+    }
+    if version == SCHEMA_VERSION {
+        return Ok(false);
+    }
+    for upgrade in &UPGRADES[version as usize..] {
+        upgrade(record);
+    }
+    record.insert("schema_version".to_owned(), Value::from(SCHEMA_VERSION));
+    Ok(true)
+}
+
+/// Upgrade every record read from `input`, writing them to `output`. Returns the number of
+/// records that had to be changed.
+fn migrate_records<R: BufRead, W: Write>(input: R, mut output: W) -> Result<u64, MigrateError> {
+    let mut upgraded = 0;
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let lineno = i as u64 + 1;
+        let mut record = match serde_json::from_str(&line) {
+            Ok(Value::Object(record)) => record,
+            _ => return Err(MigrateError::InvalidRecord { line: lineno }),
+        };
+        match upgrade_record(&mut record) {
+            Ok(true) => upgraded += 1,
+            Ok(false) => (),
+            Err(version) => {
+                return Err(MigrateError::UnsupportedVersion {
+                    line: lineno,
+                    version,
+                })
+            }
+        }
+        serde_json::to_writer(&mut output, &record).map_err(io::Error::from)?;
+        output.write_all(b"\n")?;
+    }
+    output.flush()?;
+    Ok(upgraded)
+}
+
+/// Upgrade the records in `path` in place, returning the number of records that were changed.
+pub fn migrate_file(path: &Path) -> Result<u64, MigrateError> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".migrate.tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let input = BufReader::new(File::open(path)?);
+    let mut output = BufWriter::new(File::create(&tmp_path)?);
+    let result = migrate_records(input, &mut output).and_then(|upgraded| {
+        if upgraded > 0 {
+            output.get_ref().sync_all()?;
+            std::fs::rename(&tmp_path, path)?;
+        }
+        Ok(upgraded)
+    });
+    // Nothing is left behind if the file was already current or couldn't be upgraded
+    let _ = std::fs::remove_file(&tmp_path);
+    result
+}
+
+/// Run the "migrate" subcommand on the given files, returning the process's exit code.
+pub fn run(files: &[String]) -> i32 {
+    if files.is_empty() {
+        eprintln!("usage: cfwlogd migrate <file>...");
+        return 2;
+    }
+    let mut failed = false;
+    for file in files {
+        match migrate_file(Path::new(file)) {
+            Ok(0) => println!("{}: already at schema version {}", file, SCHEMA_VERSION),
+            Ok(n) => println!(
+                "{}: upgraded {} records to schema version {}",
+                file, n, SCHEMA_VERSION
+            ),
+            Err(e) => {
+                eprintln!("{}: {}", file, e);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrate(input: &str) -> Result<(u64, String), MigrateError> {
+        let mut output = vec![];
+        let upgraded = migrate_records(input.as_bytes(), &mut output)?;
+        Ok((upgraded, String::from_utf8(output).unwrap()))
+    }
+
+    #[test]
+    fn records_are_upgraded() {
+        assert_eq!(UPGRADES.len() as u64, SCHEMA_VERSION);
+
+        let input = concat!(
+            "{\"event\":\"block\",\"vm\":\"a\"}\n",
+            "{\"schema_version\":1,\"event\":\"block\",\"protocol\":\"ICMPV6\",",
+            "\"source_port\":128,\"destination_port\":0}\n",
+            "{\"schema_version\":2,\"event\":\"allow\"}\n"
+        );
+        let (upgraded, output) = migrate(input).unwrap();
+        assert_eq!(upgraded, 2, "only the older records are upgraded");
+        let records: Vec<Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                serde_json::json!({"event": "block", "vm": "a", "schema_version": 2}),
+                serde_json::json!({
+                    "event": "block",
+                    "protocol": "ICMPV6",
+                    "source_port": 128,
+                    "destination_port": 0,
+                    "icmp_type": 128,
+                    "icmp_code": 0,
+                    "schema_version": 2
+                }),
+                serde_json::json!({"event": "allow", "schema_version": 2}),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_are_downgraded() {
+        assert_eq!(DOWNGRADES.len() as u64, SCHEMA_VERSION);
+
+        let current = serde_json::json!({
+            "schema_version": 2,
+            "event": "block",
+            "protocol": "ICMP",
+            "source_port": 8,
+            "destination_port": 0,
+            "icmp_type": 8,
+            "icmp_code": 0
+        });
+        let written = |version| {
+            let mut buf = vec![];
+            to_writer(&mut buf, &current, version).unwrap();
+            serde_json::from_slice::<Value>(&buf).unwrap()
+        };
+        assert_eq!(written(SCHEMA_VERSION), current);
+        let v1 = serde_json::json!({
+            "schema_version": 1,
+            "event": "block",
+            "protocol": "ICMP",
+            "source_port": 8,
+            "destination_port": 0
+        });
+        assert_eq!(written(1), v1);
+        let mut v0 = v1.as_object().unwrap().clone();
+        v0.remove("schema_version");
+        assert_eq!(written(0), Value::Object(v0));
+
+        let mut upgraded = match written(1) {
+            Value::Object(record) => record,
+            _ => unreachable!(),
+        };
+        assert_eq!(upgrade_record(&mut upgraded), Ok(true));
+        assert_eq!(
+            Value::Object(upgraded),
+            current,
+            "a downgrade can be migrated back"
+        );
+    }
+
+    #[test]
+    fn invalid_files_are_rejected() {
+        match migrate("{\"event\":\"block\"}\nnot json\n") {
+            Err(MigrateError::InvalidRecord { line: 2 }) => (),
+            other => panic!("unexpected result: {:?}", other.map(|(n, _)| n)),
+        }
+        match migrate("{\"schema_version\":99}\n") {
+            Err(MigrateError::UnsupportedVersion {
+                line: 1,
+                version: 99,
+            }) => (),
+            other => panic!("unexpected result: {:?}", other.map(|(n, _)| n)),
+        }
+    }
+
+    #[test]
+    fn files_are_migrated_in_place() {
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests").join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("2020-01-01T00:00:00.log");
+        std::fs::write(&path, "{\"event\":\"block\"}\n").unwrap();
+
+        assert_eq!(migrate_file(&path).unwrap(), 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "{\"event\":\"block\",\"schema_version\":2}\n"
+        );
+        assert_eq!(migrate_file(&path).unwrap(), 0, "already migrated");
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        assert_eq!(entries, 1, "no temporary files are left behind");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
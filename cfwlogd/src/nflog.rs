@@ -0,0 +1,446 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! This is the implementation of an EventSource for Linux netfilter's NFLOG target, which works
+//! with both nftables ("log group N") and iptables ("-j NFLOG --nflog-group N") rules.
+//!
+//! Packets logged to the configured group are read off of a netfilter netlink socket and
+//! re-encoded into the same binary format the ipfev device produces, so the rest of the pipeline
+//! doesn't need to know where the events came from. The information ipfev gets from the cloud
+//! firewall rule itself is carried in the rule's log prefix, which must be of the form:
+//!
+//! ```text
+//! <block|begin|end>:<zonedid>:<rule uuid>
+//! ```
+//!
+//! Packets with a prefix that doesn't match are skipped.
+
+use crate::source::{EventSource, SourceStats};
+use crate::wire::{self, RuleInfo, TRAFFIC_EVENT_SIZE};
+use chrono::{TimeZone, Utc};
+use std::io;
+use std::mem;
+use std::os::unix::io::RawFd;
+
+// From <linux/netfilter/nfnetlink.h> and <linux/netfilter/nfnetlink_log.h>
+const NFNL_SUBSYS_ULOG: u16 = 4;
+const NFULNL_MSG_PACKET: u16 = 0;
+const NFULNL_MSG_CONFIG: u16 = 1;
+const NFULA_PACKET_HDR: u16 = 1;
+const NFULA_TIMESTAMP: u16 = 3;
+const NFULA_PAYLOAD: u16 = 9;
+const NFULA_PREFIX: u16 = 10;
+const NFULA_CFG_CMD: u16 = 1;
+const NFULA_CFG_MODE: u16 = 2;
+const NFULNL_CFG_CMD_BIND: u8 = 1;
+const NFULNL_CFG_CMD_PF_BIND: u8 = 3;
+const NFULNL_COPY_PACKET: u8 = 2;
+const NFNETLINK_V0: u8 = 0;
+/// Attribute type bits that aren't part of the type itself (NLA_F_NESTED | NLA_F_NET_BYTEORDER)
+const NLA_TYPE_MASK: u16 = !(3 << 14);
+
+// From <linux/netfilter.h>
+const NF_INET_PRE_ROUTING: u8 = 0;
+const NF_INET_LOCAL_IN: u8 = 1;
+const NF_INET_FORWARD: u8 = 2;
+
+const NLMSG_HDRLEN: usize = 16;
+const NFGENMSG_LEN: usize = 4;
+
+/// We only need the IP header and the ports out of each packet
+const COPY_RANGE: u32 = 64;
+
+/// Size of the buffer used to receive netlink datagrams
+const RECV_BUF_SIZE: usize = 64 * 1024;
+
+/// Upper bound on the number of packets in a single netlink datagram. Every packet message is at
+/// least a netlink header, a nfgenmsg and a payload attribute holding an IPv4 header, so
+/// RECV_BUF_SIZE can't hold more than this and a single read can always be encoded into a buffer
+/// sized by `event_sizing`.
+const MAX_EVENTS_PER_READ: usize = 2048;
+
+/// Round up to the 4 byte alignment used by netlink messages and attributes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Iterate over the (type, value) attributes found in `bytes`
+fn attributes<'a>(mut bytes: &'a [u8]) -> impl Iterator<Item = (u16, &'a [u8])> + 'a {
+    std::iter::from_fn(move || {
+        let cur: &'a [u8] = bytes;
+        if cur.len() < 4 {
+            return None;
+        }
+        let len = u16::from_ne_bytes([cur[0], cur[1]]) as usize;
+        let kind = u16::from_ne_bytes([cur[2], cur[3]]) & NLA_TYPE_MASK;
+        if len < 4 || len > cur.len() {
+            return None;
+        }
+        bytes = &cur[align(len).min(cur.len())..];
+        Some((kind, &cur[4..len]))
+    })
+}
+
+/// Append an attribute to a netlink message being built in `msg`
+fn push_attribute(msg: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    let len = 4 + value.len();
+    msg.extend_from_slice(&(len as u16).to_ne_bytes());
+    msg.extend_from_slice(&kind.to_ne_bytes());
+    msg.extend_from_slice(value);
+    msg.resize(align(msg.len()), 0);
+}
+
+/// Build a NFULNL_MSG_CONFIG request for the given address family and group
+fn config_message(family: u8, group: u16, seq: u32, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut msg = vec![0; NLMSG_HDRLEN];
+    msg.push(family);
+    msg.push(NFNETLINK_V0);
+    msg.extend_from_slice(&group.to_be_bytes());
+    for (kind, value) in attrs {
+        push_attribute(&mut msg, *kind, value);
+    }
+    let kind = (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_CONFIG;
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK) as u16;
+    let len = msg.len() as u32;
+    msg[0..4].copy_from_slice(&len.to_ne_bytes());
+    msg[4..6].copy_from_slice(&kind.to_ne_bytes());
+    msg[6..8].copy_from_slice(&flags.to_ne_bytes());
+    msg[8..12].copy_from_slice(&seq.to_ne_bytes());
+    msg
+}
+
+/// Parse the cloud firewall information carried in a rule's log prefix
+fn parse_prefix(prefix: &[u8]) -> Option<RuleInfo> {
+    // The prefix attribute is nul terminated
+    let prefix = std::str::from_utf8(prefix).ok()?.trim_end_matches('\0');
+    let mut parts = prefix.splitn(3, ':');
+    let event = match parts.next()? {
+        "block" => 1,
+        "begin" => 2,
+        "end" => 3,
+        _ => return None,
+    };
+    let zonedid = parts.next()?.parse().ok()?;
+    let rule_uuid = parts.next()?.trim().parse().ok()?;
+    Some(RuleInfo {
+        event,
+        zonedid,
+        // NFLOG has no equivalent of ipf's numeric rule id
+        rule_id: 0,
+        rule_uuid,
+    })
+}
+
+/// Turn a single NFULNL_MSG_PACKET message (without its netlink header) into an event in `buf`,
+/// returning false if the packet should be skipped.
+fn encode_packet_message(msg: &[u8], buf: &mut [u8]) -> bool {
+    if msg.len() < NFGENMSG_LEN {
+        return false;
+    }
+    let mut hook = None;
+    let mut timestamp = None;
+    let mut payload = None;
+    let mut prefix = None;
+    for (kind, value) in attributes(&msg[NFGENMSG_LEN..]) {
+        match kind {
+            // struct nfulnl_msg_packet_hdr { __be16 hw_protocol; __u8 hook; __u8 _pad; }
+            NFULA_PACKET_HDR if value.len() >= 3 => hook = Some(value[2]),
+            // struct nfulnl_msg_packet_timestamp { __aligned_be64 sec; __aligned_be64 usec; }
+            NFULA_TIMESTAMP if value.len() >= 16 => {
+                let mut sec = [0; 8];
+                let mut usec = [0; 8];
+                sec.copy_from_slice(&value[0..8]);
+                usec.copy_from_slice(&value[8..16]);
+                let nanos = (u64::from_be_bytes(usec) * 1000) as u32;
+                timestamp = Some(Utc.timestamp(i64::from_be_bytes(sec), nanos));
+            }
+            NFULA_PAYLOAD => payload = Some(value),
+            NFULA_PREFIX => prefix = Some(value),
+            _ => (),
+        }
+    }
+
+    let prefix = match prefix.and_then(parse_prefix) {
+        Some(prefix) => prefix,
+        None => {
+            debug!("skipping NFLOG packet without a cloud firewall prefix");
+            return false;
+        }
+    };
+    let packet = match payload.and_then(wire::parse_packet) {
+        Some(packet) => packet,
+        None => {
+            debug!("skipping NFLOG packet without an IP payload");
+            return false;
+        }
+    };
+    let direction = match hook {
+        Some(NF_INET_PRE_ROUTING) | Some(NF_INET_LOCAL_IN) | Some(NF_INET_FORWARD) => 1,
+        _ => 2,
+    };
+    wire::encode_event(
+        &prefix,
+        &packet,
+        direction,
+        timestamp.unwrap_or_else(Utc::now),
+        buf,
+    );
+    true
+}
+
+/// Wrapper around a netfilter netlink socket bound to a NFLOG group
+pub struct NflogSource {
+    fd: RawFd,
+    group: u16,
+    recv_buf: Vec<u8>,
+    /// Events encoded so far
+    received: u64,
+}
+
+impl NflogSource {
+    /// Create a new `NflogSource` that receives the packets logged to `group`
+    pub fn new(group: u16) -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_NETFILTER,
+            )
+        };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Closes the socket if any of the remaining setup fails
+        let source = NflogSource {
+            fd,
+            group,
+            recv_buf: vec![0; RECV_BUF_SIZE],
+            received: 0,
+        };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let res = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Kernels older than 3.17 require nfnetlink_log to be bound to each address family
+        // before any groups can be bound, newer kernels ignore these requests.
+        for (seq, family) in [libc::AF_INET, libc::AF_INET6].iter().enumerate() {
+            source.request(&config_message(
+                *family as u8,
+                0,
+                seq as u32,
+                &[(NFULA_CFG_CMD, &[NFULNL_CFG_CMD_PF_BIND][..])],
+            ))?;
+        }
+        source.request(&config_message(
+            libc::AF_UNSPEC as u8,
+            group,
+            2,
+            &[(NFULA_CFG_CMD, &[NFULNL_CFG_CMD_BIND][..])],
+        ))?;
+        // struct nfulnl_msg_config_mode { __be32 copy_range; __u8 copy_mode; __u8 _pad; }
+        let mut mode = COPY_RANGE.to_be_bytes().to_vec();
+        mode.extend_from_slice(&[NFULNL_COPY_PACKET, 0]);
+        source.request(&config_message(
+            libc::AF_UNSPEC as u8,
+            group,
+            3,
+            &[(NFULA_CFG_MODE, &mode[..])],
+        ))?;
+
+        info!("connected to NFLOG group {}", group);
+        Ok(source)
+    }
+
+    /// Send a request to the kernel and wait for it to be acknowledged
+    fn request(&self, msg: &[u8]) -> io::Result<()> {
+        let res = unsafe { libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0) };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 1024];
+        let size = unsafe {
+            libc::recv(
+                self.fd,
+                reply.as_mut_ptr() as *mut libc::c_void,
+                reply.len(),
+                0,
+            )
+        };
+        if size == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let reply = &reply[..size as usize];
+        // An NLMSG_ERROR reply carries a negative errno, or 0 if this is just an ack
+        if reply.len() >= NLMSG_HDRLEN + 4
+            && u16::from_ne_bytes([reply[4], reply[5]]) == libc::NLMSG_ERROR as u16
+        {
+            let errno = i32::from_ne_bytes([reply[16], reply[17], reply[18], reply[19]]);
+            if errno != 0 {
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NflogSource {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl EventSource for NflogSource {
+    /// Pull at least one event from the NFLOG group into the provided buffer
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let size = unsafe {
+                libc::recv(
+                    self.fd,
+                    self.recv_buf.as_mut_ptr() as *mut libc::c_void,
+                    self.recv_buf.len(),
+                    0,
+                )
+            };
+            if size == -1 {
+                let e = io::Error::last_os_error();
+                // ENOBUFS means the kernel had to drop messages because we weren't keeping up,
+                // there isn't anything to do about it other than keep reading.
+                if e.raw_os_error() == Some(libc::ENOBUFS) {
+                    warn!("NFLOG group {} dropped packets (ENOBUFS)", self.group);
+                    continue;
+                }
+                return Err(e);
+            }
+
+            let mut written = 0;
+            let mut msgs = &self.recv_buf[..size as usize];
+            while msgs.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes([msgs[0], msgs[1], msgs[2], msgs[3]]) as usize;
+                let kind = u16::from_ne_bytes([msgs[4], msgs[5]]);
+                if len < NLMSG_HDRLEN || len > msgs.len() {
+                    break;
+                }
+                if kind == (NFNL_SUBSYS_ULOG << 8) | NFULNL_MSG_PACKET
+                    && buf.len() - written >= TRAFFIC_EVENT_SIZE
+                    && encode_packet_message(&msgs[NLMSG_HDRLEN..len], &mut buf[written..])
+                {
+                    written += TRAFFIC_EVENT_SIZE;
+                    self.received += 1;
+                }
+                msgs = &msgs[align(len).min(msgs.len())..];
+            }
+
+            // The pipeline expects every read to contain at least one event
+            if written > 0 {
+                return Ok(written);
+            }
+        }
+    }
+
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)> {
+        Ok((TRAFFIC_EVENT_SIZE, MAX_EVENTS_PER_READ))
+    }
+
+    /// The kernel only tells us that it dropped messages (ENOBUFS) and not how many, so no drops
+    /// are reported.
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        Ok(SourceStats {
+            received: self.received,
+            dropped: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvType, CfwEvent, Direction, Protocol};
+    use std::net::Ipv4Addr;
+    use uuid::Uuid;
+
+    fn tcp_packet() -> Vec<u8> {
+        let mut packet = vec![0; 24];
+        packet[0] = 0x45; // IPv4 with a 20 byte header
+        packet[9] = 6; // TCP
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+        packet[20..22].copy_from_slice(&1234u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&22u16.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn prefix_parsing() {
+        let uuid = Uuid::new_v4();
+        let prefix = format!("block:42:{}\0", uuid);
+        assert_eq!(
+            parse_prefix(prefix.as_bytes()),
+            Some(RuleInfo {
+                event: 1,
+                zonedid: 42,
+                rule_id: 0,
+                rule_uuid: uuid
+            })
+        );
+        assert_eq!(parse_prefix(b"allow:42:nope\0"), None);
+        assert_eq!(parse_prefix(b"IN=eth0 \0"), None);
+    }
+
+    #[test]
+    fn packet_message_round_trip() {
+        let uuid = Uuid::new_v4();
+        let mut msg = vec![libc::AF_INET as u8, NFNETLINK_V0, 0, 0];
+        push_attribute(
+            &mut msg,
+            NFULA_PACKET_HDR,
+            &[0x08, 0x00, NF_INET_LOCAL_IN, 0],
+        );
+        push_attribute(
+            &mut msg,
+            NFULA_PREFIX,
+            format!("begin:7:{}\0", uuid).as_bytes(),
+        );
+        push_attribute(&mut msg, NFULA_PAYLOAD, &tcp_packet());
+
+        let mut buf = [0; TRAFFIC_EVENT_SIZE];
+        assert!(encode_packet_message(&msg, &mut buf), "packet was encoded");
+
+        let event = match parser::cfwevent_parse(&buf)
+            .expect("failed to parse event")
+            .1
+        {
+            CfwEvent::Traffic(event) => event,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(event.event, CfwEvType::Begin);
+        assert_eq!(event.zonedid, 7);
+        assert_eq!(event.protocol, Protocol::TCP);
+        assert_eq!(event.direction, Direction::In);
+        assert_eq!(event.source_ip, Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped());
+        assert_eq!(event.source_port, 1234);
+        assert_eq!(event.destination_port, 22);
+        assert_eq!(event.rule_uuid, uuid);
+    }
+
+    #[test]
+    fn packets_without_prefix_are_skipped() {
+        let mut msg = vec![libc::AF_INET as u8, NFNETLINK_V0, 0, 0];
+        push_attribute(&mut msg, NFULA_PAYLOAD, &tcp_packet());
+        let mut buf = [0; TRAFFIC_EVENT_SIZE];
+        assert!(!encode_packet_message(&msg, &mut buf));
+    }
+}
@@ -0,0 +1,150 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The network a traffic event was seen on. The events the device reports don't say which of a
+//! zone's interfaces the packet crossed, so with the config's "nic_context" set it's worked out
+//! from the nics vminfod reports for the zone: the nic with the zone's end of the event, its
+//! destination for inbound traffic and its source for outbound, amongst its addresses. A zone with
+//! a single nic has all of its traffic attributed to it, which covers addresses vminfod doesn't
+//! know, such as ones handed out by DHCP. Otherwise an event whose address no nic has is left
+//! without the fields.
+
+use cfwevent::parser::{Direction, TrafficEvent};
+use serde::Serialize;
+use serde_json::Value;
+use std::net::{IpAddr, Ipv6Addr};
+use vminfod_client::Zone;
+
+/// The fields a record gains from the nic its event was seen on
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct NicContext {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vlan_id: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nic_tag: Option<String>,
+}
+
+/// One of a zone's nics, with its addresses as the device reports them
+#[derive(Clone, Debug, PartialEq)]
+pub struct Nic {
+    context: NicContext,
+    addrs: Vec<Ipv6Addr>,
+}
+
+/// An address out of a vmobj's "ip" or "ips", which can have a prefix length or be "dhcp" or
+/// "addrconf" rather than an address. IPv4 addresses are mapped like the device's are.
+fn parse_addr(addr: &str) -> Option<Ipv6Addr> {
+    let addr = addr.split('/').next()?.parse().ok()?;
+    Some(match addr {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    })
+}
+
+impl Nic {
+    fn from_value(nic: &Value) -> Nic {
+        let string = |name: &str| nic.get(name).and_then(Value::as_str).map(str::to_owned);
+        let ips = nic.get("ips").and_then(Value::as_array);
+        let addrs = ips
+            .into_iter()
+            .flatten()
+            .chain(nic.get("ip"))
+            .filter_map(Value::as_str)
+            .filter_map(parse_addr)
+            .collect();
+        Nic {
+            context: NicContext {
+                interface: string("interface"),
+                vlan_id: nic
+                    .get("vlan_id")
+                    .and_then(Value::as_u64)
+                    .filter(|&vlan_id| vlan_id <= u64::from(u16::MAX))
+                    .map(|vlan_id| vlan_id as u16),
+                nic_tag: string("nic_tag"),
+            },
+            addrs,
+        }
+    }
+}
+
+/// The zone's nics, leaving out anything that isn't a nic object
+pub fn zone_nics(zone: &Zone) -> Vec<Nic> {
+    zone.nics
+        .iter()
+        .filter(|nic| nic.is_object())
+        .map(Nic::from_value)
+        .collect()
+}
+
+/// The nic amongst the zone's `nics` that `event` was seen on, if it can be told
+pub fn attribute<'a>(nics: &'a [Nic], event: &TrafficEvent) -> Option<&'a NicContext> {
+    let local = match event.direction {
+        Direction::In => &event.destination_ip,
+        Direction::Out => &event.source_ip,
+    };
+    match nics {
+        [nic] => Some(&nic.context),
+        _ => nics
+            .iter()
+            .find(|nic| nic.addrs.contains(local))
+            .map(|nic| &nic.context),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvent};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn events_are_attributed_to_nics() {
+        let mut zone = testutils::create_zone();
+        zone.nics = serde_json::from_str(
+            r#"[
+                {"interface": "net0", "nic_tag": "external", "vlan_id": 0,
+                 "ips": ["10.0.0.2/24", "addrconf"]},
+                {"interface": "net1", "nic_tag": "internal", "vlan_id": 20,
+                 "ip": "192.168.20.5", "netmask": "255.255.255.0"},
+                "not a nic"
+            ]"#,
+        )
+        .unwrap();
+        let nics = zone_nics(&zone);
+        assert_eq!(nics.len(), 2);
+
+        let event = testutils::generate_event_for_zone(&zone);
+        let mut event = match parser::cfwevent_parse(event.as_bytes()).unwrap().1 {
+            CfwEvent::Traffic(event) => event,
+            event => panic!("unexpected event {:?}", event),
+        };
+        event.direction = Direction::In;
+        event.destination_ip = Ipv4Addr::new(192, 168, 20, 5).to_ipv6_mapped();
+        let nic = attribute(&nics, &event).expect("the destination is net1's");
+        assert_eq!(nic.interface.as_deref(), Some("net1"));
+        assert_eq!(nic.vlan_id, Some(20));
+        assert_eq!(nic.nic_tag.as_deref(), Some("internal"));
+
+        // Outbound traffic is attributed by its source
+        event.direction = Direction::Out;
+        assert_eq!(attribute(&nics, &event), None);
+        event.source_ip = Ipv4Addr::new(10, 0, 0, 2).to_ipv6_mapped();
+        assert_eq!(
+            attribute(&nics, &event).unwrap().interface.as_deref(),
+            Some("net0")
+        );
+
+        // A zone's only nic carries all of its traffic
+        event.source_ip = Ipv4Addr::new(10, 9, 9, 9).to_ipv6_mapped();
+        assert_eq!(
+            attribute(&nics[1..], &event).unwrap().interface.as_deref(),
+            Some("net1")
+        );
+        assert_eq!(attribute(&[], &event), None);
+    }
+}
@@ -0,0 +1,149 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The identity of the compute node cfwlogd is running on. When the config's "node_identity" is
+//! set every record includes the CN's server uuid and hostname, so logs aggregated centrally from
+//! many CNs remain attributable without relying on metadata added by the log shipper. The
+//! identity comes from sysinfo(1M), which has to be run before we drop the privilege to exec.
+//!
+//! "node_fields" picks which parts of the identity records get, any of "server_uuid",
+//! "server_hostname" and "datacenter". The datacenter is sysinfo's "Datacenter Name" unless the
+//! config's "datacenter" names it, and cfwlogd won't start if it's asked for but neither has it.
+
+use crate::config::NodeField;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::process::Command;
+
+const SYSINFO: &str = "/usr/bin/sysinfo";
+
+/// What records get when "node_fields" isn't set
+pub const DEFAULT_FIELDS: &[NodeField] = &[NodeField::ServerUuid, NodeField::ServerHostname];
+
+/// The parts of sysinfo's output the identity comes from
+#[derive(Debug, Deserialize)]
+struct Sysinfo {
+    #[serde(rename = "UUID")]
+    uuid: String,
+    #[serde(rename = "Hostname")]
+    hostname: String,
+    #[serde(rename = "Datacenter Name")]
+    datacenter: Option<String>,
+}
+
+/// The parts of the CN's identity every record is stamped with
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct NodeIdentity {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_uuid: Option<String>,
+    #[serde(rename = "server_hostname", skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub datacenter: Option<String>,
+}
+
+impl NodeIdentity {
+    /// Read the CN's identity from sysinfo, keeping the `fields` records get
+    pub fn from_sysinfo(
+        fields: &[NodeField],
+        datacenter: Option<&str>,
+    ) -> io::Result<NodeIdentity> {
+        let output = Command::new(SYSINFO).output()?;
+        if !output.status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} exited with {}", SYSINFO, output.status),
+            ));
+        }
+        Self::parse(&output.stdout, fields, datacenter)
+    }
+
+    fn parse(
+        sysinfo: &[u8],
+        fields: &[NodeField],
+        datacenter: Option<&str>,
+    ) -> io::Result<NodeIdentity> {
+        let sysinfo: Sysinfo = serde_json::from_slice(sysinfo)?;
+        let wanted = |field| fields.contains(&field);
+        let datacenter = datacenter.map(str::to_owned).or(sysinfo.datacenter);
+        if wanted(NodeField::Datacenter) && datacenter.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "sysinfo has no \"Datacenter Name\" and the config's \"datacenter\" isn't set",
+            ));
+        }
+        Ok(NodeIdentity {
+            server_uuid: Some(sysinfo.uuid).filter(|_| wanted(NodeField::ServerUuid)),
+            hostname: Some(sysinfo.hostname).filter(|_| wanted(NodeField::ServerHostname)),
+            datacenter: datacenter.filter(|_| wanted(NodeField::Datacenter)),
+        })
+    }
+
+    /// How the CN is known in cfwlogd's own log
+    pub fn describe(&self) -> String {
+        let parts: Vec<&str> = [&self.hostname, &self.server_uuid, &self.datacenter]
+            .iter()
+            .filter_map(|part| part.as_deref())
+            .collect();
+        parts.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sysinfo() {
+        let sysinfo = br#"{
+            "Live Image": "20200101T000000Z",
+            "System Type": "SunOS",
+            "UUID": "564d8a2c-1b0e-4a9f-8c3b-2f1e5d6c7b8a",
+            "Hostname": "cn1",
+            "Setup": "true"
+        }"#;
+        let node = NodeIdentity::parse(sysinfo, DEFAULT_FIELDS, None).expect("valid sysinfo");
+        assert_eq!(
+            node,
+            NodeIdentity {
+                server_uuid: Some("564d8a2c-1b0e-4a9f-8c3b-2f1e5d6c7b8a".to_owned()),
+                hostname: Some("cn1".to_owned()),
+                datacenter: None,
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(&node).unwrap(),
+            serde_json::json!({
+                "server_uuid": "564d8a2c-1b0e-4a9f-8c3b-2f1e5d6c7b8a",
+                "server_hostname": "cn1",
+            })
+        );
+        assert!(NodeIdentity::parse(b"{\"Hostname\": \"cn1\"}", DEFAULT_FIELDS, None).is_err());
+        assert!(
+            NodeIdentity::parse(sysinfo, &[NodeField::Datacenter], None).is_err(),
+            "the datacenter isn't known"
+        );
+    }
+
+    #[test]
+    fn fields_are_picked() {
+        let sysinfo = br#"{
+            "UUID": "564d8a2c-1b0e-4a9f-8c3b-2f1e5d6c7b8a",
+            "Hostname": "cn1",
+            "Datacenter Name": "us-east-1"
+        }"#;
+        let fields = [NodeField::ServerHostname, NodeField::Datacenter];
+        let node = NodeIdentity::parse(sysinfo, &fields, None).unwrap();
+        assert_eq!(
+            serde_json::to_value(&node).unwrap(),
+            serde_json::json!({"server_hostname": "cn1", "datacenter": "us-east-1"})
+        );
+        assert_eq!(node.describe(), "cn1 us-east-1");
+        let node = NodeIdentity::parse(sysinfo, &[NodeField::Datacenter], Some("lab")).unwrap();
+        assert_eq!(node.datacenter.as_deref(), Some("lab"), "the config wins");
+        assert_eq!(node.server_uuid, None);
+    }
+}
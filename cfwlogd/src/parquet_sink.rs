@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that writes each zone's traffic records to Parquet files, so they can land in a
+//! Presto/Athena style analytics store as they are, without a job converting the json logs first.
+//!
+//! Every "parquet.interval_secs" (aligned to the epoch, so an hour long interval starts on the
+//! hour) each zone gets a file named after the start of the interval in the "parquet" directory
+//! next to its current.log. Records are buffered until there are "parquet.row_group_rows" of
+//! them, which are then written out as a row group. The file is written as a hidden ".tmp" file,
+//! and renamed into place with its footer once the interval is over or cfwlogd stops, so anything
+//! picking up "*.parquet" files only ever sees complete ones. A temporary file left behind by a
+//! crash has no footer and can't be read.
+//!
+//! The columns are fixed by `SCHEMA`: the fields of a traffic record along with the zone's vm and
+//! alias, with nulls for the ICMP type and code of other protocols, for the number of events an
+//! unsampled record stands for and for the repeats of a record that doesn't end a run of them.
+//! The sink's entries in "sink_filters" apply as usual, but it can't be given a format, fields
+//! or a template.
+
+use crate::config::{Config, ParquetConfig};
+use crate::fileutils;
+use crate::sink::{Record, Sink, SinkStats};
+use cfwevent::parser::{logged_addr, CfwEvent, Direction};
+use chrono::{DateTime, TimeZone, Utc};
+use parquet::basic::Compression;
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{FileWriter, RowGroupWriter, SerializedFileWriter};
+use parquet::schema::parser::parse_message_type;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The columns of every file
+const SCHEMA: &str = "
+message cfw_record {
+    required int64 timestamp (TIMESTAMP_MICROS);
+    required binary event (UTF8);
+    required binary vm (UTF8);
+    required binary alias (UTF8);
+    required binary protocol (UTF8);
+    required binary direction (UTF8);
+    required binary source_ip (UTF8);
+    required binary destination_ip (UTF8);
+    required int32 source_port;
+    required int32 destination_port;
+    optional int32 icmp_type;
+    optional int32 icmp_code;
+    required binary rule (UTF8);
+    optional int64 sampled;
+    optional int64 repeats;
+}
+";
+
+/// A traffic record, until its row group is written
+struct Row {
+    timestamp: i64,
+    event: &'static str,
+    vm: String,
+    alias: String,
+    protocol: String,
+    direction: &'static str,
+    source_ip: String,
+    destination_ip: String,
+    source_port: u16,
+    destination_port: u16,
+    icmp: Option<(u8, u8)>,
+    rule: String,
+    sampled: Option<u64>,
+    repeats: Option<u64>,
+}
+
+impl Row {
+    fn new(record: &Record<'_>) -> Option<Row> {
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => return None,
+        };
+        Some(Row {
+            timestamp: event.timestamp.timestamp() * 1_000_000
+                + i64::from(event.timestamp.timestamp_subsec_micros()),
+            event: event.event.name(),
+            vm: record.vm.to_owned(),
+            alias: record.alias.to_owned(),
+            protocol: format!("{:?}", event.protocol),
+            direction: match event.direction {
+                Direction::In => "in",
+                Direction::Out => "out",
+            },
+            source_ip: logged_addr(&event.source_ip).to_string(),
+            destination_ip: logged_addr(&event.destination_ip).to_string(),
+            source_port: event.source_port,
+            destination_port: event.destination_port,
+            icmp: event
+                .icmp
+                .as_ref()
+                .map(|icmp| (icmp.icmp_type, icmp.icmp_code)),
+            rule: event.rule_uuid.to_string(),
+            sampled: record.sampled,
+            repeats: record.repeats,
+        })
+    }
+}
+
+/// The values of one column of a row group, in `SCHEMA` order, with the definition levels of
+/// optional columns
+enum Column {
+    Int64(Vec<i64>, Option<Vec<i16>>),
+    Int32(Vec<i32>, Option<Vec<i16>>),
+    Utf8(Vec<ByteArray>),
+}
+
+fn utf8(rows: &[Row], field: fn(&Row) -> &str) -> Column {
+    Column::Utf8(rows.iter().map(|row| field(row).into()).collect())
+}
+
+fn int32(rows: &[Row], field: fn(&Row) -> u16) -> Column {
+    Column::Int32(rows.iter().map(|row| i32::from(field(row))).collect(), None)
+}
+
+fn optional_int32(rows: &[Row], field: fn(&Row) -> Option<u8>) -> Column {
+    let values = rows.iter().filter_map(field).map(i32::from).collect();
+    let levels = rows.iter().map(|row| field(row).is_some() as i16).collect();
+    Column::Int32(values, Some(levels))
+}
+
+fn optional_int64(rows: &[Row], field: fn(&Row) -> Option<u64>) -> Column {
+    let values = rows.iter().filter_map(field).map(|n| n as i64).collect();
+    let levels = rows.iter().map(|row| field(row).is_some() as i16).collect();
+    Column::Int64(values, Some(levels))
+}
+
+/// Split rows into their columns
+fn columns(rows: &[Row]) -> Vec<Column> {
+    vec![
+        Column::Int64(rows.iter().map(|row| row.timestamp).collect(), None),
+        utf8(rows, |row| row.event),
+        utf8(rows, |row| &row.vm),
+        utf8(rows, |row| &row.alias),
+        utf8(rows, |row| &row.protocol),
+        utf8(rows, |row| row.direction),
+        utf8(rows, |row| &row.source_ip),
+        utf8(rows, |row| &row.destination_ip),
+        int32(rows, |row| row.source_port),
+        int32(rows, |row| row.destination_port),
+        optional_int32(rows, |row| row.icmp.map(|(icmp_type, _)| icmp_type)),
+        optional_int32(rows, |row| row.icmp.map(|(_, icmp_code)| icmp_code)),
+        utf8(rows, |row| &row.rule),
+        optional_int64(rows, |row| row.sampled),
+        optional_int64(rows, |row| row.repeats),
+    ]
+}
+
+fn parquet_error(e: ParquetError) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// The file of an interval, while it's being written
+struct OpenFile {
+    writer: SerializedFileWriter<File>,
+    /// The hidden name it's written under
+    tmp_name: String,
+    /// The name it's renamed to once it's complete
+    name: String,
+}
+
+/// Write out a zone's traffic records as Parquet files, see the "parquet_sink" module
+pub struct ParquetSink {
+    dir: PathBuf,
+    interval_secs: i64,
+    row_group_rows: usize,
+    /// The start of the interval the buffered rows belong to
+    interval_start: Option<DateTime<Utc>>,
+    rows: Vec<Row>,
+    file: Option<OpenFile>,
+    stats: SinkStats,
+}
+
+/// Open a zone's Parquet sink, if the config has a "parquet" table. The files are written to the
+/// "parquet" directory within the zone's log directory `dir`.
+pub fn open_sink(dir: &Path, config: &Config) -> Option<ParquetSink> {
+    let parquet: &ParquetConfig = config.parquet.as_ref()?;
+    Some(ParquetSink {
+        dir: dir.join("parquet"),
+        interval_secs: parquet.interval_secs as i64,
+        row_group_rows: parquet.row_group_rows,
+        interval_start: None,
+        rows: vec![],
+        file: None,
+        stats: SinkStats::default(),
+    })
+}
+
+impl ParquetSink {
+    /// The start of the interval `now` falls in
+    fn interval_of(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let secs = now.timestamp();
+        Utc.timestamp(secs - secs.rem_euclid(self.interval_secs), 0)
+    }
+
+    /// Write the buffered rows out as a row group of the interval's file
+    fn write_row_group(&mut self) -> io::Result<()> {
+        let start = match self.interval_start {
+            Some(start) if !self.rows.is_empty() => start,
+            _ => return Ok(()),
+        };
+        if self.file.is_none() {
+            self.file = Some(open_file(&self.dir, start)?);
+        }
+        let file = self.file.as_mut().expect("the interval's file is open");
+        write_row_group(&mut file.writer, &self.rows)?;
+        self.rows.clear();
+        Ok(())
+    }
+
+    /// Write out what's left of the interval and rename its file into place
+    fn finish_interval(&mut self) -> io::Result<()> {
+        self.write_row_group()?;
+        self.interval_start = None;
+        let mut file = match self.file.take() {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        file.writer.close().map_err(parquet_error)?;
+        let dir = fileutils::create_dir_all_nofollow(&self.dir)?;
+        fileutils::rename_at(&dir, &file.tmp_name, &file.name)
+    }
+
+    /// Finish the interval if it's over by `now`
+    fn check_interval(&mut self, now: DateTime<Utc>) -> io::Result<()> {
+        match self.interval_start {
+            Some(start) if self.interval_of(now) != start => self.finish_interval(),
+            _ => Ok(()),
+        }
+    }
+
+    fn write_rows(&mut self, records: &[Record<'_>], now: DateTime<Utc>) -> io::Result<()> {
+        self.check_interval(now)?;
+        self.interval_start = Some(self.interval_of(now));
+        for row in records.iter().filter_map(Row::new) {
+            self.rows.push(row);
+            self.stats.records += 1;
+            if self.rows.len() >= self.row_group_rows {
+                self.write_row_group()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write `rows` out as the next row group of a file
+fn write_row_group(writer: &mut SerializedFileWriter<File>, rows: &[Row]) -> io::Result<()> {
+    let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+    for values in columns(rows) {
+        let mut column = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .expect("a column for every value");
+        let written = match (&mut column, &values) {
+            (ColumnWriter::Int64ColumnWriter(w), Column::Int64(v, levels)) => {
+                w.write_batch(v, levels.as_deref(), None)
+            }
+            (ColumnWriter::Int32ColumnWriter(w), Column::Int32(v, levels)) => {
+                w.write_batch(v, levels.as_deref(), None)
+            }
+            (ColumnWriter::ByteArrayColumnWriter(w), Column::Utf8(v)) => {
+                w.write_batch(v, None, None)
+            }
+            _ => unreachable!("the columns follow SCHEMA"),
+        };
+        written.map_err(parquet_error)?;
+        row_group.close_column(column).map_err(parquet_error)?;
+    }
+    writer.close_row_group(row_group).map_err(parquet_error)
+}
+
+/// A writer of `SCHEMA` rows to `file`
+fn file_writer(file: File) -> io::Result<SerializedFileWriter<File>> {
+    let schema = Arc::new(parse_message_type(SCHEMA).map_err(parquet_error)?);
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    SerializedFileWriter::new(file, schema, Arc::new(properties)).map_err(parquet_error)
+}
+
+/// Start the file of the interval starting at `start`
+fn open_file(dir: &Path, start: DateTime<Utc>) -> io::Result<OpenFile> {
+    let name = format!("{}.parquet", start.format("%Y%m%dT%H%M%SZ"));
+    let tmp_name = format!(".{}.tmp", name);
+    let dir_file = fileutils::create_dir_all_nofollow(dir)?;
+    // Whatever a crash left behind can't be finished
+    let _ = std::fs::remove_file(dir.join(&tmp_name));
+    let file = fileutils::open_append_nofollow(&dir_file, &tmp_name)?;
+    Ok(OpenFile {
+        writer: file_writer(file)?,
+        tmp_name,
+        name,
+    })
+}
+
+/// A single Parquet file of traffic records with the sink's columns, for `cfwlogd convert`
+pub struct ParquetFile {
+    writer: SerializedFileWriter<File>,
+    row_group_rows: usize,
+    rows: Vec<Row>,
+}
+
+impl ParquetFile {
+    pub fn new(file: File, row_group_rows: usize) -> io::Result<ParquetFile> {
+        Ok(ParquetFile {
+            writer: file_writer(file)?,
+            row_group_rows,
+            rows: vec![],
+        })
+    }
+
+    /// Add a record, returning false if it isn't a traffic record and has no row
+    pub fn write(&mut self, record: &Record<'_>) -> io::Result<bool> {
+        let row = match Row::new(record) {
+            Some(row) => row,
+            None => return Ok(false),
+        };
+        self.rows.push(row);
+        if self.rows.len() >= self.row_group_rows {
+            write_row_group(&mut self.writer, &self.rows)?;
+            self.rows.clear();
+        }
+        Ok(true)
+    }
+
+    /// Write out the rest of the rows along with the footer
+    pub fn close(mut self) -> io::Result<()> {
+        if !self.rows.is_empty() {
+            write_row_group(&mut self.writer, &self.rows)?;
+        }
+        self.writer.close().map_err(parquet_error)
+    }
+}
+
+impl Sink for ParquetSink {
+    fn name(&self) -> &str {
+        "parquet"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        self.write_rows(records, Utc::now())
+    }
+
+    /// Rows are only written out once there are enough for a row group, since every row group
+    /// costs a round of column headers and statistics
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        self.check_interval(Utc::now())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.finish_interval()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+
+    #[test]
+    fn zones_are_written_per_interval() {
+        let dir = PathBuf::from("/var/tmp/cfwlogd-tests").join(uuid::Uuid::new_v4().to_string());
+        let config = Config::from_toml("[parquet]\ninterval_secs = 3600\nrow_group_rows = 2\n")
+            .expect("valid parquet config");
+        let mut sink = open_sink(&dir, &config).unwrap();
+        let event = testutils::generate_event();
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            "web",
+        );
+        let records = vec![record.clone(), record.clone(), record];
+
+        let start = Utc.ymd(2020, 5, 12).and_hms(19, 0, 0);
+        sink.write_rows(&records, start + chrono::Duration::minutes(10))
+            .unwrap();
+        let files = |dir: &Path| {
+            let mut names: Vec<String> = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+        let parquet_dir = dir.join("parquet");
+        assert_eq!(
+            files(&parquet_dir),
+            vec![".20200512T190000Z.parquet.tmp"],
+            "the interval isn't over"
+        );
+
+        sink.write_rows(&[], start + chrono::Duration::minutes(70))
+            .unwrap();
+        assert_eq!(files(&parquet_dir), vec!["20200512T190000Z.parquet"]);
+        let file = File::open(parquet_dir.join("20200512T190000Z.parquet")).unwrap();
+        let reader = SerializedFileReader::new(file).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 3);
+        assert_eq!(
+            metadata.num_row_groups(),
+            2,
+            "a full row group and the rest"
+        );
+        assert_eq!(sink.stats().records, 3);
+
+        sink.close().unwrap();
+        assert_eq!(
+            files(&parquet_dir).len(),
+            1,
+            "nothing to write for the next interval"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,176 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Pausing a zone's logging at runtime, for incident response when one zone is flooding the
+//! pipeline. The admin socket's "pause" and "resume" commands, which `cfwlogd ctl pause <vm>` and
+//! `cfwlogd ctl resume <vm>` send, flip the `Pause` kept in the zone's `ZoneCounters`. While a
+//! zone is paused its `Logger` counts its events by rule rather than writing them to any sink,
+//! and once it's resumed the first thing its log gets is a `ResumeNotice` saying how long the
+//! pause lasted and what was left out. cfwlogd's own records are still written while a zone is
+//! paused, and a pause doesn't outlast cfwlogd.
+
+use crate::rules::Rules;
+use crate::sink::SchemaVersion;
+use crate::stats::{self, RuleReport};
+use cfwevent::parser;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Whether a zone's logging is paused, with what was counted while it was
+#[derive(Debug, Default)]
+pub struct Pause {
+    /// Checked for every batch, without taking the lock
+    paused: AtomicBool,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// When the zone was paused, while it is
+    since: Option<DateTime<Utc>>,
+    counts: Counts,
+    /// A pause that ended which the zone's log hasn't been told about yet
+    ended: Option<(DateTime<Utc>, DateTime<Utc>, Counts)>,
+}
+
+/// The events left out during a pause
+#[derive(Debug, Default)]
+struct Counts {
+    events: u64,
+    /// The traffic events among them by rule
+    rules: HashMap<Uuid, u64>,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.events += other.events;
+        for (rule, events) in other.rules {
+            *self.rules.entry(rule).or_insert(0) += events;
+        }
+    }
+}
+
+/// Logged when a paused zone is resumed, in place of the events left out while it was paused
+#[derive(Debug, Serialize)]
+pub struct ResumeNotice<'a> {
+    pub schema_version: SchemaVersion,
+    pub event: &'static str,
+    pub vm: &'a str,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub paused_at: DateTime<Utc>,
+    pub events: u64,
+    pub rules: Vec<RuleReport>,
+    /// When the zone was resumed
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl Pause {
+    /// Pause the zone, returning false if it already was
+    pub fn pause(&self, at: DateTime<Utc>) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.since.is_some() {
+            return false;
+        }
+        state.since = Some(at);
+        self.paused.store(true, Ordering::Relaxed);
+        true
+    }
+
+    /// Resume the zone, returning the events left out while it was paused, or None if it wasn't
+    pub fn resume(&self, at: DateTime<Utc>) -> Option<u64> {
+        let mut state = self.state.lock().unwrap();
+        let since = state.since.take()?;
+        self.paused.store(false, Ordering::Relaxed);
+        let mut counts = std::mem::take(&mut state.counts);
+        let events = counts.events;
+        // Should the zone have been paused again before its log was told, that's one notice
+        let paused_at = match state.ended.take() {
+            Some((paused_at, _, earlier)) => {
+                counts.add(earlier);
+                paused_at
+            }
+            None => since,
+        };
+        state.ended = Some((paused_at, at, counts));
+        Some(events)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Count an event of the paused zone, along with its rule if it's a traffic event
+    pub fn counted(&self, rule: Option<Uuid>) {
+        let mut state = self.state.lock().unwrap();
+        state.counts.events += 1;
+        if let Some(rule) = rule {
+            *state.counts.rules.entry(rule).or_insert(0) += 1;
+        }
+    }
+
+    /// The notice to log for a pause that ended since the last call, if there was one
+    pub fn take_resumed<'a>(&self, vm: &'a str, rules: &Rules) -> Option<ResumeNotice<'a>> {
+        let (paused_at, resumed_at, counts) = self.state.lock().unwrap().ended.take()?;
+        Some(ResumeNotice {
+            schema_version: SchemaVersion,
+            event: "logging_resumed",
+            vm,
+            paused_at,
+            events: counts.events,
+            rules: stats::reports(counts.rules, rules),
+            timestamp: resumed_at,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::sync::ShardedLock;
+    use std::sync::Arc;
+
+    #[test]
+    fn pauses_are_counted() {
+        let pause = Pause::default();
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        let rule = Uuid::new_v4();
+        let start = Utc::now();
+        assert!(!pause.is_paused());
+        assert_eq!(pause.resume(start), None, "the zone isn't paused");
+        assert!(pause.pause(start));
+        assert!(!pause.pause(start), "the zone is already paused");
+        assert!(pause.is_paused());
+        pause.counted(Some(rule));
+        pause.counted(Some(rule));
+        pause.counted(None);
+        assert!(pause.take_resumed("vm1", &rules).is_none());
+
+        let resumed = start + chrono::Duration::seconds(30);
+        assert_eq!(pause.resume(resumed), Some(3));
+        assert!(!pause.is_paused());
+        let notice = pause.take_resumed("vm1", &rules).unwrap();
+        assert_eq!((notice.paused_at, notice.timestamp), (start, resumed));
+        assert_eq!(notice.events, 3);
+        assert_eq!((notice.rules[0].rule, notice.rules[0].events), (rule, 2));
+        assert!(pause.take_resumed("vm1", &rules).is_none(), "told once");
+
+        // Pauses that end before the log is told are told about together
+        pause.pause(resumed);
+        pause.counted(None);
+        pause.resume(resumed);
+        pause.pause(resumed);
+        pause.counted(Some(rule));
+        assert_eq!(pause.resume(resumed), Some(1));
+        let notice = pause.take_resumed("vm1", &rules).unwrap();
+        assert_eq!(notice.events, 2);
+        assert_eq!(notice.rules.len(), 1);
+    }
+}
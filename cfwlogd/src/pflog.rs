@@ -0,0 +1,314 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! This is the implementation of an EventSource for the BSD pf(4) firewall, which logs packets
+//! matching rules marked with "log" to the pflog(4) interface. Packets are captured off of that
+//! interface with libpcap and re-encoded into the same binary format the ipfev device produces.
+//!
+//! pf doesn't know anything about zones or cloud firewall rule uuids, so the rules for each zone
+//! are expected to be loaded into an anchor named:
+//!
+//! ```text
+//! cfw/<zonedid>
+//! ```
+//!
+//! and the rule uuid is looked up in the "rules" table of the source config using a key of
+//! "<zonedid>/<rule number>". Packets logged from any other anchor are skipped, and rules missing
+//! from the table are logged with the nil uuid.
+
+use crate::source::{EventSource, SourceStats};
+use crate::wire::{self, RuleInfo, TRAFFIC_EVENT_SIZE};
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// DLT_PFLOG from <pcap/dlt.h>
+const DLT_PFLOG: i32 = 117;
+
+// From <net/pfvar.h> and <net/if_pflog.h>
+const PF_PASS: u8 = 0;
+const PF_DROP: u8 = 1;
+const PF_IN: u8 = 1;
+const PF_OUT: u8 = 2;
+/// Offset of the `ruleset` member of struct pfloghdr
+const PFLOG_RULESET: usize = 20;
+const PF_RULESET_NAME_SIZE: usize = 16;
+/// Offset of the `rulenr` member of struct pfloghdr
+const PFLOG_RULENR: usize = 36;
+/// Offset of the `dir` member of struct pfloghdr
+const PFLOG_DIR: usize = 60;
+
+/// Name of the anchor each zone's rules are loaded into, followed by "/<zonedid>"
+const CFW_ANCHOR: &str = "cfw";
+
+/// We only need the pflog header, the IP header and the ports out of each packet
+const SNAPLEN: i32 = 160;
+/// How long pcap waits for a packet before handing control back to us
+const READ_TIMEOUT_MS: i32 = 100;
+/// How long a read keeps collecting packets after the first one arrives
+const BATCH_WINDOW: Duration = Duration::from_millis(100);
+const MAX_EVENTS_PER_READ: usize = 1024;
+
+/// Round up to the 4 byte alignment the packet is placed at after the pflog header
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn pcap_error(e: pcap::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+/// The part of a pflog header we care about
+#[derive(Debug, PartialEq)]
+struct PflogHeader<'a> {
+    action: u8,
+    ruleset: &'a str,
+    rulenr: u32,
+    dir: u8,
+    /// The logged IP packet
+    payload: &'a [u8],
+}
+
+fn parse_header(frame: &[u8]) -> Option<PflogHeader<'_>> {
+    let length = usize::from(*frame.first()?);
+    if length <= PFLOG_DIR || frame.len() < length {
+        return None;
+    }
+    let ruleset = &frame[PFLOG_RULESET..PFLOG_RULESET + PF_RULESET_NAME_SIZE];
+    let end = ruleset
+        .iter()
+        .position(|&b| b == 0)
+        .unwrap_or(ruleset.len());
+    let ruleset = std::str::from_utf8(&ruleset[..end]).ok()?;
+    let mut rulenr = [0; 4];
+    rulenr.copy_from_slice(&frame[PFLOG_RULENR..PFLOG_RULENR + 4]);
+    Some(PflogHeader {
+        action: frame[2],
+        ruleset,
+        rulenr: u32::from_be_bytes(rulenr),
+        dir: frame[PFLOG_DIR],
+        payload: frame.get(align(length)..)?,
+    })
+}
+
+/// Pull the zonedid out of a "cfw/<zonedid>" anchor name
+fn anchor_zonedid(ruleset: &str) -> Option<u32> {
+    let mut parts = ruleset.splitn(2, '/');
+    if parts.next()? != CFW_ANCHOR {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Turn a single captured pflog frame into an event in `buf`, returning false if it was skipped
+fn encode_frame(
+    frame: &[u8],
+    timestamp: DateTime<Utc>,
+    rules: &HashMap<String, Uuid>,
+    buf: &mut [u8],
+) -> bool {
+    let header = match parse_header(frame) {
+        Some(header) => header,
+        None => {
+            debug!("skipping truncated pflog frame");
+            return false;
+        }
+    };
+    let event = match header.action {
+        PF_DROP => 1,
+        PF_PASS => 2,
+        _ => return false,
+    };
+    let direction = match header.dir {
+        PF_IN | PF_OUT => header.dir,
+        _ => return false,
+    };
+    let zonedid = match anchor_zonedid(header.ruleset) {
+        Some(zonedid) => zonedid,
+        None => {
+            debug!(
+                "skipping pflog packet from non cfw anchor \"{}\"",
+                header.ruleset
+            );
+            return false;
+        }
+    };
+    let packet = match wire::parse_packet(header.payload) {
+        Some(packet) => packet,
+        None => {
+            debug!("skipping pflog packet without an IP payload");
+            return false;
+        }
+    };
+    let rule_uuid = rules
+        .get(&format!("{}/{}", zonedid, header.rulenr))
+        .cloned()
+        .unwrap_or_else(Uuid::nil);
+    let rule = RuleInfo {
+        event,
+        zonedid,
+        rule_id: header.rulenr,
+        rule_uuid,
+    };
+    wire::encode_event(&rule, &packet, direction, timestamp, buf);
+    true
+}
+
+/// Wrapper around a pcap capture on a pflog interface
+pub struct PflogSource {
+    capture: pcap::Capture<pcap::Active>,
+    rules: HashMap<String, Uuid>,
+}
+
+impl PflogSource {
+    pub fn new(interface: &str, rules: HashMap<String, Uuid>) -> io::Result<Self> {
+        let capture = pcap::Capture::from_device(interface)
+            .map_err(pcap_error)?
+            .snaplen(SNAPLEN)
+            .timeout(READ_TIMEOUT_MS)
+            .open()
+            .map_err(pcap_error)?;
+        if capture.get_datalink().0 != DLT_PFLOG {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a pflog interface", interface),
+            ));
+        }
+        info!("capturing pf events from {}", interface);
+        Ok(PflogSource { capture, rules })
+    }
+}
+
+impl EventSource for PflogSource {
+    /// Pull at least one event from the pflog interface into the provided buffer
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut written = 0;
+        let mut first = None;
+        while buf.len() - written >= TRAFFIC_EVENT_SIZE {
+            if first.map_or(false, |t: Instant| t.elapsed() >= BATCH_WINDOW) {
+                break;
+            }
+            match self.capture.next() {
+                Ok(packet) => {
+                    let ts = packet.header.ts;
+                    let timestamp = Utc.timestamp(ts.tv_sec as i64, ts.tv_usec as u32 * 1000);
+                    if encode_frame(packet.data, timestamp, &self.rules, &mut buf[written..]) {
+                        written += TRAFFIC_EVENT_SIZE;
+                        first.get_or_insert_with(Instant::now);
+                    }
+                }
+                // The pipeline expects every read to contain at least one event
+                Err(pcap::Error::TimeoutExpired) if written == 0 => continue,
+                Err(pcap::Error::TimeoutExpired) => break,
+                Err(e) => return Err(pcap_error(e)),
+            }
+        }
+        Ok(written)
+    }
+
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)> {
+        Ok((TRAFFIC_EVENT_SIZE, MAX_EVENTS_PER_READ))
+    }
+
+    /// Counters from libpcap, which include packets that weren't logged by cloud firewall rules
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        let stats = self.capture.stats().map_err(pcap_error)?;
+        Ok(SourceStats {
+            received: u64::from(stats.received),
+            dropped: u64::from(stats.dropped) + u64::from(stats.if_dropped),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvType, CfwEvent, Direction, Protocol};
+    use std::net::Ipv4Addr;
+
+    /// Length of struct pfloghdr as reported in its `length` member
+    const PFLOG_HDRLEN: u8 = 61;
+
+    fn frame(action: u8, ruleset: &str, rulenr: u32, dir: u8) -> Vec<u8> {
+        let mut frame = vec![0; align(usize::from(PFLOG_HDRLEN))];
+        frame[0] = PFLOG_HDRLEN;
+        frame[2] = action;
+        frame[PFLOG_RULESET..PFLOG_RULESET + ruleset.len()].copy_from_slice(ruleset.as_bytes());
+        frame[PFLOG_RULENR..PFLOG_RULENR + 4].copy_from_slice(&rulenr.to_be_bytes());
+        frame[PFLOG_DIR] = dir;
+
+        let mut packet = vec![0; 24];
+        packet[0] = 0x45; // IPv4 with a 20 byte header
+        packet[9] = 17; // UDP
+        packet[12..16].copy_from_slice(&[192, 168, 0, 1]);
+        packet[16..20].copy_from_slice(&[192, 168, 0, 2]);
+        packet[20..22].copy_from_slice(&5000u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&53u16.to_be_bytes());
+        frame.extend(packet);
+        frame
+    }
+
+    #[test]
+    fn pflog_frame_round_trip() {
+        let uuid = Uuid::new_v4();
+        let mut rules = HashMap::new();
+        rules.insert("12/3".to_owned(), uuid);
+
+        let mut buf = [0; TRAFFIC_EVENT_SIZE];
+        let frame = frame(PF_DROP, "cfw/12", 3, PF_OUT);
+        assert!(encode_frame(&frame, Utc::now(), &rules, &mut buf));
+
+        let event = match parser::cfwevent_parse(&buf)
+            .expect("failed to parse event")
+            .1
+        {
+            CfwEvent::Traffic(event) => event,
+            e => panic!("unexpected event {:?}", e),
+        };
+        assert_eq!(event.event, CfwEvType::Block);
+        assert_eq!(event.zonedid, 12);
+        assert_eq!(event.rule_id, 3);
+        assert_eq!(event.protocol, Protocol::UDP);
+        assert_eq!(event.direction, Direction::Out);
+        assert_eq!(
+            event.destination_ip,
+            Ipv4Addr::new(192, 168, 0, 2).to_ipv6_mapped()
+        );
+        assert_eq!(event.destination_port, 53);
+        assert_eq!(event.rule_uuid, uuid);
+    }
+
+    #[test]
+    fn unknown_rules_and_anchors() {
+        let rules = HashMap::new();
+        let mut buf = [0; TRAFFIC_EVENT_SIZE];
+        assert!(
+            !encode_frame(
+                &frame(PF_PASS, "other", 1, PF_IN),
+                Utc::now(),
+                &rules,
+                &mut buf
+            ),
+            "packets from other anchors are skipped"
+        );
+        assert!(
+            encode_frame(
+                &frame(PF_PASS, "cfw/4", 1, PF_IN),
+                Utc::now(),
+                &rules,
+                &mut buf
+            ),
+            "packets from rules missing from the table are kept"
+        );
+        match parser::cfwevent_parse(&buf).unwrap().1 {
+            CfwEvent::Traffic(event) => assert_eq!(event.rule_uuid, Uuid::nil()),
+            e => panic!("unexpected event {:?}", e),
+        }
+        assert!(!encode_frame(&[PFLOG_HDRLEN], Utc::now(), &rules, &mut buf));
+    }
+}
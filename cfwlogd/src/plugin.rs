@@ -0,0 +1,251 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Support for out-of-tree sinks loaded from shared objects, for operators with destinations we
+//! don't ship a sink for. Every path listed in "sink_plugins" is loaded once at startup, before we
+//! chroot into the log directory, and each `Logger` then opens one instance of every plugin for
+//! its zone. A plugin exports a single function returning a table of C functions:
+//!
+//! ```c
+//! struct cfwlogd_sink_v1 {
+//!     uint32_t version;    /* CFWLOGD_SINK_VERSION, currently 1 */
+//!     const char *name;
+//!     void *(*open)(const char *vm, const char *customer);
+//!     int (*write_batch)(void *sink, const uint8_t *records, size_t len, size_t count);
+//!     int (*flush)(void *sink);
+//!     int (*rotate)(void *sink);
+//!     int (*close)(void *sink);
+//! };
+//!
+//! const struct cfwlogd_sink_v1 *cfwlogd_sink_v1(void);
+//! ```
+//!
+//! Records are handed to `write_batch` as newline separated json, the same format as the zone's
+//! log file unless the plugin's name has an entry in "sink_fields" or "sink_templates", or one
+//! after another without a separator when it has a binary format in "sink_formats". `open`
+//! returns NULL on failure and every other function returns 0 on success or an errno value.
+//! `close` must release everything associated with the sink. A plugin's functions may be called
+//! from many `Logger` threads at once, but any one sink is only ever used from a single thread.
+
+use crate::config::Config;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use libc::{c_char, c_int, c_void, size_t};
+use libloading::{Library, Symbol};
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// The version of `SinkV1` this build of cfwlogd understands
+const CFWLOGD_SINK_VERSION: u32 = 1;
+
+/// Name of the function a plugin must export
+const ENTRY_POINT: &[u8] = b"cfwlogd_sink_v1\0";
+
+#[repr(C)]
+struct SinkV1 {
+    version: u32,
+    name: *const c_char,
+    open: extern "C" fn(vm: *const c_char, customer: *const c_char) -> *mut c_void,
+    write_batch:
+        extern "C" fn(sink: *mut c_void, records: *const u8, len: size_t, count: size_t) -> c_int,
+    flush: extern "C" fn(sink: *mut c_void) -> c_int,
+    rotate: extern "C" fn(sink: *mut c_void) -> c_int,
+    close: extern "C" fn(sink: *mut c_void) -> c_int,
+}
+
+/// A loaded shared object
+struct Plugin {
+    name: String,
+    vtable: *const SinkV1,
+    /// Keeps the shared object mapped for as long as `vtable` is in use
+    _lib: Library,
+}
+
+// The vtable points at immutable data inside the shared object, which stays loaded for as long as
+// the `Plugin` exists.
+unsafe impl Send for Plugin {}
+unsafe impl Sync for Plugin {}
+
+impl Plugin {
+    fn load(path: &Path) -> io::Result<Plugin> {
+        let lib = Library::new(path)?;
+        let vtable = unsafe {
+            let entry: Symbol<unsafe extern "C" fn() -> *const SinkV1> = lib.get(ENTRY_POINT)?;
+            entry()
+        };
+        if vtable.is_null() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} returned a NULL sink table", path.display()),
+            ));
+        }
+        let version = unsafe { (*vtable).version };
+        if version != CFWLOGD_SINK_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} implements sink version {}, expected {}",
+                    path.display(),
+                    version,
+                    CFWLOGD_SINK_VERSION
+                ),
+            ));
+        }
+        let name = unsafe { CStr::from_ptr((*vtable).name) }
+            .to_string_lossy()
+            .into_owned();
+        Ok(Plugin {
+            name,
+            vtable,
+            _lib: lib,
+        })
+    }
+
+    fn vtable(&self) -> &SinkV1 {
+        unsafe { &*self.vtable }
+    }
+}
+
+lazy_static! {
+    /// Every plugin loaded at startup. Shared objects are process wide by nature, so rather than
+    /// handing them to every `Logger` they are kept here.
+    static ref PLUGINS: Mutex<Vec<Arc<Plugin>>> = Mutex::new(vec![]);
+}
+
+/// Load every plugin found at `paths`. This must happen before we chroot since the paths are
+/// outside of the log directory.
+pub fn load<P: AsRef<Path>>(paths: &[P]) -> io::Result<()> {
+    let mut plugins = PLUGINS.lock().unwrap();
+    for path in paths {
+        let path = path.as_ref();
+        let plugin = Plugin::load(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        info!("loaded {} sink from {}", plugin.name, path.display());
+        plugins.push(Arc::new(plugin));
+    }
+    Ok(())
+}
+
+/// Open an instance of every loaded plugin for the given zone, each encoding records as `config`
+/// says for its name. Plugins that fail to open are logged and skipped so they can't prevent the
+/// zone from being logged to its file.
+pub fn open_sinks(vm: &str, customer: &str, config: &Config) -> Vec<Box<dyn Sink>> {
+    let plugins = PLUGINS.lock().unwrap();
+    plugins
+        .iter()
+        .filter_map(|plugin| {
+            let encoder = Encoder::for_sink(&plugin.name, config);
+            match DynamicSink::open(Arc::clone(plugin), vm, customer, encoder) {
+                Ok(sink) => Some(Box::new(sink) as Box<dyn Sink>),
+                Err(e) => {
+                    error!("failed to open {} sink for {}: {}", plugin.name, vm, e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// Turn the return value of a plugin function into a Result
+fn check(rc: c_int) -> io::Result<()> {
+    match rc {
+        0 => Ok(()),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// A zone's instance of a plugin
+struct DynamicSink {
+    plugin: Arc<Plugin>,
+    /// The plugin's state for this sink, NULL once the sink is closed
+    handle: *mut c_void,
+    /// Reused for serializing each batch
+    buf: Vec<u8>,
+    encoder: Encoder,
+    stats: SinkStats,
+}
+
+// The handle is only ever used by the `Logger` thread that owns the sink.
+unsafe impl Send for DynamicSink {}
+
+impl DynamicSink {
+    fn open(
+        plugin: Arc<Plugin>,
+        vm: &str,
+        customer: &str,
+        encoder: Encoder,
+    ) -> io::Result<DynamicSink> {
+        let nul = |_| io::Error::new(io::ErrorKind::InvalidInput, "zone contained nuls");
+        let vm = CString::new(vm).map_err(nul)?;
+        let customer = CString::new(customer).map_err(nul)?;
+        let handle = (plugin.vtable().open)(vm.as_ptr(), customer.as_ptr());
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(DynamicSink {
+            plugin,
+            handle,
+            buf: vec![],
+            encoder,
+            stats: SinkStats::default(),
+        })
+    }
+}
+
+impl Sink for DynamicSink {
+    fn name(&self) -> &str {
+        &self.plugin.name
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        self.buf.clear();
+        for record in records {
+            self.encoder.encode(record, &mut self.buf)?;
+            self.buf.extend_from_slice(self.encoder.separator());
+        }
+        check((self.plugin.vtable().write_batch)(
+            self.handle,
+            self.buf.as_ptr(),
+            self.buf.len(),
+            records.len(),
+        ))?;
+        self.stats.records += records.len() as u64;
+        self.stats.bytes += self.buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        check((self.plugin.vtable().flush)(self.handle))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        check((self.plugin.vtable().rotate)(self.handle))
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(&self.plugin.name, config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        if self.handle.is_null() {
+            return Ok(());
+        }
+        let rc = (self.plugin.vtable().close)(self.handle);
+        self.handle = std::ptr::null_mut();
+        check(rc)
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+impl Drop for DynamicSink {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
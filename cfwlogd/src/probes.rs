@@ -0,0 +1,59 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! DTrace USDT probes along the path an event takes through cfwlogd, so a zone's event flow can
+//! be traced live on a CN without restarting cfwlogd. The "cfwlogd" provider is defined in
+//! "probes.d" and only built with `--features usdt`; without it the `probe!` macro compiles to
+//! nothing. A probe's arguments are only evaluated while it's enabled.
+
+use crate::stats::DropReason;
+
+#[cfg(feature = "usdt")]
+usdt::dtrace_provider!("probes.d");
+
+/// Fire one of the "cfwlogd" provider's probes, e.g. `probe!(parse(zonedid))`
+#[cfg(feature = "usdt")]
+macro_rules! probe {
+    ($probe:ident($arg:expr)) => {
+        crate::probes::cfwlogd::$probe!(|| $arg)
+    };
+    ($probe:ident($($arg:expr),+)) => {
+        crate::probes::cfwlogd::$probe!(|| ($($arg),+))
+    };
+}
+
+/// Without the "usdt" feature the arguments are still type checked, but never evaluated
+#[cfg(not(feature = "usdt"))]
+macro_rules! probe {
+    ($probe:ident($($arg:expr),+)) => {
+        if false {
+            let _ = ($(&$arg,)+);
+        }
+    };
+}
+
+/// Register the probes with DTrace. This has to happen in the process that fires them, so under
+/// SMF it must be done after we have daemonized, and before we chroot away from /dev/dtrace.
+pub fn register() {
+    #[cfg(feature = "usdt")]
+    match usdt::register_probes() {
+        Ok(()) => debug!("registered USDT probes"),
+        Err(e) => warn!("failed to register USDT probes: {}", e),
+    }
+}
+
+/// The name the "drop" probe gives a `DropReason`
+pub fn drop_reason(reason: DropReason) -> &'static str {
+    match reason {
+        DropReason::QueueFull => "queue_full",
+        DropReason::LoggerDisconnected => "logger_disconnected",
+        DropReason::MemoryPressure => "memory_pressure",
+        DropReason::DiskSpace => "disk_space",
+        DropReason::DiskFull => "disk_full",
+        DropReason::RateLimited => "rate_limited",
+        DropReason::DiskBudget => "disk_budget",
+    }
+}
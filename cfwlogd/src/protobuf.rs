@@ -0,0 +1,155 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The "protobuf" record format, for consumers that would rather generate bindings from a schema
+//! than parse json. The message is defined in "proto/record.proto", which `cfwlogd schema --proto`
+//! prints. Records are converted from their json fields, so they follow the configured
+//! "schema_version" the same as every other format, and the records cfwlogd generates itself are
+//! carried as json inside the message.
+
+use prost::Message;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::io;
+
+mod proto {
+    include!(concat!(env!("OUT_DIR"), "/cfwlogd.record.rs"));
+}
+
+pub use proto::Record;
+
+/// Whether the fields belong to a traffic record rather than one cfwlogd generated itself
+fn is_traffic(fields: &Map<String, Value>) -> bool {
+    let event = fields.get("event").and_then(Value::as_str);
+    matches!(event, Some("block") | Some("begin") | Some("end")) && fields.contains_key("protocol")
+}
+
+/// Remove a string field, leaving it empty if the record doesn't have it. A number, which is how
+/// "timestamp_format" can have timestamps written, is given as its digits.
+fn take_str(fields: &mut Map<String, Value>, name: &str) -> String {
+    match fields.remove(name) {
+        Some(Value::String(s)) => s,
+        Some(Value::Number(n)) => n.to_string(),
+        _ => String::new(),
+    }
+}
+
+/// The message for a record's json fields
+fn to_message(mut fields: Map<String, Value>) -> Record {
+    let traffic = is_traffic(&fields);
+    let mut record = Record {
+        schema_version: fields
+            .get("schema_version")
+            .and_then(Value::as_u64)
+            .unwrap_or(0),
+        event: take_str(&mut fields, "event"),
+        vm: take_str(&mut fields, "vm"),
+        ..Record::default()
+    };
+    if !traffic {
+        fields.remove("schema_version");
+        record.json = Value::Object(fields).to_string();
+        return record;
+    }
+    let uint = |name: &str| fields.get(name).and_then(Value::as_u64).unwrap_or(0);
+    let int = |name: &str| fields.get(name).and_then(Value::as_i64).unwrap_or(0);
+    record.source_port = uint("source_port") as u32;
+    record.destination_port = uint("destination_port") as u32;
+    record.icmp_type = uint("icmp_type") as u32;
+    record.icmp_code = uint("icmp_code") as u32;
+    record.vlan_id = uint("vlan_id") as u32;
+    record.remote_asn = uint("remote_asn") as u32;
+    record.sampled = uint("sampled");
+    record.repeats = uint("repeats");
+    record.epoch_ms = int("epoch_ms");
+    record.epoch_ns = int("epoch_ns");
+    record.received_hrtime = uint("received_hrtime");
+    record.lag_ms = int("lag_ms");
+    record.received_timestamp = take_str(&mut fields, "received_timestamp");
+    record.alias = take_str(&mut fields, "alias");
+    record.timestamp = take_str(&mut fields, "timestamp");
+    record.protocol = take_str(&mut fields, "protocol");
+    record.direction = take_str(&mut fields, "direction");
+    record.source_ip = take_str(&mut fields, "source_ip");
+    record.destination_ip = take_str(&mut fields, "destination_ip");
+    record.rule = take_str(&mut fields, "rule");
+    record.rule_owner = take_str(&mut fields, "rule_owner");
+    record.rule_description = take_str(&mut fields, "rule_description");
+    record.remote_country = take_str(&mut fields, "remote_country");
+    record.remote_as_org = take_str(&mut fields, "remote_as_org");
+    record.remote_hostname = take_str(&mut fields, "remote_hostname");
+    record.server_uuid = take_str(&mut fields, "server_uuid");
+    record.server_hostname = take_str(&mut fields, "server_hostname");
+    record.datacenter = take_str(&mut fields, "datacenter");
+    record.interface = take_str(&mut fields, "interface");
+    record.nic_tag = take_str(&mut fields, "nic_tag");
+    record
+}
+
+/// Append a record as a length delimited message
+pub fn encode<T: Serialize>(record: &T, buf: &mut Vec<u8>) -> io::Result<()> {
+    let message = match serde_json::to_value(record)? {
+        Value::Object(fields) => to_message(fields),
+        value => Record {
+            json: value.to_string(),
+            ..Record::default()
+        },
+    };
+    message
+        .encode_length_delimited(buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_are_encoded_as_messages() {
+        let traffic = serde_json::json!({
+            "schema_version": 2,
+            "event": "block",
+            "source_port": 0,
+            "destination_port": 0,
+            "protocol": "ICMP",
+            "icmp_type": 8,
+            "icmp_code": 0,
+            "direction": "in",
+            "source_ip": "192.0.2.1",
+            "destination_ip": "172.24.4.151",
+            "timestamp": "2020-05-12T19:14:41.214655Z",
+            "rule": "f5a5dd1a-ef3c-4f50-8d1e-99e7b6a6a2d4",
+            "vm": "vm1",
+            "alias": "web",
+            "sampled": 10
+        });
+        let lifecycle = serde_json::json!({
+            "schema_version": 2,
+            "event": "lifecycle",
+            "vm": "vm1",
+            "action": "start",
+        });
+        let mut buf = vec![];
+        encode(&traffic, &mut buf).unwrap();
+        encode(&lifecycle, &mut buf).unwrap();
+
+        let mut rest = &buf[..];
+        let record = Record::decode_length_delimited(&mut rest).unwrap();
+        assert_eq!(record.event, "block");
+        assert_eq!(record.schema_version, 2);
+        assert_eq!(record.icmp_type, 8);
+        assert_eq!(record.source_ip, "192.0.2.1");
+        assert_eq!(record.sampled, 10);
+        assert!(record.json.is_empty());
+
+        let record = Record::decode_length_delimited(&mut rest).unwrap();
+        assert_eq!(record.event, "lifecycle");
+        assert_eq!(record.vm, "vm1");
+        let json: Value = serde_json::from_str(&record.json).unwrap();
+        assert_eq!(json, serde_json::json!({"action": "start"}));
+        assert!(rest.is_empty(), "messages are length delimited");
+    }
+}
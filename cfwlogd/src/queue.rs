@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The bounded queues between the device reader and the fanout thread, and between the fanout
+//! thread and each zone's `Logger`. What happens to an event that arrives at a full queue is up
+//! to the config's "queues.overflow" policy:
+//!
+//! - "block" waits for room, which holds up the sender. For the device reader that means the
+//!   device itself drops events once its ring fills up, and for the fanout thread it means one
+//!   slow zone holds up every other zone.
+//! - "drop-newest" drops the arriving event, the way the device reader always has.
+//! - "drop-oldest" drops the event that has been waiting the longest to make room for it.
+//!
+//! Every dropped event is handed back to the sender so it can be accounted for, and a
+//! `DropReport` keeps the log from being flooded with a line per dropped event.
+
+use crate::zones::Zonedid;
+use crossbeam::channel::{self, SendError, TrySendError};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often a `DropReport` logs the events dropped since its last report
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What to do with an event that arrives at a full queue
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    Block,
+    DropOldest,
+    DropNewest,
+}
+
+impl Default for Overflow {
+    fn default() -> Self {
+        Overflow::DropNewest
+    }
+}
+
+/// The sending half of a bounded queue
+pub struct Sender<T> {
+    tx: channel::Sender<T>,
+    /// Only kept when dropping the oldest events, to make room with
+    oldest: Option<channel::Receiver<T>>,
+    overflow: Overflow,
+    /// Set once the `Receiver` is dropped, since `oldest` keeps the channel itself connected
+    closed: Arc<AtomicBool>,
+}
+
+/// The receiving half of a bounded queue, which is used as a plain channel `Receiver`
+pub struct Receiver<T> {
+    rx: channel::Receiver<T>,
+    closed: Arc<AtomicBool>,
+}
+
+/// Create a queue holding up to `capacity` items, which has to be at least 1
+pub fn bounded<T>(capacity: usize, overflow: Overflow) -> (Sender<T>, Receiver<T>) {
+    let (tx, rx) = channel::bounded(capacity);
+    let closed = Arc::new(AtomicBool::new(false));
+    let oldest = match overflow {
+        Overflow::DropOldest => Some(rx.clone()),
+        _ => None,
+    };
+    (
+        Sender {
+            tx,
+            oldest,
+            overflow,
+            closed: Arc::clone(&closed),
+        },
+        Receiver { rx, closed },
+    )
+}
+
+impl<T> Sender<T> {
+    /// Queue `item`, handing every item dropped to make room for it, or `item` itself, to
+    /// `dropped`. An error is only returned once the `Receiver` is gone.
+    pub fn send<F: FnMut(T)>(&self, item: T, mut dropped: F) -> Result<(), SendError<T>> {
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(SendError(item));
+        }
+        let mut item = item;
+        loop {
+            let full = match self.overflow {
+                Overflow::Block => return self.tx.send(item),
+                _ => match self.tx.try_send(item) {
+                    Ok(()) => return Ok(()),
+                    Err(TrySendError::Disconnected(item)) => return Err(SendError(item)),
+                    Err(TrySendError::Full(item)) => item,
+                },
+            };
+            match &self.oldest {
+                Some(oldest) => {
+                    if let Ok(old) = oldest.try_recv() {
+                        dropped(old);
+                    }
+                    item = full;
+                }
+                None => {
+                    dropped(full);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Number of items waiting in the queue
+    pub fn queued(&self) -> usize {
+        self.tx.len()
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            tx: self.tx.clone(),
+            oldest: self.oldest.clone(),
+            overflow: self.overflow,
+            closed: Arc::clone(&self.closed),
+        }
+    }
+}
+
+impl<T> Deref for Receiver<T> {
+    type Target = channel::Receiver<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.rx
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Counts the events a queue dropped for each zone and periodically logs them
+pub struct DropReport {
+    /// Which queue this reports on
+    queue: &'static str,
+    dropped: BTreeMap<Zonedid, u64>,
+    last_report: Instant,
+}
+
+impl DropReport {
+    pub fn new(queue: &'static str) -> Self {
+        DropReport {
+            queue,
+            dropped: BTreeMap::new(),
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn dropped(&mut self, zonedid: Zonedid) {
+        *self.dropped.entry(zonedid).or_insert(0) += 1;
+    }
+
+    /// Log the events dropped since the last report, if it has been long enough
+    pub fn check(&mut self) {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+        for (zonedid, dropped) in std::mem::take(&mut self.dropped) {
+            warn!(
+                "the {} queue was full, dropped {} events for zonedid {}",
+                self.queue, dropped, zonedid
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fill(overflow: Overflow) -> (Receiver<u32>, Vec<u32>) {
+        let (tx, rx) = bounded(2, overflow);
+        let mut dropped = vec![];
+        for i in 0..4 {
+            tx.send(i, |item| dropped.push(item)).unwrap();
+        }
+        (rx, dropped)
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let (rx, dropped) = fill(Overflow::DropNewest);
+        assert_eq!(dropped, vec![2, 3]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![0, 1]);
+
+        let (rx, dropped) = fill(Overflow::DropOldest);
+        assert_eq!(dropped, vec![0, 1]);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![2, 3]);
+
+        let (tx, rx) = bounded(1, Overflow::Block);
+        let sender = std::thread::spawn(move || {
+            for i in 0..3 {
+                tx.send(i, |_| panic!("nothing is dropped")).unwrap();
+            }
+        });
+        let received: Vec<u32> = (0..3).map(|_| rx.recv().unwrap()).collect();
+        sender.join().unwrap();
+        assert_eq!(received, vec![0, 1, 2], "the sender waited for room");
+    }
+
+    #[test]
+    fn closed_queues() {
+        for overflow in &[Overflow::Block, Overflow::DropOldest, Overflow::DropNewest] {
+            let (tx, rx) = bounded(1, *overflow);
+            drop(rx);
+            assert!(
+                tx.send(1, |_| ()).is_err(),
+                "{:?} notices the receiver is gone",
+                overflow
+            );
+        }
+    }
+}
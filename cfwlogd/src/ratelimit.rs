@@ -0,0 +1,105 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Rate limiting each zone's events before they're queued for its `Logger`, so that a single zone
+//! flooding the device can't fill up the disk or crowd its neighbours out of the fanout thread.
+//! With a "rate_limit" configured every zone gets a token bucket that's refilled at
+//! "rate_limit.per_sec" events a second and holds up to "rate_limit.burst" of them. Events
+//! arriving at an empty bucket are dropped and tallied as suppressed, so the zone's log still gets
+//! a summary of how many were left out for each rule, see `stats::record_suppressed`.
+
+use crate::config::RateLimitConfig;
+use std::time::Instant;
+
+/// Admits events at a steady rate, with bursts of up to `burst` after a quiet period
+#[derive(Debug)]
+pub struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket
+    pub fn new(per_sec: u64, burst: u64, now: Instant) -> Self {
+        TokenBucket {
+            per_sec: per_sec as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled: now,
+        }
+    }
+
+    /// Take a token for an event, returning false if there are none left
+    pub fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A zone's rate limit
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    /// The limit the bucket was filled for
+    limit: Option<(RateLimitConfig, TokenBucket)>,
+}
+
+impl RateLimiter {
+    /// Whether an event arriving `now` is within the configured limit. A changed limit starts over
+    /// with a full bucket.
+    pub fn admit(&mut self, config: Option<&RateLimitConfig>, now: Instant) -> bool {
+        let config = match config {
+            Some(config) => config,
+            None => {
+                self.limit = None;
+                return true;
+            }
+        };
+        match &mut self.limit {
+            Some((limit, bucket)) if limit == config => bucket.take(now),
+            limit => {
+                let mut bucket = TokenBucket::new(config.per_sec, config.burst(), now);
+                let admitted = bucket.take(now);
+                *limit = Some((*config, bucket));
+                admitted
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::time::Duration;
+
+    #[test]
+    fn events_over_the_limit_are_refused() {
+        let start = Instant::now();
+        let config = Config::from_toml("[rate_limit]\nper_sec = 2\nburst = 3\n").unwrap();
+        let limit = config.rate_limit.as_ref();
+        let mut limiter = RateLimiter::default();
+        let admitted = (0..5).filter(|_| limiter.admit(limit, start)).count();
+        assert_eq!(admitted, 3, "the burst");
+        let later = start + Duration::from_millis(500);
+        assert!(limiter.admit(limit, later), "refilled at two a second");
+        assert!(!limiter.admit(limit, later));
+
+        let raised = Config::from_toml("[rate_limit]\nper_sec = 10\n").unwrap();
+        let raised = raised.rate_limit.as_ref();
+        let admitted = (0..20).filter(|_| limiter.admit(raised, later)).count();
+        assert_eq!(admitted, 10, "a changed limit starts with a full bucket");
+        assert!(limiter.admit(None, later), "no limit");
+    }
+}
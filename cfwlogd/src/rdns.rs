@@ -0,0 +1,59 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Annotating remote addresses with the hostname their PTR record points to, as an "enrich" module
+//! lookup. The nameservers are read from /etc/resolv.conf at startup since it's out of reach once
+//! we chroot, so changes to it need a restart. There's a resolver for every enricher thread since
+//! each one only runs a single query at a time. Addresses without a PTR record, or whose query
+//! fails or times out, are annotated without a hostname and so aren't asked about again until
+//! their annotation expires from the cache.
+
+use crate::config::ReverseDnsConfig;
+use crate::enrich::{Annotation, Lookup};
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use trust_dns_resolver::{system_conf, Resolver};
+
+pub struct ReverseDns {
+    resolvers: Vec<Resolver>,
+    next: AtomicUsize,
+}
+
+impl ReverseDns {
+    /// Read the system's resolver config and set up `count` resolvers. This must happen before we
+    /// chroot.
+    pub fn new(config: &ReverseDnsConfig, count: usize) -> io::Result<ReverseDns> {
+        let (resolver_config, mut opts) = system_conf::read_system_conf()?;
+        opts.timeout = Duration::from_millis(config.timeout_ms);
+        let resolvers = (0..count)
+            .map(|_| Resolver::new(resolver_config.clone(), opts))
+            .collect::<io::Result<_>>()?;
+        Ok(ReverseDns {
+            resolvers,
+            next: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Lookup for ReverseDns {
+    fn annotate(&self, addr: IpAddr, annotation: &mut Annotation) {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.resolvers.len();
+        match self.resolvers[i].reverse_lookup(addr) {
+            Ok(names) => {
+                annotation.remote_hostname = names
+                    .iter()
+                    .next()
+                    .map(|name| name.to_utf8().trim_end_matches('.').to_owned());
+            }
+            Err(e) => debug!("no hostname for {}: {}", addr, e),
+        }
+    }
+
+    /// /etc/resolv.conf can't be reread once we've chrooted
+    fn reload(&self) {}
+}
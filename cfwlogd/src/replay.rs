@@ -0,0 +1,374 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An EventSource that replays a file of raw ipfev records. This allows the whole pipeline to be
+//! exercised off-platform with real events, such as those behind a parser bug reported from
+//! production. Two kinds of files are replayed:
+//!
+//! - A capture written by `cfwlogd capture`, see the "capture" module. Its reads are replayed at
+//!   "source.speed" times the pace they were captured at, or as fast as the pipeline takes them
+//!   when no speed is configured.
+//! - Records laid out back to back just as the device hands them back from a read, which are
+//!   always replayed as fast as the pipeline takes them.
+//!
+//! Once every record has been replayed the reader stops.
+
+use crate::capture::{FRAME_HEADER_LEN, MAGIC};
+use crate::source::{EventSource, SourceStats};
+use crate::wire::TRAFFIC_EVENT_SIZE;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_EVENTS_PER_READ: usize = 1024;
+
+/// The event type, length and zonedid every record starts with
+const RECORD_HEADER_LEN: usize = 8;
+
+/// The length of the record `bytes` starts with, if it has a valid header
+fn record_len(bytes: &[u8]) -> Option<usize> {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let len = usize::from(u16::from_le_bytes([bytes[2], bytes[3]]));
+    if len < RECORD_HEADER_LEN {
+        return None;
+    }
+    Some(len)
+}
+
+/// Where a capture's replay is at
+struct Capture {
+    /// The bytes of the read being replayed
+    frame: Vec<u8>,
+    /// Offset of the next record into `frame`
+    pos: usize,
+    /// The timestamp and length of a frame that isn't due yet
+    next: Option<(i64, usize)>,
+    speed: Option<f64>,
+    /// The first frame's timestamp and when it was replayed, which later frames are paced by
+    start: Option<(i64, Instant)>,
+}
+
+impl Capture {
+    /// When a frame captured at `micros` is due to be replayed
+    fn due(&mut self, micros: i64) -> Instant {
+        let now = Instant::now();
+        let (first, at) = *self.start.get_or_insert((micros, now));
+        match self.speed {
+            Some(speed) => {
+                at + Duration::from_micros((micros - first).max(0) as u64).div_f64(speed)
+            }
+            None => now,
+        }
+    }
+}
+
+pub struct ReplaySource {
+    path: PathBuf,
+    file: BufReader<File>,
+    /// Set when the file is a capture rather than back to back records
+    capture: Option<Capture>,
+    /// A record that didn't fit in the last read
+    pending: Option<Vec<u8>>,
+    /// An error reading a record after the last read already had some, returned by the next one
+    error: Option<io::Error>,
+    /// Offset into the file of the next record, for error messages
+    offset: u64,
+    replayed: u64,
+}
+
+impl ReplaySource {
+    /// Replay the file at `path`, a capture's reads at `speed` times the pace they were captured at
+    pub fn new(path: &Path, speed: Option<f64>) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        let is_capture = match file.read_exact(&mut magic) {
+            Ok(()) => &magic == MAGIC,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+        let (capture, offset) = if is_capture {
+            let capture = Capture {
+                frame: vec![],
+                pos: 0,
+                next: None,
+                speed,
+                start: None,
+            };
+            (Some(capture), MAGIC.len() as u64)
+        } else {
+            if speed.is_some() {
+                warn!(
+                    "{} isn't a capture, replaying it without its original pace",
+                    path.display()
+                );
+            }
+            // Start over since the bytes read weren't a capture's header
+            file = BufReader::new(File::open(path)?);
+            (None, 0)
+        };
+        info!("replaying events from {}", path.display());
+        Ok(ReplaySource {
+            path: path.to_owned(),
+            file,
+            capture,
+            pending: None,
+            error: None,
+            offset,
+            replayed: 0,
+        })
+    }
+
+    fn invalid(&self, msg: &str) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} at offset {} of {}",
+                msg,
+                self.offset,
+                self.path.display()
+            ),
+        )
+    }
+
+    /// Fill `buf` from the file, returning false if the file ended before any of it was read
+    fn read_exact_or_eof(&mut self, buf: &mut [u8], what: &str) -> io::Result<bool> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.file.read(&mut buf[read..]) {
+                Ok(0) if read == 0 => return Ok(false),
+                Ok(0) => return Err(self.invalid(&format!("{} is truncated", what))),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read the next record, or nothing once the file has been replayed. A capture's next read is
+    /// only waited for if `wait` is set, otherwise nothing is returned until it's due.
+    fn next_record(&mut self, wait: bool) -> io::Result<Option<Vec<u8>>> {
+        if let Some(record) = self.pending.take() {
+            return Ok(Some(record));
+        }
+        match self.capture.take() {
+            Some(mut capture) => {
+                let record = self.next_captured_record(&mut capture, wait);
+                self.capture = Some(capture);
+                record
+            }
+            None => self.next_raw_record(),
+        }
+    }
+
+    fn next_raw_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut header = [0; RECORD_HEADER_LEN];
+        if !self.read_exact_or_eof(&mut header, "record")? {
+            return Ok(None);
+        }
+        let len = record_len(&header).ok_or_else(|| self.invalid("record is too short"))?;
+        let mut record = vec![0; len];
+        record[..RECORD_HEADER_LEN].copy_from_slice(&header);
+        if !self.read_exact_or_eof(&mut record[RECORD_HEADER_LEN..], "record")? {
+            return Err(self.invalid("record is truncated"));
+        }
+        Ok(Some(record))
+    }
+
+    fn next_captured_record(
+        &mut self,
+        capture: &mut Capture,
+        wait: bool,
+    ) -> io::Result<Option<Vec<u8>>> {
+        while capture.pos == capture.frame.len() {
+            let (micros, len) = match capture.next.take() {
+                Some(next) => next,
+                None => {
+                    let mut header = [0; FRAME_HEADER_LEN];
+                    if !self.read_exact_or_eof(&mut header, "frame")? {
+                        return Ok(None);
+                    }
+                    self.offset += FRAME_HEADER_LEN as u64;
+                    let micros = i64::from_le_bytes(header[..8].try_into().unwrap());
+                    let len = u32::from_le_bytes(header[8..].try_into().unwrap());
+                    (micros, len as usize)
+                }
+            };
+            let due = capture.due(micros);
+            let now = Instant::now();
+            if due > now {
+                if !wait {
+                    capture.next = Some((micros, len));
+                    return Ok(None);
+                }
+                thread::sleep(due - now);
+            }
+            capture.frame = vec![0; len];
+            capture.pos = 0;
+            if !self.read_exact_or_eof(&mut capture.frame, "frame")? {
+                return Err(self.invalid("frame is truncated"));
+            }
+        }
+        let rest = &capture.frame[capture.pos..];
+        let len = match record_len(rest) {
+            Some(len) if len <= rest.len() => len,
+            _ => return Err(self.invalid("frame ends in a partial record")),
+        };
+        capture.pos += len;
+        Ok(Some(rest[..len].to_vec()))
+    }
+}
+
+impl EventSource for ReplaySource {
+    /// Fill the buffer with as many whole records as fit, returning an `UnexpectedEof` error once
+    /// there are none left
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let mut size = 0;
+        for _ in 0..MAX_EVENTS_PER_READ {
+            let record = match self.next_record(size == 0) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(e) if size > 0 => {
+                    self.error = Some(e);
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
+            if size + record.len() > buf.len() {
+                if size == 0 {
+                    return Err(self.invalid("record is larger than the read buffer"));
+                }
+                self.pending = Some(record);
+                break;
+            }
+            buf[size..size + record.len()].copy_from_slice(&record);
+            size += record.len();
+            self.offset += record.len() as u64;
+            self.replayed += 1;
+        }
+        if size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "every event has been replayed",
+            ));
+        }
+        Ok(size)
+    }
+
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)> {
+        Ok((TRAFFIC_EVENT_SIZE, MAX_EVENTS_PER_READ))
+    }
+
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        Ok(SourceStats {
+            received: self.replayed,
+            dropped: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::CaptureWriter;
+    use cfwevent::parser;
+    use chrono::Utc;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new("/var/tmp/cfwlogd-tests/replay").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn replay_file() {
+        let dir = test_dir("raw");
+        let path = dir.join("events.bin");
+        let event = testutils::generate_event();
+        let bytes = event.as_bytes();
+        let mut contents = vec![];
+        for _ in 0..3 {
+            contents.extend_from_slice(bytes);
+        }
+        std::fs::write(&path, &contents).unwrap();
+
+        let mut source = ReplaySource::new(&path, None).unwrap();
+        // Room for two events, the third is held for the next read
+        let mut buf = vec![0; bytes.len() * 2 + 1];
+        assert_eq!(source.read_events(&mut buf).unwrap(), bytes.len() * 2);
+        let (rest, first) = parser::cfwevent_parse(&buf).unwrap();
+        assert_eq!(first.zone(), event.zonedid);
+        assert!(parser::cfwevent_parse(rest).is_ok());
+        assert_eq!(source.read_events(&mut buf).unwrap(), bytes.len());
+        let e = source.read_events(&mut buf).unwrap_err();
+        assert_eq!(
+            e.kind(),
+            io::ErrorKind::UnexpectedEof,
+            "the file was replayed"
+        );
+        assert_eq!(source.stats().unwrap().received, 3);
+
+        std::fs::write(&path, &contents[..bytes.len() + 10]).unwrap();
+        let mut source = ReplaySource::new(&path, None).unwrap();
+        assert_eq!(source.read_events(&mut buf).unwrap(), bytes.len());
+        let e = source.read_events(&mut buf).unwrap_err();
+        assert_eq!(
+            e.kind(),
+            io::ErrorKind::InvalidData,
+            "the record was truncated"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_capture() {
+        let dir = test_dir("capture");
+        let path = dir.join("events.cap");
+        let event = testutils::generate_event();
+        let bytes = event.as_bytes();
+        let two: Vec<u8> = bytes.iter().chain(bytes.iter()).copied().collect();
+        let at = Utc::now();
+        let mut writer = CaptureWriter::new(File::create(&path).unwrap()).unwrap();
+        writer.write_frame(at, &two).unwrap();
+        writer
+            .write_frame(at + chrono::Duration::seconds(2), bytes)
+            .unwrap();
+        drop(writer);
+
+        let mut buf = vec![0; bytes.len() * 4];
+        let mut source = ReplaySource::new(&path, None).unwrap();
+        assert_eq!(
+            source.read_events(&mut buf).unwrap(),
+            bytes.len() * 3,
+            "without a speed every read is due right away"
+        );
+
+        let mut source = ReplaySource::new(&path, Some(40.0)).unwrap();
+        let started = Instant::now();
+        assert_eq!(
+            source.read_events(&mut buf).unwrap(),
+            bytes.len() * 2,
+            "the second read isn't due yet"
+        );
+        assert_eq!(source.read_events(&mut buf).unwrap(), bytes.len());
+        assert!(
+            started.elapsed() >= Duration::from_millis(50),
+            "the second read was replayed at 40 times its pace"
+        );
+        let e = source.read_events(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
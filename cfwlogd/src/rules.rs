@@ -0,0 +1,51 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! What we know about the cloud firewall rules that events are logged for. Events only carry a
+//! rule's uuid, so when cfwlogd is built with the "fwapi" feature the mapping is periodically
+//! synced from FWAPI (see the "fwapi" module), or from fwadm's rule files on the CN (see the
+//! "fwadm" module), and used to attribute records and each zone's per-rule counts to the user that
+//! owns the rule and to say what the rule is for. Without either the mapping simply stays empty.
+
+use crossbeam::sync::ShardedLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Holds a rw lock protected mapping of rule uuid to the rule's attribution
+pub type Rules = Arc<ShardedLock<HashMap<Uuid, RuleOwner>>>;
+
+/// Attribution of a rule, serialized alongside records and per-rule counts
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RuleOwner {
+    /// The user owning the rule, global rules don't have one
+    #[serde(rename = "rule_owner", skip_serializing_if = "Option::is_none")]
+    pub owner_uuid: Option<Uuid>,
+    #[serde(rename = "rule_description", skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+
+/// The parts of a rule object we care about, which FWAPI and fwadm's rule files have in common
+#[derive(Debug, Deserialize)]
+pub struct RuleObject {
+    pub uuid: Uuid,
+    owner_uuid: Option<Uuid>,
+    description: Option<String>,
+}
+
+impl RuleObject {
+    /// The rule's uuid along with its attribution
+    pub fn into_entry(self) -> (Uuid, RuleOwner) {
+        (
+            self.uuid,
+            RuleOwner {
+                owner_uuid: self.owner_uuid,
+                description: self.description,
+            },
+        )
+    }
+}
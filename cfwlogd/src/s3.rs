@@ -0,0 +1,234 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Uploading rotated logs to an S3-compatible object store, with the "upload" module deciding what
+//! is uploaded when. Objects are addressed by path, as "<endpoint>/<bucket>/<key>", which AWS and
+//! the S3-compatible stores alike take, and requests are signed with AWS Signature Version 4, see
+//! the "sigv4" module, leaving the payload unsigned since it's sent over TLS.
+//!
+//! A file of at least "multipart_threshold_bytes" is sent in parts of "part_bytes" with a
+//! multipart upload, more of them when it would take over `MAX_PARTS`. If any part fails the
+//! upload is aborted, so that the store doesn't hold on to its parts, and the whole file is sent
+//! again when it's retried. Like the "http" module the endpoint's address is resolved once at
+//! startup, and the credentials are read then too.
+
+use crate::config::S3Config;
+use crate::http;
+use crate::sigv4::{self, Credentials};
+use crate::upload::Store;
+use chrono::Utc;
+use std::fs::File;
+use std::io::{self, Read};
+
+/// How long to wait on the store before giving up on a request
+const REQUEST_TIMEOUT_MS: u64 = 60_000;
+/// The most parts a multipart upload can have
+const MAX_PARTS: u64 = 10_000;
+
+const SERVICE: &str = "s3";
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+fn error(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+/// The sizes of the parts a file of `size` bytes is sent in
+fn part_sizes(size: u64, part_bytes: u64) -> Vec<u64> {
+    let part_bytes = part_bytes.max((size + MAX_PARTS - 1) / MAX_PARTS);
+    let mut sizes = vec![part_bytes; (size / part_bytes) as usize];
+    if size % part_bytes != 0 {
+        sizes.push(size % part_bytes);
+    }
+    sizes
+}
+
+/// The text of the first `name` element of a response, which is all we need out of S3's XML
+fn element<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(&xml[start..end])
+}
+
+pub struct S3 {
+    agent: ureq::Agent,
+    /// "https://" and the endpoint's host
+    url: String,
+    host: String,
+    region: String,
+    bucket: String,
+    credentials: Credentials,
+    multipart_threshold: u64,
+    part_bytes: u64,
+}
+
+impl S3 {
+    /// Load the credentials and resolve the endpoint's address, which has to happen before we
+    /// chroot
+    pub fn new(config: &S3Config) -> io::Result<S3> {
+        let host = config
+            .endpoint
+            .trim_start_matches("https://")
+            .split('/')
+            .next()
+            .unwrap_or("")
+            .to_owned();
+        Ok(S3 {
+            agent: http::pinned_agent(&config.endpoint)?,
+            url: format!("https://{}", host),
+            host,
+            region: config.region.clone(),
+            bucket: config.bucket.clone(),
+            credentials: Credentials::load(
+                &config.access_key_id,
+                &config.secret_access_key,
+                &config.session_token,
+            )?,
+            multipart_threshold: config.multipart_threshold_bytes,
+            part_bytes: config.part_bytes,
+        })
+    }
+
+    /// The template of the keys files are uploaded to, see `upload::Settings`
+    pub fn path(config: &S3Config) -> String {
+        format!("{}{{file}}", config.prefix)
+    }
+
+    /// A signed request for the object at `key`
+    fn request(&self, method: &str, key: &str, query: &[(&str, &str)]) -> ureq::Request {
+        let now = Utc::now();
+        let path = format!(
+            "/{}/{}",
+            sigv4::uri_encode(&self.bucket, true),
+            sigv4::uri_encode(key, true)
+        );
+        let mut headers = vec![
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_owned()),
+            ("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string()),
+        ];
+        if let Some(token) = &self.credentials.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        let signed = sigv4::Request {
+            method,
+            path: &path,
+            query,
+            headers: &headers,
+            payload_hash: UNSIGNED_PAYLOAD,
+        };
+        let authorization =
+            sigv4::authorization(&self.credentials, &self.region, SERVICE, &signed, &now);
+        let query: Vec<String> = query
+            .iter()
+            .map(|(name, value)| {
+                let value = sigv4::uri_encode(value, false);
+                format!("{}={}", sigv4::uri_encode(name, false), value)
+            })
+            .collect();
+        let mut url = format!("{}{}", self.url, path);
+        if !query.is_empty() {
+            url.push('?');
+            url.push_str(&query.join("&"));
+        }
+        let mut request = self.agent.request(method, &url);
+        // ureq sets the host header itself, from the url
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            request.set(name, value);
+        }
+        request
+            .set("Authorization", &authorization)
+            .timeout_connect(REQUEST_TIMEOUT_MS)
+            .timeout_read(REQUEST_TIMEOUT_MS);
+        request
+    }
+
+    /// Send a file to `key` with a multipart upload, aborting it if it fails
+    fn put_in_parts(&self, file: File, size: u64, key: &str) -> io::Result<()> {
+        let resp = http::check_response(self.request("POST", key, &[("uploads", "")]).call())?;
+        let body = resp.into_string()?;
+        let upload_id = element(&body, "UploadId")
+            .ok_or_else(|| error(format!("no UploadId for {} in {}", key, body)))?
+            .to_owned();
+        let result = self.put_parts(file, size, key, &upload_id);
+        if result.is_err() {
+            let abort = self
+                .request("DELETE", key, &[("uploadId", &upload_id)])
+                .call();
+            if let Err(e) = http::check_response(abort) {
+                warn!("failed to abort the multipart upload of {}: {}", key, e);
+            }
+        }
+        result
+    }
+
+    /// Send each part of a file and complete its multipart upload
+    fn put_parts(&self, file: File, size: u64, key: &str, upload_id: &str) -> io::Result<()> {
+        let mut parts = String::from("<CompleteMultipartUpload>");
+        for (i, part_size) in part_sizes(size, self.part_bytes).into_iter().enumerate() {
+            let number = (i + 1).to_string();
+            let query = [("partNumber", number.as_str()), ("uploadId", upload_id)];
+            let mut request = self.request("PUT", key, &query);
+            request.set("Content-Length", &part_size.to_string());
+            // The clone shares the file's offset, so each part picks up where the last left off
+            let part = file.try_clone()?.take(part_size);
+            let resp = http::check_response(request.send(part))?;
+            let etag = resp
+                .header("ETag")
+                .ok_or_else(|| error(format!("no ETag for part {} of {}", number, key)))?;
+            parts.push_str(&format!(
+                "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                number, etag
+            ));
+        }
+        parts.push_str("</CompleteMultipartUpload>");
+        let request = self
+            .request("POST", key, &[("uploadId", upload_id)])
+            .send_string(&parts);
+        // Completing an upload can still fail once the store has responded with 200
+        let body = http::check_response(request)?.into_string()?;
+        match element(&body, "Code") {
+            Some(code) => Err(error(format!("failed to complete {}: {}", key, code))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Store for S3 {
+    fn put(&mut self, file: File, size: u64, remote: &str) -> io::Result<()> {
+        if size >= self.multipart_threshold {
+            return self.put_in_parts(file, size, remote);
+        }
+        let mut request = self.request("PUT", remote, &[]);
+        request.set("Content-Length", &size.to_string());
+        http::check_response(request.send(file)).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn large_files_are_sent_in_parts() {
+        let mib = 1024 * 1024;
+        assert_eq!(part_sizes(64 * mib, 16 * mib), vec![16 * mib; 4]);
+        assert_eq!(
+            part_sizes(40 * mib + 1, 16 * mib),
+            vec![16 * mib, 16 * mib, 8 * mib + 1]
+        );
+        let sizes = part_sizes(MAX_PARTS * 20 * mib + 1, 16 * mib);
+        assert!(sizes.len() as u64 <= MAX_PARTS, "at most 10,000 parts");
+        assert_eq!(sizes.iter().sum::<u64>(), MAX_PARTS * 20 * mib + 1);
+
+        let created = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<InitiateMultipartUploadResult>\
+                       <Bucket>logs</Bucket><Key>a.log</Key><UploadId>VXBsb2FkIElE</UploadId>\
+                       </InitiateMultipartUploadResult>";
+        assert_eq!(element(created, "UploadId"), Some("VXBsb2FkIElE"));
+        assert_eq!(element(created, "Code"), None);
+        assert_eq!(element("<Code>InternalError", "Code"), None);
+    }
+}
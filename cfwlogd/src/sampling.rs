@@ -0,0 +1,221 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Sampling the traffic events of extremely hot rules or zones, from the config's "sampling"
+//! entries, rather than logging every one of them. An entry either logs one event out of every
+//! "one_in", or at most "per_sec" events a second with bursts of up to "burst" after a quiet
+//! period. Every record that is logged for a sampled rule or zone gets a "sampled" field with the
+//! number of events it stands for, itself and the ones left out since the previous record, so that
+//! counts can be rescaled downstream. An entry for a rule takes precedence over one for the zone,
+//! and each zone's `Logger` samples its own events.
+//!
+//! An entry with a "direction" only samples the events in that direction, so a zone's outbound
+//! traffic can be sampled differently from its inbound traffic. It takes precedence over an entry
+//! for the same rule or zone without one, which samples the events in the other direction.
+
+use crate::config::SamplingConfig;
+use crate::ratelimit::TokenBucket;
+use cfwevent::parser::{Direction, TrafficEvent};
+use std::collections::HashMap;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// What to do with an event
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    /// Nothing samples the event's rule or zone
+    Unsampled,
+    /// Log the event as standing for this many events
+    Keep(u64),
+    /// Leave the event out
+    Skip,
+}
+
+#[derive(Debug)]
+enum Rate {
+    OneIn(u64),
+    PerSec(TokenBucket),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Rate,
+    /// Events left out since the last one that was kept
+    skipped: u64,
+}
+
+impl Bucket {
+    fn new(config: &SamplingConfig, now: Instant) -> Self {
+        let rate = match (config.one_in, config.per_sec) {
+            (Some(one_in), _) => Rate::OneIn(one_in),
+            (None, per_sec) => {
+                let per_sec = per_sec.expect("validated to have a rate");
+                Rate::PerSec(TokenBucket::new(
+                    per_sec,
+                    config.burst.unwrap_or(per_sec),
+                    now,
+                ))
+            }
+        };
+        Bucket { rate, skipped: 0 }
+    }
+
+    fn sample(&mut self, now: Instant) -> Decision {
+        let keep = match &mut self.rate {
+            Rate::OneIn(one_in) => self.skipped + 1 >= *one_in,
+            Rate::PerSec(bucket) => bucket.take(now),
+        };
+        if keep {
+            let events = self.skipped + 1;
+            self.skipped = 0;
+            Decision::Keep(events)
+        } else {
+            self.skipped += 1;
+            Decision::Skip
+        }
+    }
+}
+
+/// A zone's sampling state
+#[derive(Debug)]
+pub struct Sampler {
+    /// Rules sampled by their own entries, for their events in one direction or either
+    rules: HashMap<(Uuid, Option<Direction>), Bucket>,
+    /// The zone's entries, which sample its other rules
+    zone: HashMap<Option<Direction>, Bucket>,
+}
+
+impl Sampler {
+    /// The sampler for the zone, if any of the entries apply to it. Entries for rules apply to
+    /// every zone.
+    pub fn new(config: &[SamplingConfig], vm: &str, now: Instant) -> Option<Sampler> {
+        let vm = Uuid::parse_str(vm).ok();
+        let mut sampler = Sampler {
+            rules: HashMap::new(),
+            zone: HashMap::new(),
+        };
+        for entry in config {
+            match (entry.rule, entry.vm) {
+                (Some(rule), _) => {
+                    let key = (rule, entry.direction.clone());
+                    sampler.rules.insert(key, Bucket::new(entry, now));
+                }
+                (None, Some(entry_vm)) if Some(entry_vm) == vm => {
+                    let key = entry.direction.clone();
+                    sampler.zone.insert(key, Bucket::new(entry, now));
+                }
+                _ => (),
+            }
+        }
+        if sampler.rules.is_empty() && sampler.zone.is_empty() {
+            None
+        } else {
+            Some(sampler)
+        }
+    }
+
+    pub fn sample(&mut self, event: &TrafficEvent, now: Instant) -> Decision {
+        let directed = (event.rule_uuid, Some(event.direction.clone()));
+        let either = (event.rule_uuid, None);
+        let bucket = if self.rules.contains_key(&directed) {
+            self.rules.get_mut(&directed)
+        } else if self.rules.contains_key(&either) {
+            self.rules.get_mut(&either)
+        } else if self.zone.contains_key(&directed.1) {
+            self.zone.get_mut(&directed.1)
+        } else {
+            self.zone.get_mut(&None)
+        };
+        match bucket {
+            Some(bucket) => bucket.sample(now),
+            None => Decision::Unsampled,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use cfwevent::parser::CfwEvType;
+    use std::time::Duration;
+    use testutils::{traffic_event, RULE};
+
+    const VM: &str = "2b3aa5a4-8a18-4c8a-a8a3-3b9e42f0f00d";
+
+    fn sampler(config: &str, vm: &str, now: Instant) -> Option<Sampler> {
+        Sampler::new(&Config::from_toml(config).unwrap().sampling, vm, now)
+    }
+
+    #[test]
+    fn one_in_n() {
+        let now = Instant::now();
+        let config = format!(
+            "[[sampling]]\nrule = \"{}\"\none_in = 3\n[[sampling]]\nvm = \"{}\"\none_in = 2\n",
+            RULE, VM
+        );
+        assert!(
+            sampler(&config, "0f2d6d34-9f35-4a92-8bd2-e3a8a1e2bf5c", now).is_some(),
+            "rule entries apply to every zone"
+        );
+        let mut sampler = sampler(&config, VM, now).expect("the zone is sampled");
+        let event = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        let decisions: Vec<Decision> = (0..6).map(|_| sampler.sample(&event, now)).collect();
+        use Decision::*;
+        assert_eq!(decisions, vec![Skip, Skip, Keep(3), Skip, Skip, Keep(3)]);
+        let other_rule = "0f8c2972-0a38-4e39-9f76-46b5738ed404";
+        let other = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, other_rule);
+        assert_eq!(sampler.sample(&other, now), Skip, "the zone's entry");
+        assert_eq!(sampler.sample(&other, now), Keep(2));
+
+        let config = format!("[[sampling]]\nrule = \"{}\"\none_in = 3\n", RULE);
+        let mut sampler = self::sampler(&config, VM, now).unwrap();
+        assert_eq!(sampler.sample(&other, now), Unsampled);
+    }
+
+    #[test]
+    fn directions() {
+        let now = Instant::now();
+        let config = format!(
+            "[[sampling]]\nvm = \"{}\"\none_in = 2\n[[sampling]]\nvm = \"{}\"\n\
+             direction = \"out\"\none_in = 3\n",
+            VM, VM
+        );
+        let mut sampler = sampler(&config, VM, now).unwrap();
+        let mut outbound = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        outbound.direction = Direction::Out;
+        let mut inbound = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        inbound.direction = Direction::In;
+        use Decision::*;
+        let decisions: Vec<Decision> = (0..3).map(|_| sampler.sample(&outbound, now)).collect();
+        assert_eq!(decisions, vec![Skip, Skip, Keep(3)], "the outbound entry");
+        assert_eq!(
+            sampler.sample(&inbound, now),
+            Skip,
+            "the zone's other entry"
+        );
+        assert_eq!(sampler.sample(&inbound, now), Keep(2));
+    }
+
+    #[test]
+    fn per_sec() {
+        let start = Instant::now();
+        let config = format!("[[sampling]]\nvm = \"{}\"\nper_sec = 2\nburst = 3\n", VM);
+        let mut sampler = sampler(&config, VM, start).unwrap();
+        let event = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        let mut sample = |now| sampler.sample(&event, now);
+        use Decision::*;
+        assert_eq!(sample(start), Keep(1), "the burst");
+        assert_eq!(sample(start), Keep(1));
+        assert_eq!(sample(start), Keep(1));
+        assert_eq!(sample(start), Skip);
+        assert_eq!(sample(start), Skip);
+        let later = start + Duration::from_millis(500);
+        assert_eq!(sample(later), Keep(3), "refilled at two a second");
+        assert_eq!(sample(later), Skip);
+        assert_eq!(sample(start + Duration::from_secs(60)), Keep(2));
+    }
+}
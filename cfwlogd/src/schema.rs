@@ -0,0 +1,517 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd schema` prints the schema of the records cfwlogd writes, so consumers can generate
+//! parsers for the cfwlogd they're reading from without a copy of its source:
+//!
+//! - `--json-schema` prints a JSON Schema (draft 7) of a json record. Every record has a
+//!   "schema_version", "event" and "vm", and traffic records have the rest of their fields too.
+//! - `--avro` prints an Avro record schema of traffic records.
+//! - `--proto` prints the protobuf definition of the records written by sinks formatted as
+//!   "protobuf", whether or not this build has the protobuf feature.
+//!
+//! The schemas are of the current `SCHEMA_VERSION`, which they are named after. The fields come
+//! from `FIELDS`, and the values of the enumerated fields from the parser's types, and the tests
+//! check `FIELDS` against what a `Record` with every optional field set serializes to.
+
+use crate::sink::SCHEMA_VERSION;
+use cfwevent::parser::{CfwEvType, Direction, Protocol};
+use serde_json::{json, Map, Value};
+
+/// The definition of the `Record` message, see the "protobuf" module
+pub const RECORD_PROTO: &str = include_str!("../proto/record.proto");
+
+/// What a record field holds
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    /// A non-negative number
+    Unsigned,
+    /// A number that can be negative
+    Signed,
+    String,
+    Uuid,
+    /// An IPv4 or IPv6 address, with the zone of a scoped IPv6 address
+    Ip,
+    /// RFC 3339 text, or a number with an epoch "timestamp_format"
+    Timestamp,
+    /// The name of a `CfwEvType`
+    Event,
+    Protocol,
+    Direction,
+}
+
+impl Kind {
+    /// The values of an enumerated field, as they're serialized
+    fn names(self) -> Option<Vec<String>> {
+        match self {
+            Kind::Event => Some(event_names()),
+            Kind::Protocol => {
+                let mut protocols: Vec<Protocol> = (0..=255u8).map(Protocol::from).collect();
+                protocols.sort_by_key(|protocol| format!("{:?}", protocol));
+                Some(serialized_names(&protocols))
+            }
+            Kind::Direction => Some(serialized_names(&[Direction::In, Direction::Out])),
+            _ => None,
+        }
+    }
+}
+
+/// A field of a traffic record
+struct Field {
+    name: &'static str,
+    kind: Kind,
+    /// Whether every traffic record has the field, rather than only some configurations
+    required: bool,
+    doc: &'static str,
+}
+
+fn event_names() -> Vec<String> {
+    [CfwEvType::Block, CfwEvType::Begin, CfwEvType::End]
+        .iter()
+        .map(|event| event.name().to_owned())
+        .collect()
+}
+
+/// The names a type serializes as
+fn serialized_names<T: serde::Serialize>(values: &[T]) -> Vec<String> {
+    let mut names: Vec<String> = values
+        .iter()
+        .filter_map(|value| {
+            serde_json::to_value(value)
+                .ok()?
+                .as_str()
+                .map(str::to_owned)
+        })
+        .collect();
+    names.dedup();
+    names
+}
+
+/// The fields of a traffic record, in the order they're written
+const FIELDS: &[Field] = &[
+    Field {
+        name: "schema_version",
+        kind: Kind::Unsigned,
+        required: true,
+        doc: "The record schema version, see the README",
+    },
+    Field {
+        name: "event",
+        kind: Kind::Event,
+        required: true,
+        doc: "What happened to the packet",
+    },
+    Field {
+        name: "source_port",
+        kind: Kind::Unsigned,
+        required: true,
+        doc: "The ICMP type for ICMP and ICMPv6",
+    },
+    Field {
+        name: "destination_port",
+        kind: Kind::Unsigned,
+        required: true,
+        doc: "The ICMP code for ICMP and ICMPv6",
+    },
+    Field {
+        name: "protocol",
+        kind: Kind::Protocol,
+        required: true,
+        doc: "The packet's protocol",
+    },
+    Field {
+        name: "icmp_type",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "Only ICMP and ICMPv6, from version 2",
+    },
+    Field {
+        name: "icmp_code",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "Only ICMP and ICMPv6, from version 2",
+    },
+    Field {
+        name: "direction",
+        kind: Kind::Direction,
+        required: true,
+        doc: "Relative to the vm",
+    },
+    Field {
+        name: "source_ip",
+        kind: Kind::Ip,
+        required: true,
+        doc: "The packet's source address",
+    },
+    Field {
+        name: "destination_ip",
+        kind: Kind::Ip,
+        required: true,
+        doc: "The packet's destination address",
+    },
+    Field {
+        name: "timestamp",
+        kind: Kind::Timestamp,
+        required: true,
+        doc: "When the packet was seen",
+    },
+    Field {
+        name: "rule",
+        kind: Kind::Uuid,
+        required: true,
+        doc: "The uuid of the rule that matched",
+    },
+    Field {
+        name: "vm",
+        kind: Kind::Uuid,
+        required: true,
+        doc: "The vm the record was logged for",
+    },
+    Field {
+        name: "alias",
+        kind: Kind::String,
+        required: true,
+        doc: "The vm's alias",
+    },
+    Field {
+        name: "rule_owner",
+        kind: Kind::Uuid,
+        required: false,
+        doc: "The rule's owner, from FWAPI or fwadm",
+    },
+    Field {
+        name: "rule_description",
+        kind: Kind::String,
+        required: false,
+        doc: "The rule's description",
+    },
+    Field {
+        name: "remote_country",
+        kind: Kind::String,
+        required: false,
+        doc: "ISO 3166-1 code of the remote address",
+    },
+    Field {
+        name: "remote_asn",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "The remote address's autonomous system",
+    },
+    Field {
+        name: "remote_as_org",
+        kind: Kind::String,
+        required: false,
+        doc: "The autonomous system's organization",
+    },
+    Field {
+        name: "remote_hostname",
+        kind: Kind::String,
+        required: false,
+        doc: "The remote address's PTR record",
+    },
+    Field {
+        name: "sampled",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "Events the record stands for when sampled",
+    },
+    Field {
+        name: "repeats",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "Identical events a run of them ended with",
+    },
+    Field {
+        name: "epoch_ms",
+        kind: Kind::Signed,
+        required: false,
+        doc: "The timestamp in milliseconds since the epoch",
+    },
+    Field {
+        name: "epoch_ns",
+        kind: Kind::Signed,
+        required: false,
+        doc: "The timestamp in nanoseconds since the epoch",
+    },
+    Field {
+        name: "received_timestamp",
+        kind: Kind::String,
+        required: false,
+        doc: "When cfwlogd read the event, RFC 3339",
+    },
+    Field {
+        name: "received_hrtime",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "The high resolution clock when read",
+    },
+    Field {
+        name: "lag_ms",
+        kind: Kind::Signed,
+        required: false,
+        doc: "Milliseconds from the timestamp until it was read",
+    },
+    Field {
+        name: "server_uuid",
+        kind: Kind::Uuid,
+        required: false,
+        doc: "The CN the record was logged on",
+    },
+    Field {
+        name: "server_hostname",
+        kind: Kind::String,
+        required: false,
+        doc: "The CN's hostname",
+    },
+    Field {
+        name: "datacenter",
+        kind: Kind::String,
+        required: false,
+        doc: "The CN's datacenter",
+    },
+    Field {
+        name: "interface",
+        kind: Kind::String,
+        required: false,
+        doc: "The zone's interface the event was seen on",
+    },
+    Field {
+        name: "vlan_id",
+        kind: Kind::Unsigned,
+        required: false,
+        doc: "The VLAN of the interface",
+    },
+    Field {
+        name: "nic_tag",
+        kind: Kind::String,
+        required: false,
+        doc: "The nic tag of the interface",
+    },
+];
+
+/// The fields every record has, whatever its "event"
+const COMMON_FIELDS: &[&str] = &["schema_version", "event", "vm"];
+
+fn json_schema_type(field: &Field) -> Value {
+    let mut schema = match field.kind {
+        Kind::Unsigned => json!({"type": "integer", "minimum": 0}),
+        Kind::Signed => json!({"type": "integer"}),
+        Kind::String | Kind::Ip => json!({"type": "string"}),
+        Kind::Uuid => json!({"type": "string", "format": "uuid"}),
+        Kind::Timestamp => json!({"type": ["string", "integer"]}),
+        // Other records have an "event" of their own, which traffic records are told apart by
+        Kind::Event => json!({"type": "string"}),
+        Kind::Protocol | Kind::Direction => json!({"type": "string", "enum": field.kind.names()}),
+    };
+    if field.name == "schema_version" {
+        schema["const"] = Value::from(SCHEMA_VERSION);
+    }
+    schema["description"] = Value::from(field.doc);
+    schema
+}
+
+/// The JSON Schema of a json record
+fn json_schema() -> Value {
+    let properties: Map<String, Value> = FIELDS
+        .iter()
+        .map(|field| (field.name.to_owned(), json_schema_type(field)))
+        .collect();
+    let required: Vec<&str> = FIELDS
+        .iter()
+        .filter(|field| field.required)
+        .map(|field| field.name)
+        .collect();
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("cfwlogd record, schema version {}", SCHEMA_VERSION),
+        "type": "object",
+        "properties": properties,
+        "required": COMMON_FIELDS,
+        "if": {"properties": {"event": {"enum": event_names()}}},
+        "then": {"required": required},
+    })
+}
+
+fn avro_type(field: &Field) -> Value {
+    let avro = match field.kind {
+        Kind::Unsigned | Kind::Signed => json!("long"),
+        Kind::String | Kind::Ip => json!("string"),
+        Kind::Uuid => json!({"type": "string", "logicalType": "uuid"}),
+        Kind::Timestamp => json!(["string", "long"]),
+        Kind::Event | Kind::Protocol | Kind::Direction => json!({
+            "type": "enum",
+            "name": format!("{:?}", field.kind),
+            "symbols": field.kind.names(),
+        }),
+    };
+    if field.required {
+        return avro;
+    }
+    match avro {
+        Value::Array(mut types) => {
+            types.insert(0, json!("null"));
+            Value::Array(types)
+        }
+        avro => json!(["null", avro]),
+    }
+}
+
+/// The Avro schema of a traffic record
+fn avro_schema() -> Value {
+    let fields: Vec<Value> = FIELDS
+        .iter()
+        .map(|field| {
+            let mut avro = json!({
+                "name": field.name,
+                "type": avro_type(field),
+                "doc": field.doc,
+            });
+            if !field.required {
+                avro["default"] = Value::Null;
+            }
+            avro
+        })
+        .collect();
+    json!({
+        "type": "record",
+        "name": "Record",
+        "namespace": format!("cfwlogd.record.v{}", SCHEMA_VERSION),
+        "doc": format!("A cfwlogd traffic record, schema version {}", SCHEMA_VERSION),
+        "fields": fields,
+    })
+}
+
+fn print_json(schema: &Value) -> i32 {
+    match serde_json::to_string_pretty(schema) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        }
+        Err(e) => {
+            eprintln!("failed to serialize schema: {}", e);
+            1
+        }
+    }
+}
+
+/// Run the "schema" subcommand, returning the process's exit code.
+pub fn run(args: &[String]) -> i32 {
+    match args {
+        [flag] if flag == "--proto" => {
+            print!("{}", RECORD_PROTO);
+            0
+        }
+        [flag] if flag == "--json-schema" => print_json(&json_schema()),
+        [flag] if flag == "--avro" => print_json(&avro_schema()),
+        _ => {
+            eprintln!("usage: cfwlogd schema --json-schema | --avro | --proto");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enrich::Annotation;
+    use crate::nic::NicContext;
+    use crate::node::NodeIdentity;
+    use crate::rules::RuleOwner;
+    use crate::sink::{Epoch, ReceiveTimes, Record};
+    use cfwevent::parser::{self, CfwEvent, Icmp};
+
+    #[test]
+    fn fields_match_records() {
+        let zone = testutils::create_zone();
+        let event = testutils::generate_event_for_zone(&zone);
+        let mut event = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        if let CfwEvent::Traffic(traffic) = &mut event {
+            traffic.icmp = Some(Icmp {
+                icmp_type: 8,
+                icmp_code: 0,
+            });
+        }
+        let owner = RuleOwner {
+            owner_uuid: Some(zone.owner_uuid.parse().unwrap()),
+            description: Some("allow ssh".to_owned()),
+        };
+        let annotation = Annotation {
+            remote_country: Some("CA".to_owned()),
+            remote_asn: Some(64496),
+            remote_as_org: Some("Example".to_owned()),
+            remote_hostname: Some("host.example.com".to_owned()),
+        };
+        let node = NodeIdentity {
+            server_uuid: Some(zone.uuid.clone()),
+            hostname: Some("cn1".to_owned()),
+            datacenter: Some("us-east-1".to_owned()),
+        };
+        let nic = NicContext {
+            interface: Some("net0".to_owned()),
+            vlan_id: Some(20),
+            nic_tag: Some("external".to_owned()),
+        };
+        let record = Record {
+            rule_owner: Some(&owner),
+            annotation: Some(&annotation),
+            sampled: Some(10),
+            repeats: Some(2),
+            epoch: Some(Epoch::Millis(1)),
+            received: Some(ReceiveTimes {
+                received_timestamp: chrono::Utc::now(),
+                received_hrtime: 1,
+                lag_ms: -1,
+            }),
+            node: Some(&node),
+            nic: Some(&nic),
+            ..Record::new(event, &zone.uuid, zone.alias.as_deref().unwrap_or(""))
+        };
+        let mut fields = serde_json::to_value(&record).unwrap();
+        let nanos = Record {
+            epoch: Some(Epoch::Nanos(1)),
+            ..record
+        };
+        let nanos = serde_json::to_value(&nanos).unwrap();
+        fields["epoch_ns"] = nanos["epoch_ns"].clone();
+
+        let fields = fields.as_object().unwrap();
+        for field in FIELDS {
+            assert!(fields.contains_key(field.name), "{} is logged", field.name);
+        }
+        for name in fields.keys() {
+            assert!(
+                FIELDS.iter().any(|field| field.name == name),
+                "{} is in the schema",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn schemas_are_versioned() {
+        let schema = json_schema();
+        assert_eq!(
+            schema["properties"]["schema_version"]["const"],
+            SCHEMA_VERSION
+        );
+        assert_eq!(schema["then"]["required"].as_array().unwrap().len(), 12);
+        let protocols = &schema["properties"]["protocol"]["enum"];
+        assert!(protocols.as_array().unwrap().contains(&json!("ICMPV6")));
+
+        let avro = avro_schema();
+        assert_eq!(
+            avro["namespace"],
+            format!("cfwlogd.record.v{}", SCHEMA_VERSION)
+        );
+        let fields = avro["fields"].as_array().unwrap();
+        assert_eq!(fields.len(), FIELDS.len());
+        assert_eq!(
+            fields[5]["type"],
+            json!(["null", "long"]),
+            "icmp_type is optional"
+        );
+    }
+}
@@ -0,0 +1,343 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An end-to-end check of the pipeline, for monitoring that needs to know events make it all the
+//! way to every sink and not only that cfwlogd is running. The admin socket's "selftest" command,
+//! which `cfwlogd --selftest` sends, injects a synthetic traffic event for one of the zones into
+//! the queue the event readers fill, just as if it had been read off the device, and waits up to
+//! `TIMEOUT` for it to reach each stage: the fanout thread handing it to the zone's `Logger`, the
+//! `Logger` taking it off its queue, and each of the zone's sinks writing it.
+//!
+//! The event is logged and counted like any other, with the rule `SELFTEST_RULE`, which is how
+//! consumers downstream can tell test records apart. It's never left out by the zone's rate
+//! limit, "log_actions", the drop or sink filters, "count_only", sampling, a disk budget or a
+//! pause, so that a zone's config or state can't fail the test. The admin socket answers one
+//! client at a time, so only one test is ever running.
+
+use crate::audit::LossAudit;
+use crate::clock;
+use crate::inflight::InFlight;
+use crate::memory::MemoryTracker;
+use crate::queue;
+use crate::zones::Zonedid;
+use cfwevent::parser::{CfwEvType, CfwEvent, Direction, Protocol, Received, TrafficEvent};
+use chrono::Utc;
+use crossbeam::channel::SendError;
+use serde::Serialize;
+use std::net::Ipv6Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+lazy_static! {
+    /// The rule every test record is logged with
+    pub static ref SELFTEST_RULE: Uuid = Uuid::from_bytes([
+        0xcf, 0xd0, 0x5e, 0x1f, 0x7e, 0x57, 0x4e, 0x57, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x01,
+    ]);
+}
+
+/// How long a test waits for its event to reach every stage
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a test checks on its event
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Each test's event gets the next source port, so a late event of an earlier test isn't taken
+/// for the current one's
+static NEXT_ID: AtomicU16 = AtomicU16::new(0);
+
+lazy_static! {
+    static ref RUNNING: Mutex<Option<Trace>> = Mutex::new(None);
+}
+
+/// Where the running test's event has got to
+struct Trace {
+    id: u16,
+    started: Instant,
+    reached: Vec<(String, Duration, Result<(), String>)>,
+    /// The zone's sinks, once its `Logger` has the event
+    sinks: Option<Vec<String>>,
+}
+
+/// How a stage of a test went
+#[derive(Debug, Serialize)]
+pub struct StageResult {
+    pub stage: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elapsed_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The outcome of a test, which passed only if every stage did
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    pub vm: String,
+    pub zonedid: Zonedid,
+    pub stages: Vec<StageResult>,
+}
+
+/// The name of the stage of a zone's sink
+fn sink_stage(sink: &str) -> String {
+    format!("sink:{}", sink)
+}
+
+/// The id of the test `event` was injected by, if it's a test event
+pub fn test_id(event: &CfwEvent) -> Option<u16> {
+    match event {
+        CfwEvent::Traffic(traffic) if traffic.rule_uuid == *SELFTEST_RULE => {
+            Some(traffic.source_port)
+        }
+        _ => None,
+    }
+}
+
+/// Note that the event of test `id` reached `stage`, or failed to
+pub fn reached(id: u16, stage: String, result: Result<(), String>) {
+    if let Some(trace) = RUNNING
+        .lock()
+        .unwrap()
+        .as_mut()
+        .filter(|trace| trace.id == id)
+    {
+        let elapsed = trace.started.elapsed();
+        trace.reached.push((stage, elapsed, result));
+    }
+}
+
+/// Note that one of the zone's sinks wrote the event of test `id`, or failed to
+pub fn written(id: u16, sink: &str, result: Result<(), String>) {
+    reached(id, sink_stage(sink), result);
+}
+
+/// Note that the event of test `id` reached its zone's `Logger`, which writes it to `sinks`
+pub fn routed<'a, I: IntoIterator<Item = &'a str>>(id: u16, sinks: I) {
+    if let Some(trace) = RUNNING
+        .lock()
+        .unwrap()
+        .as_mut()
+        .filter(|trace| trace.id == id)
+    {
+        trace.sinks = Some(sinks.into_iter().map(str::to_owned).collect());
+        let elapsed = trace.started.elapsed();
+        trace.reached.push(("logger".to_owned(), elapsed, Ok(())));
+    }
+}
+
+/// Queues events that aren't read off a source, see `events::start_event_readers`
+pub struct Injector {
+    tx: queue::Sender<CfwEvent>,
+    memory: Arc<MemoryTracker>,
+    inflight: Arc<InFlight>,
+    audit: Arc<LossAudit>,
+}
+
+impl Injector {
+    pub fn new(
+        tx: queue::Sender<CfwEvent>,
+        memory: Arc<MemoryTracker>,
+        inflight: Arc<InFlight>,
+        audit: Arc<LossAudit>,
+    ) -> Self {
+        Injector {
+            tx,
+            memory,
+            inflight,
+            audit,
+        }
+    }
+
+    /// Queue an event the way the event readers do, returning false if the fanout thread is gone
+    fn inject(&self, mut event: CfwEvent) -> bool {
+        self.audit.stamp(&mut event);
+        event.set_received(Received {
+            timestamp: Utc::now(),
+            hrtime: clock::hrtime(),
+        });
+        self.memory.event_queued();
+        self.inflight.queued(event.zone());
+        let (memory, inflight, audit) = (&self.memory, &self.inflight, &self.audit);
+        let dropped = |dropped: CfwEvent| {
+            memory.events_done(1);
+            inflight.dropped(dropped.zone());
+            audit.dropped(&dropped);
+        };
+        match self.tx.send(event, dropped) {
+            Ok(()) => true,
+            Err(SendError(event)) => {
+                dropped(event);
+                false
+            }
+        }
+    }
+}
+
+/// The synthetic event of test `id`
+fn test_event(id: u16, zonedid: Zonedid) -> CfwEvent {
+    CfwEvent::Traffic(TrafficEvent {
+        event: CfwEvType::Begin,
+        length: 0,
+        zonedid,
+        rule_id: 0,
+        source_port: id,
+        destination_port: 0,
+        protocol: Protocol::from(6),
+        icmp: None,
+        direction: Direction::In,
+        source_ip: Ipv6Addr::LOCALHOST,
+        destination_ip: Ipv6Addr::LOCALHOST,
+        timestamp: Utc::now(),
+        rule_uuid: *SELFTEST_RULE,
+        seq: 0,
+        received: None,
+    })
+}
+
+/// The stages the test's event has to reach, its zone's sinks among them once they're known
+fn stages(trace: &Trace) -> Vec<String> {
+    let mut stages = vec!["fanout".to_owned(), "logger".to_owned()];
+    stages.extend(trace.sinks.iter().flatten().map(|sink| sink_stage(sink)));
+    stages
+}
+
+/// Whether the test's event has reached every stage
+fn finished(trace: &Trace) -> bool {
+    trace.sinks.is_some()
+        && stages(trace)
+            .iter()
+            .all(|stage| trace.reached.iter().any(|(reached, _, _)| reached == stage))
+}
+
+/// How each stage went, with those the event never reached failed
+fn results(trace: Trace, timeout: Duration) -> Vec<StageResult> {
+    stages(&trace)
+        .into_iter()
+        .map(|stage| {
+            let reached = trace
+                .reached
+                .iter()
+                .find(|(reached, _, _)| *reached == stage);
+            match reached {
+                Some((_, elapsed, result)) => StageResult {
+                    stage,
+                    ok: result.is_ok(),
+                    elapsed_ms: Some(elapsed.as_millis() as u64),
+                    error: result.clone().err(),
+                },
+                None => StageResult {
+                    stage,
+                    ok: false,
+                    elapsed_ms: None,
+                    error: Some(format!("not reached within {}ms", timeout.as_millis())),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Inject a test event for the zone and wait up to `timeout` for it to reach every stage
+pub fn run(injector: &Injector, zonedid: Zonedid, vm: &str, timeout: Duration) -> Report {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    *RUNNING.lock().unwrap() = Some(Trace {
+        id,
+        started: Instant::now(),
+        reached: vec![],
+        sinks: None,
+    });
+    let injected = injector.inject(test_event(id, zonedid));
+    let deadline = Instant::now() + timeout;
+    while injected && Instant::now() < deadline {
+        if RUNNING.lock().unwrap().as_ref().map_or(true, finished) {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    let trace = RUNNING
+        .lock()
+        .unwrap()
+        .take()
+        .expect("only one test runs at a time");
+    let mut stages = results(trace, timeout);
+    if !injected {
+        stages[0].error = Some("the event queue is disconnected".to_owned());
+    }
+    Report {
+        ok: stages.iter().all(|stage| stage.ok),
+        vm: vm.to_owned(),
+        zonedid,
+        stages,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::Overflow;
+
+    #[test]
+    fn stages_are_reported() {
+        let (tx, rx) = queue::bounded(16, Overflow::DropNewest);
+        let memory = Arc::new(MemoryTracker::new(None));
+        let injector = Injector::new(
+            tx,
+            Arc::clone(&memory),
+            Arc::new(InFlight::new()),
+            Arc::new(LossAudit::new(false)),
+        );
+
+        // Nothing takes the event off the queue
+        let report = run(&injector, 7, "vm1", Duration::from_millis(50));
+        assert!(!report.ok);
+        assert_eq!(report.stages.len(), 2, "the sinks aren't known");
+        assert_eq!(
+            report.stages[0].error.as_deref(),
+            Some("not reached within 50ms")
+        );
+        let stale = rx.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!(memory.queued_events(), 1);
+
+        let handle = thread::spawn(move || run(&injector, 7, "vm1", TIMEOUT));
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let id = test_id(&event).expect("tagged as a test");
+        assert_eq!(event.zone(), 7);
+        reached(test_id(&stale).unwrap(), "fanout".to_owned(), Ok(()));
+        reached(id, "fanout".to_owned(), Ok(()));
+        routed(id, vec!["file", "webhook"]);
+        written(id, "file", Ok(()));
+        written(id, "webhook", Err("connection refused".to_owned()));
+        let report = handle.join().unwrap();
+        assert!(!report.ok);
+        let stages: Vec<(&str, bool)> = report
+            .stages
+            .iter()
+            .map(|stage| (stage.stage.as_str(), stage.ok))
+            .collect();
+        assert_eq!(
+            stages,
+            vec![
+                ("fanout", true),
+                ("logger", true),
+                ("sink:file", true),
+                ("sink:webhook", false)
+            ]
+        );
+        assert_eq!(
+            report.stages[3].error.as_deref(),
+            Some("connection refused")
+        );
+
+        let mut other = test_event(1, 7);
+        if let CfwEvent::Traffic(traffic) = &mut other {
+            traffic.rule_uuid = Uuid::nil();
+        }
+        assert!(test_id(&other).is_none(), "only the test rule is a test");
+    }
+}
@@ -0,0 +1,198 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Everything that depends on the service manager cfwlogd runs under: whether we fork into the
+//! background, how we tell the service manager we're ready, and which exit codes we use.
+//!
+//! Ready means the event source is open, vminfod's zones have been loaded, and the pipeline is
+//! running, so services that depend on cfwlogd aren't started before it's logging.
+//!
+//! - Under SMF we daemonize and the parent waits for the child to become ready before exiting, so
+//!   the start method only returns once we're running. In permissive startup mode the parent exits
+//!   right away instead, since waiting on a prerequisite could outlast the start method's timeout.
+//! - Under systemd (Type=notify) we stay in the foreground and send "READY=1" to
+//!   `$NOTIFY_SOCKET`, and "STOPPING=1" once we start shutting down.
+//! - The generic mode stays in the foreground and notifies nobody, which suits supervisors that
+//!   only watch the process.
+
+use crate::config::{Config, StartupMode};
+use crate::exit::{self, ExitReason};
+use daemonize::Daemonize;
+use serde::Deserialize;
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::FromRawFd;
+use std::os::unix::net::UnixDatagram;
+
+/// The byte the daemonized child sends its parent once it's ready
+const READY: u8 = b'R';
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceManager {
+    Smf,
+    Systemd,
+    Generic,
+}
+
+impl Default for ServiceManager {
+    fn default() -> Self {
+        ServiceManager::Smf
+    }
+}
+
+/// How we tell the service manager we're ready
+enum Notifier {
+    /// Nobody is waiting on us
+    None,
+    /// The write end of a pipe our daemonizing parent is waiting on
+    Parent(File),
+    /// systemd's notification socket
+    Systemd(UnixDatagram),
+}
+
+pub struct Service {
+    notifier: Notifier,
+}
+
+impl Service {
+    /// Start running under the configured service manager, which for SMF means daemonizing. This
+    /// has to be called before we chroot since systemd's notification socket lives outside of the
+    /// log directory.
+    pub fn start(config: &Config) -> io::Result<Service> {
+        exit::set_smf_exit_codes(config.service_manager == ServiceManager::Smf);
+        let notifier = match config.service_manager {
+            ServiceManager::Systemd => Notifier::Systemd(connect_systemd()?),
+            ServiceManager::Smf if !config.foreground => {
+                daemonize(config.startup_mode == StartupMode::Strict)?
+            }
+            ServiceManager::Smf | ServiceManager::Generic => {
+                info!("staying in the foreground");
+                Notifier::None
+            }
+        };
+        Ok(Service { notifier })
+    }
+
+    /// Tell the service manager we're up and logging events
+    pub fn ready(&mut self) {
+        let result = match std::mem::replace(&mut self.notifier, Notifier::None) {
+            Notifier::None => Ok(()),
+            Notifier::Parent(mut pipe) => pipe.write_all(&[READY]),
+            Notifier::Systemd(socket) => {
+                let result = socket.send(b"READY=1").map(|_| ());
+                self.notifier = Notifier::Systemd(socket);
+                result
+            }
+        };
+        match result {
+            Ok(()) => info!("ready"),
+            Err(e) => warn!("failed to notify the service manager we're ready: {}", e),
+        }
+    }
+
+    /// Tell the service manager we've started shutting down
+    pub fn stopping(&self) {
+        if let Notifier::Systemd(socket) = &self.notifier {
+            if let Err(e) = socket.send(b"STOPPING=1") {
+                warn!("failed to notify systemd we're stopping: {}", e);
+            }
+        }
+    }
+}
+
+/// Connect to the socket systemd told us to send notifications to
+fn connect_systemd() -> io::Result<UnixDatagram> {
+    let path = std::env::var_os("NOTIFY_SOCKET").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "NOTIFY_SOCKET isn't set, is the unit Type=notify?",
+        )
+    })?;
+    if path.to_string_lossy().starts_with('@') {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "abstract notification sockets aren't supported",
+        ));
+    }
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// Returns the read and write ends of a new pipe
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Block until the child says it's ready, returning the exit code the parent should exit with.
+/// If the child exits first the pipe is closed without it ever saying so.
+fn wait_for_ready(mut pipe: File) -> i32 {
+    let mut buf = [0];
+    match pipe.read(&mut buf) {
+        Ok(1) if buf[0] == READY => ExitReason::Shutdown.code(),
+        _ => ExitReason::Setup.code(),
+    }
+}
+
+/// Fork cfwlogd as a daemon. When `wait` is set the parent only exits once the child is ready.
+fn daemonize(wait: bool) -> io::Result<Notifier> {
+    // The "daemonize" crate unfortunately sets stdout/stderr to devnull if you don't specify a
+    // path, so for now we reopen them manually via their respective devices.
+    let stdout = OpenOptions::new().write(true).open("/dev/stdout")?;
+    let stderr = OpenOptions::new().write(true).open("/dev/stderr")?;
+
+    // Drop all groups
+    if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let (read, write) = pipe()?;
+    // The parent has to close its copy of the write end, otherwise it would never see the pipe
+    // close if the child exits before becoming ready.
+    let parent = Cell::new(Some((read, write.try_clone()?)));
+    Daemonize::new()
+        .stdout(stdout)
+        .stderr(stderr)
+        .group("daemon")
+        .umask(0o022)
+        .exit_action(move || {
+            if let Some((read, write)) = parent.take() {
+                drop(write);
+                if wait {
+                    std::process::exit(wait_for_ready(read));
+                }
+            }
+        })
+        .start()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+    Ok(Notifier::Parent(write))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parent_waits_for_ready() {
+        let (read, mut write) = pipe().unwrap();
+        write.write_all(&[READY]).unwrap();
+        assert_eq!(wait_for_ready(read), exit::SMF_EXIT_OK);
+
+        let (read, write) = pipe().unwrap();
+        drop(write);
+        assert_eq!(
+            wait_for_ready(read),
+            exit::SMF_EXIT_ERR_FATAL,
+            "the child exited without becoming ready"
+        );
+    }
+}
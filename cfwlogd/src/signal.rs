@@ -4,6 +4,7 @@
 
 // Copyright 2019 Joyent, Inc.
 
+use crate::exit::{self, Subsystem};
 use crossbeam::channel::Sender;
 use libc::c_int;
 use std::sync::{Arc, Barrier};
@@ -17,7 +18,12 @@ fn signal_handler(tx: Sender<c_int>, b: Arc<Barrier>) {
         libc::SIGUSR1,
         libc::SIGUSR2,
     ])
-    .expect("unable to create signal handler");
+    .unwrap_or_else(|e| {
+        exit::fault(
+            Subsystem::Setup,
+            &format!("unable to create signal handler: {}", e),
+        )
+    });
 
     // signal handler has started
     b.wait();
@@ -36,7 +42,12 @@ pub fn start_signalhandler(tx: Sender<c_int>) -> thread::JoinHandle<()> {
     let handle = thread::Builder::new()
         .name("signal_handler".to_owned())
         .spawn(move || signal_handler(tx, b2))
-        .expect("failed to spawn signal watcher thread");
+        .unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Setup,
+                &format!("failed to spawn signal watcher thread: {}", e),
+            )
+        });
 
     // Block until the signal handler is setup
     b.wait();
@@ -0,0 +1,135 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An EventSource that generates synthetic traffic events at a fixed rate, which allows the rest
+//! of the pipeline to be developed and load tested on systems without ipfilter. Events cycle
+//! through the configured zonedids, so they are only logged for zones vminfod knows about.
+
+use crate::source::{EventSource, SourceStats};
+use crate::wire::{self, Packet, RuleInfo, TRAFFIC_EVENT_SIZE};
+use chrono::Utc;
+use std::io;
+use std::net::Ipv4Addr;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const MAX_EVENTS_PER_READ: usize = 1024;
+
+pub struct Simulator {
+    /// Events per second
+    rate: u64,
+    zones: Vec<u32>,
+    /// A rule uuid for each zone
+    rules: Vec<Uuid>,
+    started: Instant,
+    generated: u64,
+}
+
+impl Simulator {
+    /// Create a new `Simulator` producing `rate` events per second spread across `zones`
+    pub fn new(rate: u64, zones: Vec<u32>) -> Self {
+        assert!(rate > 0 && !zones.is_empty(), "validated by the config");
+        info!(
+            "simulating {} events per second for zones {:?}",
+            rate, zones
+        );
+        Simulator {
+            rate,
+            rules: zones.iter().map(|_| Uuid::new_v4()).collect(),
+            zones,
+            started: Instant::now(),
+            generated: 0,
+        }
+    }
+
+    /// How many events should have been generated by now
+    fn due(&self) -> u64 {
+        (self.started.elapsed().as_micros() as u64).saturating_mul(self.rate) / 1_000_000
+    }
+
+    fn encode(&self, n: u64, buf: &mut [u8]) {
+        let idx = (n % self.zones.len() as u64) as usize;
+        let rule = RuleInfo {
+            // Every tenth event is a block
+            event: if n % 10 == 9 { 1 } else { 2 },
+            zonedid: self.zones[idx],
+            rule_id: idx as u32,
+            rule_uuid: self.rules[idx],
+        };
+        let packet = Packet {
+            protocol: 6,
+            source_ip: Ipv4Addr::new(10, 0, (n >> 8) as u8, n as u8).to_ipv6_mapped(),
+            destination_ip: Ipv4Addr::new(10, 1, 0, idx as u8).to_ipv6_mapped(),
+            source_port: 32768 + (n % 28232) as u16,
+            destination_port: 443,
+        };
+        wire::encode_event(&rule, &packet, 1 + (n % 2) as u8, Utc::now(), buf);
+    }
+}
+
+impl EventSource for Simulator {
+    /// Wait until at least one event is due and generate everything that is due
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut due = self.due();
+        while due <= self.generated {
+            thread::sleep(
+                Duration::from_micros(1_000_000 / self.rate).max(Duration::from_micros(100)),
+            );
+            due = self.due();
+        }
+        let count = ((due - self.generated) as usize).min(buf.len() / TRAFFIC_EVENT_SIZE);
+        for (i, chunk) in buf
+            .chunks_exact_mut(TRAFFIC_EVENT_SIZE)
+            .take(count)
+            .enumerate()
+        {
+            self.encode(self.generated + i as u64, chunk);
+        }
+        self.generated += count as u64;
+        Ok(count * TRAFFIC_EVENT_SIZE)
+    }
+
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)> {
+        Ok((TRAFFIC_EVENT_SIZE, MAX_EVENTS_PER_READ))
+    }
+
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        Ok(SourceStats {
+            received: self.generated,
+            dropped: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvent};
+
+    #[test]
+    fn simulated_events_parse() {
+        let mut sim = Simulator::new(1_000_000, vec![7, 8]);
+        let (max, count) = sim.event_sizing().unwrap();
+        let mut buf = vec![0; max * count];
+        let size = sim.read_events(&mut buf).expect("failed to read events");
+        assert!(size >= TRAFFIC_EVENT_SIZE, "at least one event is returned");
+
+        let mut bytes = &buf[..size];
+        let mut zones = vec![];
+        while !bytes.is_empty() {
+            let (rest, event) = parser::cfwevent_parse(bytes).expect("failed to parse event");
+            match event {
+                CfwEvent::Traffic(event) => zones.push(event.zonedid),
+                e => panic!("unexpected event {:?}", e),
+            }
+            bytes = rest;
+        }
+        assert_eq!(zones[0], 7);
+        assert!(zones.iter().all(|z| *z == 7 || *z == 8));
+        assert_eq!(sim.stats().unwrap().received, zones.len() as u64);
+    }
+}
@@ -0,0 +1,289 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The `Sink` abstraction that a `Logger` writes its zone's records to. Each `Logger` opens one of
+//! every configured sink for its zone when it starts, and from then on the sinks are only ever
+//! used from that `Logger`'s thread. The zone's log file is always the first sink, see
+//! `logger::ZoneLog`. When cfwlogd is built with the "dynamic-sinks" feature additional sinks can
+//! be loaded from shared objects, see the "plugin" module.
+
+use crate::config::{Config, EpochUnit};
+use crate::enrich::Annotation;
+use crate::fields::Fields;
+use crate::format::{self, Format};
+use crate::nic::NicContext;
+use crate::node::NodeIdentity;
+use crate::rules::RuleOwner;
+use crate::template::Template;
+use cfwevent::parser::{CfwEvent, TrafficEvent};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::io;
+use std::sync::Arc;
+
+pub use cfwevent::record::{SchemaVersion, SCHEMA_VERSION};
+
+/// A `CfwEvent` along with the zone information it's logged with
+#[derive(Clone, Debug, Serialize)]
+pub struct Record<'a> {
+    pub schema_version: SchemaVersion,
+    #[serde(flatten)]
+    pub event: CfwEvent,
+    pub vm: &'a str,
+    pub alias: &'a str,
+    /// Who owns the record's rule, when FWAPI told us
+    #[serde(flatten)]
+    pub rule_owner: Option<&'a RuleOwner>,
+    /// What's known about the remote address of a traffic record, see the "enrich" module
+    #[serde(flatten)]
+    pub annotation: Option<&'a Annotation>,
+    /// The number of events the record stands for, when its rule or zone is sampled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sampled: Option<u64>,
+    /// The number of identical events the record stands for, when it ends a run of them, see the
+    /// "coalesce" module
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub repeats: Option<u64>,
+    /// The event's timestamp as a number, when "epoch_timestamp" is configured
+    #[serde(flatten)]
+    pub epoch: Option<Epoch>,
+    /// When cfwlogd read the event, when "receive_timestamps" is configured
+    #[serde(flatten)]
+    pub received: Option<ReceiveTimes>,
+    /// The CN the record was logged on, when "node_identity" is configured
+    #[serde(flatten)]
+    pub node: Option<&'a NodeIdentity>,
+    /// The nic the event was seen on, when "nic_context" is configured
+    #[serde(flatten)]
+    pub nic: Option<&'a NicContext>,
+}
+
+impl<'a> Record<'a> {
+    /// A record of `event` for the zone `vm`, without any of the optional fields
+    pub fn new(event: CfwEvent, vm: &'a str, alias: &'a str) -> Self {
+        Record {
+            schema_version: SchemaVersion,
+            event,
+            vm,
+            alias,
+            rule_owner: None,
+            annotation: None,
+            sampled: None,
+            repeats: None,
+            epoch: None,
+            received: None,
+            node: None,
+            nic: None,
+        }
+    }
+}
+
+/// A timestamp as the number of units since the Unix epoch, serialized as an "epoch_ms" or
+/// "epoch_ns" field
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Epoch {
+    #[serde(rename = "epoch_ms")]
+    Millis(i64),
+    #[serde(rename = "epoch_ns")]
+    Nanos(i64),
+}
+
+impl Epoch {
+    pub fn new(unit: EpochUnit, timestamp: &DateTime<Utc>) -> Epoch {
+        match unit {
+            EpochUnit::Ms => Epoch::Millis(timestamp.timestamp_millis()),
+            EpochUnit::Ns => Epoch::Nanos(timestamp.timestamp_nanos()),
+        }
+    }
+}
+
+/// When cfwlogd read a traffic event off the device, alongside the "timestamp" the kernel gave it
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ReceiveTimes {
+    pub received_timestamp: DateTime<Utc>,
+    /// The high resolution clock's nanoseconds, which orders events across wall clock steps
+    pub received_hrtime: u64,
+    /// Milliseconds from the event's timestamp until it was read, which is negative if the wall
+    /// clock was stepped back in between
+    pub lag_ms: i64,
+}
+
+impl ReceiveTimes {
+    /// The event's times, if it was read off a device
+    pub fn new(event: &TrafficEvent) -> Option<ReceiveTimes> {
+        event.received.map(|received| ReceiveTimes {
+            received_timestamp: received.timestamp,
+            received_hrtime: received.hrtime,
+            lag_ms: (received.timestamp - event.timestamp).num_milliseconds(),
+        })
+    }
+}
+
+/// How a sink turns each record into a line, from the sink's entries in the config's
+/// "sink_formats", "sink_fields" and "sink_templates". Json lines use the template if there is
+/// one, and the field selection otherwise.
+#[derive(Clone, Debug, Default)]
+pub struct Encoder {
+    format: Format,
+    fields: Fields,
+    template: Option<Template>,
+    /// The older schema json records are written in, from the config's "schema_version"
+    schema_version: Option<u64>,
+}
+
+impl Encoder {
+    /// The encoder configured for the sink with the given name
+    pub fn for_sink(name: &str, config: &Config) -> Encoder {
+        Encoder {
+            format: config.sink_formats.get(name).copied().unwrap_or_default(),
+            fields: config.sink_fields.get(name).cloned().unwrap_or_default(),
+            template: config.sink_templates.get(name).cloned(),
+            schema_version: config.schema_version,
+        }
+    }
+
+    /// Append the encoded record to `buf`, without a newline
+    pub fn encode(&self, record: &Record<'_>, buf: &mut Vec<u8>) -> serde_json::Result<()> {
+        match self.format {
+            Format::Json => (),
+            Format::Cef => {
+                format::cef(record, buf);
+                return Ok(());
+            }
+            Format::Leef => {
+                format::leef(record, buf);
+                return Ok(());
+            }
+            Format::Cbor | Format::Msgpack | Format::Protobuf => {
+                let version = self.schema_version.unwrap_or(SCHEMA_VERSION);
+                return format::binary(self.format, record, version, buf)
+                    .map_err(serde_json::Error::io);
+            }
+        }
+        match &self.template {
+            Some(template) => {
+                let mut line = String::new();
+                template.render(record, &mut line)?;
+                buf.extend_from_slice(line.as_bytes());
+                Ok(())
+            }
+            None => {
+                let version = self.schema_version.unwrap_or(SCHEMA_VERSION);
+                self.fields.to_writer(buf, record, version)
+            }
+        }
+    }
+
+    /// What goes between encoded records, nothing for binary formats since each of their values
+    /// delimits itself
+    pub fn separator(&self) -> &'static [u8] {
+        if self.format.is_binary() {
+            b""
+        } else {
+            b"\n"
+        }
+    }
+
+    /// Encode the record as a string, without a newline. Only sinks that can't be given a binary
+    /// format use this.
+    pub fn to_line(&self, record: &Record<'_>) -> serde_json::Result<String> {
+        match &self.template {
+            Some(template) => {
+                let mut line = String::new();
+                template.render(record, &mut line)?;
+                Ok(line)
+            }
+            None => {
+                let mut buf = vec![];
+                self.encode(record, &mut buf)?;
+                Ok(String::from_utf8(buf).expect("every format writes utf-8"))
+            }
+        }
+    }
+}
+
+/// Counters kept by a sink about what it has written
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SinkStats {
+    pub records: u64,
+    pub bytes: u64,
+    /// Records left out because the sink's queue was full, see the "fanout" module
+    pub dropped: u64,
+}
+
+/// A `Record` that owns what it refers to, for handing records to another thread
+#[derive(Clone, Debug)]
+pub struct OwnedRecord {
+    event: CfwEvent,
+    vm: String,
+    alias: String,
+    rule_owner: Option<RuleOwner>,
+    annotation: Option<Annotation>,
+    sampled: Option<u64>,
+    repeats: Option<u64>,
+    epoch: Option<Epoch>,
+    received: Option<ReceiveTimes>,
+    node: Option<NodeIdentity>,
+    nic: Option<NicContext>,
+}
+
+impl OwnedRecord {
+    pub fn new(record: &Record<'_>) -> Self {
+        OwnedRecord {
+            event: record.event.clone(),
+            vm: record.vm.to_owned(),
+            alias: record.alias.to_owned(),
+            rule_owner: record.rule_owner.cloned(),
+            annotation: record.annotation.cloned(),
+            sampled: record.sampled,
+            repeats: record.repeats,
+            epoch: record.epoch,
+            received: record.received,
+            node: record.node.cloned(),
+            nic: record.nic.cloned(),
+        }
+    }
+
+    /// The record, borrowing from this one
+    pub fn record(&self) -> Record<'_> {
+        Record {
+            schema_version: SchemaVersion,
+            event: self.event.clone(),
+            vm: &self.vm,
+            alias: &self.alias,
+            rule_owner: self.rule_owner.as_ref(),
+            annotation: self.annotation.as_ref(),
+            sampled: self.sampled,
+            repeats: self.repeats,
+            epoch: self.epoch,
+            received: self.received,
+            node: self.node.as_ref(),
+            nic: self.nic.as_ref(),
+        }
+    }
+}
+
+/// Trait that represents a destination for a zone's records. Sinks are opened by their own
+/// constructors since what they need to know differs between implementations.
+pub trait Sink: Send {
+    /// Short name used when logging problems with the sink
+    fn name(&self) -> &str;
+    /// Write out a batch of records. Sinks are free to buffer records until `flush` is called.
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()>;
+    /// Make sure everything written so far has reached its destination
+    fn flush(&mut self) -> io::Result<()>;
+    /// Called when the zone's log files are rotated (SIGHUP or SIGUSR1)
+    fn rotate(&mut self) -> io::Result<()>;
+    /// Called periodically so the sink can check on its destination
+    fn check(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// Called when the config file is reloaded (SIGHUP), for sinks holding on to settings
+    fn reload(&mut self, _config: &Arc<Config>) {}
+    /// Flush and release the sink, no other methods are called afterwards
+    fn close(&mut self) -> io::Result<()>;
+    fn stats(&self) -> SinkStats;
+}
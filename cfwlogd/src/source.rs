@@ -0,0 +1,136 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The `EventSource` abstraction that the event pipeline reads from. Every source hands back
+//! events in the binary format produced by the illumos ipfev device, so the rest of the pipeline
+//! doesn't need to know where they came from. The source in use is selected by the "source"
+//! section of the config file:
+//!
+//! - "ipfev": the illumos ipfilter event devices (by default just /dev/ipfev), see the "ipf"
+//!   module. Each device is read by its own thread, and their events are merged into the same
+//!   pipeline.
+//! - "simulator": synthetic events for development and load testing, see the "simulator" module
+//! - "replay": a file of raw ipfev records, see the "replay" module
+//! - "nflog": a Linux netfilter NFLOG group, see the "nflog" module
+//! - "pflog": a BSD pflog interface, see the "pflog" module
+
+use crate::config::SourceConfig;
+use crate::ipf::IpfevDevice;
+use crate::replay::ReplaySource;
+use crate::simulator::Simulator;
+use std::fs::File;
+use std::io;
+
+/// Counters kept by a source about the events it has produced
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourceStats {
+    /// Events the source has handed back to us
+    pub received: u64,
+    /// Events the source knows it had to drop before we could read them
+    pub dropped: u64,
+}
+
+/// Trait that represents a Firewall Event Source
+pub trait EventSource: Send {
+    /// Reads n events into the given buffer, returning how many bytes were read. Every read must
+    /// return at least one complete event, a source that has run out of events returns an
+    /// `UnexpectedEof` error instead.
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    /// Returns a tuple that tells you the largest event size, and the max number of events that
+    /// may be returned in a single read.
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)>;
+    /// Returns the source's own counters, sources that don't keep any report zeros.
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        Ok(SourceStats::default())
+    }
+    /// Called once the pipeline has stopped reading from the source.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+    /// A duplicate of the source's descriptor, for sources that can be handed over to a new
+    /// cfwlogd, see the "handoff" module
+    fn descriptor(&self) -> Option<File> {
+        None
+    }
+}
+
+impl<T: EventSource + ?Sized> EventSource for Box<T> {
+    fn read_events(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read_events(buf)
+    }
+    fn event_sizing(&mut self) -> io::Result<(usize, usize)> {
+        (**self).event_sizing()
+    }
+    fn stats(&mut self) -> io::Result<SourceStats> {
+        (**self).stats()
+    }
+    fn close(&mut self) -> io::Result<()> {
+        (**self).close()
+    }
+    fn descriptor(&self) -> Option<File> {
+        (**self).descriptor()
+    }
+}
+
+/// Open the configured sources of firewall events, each along with the name it's known by in the
+/// daemon log. Devices that were handed over by the previous cfwlogd are taken out of `inherited`
+/// rather than opened again.
+pub fn open(
+    source: &SourceConfig,
+    inherited: &mut Vec<(String, File)>,
+) -> io::Result<Vec<(String, Box<dyn EventSource>)>> {
+    let (name, source): (&str, Box<dyn EventSource>) = match source {
+        SourceConfig::Ipfev { devices } => {
+            return devices
+                .iter()
+                .map(|device| {
+                    let name = device.display().to_string();
+                    if let Some(i) = inherited.iter().position(|(n, _)| *n == name) {
+                        let (_, file) = inherited.swap_remove(i);
+                        let ipfev: Box<dyn EventSource> =
+                            Box::new(IpfevDevice::inherit(file, device));
+                        return Ok((name, ipfev));
+                    }
+                    let ipfev: Box<dyn EventSource> = match IpfevDevice::new(device) {
+                        Ok(ipfev) => Box::new(ipfev),
+                        Err(e) => return Err(io::Error::new(e.kind(), format!("{}: {}", name, e))),
+                    };
+                    Ok((name, ipfev))
+                })
+                .collect()
+        }
+        SourceConfig::Simulator { rate, zones } => {
+            ("simulator", Box::new(Simulator::new(*rate, zones.clone())))
+        }
+        SourceConfig::Replay { path, speed } => {
+            ("replay", Box::new(ReplaySource::new(path, *speed)?))
+        }
+        #[cfg(target_os = "linux")]
+        SourceConfig::Nflog { group } => {
+            ("nflog", Box::new(crate::nflog::NflogSource::new(*group)?))
+        }
+        #[cfg(not(target_os = "linux"))]
+        SourceConfig::Nflog { .. } => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "NFLOG is only supported on Linux",
+            ))
+        }
+        #[cfg(feature = "pflog")]
+        SourceConfig::Pflog { interface, rules } => {
+            let pflog = crate::pflog::PflogSource::new(interface, rules.clone())?;
+            (interface.as_str(), Box::new(pflog))
+        }
+        #[cfg(not(feature = "pflog"))]
+        SourceConfig::Pflog { .. } => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "cfwlogd was built without pflog support",
+            ))
+        }
+    };
+    Ok(vec![(name.to_owned(), source)])
+}
@@ -0,0 +1,291 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An on-disk ring of records that a remote sink spills into while its destination is down or
+//! slow, and replays from once it has recovered, so an outage of the collector doesn't cost us
+//! the zone's records. Sinks configured with "spool_bytes" keep one ring file per zone, named
+//! after the sink, in the zone's log directory.
+//!
+//! The file starts with a header holding the ring's size along with where its oldest record is
+//! and how much of it is in use, followed by the ring itself. Each record is stored as its length
+//! followed by its bytes, and may wrap around the end of the ring. Once the ring is full the
+//! oldest records are dropped to make room for new ones. The header is rewritten after every
+//! change, so the records left in the ring when cfwlogd stops are replayed once it starts again.
+//! Records are replayed at least once, a record that was sent just before cfwlogd stopped may be
+//! sent again.
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+
+/// The smallest "spool_bytes" accepted
+pub const MIN_BYTES: u64 = 65_536;
+
+/// Every spool file starts with these bytes
+const MAGIC: &[u8; 8] = b"cfwspool";
+/// The magic followed by the ring's size, the oldest record's offset, the bytes in use and the
+/// number of records
+const HEADER_LEN: u64 = 40;
+/// Records are prefixed by their length as a little endian u32
+const LEN_PREFIX: u64 = 4;
+
+/// The file name of the named sink's spool
+pub fn file_name(sink: &str) -> String {
+    format!("{}.spool", sink)
+}
+
+pub struct Spool {
+    path: PathBuf,
+    file: File,
+    /// Size of the ring following the header
+    capacity: u64,
+    /// Offset of the oldest record into the ring
+    head: u64,
+    /// Bytes of the ring in use
+    used: u64,
+    /// Number of records waiting to be replayed
+    records: u64,
+    /// Records dropped to make room since the spool was last empty
+    overwritten: u64,
+}
+
+impl Spool {
+    /// Open the spool at `path`, creating it if needed, holding at most `max_bytes` including its
+    /// header. A spool of a different size is started over.
+    pub fn open(path: PathBuf, max_bytes: u64) -> io::Result<Spool> {
+        let capacity = max_bytes.saturating_sub(HEADER_LEN);
+        if capacity <= LEN_PREFIX {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "spool is too small to hold any records",
+            ));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&path)?;
+        let mut spool = Spool {
+            path,
+            file,
+            capacity,
+            head: 0,
+            used: 0,
+            records: 0,
+            overwritten: 0,
+        };
+
+        let mut header = [0; HEADER_LEN as usize];
+        let len = spool.file.metadata()?.len();
+        if len >= HEADER_LEN {
+            spool.file.read_exact_at(&mut header, 0)?;
+        }
+        let field = |i: usize| u64::from_le_bytes(header[i..i + 8].try_into().unwrap());
+        let (size, head, used, records) = (field(8), field(16), field(24), field(32));
+        if &header[..8] == MAGIC && size == capacity && head < size && used <= size {
+            spool.head = head;
+            spool.used = used;
+            spool.records = records;
+            if records > 0 {
+                info!(
+                    "replaying {} records from {}",
+                    records,
+                    spool.path.display()
+                );
+            }
+        } else {
+            if len > 0 {
+                warn!(
+                    "starting {} over since it doesn't match the configured size",
+                    spool.path.display()
+                );
+            }
+            spool.file.set_len(0)?;
+            spool.write_header()?;
+        }
+        Ok(spool)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    /// Add a record to the end of the ring, dropping the oldest records if there isn't room
+    pub fn push(&mut self, record: &[u8]) -> io::Result<()> {
+        let need = LEN_PREFIX + record.len() as u64;
+        if self.is_empty() && self.overwritten == 0 {
+            warn!(
+                "spooling records to {} until its destination is available again",
+                self.path.display()
+            );
+        }
+        if need > self.capacity {
+            self.overwrite();
+            return Ok(());
+        }
+        while self.used + need > self.capacity {
+            let len = self.record_len(self.head)?;
+            self.head = (self.head + LEN_PREFIX + len) % self.capacity;
+            self.used -= LEN_PREFIX + len;
+            self.records -= 1;
+            self.overwrite();
+        }
+        let tail = (self.head + self.used) % self.capacity;
+        self.write_ring(tail, &(record.len() as u32).to_le_bytes())?;
+        self.write_ring((tail + LEN_PREFIX) % self.capacity, record)?;
+        self.used += need;
+        self.records += 1;
+        self.write_header()
+    }
+
+    /// Up to `max` of the oldest records, which stay in the ring until they are popped
+    pub fn peek(&self, max: usize) -> io::Result<Vec<Vec<u8>>> {
+        let mut records = vec![];
+        let mut offset = self.head;
+        for _ in 0..std::cmp::min(max as u64, self.records) {
+            let len = self.record_len(offset)?;
+            let mut record = vec![0; len as usize];
+            self.read_ring((offset + LEN_PREFIX) % self.capacity, &mut record)?;
+            records.push(record);
+            offset = (offset + LEN_PREFIX + len) % self.capacity;
+        }
+        Ok(records)
+    }
+
+    /// Remove the `n` oldest records once they have been replayed
+    pub fn pop(&mut self, n: usize) -> io::Result<()> {
+        for _ in 0..std::cmp::min(n as u64, self.records) {
+            let len = self.record_len(self.head)?;
+            self.head = (self.head + LEN_PREFIX + len) % self.capacity;
+            self.used -= LEN_PREFIX + len;
+            self.records -= 1;
+        }
+        if self.is_empty() {
+            info!(
+                "caught up on the records spooled to {} ({} were dropped while it was full)",
+                self.path.display(),
+                self.overwritten
+            );
+            self.head = 0;
+            self.overwritten = 0;
+            // Give the space back until the next outage
+            self.file.set_len(HEADER_LEN)?;
+        }
+        self.write_header()
+    }
+
+    /// Count a record dropped to make room
+    fn overwrite(&mut self) {
+        if self.overwritten == 0 {
+            warn!(
+                "{} is full, dropping its oldest records",
+                self.path.display()
+            );
+        }
+        self.overwritten += 1;
+    }
+
+    fn record_len(&self, offset: u64) -> io::Result<u64> {
+        let mut len = [0; LEN_PREFIX as usize];
+        self.read_ring(offset, &mut len)?;
+        Ok(u64::from(u32::from_le_bytes(len)))
+    }
+
+    fn write_header(&self) -> io::Result<()> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(MAGIC);
+        for value in &[self.capacity, self.head, self.used, self.records] {
+            header.extend_from_slice(&value.to_le_bytes());
+        }
+        self.file.write_all_at(&header, 0)
+    }
+
+    /// Write `buf` at `offset` into the ring, wrapping around its end
+    fn write_ring(&self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        let first = std::cmp::min(buf.len() as u64, self.capacity - offset) as usize;
+        self.file.write_all_at(&buf[..first], HEADER_LEN + offset)?;
+        self.file.write_all_at(&buf[first..], HEADER_LEN)
+    }
+
+    /// Fill `buf` from `offset` into the ring, wrapping around its end
+    fn read_ring(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let first = std::cmp::min(buf.len() as u64, self.capacity - offset) as usize;
+        let (start, rest) = buf.split_at_mut(first);
+        self.file.read_exact_at(start, HEADER_LEN + offset)?;
+        self.file.read_exact_at(rest, HEADER_LEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = Path::new("/var/tmp/cfwlogd-tests/spool").join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn ring_wraps_and_drops_oldest() {
+        let dir = test_dir("ring");
+        // Room for three 10 byte records with their length prefixes
+        let mut spool = Spool::open(dir.join("sink.spool"), HEADER_LEN + 42).unwrap();
+        for record in &[b"record 001", b"record 002", b"record 003"] {
+            spool.push(*record).unwrap();
+        }
+        assert_eq!(spool.records, 3);
+        spool.pop(1).unwrap();
+        spool.push(b"record 004").unwrap();
+        assert_eq!(
+            spool.peek(10).unwrap(),
+            vec![b"record 002", b"record 003", b"record 004"],
+            "the new record wrapped around the end of the ring"
+        );
+
+        spool.push(b"record 005").unwrap();
+        assert_eq!(spool.overwritten, 1);
+        assert_eq!(
+            spool.peek(1).unwrap(),
+            vec![b"record 003"],
+            "the oldest record made room"
+        );
+        spool.push(&[0; 100]).unwrap();
+        assert_eq!(spool.records, 3, "records larger than the ring are dropped");
+
+        spool.pop(3).unwrap();
+        assert!(spool.is_empty());
+        assert_eq!(
+            std::fs::metadata(dir.join("sink.spool")).unwrap().len(),
+            HEADER_LEN,
+            "an empty spool gives back its space"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = test_dir("reopen");
+        let path = dir.join("sink.spool");
+        let mut spool = Spool::open(path.clone(), MIN_BYTES).unwrap();
+        spool.push(b"first").unwrap();
+        spool.push(b"second").unwrap();
+        spool.pop(1).unwrap();
+        drop(spool);
+
+        let spool = Spool::open(path.clone(), MIN_BYTES).unwrap();
+        assert_eq!(spool.peek(10).unwrap(), vec![b"second".to_vec()]);
+        drop(spool);
+
+        let spool = Spool::open(path, MIN_BYTES * 2).unwrap();
+        assert!(spool.is_empty(), "a spool of another size is started over");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
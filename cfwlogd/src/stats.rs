@@ -0,0 +1,577 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Per-zone accounting of the events that flow through the pipeline. Counters are shared between
+//! the device reader, the fanout thread and each `Logger` so that an event dropped anywhere along
+//! the way can still be attributed to the zone it belonged to.  A `Logger` takes (and resets) its
+//! zone's counters every time its log file is rotated, and writes the result out as a `Rollup`.
+//!
+//! Events suppressed by sampling under memory or disk pressure, or by the zone's rate limit, are
+//! also tallied by rule, so the zone's `Logger` can periodically log a summary of what it never
+//! got to see.
+//!
+//! Events the `EventSource` itself dropped, such as when the ipfev device's ring overflowed,
+//! can't be attributed to a zone since the source only counts them. Every zone is told about
+//! them instead, so each zone's `Logger` can mark the gap in its log.
+
+use crate::histogram::Histograms;
+use crate::pause::Pause;
+use crate::probes;
+use crate::rules::{RuleOwner, Rules};
+use crate::talkers::Top;
+use crate::zones::Zonedid;
+use cfwevent::parser::{CfwEvType, CfwEvent};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Holds a Mutex protected mapping of zonedid to the zone's counters
+pub type Stats = Arc<Mutex<HashMap<Zonedid, Arc<ZoneCounters>>>>;
+
+/// The reasons an event may be dropped before it made it to disk
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DropReason {
+    /// The queue to the fanout thread or to the zone's `Logger` was full
+    QueueFull,
+    /// The zone's `Logger` was no longer accepting events
+    LoggerDisconnected,
+    /// cfwlogd was approaching its memory ceiling
+    MemoryPressure,
+    /// The log filesystem was critically low on space
+    DiskSpace,
+    /// The zone's log file couldn't be written because the filesystem was out of space or quota,
+    /// and the events overflowed the zone's buffer
+    DiskFull,
+    /// The zone was over its "rate_limit"
+    RateLimited,
+    /// The zone's logs were over its "disk_budget"
+    DiskBudget,
+}
+
+/// Counters for a single zone covering the period since they were last taken.
+#[derive(Debug, Default)]
+pub struct ZoneCounters {
+    written: AtomicU64,
+    dropped_queue_full: AtomicU64,
+    dropped_logger_disconnected: AtomicU64,
+    dropped_memory_pressure: AtomicU64,
+    dropped_disk_space: AtomicU64,
+    dropped_disk_full: AtomicU64,
+    dropped_rate_limited: AtomicU64,
+    dropped_disk_budget: AtomicU64,
+    /// Events written broken down by the rule they were logged for
+    rules: Mutex<HashMap<Uuid, u64>>,
+    /// Events of "count_only" rules counted rather than written, broken down by rule
+    counted: Mutex<HashMap<Uuid, u64>>,
+    /// Running totals of the events written by type, these are never reset
+    total_block: AtomicU64,
+    total_begin: AtomicU64,
+    total_end: AtomicU64,
+    /// Running total of the events dropped at a full queue, this is never reset
+    total_queue_full: AtomicU64,
+    /// Running total of the events dropped for any reason, this is never reset
+    total_dropped: AtomicU64,
+    /// Running total of the events matching the alert filter, this is never reset
+    total_alerting: AtomicU64,
+    /// Events the source dropped that the zone's log hasn't been told about yet
+    source_lost: AtomicU64,
+    /// Running total of the events the source dropped, this is never reset
+    total_source_lost: AtomicU64,
+    /// Events suppressed by sampling, broken down by reason and the rule they were logged for
+    suppressed: Mutex<HashMap<(DropReason, Option<Uuid>), u64>>,
+    /// Timestamp in milliseconds of the newest event written, or 0 if none have been
+    last_event_ms: AtomicI64,
+    /// The zone's latest top talkers and rules, and when they were taken, when "top_talkers" is
+    /// configured
+    top: Mutex<Option<(Top, DateTime<Utc>)>>,
+    /// Set while the zone's logs are over its "disk_budget", see the "budget" module
+    over_budget: AtomicBool,
+    histograms: Histograms,
+    /// Whether an operator has paused the zone's logging, see the "pause" module
+    pause: Pause,
+}
+
+impl ZoneCounters {
+    /// Record that `n` events were written out to the zone's log
+    pub fn written(&self, n: u64) {
+        self.written.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Add an event of the given type to the running totals
+    pub fn event_written(&self, event: &CfwEvType) {
+        let counter = match event {
+            CfwEvType::Block => &self.total_block,
+            CfwEvType::Begin => &self.total_begin,
+            CfwEvType::End => &self.total_end,
+            CfwEvType::Unknown => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the timestamp of an event that was written
+    pub fn event_at(&self, timestamp: &DateTime<Utc>) {
+        self.last_event_ms
+            .fetch_max(timestamp.timestamp_millis(), Ordering::Relaxed);
+    }
+
+    /// The timestamp of the newest event written since cfwlogd started
+    pub fn last_event(&self) -> Option<DateTime<Utc>> {
+        match self.last_event_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(Utc.timestamp_millis(ms)),
+        }
+    }
+
+    /// The running totals of events written, and dropped at a full queue or by the source, since
+    /// cfwlogd started
+    pub fn totals(&self) -> Totals {
+        Totals {
+            block: self.total_block.load(Ordering::Relaxed),
+            begin: self.total_begin.load(Ordering::Relaxed),
+            end: self.total_end.load(Ordering::Relaxed),
+            queue_full: self.total_queue_full.load(Ordering::Relaxed),
+            dropped: self.total_dropped.load(Ordering::Relaxed),
+            source_lost: self.total_source_lost.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Record that the source dropped `n` events, some of which may have been the zone's
+    pub fn source_lost(&self, n: u64) {
+        self.source_lost.fetch_add(n, Ordering::Relaxed);
+        self.total_source_lost.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Reset the count of events the source dropped, returning those dropped since the last call
+    pub fn take_source_lost(&self) -> u64 {
+        self.source_lost.swap(0, Ordering::Relaxed)
+    }
+
+    /// Add events matching the alert filter to their running total
+    pub fn alerting_written(&self, n: u64) {
+        self.total_alerting.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// The running total of events written that matched the alert filter
+    pub fn alerting_total(&self) -> u64 {
+        self.total_alerting.load(Ordering::Relaxed)
+    }
+
+    /// Record that events were written out for each of the given rules
+    pub fn rules_written<I: IntoIterator<Item = Uuid>>(&self, rules: I) {
+        let mut counts = self.rules.lock().unwrap();
+        for rule in rules {
+            *counts.entry(rule).or_insert(0) += 1;
+        }
+    }
+
+    /// Record that an event was dropped for the given reason
+    pub fn dropped(&self, reason: DropReason) {
+        self.total_dropped.fetch_add(1, Ordering::Relaxed);
+        let counter = match reason {
+            DropReason::QueueFull => {
+                self.total_queue_full.fetch_add(1, Ordering::Relaxed);
+                &self.dropped_queue_full
+            }
+            DropReason::LoggerDisconnected => &self.dropped_logger_disconnected,
+            DropReason::MemoryPressure => &self.dropped_memory_pressure,
+            DropReason::DiskSpace => &self.dropped_disk_space,
+            DropReason::DiskFull => &self.dropped_disk_full,
+            DropReason::RateLimited => &self.dropped_rate_limited,
+            DropReason::DiskBudget => &self.dropped_disk_budget,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that an event logged for `rule` was suppressed for the given reason
+    pub fn suppressed(&self, reason: DropReason, rule: Option<Uuid>) {
+        *self
+            .suppressed
+            .lock()
+            .unwrap()
+            .entry((reason, rule))
+            .or_insert(0) += 1;
+    }
+
+    /// Reset the suppressed event counts, returning everything suppressed since the last call
+    pub fn take_suppressed(&self) -> Vec<Suppressed> {
+        let counts = std::mem::replace(&mut *self.suppressed.lock().unwrap(), HashMap::new());
+        let mut suppressed: Vec<Suppressed> = counts
+            .into_iter()
+            .map(|((reason, rule), events)| Suppressed {
+                reason,
+                rule,
+                events,
+            })
+            .collect();
+        suppressed.sort_by_key(|s| (s.rule, s.events));
+        suppressed
+    }
+
+    /// Mark the zone as over or under its disk budget, returning whether it was over before
+    pub fn set_over_budget(&self, over: bool) -> bool {
+        self.over_budget.swap(over, Ordering::Relaxed)
+    }
+
+    /// Whether the zone's logs are over its disk budget
+    pub fn over_budget(&self) -> bool {
+        self.over_budget.load(Ordering::Relaxed)
+    }
+
+    /// How the zone's events have been written since it was first logged, which like the running
+    /// totals is never reset
+    pub fn histograms(&self) -> &Histograms {
+        &self.histograms
+    }
+
+    /// Whether the zone's logging is paused, which is shared with the admin socket
+    pub fn pause(&self) -> &Pause {
+        &self.pause
+    }
+
+    /// Keep the zone's latest top talkers and rules, see the "talkers" module
+    pub fn set_top(&self, top: Top, timestamp: DateTime<Utc>) {
+        *self.top.lock().unwrap() = Some((top, timestamp));
+    }
+
+    /// The zone's latest top talkers and rules, and when they were taken
+    pub fn top(&self) -> Option<(Top, DateTime<Utc>)> {
+        self.top.lock().unwrap().clone()
+    }
+
+    /// Reset the per-rule counts, returning a report for every rule seen since the last call
+    /// attributed with what we know about the rule.
+    pub fn take_rules(&self, rules: &Rules) -> Vec<RuleReport> {
+        rule_reports(&self.rules, rules)
+    }
+
+    /// Record that `n` events of a "count_only" rule were counted rather than written
+    pub fn counted(&self, rule: Uuid, n: u64) {
+        *self.counted.lock().unwrap().entry(rule).or_insert(0) += n;
+    }
+
+    /// Reset the counts of "count_only" rules, returning a report for every rule counted since
+    /// the last call
+    pub fn take_counted(&self, rules: &Rules) -> Vec<RuleReport> {
+        rule_reports(&self.counted, rules)
+    }
+
+    /// The values accumulated since the counters were last taken, without resetting them
+    pub fn peek(&self) -> Counts {
+        Counts {
+            events_written: self.written.load(Ordering::Relaxed),
+            dropped: DropCounts {
+                queue_full: self.dropped_queue_full.load(Ordering::Relaxed),
+                logger_disconnected: self.dropped_logger_disconnected.load(Ordering::Relaxed),
+                memory_pressure: self.dropped_memory_pressure.load(Ordering::Relaxed),
+                disk_space: self.dropped_disk_space.load(Ordering::Relaxed),
+                disk_full: self.dropped_disk_full.load(Ordering::Relaxed),
+                rate_limited: self.dropped_rate_limited.load(Ordering::Relaxed),
+                disk_budget: self.dropped_disk_budget.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// Reset all of the counters, returning the values accumulated since the last call.
+    pub fn take(&self) -> Counts {
+        Counts {
+            events_written: self.written.swap(0, Ordering::Relaxed),
+            dropped: DropCounts {
+                queue_full: self.dropped_queue_full.swap(0, Ordering::Relaxed),
+                logger_disconnected: self.dropped_logger_disconnected.swap(0, Ordering::Relaxed),
+                memory_pressure: self.dropped_memory_pressure.swap(0, Ordering::Relaxed),
+                disk_space: self.dropped_disk_space.swap(0, Ordering::Relaxed),
+                disk_full: self.dropped_disk_full.swap(0, Ordering::Relaxed),
+                rate_limited: self.dropped_rate_limited.swap(0, Ordering::Relaxed),
+                disk_budget: self.dropped_disk_budget.swap(0, Ordering::Relaxed),
+            },
+        }
+    }
+}
+
+/// A point in time copy of a zone's counters
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct Counts {
+    pub events_written: u64,
+    pub dropped: DropCounts,
+}
+
+/// Number of dropped events broken down by reason
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct DropCounts {
+    pub queue_full: u64,
+    pub logger_disconnected: u64,
+    pub memory_pressure: u64,
+    pub disk_space: u64,
+    pub disk_full: u64,
+    pub rate_limited: u64,
+    pub disk_budget: u64,
+}
+
+/// Number of events of each type written since cfwlogd started
+#[derive(Debug, Default, PartialEq)]
+pub struct Totals {
+    pub block: u64,
+    pub begin: u64,
+    pub end: u64,
+    /// Events dropped at a full queue rather than written
+    pub queue_full: u64,
+    /// Events dropped for any reason, including a full queue, rather than written
+    pub dropped: u64,
+    /// Events the source dropped while the zone was being logged, which may not have been the
+    /// zone's
+    pub source_lost: u64,
+}
+
+/// Number of events written for a single rule
+#[derive(Debug, PartialEq, Serialize)]
+pub struct RuleReport {
+    pub rule: Uuid,
+    pub events: u64,
+    #[serde(flatten)]
+    pub owner: Option<RuleOwner>,
+}
+
+/// Number of events suppressed for a single reason and rule
+#[derive(Debug, PartialEq)]
+pub struct Suppressed {
+    pub reason: DropReason,
+    /// The rule the events were logged for, if they were traffic events
+    pub rule: Option<Uuid>,
+    pub events: u64,
+}
+
+/// The per-rule counts of a zone, written every "rule_stats_secs" when configured
+#[derive(Debug, Serialize)]
+pub struct RuleStats<'a> {
+    pub vm: &'a str,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub rules: Vec<RuleReport>,
+}
+
+/// Summary of a zone's log file covering the period between two rotations
+#[derive(Debug, Serialize)]
+pub struct Rollup<'a> {
+    pub vm: &'a str,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    #[serde(flatten)]
+    pub counts: Counts,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<RuleReport>,
+}
+
+/// Reset per-rule counts, returning them attributed with what we know about each rule
+fn rule_reports(counts: &Mutex<HashMap<Uuid, u64>>, rules: &Rules) -> Vec<RuleReport> {
+    reports(
+        std::mem::replace(&mut *counts.lock().unwrap(), HashMap::new()),
+        rules,
+    )
+}
+
+/// A report for every rule with a count, attributed with what we know about the rule
+pub fn reports(counts: HashMap<Uuid, u64>, rules: &Rules) -> Vec<RuleReport> {
+    let rules = rules.read().unwrap();
+    let mut reports: Vec<RuleReport> = counts
+        .into_iter()
+        .map(|(rule, events)| RuleReport {
+            rule,
+            events,
+            owner: rules.get(&rule).cloned(),
+        })
+        .collect();
+    reports.sort_by_key(|report| report.rule);
+    reports
+}
+
+/// Return the counters for the given zone, creating them if this is the first time we have seen
+/// the zone.
+pub fn zone_counters(stats: &Stats, zonedid: Zonedid) -> Arc<ZoneCounters> {
+    let mut stats = stats.lock().unwrap();
+    Arc::clone(
+        stats
+            .entry(zonedid)
+            .or_insert_with(|| Arc::new(ZoneCounters::default())),
+    )
+}
+
+/// Attribute a dropped event to the given zone
+pub fn record_drop(stats: &Stats, zonedid: Zonedid, reason: DropReason) {
+    probe!(drop(zonedid, probes::drop_reason(reason)));
+    zone_counters(stats, zonedid).dropped(reason);
+}
+
+/// Tell every zone that the source dropped `n` events
+pub fn record_source_lost(stats: &Stats, n: u64) {
+    for counters in stats.lock().unwrap().values() {
+        counters.source_lost(n);
+    }
+}
+
+/// Attribute an event suppressed by sampling or rate limiting to its zone and rule
+pub fn record_suppressed(stats: &Stats, event: &CfwEvent, reason: DropReason) {
+    probe!(drop(event.zone(), probes::drop_reason(reason)));
+    let counters = zone_counters(stats, event.zone());
+    counters.dropped(reason);
+    let rule = match event {
+        CfwEvent::Traffic(event) => Some(event.rule_uuid),
+        CfwEvent::Unknown(_) => None,
+    };
+    counters.suppressed(reason, rule);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam::sync::ShardedLock;
+
+    #[test]
+    fn take_resets_counters() {
+        let counters = ZoneCounters::default();
+        counters.written(10);
+        counters.dropped(DropReason::QueueFull);
+        counters.dropped(DropReason::QueueFull);
+        counters.dropped(DropReason::LoggerDisconnected);
+
+        assert_eq!(counters.peek().events_written, 10, "peeking doesn't reset");
+        let counts = counters.take();
+        assert_eq!(counts.events_written, 10, "written events were counted");
+        assert_eq!(
+            counts.dropped.queue_full, 2,
+            "queue full drops were counted"
+        );
+        assert_eq!(
+            counts.dropped.logger_disconnected, 1,
+            "logger disconnected drops were counted"
+        );
+        assert_eq!(counters.take(), Counts::default(), "counters were reset");
+
+        counters.event_written(&CfwEvType::Block);
+        counters.event_written(&CfwEvType::Begin);
+        counters.take();
+        assert_eq!(
+            counters.totals(),
+            Totals {
+                block: 1,
+                begin: 1,
+                end: 0,
+                queue_full: 2,
+                dropped: 3,
+                source_lost: 0,
+            },
+            "totals are not reset"
+        );
+
+        assert_eq!(counters.last_event(), None);
+        let newest = Utc.timestamp_millis(1_600_000_000_123);
+        counters.event_at(&newest);
+        counters.event_at(&Utc.timestamp_millis(1_500_000_000_000));
+        assert_eq!(
+            counters.last_event(),
+            Some(newest),
+            "the newest event is kept"
+        );
+    }
+
+    #[test]
+    fn zone_counters_are_shared() {
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let counters = zone_counters(&stats, 10);
+        record_drop(&stats, 10, DropReason::QueueFull);
+        record_drop(&stats, 11, DropReason::QueueFull);
+
+        assert_eq!(stats.lock().unwrap().len(), 2, "two zones are tracked");
+        assert_eq!(
+            counters.take().dropped.queue_full,
+            1,
+            "drop is visible through the previously returned counters"
+        );
+
+        record_source_lost(&stats, 5);
+        assert_eq!(zone_counters(&stats, 11).take_source_lost(), 5);
+        assert_eq!(counters.take_source_lost(), 5, "every zone is told");
+        assert_eq!(counters.take_source_lost(), 0);
+        assert_eq!(counters.totals().source_lost, 5, "the total is not reset");
+    }
+
+    #[test]
+    fn take_rules_attributes_rules() {
+        let owned = Uuid::new_v4();
+        let unknown = Uuid::new_v4();
+        let owner = RuleOwner {
+            owner_uuid: Some(Uuid::new_v4()),
+            description: Some("allow ssh".to_owned()),
+        };
+        let rules: Rules = Arc::new(ShardedLock::new(HashMap::new()));
+        rules.write().unwrap().insert(owned, owner.clone());
+
+        let counters = ZoneCounters::default();
+        counters.rules_written(vec![owned, unknown, owned]);
+        let mut reports = counters.take_rules(&rules);
+        reports.sort_by_key(|report| report.events);
+        assert_eq!(
+            reports,
+            vec![
+                RuleReport {
+                    rule: unknown,
+                    events: 1,
+                    owner: None,
+                },
+                RuleReport {
+                    rule: owned,
+                    events: 2,
+                    owner: Some(owner),
+                },
+            ]
+        );
+        assert!(counters.take_rules(&rules).is_empty(), "counts were reset");
+    }
+
+    #[test]
+    fn suppressed_events_are_tallied_by_rule() {
+        let stats: Stats = Arc::new(Mutex::new(HashMap::new()));
+        let event = testutils::generate_event();
+        let event = cfwevent::parser::cfwevent_parse(event.as_bytes())
+            .unwrap()
+            .1;
+        let rule = match &event {
+            CfwEvent::Traffic(event) => event.rule_uuid,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        record_suppressed(&stats, &event, DropReason::MemoryPressure);
+        record_suppressed(&stats, &event, DropReason::MemoryPressure);
+        record_suppressed(&stats, &event, DropReason::DiskSpace);
+
+        let counters = zone_counters(&stats, event.zone());
+        assert_eq!(
+            counters.take().dropped.memory_pressure,
+            2,
+            "drops are counted"
+        );
+        let mut suppressed = counters.take_suppressed();
+        suppressed.sort_by_key(|s| s.events);
+        assert_eq!(
+            suppressed,
+            vec![
+                Suppressed {
+                    reason: DropReason::DiskSpace,
+                    rule: Some(rule),
+                    events: 1,
+                },
+                Suppressed {
+                    reason: DropReason::MemoryPressure,
+                    rule: Some(rule),
+                    events: 2,
+                },
+            ]
+        );
+        assert!(counters.take_suppressed().is_empty(), "counts were reset");
+    }
+}
@@ -0,0 +1,100 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that writes every zone's records to cfwlogd's stdout, for running cfwlogd in the
+//! foreground under a debugger or in a container where something else collects the output. Each
+//! batch is written while holding stdout's lock, so records from different zones never interleave
+//! mid-line, and every record already names its zone in the "vm" field.
+
+use crate::config::Config;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+pub struct StdoutSink {
+    encoder: Encoder,
+    buf: Vec<u8>,
+    stats: SinkStats,
+}
+
+impl StdoutSink {
+    pub fn new(encoder: Encoder) -> Self {
+        StdoutSink {
+            encoder,
+            buf: vec![],
+            stats: SinkStats::default(),
+        }
+    }
+}
+
+/// Encode the records into `buf` as newline separated lines
+fn encode_lines(encoder: &Encoder, records: &[Record<'_>], buf: &mut Vec<u8>) -> io::Result<()> {
+    buf.clear();
+    for record in records {
+        encoder.encode(record, buf)?;
+        buf.push(b'\n');
+    }
+    Ok(())
+}
+
+impl Sink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        encode_lines(&self.encoder, records, &mut self.buf)?;
+        io::stdout().lock().write_all(&self.buf)?;
+        self.stats.records += records.len() as u64;
+        self.stats.bytes += self.buf.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+
+    #[test]
+    fn records_are_encoded_as_lines() {
+        let records: Vec<Record> = ["vm1", "vm2"]
+            .iter()
+            .map(|&vm| {
+                let event = testutils::generate_event();
+                Record::new(parser::cfwevent_parse(event.as_bytes()).unwrap().1, vm, "")
+            })
+            .collect();
+        let mut buf = b"left over".to_vec();
+        encode_lines(&Encoder::default(), &records, &mut buf).unwrap();
+        let vms: Vec<serde_json::Value> = String::from_utf8(buf)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["vm"].clone())
+            .collect();
+        assert_eq!(vms, vec!["vm1", "vm2"], "one zone-tagged line per record");
+    }
+}
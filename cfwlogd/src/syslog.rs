@@ -0,0 +1,588 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that ships records to a syslog collector as RFC 5424 messages over TCP, or TLS when
+//! built with the "syslog-tls" feature (see the "tls" module), framed by octet counting as
+//! described in RFC 6587. Each
+//! message carries the record's rule, vm, direction, addresses and ports as structured data, and
+//! the record encoded as usual as its MSG, so the sink's entries in "sink_fields" and
+//! "sink_templates" apply.
+//!
+//! The collector's address is resolved when cfwlogd starts since it can't be once we have chrooted.
+//! By default every zone's sink shares a single
+//! connection, with "per_zone" set each zone gets its own. A connection that fails is retried
+//! at most every `RETRY_INTERVAL`, and the records written in the meantime are dropped rather
+//! than holding up the zone's log file. With "spool_bytes" set they are spilled to the zone's
+//! spool instead and replayed once the collector is reachable again, see the "spool" module.
+
+use crate::config::{Config, SyslogConfig};
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use crate::spool::{self, Spool};
+#[cfg(feature = "syslog-tls")]
+use crate::tls::Tls;
+use cfwevent::parser::{self, CfwEvType, CfwEvent};
+use chrono::SecondsFormat;
+use std::ffi::CStr;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait after a failed connection before trying again
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+/// Spooled messages sent at a time while replaying
+const REPLAY_BATCH: usize = 1024;
+
+/// Severities of the messages, block events are more interesting than the others
+const SEVERITY_WARNING: u8 = 4;
+const SEVERITY_INFO: u8 = 6;
+
+/// The record fields that make up each message's structured data, in order
+const SD_PARAMS: &[&str] = &[
+    "event",
+    "rule",
+    "vm",
+    "alias",
+    "direction",
+    "protocol",
+    "source_ip",
+    "source_port",
+    "destination_ip",
+    "destination_port",
+    "icmp_type",
+    "icmp_code",
+];
+
+lazy_static! {
+    /// The collector set up at startup, if there is one
+    static ref COLLECTOR: Mutex<Option<Arc<Collector>>> = Mutex::new(None);
+}
+
+/// The parts of every message's header that don't depend on the record
+#[derive(Debug)]
+struct Header {
+    facility: u8,
+    hostname: String,
+    procid: u32,
+    sd_id: String,
+}
+
+struct Collector {
+    addrs: Vec<SocketAddr>,
+    /// The TLS settings and the name the collector's certificate is verified against
+    #[cfg(feature = "syslog-tls")]
+    tls: Option<(Arc<Tls>, String)>,
+    header: Header,
+    /// The connection shared by every zone, unless each gets its own
+    shared: Option<Arc<Mutex<Connection>>>,
+    /// Size of each zone's spool, if messages are spooled while the collector is unavailable
+    spool_bytes: Option<u64>,
+}
+
+impl Collector {
+    fn connect(&self) -> io::Result<Box<dyn Write + Send>> {
+        let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to");
+        for addr in &self.addrs {
+            match TcpStream::connect_timeout(addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    return self.wrap(stream);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    #[cfg(feature = "syslog-tls")]
+    fn wrap(&self, stream: TcpStream) -> io::Result<Box<dyn Write + Send>> {
+        match &self.tls {
+            Some((tls, server_name)) => Ok(Box::new(tls.connect(server_name, stream)?)),
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    #[cfg(not(feature = "syslog-tls"))]
+    fn wrap(&self, stream: TcpStream) -> io::Result<Box<dyn Write + Send>> {
+        Ok(Box::new(stream))
+    }
+}
+
+/// A connection to the collector, which is (re)established whenever there is something to send
+#[derive(Default)]
+struct Connection {
+    stream: Option<Box<dyn Write + Send>>,
+    /// When the connection may next be attempted after a failure
+    retry_at: Option<Instant>,
+    /// Messages dropped since the connection was lost
+    dropped: u64,
+}
+
+impl Connection {
+    /// Send `count` framed messages, dropping them if we aren't able to
+    fn send(&mut self, collector: &Collector, messages: &[u8], count: u64) -> io::Result<()> {
+        match self.deliver(collector, messages) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                self.dropped += count;
+                match e.kind() {
+                    io::ErrorKind::NotConnected => Ok(()),
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+
+    /// Send framed messages, failing with `NotConnected` until a lost connection may be retried
+    fn deliver(&mut self, collector: &Collector, messages: &[u8]) -> io::Result<()> {
+        if self.stream.is_none() {
+            if self.retry_at.map_or(false, |at| Instant::now() < at) {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "waiting to reconnect to the syslog collector",
+                ));
+            }
+            match collector.connect() {
+                Ok(stream) => {
+                    if self.dropped > 0 {
+                        warn!(
+                            "reconnected to the syslog collector, {} messages were dropped \
+                             while disconnected",
+                            self.dropped
+                        );
+                    }
+                    self.stream = Some(stream);
+                    self.retry_at = None;
+                    self.dropped = 0;
+                }
+                Err(e) => return Err(self.lost(e)),
+            }
+        }
+        let stream = self.stream.as_mut().expect("we just connected");
+        if let Err(e) = stream.write_all(messages).and_then(|_| stream.flush()) {
+            return Err(self.lost(e));
+        }
+        Ok(())
+    }
+
+    /// Give up on the connection
+    fn lost(&mut self, e: io::Error) -> io::Error {
+        self.stream = None;
+        self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+        e
+    }
+}
+
+/// Resolve the collector and prepare its connection, which has to happen before we chroot
+pub fn init(config: &SyslogConfig) -> io::Result<()> {
+    let addrs: Vec<SocketAddr> = config.address.to_socket_addrs()?.collect();
+    #[cfg(feature = "syslog-tls")]
+    let tls = if config.tls {
+        let tls = Tls::open(
+            "syslog",
+            config.tls_ca.as_deref(),
+            config.tls_cert.as_deref(),
+            config.tls_key.as_deref(),
+            &config.tls_pin_sha256,
+        )?;
+        let host = config.address.rsplitn(2, ':').last().unwrap_or("");
+        let server_name = config
+            .tls_server_name
+            .clone()
+            .unwrap_or_else(|| host.trim_matches(&['[', ']'][..]).to_owned());
+        Some((tls, server_name))
+    } else {
+        None
+    };
+    let collector = Collector {
+        addrs,
+        #[cfg(feature = "syslog-tls")]
+        tls,
+        header: Header {
+            facility: config.facility,
+            hostname: hostname().unwrap_or_else(|| "-".to_owned()),
+            procid: std::process::id(),
+            sd_id: config.sd_id.clone(),
+        },
+        shared: if config.per_zone {
+            None
+        } else {
+            Some(Arc::new(Mutex::new(Connection::default())))
+        },
+        spool_bytes: config.spool_bytes,
+    };
+    info!(
+        "shipping records to the syslog collector at {}",
+        config.address
+    );
+    *COLLECTOR.lock().unwrap() = Some(Arc::new(collector));
+    Ok(())
+}
+
+/// Open a zone's syslog sink, if a collector was set up at startup. The zone's spool, if any, is
+/// kept in `dir`.
+pub fn open_sink(dir: &Path, config: &Config) -> Option<SyslogSink> {
+    let collector = Arc::clone(COLLECTOR.lock().unwrap().as_ref()?);
+    let connection = match &collector.shared {
+        Some(shared) => Arc::clone(shared),
+        None => Arc::new(Mutex::new(Connection::default())),
+    };
+    let spool = collector.spool_bytes.and_then(|bytes| {
+        let path = dir.join(spool::file_name("syslog"));
+        Spool::open(path, bytes)
+            .map_err(|e| {
+                error!(
+                    "failed to open the syslog spool in {}: {}",
+                    dir.display(),
+                    e
+                )
+            })
+            .ok()
+    });
+    Some(SyslogSink {
+        collector,
+        connection,
+        encoder: Encoder::for_sink("syslog", config),
+        buf: vec![],
+        ends: vec![],
+        spool,
+        stats: SinkStats::default(),
+    })
+}
+
+/// The node's hostname, as messages are sent with it
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let name = CStr::from_bytes_with_nul(&buf[..=buf.iter().position(|&b| b == 0)?]).ok()?;
+    name.to_str().ok().map(str::to_owned)
+}
+
+/// Append `value` as an SD-PARAM value, escaping the characters RFC 5424 requires us to
+fn push_param_value(value: &str, out: &mut Vec<u8>) {
+    for c in value.chars() {
+        if c == '"' || c == '\\' || c == ']' {
+            out.push(b'\\');
+        }
+        let mut bytes = [0; 4];
+        out.extend_from_slice(c.encode_utf8(&mut bytes).as_bytes());
+    }
+}
+
+/// Append the record as an RFC 5424 message, prefixed by its length in octets
+fn frame_message(
+    header: &Header,
+    encoder: &Encoder,
+    record: &Record<'_>,
+    out: &mut Vec<u8>,
+) -> io::Result<()> {
+    let (severity, timestamp) = match &record.event {
+        CfwEvent::Traffic(event) => (
+            match event.event {
+                CfwEvType::Block => SEVERITY_WARNING,
+                _ => SEVERITY_INFO,
+            },
+            parser::rfc3339(&event.timestamp, SecondsFormat::Micros),
+        ),
+        CfwEvent::Unknown(_) => (SEVERITY_INFO, "-".to_owned()),
+    };
+    let fields = serde_json::to_value(record)?;
+    let msgid = fields["event"].as_str().unwrap_or("-");
+
+    let mut message = format!(
+        "<{}>1 {} {} cfwlogd {} {} [{}",
+        header.facility * 8 + severity,
+        timestamp,
+        header.hostname,
+        header.procid,
+        msgid,
+        header.sd_id
+    )
+    .into_bytes();
+    for name in SD_PARAMS {
+        let value = match &fields[*name] {
+            serde_json::Value::Null => continue,
+            serde_json::Value::String(s) => s.clone(),
+            value => value.to_string(),
+        };
+        message.extend_from_slice(format!(" {}=\"", name).as_bytes());
+        push_param_value(&value, &mut message);
+        message.push(b'"');
+    }
+    message.extend_from_slice(b"] ");
+    encoder.encode(record, &mut message)?;
+
+    write!(out, "{} ", message.len())?;
+    out.extend_from_slice(&message);
+    Ok(())
+}
+
+pub struct SyslogSink {
+    collector: Arc<Collector>,
+    connection: Arc<Mutex<Connection>>,
+    encoder: Encoder,
+    buf: Vec<u8>,
+    /// Where each message in `buf` ends
+    ends: Vec<usize>,
+    spool: Option<Spool>,
+    stats: SinkStats,
+}
+
+impl SyslogSink {
+    /// Send the spooled messages, oldest first, until the spool is empty or the collector is
+    /// unavailable again
+    fn replay(&mut self) -> io::Result<()> {
+        let spool = match &mut self.spool {
+            Some(spool) => spool,
+            None => return Ok(()),
+        };
+        let mut connection = self.connection.lock().unwrap();
+        while !spool.is_empty() {
+            let messages = spool.peek(REPLAY_BATCH)?;
+            let bytes = messages.concat();
+            if connection.deliver(&self.collector, &bytes).is_err() {
+                break;
+            }
+            spool.pop(messages.len())?;
+            self.stats.records += messages.len() as u64;
+            self.stats.bytes += bytes.len() as u64;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for SyslogSink {
+    fn name(&self) -> &str {
+        "syslog"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        self.buf.clear();
+        self.ends.clear();
+        for record in records {
+            frame_message(&self.collector.header, &self.encoder, record, &mut self.buf)?;
+            self.ends.push(self.buf.len());
+        }
+        if self.spool.is_none() {
+            self.connection.lock().unwrap().send(
+                &self.collector,
+                &self.buf,
+                records.len() as u64,
+            )?;
+            self.stats.records += records.len() as u64;
+            self.stats.bytes += self.buf.len() as u64;
+            return Ok(());
+        }
+
+        // Older messages waiting in the spool go first
+        self.replay()?;
+        if self.spool.as_ref().map_or(false, Spool::is_empty) {
+            let sent = self
+                .connection
+                .lock()
+                .unwrap()
+                .deliver(&self.collector, &self.buf);
+            match sent {
+                Ok(()) => {
+                    self.stats.records += records.len() as u64;
+                    self.stats.bytes += self.buf.len() as u64;
+                    return Ok(());
+                }
+                Err(e) => debug!("spooling messages for the syslog collector: {}", e),
+            }
+        }
+        let spool = self.spool.as_mut().expect("only spooling with a spool");
+        let mut start = 0;
+        for &end in &self.ends {
+            spool.push(&self.buf[start..end])?;
+            start = end;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Every batch is sent as soon as it's written
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        self.replay()
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn header() -> Header {
+        Header {
+            facility: 16,
+            hostname: "cn1".to_owned(),
+            procid: 42,
+            sd_id: "cfw@32473".to_owned(),
+        }
+    }
+
+    fn record(alias: &str) -> Record<'_> {
+        let event = testutils::generate_event();
+        Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            "vm1",
+            alias,
+        )
+    }
+
+    #[test]
+    fn records_are_framed_as_rfc5424() {
+        let record = record("web \"1\"]");
+        let mut buf = vec![];
+        frame_message(&header(), &Encoder::default(), &record, &mut buf).unwrap();
+        let framed = String::from_utf8(buf).unwrap();
+
+        let (len, message) = framed.split_at(framed.find(' ').unwrap());
+        let message = &message[1..];
+        assert_eq!(
+            len.parse::<usize>().unwrap(),
+            message.len(),
+            "octet counted"
+        );
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => unreachable!(),
+        };
+        let pri = match event.event {
+            CfwEvType::Block => 132,
+            _ => 134,
+        };
+        assert!(
+            message.starts_with(&format!(
+                "<{}>1 {} cn1 cfwlogd 42 ",
+                pri,
+                event.timestamp.to_rfc3339_opts(SecondsFormat::Micros, true)
+            )),
+            "header: {}",
+            message
+        );
+        assert!(
+            message.contains(&format!(
+                "[cfw@32473 event=\"{}\" rule=\"{}\" vm=\"vm1\" alias=\"web \\\"1\\\"\\]\"",
+                serde_json::to_value(&event.event)
+                    .unwrap()
+                    .as_str()
+                    .unwrap(),
+                event.rule_uuid
+            )),
+            "structured data: {}",
+            message
+        );
+        let json = &message[message.find("] {").unwrap() + 2..];
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(value["vm"], "vm1", "the record follows as the MSG");
+    }
+
+    #[test]
+    fn connection_drops_messages_while_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let collector = Collector {
+            addrs: vec![listener.local_addr().unwrap()],
+            #[cfg(feature = "syslog-tls")]
+            tls: None,
+            header: header(),
+            shared: None,
+            spool_bytes: None,
+        };
+        let mut connection = Connection::default();
+        connection.send(&collector, b"5 first", 1).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buf = [0; 7];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"5 first");
+
+        drop(listener);
+        drop(peer);
+        connection.stream = None;
+        assert!(
+            connection.send(&collector, b"6 second", 1).is_err(),
+            "the collector is gone"
+        );
+        connection
+            .send(&collector, b"5 third", 1)
+            .expect("messages are dropped until the retry interval has passed");
+        assert_eq!(connection.dropped, 2);
+    }
+
+    #[test]
+    fn spooled_messages_are_replayed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let dir = std::path::PathBuf::from("/var/tmp/cfwlogd-tests/syslog-spool");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut sink = SyslogSink {
+            collector: Arc::new(Collector {
+                addrs: vec![addr],
+                #[cfg(feature = "syslog-tls")]
+                tls: None,
+                header: header(),
+                shared: None,
+                spool_bytes: Some(spool::MIN_BYTES),
+            }),
+            connection: Arc::new(Mutex::new(Connection::default())),
+            encoder: Encoder::default(),
+            buf: vec![],
+            ends: vec![],
+            spool: Some(Spool::open(dir.join("syslog.spool"), spool::MIN_BYTES).unwrap()),
+            stats: SinkStats::default(),
+        };
+
+        sink.write_batch(&[record("first"), record("second")])
+            .expect("messages are spooled while the collector is down");
+        assert!(
+            !sink.spool.as_ref().unwrap().is_empty(),
+            "the messages were spooled"
+        );
+        let spooled = sink.buf.clone();
+
+        let listener = TcpListener::bind(addr).unwrap();
+        sink.connection.lock().unwrap().retry_at = None;
+        sink.check().unwrap();
+        assert!(
+            sink.spool.as_ref().unwrap().is_empty(),
+            "the spool was replayed"
+        );
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut buf = vec![0; spooled.len()];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, spooled, "the messages arrived in order");
+        assert_eq!(sink.stats().records, 2);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An optional HTTP endpoint on the loopback interface that streams records as they are logged,
+//! `tail -f` for support tooling that would rather use curl than a WebSocket client:
+//!
+//! ```text
+//! curl -N 'http://127.0.0.1:9202/events?vm=<vm uuid>&proto=tcp&dir=in'
+//! ```
+//!
+//! The response is newline separated json formatted the same way as the zone log files, and lasts
+//! until the client goes away. Every query parameter is one of the fields of a filter expression
+//! compared to a value, see `Expr::fields`, so `vm`, `rule`, `proto`, `dir`, `action`, `port` and
+//! the rest of them can be combined, and a field given more than once matches any of its values.
+//! An idle response is sent an empty line every `KEEPALIVE_INTERVAL` so dead clients are noticed.
+
+use crate::expr::Expr;
+use crate::live::{Filter, LiveHub};
+use crossbeam::channel::RecvTimeoutError;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Limit on the number of clients connected at once
+const MAX_CONNECTIONS: usize = 16;
+/// Records queued in the `LiveHub` for each client before they start getting dropped
+const SUBSCRIBER_CAPACITY: usize = 1024;
+/// How often an idle response is sent an empty line
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a client has to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Build the `Filter` for the target of a request of the form "/events?<query>"
+fn target_filter(target: &str) -> Result<Filter, (u16, String)> {
+    let mut parts = target.splitn(2, '?');
+    if parts.next() != Some("/events") {
+        return Err((404, format!("unknown path \"{}\"", target)));
+    }
+    let pairs = parts
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty())
+        .map(|param| {
+            let mut kv = param.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some(field), Some(value)) if !value.is_empty() => Ok((field, value)),
+                _ => Err((400, format!("invalid query parameter \"{}\"", param))),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if pairs.is_empty() {
+        return Ok(Filter::default());
+    }
+    let expr = Expr::fields(&pairs).map_err(|e| (400, e))?;
+    Ok(Filter {
+        expr: Some(expr),
+        ..Filter::default()
+    })
+}
+
+/// Read the request line and headers, returning the request's target
+fn read_request(stream: &TcpStream) -> Result<String, (u16, String)> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    let bad_request = |e: io::Error| (400, format!("failed to read the request: {}", e));
+    reader.read_line(&mut line).map_err(bad_request)?;
+    let mut request = line.split_whitespace();
+    let (method, target) = match (request.next(), request.next()) {
+        (Some(method), Some(target)) => (method.to_owned(), target.to_owned()),
+        _ => return Err((400, "invalid request line".to_owned())),
+    };
+    // We don't care about any of the headers
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(bad_request)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+    if method != "GET" {
+        return Err((405, format!("unsupported method {}", method)));
+    }
+    Ok(target)
+}
+
+/// Respond to a request that can't be served
+fn refuse(mut stream: &TcpStream, status: u16, msg: &str) -> io::Result<()> {
+    let reason = match status {
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        503 => "Service Unavailable",
+        _ => "Bad Request",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        msg.len() + 1,
+        msg
+    )
+}
+
+/// Stream records to a client until it goes away
+fn serve_client(stream: TcpStream, hub: &LiveHub) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let filter = match read_request(&stream).and_then(|target| target_filter(&target)) {
+        Ok(filter) => filter,
+        Err((status, msg)) => return refuse(&stream, status, &msg),
+    };
+    debug!("new HTTP tail subscriber: {:?}", filter);
+
+    let records = hub.subscribe(filter, SUBSCRIBER_CAPACITY);
+    let mut writer = BufWriter::new(stream);
+    // The response ends when the connection is closed, so there's no need for chunking
+    writer.write_all(
+        b"HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nCache-Control: no-cache\r\n\
+          Connection: close\r\n\r\n",
+    )?;
+    writer.flush()?;
+    loop {
+        match records.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(record) => {
+                writer.write_all(record.json.as_bytes())?;
+                writer.write_all(b"\n")?;
+            }
+            Err(RecvTimeoutError::Timeout) => writer.write_all(b"\n")?,
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        // Batch up writes while records are arriving faster than we can send them
+        if records.is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Bind `addr` and start streaming records published on `hub` to every client
+pub fn start_tail_server(
+    addr: SocketAddr,
+    hub: Arc<LiveHub>,
+) -> io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    info!("HTTP tail listening on {}", addr);
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("tail_server".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept HTTP tail connection: {}", e);
+                        continue;
+                    }
+                };
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("too many HTTP tail clients, rejecting connection");
+                    let _ = refuse(&stream, 503, "too many clients");
+                    continue;
+                }
+                let hub = Arc::clone(&hub);
+                let connections2 = Arc::clone(&connections);
+                let res = thread::Builder::new()
+                    .name("tail_client".to_owned())
+                    .spawn(move || {
+                        if let Err(e) = serve_client(stream, &hub) {
+                            debug!("HTTP tail client went away: {}", e);
+                        }
+                        connections2.fetch_sub(1, Ordering::SeqCst);
+                    });
+                if let Err(e) = res {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    error!("failed to spawn HTTP tail client thread: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_targets() {
+        let filter = target_filter("/events?vm=vm1&vm=vm2&proto=tcp&dir=in").unwrap();
+        assert_eq!(
+            filter.expr,
+            Some(
+                Expr::fields(&[
+                    ("vm", "vm1"),
+                    ("vm", "vm2"),
+                    ("proto", "tcp"),
+                    ("dir", "in")
+                ])
+                .unwrap()
+            )
+        );
+        assert_eq!(target_filter("/events"), Ok(Filter::default()));
+        assert_eq!(
+            target_filter("/zones").map_err(|(status, _)| status),
+            Err(404)
+        );
+        for target in &[
+            "/events?proto=gre",
+            "/events?bogus=1",
+            "/events?rule=",
+            "/events?vm",
+        ] {
+            assert_eq!(
+                target_filter(target).map_err(|(status, _)| status),
+                Err(400),
+                "{} is invalid",
+                target
+            );
+        }
+    }
+}
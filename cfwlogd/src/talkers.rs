@@ -0,0 +1,225 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Tracking which remote addresses a zone's rules block the most, and which of its rules log the
+//! most events, so that "who is hammering this VM" and "which rule is misbehaving" can be answered
+//! from the zone's log alone. Each zone's `ZoneLog` counts blocked events by remote address (the
+//! source of inbound traffic and the destination of outbound traffic), and all of its events by
+//! rule, in buckets of "top_talkers.interval_secs". At the end of every interval it logs the top
+//! addresses and rules over the sliding window made up of the most recent buckets, along with
+//! their rate over the window, and keeps them in its `ZoneCounters` for the admin socket's "top"
+//! command.
+
+use crate::config::TopTalkersConfig;
+use cfwevent::parser::{self, CfwEvType, Direction, TrafficEvent};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::net::Ipv6Addr;
+use uuid::Uuid;
+
+/// Most distinct addresses counted per bucket, so a scan from spoofed addresses can't grow a
+/// zone's counts without bound. Addresses already being counted keep being counted.
+const MAX_ADDRESSES: usize = 10_000;
+
+/// A remote address and the number of its events that were blocked
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Talker {
+    #[serde(serialize_with = "parser::serialize_ip")]
+    pub address: Ipv6Addr,
+    pub blocks: u64,
+    /// Blocks per second over the window
+    pub per_sec: f64,
+}
+
+/// A rule and the number of events logged for it
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TopRule {
+    pub rule: Uuid,
+    pub events: u64,
+    /// Events per second over the window
+    pub per_sec: f64,
+}
+
+/// The top addresses and rules over a window
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Top {
+    /// Seconds the counts cover, less than "top_talkers.window_secs" until the zone has been
+    /// logging for that long
+    pub window_secs: u64,
+    pub talkers: Vec<Talker>,
+    pub rules: Vec<TopRule>,
+}
+
+/// One interval's counts
+#[derive(Default)]
+struct Bucket {
+    blocks: HashMap<Ipv6Addr, u64>,
+    rules: HashMap<Uuid, u64>,
+}
+
+/// The `count` keys with the highest totals over `buckets`, most first with ties broken by key so
+/// the order is stable
+fn top<K, F>(buckets: &VecDeque<Bucket>, counts: F, count: usize) -> Vec<(K, u64)>
+where
+    K: Copy + Eq + Hash + Ord,
+    F: Fn(&Bucket) -> &HashMap<K, u64>,
+{
+    let mut totals: HashMap<K, u64> = HashMap::new();
+    for bucket in buckets {
+        for (key, n) in counts(bucket) {
+            *totals.entry(*key).or_insert(0) += n;
+        }
+    }
+    let mut top: Vec<(K, u64)> = totals.into_iter().collect();
+    top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    top.truncate(count);
+    top
+}
+
+/// A zone's blocked event counts by remote address, and event counts by rule
+pub struct TopTalkers {
+    count: usize,
+    interval_secs: u64,
+    /// Buckets making up the window, the newest at the back
+    buckets: VecDeque<Bucket>,
+    window: usize,
+}
+
+impl TopTalkers {
+    pub fn new(config: &TopTalkersConfig) -> Self {
+        // Round the window up to a whole number of intervals
+        let window = (config.window_secs + config.interval_secs - 1) / config.interval_secs;
+        let mut buckets = VecDeque::new();
+        buckets.push_back(Bucket::default());
+        TopTalkers {
+            count: config.count,
+            interval_secs: config.interval_secs,
+            buckets,
+            window: window as usize,
+        }
+    }
+
+    /// Count the event towards its rule, and its remote address if it was blocked
+    pub fn event_written(&mut self, event: &TrafficEvent) {
+        let bucket = self.buckets.back_mut().expect("there is always a bucket");
+        // Rules come from the zone's own ruleset, so there is no need to bound them
+        *bucket.rules.entry(event.rule_uuid).or_insert(0) += 1;
+        if event.event != CfwEvType::Block {
+            return;
+        }
+        let remote = match event.direction {
+            Direction::In => event.source_ip,
+            Direction::Out => event.destination_ip,
+        };
+        if bucket.blocks.len() < MAX_ADDRESSES || bucket.blocks.contains_key(&remote) {
+            *bucket.blocks.entry(remote).or_insert(0) += 1;
+        }
+    }
+
+    /// End the current interval, returning the top talkers and rules over the window
+    pub fn take(&mut self) -> Top {
+        let window_secs = self.buckets.len() as u64 * self.interval_secs;
+        let rate = |n: u64| n as f64 / window_secs as f64;
+        let talkers = top(&self.buckets, |bucket| &bucket.blocks, self.count)
+            .into_iter()
+            .map(|(address, blocks)| Talker {
+                address,
+                blocks,
+                per_sec: rate(blocks),
+            })
+            .collect();
+        let rules = top(&self.buckets, |bucket| &bucket.rules, self.count)
+            .into_iter()
+            .map(|(rule, events)| TopRule {
+                rule,
+                events,
+                per_sec: rate(events),
+            })
+            .collect();
+        if self.buckets.len() >= self.window {
+            self.buckets.pop_front();
+        }
+        self.buckets.push_back(Bucket::default());
+        Top {
+            window_secs,
+            talkers,
+            rules,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutils::{traffic_event, RULE};
+
+    #[test]
+    fn top_talkers_over_the_window() {
+        let config = TopTalkersConfig {
+            count: 2,
+            interval_secs: 60,
+            window_secs: 120,
+        };
+        let mut talkers = TopTalkers::new(&config);
+        let first = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        let second = traffic_event(CfwEvType::Block, "::ffff:192.0.2.2", 22, RULE);
+        let allowed = traffic_event(CfwEvType::Begin, "::ffff:192.0.2.3", 22, RULE);
+        for _ in 0..3 {
+            talkers.event_written(&first);
+        }
+        talkers.event_written(&second);
+        talkers.event_written(&allowed);
+        let top = talkers.take();
+        assert_eq!(
+            top.talkers,
+            vec![
+                Talker {
+                    address: "::ffff:192.0.2.1".parse().unwrap(),
+                    blocks: 3,
+                    per_sec: 0.05,
+                },
+                Talker {
+                    address: "::ffff:192.0.2.2".parse().unwrap(),
+                    blocks: 1,
+                    per_sec: 1.0 / 60.0,
+                },
+            ],
+            "only blocked events are counted"
+        );
+        let rule = top.rules[0].rule;
+        assert_eq!(top.rules.len(), 1);
+        assert_eq!(
+            top.rules[0].events, 5,
+            "every event counts towards its rule"
+        );
+        assert_eq!(top.window_secs, 60, "the window isn't full yet");
+
+        for _ in 0..4 {
+            talkers.event_written(&second);
+        }
+        let top = talkers.take();
+        assert_eq!(top.talkers[0].blocks, 5, "the window covers both intervals");
+        assert_eq!(top.talkers.len(), 2, "only the top addresses are kept");
+        assert_eq!(
+            top.rules,
+            vec![TopRule {
+                rule,
+                events: 9,
+                per_sec: 0.075,
+            }]
+        );
+
+        let top = talkers.take();
+        assert_eq!(
+            top.talkers[0].blocks, 4,
+            "the first interval left the window"
+        );
+        assert_eq!(top.window_secs, 120);
+        let top = talkers.take();
+        assert!(top.talkers.is_empty() && top.rules.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Templates for sinks that need records in a line format other than json, such as the format an
+//! existing in-house parser expects. A template is plain text with record fields substituted for
+//! `{{ field }}` placeholders, and is compiled once when the configuration is loaded:
+//!
+//! ```text
+//! {{ timestamp }} {{ vm }} {{ event }} {{ protocol }} {{ source_ip }}:{{ source_port }} -> {{ destination_ip }}:{{ destination_port }}
+//! ```
+//!
+//! Fields are named the same way they are in the zone's log file, nested fields can be reached
+//! with dots such as `{{ a.b }}`, and `{{{{` is a literal `{{`. Strings are substituted without
+//! their quotes, fields a record doesn't have are substituted with nothing, and objects or arrays
+//! are substituted as json.
+
+use crate::sink::Record;
+use serde::de::{self, Deserialize, Deserializer};
+use serde_json::Value;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Field(Vec<String>),
+}
+
+/// A compiled template
+#[derive(Clone)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(s: &str) -> Result<Template, String> {
+        let mut segments = vec![];
+        let mut literal = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("{{") {
+            literal.push_str(&rest[..start]);
+            rest = &rest[start + 2..];
+            if rest.starts_with("{{") {
+                literal.push_str("{{");
+                rest = &rest[2..];
+                continue;
+            }
+            let end = rest
+                .find("}}")
+                .ok_or_else(|| "unterminated \"{{\"".to_owned())?;
+            let name = rest[..end].trim();
+            let path: Vec<String> = name.split('.').map(str::to_owned).collect();
+            if path
+                .iter()
+                .any(|key| key.is_empty() || key.contains(char::is_whitespace))
+            {
+                return Err(format!("invalid field \"{}\"", name));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(std::mem::take(&mut literal)));
+            }
+            segments.push(Segment::Field(path));
+            rest = &rest[end + 2..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+        Ok(Template {
+            source: s.to_owned(),
+            segments,
+        })
+    }
+
+    /// Render the record into `out`
+    pub fn render(&self, record: &Record<'_>, out: &mut String) -> serde_json::Result<()> {
+        let record = serde_json::to_value(record)?;
+        for segment in &self.segments {
+            let path = match segment {
+                Segment::Literal(s) => {
+                    out.push_str(s);
+                    continue;
+                }
+                Segment::Field(path) => path,
+            };
+            let value = path
+                .iter()
+                .try_fold(&record, |value, key| value.get(key.as_str()));
+            match value {
+                None | Some(Value::Null) => (),
+                Some(Value::String(s)) => out.push_str(s),
+                Some(value) => out.push_str(&value.to_string()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Template({:?})", self.source)
+    }
+}
+
+/// Templates are equal when they were compiled from the same text
+impl PartialEq for Template {
+    fn eq(&self, other: &Template) -> bool {
+        self.source == other.source
+    }
+}
+
+impl<'de> Deserialize<'de> for Template {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Template, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Template::parse(&s)
+            .map_err(|e| de::Error::custom(format!("invalid template \"{}\": {}", s, e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvent};
+
+    fn render(template: &str) -> String {
+        let event = testutils::generate_event();
+        let mut event = parser::cfwevent_parse(event.as_bytes()).unwrap().1;
+        if let CfwEvent::Traffic(ref mut event) = event {
+            event.source_port = 40000;
+            event.destination_port = 22;
+        }
+        let record = Record::new(event, "vm1", "web");
+        let mut out = String::new();
+        Template::parse(template)
+            .unwrap()
+            .render(&record, &mut out)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn templates_render_records() {
+        assert_eq!(
+            render("{{vm}} {{ alias }} {{ event }} {{ protocol }} {{source_port}}->{{destination_port}}"),
+            "vm1 web block TCP 40000->22"
+        );
+        assert_eq!(
+            render("[{{ rule_owner }}]"),
+            "[]",
+            "missing fields are empty"
+        );
+        assert_eq!(render("{{{{vm}}"), "{{vm}}", "escaped braces");
+        assert_eq!(render("no fields"), "no fields");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        for template in &["{{ vm", "{{ }}", "{{ a..b }}", "{{ a b }}"] {
+            assert!(
+                Template::parse(template).is_err(),
+                "{:?} is invalid",
+                template
+            );
+        }
+    }
+}
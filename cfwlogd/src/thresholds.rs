@@ -0,0 +1,536 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Alerting on the remote addresses that set off too many of a zone's events, such as a host
+//! brute forcing ssh. Every entry of "thresholds" counts the zone's blocked events, or those
+//! matching its filter, by remote address (the source of inbound traffic and the destination of
+//! outbound traffic) over a sliding window of "window_secs", counted in one second buckets as
+//! each zone's `ZoneLog` writes them. Once an address has more than "events" of them within the
+//! window a `ThresholdAlert` is raised, and the address isn't alerted on again for that zone until
+//! "cooldown_secs" have passed.
+//!
+//! Alerts are handed to the alert thread, which delivers them to each of the threshold's actions
+//! so a slow destination never holds up logging: a json line in "alerts.log" in the log
+//! directory, a json POST to a webhook, or running a command with the alert as json on its
+//! stdin. Once cfwlogd has dropped its privileges it can no longer start processes, so commands
+//! are run by a `cfwlogd alert-exec` process started along with cfwlogd, which runs one command at
+//! a time as the user cfwlogd runs as, killing any that takes longer than `EXEC_TIMEOUT`.
+
+use crate::config::ThresholdConfig;
+use crate::fileutils;
+#[cfg(feature = "webhook")]
+use crate::http;
+use crate::sink::Record;
+use cfwevent::parser::{self, CfwEvType, CfwEvent, Serialization};
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{self, Sender, TrySendError};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Ipv6Addr;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{ChildStdin, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Most distinct addresses counted over a threshold's window, so a scan from spoofed addresses
+/// can't grow a zone's counts without bound. Addresses already being counted keep being counted.
+const MAX_ADDRESSES: usize = 10_000;
+/// Alerts waiting to be delivered before new ones are dropped
+const QUEUE_LEN: usize = 1024;
+/// How long a command may run before it's killed
+const EXEC_TIMEOUT: Duration = Duration::from_secs(30);
+/// The file alerts are logged to, in the log directory
+const ALERT_LOG: &str = "alerts.log";
+
+lazy_static! {
+    /// Where zones hand their alerts to the alert thread, once `init` has started it. Each alert
+    /// goes along with how the zone serializes, for the alert to be written the same way.
+    static ref ALERTS: Mutex<Option<Sender<(ThresholdAlert, Serialization)>>> = Mutex::new(None);
+}
+
+/// A remote address that crossed a threshold
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ThresholdAlert {
+    /// The threshold's name
+    pub threshold: String,
+    pub vm: String,
+    pub alias: String,
+    #[serde(serialize_with = "parser::serialize_ip")]
+    pub address: Ipv6Addr,
+    /// The address's events within the window
+    pub events: u64,
+    pub window_secs: u64,
+    /// The configured threshold, which `events` is more than
+    pub limit: u64,
+    #[serde(serialize_with = "parser::serialize_timestamp")]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// One threshold's counts for a zone
+struct Counter {
+    config: ThresholdConfig,
+    /// Events by address in each second of the window, the newest at the back
+    buckets: VecDeque<HashMap<Ipv6Addr, u64>>,
+    /// The second since `ZoneThresholds::start` the newest bucket is for
+    newest: u64,
+    /// Every bucket's counts added up
+    totals: HashMap<Ipv6Addr, u64>,
+    last_alert: HashMap<Ipv6Addr, Instant>,
+}
+
+impl Counter {
+    fn new(config: &ThresholdConfig) -> Self {
+        let mut buckets = VecDeque::new();
+        buckets.push_back(HashMap::new());
+        Counter {
+            config: config.clone(),
+            buckets,
+            newest: 0,
+            totals: HashMap::new(),
+            last_alert: HashMap::new(),
+        }
+    }
+
+    /// Whether the threshold counts the record
+    fn counts(&self, record: &Record<'_>) -> bool {
+        match (&self.config.filter, &record.event) {
+            (Some(filter), _) => filter.matches(record),
+            (None, CfwEvent::Traffic(event)) => event.event == CfwEvType::Block,
+            (None, CfwEvent::Unknown(_)) => false,
+        }
+    }
+
+    /// Move the window along to `second`, dropping the buckets that fell out of it
+    fn advance(&mut self, second: u64) {
+        let window = self.config.window_secs;
+        if second >= self.newest + window {
+            self.buckets.clear();
+            self.buckets.push_back(HashMap::new());
+            self.totals.clear();
+            self.newest = second;
+            return;
+        }
+        while self.newest < second {
+            self.buckets.push_back(HashMap::new());
+            self.newest += 1;
+        }
+        while self.buckets.len() as u64 > window {
+            for (address, n) in self.buckets.pop_front().unwrap_or_default() {
+                if let Some(total) = self.totals.get_mut(&address) {
+                    *total -= n;
+                    if *total == 0 {
+                        self.totals.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Count an event from `address`, returning the address's count if it should be alerted on
+    fn count(&mut self, address: Ipv6Addr, second: u64, now: Instant) -> Option<u64> {
+        self.advance(second);
+        if self.totals.len() >= MAX_ADDRESSES && !self.totals.contains_key(&address) {
+            return None;
+        }
+        let bucket = self.buckets.back_mut().expect("there is always a bucket");
+        *bucket.entry(address).or_insert(0) += 1;
+        let total = self.totals.entry(address).or_insert(0);
+        *total += 1;
+        let total = *total;
+        if total <= self.config.events {
+            return None;
+        }
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        if let Some(last) = self.last_alert.get(&address) {
+            if now.duration_since(*last) < cooldown {
+                return None;
+            }
+        }
+        if self.last_alert.len() >= MAX_ADDRESSES {
+            self.last_alert
+                .retain(|_, last| now.duration_since(*last) < cooldown);
+        }
+        self.last_alert.insert(address, now);
+        Some(total)
+    }
+}
+
+/// Every threshold's counts for a zone
+pub struct ZoneThresholds {
+    counters: Vec<Counter>,
+    start: Instant,
+}
+
+impl ZoneThresholds {
+    pub fn new(configs: &[ThresholdConfig], now: Instant) -> Self {
+        ZoneThresholds {
+            counters: configs.iter().map(Counter::new).collect(),
+            start: now,
+        }
+    }
+
+    /// Count a record written to the zone's log, returning the alerts it set off
+    pub fn written(
+        &mut self,
+        record: &Record<'_>,
+        now: Instant,
+        utc: DateTime<Utc>,
+    ) -> Vec<ThresholdAlert> {
+        let event = match &record.event {
+            CfwEvent::Traffic(event) => event,
+            CfwEvent::Unknown(_) => return vec![],
+        };
+        let address = crate::enrich::remote_addr(event);
+        let second = now.duration_since(self.start).as_secs();
+        let mut alerts = vec![];
+        for counter in &mut self.counters {
+            if !counter.counts(record) {
+                continue;
+            }
+            if let Some(events) = counter.count(address, second, now) {
+                alerts.push(ThresholdAlert {
+                    threshold: counter.config.name.clone(),
+                    vm: record.vm.to_owned(),
+                    alias: record.alias.to_owned(),
+                    address,
+                    events,
+                    window_secs: counter.config.window_secs,
+                    limit: counter.config.events,
+                    timestamp: utc,
+                });
+            }
+        }
+        alerts
+    }
+}
+
+/// Hand an alert to the alert thread, dropping it if the thread is too far behind
+pub fn raise(alert: ThresholdAlert) {
+    let alerts = ALERTS.lock().unwrap();
+    let sender = match alerts.as_ref() {
+        Some(sender) => sender,
+        None => return,
+    };
+    match sender.try_send((alert, Serialization::current())) {
+        Ok(()) => (),
+        Err(TrySendError::Full((alert, _))) => warn!(
+            "too many alerts are waiting to be delivered, dropping the {} alert for {}",
+            alert.threshold, alert.vm
+        ),
+        Err(TrySendError::Disconnected(_)) => (),
+    }
+}
+
+/// What `cfwlogd alert-exec` is sent for each alert
+#[derive(Debug, Deserialize, Serialize)]
+struct ExecRequest {
+    command: Vec<String>,
+    alert: serde_json::Value,
+}
+
+/// Start the `cfwlogd alert-exec` process that runs the thresholds' commands, if any of them has
+/// one. This has to happen while we can still start processes, and it's started as `user` if
+/// cfwlogd is going to run as one.
+pub fn spawn_exec(
+    configs: &[ThresholdConfig],
+    user: Option<(libc::uid_t, libc::gid_t)>,
+) -> io::Result<Option<ChildStdin>> {
+    if configs.iter().all(|config| config.exec.is_none()) {
+        return Ok(None);
+    }
+    let mut command = Command::new(std::env::current_exe()?);
+    command.arg("alert-exec").stdin(Stdio::piped());
+    if let Some((uid, gid)) = user {
+        command.uid(uid).gid(gid);
+    }
+    let child = command.spawn()?;
+    info!("running alert commands from process {}", child.id());
+    Ok(child.stdin)
+}
+
+/// Where a threshold's alerts are delivered
+struct Actions {
+    config: ThresholdConfig,
+    #[cfg(feature = "webhook")]
+    webhook: Option<ureq::Agent>,
+}
+
+/// Everything the alert thread delivers alerts with
+struct Delivery {
+    actions: Vec<Actions>,
+    log: Option<File>,
+    exec: Option<ChildStdin>,
+}
+
+impl Delivery {
+    fn deliver(&mut self, alert: &ThresholdAlert) {
+        warn!(
+            "{}: {} set off {} events in {}s on {}, sending alert",
+            alert.threshold,
+            parser::logged_addr(&alert.address),
+            alert.events,
+            alert.window_secs,
+            alert.vm
+        );
+        let actions = match self
+            .actions
+            .iter()
+            .find(|a| a.config.name == alert.threshold)
+        {
+            Some(actions) => actions,
+            None => return,
+        };
+        let mut json = serde_json::to_vec(alert).expect("failed to serialize alert");
+        if actions.config.alert_log {
+            if let Some(log) = &mut self.log {
+                json.push(b'\n');
+                if let Err(e) = log.write_all(&json) {
+                    error!("failed to write alert to {}: {}", ALERT_LOG, e);
+                }
+                json.pop();
+            }
+        }
+        #[cfg(feature = "webhook")]
+        {
+            if let (Some(agent), Some(url)) = (&actions.webhook, &actions.config.webhook) {
+                let resp = agent
+                    .post(url)
+                    .timeout_connect(5_000)
+                    .timeout_read(5_000)
+                    .set("Content-Type", "application/json")
+                    .send_bytes(&json);
+                if let Err(e) = http::check_response(resp) {
+                    error!("failed to post alert to {}: {}", url, e);
+                }
+            }
+        }
+        if let (Some(exec), Some(command)) = (&mut self.exec, &actions.config.exec) {
+            let request = ExecRequest {
+                command: command.clone(),
+                alert: serde_json::to_value(alert).expect("failed to serialize alert"),
+            };
+            let mut line = serde_json::to_vec(&request).expect("failed to serialize request");
+            line.push(b'\n');
+            if let Err(e) = exec.write_all(&line) {
+                error!("failed to hand alert to the alert-exec process: {}", e);
+            }
+        }
+    }
+}
+
+/// Set up every threshold's actions and start the alert thread. `exec` is the process from
+/// `spawn_exec`, and "alerts.log" is opened in `dir`. Webhooks are resolved and the log opened
+/// here, so this has to happen before we chroot.
+pub fn init(
+    configs: &[ThresholdConfig],
+    exec: Option<ChildStdin>,
+    dir: &Path,
+) -> io::Result<thread::JoinHandle<()>> {
+    let log = if configs.iter().any(|config| config.alert_log) {
+        Some(fileutils::open_append_nofollow(
+            &File::open(dir)?,
+            ALERT_LOG,
+        )?)
+    } else {
+        None
+    };
+    let mut actions = Vec::with_capacity(configs.len());
+    for config in configs {
+        actions.push(Actions {
+            config: config.clone(),
+            #[cfg(feature = "webhook")]
+            webhook: match &config.webhook {
+                Some(url) => Some(http::pinned_agent(url)?),
+                None => None,
+            },
+        });
+    }
+    let mut delivery = Delivery { actions, log, exec };
+    let (tx, rx) = channel::bounded(QUEUE_LEN);
+    *ALERTS.lock().unwrap() = Some(tx);
+    thread::Builder::new()
+        .name("threshold_alerts".to_owned())
+        .spawn(move || {
+            for (alert, serialization) in rx.iter() {
+                let _serialization = serialization.apply();
+                delivery.deliver(&alert);
+            }
+        })
+}
+
+/// Run a command with `input` on its stdin, killing it if it runs for longer than `timeout`
+fn run_command(command: &[String], input: &[u8], timeout: Duration) -> io::Result<()> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Ok(()),
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A command that doesn't read its input is fine
+        let _ = stdin.write_all(input);
+    }
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if !status.success() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("{} exited with {}", program, status),
+                ));
+            }
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} was killed after {:?}", program, timeout),
+            ));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Run the "alert-exec" subcommand, which runs the command of each request read from stdin until
+/// cfwlogd goes away, returning the process's exit code.
+pub fn run_exec(args: &[String]) -> i32 {
+    if !args.is_empty() {
+        eprintln!("usage: cfwlogd alert-exec");
+        return 2;
+    }
+    let stdin = io::stdin();
+    for line in BufReader::new(stdin.lock()).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("cfwlogd alert-exec: failed to read request: {}", e);
+                return 1;
+            }
+        };
+        let request: ExecRequest = match serde_json::from_str(&line) {
+            Ok(request) => request,
+            Err(e) => {
+                eprintln!("cfwlogd alert-exec: invalid request: {}", e);
+                continue;
+            }
+        };
+        let mut input = request.alert.to_string().into_bytes();
+        input.push(b'\n');
+        if let Err(e) = run_command(&request.command, &input, EXEC_TIMEOUT) {
+            eprintln!("cfwlogd alert-exec: {}", e);
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::TrafficEvent;
+    use testutils::{traffic_event, RULE};
+
+    fn config() -> ThresholdConfig {
+        ThresholdConfig {
+            name: "scan".to_owned(),
+            events: 3,
+            window_secs: 10,
+            cooldown_secs: 60,
+            filter: None,
+            exec: None,
+            webhook: None,
+            alert_log: true,
+        }
+    }
+
+    fn record(event: TrafficEvent) -> Record<'static> {
+        Record::new(CfwEvent::Traffic(event), "vm1", "web")
+    }
+
+    #[test]
+    fn addresses_over_the_threshold() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut thresholds = ZoneThresholds::new(&[config()], start);
+        let scanner = traffic_event(CfwEvType::Block, "::ffff:192.0.2.1", 22, RULE);
+        let other = traffic_event(CfwEvType::Block, "::ffff:192.0.2.2", 22, RULE);
+        let allowed = traffic_event(CfwEvType::Begin, "::ffff:192.0.2.2", 22, RULE);
+        let (scanner, other, allowed) = (record(scanner), record(other), record(allowed));
+
+        for secs in 0..3 {
+            assert!(thresholds
+                .written(&scanner, at(secs), Utc::now())
+                .is_empty());
+            assert!(thresholds.written(&other, at(secs), Utc::now()).is_empty());
+            assert!(
+                thresholds
+                    .written(&allowed, at(secs), Utc::now())
+                    .is_empty(),
+                "only blocked events count"
+            );
+        }
+        let alerts = thresholds.written(&scanner, at(3), Utc::now());
+        assert_eq!(alerts.len(), 1, "more than 3 events in the window");
+        assert_eq!(
+            alerts[0].address,
+            "::ffff:192.0.2.1".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!((alerts[0].events, alerts[0].limit), (4, 3));
+        assert_eq!(alerts[0].alias, "web");
+        assert!(
+            thresholds.written(&scanner, at(4), Utc::now()).is_empty(),
+            "cooling down"
+        );
+
+        assert!(
+            thresholds.written(&other, at(12), Utc::now()).is_empty(),
+            "the first events fell out of the window"
+        );
+        for secs in 61..64 {
+            assert!(thresholds
+                .written(&scanner, at(secs), Utc::now())
+                .is_empty());
+        }
+        assert_eq!(
+            thresholds.written(&scanner, at(64), Utc::now()).len(),
+            1,
+            "alerts again after the cooldown"
+        );
+    }
+
+    #[test]
+    fn commands_are_run() {
+        let dir = Path::new("/var/tmp/cfwlogd-tests/thresholds");
+        std::fs::create_dir_all(dir).unwrap();
+        let out = dir.join("alert.json");
+        let _ = std::fs::remove_file(&out);
+        let command = vec![
+            "/bin/sh".to_owned(),
+            "-c".to_owned(),
+            format!("cat > {}", out.display()),
+        ];
+        run_command(&command, b"{\"threshold\":\"scan\"}\n", EXEC_TIMEOUT).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&out).unwrap(),
+            "{\"threshold\":\"scan\"}\n",
+            "the alert is the command's input"
+        );
+
+        let sleep = vec!["/bin/sleep".to_owned(), "10".to_owned()];
+        let err = run_command(&sleep, b"", Duration::from_millis(100)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(run_command(&["/bin/false".to_owned()], b"", EXEC_TIMEOUT).is_err());
+
+        std::fs::remove_dir_all(dir).expect("failed to cleanup test dir");
+    }
+}
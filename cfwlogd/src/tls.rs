@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The TLS client settings shared by the sinks that send records off the CN: syslog, Elasticsearch
+//! and the webhook sink. Each sink's "tls_ca" replaces the bundled Mozilla roots with the CAs the
+//! server's certificate has to chain to, "tls_cert" and "tls_key" are the client certificate
+//! presented for mutual TLS, and "tls_pin_sha256" further limits the server's certificate to those
+//! with one of the given SHA-256 fingerprints.
+//!
+//! Like GeoIP databases, the directories these files are in are opened before we chroot, and the
+//! files are read from them at startup and again on every SIGHUP, so certificates can be renewed
+//! in place. The connections that are already up are kept, only new connections use the reloaded
+//! files. Files that fail to load on SIGHUP are logged and the settings already loaded are kept.
+
+use crate::fileutils;
+use arc_swap::ArcSwap;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, Session, StreamOwned, TLSError, WebPKIVerifier,
+};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+lazy_static! {
+    /// Every sink's settings, for reloading them on SIGHUP
+    static ref SETTINGS: Mutex<Vec<Arc<Tls>>> = Mutex::new(vec![]);
+}
+
+/// A file read at startup and again on every SIGHUP
+struct Source {
+    dir: File,
+    name: String,
+}
+
+impl Source {
+    fn open(path: &Path) -> io::Result<Source> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid file path");
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(invalid)?
+            .to_owned();
+        let dir = File::open(path.parent().ok_or_else(invalid)?)?;
+        Ok(Source { dir, name })
+    }
+
+    fn read(&self) -> io::Result<Vec<u8>> {
+        let mut buf = vec![];
+        fileutils::open_read_nofollow(&self.dir, &self.name)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// The SHA-256 fingerprint in a "tls_pin_sha256" entry, 64 hex digits with optional colons
+fn parse_pin(pin: &str) -> Option<[u8; 32]> {
+    let digits: Vec<u8> = pin.bytes().filter(|b| *b != b':').collect();
+    if digits.len() != 64 {
+        return None;
+    }
+    let mut fingerprint = [0; 32];
+    for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        *byte = u8::from_str_radix(pair, 16).ok()?;
+    }
+    Some(fingerprint)
+}
+
+/// Verifies the server's certificate chain as usual, and then that the certificate itself is one
+/// of the pinned ones
+struct PinnedVerifier {
+    pins: Vec<[u8; 32]>,
+    webpki: WebPKIVerifier,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: webpki::DNSNameRef<'_>,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        let verified =
+            self.webpki
+                .verify_server_cert(roots, presented_certs, dns_name, ocsp_response)?;
+        let leaf = presented_certs
+            .first()
+            .ok_or_else(|| TLSError::General("no server certificate".to_owned()))?;
+        let fingerprint = Sha256::digest(&leaf.0);
+        if self.pins.iter().any(|pin| pin[..] == fingerprint[..]) {
+            Ok(verified)
+        } else {
+            Err(TLSError::General(
+                "the server's certificate isn't one of tls_pin_sha256".to_owned(),
+            ))
+        }
+    }
+}
+
+/// Build the client settings out of the files' contents
+fn client_config(
+    ca: Option<&[u8]>,
+    identity: Option<(&[u8], &[u8])>,
+    pins: &[[u8; 32]],
+) -> io::Result<ClientConfig> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let mut config = ClientConfig::new();
+    match ca {
+        Some(mut ca) => {
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut ca)
+                .map_err(|()| invalid("tls_ca isn't a PEM file"))?;
+            if added == 0 {
+                return Err(invalid("tls_ca has no CA certificates"));
+            }
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    if let Some((mut cert, key)) = identity {
+        let certs = pemfile::certs(&mut cert).map_err(|()| invalid("tls_cert isn't a PEM file"))?;
+        if certs.is_empty() {
+            return Err(invalid("tls_cert has no certificates"));
+        }
+        let mut keys = pemfile::pkcs8_private_keys(&mut &key[..])
+            .map_err(|()| invalid("tls_key isn't a PEM file"))?;
+        if keys.is_empty() {
+            keys = pemfile::rsa_private_keys(&mut &key[..])
+                .map_err(|()| invalid("tls_key isn't a PEM file"))?;
+        }
+        let key = keys
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid("tls_key has no PKCS#8 or RSA private key"))?;
+        config
+            .set_single_client_cert(certs, key)
+            .map_err(|e| invalid(&format!("tls_key doesn't go with tls_cert: {}", e)))?;
+    }
+    if !pins.is_empty() {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(PinnedVerifier {
+                pins: pins.to_vec(),
+                webpki: WebPKIVerifier::new(),
+            }));
+    }
+    Ok(config)
+}
+
+/// A sink's TLS client settings, which are reloaded on SIGHUP
+pub struct Tls {
+    /// Which sink the settings are for, for logging
+    sink: String,
+    ca: Option<Source>,
+    identity: Option<(Source, Source)>,
+    pins: Vec<[u8; 32]>,
+    config: ArcSwap<ClientConfig>,
+}
+
+impl Tls {
+    /// Load a sink's settings, which has to happen before we chroot. The config makes sure that
+    /// `cert` and `key` go together and that the pins are valid.
+    pub fn open(
+        sink: &str,
+        ca: Option<&Path>,
+        cert: Option<&Path>,
+        key: Option<&Path>,
+        pins: &[String],
+    ) -> io::Result<Arc<Tls>> {
+        let pins = pins
+            .iter()
+            .map(|pin| {
+                parse_pin(pin).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, format!("invalid pin {}", pin))
+                })
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let identity = match (cert, key) {
+            (Some(cert), Some(key)) => Some((Source::open(cert)?, Source::open(key)?)),
+            _ => None,
+        };
+        let ca = ca.map(Source::open).transpose()?;
+        let config = Tls::load(&ca, &identity, &pins)?;
+        let tls = Arc::new(Tls {
+            sink: sink.to_owned(),
+            ca,
+            identity,
+            pins,
+            config: ArcSwap::new(Arc::new(config)),
+        });
+        SETTINGS.lock().unwrap().push(Arc::clone(&tls));
+        Ok(tls)
+    }
+
+    fn load(
+        ca: &Option<Source>,
+        identity: &Option<(Source, Source)>,
+        pins: &[[u8; 32]],
+    ) -> io::Result<ClientConfig> {
+        let ca = ca.as_ref().map(Source::read).transpose()?;
+        let identity = match identity {
+            Some((cert, key)) => Some((cert.read()?, key.read()?)),
+            None => None,
+        };
+        client_config(
+            ca.as_deref(),
+            identity
+                .as_ref()
+                .map(|(cert, key)| (cert.as_slice(), key.as_slice())),
+            pins,
+        )
+    }
+
+    fn reload(&self) {
+        match Tls::load(&self.ca, &self.identity, &self.pins) {
+            Ok(config) => self.config.store(Arc::new(config)),
+            // CMON TRITON-1755
+            Err(e) => error!("failed to reload the {} sink's TLS files: {}", self.sink, e),
+        }
+    }
+
+    /// The settings new connections are made with
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.config.load()
+    }
+
+    /// Start a TLS session with `server_name` over `stream`, completing the handshake so that a
+    /// server that can't be verified fails the connection rather than the first write
+    #[cfg_attr(not(feature = "syslog-tls"), allow(dead_code))]
+    pub fn connect(
+        &self,
+        server_name: &str,
+        stream: TcpStream,
+    ) -> io::Result<StreamOwned<ClientSession, TcpStream>> {
+        let name = webpki::DNSNameRef::try_from_ascii_str(server_name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a valid TLS server name", server_name),
+            )
+        })?;
+        let session = ClientSession::new(&self.client_config(), name);
+        let mut stream = StreamOwned::new(session, stream);
+        while stream.sess.is_handshaking() {
+            stream.sess.complete_io(&mut stream.sock)?;
+        }
+        Ok(stream)
+    }
+}
+
+/// Reload every sink's TLS files, see the "tls" module
+pub fn reload() {
+    for tls in SETTINGS.lock().unwrap().iter() {
+        tls.reload();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pins_are_parsed() {
+        let hex = "5d5b09f6dcb2d53a5fffc60c4ac0d55fabdf556069d6631545f42aa6e3500f2e";
+        let pin = parse_pin(hex).expect("valid pin");
+        assert_eq!(pin[0], 0x5d);
+        assert_eq!(pin[31], 0x2e);
+        let colons: Vec<String> = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| String::from_utf8(pair.to_vec()).unwrap().to_uppercase())
+            .collect();
+        assert_eq!(
+            parse_pin(&colons.join(":")),
+            Some(pin),
+            "openssl's fingerprint format"
+        );
+        assert_eq!(parse_pin(&hex[2..]), None, "too short");
+        assert_eq!(parse_pin(&hex.replace('5', "g")), None);
+    }
+
+    #[test]
+    fn invalid_files_are_refused() {
+        assert!(client_config(None, None, &[]).is_ok(), "the bundled roots");
+        assert!(client_config(Some(&b"not a certificate"[..]), None, &[]).is_err());
+        assert!(
+            client_config(None, Some((&b""[..], &b""[..])), &[]).is_err(),
+            "a client certificate is required along with its key"
+        );
+    }
+}
@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Uploading each zone's rotated log files to an object store, so that the CN's disk only stages
+//! them. Every time a `Logger` rotates its log the rotated files the "archive" module is done with
+//! are added to a ledger of pending uploads, which is kept in "uploads.json" in the log directory
+//! so that the uploads pending when cfwlogd stops are picked up again once it starts. Where each
+//! file goes is worked out when it's added, from the store's path template with the zone's
+//! `{owner_uuid}` and `{vm}`, the `{date}` it was added and its `{file}` name substituted.
+//!
+//! The "upload" thread works through the ledger in the order files were added. An upload that
+//! fails is retried with exponential backoff up to "max_backoff_secs", while the files after it
+//! carry on, and a file that was uploaded is removed along with its handoff marker. A store can
+//! have files that keep failing moved to a dead-letter directory after too many attempts, where
+//! they're kept under the same "<owner_uuid>/<vm>" directories as in the log directory for an
+//! operator to look into, rather than being retried forever. The thread is only started once we
+//! have chrooted, since the ledger names files by their path in there.
+
+use crate::archive;
+use crate::config::Config;
+use crate::fileutils;
+use crate::layout::Layout;
+use crate::logger;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The ledger's name in the log directory
+const LEDGER: &str = "uploads.json";
+/// The first delay before retrying an upload
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// The uploader started at startup, if an object store is configured
+    static ref UPLOADER: Mutex<Option<Arc<Uploader>>> = Mutex::new(None);
+}
+
+/// Somewhere rotated files are uploaded to
+pub trait Store: Send {
+    /// Upload `file`, which is `size` bytes long, to `remote`
+    fn put(&mut self, file: File, size: u64, remote: &str) -> io::Result<()>;
+}
+
+/// Where files that keep failing to upload are moved to
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeadLetter {
+    /// How many failures in a row a file is moved after
+    pub max_failures: u32,
+    pub dir: PathBuf,
+}
+
+/// How files are uploaded to a store
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// The store's path template, see the module's documentation
+    pub path: String,
+    pub max_backoff: Duration,
+    pub dead_letter: Option<DeadLetter>,
+}
+
+/// A file waiting to be uploaded
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct Pending {
+    /// The rotated file, in the log directory
+    file: PathBuf,
+    /// Where it's uploaded to
+    remote: String,
+    /// How many times uploading it has failed
+    #[serde(default)]
+    failures: u32,
+    /// When it's tried again after failing, which starts over when cfwlogd restarts
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+/// The first pending file that's due, or when the next one is if there are any
+fn due(pending: &[Pending], now: Instant) -> Result<Pending, Option<Instant>> {
+    match pending
+        .iter()
+        .find(|p| p.retry_at.map_or(true, |at| at <= now))
+    {
+        Some(next) => Ok(next.clone()),
+        None => Err(pending.iter().filter_map(|p| p.retry_at).min()),
+    }
+}
+
+/// Upload a pending file to `store`
+fn upload(store: &mut dyn Store, pending: &Pending) -> io::Result<()> {
+    let file = File::open(&pending.file)?;
+    let size = file.metadata()?.len();
+    store.put(file, size, &pending.remote)
+}
+
+struct Uploader {
+    /// The log directory the ledger is kept in
+    dir: File,
+    settings: Settings,
+    pending: Mutex<Vec<Pending>>,
+    /// Signalled whenever files are added
+    added: Condvar,
+}
+
+impl Uploader {
+    /// An uploader keeping its ledger in `dir`, picking up whatever the ledger already lists
+    fn new(dir: File, settings: Settings) -> io::Result<Uploader> {
+        let pending = match fileutils::open_read_nofollow(&dir, LEDGER) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        Ok(Uploader {
+            dir,
+            settings,
+            pending: Mutex::new(pending),
+            added: Condvar::new(),
+        })
+    }
+
+    /// Write out the ledger. A failure is only logged, the uploads carry on regardless.
+    fn save(&self, pending: &[Pending]) {
+        let saved = serde_json::to_vec(pending)
+            .map_err(io::Error::from)
+            .and_then(|contents| fileutils::replace_file_nofollow(&self.dir, LEDGER, &contents));
+        if let Err(e) = saved {
+            error!("failed to save the pending uploads: {}", e);
+        }
+    }
+
+    /// Where a zone's file is uploaded to, see the module's documentation
+    fn remote(&self, layout: &Layout, vm: &str, date: &str, file: &Path) -> Option<String> {
+        let name = file.file_name()?.to_str()?;
+        let remote = self
+            .settings
+            .path
+            .replace("{owner_uuid}", &layout.owner_uuid)
+            .replace("{vm}", vm)
+            .replace("{date}", date)
+            .replace("{file}", name);
+        Some(remote)
+    }
+
+    /// Add the zone's `files` that aren't pending already
+    fn add(&self, layout: &Layout, vm: &str, files: Vec<PathBuf>) {
+        let date = Utc::now().format("%Y-%m-%d").to_string();
+        let mut pending = self.pending.lock().unwrap();
+        let before = pending.len();
+        for file in files {
+            // The upload thread may have just uploaded and removed it
+            if !file.exists() || pending.iter().any(|p| p.file == file) {
+                continue;
+            }
+            if let Some(remote) = self.remote(layout, vm, &date, &file) {
+                pending.push(Pending {
+                    file,
+                    remote,
+                    failures: 0,
+                    retry_at: None,
+                });
+            }
+        }
+        if pending.len() > before {
+            self.save(&pending);
+            self.added.notify_one();
+        }
+    }
+
+    /// How long to wait after an upload failed `failures` times in a row
+    fn backoff(&self, failures: u32) -> Duration {
+        let max_backoff = self.settings.max_backoff;
+        INITIAL_BACKOFF
+            .checked_mul(1 << failures.saturating_sub(1).min(16))
+            .map_or(max_backoff, |backoff| backoff.min(max_backoff))
+    }
+
+    /// The dead-letter directory a file that failed `failures` times in a row is moved to, if it's
+    /// been too many
+    fn dead_letter(&self, file: &Path, failures: u32) -> Option<PathBuf> {
+        let dead_letter = self.settings.dead_letter.as_ref()?;
+        if failures < dead_letter.max_failures {
+            return None;
+        }
+        let dir = file.parent()?;
+        Some(match dir.strip_prefix(logger::LOG_DIR) {
+            Ok(relative) => dead_letter.dir.join(relative),
+            Err(_) => dead_letter.dir.clone(),
+        })
+    }
+
+    /// Record how uploading a pending file went: it's removed once uploaded, once the file is gone,
+    /// or once it's been moved to the dead-letter directory, and otherwise tried again later
+    fn finish(&self, uploaded: &Pending, result: io::Result<()>, now: Instant) {
+        let mut pending = self.pending.lock().unwrap();
+        let i = match pending.iter().position(|p| p.file == uploaded.file) {
+            Some(i) => i,
+            None => return,
+        };
+        match result {
+            Ok(()) => {
+                info!(
+                    "uploaded {} to {}",
+                    uploaded.file.display(),
+                    uploaded.remote
+                );
+                if let Err(e) = archive::remove_finalized(&uploaded.file) {
+                    warn!(
+                        "failed to remove {} once uploaded: {}",
+                        uploaded.file.display(),
+                        e
+                    );
+                }
+                pending.remove(i);
+            }
+            Err(e) if !uploaded.file.exists() => {
+                warn!(
+                    "no longer uploading {}, it's gone: {}",
+                    uploaded.file.display(),
+                    e
+                );
+                pending.remove(i);
+            }
+            Err(e) => {
+                let failed = &mut pending[i];
+                failed.failures += 1;
+                if let Some(dir) = self.dead_letter(&uploaded.file, failed.failures) {
+                    error!(
+                        "giving up on uploading {} to {} after {} attempts, moving it to {}: {}",
+                        uploaded.file.display(),
+                        uploaded.remote,
+                        failed.failures,
+                        dir.display(),
+                        e
+                    );
+                    if let Err(e) = archive::move_finalized(&uploaded.file, &dir) {
+                        error!("failed to move {}: {}", uploaded.file.display(), e);
+                    }
+                    pending.remove(i);
+                    self.save(&pending);
+                    return;
+                }
+                let backoff = self.backoff(failed.failures);
+                failed.retry_at = Some(now + backoff);
+                warn!(
+                    "failed to upload {} to {}, retrying in {}s: {}",
+                    uploaded.file.display(),
+                    uploaded.remote,
+                    backoff.as_secs(),
+                    e
+                );
+            }
+        }
+        self.save(&pending);
+    }
+
+    /// Upload files as they come due, forever
+    fn run(&self, mut store: Box<dyn Store>) {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            match due(&pending, now) {
+                Ok(next) => {
+                    drop(pending);
+                    let result = upload(&mut *store, &next);
+                    self.finish(&next, result, Instant::now());
+                    pending = self.pending.lock().unwrap();
+                }
+                Err(Some(at)) => pending = self.added.wait_timeout(pending, at - now).unwrap().0,
+                Err(None) => pending = self.added.wait(pending).unwrap(),
+            }
+        }
+    }
+}
+
+/// Start uploading rotated files to `store`, at the paths the settings' template gives them, once
+/// we have chrooted
+pub fn start(store: Box<dyn Store>, settings: Settings) -> io::Result<thread::JoinHandle<()>> {
+    let dir = fileutils::create_dir_all_nofollow(Path::new(logger::LOG_DIR))?;
+    let uploader = Arc::new(Uploader::new(dir, settings)?);
+    *UPLOADER.lock().unwrap() = Some(Arc::clone(&uploader));
+    thread::Builder::new()
+        .name("upload".to_owned())
+        .spawn(move || uploader.run(store))
+}
+
+/// Add the zone's rotated files that are ready to the pending uploads, when uploads are configured
+pub fn queue_rotated(layout: &Layout, vm: &str, active: &str, config: &Config) {
+    let uploader = match UPLOADER.lock().unwrap().as_ref() {
+        Some(uploader) => Arc::clone(uploader),
+        None => return,
+    };
+    match archive::finalized_files(&layout.dir, active, config) {
+        Ok(files) => uploader.add(layout, vm, files),
+        Err(e) => error!("failed to find {}'s rotated logs to upload: {}", vm, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vminfod_client::Zone;
+
+    /// The layout of the zone `vm` of `owner_uuid` under the default config
+    fn layout(vm: &str, owner_uuid: &str) -> Layout {
+        let zone = Zone {
+            uuid: vm.to_owned(),
+            owner_uuid: owner_uuid.to_owned(),
+            ..testutils::create_zone()
+        };
+        Layout::for_zone(&zone, &Config::default())
+    }
+
+    /// Stores what's uploaded, after failing the first `fail` uploads
+    struct TestStore {
+        fail: usize,
+        uploaded: Vec<(String, Vec<u8>)>,
+    }
+
+    impl Store for TestStore {
+        fn put(&mut self, mut file: File, size: u64, remote: &str) -> io::Result<()> {
+            if self.fail > 0 {
+                self.fail -= 1;
+                return Err(io::Error::new(io::ErrorKind::Other, "unavailable"));
+            }
+            let mut contents = vec![];
+            io::Read::read_to_end(&mut file, &mut contents)?;
+            assert_eq!(contents.len() as u64, size);
+            self.uploaded.push((remote.to_owned(), contents));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rotated_files_are_uploaded() {
+        let base: PathBuf = [logger::LOG_DIR, "upload-tests"].iter().collect();
+        let _ = std::fs::remove_dir_all(&base);
+        let layout = Layout {
+            dir: base.join("zone"),
+            ..layout("vm1", "owner1")
+        };
+        std::fs::create_dir_all(&layout.dir).unwrap();
+        let rotated = layout.path("20200102T030405Z.log");
+        std::fs::write(&rotated, b"{}\n").unwrap();
+        std::fs::write(layout.path("current.log"), b"").unwrap();
+        let open = || fileutils::create_dir_all_nofollow(&base).unwrap();
+        let settings = Settings {
+            path: "/ops/stor/{owner_uuid}/{vm}/{date}/{file}".to_owned(),
+            max_backoff: Duration::from_secs(4),
+            dead_letter: None,
+        };
+        let uploader = Uploader::new(open(), settings.clone()).unwrap();
+        let files = archive::finalized_files(&layout.dir, "current.log", &Config::default());
+        uploader.add(&layout, "vm1", files.unwrap());
+        uploader.add(&layout, "vm1", vec![rotated.clone()]);
+
+        // What's pending survives a restart
+        let uploader = Uploader::new(open(), settings).unwrap();
+        let mut store = TestStore {
+            fail: 4,
+            uploaded: vec![],
+        };
+        let now = Instant::now();
+        let mut next = due(&uploader.pending.lock().unwrap(), now).unwrap();
+        let date = Utc::now().format("%Y-%m-%d");
+        assert_eq!(
+            next.remote,
+            format!("/ops/stor/owner1/vm1/{}/20200102T030405Z.log", date)
+        );
+        for backoff in &[1, 2, 4, 4] {
+            uploader.finish(&next, upload(&mut store, &next), now);
+            let retry_at = due(&uploader.pending.lock().unwrap(), now).unwrap_err();
+            assert_eq!(retry_at, Some(now + Duration::from_secs(*backoff)));
+            next = due(&uploader.pending.lock().unwrap(), retry_at.unwrap()).unwrap();
+        }
+        uploader.finish(&next, upload(&mut store, &next), now);
+        assert_eq!(store.uploaded.len(), 1);
+        assert_eq!(store.uploaded[0].1, b"{}\n");
+        assert!(!rotated.exists(), "uploaded files are removed");
+        assert!(uploader.pending.lock().unwrap().is_empty());
+        let ledger = std::fs::read_to_string(base.join(LEDGER)).unwrap();
+        assert_eq!(ledger, "[]");
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn persistent_failures_are_dead_lettered() {
+        let base: PathBuf = [logger::LOG_DIR, "dead-letter-tests"].iter().collect();
+        let _ = std::fs::remove_dir_all(&base);
+        let zone = base.join("owner1").join("vm1");
+        std::fs::create_dir_all(&zone).unwrap();
+        let rotated = zone.join("20200102T030405Z.log");
+        std::fs::write(&rotated, b"{}\n").unwrap();
+        std::fs::write(zone.join("20200102T030405Z.log.done"), b"").unwrap();
+        let dead_letter = base.join("dead-letter");
+        let settings = Settings {
+            path: "cfwlogs/{file}".to_owned(),
+            max_backoff: Duration::from_secs(1),
+            dead_letter: Some(DeadLetter {
+                max_failures: 2,
+                dir: dead_letter.clone(),
+            }),
+        };
+        let dir = fileutils::create_dir_all_nofollow(&base).unwrap();
+        let uploader = Uploader::new(dir, settings).unwrap();
+        uploader.add(&layout("vm1", "owner1"), "vm1", vec![rotated.clone()]);
+        let mut store = TestStore {
+            fail: 2,
+            uploaded: vec![],
+        };
+        let now = Instant::now();
+        for _ in 0..2 {
+            let next = due(
+                &uploader.pending.lock().unwrap(),
+                now + Duration::from_secs(60),
+            );
+            let next = next.expect("the upload is due");
+            uploader.finish(&next, upload(&mut store, &next), now);
+        }
+        assert!(uploader.pending.lock().unwrap().is_empty());
+        assert!(store.uploaded.is_empty());
+        assert!(!rotated.exists());
+        let moved = dead_letter
+            .join("dead-letter-tests")
+            .join("owner1")
+            .join("vm1");
+        assert_eq!(
+            std::fs::read(moved.join("20200102T030405Z.log")).unwrap(),
+            b"{}\n"
+        );
+        assert!(moved.join("20200102T030405Z.log.done").exists());
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
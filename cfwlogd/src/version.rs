@@ -0,0 +1,127 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! `cfwlogd version [--json]` prints what this cfwlogd is: its version, the commit and target it
+//! was built from, the record schema version it writes and the optional features it was built
+//! with, so fleet tooling can tell what a CN is running without starting the daemon. The commit
+//! and target are recorded by build.rs, and are left out when they weren't known at build time.
+
+use crate::sink::SCHEMA_VERSION;
+use serde::Serialize;
+
+/// The optional features cfwlogd can be built with, see Cargo.toml
+const FEATURES: &[(&str, bool)] = &[
+    ("binary-formats", cfg!(feature = "binary-formats")),
+    ("cloudwatch", cfg!(feature = "cloudwatch")),
+    ("dynamic-sinks", cfg!(feature = "dynamic-sinks")),
+    ("elasticsearch", cfg!(feature = "elasticsearch")),
+    ("encryption", cfg!(feature = "encryption")),
+    ("fwapi", cfg!(feature = "fwapi")),
+    ("geoip", cfg!(feature = "geoip")),
+    ("grpc", cfg!(feature = "grpc")),
+    ("manta", cfg!(feature = "manta")),
+    ("parquet", cfg!(feature = "parquet")),
+    ("pflog", cfg!(feature = "pflog")),
+    ("protobuf", cfg!(feature = "protobuf")),
+    ("reverse-dns", cfg!(feature = "reverse-dns")),
+    ("s3", cfg!(feature = "s3")),
+    ("syslog-tls", cfg!(feature = "syslog-tls")),
+    ("tls", cfg!(feature = "tls")),
+    ("usdt", cfg!(feature = "usdt")),
+    ("webhook", cfg!(feature = "webhook")),
+    ("websocket", cfg!(feature = "websocket")),
+    ("zstd", cfg!(feature = "zstd")),
+];
+
+#[derive(Debug, Serialize)]
+struct Version {
+    version: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    commit: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<&'static str>,
+    profile: &'static str,
+    schema_version: u64,
+    features: Vec<&'static str>,
+}
+
+impl Version {
+    fn current() -> Self {
+        Version {
+            version: env!("CARGO_PKG_VERSION"),
+            commit: option_env!("CFWLOGD_COMMIT"),
+            target: option_env!("CFWLOGD_TARGET"),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+            schema_version: SCHEMA_VERSION,
+            features: FEATURES
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect(),
+        }
+    }
+}
+
+/// Run the "version" subcommand, returning the process's exit code.
+pub fn run(args: &[String]) -> i32 {
+    let version = Version::current();
+    match args {
+        [] => {
+            println!("cfwlogd {}", version.version);
+            if let Some(commit) = version.commit {
+                println!("commit: {}", commit);
+            }
+            if let Some(target) = version.target {
+                println!("target: {}", target);
+            }
+            println!("profile: {}", version.profile);
+            println!("schema version: {}", version.schema_version);
+            println!("features: {}", version.features.join(" "));
+            0
+        }
+        [flag] if flag == "--json" => match serde_json::to_string(&version) {
+            Ok(json) => {
+                println!("{}", json);
+                0
+            }
+            Err(e) => {
+                eprintln!("failed to serialize version: {}", e);
+                1
+            }
+        },
+        _ => {
+            eprintln!("usage: cfwlogd version [--json]");
+            2
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_json() {
+        let json = serde_json::to_value(Version::current()).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        let features: Vec<&str> = json["features"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|feature| feature.as_str().unwrap())
+            .collect();
+        assert_eq!(
+            features.contains(&"zstd"),
+            cfg!(feature = "zstd"),
+            "only the features built in are listed"
+        );
+    }
+}
@@ -0,0 +1,375 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! A sink that posts records to an arbitrary HTTP endpoint, as batches of newline delimited json.
+//! Each zone's sink batches its records until it has "batch_size" of them or "flush_secs" have
+//! passed, and posts them with the configured "headers", gzipped when "compression" says so. The
+//! endpoint's address is resolved once at startup, see the "http" module, and an "https://"
+//! endpoint is connected to with the "tls_*" settings, see the "tls" module.
+//!
+//! While the endpoint is unreachable or responds with 408, 429 or a 5xx status the batch is
+//! retried with exponential backoff up to "max_backoff_secs". Every zone shares one circuit
+//! breaker: once "breaker_failures" requests in a row have failed, no zone sends anything for
+//! "breaker_secs", after which a single request is let through to try the endpoint again. Like the
+//! Elasticsearch sink's, retrying and waiting on the breaker happen on the thread writing the
+//! sink, so the zone's records queue up behind it, pausing reading from the device once the
+//! `MemoryTracker`'s limit is reached, or with an entry in "sink_queues" filling the sink's queue
+//! until records are left out of it. Batches refused with any other status are logged and dropped.
+
+use crate::config::{Config, WebhookCompression, WebhookConfig};
+use crate::http;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use crate::tls::Tls;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait on the endpoint before giving up on a request
+const REQUEST_TIMEOUT_MS: u64 = 30_000;
+/// The first delay before retrying a batch
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+lazy_static! {
+    /// The endpoint set up at startup, if there is one
+    static ref ENDPOINT: Mutex<Option<Arc<Endpoint>>> = Mutex::new(None);
+}
+
+/// Stops every zone's requests for a while once the endpoint keeps failing
+#[derive(Debug)]
+struct Breaker {
+    /// Failures in a row that open the breaker
+    threshold: u32,
+    open_for: Duration,
+    failures: u32,
+    /// Set while the breaker is open
+    open_until: Option<Instant>,
+}
+
+impl Breaker {
+    fn new(threshold: u32, open_for: Duration) -> Self {
+        Breaker {
+            threshold,
+            open_for,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    /// Whether a request may be sent at `now`, or else how long until one may. Once the breaker
+    /// has been open for long enough one request is let through, and the rest wait on how that
+    /// one goes for another `open_for`.
+    fn permit(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.open_until {
+            Some(until) if now < until => Err(until - now),
+            Some(_) => {
+                self.open_until = Some(now + self.open_for);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count a request the endpoint took, returning whether that closed the breaker
+    fn succeeded(&mut self) -> bool {
+        self.failures = 0;
+        self.open_until.take().is_some()
+    }
+
+    /// Count a failed request, returning whether that opened the breaker
+    fn failed(&mut self, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < self.threshold {
+            return false;
+        }
+        let opened = self.open_until.is_none();
+        self.open_until = Some(now + self.open_for);
+        opened
+    }
+
+    fn is_open(&self) -> bool {
+        self.open_until.is_some()
+    }
+}
+
+/// Why a request has to be retried or given up on
+enum PostError {
+    /// The endpoint is unreachable or overloaded
+    Unavailable(io::Error),
+    /// The endpoint refused the batch
+    Refused(io::Error),
+}
+
+struct Endpoint {
+    agent: ureq::Agent,
+    /// Set for an "https://" endpoint
+    tls: Option<Arc<Tls>>,
+    url: String,
+    headers: Vec<(String, String)>,
+    compression: WebhookCompression,
+    batch_size: usize,
+    flush_interval: Duration,
+    max_backoff: Duration,
+    breaker: Mutex<Breaker>,
+}
+
+impl Endpoint {
+    /// Post one batch
+    fn post(&self, body: &[u8]) -> Result<(), PostError> {
+        let mut request = self.agent.post(&self.url);
+        request
+            .timeout_connect(REQUEST_TIMEOUT_MS)
+            .timeout_read(REQUEST_TIMEOUT_MS)
+            .set("Content-Type", "application/x-ndjson");
+        for (name, value) in &self.headers {
+            request.set(name, value);
+        }
+        if self.compression == WebhookCompression::Gzip {
+            request.set("Content-Encoding", "gzip");
+        }
+        if let Some(tls) = &self.tls {
+            request.set_tls_config(tls.client_config());
+        }
+        let resp = request.send_bytes(body);
+        if let Some(e) = resp.synthetic_error() {
+            return Err(PostError::Unavailable(io::Error::new(
+                io::ErrorKind::Other,
+                e.to_string(),
+            )));
+        }
+        let status = resp.status();
+        if status == 408 || status == 429 || status >= 500 {
+            return Err(PostError::Unavailable(io::Error::new(
+                io::ErrorKind::Other,
+                format!("endpoint responded with {}", resp.status_line()),
+            )));
+        }
+        http::check_response(resp)
+            .map(|_| ())
+            .map_err(PostError::Refused)
+    }
+}
+
+/// The body of a request carrying `lines`
+fn encode_body(lines: &[String], compression: WebhookCompression) -> io::Result<Vec<u8>> {
+    let ndjson = lines.concat().into_bytes();
+    match compression {
+        WebhookCompression::None => Ok(ndjson),
+        WebhookCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&ndjson)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Resolve the endpoint's address, which has to happen before we chroot
+pub fn init(config: &WebhookConfig) -> io::Result<()> {
+    let tls = if config.url.starts_with("https://") {
+        Some(Tls::open(
+            "webhook",
+            config.tls_ca.as_deref(),
+            config.tls_cert.as_deref(),
+            config.tls_key.as_deref(),
+            &config.tls_pin_sha256,
+        )?)
+    } else {
+        None
+    };
+    let endpoint = Endpoint {
+        agent: http::pinned_agent(&config.url)?,
+        tls,
+        url: config.url.clone(),
+        headers: config
+            .headers
+            .iter()
+            .map(|(name, value)| (name.clone(), value.0.clone()))
+            .collect(),
+        compression: config.compression,
+        batch_size: config.batch_size,
+        flush_interval: Duration::from_secs(config.flush_secs),
+        max_backoff: Duration::from_secs(config.max_backoff_secs),
+        breaker: Mutex::new(Breaker::new(
+            config.breaker_failures,
+            Duration::from_secs(config.breaker_secs),
+        )),
+    };
+    info!("posting records to {}", config.url);
+    *ENDPOINT.lock().unwrap() = Some(Arc::new(endpoint));
+    Ok(())
+}
+
+/// Open a zone's webhook sink, if an endpoint was set up at startup
+pub fn open_sink(config: &Config) -> Option<WebhookSink> {
+    let endpoint = Arc::clone(ENDPOINT.lock().unwrap().as_ref()?);
+    Some(WebhookSink {
+        endpoint,
+        encoder: Encoder::for_sink("webhook", config),
+        pending: vec![],
+        last_sent: Instant::now(),
+        stats: SinkStats::default(),
+    })
+}
+
+pub struct WebhookSink {
+    endpoint: Arc<Endpoint>,
+    encoder: Encoder,
+    /// The line of every record not yet posted
+    pending: Vec<String>,
+    last_sent: Instant,
+    stats: SinkStats,
+}
+
+impl WebhookSink {
+    /// Post every pending record, retrying until the endpoint takes them or refuses them
+    fn send(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let lines = std::mem::take(&mut self.pending);
+        let body = encode_body(&lines, self.endpoint.compression)?;
+        let endpoint = &self.endpoint;
+        let mut backoff = INITIAL_BACKOFF;
+        let result = loop {
+            let permit = endpoint.breaker.lock().unwrap().permit(Instant::now());
+            if let Err(wait) = permit {
+                thread::sleep(wait);
+                continue;
+            }
+            match endpoint.post(&body) {
+                Ok(()) => {
+                    if endpoint.breaker.lock().unwrap().succeeded() {
+                        info!("{} is taking records again", endpoint.url);
+                    }
+                    self.stats.records += lines.len() as u64;
+                    self.stats.bytes += body.len() as u64;
+                    break Ok(());
+                }
+                Err(PostError::Unavailable(e)) => {
+                    let (opened, open) = {
+                        let mut breaker = endpoint.breaker.lock().unwrap();
+                        (breaker.failed(Instant::now()), breaker.is_open())
+                    };
+                    if opened {
+                        warn!(
+                            "{} keeps failing, holding off every zone's records: {}",
+                            endpoint.url, e
+                        );
+                    } else if !open {
+                        warn!(
+                            "{} is unavailable, retrying {} records in {:?}: {}",
+                            endpoint.url,
+                            lines.len(),
+                            backoff,
+                            e
+                        );
+                        thread::sleep(backoff);
+                        backoff = std::cmp::min(backoff * 2, endpoint.max_backoff);
+                    }
+                }
+                Err(PostError::Refused(e)) => break Err(e),
+            }
+        };
+        self.last_sent = Instant::now();
+        result
+    }
+}
+
+impl Sink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        for record in records {
+            let mut line = self.encoder.to_line(record)?;
+            line.push('\n');
+            self.pending.push(line);
+        }
+        if self.pending.len() >= self.endpoint.batch_size {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn check(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() && self.last_sent.elapsed() >= self.endpoint.flush_interval {
+            self.send()?;
+        }
+        Ok(())
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.send()
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn breaker_opens_and_closes() {
+        let start = Instant::now();
+        let mut breaker = Breaker::new(3, Duration::from_secs(30));
+        assert!(!breaker.failed(start));
+        assert!(!breaker.failed(start));
+        assert!(!breaker.succeeded(), "the breaker wasn't open");
+        assert!(!breaker.failed(start), "a success starts the count over");
+        assert!(!breaker.failed(start));
+        assert!(breaker.failed(start), "the third failure in a row opens it");
+        assert_eq!(
+            breaker.permit(start + Duration::from_secs(10)),
+            Err(Duration::from_secs(20))
+        );
+
+        let retry = start + Duration::from_secs(30);
+        assert_eq!(breaker.permit(retry), Ok(()), "one request tries again");
+        assert!(
+            breaker.permit(retry).is_err(),
+            "the rest wait on how it goes"
+        );
+        assert!(!breaker.failed(retry), "a failed retry keeps it open");
+        assert!(breaker.permit(retry + Duration::from_secs(29)).is_err());
+        assert_eq!(breaker.permit(retry + Duration::from_secs(30)), Ok(()));
+        assert!(breaker.succeeded(), "a successful retry closes it");
+        assert_eq!(breaker.permit(retry + Duration::from_secs(30)), Ok(()));
+    }
+
+    #[test]
+    fn bodies_are_compressed() {
+        let lines = vec!["{\"a\":1}\n".to_owned(), "{\"b\":2}\n".to_owned()];
+        let plain = encode_body(&lines, WebhookCompression::None).unwrap();
+        assert_eq!(plain, b"{\"a\":1}\n{\"b\":2}\n");
+
+        let gzipped = encode_body(&lines, WebhookCompression::Gzip).unwrap();
+        let mut decoded = vec![];
+        GzDecoder::new(&gzipped[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+    }
+}
@@ -0,0 +1,182 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An optional WebSocket endpoint that streams a single zone's records as they are logged, which
+//! lets the operator portal show live firewall activity for a VM without shelling into the CN.
+//! Clients connect to:
+//!
+//! ```text
+//! ws://<listen address>/zones/<vm uuid>[?action=<block|begin|end>&port=<port>]
+//! ```
+//!
+//! and receive one text message per record, holding the record's json exactly as it appears in
+//! the zone's current.log followed by a newline. Every connection is served by its own thread, so
+//! the number of concurrent connections is capped.
+
+use crate::live::{Filter, LiveHub};
+use crossbeam::channel::RecvTimeoutError;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::Message;
+
+/// Limit on the number of clients connected at once
+const MAX_CONNECTIONS: usize = 16;
+/// Records queued in the `LiveHub` for each client before they start getting dropped
+const SUBSCRIBER_CAPACITY: usize = 1024;
+/// How often an idle connection is pinged so dead clients are noticed
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Build the `Filter` for a request path of the form "/zones/<vm uuid>?<query>"
+fn path_filter(path: &str) -> Result<Filter, String> {
+    let mut parts = path.splitn(2, '?');
+    let vm = match parts
+        .next()
+        .unwrap_or("")
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>()[..]
+    {
+        ["zones", vm] if !vm.is_empty() => vm.to_owned(),
+        _ => return Err(format!("unknown path \"{}\"", path)),
+    };
+    let mut filter = Filter {
+        vms: vec![vm],
+        ..Filter::default()
+    };
+    for param in parts
+        .next()
+        .unwrap_or("")
+        .split('&')
+        .filter(|p| !p.is_empty())
+    {
+        let mut kv = param.splitn(2, '=');
+        match (kv.next(), kv.next()) {
+            (Some("action"), Some(action)) => filter.action = Some(action.parse()?),
+            (Some("port"), Some(port)) => {
+                filter.port = Some(
+                    port.parse()
+                        .map_err(|_| format!("invalid port \"{}\"", port))?,
+                )
+            }
+            _ => return Err(format!("unknown query parameter \"{}\"", param)),
+        }
+    }
+    Ok(filter)
+}
+
+fn bad_request(msg: String) -> ErrorResponse {
+    let mut resp = ErrorResponse::new(Some(msg));
+    *resp.status_mut() = StatusCode::BAD_REQUEST;
+    resp
+}
+
+/// Complete the WebSocket handshake with a client and stream it records until it goes away
+fn serve_client(stream: TcpStream, hub: &LiveHub) -> tungstenite::Result<()> {
+    let mut filter = None;
+    let mut ws =
+        tungstenite::accept_hdr(stream, |req: &Request, resp: Response| {
+            match path_filter(&req.uri().to_string()) {
+                Ok(f) => {
+                    filter = Some(f);
+                    Ok(resp)
+                }
+                Err(e) => Err(bad_request(e)),
+            }
+        })
+        .map_err(|e| match e {
+            tungstenite::HandshakeError::Failure(e) => e,
+            tungstenite::HandshakeError::Interrupted(_) => tungstenite::Error::ConnectionClosed,
+        })?;
+    let filter = filter.expect("the handshake succeeded with a filter");
+    debug!("new WebSocket subscriber: {:?}", filter);
+
+    let records = hub.subscribe(filter, SUBSCRIBER_CAPACITY);
+    loop {
+        match records.recv_timeout(PING_INTERVAL) {
+            Ok(record) => {
+                let mut line = record.json.clone();
+                line.push('\n');
+                ws.write_message(Message::Text(line))?;
+            }
+            Err(RecvTimeoutError::Timeout) => ws.write_message(Message::Ping(vec![]))?,
+            Err(RecvTimeoutError::Disconnected) => return ws.close(None),
+        }
+    }
+}
+
+/// Bind `addr` and start accepting WebSocket clients that want records published on `hub`
+pub fn start_websocket_server(
+    addr: SocketAddr,
+    hub: Arc<LiveHub>,
+) -> std::io::Result<thread::JoinHandle<()>> {
+    let listener = TcpListener::bind(addr)?;
+    info!("WebSocket live tail listening on {}", addr);
+    let connections = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("websocket_server".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("failed to accept WebSocket connection: {}", e);
+                        continue;
+                    }
+                };
+                if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    warn!("too many WebSocket clients, rejecting connection");
+                    continue;
+                }
+                let hub = Arc::clone(&hub);
+                let connections2 = Arc::clone(&connections);
+                let res = thread::Builder::new()
+                    .name("websocket_client".to_owned())
+                    .spawn(move || {
+                        if let Err(e) = serve_client(stream, &hub) {
+                            debug!("WebSocket client went away: {}", e);
+                        }
+                        connections2.fetch_sub(1, Ordering::SeqCst);
+                    });
+                if let Err(e) = res {
+                    connections.fetch_sub(1, Ordering::SeqCst);
+                    error!("failed to spawn WebSocket client thread: {}", e);
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::CfwEvType;
+
+    #[test]
+    fn request_paths() {
+        assert_eq!(
+            path_filter("/zones/vm1?action=block&port=22"),
+            Ok(Filter {
+                vms: vec!["vm1".to_owned()],
+                action: Some(CfwEvType::Block),
+                port: Some(22),
+                expr: None,
+            })
+        );
+        assert_eq!(
+            path_filter("/zones/vm1").map(|f| f.vms),
+            Ok(vec!["vm1".to_owned()])
+        );
+        assert!(path_filter("/zones/").is_err(), "a vm is required");
+        assert!(path_filter("/vms/vm1").is_err());
+        assert!(path_filter("/zones/vm1?port=http").is_err());
+        assert!(path_filter("/zones/vm1?alias=web").is_err());
+    }
+}
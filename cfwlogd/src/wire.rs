@@ -0,0 +1,208 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! Helpers shared by the event sources that produce events themselves rather than reading them
+//! from ipfev. Those sources encode what they have into the ipfev wire format with
+//! `cfwevent::wire`, so the rest of the pipeline can't tell the difference. Sources that capture
+//! packets can pull what they need out of the raw IP packet with `parse_packet`.
+
+#[cfg(any(target_os = "linux", feature = "pflog"))]
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+pub use cfwevent::wire::{encode_event, Packet, RuleInfo, TRAFFIC_EVENT_SIZE};
+
+/// Pull the addresses and ports out of a raw IPv4 or IPv6 packet
+#[cfg(any(target_os = "linux", feature = "pflog"))]
+pub fn parse_packet(payload: &[u8]) -> Option<Packet> {
+    let version = *payload.first()? >> 4;
+    let (protocol, source_ip, destination_ip, transport) = match version {
+        4 => {
+            let ihl = usize::from(payload[0] & 0x0f) * 4;
+            if payload.len() < 20 || ihl < 20 {
+                return None;
+            }
+            let src = Ipv4Addr::new(payload[12], payload[13], payload[14], payload[15]);
+            let dst = Ipv4Addr::new(payload[16], payload[17], payload[18], payload[19]);
+            (
+                payload[9],
+                src.to_ipv6_mapped(),
+                dst.to_ipv6_mapped(),
+                payload.get(ihl..),
+            )
+        }
+        6 => {
+            if payload.len() < 40 {
+                return None;
+            }
+            let mut src = [0; 16];
+            let mut dst = [0; 16];
+            src.copy_from_slice(&payload[8..24]);
+            dst.copy_from_slice(&payload[24..40]);
+            // Extension headers aren't followed, so ports are only found when the next header
+            // is the transport protocol itself.
+            (
+                payload[6],
+                Ipv6Addr::from(src),
+                Ipv6Addr::from(dst),
+                payload.get(40..),
+            )
+        }
+        _ => return None,
+    };
+
+    let (source_port, destination_port) = match (protocol, transport) {
+        // TCP and UDP
+        (6, Some(t)) | (17, Some(t)) if t.len() >= 4 => (
+            u16::from_be_bytes([t[0], t[1]]),
+            u16::from_be_bytes([t[2], t[3]]),
+        ),
+        // ICMP and ICMPv6 events carry the type and code in place of the ports, like the
+        // device's do
+        (1, Some(t)) | (58, Some(t)) if t.len() >= 2 => (u16::from(t[0]), u16::from(t[1])),
+        _ => (0, 0),
+    };
+    Some(Packet {
+        protocol,
+        source_ip,
+        destination_ip,
+        source_port,
+        destination_port,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvType, CfwEvent, Direction, Icmp, Protocol, TrafficEvent};
+    use chrono::{DateTime, TimeZone, Utc};
+    use proptest::prelude::*;
+    use std::net::Ipv6Addr;
+    use uuid::Uuid;
+
+    /// Both IPv4-mapped and plain IPv6 addresses
+    fn address() -> impl Strategy<Value = Ipv6Addr> {
+        prop_oneof![
+            any::<u32>().prop_map(|ip| std::net::Ipv4Addr::from(ip).to_ipv6_mapped()),
+            any::<u128>().prop_map(Ipv6Addr::from),
+        ]
+    }
+
+    /// Every protocol the parser knows about, along with ones it doesn't
+    fn protocol() -> impl Strategy<Value = u8> {
+        prop_oneof![
+            prop::sample::select(vec![1u8, 6, 17, 50, 51, 58]),
+            any::<u8>(),
+        ]
+    }
+
+    fn port() -> impl Strategy<Value = u16> {
+        prop_oneof![Just(0), Just(1), Just(u16::max_value()), any::<u16>()]
+    }
+
+    fn id() -> impl Strategy<Value = u32> {
+        prop_oneof![Just(0), Just(1), Just(u32::max_value()), any::<u32>()]
+    }
+
+    /// Timestamps with microsecond precision, as a timeval has, from the epoch to well past 2038
+    fn timestamp() -> impl Strategy<Value = DateTime<Utc>> {
+        (0..1i64 << 36, 0..1_000_000u32)
+            .prop_map(|(secs, micros)| Utc.timestamp(secs, micros * 1000))
+    }
+
+    proptest! {
+        #[test]
+        fn encoded_events_parse_back(
+            event in 1..=3u16,
+            zonedid in id(),
+            rule_id in id(),
+            rule_uuid in any::<[u8; 16]>(),
+            protocol in protocol(),
+            source_ip in address(),
+            destination_ip in address(),
+            source_port in port(),
+            destination_port in port(),
+            direction in 1..=2u8,
+            timestamp in timestamp(),
+        ) {
+            let rule = RuleInfo {
+                event,
+                zonedid,
+                rule_id,
+                rule_uuid: Uuid::from_bytes(rule_uuid),
+            };
+            let packet = Packet {
+                protocol,
+                source_ip,
+                destination_ip,
+                source_port,
+                destination_port,
+            };
+            let mut buf = [0; TRAFFIC_EVENT_SIZE];
+            encode_event(&rule, &packet, direction, timestamp, &mut buf);
+
+            let (leftover, parsed) = parser::cfwevent_parse(&buf).unwrap();
+            prop_assert!(leftover.is_empty());
+            let icmp = match protocol {
+                1 | 58 if source_port < 256 && destination_port < 256 => Some(Icmp {
+                    icmp_type: source_port as u8,
+                    icmp_code: destination_port as u8,
+                }),
+                _ => None,
+            };
+            prop_assert_eq!(
+                parsed,
+                CfwEvent::Traffic(TrafficEvent {
+                    event: CfwEvType::from(event),
+                    length: TRAFFIC_EVENT_SIZE as u16,
+                    zonedid,
+                    rule_id,
+                    source_port,
+                    destination_port,
+                    protocol: Protocol::from(protocol),
+                    icmp,
+                    direction: if direction == 1 { Direction::In } else { Direction::Out },
+                    source_ip,
+                    destination_ip,
+                    timestamp,
+                    rule_uuid: rule.rule_uuid,
+                    seq: 0,
+                    received: None,
+                })
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", feature = "pflog"))]
+    fn ipv6_packet() {
+        let mut packet = vec![0; 44];
+        packet[0] = 0x60;
+        packet[6] = 17; // UDP
+        packet[23] = 1;
+        packet[39] = 2;
+        packet[40..42].copy_from_slice(&53u16.to_be_bytes());
+        packet[42..44].copy_from_slice(&5353u16.to_be_bytes());
+        assert_eq!(
+            parse_packet(&packet),
+            Some(Packet {
+                protocol: 17,
+                source_ip: "::1".parse().unwrap(),
+                destination_ip: "::2".parse().unwrap(),
+                source_port: 53,
+                destination_port: 5353,
+            })
+        );
+        assert_eq!(parse_packet(&packet[..30]), None, "truncated header");
+
+        packet[6] = 58; // ICMPv6
+        packet[40] = 135; // Neighbor Solicitation
+        packet[41] = 0;
+        let icmp = parse_packet(&packet).unwrap();
+        assert_eq!((icmp.source_port, icmp.destination_port), (135, 0));
+
+        assert_eq!(parse_packet(&[0x10]), None, "unknown ip version");
+    }
+}
@@ -0,0 +1,153 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! By default every zone's `Logger` runs on a thread of its own, which on a CN with thousands of
+//! zones is thousands of mostly idle threads. With "logger_workers" set the zones are spread over
+//! that many worker threads instead. A zone stays on the worker it was handed to until its logger
+//! stops, so its events are still written in the order they were queued. Each worker waits on the
+//! queues and signal channels of all of its zones at once and writes out a batch for whichever
+//! zone has events ready, without waiting on "queues.batch_delay_us" since other zones might be
+//! waiting. A zone that's slow to write holds up the rest of its worker's zones, which the health
+//! socket then reports as stuck along with it.
+
+use crate::health;
+use crate::logger::{ZoneSetup, ZoneTask};
+use crossbeam::channel::{self, Receiver, Select, SendError, Sender, TryRecvError};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+lazy_static! {
+    /// The pool started at startup, if "logger_workers" is set
+    static ref POOL: Mutex<Option<Arc<Pool>>> = Mutex::new(None);
+}
+
+/// A zone for a worker to take on, and what its `Logger` waits on at shutdown
+struct Assignment {
+    setup: ZoneSetup,
+    done: Sender<()>,
+}
+
+pub struct Pool {
+    workers: Vec<Sender<Assignment>>,
+    /// Zones are handed out to the workers in turn
+    next: AtomicUsize,
+}
+
+impl Pool {
+    pub fn start(workers: usize) -> io::Result<Pool> {
+        let mut senders = Vec::with_capacity(workers);
+        for i in 0..workers {
+            let (tx, rx) = channel::unbounded();
+            thread::Builder::new()
+                .name(format!("logger_worker_{}", i))
+                .spawn(move || run(&rx))?;
+            senders.push(tx);
+        }
+        Ok(Pool {
+            workers: senders,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Hand a zone to the next worker, returning a channel that's disconnected once the worker is
+    /// done with the zone. If the worker is gone the setup is handed back.
+    pub fn assign(&self, setup: ZoneSetup) -> Result<Receiver<()>, ZoneSetup> {
+        let next = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+        let (done, finished) = channel::bounded(0);
+        match self.workers[next].send(Assignment { setup, done }) {
+            Ok(()) => Ok(finished),
+            Err(SendError(assignment)) => {
+                error!("logger worker {} has exited", next);
+                Err(assignment.setup)
+            }
+        }
+    }
+}
+
+/// Start the workers
+pub fn init(workers: usize) -> io::Result<()> {
+    let pool = Pool::start(workers)?;
+    info!("logging zones on {} worker threads", workers);
+    *POOL.lock().unwrap() = Some(Arc::new(pool));
+    Ok(())
+}
+
+/// Hand a zone to a worker, see `Pool::assign`. Without workers the setup is handed back so the
+/// zone can be given a thread of its own.
+pub fn assign(setup: ZoneSetup) -> Result<Receiver<()>, ZoneSetup> {
+    let pool = POOL.lock().unwrap().clone();
+    match pool {
+        Some(pool) => pool.assign(setup),
+        None => Err(setup),
+    }
+}
+
+/// What a worker was woken up for
+enum Ready {
+    Assigned,
+    Events(usize),
+    Signal(usize),
+}
+
+/// Run every zone the worker is handed until the pool goes away and its last zone has stopped
+fn run(assignments: &Receiver<Assignment>) {
+    // Each zone along with what its `Logger` waits on at shutdown, which is dropped along with it
+    let mut zones: Vec<(ZoneTask, Sender<()>)> = vec![];
+    let mut open = true;
+    while open || !zones.is_empty() {
+        let ready = {
+            let mut sel = Select::new();
+            for (task, _) in &zones {
+                sel.recv(&task.events);
+                sel.recv(&task.signal);
+            }
+            let assigned = if open {
+                Some(sel.recv(assignments))
+            } else {
+                None
+            };
+            let timeout = zones
+                .iter()
+                .map(|(task, _)| task.check_interval())
+                .fold(health::HEARTBEAT_INTERVAL, Duration::min);
+            sel.ready_timeout(timeout).map(|i| match i {
+                _ if Some(i) == assigned => Ready::Assigned,
+                _ if i % 2 == 0 => Ready::Events(i / 2),
+                _ => Ready::Signal(i / 2),
+            })
+        };
+        match ready {
+            Ok(Ready::Assigned) => match assignments.try_recv() {
+                Ok(Assignment { setup, done }) => {
+                    if let Some(task) = ZoneTask::open(setup) {
+                        zones.push((task, done));
+                    }
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => open = false,
+            },
+            Ok(Ready::Events(i)) => zones[i].0.write_events(Duration::from_secs(0)),
+            Ok(Ready::Signal(i)) => {
+                if zones[i].0.handle_signal() {
+                    zones.swap_remove(i).0.finish();
+                }
+            }
+            // Nothing happened within the interval, so fall through to checking the files
+            Err(_) => (),
+        }
+        let mut i = 0;
+        while i < zones.len() {
+            if zones[i].0.check() {
+                i += 1;
+            } else {
+                zones.swap_remove(i).0.finish();
+            }
+        }
+    }
+}
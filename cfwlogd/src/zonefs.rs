@@ -0,0 +1,231 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! An optional sink that also writes a zone's records into the zone's own filesystem, so tenants
+//! can read their firewall logs from inside the zone without asking an operator. The zones listed
+//! in the config's "zone_fs.zones", and those tagged with `ZONE_FS_TAG`, have their records
+//! appended to "zone_fs.path" under their root, "/var/log/firewall.log" by default, which can also
+//! be in a delegated dataset such as "zones/{vm}/data/firewall.log".
+//!
+//! Everything under a zone's root belongs to the tenant, so the path is walked one component at
+//! a time from the zones directory opened before we chroot, never following a symlink and never
+//! creating a directory, and only a regular file with no other links is written to. The file isn't
+//! held open between batches: every batch walks the path again, so halting the zone or unmounting
+//! its datasets isn't held up by us, and once the zone boots records go to whatever the path leads
+//! to then. While the file can't be written, because the zone is halted, the directory is missing
+//! or the zone is out of space, the records are counted as dropped and this is only logged once.
+//! The file is moved to "<path>.1" once it reaches "zone_fs.max_bytes", so the records never take
+//! up more than twice that much of the zone's quota.
+
+use crate::config::{Config, ZoneFsConfig};
+use crate::fileutils;
+use crate::sink::{Encoder, Record, Sink, SinkStats};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+use vminfod_client::Zone;
+
+/// The tag that has a zone's records written into it when set to true
+pub const ZONE_FS_TAG: &str = "triton.cfwlog_zone_fs";
+
+lazy_static! {
+    /// The directory zones' filesystems are found in, opened at startup
+    static ref ZONES_DIR: Mutex<Option<Arc<File>>> = Mutex::new(None);
+}
+
+/// Open the zones directory, which has to happen before we chroot
+pub fn init(config: &ZoneFsConfig) -> io::Result<()> {
+    let dir = File::open(&config.zones_dir)?;
+    if !dir.metadata()?.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a directory",
+        ));
+    }
+    *ZONES_DIR.lock().unwrap() = Some(Arc::new(dir));
+    Ok(())
+}
+
+/// Whether the zone's records are written into it
+fn wanted(zone: &Zone, config: &ZoneFsConfig) -> bool {
+    let listed = Uuid::parse_str(&zone.uuid).map_or(false, |vm| config.zones.contains(&vm));
+    listed || zone.tags.get(ZONE_FS_TAG) == Some(&serde_json::Value::Bool(true))
+}
+
+/// The zone's file relative to the zones directory, with `{owner_uuid}` and `{vm}` substituted
+fn zone_path(zone: &Zone, config: &ZoneFsConfig) -> PathBuf {
+    let path = config
+        .path
+        .replace("{owner_uuid}", &zone.owner_uuid)
+        .replace("{vm}", &zone.uuid);
+    Path::new(&zone.uuid).join("root").join(path)
+}
+
+/// Open the zone's sink, if the zone's records are written into it
+pub fn open_sink(zone: &Zone, config: &Config) -> Option<ZoneFsSink> {
+    let settings = config.zone_fs.as_ref()?;
+    if !wanted(zone, settings) {
+        return None;
+    }
+    let zones_dir = Arc::clone(ZONES_DIR.lock().unwrap().as_ref()?);
+    let path = zone_path(zone, settings);
+    Some(ZoneFsSink {
+        zones_dir,
+        dir: path.parent()?.to_owned(),
+        name: path.file_name()?.to_str()?.to_owned(),
+        max_bytes: settings.max_bytes,
+        encoder: Encoder::for_sink("zone_fs", config),
+        buf: vec![],
+        unavailable: false,
+        stats: SinkStats::default(),
+    })
+}
+
+pub struct ZoneFsSink {
+    zones_dir: Arc<File>,
+    /// The file's directory relative to the zones directory
+    dir: PathBuf,
+    name: String,
+    max_bytes: u64,
+    encoder: Encoder,
+    buf: Vec<u8>,
+    /// Set while the file can't be written, so that's only logged once
+    unavailable: bool,
+    stats: SinkStats,
+}
+
+impl ZoneFsSink {
+    /// Walk the path to the file and append the encoded records to it, moving it aside once it's
+    /// full
+    fn append(&self) -> io::Result<()> {
+        let dir = fileutils::open_foreign_dir(&self.zones_dir, &self.dir)?;
+        let mut file = fileutils::open_foreign_append(&dir, &self.name)?;
+        file.write_all(&self.buf)?;
+        if file.metadata()?.len() >= self.max_bytes {
+            fileutils::rename_at(&dir, &self.name, &format!("{}.1", self.name))?;
+        }
+        Ok(())
+    }
+}
+
+impl Sink for ZoneFsSink {
+    fn name(&self) -> &str {
+        "zone_fs"
+    }
+
+    fn write_batch(&mut self, records: &[Record<'_>]) -> io::Result<()> {
+        self.buf.clear();
+        for record in records {
+            self.encoder.encode(record, &mut self.buf)?;
+            self.buf.extend_from_slice(self.encoder.separator());
+        }
+        let path = self.dir.join(&self.name);
+        match self.append() {
+            Ok(()) => {
+                if self.unavailable {
+                    info!("writing records to {} again", path.display());
+                    self.unavailable = false;
+                }
+                self.stats.records += records.len() as u64;
+                self.stats.bytes += self.buf.len() as u64;
+            }
+            Err(e) => {
+                if !self.unavailable {
+                    warn!(
+                        "dropping records until {} can be written again: {}",
+                        path.display(),
+                        e
+                    );
+                    self.unavailable = true;
+                }
+                self.stats.dropped += records.len() as u64;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reload(&mut self, config: &Arc<Config>) {
+        self.encoder = Encoder::for_sink(self.name(), config);
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn stats(&self) -> SinkStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logger::LOG_DIR;
+    use cfwevent::parser;
+
+    #[test]
+    fn records_are_written_into_the_zone() {
+        let zone = testutils::create_zone();
+        let zones: PathBuf = [LOG_DIR, "zone-fs-test"].iter().collect();
+        let log_dir = zones.join(&zone.uuid).join("root/var/log");
+        std::fs::create_dir_all(&log_dir).unwrap();
+        let settings = ZoneFsConfig {
+            zones: vec![zone.uuid.parse().unwrap()],
+            zones_dir: zones.clone(),
+            path: "var/log/firewall.log".to_owned(),
+            max_bytes: 4096,
+        };
+        assert!(wanted(&zone, &settings));
+        let path = zone_path(&zone, &settings);
+        let mut sink = ZoneFsSink {
+            zones_dir: Arc::new(File::open(&zones).unwrap()),
+            dir: path.parent().unwrap().to_owned(),
+            name: "firewall.log".to_owned(),
+            max_bytes: settings.max_bytes,
+            encoder: Encoder::default(),
+            buf: vec![],
+            unavailable: false,
+            stats: SinkStats::default(),
+        };
+        let event = testutils::generate_event_for_zone(&zone);
+        let record = Record::new(
+            parser::cfwevent_parse(event.as_bytes()).unwrap().1,
+            &zone.uuid,
+            "",
+        );
+
+        sink.write_batch(&[record.clone()]).unwrap();
+        let contents = std::fs::read_to_string(log_dir.join("firewall.log")).unwrap();
+        assert_eq!(contents.lines().count(), 1);
+        let records = vec![record; 4096 / contents.len()];
+        sink.write_batch(&records).unwrap();
+        assert!(
+            log_dir.join("firewall.log.1").is_file(),
+            "full files are moved aside"
+        );
+
+        std::fs::remove_dir_all(&log_dir).unwrap();
+        std::os::unix::fs::symlink("/var/tmp", &log_dir).unwrap();
+        assert!(sink.write_batch(&records).is_ok());
+        assert_eq!(
+            sink.stats().dropped,
+            records.len() as u64,
+            "symlinks aren't followed"
+        );
+
+        std::fs::remove_dir_all(&zones).unwrap();
+    }
+}
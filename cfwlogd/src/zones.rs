@@ -4,87 +4,951 @@
 
 // Copyright 2019 Joyent, Inc.
 
-use std::collections::HashMap;
-use std::sync::{Arc, Barrier};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
-use crossbeam::sync::ShardedLock;
-use vminfod_client::{Changes, VminfodEvent, Zone};
+use crate::config::StartupMode;
+use crate::exit::{self, ExitReason, Subsystem};
+use crate::fileutils;
+use crate::nic::{self, Nic};
+use arc_swap::ArcSwap;
+use crossbeam::channel::Sender;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use vminfod_client::{Changes, Settings, VminfodEvent, Zone};
 
-pub type Vmobjs = Arc<ShardedLock<HashMap<Zonedid, Zone>>>;
+pub type Vmobjs = Arc<SharedVmTable>;
 pub type Zonedid = u32;
 
-/// Inserts or updates an existing vmobj into a given `Vmobjs`
-fn insert_vmobj(zone: Zone, vmobjs: &Vmobjs) {
-    let mut w = vmobjs.write().unwrap();
-    w.insert(zone.zonedid, zone);
-}
-
-/// Search through a vminfod changes payload and see if the alias was a part of the update
-fn alias_changed(changes: &[Changes]) -> bool {
-    changes.iter().any(|change| {
-        dbg!(change.path.first().map(|v| v.as_ref()));
-        change
-            .path
-            .first()
-            // double map_or because path is a `Vec<Option<String>>`
-            .map_or(false, |v| v.as_ref().map_or(false, |a| a == "alias"))
+/// The tag that, set to true, opts a zone out of being logged. vminfod reports changes to it so
+/// it can be toggled without restarting, as long as "tags" is amongst the tracked fields.
+pub const LOGGING_DISABLED_TAG: &str = "triton.cfwlog_disabled";
+
+/// Whether the zone opted out of being logged with `LOGGING_DISABLED_TAG`
+fn logging_disabled(zone: &Zone) -> bool {
+    match zone.tags.get(LOGGING_DISABLED_TAG) {
+        Some(serde_json::Value::Bool(disabled)) => *disabled,
+        Some(serde_json::Value::String(disabled)) => disabled == "true",
+        _ => false,
+    }
+}
+
+/// What the vminfod watcher tells the event fanout thread about zones coming and going
+#[derive(Clone, Debug, PartialEq)]
+pub enum ZoneChange {
+    /// The zone was added to `Vmobjs`
+    Created(Zonedid),
+    /// The zone with the uuid was deleted, it stays in `Vmobjs` until its `Logger` is retired. The
+    /// uuid tells the zone apart from one that has since been given the same zonedid.
+    Deleted(Zonedid, String),
+    /// The first snapshot from vminfod was applied, or zones were loaded from the cache, so a
+    /// zonedid that still isn't in `Vmobjs` belongs to a zone vminfod hasn't reported yet
+    Ready,
+}
+
+/// Every zone we know about keyed by zonedid, along with indexes for finding zones by their uuid
+/// or alias, the zones that opted out of being logged, and the zones' parsed nics. The indexes
+/// are only updated along with the zones themselves, in the same `SharedVmTable::update`, so they
+/// can never disagree.
+#[derive(Clone, Debug, Default)]
+pub struct VmTable {
+    zones: HashMap<Zonedid, Zone>,
+    by_uuid: HashMap<String, Zonedid>,
+    /// Aliases aren't unique, even amongst one owner's zones
+    by_alias: HashMap<String, Vec<Zonedid>>,
+    disabled: HashSet<Zonedid>,
+    nics: HashMap<Zonedid, Vec<Nic>>,
+}
+
+impl VmTable {
+    pub fn get(&self, zonedid: &Zonedid) -> Option<&Zone> {
+        self.zones.get(zonedid)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Zone> {
+        self.zones.values()
+    }
+
+    /// Insert or replace the zone with the same zonedid, returning the zone it replaced. The zone's
+    /// entry under another zonedid, from before it rebooted, is dropped, see `displaced_by`.
+    pub fn insert(&mut self, zone: Zone) -> Option<Zone> {
+        let zonedid = zone.zonedid;
+        if let Some(previous) = self.zonedid_by_uuid(&zone.uuid) {
+            if previous != zonedid {
+                self.remove(&previous);
+            }
+        }
+        let old = self.remove(&zonedid);
+        self.by_uuid.insert(zone.uuid.clone(), zonedid);
+        if let Some(alias) = &zone.alias {
+            self.by_alias
+                .entry(alias.clone())
+                .or_default()
+                .push(zonedid);
+        }
+        if logging_disabled(&zone) {
+            self.disabled.insert(zonedid);
+        }
+        let nics = nic::zone_nics(&zone);
+        if !nics.is_empty() {
+            self.nics.insert(zonedid, nics);
+        }
+        self.zones.insert(zonedid, zone);
+        old
+    }
+
+    pub fn remove(&mut self, zonedid: &Zonedid) -> Option<Zone> {
+        let zone = self.zones.remove(zonedid)?;
+        self.by_uuid.remove(&zone.uuid);
+        self.disabled.remove(zonedid);
+        self.nics.remove(zonedid);
+        if let Some(alias) = &zone.alias {
+            if let Some(zonedids) = self.by_alias.get_mut(alias) {
+                zonedids.retain(|z| z != zonedid);
+                if zonedids.is_empty() {
+                    self.by_alias.remove(alias);
+                }
+            }
+        }
+        Some(zone)
+    }
+
+    /// Remove the zone with the given zonedid, as long as it's still the zone with that uuid
+    pub fn remove_zone(&mut self, zonedid: &Zonedid, uuid: &str) -> Option<Zone> {
+        if self.zones.get(zonedid)?.uuid != uuid {
+            return None;
+        }
+        self.remove(zonedid)
+    }
+
+    /// The zone with the given zonedid and uuid. A zonedid is only unique amongst the zones that
+    /// are running, another zone can boot with it once the zone halts, and a zone that reboots
+    /// gets a new one, so the uuid makes sure that's still the zone that had it.
+    pub fn get_zone(&self, zonedid: &Zonedid, uuid: &str) -> Option<&Zone> {
+        self.zones.get(zonedid).filter(|zone| zone.uuid == uuid)
+    }
+
+    /// The zones inserting `zone` would displace, as their zonedids and uuids: the zone it takes
+    /// the zonedid of, and its own entry from before it rebooted with a different zonedid
+    pub fn displaced_by(&self, zone: &Zone) -> Vec<(Zonedid, String)> {
+        let mut displaced = vec![];
+        if let Some(other) = self.zones.get(&zone.zonedid) {
+            if other.uuid != zone.uuid {
+                displaced.push((other.zonedid, other.uuid.clone()));
+            }
+        }
+        if let Some(previous) = self.zonedid_by_uuid(&zone.uuid) {
+            if previous != zone.zonedid {
+                displaced.push((previous, zone.uuid.clone()));
+            }
+        }
+        displaced
+    }
+
+    /// The zonedid of the zone with the given uuid
+    pub fn zonedid_by_uuid(&self, uuid: &str) -> Option<Zonedid> {
+        self.by_uuid.get(uuid).copied()
+    }
+
+    /// The zonedids of every zone with the given alias
+    pub fn zonedids_by_alias(&self, alias: &str) -> &[Zonedid] {
+        self.by_alias.get(alias).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// The nics of the zone with the given zonedid and uuid, see the "nic" module
+    pub fn nics(&self, zonedid: &Zonedid, uuid: &str) -> Option<&[Nic]> {
+        self.get_zone(zonedid, uuid)?;
+        Some(self.nics.get(zonedid).map_or(&[][..], Vec::as_slice))
+    }
+
+    /// Whether the zone's events are discarded rather than logged, see `LOGGING_DISABLED_TAG`
+    pub fn logging_disabled(&self, zonedid: &Zonedid) -> bool {
+        self.disabled.contains(zonedid)
+    }
+}
+
+/// The `VmTable` shared between the vminfod watcher that keeps it up to date and the threads that
+/// look zones up for every event. Readers load the current table without taking a lock, while an
+/// update changes a copy of the table and swaps it in. So a reader never waits on vminfod, and sees
+/// either all of an update or none of it. Copying the table makes updates the expensive side, which
+/// suits vminfod events coming in far less often than the events being enriched.
+pub struct SharedVmTable {
+    current: ArcSwap<VmTable>,
+    /// Both the vminfod watcher and loggers retiring their zone update the table, one at a time so
+    /// neither loses the other's change
+    updating: Mutex<()>,
+}
+
+impl Default for SharedVmTable {
+    fn default() -> Self {
+        SharedVmTable::new(VmTable::default())
+    }
+}
+
+impl SharedVmTable {
+    pub fn new(table: VmTable) -> Self {
+        SharedVmTable {
+            current: ArcSwap::new(Arc::new(table)),
+            updating: Mutex::new(()),
+        }
+    }
+
+    /// The current table, which later updates leave untouched
+    pub fn load(&self) -> Arc<VmTable> {
+        self.current.load()
+    }
+
+    /// Apply `f` to a copy of the current table and swap it in for readers
+    pub fn update<R, F: FnOnce(&mut VmTable) -> R>(&self, f: F) -> R {
+        let _updating = self.updating.lock().unwrap();
+        let mut table = VmTable::clone(&self.current.load());
+        let r = f(&mut table);
+        self.current.store(Arc::new(table));
+        r
+    }
+}
+
+/// The file in the log directory that the zones we know about are saved to, see
+/// `start_vmobjs_cache`
+pub const VMOBJS_CACHE: &str = "vmobjs-cache.json";
+
+/// Load the zones saved to `VMOBJS_CACHE` in `dir` into `vmobjs`, returning how many there were.
+/// A missing cache is the same as an empty one.
+pub fn load_vmobjs_cache(dir: &File, vmobjs: &Vmobjs) -> io::Result<usize> {
+    let mut buf = vec![];
+    match fileutils::open_read_nofollow(dir, VMOBJS_CACHE) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let zones: Vec<Zone> = serde_json::from_slice(&buf)?;
+    let count = zones.len();
+    vmobjs.update(|w| {
+        for zone in zones {
+            w.insert(zone);
+        }
+    });
+    Ok(count)
+}
+
+/// Save the zones in `vms` to `VMOBJS_CACHE` in `dir`
+fn save_vmobjs_cache(dir: &File, vms: &VmTable) -> io::Result<()> {
+    let zones: Vec<&Zone> = vms.values().collect();
+    fileutils::replace_file_nofollow(dir, VMOBJS_CACHE, &serde_json::to_vec(&zones)?)
+}
+
+/// Start a thread that saves the zones in `vmobjs` to `VMOBJS_CACHE` in `dir` every `interval`
+/// they have changed, so that after a restart events can be attributed to their zones before
+/// vminfod is up. The cache is only a head start, the first `Ready` event reconciles it with
+/// vminfod, retiring the zones that were deleted in the meantime.
+pub fn start_vmobjs_cache(
+    vmobjs: Vmobjs,
+    dir: File,
+    interval: Duration,
+) -> io::Result<thread::JoinHandle<()>> {
+    thread::Builder::new()
+        .name("vmobjs_cache".to_owned())
+        .spawn(move || {
+            let mut saved: Option<Arc<VmTable>> = None;
+            loop {
+                thread::sleep(interval);
+                let vms = vmobjs.load();
+                if saved
+                    .as_ref()
+                    .map_or(false, |saved| Arc::ptr_eq(saved, &vms))
+                {
+                    continue;
+                }
+                match save_vmobjs_cache(&dir, &vms) {
+                    Ok(()) => saved = Some(vms),
+                    Err(e) => error!("failed to save {}: {}", VMOBJS_CACHE, e),
+                }
+            }
+        })
+}
+
+/// How long to wait before reconnecting to vminfod when it's unavailable at startup and we are
+/// running in permissive mode.
+const VMINFOD_STARTUP_RETRY: Duration = Duration::from_secs(5);
+
+/// The fields of a vmobj whose changes, as reported by vminfod `Modify` events, are applied to
+/// `Vmobjs`. Changes to other fields are ignored since they never end up in log records.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum VmField {
+    Alias,
+    OwnerUuid,
+    Tags,
+    Nics,
+}
+
+impl VmField {
+    /// Every field, which is what's tracked by default
+    pub const ALL: &'static [VmField] = &[
+        VmField::Alias,
+        VmField::OwnerUuid,
+        VmField::Tags,
+        VmField::Nics,
+    ];
+
+    /// The field's name in a vmobj
+    fn name(self) -> &'static str {
+        match self {
+            VmField::Alias => "alias",
+            VmField::OwnerUuid => "owner_uuid",
+            VmField::Tags => "tags",
+            VmField::Nics => "nics",
+        }
+    }
+}
+
+/// The vmobj fields we ask vminfod for when "vminfod.request_fields" is set: everything a `Zone`
+/// needs, but a vm's nics only while they are tracked
+pub fn requested_fields(tracked: &[VmField]) -> Vec<String> {
+    let mut fields = vec![
+        "uuid",
+        "zonedid",
+        "firewall_enabled",
+        "alias",
+        "owner_uuid",
+        "tags",
+    ];
+    if tracked.contains(&VmField::Nics) {
+        fields.push("nics");
+    }
+    fields.into_iter().map(str::to_owned).collect()
+}
+
+/// A vm in a `Ready` snapshot, which only keeps the fields a `Zone` has so the rest of the vmobj
+/// is skipped rather than parsed. Fields are kept as json so a malformed vm can be left out on its
+/// own.
+#[derive(Deserialize)]
+struct ReadyVm {
+    uuid: Option<Value>,
+    alias: Option<Value>,
+    owner_uuid: Option<Value>,
+    firewall_enabled: Option<Value>,
+    zonedid: Option<Value>,
+    tags: Option<Value>,
+    nics: Option<Value>,
+}
+
+impl ReadyVm {
+    fn into_zone(self, tracked: &[VmField]) -> serde_json::Result<Zone> {
+        let nics = self.nics.filter(|_| tracked.contains(&VmField::Nics));
+        let fields = vec![
+            ("uuid", self.uuid),
+            ("alias", self.alias),
+            ("owner_uuid", self.owner_uuid),
+            ("firewall_enabled", self.firewall_enabled),
+            ("zonedid", self.zonedid),
+            ("tags", self.tags),
+            ("nics", nics),
+        ];
+        let vm: Map<String, Value> = fields
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name.to_owned(), value)))
+            .collect();
+        serde_json::from_value(Value::Object(vm))
+    }
+}
+
+/// Log the zones a vmobj displaced when it was inserted, see `VmTable::displaced_by`
+fn log_displaced(zone: &Zone, displaced: &[(Zonedid, String)]) {
+    for (zonedid, uuid) in displaced {
+        if *uuid == zone.uuid {
+            info!(
+                "{} rebooted with zonedid {}, was {}",
+                uuid, zone.zonedid, zonedid
+            );
+        } else {
+            info!(
+                "zonedid {} was reused by {}, was {}",
+                zonedid, zone.uuid, uuid
+            );
+        }
+    }
+}
+
+/// Inserts or updates an existing vmobj into a given `Vmobjs`, returning the zones it displaced,
+/// which have to be retired
+fn insert_vmobj(zone: Zone, vmobjs: &Vmobjs) -> Vec<(Zonedid, String)> {
+    let alias = zone.alias.clone();
+    let (zonedid, uuid) = (zone.zonedid, zone.uuid.clone());
+    let (was_disabled, disabled, displaced) = vmobjs.update(|w| {
+        let was_disabled = w.logging_disabled(&zonedid);
+        let displaced = w.displaced_by(&zone);
+        log_displaced(&zone, &displaced);
+        w.insert(zone);
+        (was_disabled, w.logging_disabled(&zonedid), displaced)
+    });
+    match (was_disabled, disabled) {
+        (false, true) => info!(
+            "{} opted out of logging with {}",
+            uuid, LOGGING_DISABLED_TAG
+        ),
+        (true, false) => info!("{} opted back in to logging", uuid),
+        _ => (),
+    }
+    if let Some(alias) = alias {
+        let vms = vmobjs.load();
+        let shared = vms.zonedids_by_alias(&alias);
+        if shared.len() > 1 {
+            debug!("alias {} is shared by zonedids {:?}", alias, shared);
+        }
+    }
+    displaced
+}
+
+/// The vms of a vminfod `Ready` event
+#[derive(Default)]
+struct Snapshot {
+    zones: Vec<Zone>,
+    /// The uuid of every vm in the snapshot, including those that were malformed, or `None` if
+    /// some vm didn't have one and so we can't tell which zones are missing from the snapshot
+    uuids: Option<HashSet<String>>,
+}
+
+/// Apply the vms found in a vminfod `Ready` event to a given `Vmobjs`, returning the zonedids of
+/// those that were previously unknown, and the zonedids and uuids of those that are missing from
+/// the snapshot or were displaced by a zone in it, see `VmTable::displaced_by`. The first
+/// `Ready` event on a connection is a full snapshot, but some proxies have been seen delivering it
+/// more than once, and every reconnection starts with another, so a repeat is reconciled against
+/// the existing mapping rather than treated as an error. Zones missing from it were deleted while
+/// we weren't looking and are retired like those vminfod tells us about.
+fn apply_ready(snapshot: Snapshot, vmobjs: &Vmobjs) -> (Vec<Zonedid>, Vec<(Zonedid, String)>) {
+    vmobjs.update(|w| {
+        let mut added = vec![];
+        let mut removed = vec![];
+        for vm in snapshot.zones {
+            let zonedid = vm.zonedid;
+            let displaced = w.displaced_by(&vm);
+            log_displaced(&vm, &displaced);
+            let uuid = vm.uuid.clone();
+            match w.insert(vm) {
+                Some(old) if old.uuid == uuid => (),
+                _ => added.push(zonedid),
+            }
+            removed.extend(displaced);
+        }
+        if let Some(uuids) = &snapshot.uuids {
+            for zone in w.values().filter(|zone| !uuids.contains(&zone.uuid)) {
+                info!(
+                    "{} ({}) is missing from the snapshot",
+                    zone.uuid, zone.zonedid
+                );
+                removed.push((zone.zonedid, zone.uuid.clone()));
+            }
+        }
+        (added, removed)
+    })
+}
+
+/// Parse the vms payload of a vminfod `Ready` event, keeping only the fields we use, see `ReadyVm`.
+/// A vm that doesn't parse is left out with a warning rather than losing the rest of the snapshot.
+fn parse_ready(raw_vms: &str, tracked: &[VmField]) -> serde_json::Result<Snapshot> {
+    let vms: Vec<ReadyVm> = serde_json::from_str(raw_vms)?;
+    let mut uuids = Some(HashSet::new());
+    let zones = vms
+        .into_iter()
+        .filter_map(|vm| {
+            let uuid = vm.uuid.as_ref().and_then(Value::as_str).map(str::to_owned);
+            match (&mut uuids, &uuid) {
+                (Some(uuids), Some(uuid)) => {
+                    uuids.insert(uuid.clone());
+                }
+                _ => uuids = None,
+            }
+            let uuid = uuid.unwrap_or_else(|| "unknown".to_owned());
+            vm.into_zone(tracked)
+                .map_err(|e| {
+                    warn!(
+                        "ignoring malformed vm {} in vminfod ready event: {}",
+                        uuid, e
+                    )
+                })
+                .ok()
+        })
+        .collect();
+    Ok(Snapshot { zones, uuids })
+}
+
+/// Search through a vminfod changes payload and return the first of the tracked fields that was a
+/// part of the update. A change's path starts with the top level field, e.g. `["tags", "role"]`.
+fn tracked_change(changes: &[Changes], tracked: &[VmField]) -> Option<VmField> {
+    changes.iter().find_map(|change| {
+        // `path` is a `Vec<Option<String>>`
+        let field = change.path.first()?.as_ref()?;
+        tracked.iter().copied().find(|f| f.name() == field)
     })
 }
 
 /// Start a vminfod watcher thread that will keep a `Vmobjs` object up-to-date.
-/// This function will block until the spawned thread has processed the `Ready` event from vminfod
-pub fn start_vminfod(vmobjs: Vmobjs) -> thread::JoinHandle<()> {
+/// This returns right away so the devices are read while we wait for vminfod, and
+/// `ZoneChange::Ready` is sent to `changes` once the first `Ready` event from vminfod is
+/// processed, or straight away when `cached` zones were loaded with `load_vmobjs_cache`. Until
+/// then the event fanout thread holds the events of every zone it doesn't know about.
+/// If vminfod is unavailable before the first `Ready` event is seen the process exits when running
+/// in strict mode without a cache, otherwise we keep trying to connect until vminfod comes up.
+/// Once connected, the client reconnects whenever vminfod restarts, and the `Ready` event every
+/// new connection starts with resynchronizes `Vmobjs`. A stream vminfod resumed after
+/// "vminfod.resume" carries on without one.
+///
+/// New zones are sent to `changes` so the event fanout thread can log any of their events it's
+/// holding. Deleted zones, and zones missing from a `Ready` snapshot, aren't removed from `Vmobjs`
+/// here since their events may still be queued, instead they are sent to `changes` so the event
+/// fanout thread can retire the zone's `Logger`. Sending only fails once the fanout thread has
+/// shut down.
+pub fn start_vminfod(
+    vmobjs: Vmobjs,
+    mode: StartupMode,
+    settings: Settings,
+    tracked: Vec<VmField>,
+    changes: Sender<ZoneChange>,
+    cached: bool,
+) -> thread::JoinHandle<()> {
     let version = env!("CARGO_PKG_VERSION");
-    let b = Arc::new(Barrier::new(2));
-    let b2 = Arc::clone(&b);
-    let handle = thread::Builder::new()
+    // With cached zones logging gets underway without waiting for the first `Ready` event, and
+    // carries on while vminfod is unavailable rather than exiting
+    if cached {
+        let _ = changes.send(ZoneChange::Ready);
+    }
+    let mode = if cached {
+        StartupMode::Permissive
+    } else {
+        mode
+    };
+    thread::Builder::new()
         .name("vminfod_event_processor".to_owned())
         .spawn(move || {
             info!("starting vminfod thread");
             let mut init = true;
-            let (r, _) = vminfod_client::start_vminfod_stream(version);
-            for event in r.iter() {
-                match event {
-                    VminfodEvent::Ready(event) => {
-                        let raw_vms = event.vms;
-                        let vms: Vec<Zone> = serde_json::from_str(&raw_vms)
-                            .expect("failed to parse vms payload from vminfod");
-                        let mut w = vmobjs.write().unwrap();
-                        for vm in vms {
-                            w.insert(vm.zonedid, vm);
+            loop {
+                // A client that can't be started is handled like vminfod being unavailable
+                let r = match vminfod_client::start_vminfod_stream(version, settings.clone()) {
+                    Ok((r, _)) => Some(r),
+                    Err(e) => {
+                        error!("failed to start vminfod client: {}", e);
+                        None
+                    }
+                };
+                let mut ready = false;
+                for event in r.into_iter().flatten() {
+                    match event {
+                        VminfodEvent::Ready(event) => {
+                            let snapshot = match parse_ready(&event.vms, &tracked) {
+                                Ok(snapshot) => snapshot,
+                                Err(e) => {
+                                    // What we already know about the zones still stands, and
+                                    // zones we don't know about are learned from their events
+                                    error!("ignoring malformed vminfod ready event: {}", e);
+                                    Snapshot::default()
+                                }
+                            };
+                            let (added, removed) = apply_ready(snapshot, &vmobjs);
+                            // Every reconnection that wasn't resumed starts with another `Ready`
+                            // event
+                            if ready {
+                                info!(
+                                    "vminfod ready event resynchronized vmobjs \
+                                     ({} previously unknown zones, {} retired zones)",
+                                    added.len(),
+                                    removed.len()
+                                );
+                            }
+                            for zonedid in added {
+                                let _ = changes.send(ZoneChange::Created(zonedid));
+                            }
+                            for (zonedid, uuid) in removed {
+                                let _ = changes.send(ZoneChange::Deleted(zonedid, uuid));
+                            }
+                            ready = true;
+                            debug!("vminfod ready event processed");
+                            if init {
+                                let _ = changes.send(ZoneChange::Ready);
+                                init = false;
+                            }
                         }
-                        debug!("vminfod ready event processed");
-                        // Barriers reset after wait is called n times. Since this thread won't be
-                        // spawned multiple times we should only call wait on the barrier during
-                        // initialization.
-                        if init {
-                            b2.wait();
-                            init = false;
+                        VminfodEvent::Create(event) => {
+                            let zonedid = event.vm.zonedid;
+                            for (zonedid, uuid) in insert_vmobj(event.vm, &vmobjs) {
+                                let _ = changes.send(ZoneChange::Deleted(zonedid, uuid));
+                            }
+                            let _ = changes.send(ZoneChange::Created(zonedid));
                         }
-                    }
-                    VminfodEvent::Create(event) => insert_vmobj(event.vm, &vmobjs),
-                    VminfodEvent::Modify(event) => {
-                        if alias_changed(&event.changes) {
-                            debug!(
-                                "alias changed for {} ({}), updating vmobj mapping",
-                                &event.vm.uuid, &event.vm.zonedid
-                            );
-                            insert_vmobj(event.vm, &vmobjs);
+                        VminfodEvent::Modify(event) => {
+                            // A zone that rebooted is back with a new zonedid whether or not any
+                            // of the tracked fields changed
+                            let zonedid = event.vm.zonedid;
+                            let rebooted = vmobjs
+                                .load()
+                                .zonedid_by_uuid(&event.vm.uuid)
+                                .map_or(false, |previous| previous != zonedid);
+                            let field = tracked_change(&event.changes, &tracked);
+                            if let Some(field) = field {
+                                debug!(
+                                    "{} changed for {} ({}), updating vmobj mapping",
+                                    field.name(),
+                                    &event.vm.uuid,
+                                    &event.vm.zonedid
+                                );
+                            }
+                            if rebooted || field.is_some() {
+                                for (zonedid, uuid) in insert_vmobj(event.vm, &vmobjs) {
+                                    let _ = changes.send(ZoneChange::Deleted(zonedid, uuid));
+                                }
+                            }
+                            if rebooted {
+                                let _ = changes.send(ZoneChange::Created(zonedid));
+                            }
                         }
+                        VminfodEvent::Delete(event) => {
+                            match vmobjs.load().zonedid_by_uuid(&event.uuid) {
+                                Some(zonedid) => {
+                                    info!("{} ({}) was deleted", &event.uuid, zonedid);
+                                    let _ = changes.send(ZoneChange::Deleted(zonedid, event.uuid));
+                                }
+                                None => debug!("ignoring delete of unknown zone {}", &event.uuid),
+                            }
+                        }
+                    }
+                }
+
+                // Once connected the client reconnects on its own, so the stream only closes
+                // before we have seen the first `Ready` event
+                if !init {
+                    exit::fatal(ExitReason::Vminfod, "vminfod client exited unexpectedly");
+                }
+
+                match mode {
+                    StartupMode::Strict => {
+                        exit::fatal(
+                            ExitReason::Vminfod,
+                            "vminfod is unavailable, unable to start",
+                        );
+                    }
+                    StartupMode::Permissive => {
+                        warn!(
+                            "vminfod is unavailable, retrying in {} seconds",
+                            VMINFOD_STARTUP_RETRY.as_secs()
+                        );
+                        thread::sleep(VMINFOD_STARTUP_RETRY);
                     }
-                    // Nothing to be done with deletes currently. We don't modify `Vmobjs` since
-                    // cfw event logs in various processing queues may not have made it to disk
-                    // yet. We may eventually want to signal a logger that it's okay to shutdown.
-                    VminfodEvent::Delete(_) => (),
                 }
             }
-            // TODO TRITON-1754: implement retry logic here, until then just panic
-            panic!("vminfod event stream closed");
         })
-        .expect("vminfod client thread spawn failed.");
+        .unwrap_or_else(|e| {
+            exit::fault(
+                Subsystem::Vminfod,
+                &format!("failed to start vminfod client thread: {}", e),
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(zonedid: Zonedid, alias: &str) -> Zone {
+        Zone {
+            uuid: format!("uuid-{}", zonedid),
+            alias: Some(alias.to_owned()),
+            owner_uuid: "owner".to_owned(),
+            firewall_enabled: true,
+            zonedid,
+            tags: HashMap::new(),
+            nics: vec![],
+        }
+    }
+
+    fn changes(paths: &[&[Option<&str>]]) -> Vec<Changes> {
+        paths
+            .iter()
+            .map(|path| Changes {
+                path: path.iter().map(|p| p.map(str::to_owned)).collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tracked_changes() {
+        let retagged = changes(&[&[Some("tags"), Some("role")]]);
+        assert_eq!(tracked_change(&retagged, VmField::ALL), Some(VmField::Tags));
+        assert_eq!(
+            tracked_change(&retagged, &[VmField::Alias]),
+            None,
+            "only the configured fields are tracked"
+        );
+
+        let reprovisioned = changes(&[&[Some("state")], &[Some("owner_uuid")]]);
+        assert_eq!(
+            tracked_change(&reprovisioned, VmField::ALL),
+            Some(VmField::OwnerUuid)
+        );
+        assert_eq!(
+            tracked_change(&changes(&[&[None], &[]]), VmField::ALL),
+            None
+        );
+    }
+
+    #[test]
+    fn duplicate_ready_is_reconciled() {
+        let snapshot = |zones: Vec<Zone>| Snapshot {
+            uuids: Some(zones.iter().map(|zone| zone.uuid.clone()).collect()),
+            zones,
+        };
+        let vmobjs = Vmobjs::default();
+        let (added, removed) = apply_ready(snapshot(vec![zone(1, "a"), zone(2, "b")]), &vmobjs);
+        assert_eq!((added.len(), removed.len()), (2, 0));
+
+        // A replayed snapshot with an alias change, a new zone, and a missing zone
+        assert_eq!(
+            apply_ready(snapshot(vec![zone(1, "renamed"), zone(3, "c")]), &vmobjs),
+            (vec![3], vec![(2, "uuid-2".to_owned())])
+        );
+        let vms = vmobjs.load();
+        assert_eq!(
+            vms.values().count(),
+            3,
+            "missing zones are retired by the fanout thread"
+        );
+        assert_eq!(vms.get(&1).unwrap().alias.as_ref().unwrap(), "renamed");
+
+        // Nothing is missing from a snapshot we couldn't fully identify
+        let partial = Snapshot {
+            zones: vec![zone(1, "renamed")],
+            uuids: None,
+        };
+        assert_eq!(apply_ready(partial, &vmobjs), (vec![], vec![]));
+    }
+
+    #[test]
+    fn malformed_ready_vms_are_skipped() {
+        let raw = r#"[
+            {"uuid": "uuid-1", "alias": "web", "owner_uuid": "owner", "firewall_enabled": true,
+             "zonedid": 1},
+            {"uuid": "uuid-2", "owner_uuid": "owner", "firewall_enabled": true,
+             "zonedid": "two"}
+        ]"#;
+        let snapshot = parse_ready(raw, VmField::ALL).unwrap();
+        assert_eq!(
+            snapshot.zones.len(),
+            1,
+            "the second vm's zonedid is malformed"
+        );
+        assert_eq!(snapshot.zones[0].uuid, "uuid-1");
+        assert!(
+            snapshot.uuids.unwrap().contains("uuid-2"),
+            "a malformed vm isn't missing from the snapshot"
+        );
+        assert!(parse_ready(r#"{"vms": []}"#, VmField::ALL).is_err());
+
+        let raw = r#"[{"uuid": "uuid-1", "owner_uuid": "owner", "firewall_enabled": true,
+            "zonedid": 1, "nics": [{"vlan_id": 0}], "disks": [{"size": 10240}]}]"#;
+        let snapshot = parse_ready(raw, &[VmField::Alias]).unwrap();
+        assert!(
+            snapshot.zones[0].nics.is_empty(),
+            "untracked nics aren't kept"
+        );
+        assert!(!requested_fields(&[VmField::Alias]).contains(&"nics".to_owned()));
+    }
+
+    #[test]
+    fn vmobjs_cache_round_trip() {
+        let path = std::path::Path::new(crate::logger::LOG_DIR).join("vmobjs-cache");
+        let dir = fileutils::create_dir_all_nofollow(&path).unwrap();
+        let _ = std::fs::remove_file(path.join(VMOBJS_CACHE));
+        let vmobjs = Vmobjs::default();
+        assert_eq!(load_vmobjs_cache(&dir, &vmobjs).unwrap(), 0, "no cache yet");
+
+        let mut vms = VmTable::default();
+        vms.insert(zone(1, "web"));
+        vms.insert(zone(2, "db"));
+        save_vmobjs_cache(&dir, &vms).unwrap();
+        assert_eq!(load_vmobjs_cache(&dir, &vmobjs).unwrap(), 2);
+        let loaded = vmobjs.load();
+        assert_eq!(loaded.zonedid_by_uuid("uuid-2"), Some(2));
+        assert_eq!(loaded.zonedids_by_alias("web"), &[1]);
+
+        std::fs::write(path.join(VMOBJS_CACHE), "[{").unwrap();
+        assert!(load_vmobjs_cache(&dir, &vmobjs).is_err());
+
+        std::fs::remove_dir_all(&path).expect("failed to cleanup test dir");
+    }
+
+    #[test]
+    fn vm_table_indexes() {
+        let mut vms = VmTable::default();
+        vms.insert(zone(1, "web"));
+        vms.insert(zone(2, "web"));
+        vms.insert(zone(3, "db"));
+        assert_eq!(vms.zonedid_by_uuid("uuid-2"), Some(2));
+        assert_eq!(vms.zonedid_by_uuid("uuid-4"), None);
+        assert_eq!(vms.zonedids_by_alias("web"), &[1, 2]);
+
+        // Renaming a zone moves it between aliases
+        assert!(vms.insert(zone(1, "db")).is_some());
+        assert_eq!(vms.zonedids_by_alias("web"), &[2]);
+        assert_eq!(vms.zonedids_by_alias("db"), &[3, 1]);
+
+        assert!(vms.remove(&2).is_some());
+        assert_eq!(vms.zonedid_by_uuid("uuid-2"), None);
+        assert!(vms.zonedids_by_alias("web").is_empty());
+        assert!(vms.remove(&2).is_none());
+    }
+
+    #[test]
+    fn reused_zonedids() {
+        let mut vms = VmTable::default();
+        vms.insert(zone(1, "web"));
+        vms.insert(zone(2, "db"));
+
+        // uuid-1 reboots as zonedid 3, and another zone boots with its old zonedid
+        let mut rebooted = zone(1, "web");
+        rebooted.zonedid = 3;
+        assert_eq!(vms.displaced_by(&rebooted), vec![(1, "uuid-1".to_owned())]);
+        assert!(vms.insert(rebooted).is_none());
+        assert!(vms.get(&1).is_none(), "the old entry is dropped");
+        assert_eq!(vms.zonedids_by_alias("web"), &[3]);
+
+        let mut reused = zone(2, "cache");
+        reused.uuid = "uuid-4".to_owned();
+        assert_eq!(vms.displaced_by(&reused), vec![(2, "uuid-2".to_owned())]);
+        vms.insert(reused);
+        assert_eq!(vms.zonedid_by_uuid("uuid-2"), None);
+        assert!(vms.get_zone(&2, "uuid-2").is_none());
+        assert!(vms.get_zone(&2, "uuid-4").is_some());
+        assert!(
+            vms.remove_zone(&2, "uuid-2").is_none(),
+            "retiring the old zone leaves the new one be"
+        );
+        assert!(vms.remove_zone(&2, "uuid-4").is_some());
+    }
+
+    #[test]
+    fn logging_can_be_disabled_by_tag() {
+        let mut vms = VmTable::default();
+        let mut quiet = zone(1, "quiet");
+        quiet.tags.insert(
+            LOGGING_DISABLED_TAG.to_owned(),
+            serde_json::Value::Bool(true),
+        );
+        vms.insert(quiet);
+        let mut other = zone(2, "other");
+        other.tags.insert(
+            LOGGING_DISABLED_TAG.to_owned(),
+            serde_json::Value::String("false".to_owned()),
+        );
+        vms.insert(other);
+        assert!(vms.logging_disabled(&1));
+        assert!(!vms.logging_disabled(&2));
+
+        // Modify events replace the zone, so removing the tag opts it back in
+        vms.insert(zone(1, "quiet"));
+        assert!(!vms.logging_disabled(&1));
+        vms.insert(zone(3, "gone"));
+        vms.remove(&3);
+        assert!(!vms.logging_disabled(&3));
+    }
+
+    #[test]
+    fn readers_keep_their_snapshot() {
+        let vmobjs = Vmobjs::default();
+        vmobjs.update(|vms| vms.insert(zone(1, "web")));
+        let before = vmobjs.load();
+        let replaced = vmobjs.update(|vms| {
+            assert!(
+                vmobjs.load().get(&2).is_none(),
+                "the update isn't visible until it's done"
+            );
+            vms.insert(zone(2, "db"));
+            vms.insert(zone(1, "renamed"))
+        });
+        assert_eq!(replaced.unwrap().alias.unwrap(), "web");
+        assert_eq!(before.get(&1).unwrap().alias.as_ref().unwrap(), "web");
+        assert!(before.get(&2).is_none());
+        let after = vmobjs.load();
+        assert_eq!(after.get(&1).unwrap().alias.as_ref().unwrap(), "renamed");
+        assert_eq!(after.zonedids_by_alias("db"), &[2]);
+    }
+
+    const ZONES: Zonedid = 1000;
+    const READERS: usize = 8;
+    const LOOKUPS: Zonedid = 1_000_000;
+
+    /// How long it takes `READERS` threads to each look up `LOOKUPS` zones while `update` is run
+    /// in a loop, the way a busy vminfod would
+    fn time_lookups<L, U>(lookup: L, update: U) -> Duration
+    where
+        L: Fn(Zonedid) -> bool + Send + Sync + 'static,
+        U: Fn(Zonedid) + Send + 'static,
+    {
+        let lookup = Arc::new(lookup);
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let updater = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut i = 0;
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    update(i % ZONES);
+                    i += 1;
+                    thread::sleep(Duration::from_micros(100));
+                }
+            })
+        };
+        let start = std::time::Instant::now();
+        let readers: Vec<_> = (0..READERS)
+            .map(|_| {
+                let lookup = Arc::clone(&lookup);
+                thread::spawn(move || (0..LOOKUPS).filter(|i| lookup(i % ZONES)).count())
+            })
+            .collect();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), LOOKUPS as usize);
+        }
+        let elapsed = start.elapsed();
+        done.store(true, std::sync::atomic::Ordering::Relaxed);
+        updater.join().unwrap();
+        elapsed
+    }
+
+    /// Compares event enrichment's zone lookups against the `ShardedLock` that used to guard the
+    /// table. It's slow, so run it on its own with
+    /// `cargo test --release vm_table_contention -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn vm_table_contention() {
+        let mut table = VmTable::default();
+        for zonedid in 0..ZONES {
+            table.insert(zone(zonedid, "web"));
+        }
+
+        let locked = Arc::new(crossbeam::sync::ShardedLock::new(table.clone()));
+        let writer = Arc::clone(&locked);
+        let locked = time_lookups(
+            move |zonedid| locked.read().unwrap().get(&zonedid).is_some(),
+            move |zonedid| {
+                writer.write().unwrap().insert(zone(zonedid, "web"));
+            },
+        );
+
+        let shared = Arc::new(SharedVmTable::new(table));
+        let writer = Arc::clone(&shared);
+        let shared = time_lookups(
+            move |zonedid| shared.load().get(&zonedid).is_some(),
+            move |zonedid| {
+                writer.update(|vms| vms.insert(zone(zonedid, "web")));
+            },
+        );
 
-    // Block until we process the very first "Ready" event from vminfod
-    b.wait();
-    handle
+        let rate = |elapsed: Duration| {
+            (READERS as f64 * f64::from(LOOKUPS) / elapsed.as_secs_f64()) as u64
+        };
+        println!("ShardedLock: {} lookups/s", rate(locked));
+        println!("SharedVmTable: {} lookups/s", rate(shared));
+    }
 }
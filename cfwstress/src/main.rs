@@ -0,0 +1,237 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! cfwstress generates a realistic mix of traffic events at a target rate, for load testing
+//! cfwlogd wherever there's no ipfilter to produce them. The events are written as a capture, the
+//! format `cfwlogd capture` writes, with a frame for every batch of events the way the device
+//! hands them back from a read. Written to a FIFO that cfwlogd replays with a "replay" source, the
+//! whole pipeline runs at the generated rate. See the README's "Load testing" section.
+
+mod mix;
+
+use chrono::Utc;
+use mix::{Generator, Mix};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "usage: cfwstress --zones <zonedid>,... [--rate <events/s>] [--seconds <n>]
+                 [--remotes <n>] [--block-percent <n>] [--udp-percent <n>]
+                 [--icmp-percent <n>] [--ipv6-percent <n>] [--inbound-percent <n>] <file>";
+
+/// Every capture file starts with these bytes, see cfwlogd's "capture" module
+const CAPTURE_MAGIC: &[u8; 8] = b"cfwcap01";
+
+/// How often a frame of the events due by then is written
+const FRAME_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What the command line asked for
+#[derive(Debug, PartialEq)]
+struct Options {
+    mix: Mix,
+    /// Events per second
+    rate: u64,
+    /// Stop after this long, rather than when interrupted
+    seconds: Option<u64>,
+    /// "-" is stdout
+    file: String,
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        mix: Mix::default(),
+        rate: 100_000,
+        seconds: None,
+        file: String::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .map(String::as_str)
+                .ok_or_else(|| format!("{} needs a value", arg))
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid {} \"{}\"", arg, value))
+        };
+        let percent = |value: &str| match value.parse::<u32>() {
+            Ok(percent) if percent <= 100 => Ok(percent),
+            _ => Err(format!("invalid {} \"{}\"", arg, value)),
+        };
+        match arg.as_str() {
+            "--zones" => {
+                let zones = value()?;
+                options.mix.zones = zones
+                    .split(',')
+                    .map(|zone| zone.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("invalid zonedids \"{}\"", zones))?;
+            }
+            "--rate" => options.rate = number(value()?)?,
+            "--seconds" => options.seconds = Some(number(value()?)?),
+            "--remotes" => options.mix.remotes = number(value()?)?.max(1) as u32,
+            "--block-percent" => options.mix.block_percent = percent(value()?)?,
+            "--udp-percent" => options.mix.udp_percent = percent(value()?)?,
+            "--icmp-percent" => options.mix.icmp_percent = percent(value()?)?,
+            "--ipv6-percent" => options.mix.ipv6_percent = percent(value()?)?,
+            "--inbound-percent" => options.mix.inbound_percent = percent(value()?)?,
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(format!("unknown argument \"{}\"", arg))
+            }
+            _ if options.file.is_empty() => options.file = arg.clone(),
+            _ => return Err("only a single file can be written".to_owned()),
+        }
+    }
+    if options.mix.zones.is_empty() {
+        return Err("no zones given".to_owned());
+    }
+    if options.rate == 0 {
+        return Err("the rate must be non-zero".to_owned());
+    }
+    if options.file.is_empty() {
+        return Err("no file given".to_owned());
+    }
+    Ok(options)
+}
+
+/// Write a frame in the capture format: the time in microseconds since the epoch, the length,
+/// and then the events
+fn write_frame<W: Write>(writer: &mut W, events: &[u8]) -> io::Result<()> {
+    let now = Utc::now();
+    let micros = now.timestamp() * 1_000_000 + i64::from(now.timestamp_subsec_micros());
+    writer.write_all(&micros.to_le_bytes())?;
+    writer.write_all(&(events.len() as u32).to_le_bytes())?;
+    writer.write_all(events)?;
+    writer.flush()
+}
+
+/// Generate events until `options.seconds` have passed, reporting the rate achieved every second
+fn run<W: Write>(options: &Options, mut writer: W) -> io::Result<u64> {
+    let mut generator = Generator::new(options.mix.clone());
+    writer.write_all(CAPTURE_MAGIC)?;
+    let size = cfwevent::wire::TRAFFIC_EVENT_SIZE;
+    let mut frame = vec![];
+    let start = Instant::now();
+    let (mut generated, mut reported, mut last_report) = (0u64, 0u64, start);
+    loop {
+        let elapsed = start.elapsed();
+        if options
+            .seconds
+            .map_or(false, |secs| elapsed.as_secs() >= secs)
+        {
+            return Ok(generated);
+        }
+        let due = (elapsed.as_micros() as u64).saturating_mul(options.rate) / 1_000_000;
+        if due > generated {
+            let events = (due - generated) as usize;
+            frame.resize(events * size, 0);
+            let now = Utc::now();
+            for event in frame.chunks_mut(size) {
+                generator.encode(now, event);
+            }
+            write_frame(&mut writer, &frame)?;
+            generated = due;
+        }
+        if last_report.elapsed() >= Duration::from_secs(1) {
+            let rate = (generated - reported) as f64 / last_report.elapsed().as_secs_f64();
+            eprintln!("cfwstress: {:.0} events/s, {} in total", rate, generated);
+            reported = generated;
+            last_report = Instant::now();
+        }
+        thread::sleep(FRAME_INTERVAL);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("cfwstress: {}\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let result = if options.file == "-" {
+        let stdout = io::stdout();
+        run(&options, stdout.lock())
+    } else {
+        File::create(&options.file).and_then(|file| run(&options, BufWriter::new(file)))
+    };
+    match result {
+        Ok(generated) => eprintln!("cfwstress: generated {} events", generated),
+        // Whoever was reading, cfwlogd or otherwise, has stopped
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => (),
+        Err(e) => {
+            eprintln!("cfwstress: {}: {}", options.file, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn args_are_parsed() {
+        let options = parse_args(&args(&[
+            "--zones",
+            "1,2",
+            "--rate",
+            "5000",
+            "--block-percent",
+            "50",
+            "stress.fifo",
+        ]))
+        .unwrap();
+        assert_eq!(options.mix.zones, vec![1, 2]);
+        assert_eq!(options.mix.block_percent, 50);
+        assert_eq!(options.mix.udp_percent, Mix::default().udp_percent);
+        assert_eq!(options.rate, 5000);
+        assert_eq!(options.file, "stress.fifo");
+
+        assert!(parse_args(&args(&["stress.fifo"])).is_err(), "no zones");
+        assert!(parse_args(&args(&["--zones", "1,x", "f"])).is_err());
+        assert!(parse_args(&args(&["--zones", "1", "--udp-percent", "101", "f"])).is_err());
+        assert!(parse_args(&args(&["--zones", "1", "--rate", "0", "f"])).is_err());
+        assert!(parse_args(&args(&["--zones", "1"])).is_err(), "no file");
+    }
+
+    #[test]
+    fn events_are_written_as_a_capture() {
+        let options = Options {
+            mix: Mix {
+                zones: vec![1],
+                ..Mix::default()
+            },
+            rate: 10_000,
+            seconds: Some(1),
+            file: "-".to_owned(),
+        };
+        let mut buf = vec![];
+        let generated = run(&options, &mut buf).unwrap();
+        assert!(generated > 0);
+        assert_eq!(&buf[..8], CAPTURE_MAGIC);
+
+        // Every frame's length covers the events that follow it
+        let mut bytes = &buf[8..];
+        let mut events = 0;
+        while !bytes.is_empty() {
+            let len = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+            assert_eq!(len % cfwevent::wire::TRAFFIC_EVENT_SIZE, 0);
+            events += len / cfwevent::wire::TRAFFIC_EVENT_SIZE;
+            bytes = &bytes[12 + len..];
+        }
+        assert_eq!(events as u64, generated);
+    }
+}
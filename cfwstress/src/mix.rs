@@ -0,0 +1,185 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The mix of events cfwstress generates. Each zone has a rule of its own and talks to a fixed set
+//! of remote addresses, so enrichment caches and per flow state see repeat visitors the way they
+//! do on a CN, while the protocol, direction and event type of each event are picked at random in
+//! the configured proportions.
+
+use cfwevent::wire::{self, Packet, RuleInfo, TRAFFIC_EVENT_SIZE};
+use chrono::{DateTime, Utc};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use uuid::Uuid;
+
+/// The proportions of the events generated, each a percentage
+#[derive(Clone, Debug, PartialEq)]
+pub struct Mix {
+    pub zones: Vec<u32>,
+    /// Remote addresses each zone talks to
+    pub remotes: u32,
+    pub block_percent: u32,
+    pub udp_percent: u32,
+    pub icmp_percent: u32,
+    pub ipv6_percent: u32,
+    pub inbound_percent: u32,
+}
+
+impl Default for Mix {
+    fn default() -> Self {
+        Mix {
+            zones: vec![],
+            remotes: 1024,
+            block_percent: 10,
+            udp_percent: 10,
+            icmp_percent: 2,
+            ipv6_percent: 10,
+            inbound_percent: 50,
+        }
+    }
+}
+
+/// Generates events in the ipfev wire format
+pub struct Generator {
+    mix: Mix,
+    /// A rule uuid for each zone
+    rules: Vec<Uuid>,
+    /// xorshift64 state, seeded so that runs are repeatable
+    state: u64,
+}
+
+impl Generator {
+    pub fn new(mix: Mix) -> Self {
+        assert!(!mix.zones.is_empty(), "checked by parse_args");
+        Generator {
+            rules: mix.zones.iter().map(|_| Uuid::new_v4()).collect(),
+            mix,
+            state: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    /// Whether an event falls in the given percentage
+    fn chance(&mut self, percent: u32) -> bool {
+        self.next() % 100 < u64::from(percent)
+    }
+
+    /// Encode the next event into the first `TRAFFIC_EVENT_SIZE` bytes of `buf`
+    pub fn encode(&mut self, at: DateTime<Utc>, buf: &mut [u8]) {
+        let zone = (self.next() % self.mix.zones.len() as u64) as usize;
+        let remote = (self.next() % u64::from(self.mix.remotes.max(1))) as u32;
+        let rule = RuleInfo {
+            event: if self.chance(self.mix.block_percent) {
+                1
+            } else {
+                2
+            },
+            zonedid: self.mix.zones[zone],
+            rule_id: zone as u32,
+            rule_uuid: self.rules[zone],
+        };
+        let (local, remote) = if self.chance(self.mix.ipv6_percent) {
+            (
+                Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, zone as u16 + 1),
+                Ipv6Addr::new(
+                    0x2001,
+                    0xdb8,
+                    0,
+                    0,
+                    0,
+                    0,
+                    (remote >> 16) as u16,
+                    remote as u16,
+                ),
+            )
+        } else {
+            (
+                Ipv4Addr::new(10, 0, (zone >> 8) as u8, zone as u8).to_ipv6_mapped(),
+                Ipv4Addr::from(0xc633_6400 + remote).to_ipv6_mapped(),
+            )
+        };
+        let inbound = self.chance(self.mix.inbound_percent);
+        let (protocol, ephemeral, service) = if self.chance(self.mix.icmp_percent) {
+            // Echo requests, with the type and code in place of the ports
+            match local.to_ipv4() {
+                Some(_) => (1, 8, 0),
+                None => (58, 128, 0),
+            }
+        } else if self.chance(self.mix.udp_percent) {
+            (17, 32768 + (self.next() % 28232) as u16, 53)
+        } else {
+            (6, 32768 + (self.next() % 28232) as u16, 443)
+        };
+        let packet = if inbound {
+            Packet {
+                protocol,
+                source_ip: remote,
+                destination_ip: local,
+                source_port: ephemeral,
+                destination_port: service,
+            }
+        } else {
+            Packet {
+                protocol,
+                source_ip: local,
+                destination_ip: remote,
+                source_port: ephemeral,
+                destination_port: service,
+            }
+        };
+        let direction = if inbound { 1 } else { 2 };
+        wire::encode_event(
+            &rule,
+            &packet,
+            direction,
+            at,
+            &mut buf[..TRAFFIC_EVENT_SIZE],
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cfwevent::parser::{self, CfwEvType, CfwEvent, Direction, Protocol};
+
+    #[test]
+    fn generated_events_parse_back() {
+        let mut generator = Generator::new(Mix {
+            zones: vec![7, 8],
+            ..Mix::default()
+        });
+        let mut buf = vec![0; TRAFFIC_EVENT_SIZE];
+        let (mut blocks, mut inbound, mut tcp) = (0, 0, 0);
+        for _ in 0..1000 {
+            generator.encode(Utc::now(), &mut buf);
+            let event = match parser::cfwevent_parse(&buf) {
+                Ok((rest, CfwEvent::Traffic(event))) if rest.is_empty() => event,
+                other => panic!("unexpected event {:?}", other),
+            };
+            assert!(event.zonedid == 7 || event.zonedid == 8);
+            blocks += (event.event == CfwEvType::Block) as u32;
+            inbound += (event.direction == Direction::In) as u32;
+            tcp += (event.protocol == Protocol::TCP) as u32;
+        }
+        assert!(
+            blocks > 50 && blocks < 150,
+            "about 10% are blocks: {}",
+            blocks
+        );
+        assert!(
+            inbound > 400 && inbound < 600,
+            "about half are inbound: {}",
+            inbound
+        );
+        assert!(tcp > 800, "most are TCP: {}", tcp);
+    }
+}
@@ -4,6 +4,7 @@
 
 // Copyright 2019 Joyent, Inc.
 
+use cfwevent::parser::{self, CfwEvType, CfwEvent, TrafficEvent};
 use chrono::{TimeZone, Utc};
 use rand::{thread_rng, Rng};
 use std::net::Ipv6Addr;
@@ -101,6 +102,32 @@ pub fn generate_event() -> Event {
     }
 }
 
+/// The rule matched by the events of tests that don't care which rule it is
+pub const RULE: &str = "5a5e9d7e-4a5a-4f5e-8b9e-3c4b2a1d0e9f";
+
+/// A traffic event parsed from `generate_event`, of type `event` from `source` to
+/// `destination_port` and matching `rule`. Events built with the same arguments repeat each other
+/// apart from their timestamps.
+pub fn traffic_event(
+    event: CfwEvType,
+    source: &str,
+    destination_port: u16,
+    rule: &str,
+) -> TrafficEvent {
+    let raw = generate_event();
+    match parser::cfwevent_parse(raw.as_bytes()).unwrap().1 {
+        CfwEvent::Traffic(traffic) => TrafficEvent {
+            event,
+            source_ip: source.parse().unwrap(),
+            source_port: 50000,
+            destination_port,
+            rule_uuid: rule.parse().unwrap(),
+            ..traffic
+        },
+        CfwEvent::Unknown(_) => unreachable!(),
+    }
+}
+
 pub fn generate_event_for_zone(z: &Zone) -> Event {
     let mut e = generate_event();
     e.zonedid = z.zonedid;
@@ -115,6 +142,8 @@ pub fn create_zone() -> Zone {
         owner_uuid: Uuid::new_v4().to_string(),
         firewall_enabled: true,
         zonedid: rng.gen_range(0, u32::max_value()),
+        tags: Default::default(),
+        nics: Default::default(),
     }
 }
 
@@ -5,12 +5,14 @@
 // // Copyright 2019 Joyent, Inc.
 
 fn main() {
-    // starts a new thread that sends events back over a channel
+    // starts a new thread that sends events back over a queue
     let version = env!("CARGO_PKG_VERSION");
-    let (rx, _vminfod_handle) = vminfod_client::start_vminfod_stream(version);
+    let settings = vminfod_client::Settings::default();
+    let (rx, _vminfod_handle) = vminfod_client::start_vminfod_stream(version, settings)
+        .expect("failed to start the vminfod client");
 
-    // do something with each event
-    for event in rx.iter() {
+    // do something with each event, in the order the queue hands them out
+    for event in rx {
         println!("{:#?}", event);
     }
 }
@@ -0,0 +1,77 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential backoff between reconnection attempts. Each delay is twice the previous one up to
+/// `max`, and has up to half of itself added as jitter so that many clients restarted by the same
+/// vminfod restart don't all reconnect in lockstep.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max,
+            current: initial,
+        }
+    }
+
+    /// Start over from the initial delay, which should be done once a connection has succeeded
+    pub fn reset(&mut self) {
+        self.current = self.initial;
+    }
+
+    /// The delay before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.current;
+        self.current = std::cmp::min(self.current * 2, self.max);
+        delay + jitter(delay / 2)
+    }
+}
+
+/// A pseudo-random duration up to `max`. This only has to differ between processes, so the clock
+/// is a good enough source.
+fn jitter(max: Duration) -> Duration {
+    let max_nanos = max.as_secs() * 1_000_000_000 + u64::from(max.subsec_nanos());
+    if max_nanos == 0 {
+        return Duration::from_secs(0);
+    }
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| u64::from(d.subsec_nanos()) ^ u64::from(std::process::id()).rotate_left(17))
+        .unwrap_or(0);
+    Duration::from_nanos(seed % max_nanos)
+}
+
+#[cfg(test)]
+mod backoff_tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let bounds = [(1, 1.5), (2, 3.0), (4, 6.0), (5, 7.5), (5, 7.5)];
+        for (min, max) in bounds.iter() {
+            let delay = backoff.next_delay();
+            assert!(
+                delay >= Duration::from_secs(*min) && delay <= Duration::from_secs_f64(*max),
+                "{:?} is within [{}, {}]",
+                delay,
+                min,
+                max
+            );
+        }
+
+        backoff.reset();
+        assert!(backoff.next_delay() < Duration::from_secs(2), "reset");
+    }
+}
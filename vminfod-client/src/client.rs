@@ -4,25 +4,112 @@
 
 // Copyright 2019 Joyent, Inc.
 
+use crate::backoff::Backoff;
 use crate::linefeed::Lines;
+use crate::queue::EventSender;
 use crate::VminfodEvent;
-use crossbeam_channel::Sender;
-use futures::{Future, Stream};
-use hyper::{Body, Client as HyperClient, Request};
-use tokio::runtime::current_thread::Runtime;
+use futures::{StreamExt, TryStreamExt};
+use hyper::{Body, Client as HyperClient, Request, Response, StatusCode};
+use serde::Deserialize;
+use tokio::net::UnixStream;
+use tokio::runtime::Builder;
+use tokio::time::timeout;
 
+use std::cell::Cell;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Where vminfod serves its event stream over TCP
+const DEFAULT_URL: &str = "http://127.0.0.1:9090/events";
+
+/// How we reach vminfod
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transport {
+    /// HTTP over TCP to the events url
+    Tcp(String),
+    /// HTTP over the Unix socket at this path
+    Unix(PathBuf),
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport::Tcp(DEFAULT_URL.to_owned())
+    }
+}
+
+/// How long to wait for vminfod to accept our connection and respond, unless configured otherwise
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How we reach vminfod and how long we wait on it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    pub transport: Transport,
+    /// Give up on a connection attempt that hasn't been responded to within this long
+    pub connect_timeout: Duration,
+    /// Treat the event stream as lost when no event arrives within this long. vminfod only sends
+    /// events when zones change, so this should be generous.
+    pub idle_timeout: Option<Duration>,
+    /// Ask vminfod to resume a lost stream after the last event we were sent, rather than start
+    /// over with a `Ready` snapshot
+    pub resume: bool,
+    /// Ask vminfod to only include these fields of each vmobj, or every field if empty
+    pub fields: Vec<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            transport: Transport::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            idle_timeout: None,
+            resume: false,
+            fields: vec![],
+        }
+    }
+}
+
+/// Whether we currently have an event stream open
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns true while the vminfod event stream is open
+pub fn connected() -> bool {
+    CONNECTED.load(Ordering::Relaxed)
+}
+
+/// Delay before the first reconnection attempt after the event stream is lost
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_millis(500);
+
+/// Reconnection attempts are never further apart than this
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 enum Error {
+    Io(std::io::Error),
     Hyper(hyper::Error),
-    Serde(serde_json::error::Error),
+    /// The request for the event stream couldn't be built
+    Request(hyper::http::Error),
     FromUtf8(FromUtf8Error),
+    /// vminfod didn't respond within the connect timeout
+    ConnectTimeout,
+    /// No event arrived within the idle timeout
+    IdleTimeout,
+    /// vminfod can't resume the stream from our cursor, we need a new snapshot
+    CursorExpired,
 }
 
-impl From<serde_json::error::Error> for Error {
-    fn from(err: serde_json::error::Error) -> Error {
-        Error::Serde(err)
+/// Why we stopped streaming events
+#[derive(Debug)]
+enum StreamError {
+    Stream(Error),
+    /// Nobody is listening for events anymore
+    Disconnected,
+}
+
+impl From<Error> for StreamError {
+    fn from(err: Error) -> StreamError {
+        StreamError::Stream(err)
     }
 }
 
@@ -33,50 +120,231 @@ impl From<FromUtf8Error> for Error {
 }
 
 pub(crate) struct Client {
-    sender: Sender<VminfodEvent>,
+    sender: EventSender,
     version: String,
+    settings: Settings,
+    /// The sequence number of the last event we were sent, when vminfod numbers its events
+    cursor: Cell<Option<u64>>,
+}
+
+/// The position of an event in the stream, which vminfod adds to every event when it supports
+/// resuming streams
+#[derive(Deserialize)]
+struct Position {
+    seq: Option<u64>,
+}
+
+/// The events url, which asks vminfod for only the given vmobj `fields`, and to resume the stream
+/// after `cursor` if there is one. vminfod either resumes the stream, starts a new one with a
+/// `Ready` snapshot, or responds with 410 Gone when the cursor is too old to resume from.
+fn events_uri(url: &str, fields: &[String], cursor: Option<u64>) -> String {
+    let mut params = vec![];
+    if !fields.is_empty() {
+        params.push(format!("fields={}", fields.join(",")));
+    }
+    if let Some(seq) = cursor {
+        params.push(format!("since={}", seq));
+    }
+    if params.is_empty() {
+        return url.to_owned();
+    }
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, params.join("&"))
 }
 
 impl Client {
-    pub(crate) fn new(version: String, sender: Sender<VminfodEvent>) -> Self {
-        Client { version, sender }
+    pub(crate) fn new(version: String, settings: Settings, sender: EventSender) -> Self {
+        Client {
+            version,
+            settings,
+            sender,
+            cursor: Cell::new(None),
+        }
     }
 
+    /// Stream events from vminfod until the receiving side of our channel goes away. If vminfod
+    /// can't be reached the first time we give up, and our channel is closed, so the caller can
+    /// decide what to do. Once we have been connected, a lost event stream is reconnected with
+    /// exponential backoff. vminfod starts every new stream with a `Ready` event, which the caller
+    /// uses to resynchronize its view of the zones, unless `Settings::resume` is set and vminfod
+    /// resumes the stream where it left off. Failing to set up the runtime is treated the same as
+    /// vminfod being unreachable.
     pub(crate) fn run(&self) {
-        let req = Request::builder()
-            .method("GET")
-            .header(
-                "User-Agent",
-                format!(
-                    "cfwlogd v{} - VminfodWatcher (firewall-logger-agent)",
-                    self.version
-                ),
-            )
-            .uri("http://127.0.0.1:9090/events")
-            .body(Body::empty())
-            .expect("invalid hyper request params");
-
-        let tx = self.sender.clone();
-        let client = HyperClient::new();
-        let connection = client
-            .request(req)
+        // The vminfod stream is processed by the current thread rather than a pool of threads
+        let rt = match Builder::new_current_thread().enable_all().build() {
+            Ok(rt) => rt,
+            Err(e) => {
+                error!("failed to create vminfod tokio runtime: {}", e);
+                return;
+            }
+        };
+        rt.block_on(self.reconnect_events());
+    }
+
+    async fn reconnect_events(&self) {
+        let mut backoff = Backoff::new(RECONNECT_INITIAL_DELAY, RECONNECT_MAX_DELAY);
+        let mut connected = false;
+        loop {
+            let mut received = 0;
+            let result = self.stream_events(&mut received).await;
+            CONNECTED.store(false, Ordering::Relaxed);
+            if received > 0 {
+                connected = true;
+                backoff.reset();
+            }
+            if !connected {
+                return;
+            }
+            match result {
+                Ok(()) => warn!("vminfod event stream ended"),
+                Err(StreamError::Disconnected) => return,
+                Err(StreamError::Stream(Error::CursorExpired)) => {
+                    info!("vminfod can't resume the event stream, starting a new one");
+                    self.cursor.set(None);
+                    continue;
+                }
+                Err(e) => warn!("vminfod event stream failed: {:?}", e),
+            }
+            let delay = backoff.next_delay();
+            info!("reconnecting to vminfod in {:?}", delay);
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Connect to vminfod and forward every event we're sent until the stream ends, counting the
+    /// events in `received`. An event that doesn't parse is skipped rather than dropping the
+    /// stream, since reconnecting would only be sent the same snapshot again.
+    async fn stream_events(&self, received: &mut u64) -> Result<(), StreamError> {
+        let res = timeout(self.settings.connect_timeout, self.connect())
+            .await
+            .unwrap_or(Err(Error::ConnectTimeout))
             .map_err(|e| {
-                error!("failed to connect to vminfod: {}", e);
-            })
-            .and_then(|res| {
-                Lines::new(res.into_body().map_err(Error::Hyper))
-                    .for_each(move |line| {
-                        let event: VminfodEvent = serde_json::from_str(&line)?;
-                        tx.send(event)
-                            .expect("vminfod receiving channel should always be listening");
-                        Ok(())
-                    })
-                    .map_err(|e| error!("vminfod event stream closed: {:#?}", e))
-            });
+                error!("failed to connect to vminfod: {:?}", e);
+                e
+            })?;
+        let resumed = self.settings.resume && self.cursor.get().is_some();
+        if resumed && res.status() == StatusCode::GONE {
+            return Err(Error::CursorExpired.into());
+        }
+        CONNECTED.store(true, Ordering::Relaxed);
+        let mut lines = Lines::new(res.into_body().map_err(Error::Hyper));
+        loop {
+            let next = match self.settings.idle_timeout {
+                Some(idle) => timeout(idle, lines.next())
+                    .await
+                    .map_err(|_| Error::IdleTimeout)?,
+                None => lines.next().await,
+            };
+            let line = match next {
+                Some(line) => line,
+                None => break,
+            };
+            let line = line?;
+            *received += 1;
+            if self.settings.resume {
+                if let Ok(Position { seq: Some(seq) }) = serde_json::from_str(&line) {
+                    self.cursor.set(Some(seq));
+                }
+            }
+            let event = match parse_event(&line) {
+                Some(event) => event,
+                None => continue,
+            };
+            // The queue is bounded, and blocking here is fine since this runtime has nothing
+            // else to do until there's room for the event
+            self.sender
+                .send(event)
+                .map_err(|_| StreamError::Disconnected)?;
+        }
+        Ok(())
+    }
 
-        // The vminfod stream is processed by the current thread rather than a pool of threads
-        let mut rt = Runtime::new().expect("failed to create vminfod tokio runtime");
-        rt.spawn(connection);
-        rt.run().expect("failed to run vminfod tokio runtime");
+    /// Request the event stream from vminfod over our transport
+    async fn connect(&self) -> Result<Response<Body>, Error> {
+        let req = Request::builder().method("GET").header(
+            "User-Agent",
+            format!(
+                "cfwlogd v{} - VminfodWatcher (firewall-logger-agent)",
+                self.version
+            ),
+        );
+        let cursor = self.cursor.get().filter(|_| self.settings.resume);
+        match &self.settings.transport {
+            Transport::Tcp(url) => {
+                let req = req
+                    .uri(events_uri(url, &self.settings.fields, cursor))
+                    .body(Body::empty())
+                    .map_err(Error::Request)?;
+                HyperClient::new().request(req).await.map_err(Error::Hyper)
+            }
+            Transport::Unix(path) => {
+                // The connection is ours alone, so we drive it ourselves rather than pooling it
+                let stream = UnixStream::connect(path).await.map_err(Error::Io)?;
+                let (mut sender, connection) = hyper::client::conn::handshake(stream)
+                    .await
+                    .map_err(Error::Hyper)?;
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        debug!("vminfod connection closed: {}", e);
+                    }
+                });
+                let req = req
+                    .uri(events_uri("/events", &self.settings.fields, cursor))
+                    .header("Host", "localhost")
+                    .body(Body::empty())
+                    .map_err(Error::Request)?;
+                sender.send_request(req).await.map_err(Error::Hyper)
+            }
+        }
+    }
+}
+
+/// Parse a line of the event stream, logging and skipping one that isn't an event we know
+fn parse_event(line: &str) -> Option<VminfodEvent> {
+    match serde_json::from_str(line) {
+        Ok(event) => Some(event),
+        Err(e) => {
+            warn!("skipping malformed vminfod event: {}", e);
+            debug!("malformed vminfod event: {}", line);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn malformed_events_are_skipped() {
+        let delete = r#"{"type":"delete","zonename":"z1","uuid":"z1"}"#;
+        match parse_event(delete) {
+            Some(VminfodEvent::Delete(event)) => assert_eq!(event.uuid, "z1"),
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert!(parse_event(r#"{"type":"delete","zonename":"z1"}"#).is_none());
+        assert!(parse_event(r#"{"type":"unknown"}"#).is_none());
+        assert!(parse_event("{").is_none());
+    }
+
+    #[test]
+    fn streams_are_resumed() {
+        assert_eq!(events_uri(DEFAULT_URL, &[], None), DEFAULT_URL);
+        assert_eq!(
+            events_uri(DEFAULT_URL, &[], Some(42)),
+            "http://127.0.0.1:9090/events?since=42"
+        );
+        let fields = vec!["uuid".to_owned(), "zonedid".to_owned()];
+        assert_eq!(
+            events_uri("/events?x=1", &fields, Some(7)),
+            "/events?x=1&fields=uuid,zonedid&since=7"
+        );
+        let delete = r#"{"type":"delete","zonename":"z1","uuid":"z1","seq":42}"#;
+        let position: Position = serde_json::from_str(delete).unwrap();
+        assert_eq!(position.seq, Some(42));
+        assert!(
+            parse_event(delete).is_some(),
+            "events are parsed along with their seq"
+        );
     }
 }
@@ -4,9 +4,13 @@
 
 // Copyright 2019 Joyent, Inc.
 
+mod backoff;
 pub mod client;
 pub mod linefeed;
+mod queue;
 
+use std::collections::HashMap;
+use std::io;
 use std::thread;
 
 #[macro_use]
@@ -14,7 +18,9 @@ extern crate log;
 
 // use fully qualified path (crate::*) here until jenkins is no longer on rust 1.31
 use crate::client::Client;
-use serde::Deserialize;
+pub use crate::client::{connected, Settings, Transport};
+pub use crate::queue::{coalesced, superseded, EventReceiver};
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -51,13 +57,17 @@ pub struct DeleteEvent {
     pub uuid: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Clone, Deserialize, Serialize, Debug)]
 pub struct Zone {
     pub uuid: String,
     pub alias: Option<String>,
     pub owner_uuid: String,
     pub firewall_enabled: bool,
     pub zonedid: u32,
+    #[serde(default)]
+    pub tags: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    pub nics: Vec<serde_json::Value>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -66,27 +76,23 @@ pub struct Changes {
 }
 
 /// Starts a new thread that runs a tokio executor/runtime responsible for watching a vminfod event
-/// stream and sending corresponding events back over the receive half of a channel
+/// stream and sending corresponding events back over the returned `EventReceiver`, which hands out
+/// `Ready`, `Create` and `Delete` events ahead of any `Modify` events still queued
 pub fn start_vminfod_stream<S: Into<String>>(
     version: S,
-) -> (
-    crossbeam_channel::Receiver<VminfodEvent>,
-    thread::JoinHandle<()>,
-) {
-    // We allow up to 10 events to be buffered
+    settings: Settings,
+) -> io::Result<(EventReceiver, thread::JoinHandle<()>)> {
+    // We allow up to 10 events of each kind to be buffered
     const NUM_EVENTS_BUFFERED: usize = 10;
 
     let version = version.into();
-    let (tx, rx) = crossbeam_channel::bounded(NUM_EVENTS_BUFFERED);
+    let (tx, rx) = queue::queue(NUM_EVENTS_BUFFERED);
 
-    (
-        rx,
-        thread::Builder::new()
-            .name("vminfod_client".to_string())
-            .spawn(move || {
-                let c = Client::new(version, tx);
-                c.run();
-            })
-            .expect("vminfod client thread spawn failed."),
-    )
+    let handle = thread::Builder::new()
+        .name("vminfod_client".to_string())
+        .spawn(move || {
+            let c = Client::new(version, settings, tx);
+            c.run();
+        })?;
+    Ok((rx, handle))
 }
@@ -4,11 +4,11 @@
 
 // Copyright 2019 Joyent, Inc.
 
-use std::mem::replace;
+use std::pin::Pin;
 use std::string::FromUtf8Error;
+use std::task::{Context, Poll};
 
-use futures::stream::Fuse;
-use futures::{try_ready, Async, Poll, Stream};
+use futures::stream::{Fuse, Stream, StreamExt};
 
 /// The following code is a modified version of:
 /// <https://play.rust-lang.org/?gist=971e438cabd6f91efb76b7e45b15edf3&version=stable>
@@ -31,57 +31,55 @@ impl<S: Stream> Lines<S> {
     }
 
     fn process(&mut self, flush: bool) -> Option<Result<String, FromUtf8Error>> {
-        let buffered = replace(&mut self.buffered, None);
+        let buffered = self.buffered.take();
         if let Some(ref buffer) = buffered {
             let mut split = buffer.splitn(2, |c| *c == b'\n');
             if let Some(first) = split.next() {
                 if let Some(second) = split.next() {
-                    replace(&mut self.buffered, Some(second.to_vec()));
+                    self.buffered = Some(second.to_vec());
                     return Some(String::from_utf8(first.to_vec()));
                 } else if flush {
                     return Some(String::from_utf8(first.to_vec()));
                 }
             }
         }
-        replace(&mut self.buffered, buffered);
+        self.buffered = buffered;
         None
     }
 }
 
-impl<S> Stream for Lines<S>
+impl<S, T, E> Stream for Lines<S>
 where
-    S: Stream,
-    S::Item: AsRef<[u8]>,
-    S::Error: From<FromUtf8Error>,
+    S: Stream<Item = Result<T, E>> + Unpin,
+    T: AsRef<[u8]>,
+    E: From<FromUtf8Error>,
 {
-    type Item = String;
-    type Error = S::Error;
+    type Item = Result<String, E>;
 
-    fn poll(&mut self) -> Poll<Option<String>, S::Error> {
-        // It's important that we loop here so that we only return Async::NotReady when our inner
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        // It's important that we loop here so that we only return Poll::Pending when our inner
         // Stream does.  Otherwise the current task will never be polled again and things will
         // stall
         loop {
-            match try_ready!(self.stream.poll()) {
+            match Pin::new(&mut this.stream).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
                 // We got a chunk of data from the inner stream
-                Some(chunk) => {
-                    if let Some(ref mut buffer) = self.buffered {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    if let Some(ref mut buffer) = this.buffered {
                         buffer.extend(chunk.as_ref());
                     } else {
-                        self.buffered = Some(chunk.as_ref().to_vec());
+                        this.buffered = Some(chunk.as_ref().to_vec());
                     }
-                    match self.process(false) {
-                        Some(Ok(line)) => return Ok(Async::Ready(Some(line))),
-                        Some(Err(err)) => return Err(err.into()),
-                        None => (),
+                    if let Some(line) = this.process(false) {
+                        return Poll::Ready(Some(line.map_err(E::from)));
                     }
                 }
                 // The inner stream has finished
-                None => match self.process(true) {
-                    Some(Ok(line)) => return Ok(Async::Ready(Some(line))),
-                    Some(Err(err)) => return Err(err.into()),
-                    None => return Ok(Async::Ready(None)),
-                },
+                Poll::Ready(None) => {
+                    return Poll::Ready(this.process(true).map(|line| line.map_err(E::from)))
+                }
             }
         }
     }
@@ -90,8 +88,8 @@ where
 #[cfg(test)]
 mod linefeed_tests {
     use super::*;
-    use futures::stream::iter_ok;
-    use futures::Async;
+    use futures::executor::block_on;
+    use futures::stream::iter;
 
     #[test]
     // test that `Lines` is able to buffer and split by new lines
@@ -103,29 +101,17 @@ mod linefeed_tests {
             "world\n",
             "escaped\\n\n",
         ];
-        let stream = iter_ok::<_, FromUtf8Error>(chunks);
+        let stream = iter(chunks.into_iter().map(Ok::<_, FromUtf8Error>));
         let mut lines = Lines::new(stream);
+        let mut next = || block_on(lines.next()).map(Result::unwrap);
 
-        // iter_ok gives us an iterator that is always Ready
-        assert_eq!(
-            lines.poll().unwrap(),
-            Async::Ready(Some("hello world".to_string()))
-        );
-        assert_eq!(
-            lines.poll().unwrap(),
-            Async::Ready(Some("good".to_string()))
-        );
-        assert_eq!(lines.poll().unwrap(), Async::Ready(Some("bye".to_string())));
-        assert_eq!(
-            lines.poll().unwrap(),
-            Async::Ready(Some("world".to_string()))
-        );
-        assert_eq!(
-            lines.poll().unwrap(),
-            Async::Ready(Some("escaped\\n".to_string()))
-        );
-        assert_eq!(lines.poll().unwrap(), Async::Ready(Some("".to_string())));
-        assert_eq!(lines.poll().unwrap(), Async::Ready(None));
+        // iter gives us a stream that is always ready
+        assert_eq!(next(), Some("hello world".to_string()));
+        assert_eq!(next(), Some("good".to_string()));
+        assert_eq!(next(), Some("bye".to_string()));
+        assert_eq!(next(), Some("world".to_string()));
+        assert_eq!(next(), Some("escaped\\n".to_string()));
+        assert_eq!(next(), Some("".to_string()));
+        assert_eq!(next(), None);
     }
-
 }
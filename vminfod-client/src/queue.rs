@@ -0,0 +1,300 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Copyright 2020 Joyent, Inc.
+
+//! The queue between the event stream and whoever takes its events. A mass reprovision can send
+//! a burst of `Modify` events, and in a plain FIFO the `Ready` and `Create` events that zones
+//! need for their events to be attributed would wait behind all of them. So the queue has two
+//! lanes, each bounded: `Modify` events in one, and every other event in the other, which is
+//! always taken from first. The stream only blocks when the lane of the event it wants to queue
+//! is full, which keeps the backpressure of a bounded channel.
+//!
+//! Taking events out of order is only safe because of what each event supersedes. A `Modify`
+//! carries the whole vmobj, so a newer `Modify` of a zone that is still queued replaces the
+//! queued one, with both sets of changes, and a `Create` or `Delete` of a zone drops its queued
+//! `Modify` events. A `Ready` snapshot supersedes everything still queued.
+
+use crate::{ModifyEvent, VminfodEvent};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Running total of the `Modify` events merged into one that was already queued
+static COALESCED: AtomicU64 = AtomicU64::new(0);
+
+/// Running total of the events dropped because a later event superseded them
+static SUPERSEDED: AtomicU64 = AtomicU64::new(0);
+
+/// The `Modify` events merged into an already queued one since we started
+pub fn coalesced() -> u64 {
+    COALESCED.load(Ordering::Relaxed)
+}
+
+/// The events superseded before they were taken since we started
+pub fn superseded() -> u64 {
+    SUPERSEDED.load(Ordering::Relaxed)
+}
+
+struct Lanes {
+    /// `Ready`, `Create` and `Delete` events
+    urgent: VecDeque<VminfodEvent>,
+    modify: VecDeque<ModifyEvent>,
+    senders: bool,
+    receiver: bool,
+}
+
+struct Shared {
+    capacity: usize,
+    lanes: Mutex<Lanes>,
+    /// Signalled when an event is queued or the sender goes away
+    queued: Condvar,
+    /// Signalled when an event is taken or the receiver goes away
+    taken: Condvar,
+}
+
+/// The sending half of the queue, which the stream owns
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+}
+
+/// The receiving half of the queue. Iterating over it blocks for each event until the stream
+/// stops and everything queued has been taken.
+pub struct EventReceiver {
+    shared: Arc<Shared>,
+}
+
+/// The receiver went away
+#[derive(Debug)]
+pub(crate) struct Disconnected;
+
+/// A queue with lanes of up to `capacity` events each
+pub(crate) fn queue(capacity: usize) -> (EventSender, EventReceiver) {
+    let shared = Arc::new(Shared {
+        capacity,
+        lanes: Mutex::new(Lanes {
+            urgent: VecDeque::new(),
+            modify: VecDeque::new(),
+            senders: true,
+            receiver: true,
+        }),
+        queued: Condvar::new(),
+        taken: Condvar::new(),
+    });
+    (
+        EventSender {
+            shared: Arc::clone(&shared),
+        },
+        EventReceiver { shared },
+    )
+}
+
+/// The uuid of the zone an event is about, unless it's a `Ready` snapshot
+fn zone_of(event: &VminfodEvent) -> Option<&str> {
+    match event {
+        VminfodEvent::Ready(_) => None,
+        VminfodEvent::Create(event) => Some(&event.vm.uuid),
+        VminfodEvent::Modify(event) => Some(&event.vm.uuid),
+        VminfodEvent::Delete(event) => Some(&event.uuid),
+    }
+}
+
+fn superseded_by(n: usize) {
+    SUPERSEDED.fetch_add(n as u64, Ordering::Relaxed);
+}
+
+impl EventSender {
+    /// Queue an event, waiting for room in its lane
+    pub(crate) fn send(&self, event: VminfodEvent) -> Result<(), Disconnected> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        match event {
+            VminfodEvent::Modify(mut modify) => {
+                if let Some(queued) = lanes
+                    .modify
+                    .iter_mut()
+                    .find(|queued| queued.vm.uuid == modify.vm.uuid)
+                {
+                    queued.changes.append(&mut modify.changes);
+                    queued.vm = modify.vm;
+                    COALESCED.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                while lanes.receiver && lanes.modify.len() >= self.shared.capacity {
+                    lanes = self.shared.taken.wait(lanes).unwrap();
+                }
+                if !lanes.receiver {
+                    return Err(Disconnected);
+                }
+                lanes.modify.push_back(modify);
+            }
+            event => {
+                match zone_of(&event) {
+                    Some(uuid) => {
+                        let before = lanes.modify.len();
+                        lanes.modify.retain(|queued| queued.vm.uuid != uuid);
+                        superseded_by(before - lanes.modify.len());
+                    }
+                    None => {
+                        superseded_by(lanes.urgent.len() + lanes.modify.len());
+                        lanes.urgent.clear();
+                        lanes.modify.clear();
+                        self.shared.taken.notify_all();
+                    }
+                }
+                while lanes.receiver && lanes.urgent.len() >= self.shared.capacity {
+                    lanes = self.shared.taken.wait(lanes).unwrap();
+                }
+                if !lanes.receiver {
+                    return Err(Disconnected);
+                }
+                lanes.urgent.push_back(event);
+            }
+        }
+        self.shared.queued.notify_one();
+        Ok(())
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.shared.lanes.lock().unwrap().senders = false;
+        self.shared.queued.notify_all();
+    }
+}
+
+impl EventReceiver {
+    /// Take the next event, urgent ones first, or None once the stream has stopped and nothing
+    /// is left
+    pub fn recv(&self) -> Option<VminfodEvent> {
+        let mut lanes = self.shared.lanes.lock().unwrap();
+        loop {
+            let event = lanes
+                .urgent
+                .pop_front()
+                .or_else(|| lanes.modify.pop_front().map(VminfodEvent::Modify));
+            if let Some(event) = event {
+                self.shared.taken.notify_one();
+                return Some(event);
+            }
+            if !lanes.senders {
+                return None;
+            }
+            lanes = self.shared.queued.wait(lanes).unwrap();
+        }
+    }
+}
+
+impl Iterator for EventReceiver {
+    type Item = VminfodEvent;
+
+    fn next(&mut self) -> Option<VminfodEvent> {
+        self.recv()
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.shared.lanes.lock().unwrap().receiver = false;
+        self.shared.taken.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Changes, CreateEvent, DeleteEvent, ReadyEvent, Zone};
+    use std::collections::HashMap;
+    use std::thread;
+    use std::time::Duration;
+
+    fn zone(uuid: &str, zonedid: u32) -> Zone {
+        Zone {
+            uuid: uuid.to_owned(),
+            alias: None,
+            owner_uuid: "owner".to_owned(),
+            firewall_enabled: true,
+            zonedid,
+            tags: HashMap::new(),
+            nics: vec![],
+        }
+    }
+
+    fn modify(uuid: &str, zonedid: u32, change: &str) -> VminfodEvent {
+        VminfodEvent::Modify(ModifyEvent {
+            vm: zone(uuid, zonedid),
+            changes: vec![Changes {
+                path: vec![Some(change.to_owned())],
+            }],
+        })
+    }
+
+    fn create(uuid: &str) -> VminfodEvent {
+        VminfodEvent::Create(CreateEvent { vm: zone(uuid, 1) })
+    }
+
+    /// Each event as its type and zone
+    fn summary(event: VminfodEvent) -> (&'static str, String) {
+        match event {
+            VminfodEvent::Ready(_) => ("ready", String::new()),
+            VminfodEvent::Create(event) => ("create", event.vm.uuid),
+            VminfodEvent::Modify(event) => ("modify", event.vm.uuid),
+            VminfodEvent::Delete(event) => ("delete", event.uuid),
+        }
+    }
+
+    #[test]
+    fn urgent_events_go_first() {
+        let (tx, mut rx) = queue(2);
+        tx.send(modify("a", 1, "alias")).unwrap();
+        tx.send(modify("b", 2, "alias")).unwrap();
+        tx.send(modify("a", 3, "tags")).unwrap();
+        tx.send(create("c")).unwrap();
+        // The modify lane is full, so queueing another waits for one to be taken
+        let handle = thread::spawn(move || {
+            tx.send(modify("d", 4, "alias")).unwrap();
+            tx.send(VminfodEvent::Delete(DeleteEvent {
+                zonename: "b".to_owned(),
+                uuid: "b".to_owned(),
+            }))
+            .unwrap();
+            tx
+        });
+        assert_eq!(summary(rx.recv().unwrap()), ("create", "c".to_owned()));
+        match rx.recv().unwrap() {
+            VminfodEvent::Modify(event) => {
+                assert_eq!(event.vm.zonedid, 3, "the newer vmobj is kept");
+                assert_eq!(event.changes.len(), 2, "along with both changes");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        let tx = handle.join().unwrap();
+        assert_eq!(summary(rx.recv().unwrap()), ("delete", "b".to_owned()));
+        assert_eq!(
+            summary(rx.recv().unwrap()),
+            ("modify", "d".to_owned()),
+            "b's modify was superseded by its delete"
+        );
+
+        tx.send(modify("e", 5, "alias")).unwrap();
+        tx.send(VminfodEvent::Ready(ReadyEvent {
+            vms: "[]".to_owned(),
+        }))
+        .unwrap();
+        drop(tx);
+        let rest: Vec<_> = rx.by_ref().map(summary).collect();
+        assert_eq!(rest, vec![("ready", String::new())]);
+        assert!(superseded() >= 2);
+        assert!(coalesced() >= 1);
+    }
+
+    #[test]
+    fn senders_stop_with_the_receiver() {
+        let (tx, rx) = queue(1);
+        tx.send(modify("a", 1, "alias")).unwrap();
+        let handle = thread::spawn(move || tx.send(modify("b", 2, "alias")));
+        thread::sleep(Duration::from_millis(50));
+        drop(rx);
+        assert!(handle.join().unwrap().is_err(), "a blocked sender gives up");
+    }
+}